@@ -0,0 +1,67 @@
+//! Frame throughput of the TCP protocol codec
+//!
+//! Encodes batches of message frames with `NsqEncoder` into one buffer and
+//! decodes them back with `NsqDecoder`, the way a connection reads a burst of
+//! deliveries. Run with `cargo bench -p nsq-benches --bench codec`.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nsq_protocol::{Frame, FrameType, Message, NsqDecoder, NsqEncoder};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Frames in each encoded batch
+const FRAMES: usize = 1_000;
+/// Message body sizes to measure
+const BODY_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+fn frames(body_size: usize) -> Vec<Frame> {
+    (0..FRAMES)
+        .map(|_| {
+            let message = Message::new(Bytes::from(vec![b'x'; body_size]));
+            Frame::new(FrameType::Message, message.to_bytes())
+        })
+        .collect()
+}
+
+fn encode(frames: &[Frame]) -> BytesMut {
+    let mut buffer = BytesMut::new();
+    for frame in frames {
+        NsqEncoder.encode(frame.clone(), &mut buffer).unwrap();
+    }
+    buffer
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/encode");
+    for body_size in BODY_SIZES {
+        let frames = frames(body_size);
+        group.throughput(Throughput::Bytes(encode(&frames).len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(body_size), &frames, |b, frames| {
+            b.iter(|| encode(frames));
+        });
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/decode");
+    for body_size in BODY_SIZES {
+        let encoded = encode(&frames(body_size));
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(body_size), &encoded, |b, encoded| {
+            b.iter(|| {
+                let mut buffer = encoded.clone();
+                let mut decoder = NsqDecoder::new();
+                let mut decoded = 0;
+                while decoder.decode(&mut buffer).unwrap().is_some() {
+                    decoded += 1;
+                }
+                assert_eq!(decoded, FRAMES);
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+//! Disk queue append and read
+//!
+//! Appends batches of records to a `DiskQueue` in a temporary directory and
+//! reads them back, with nsqd's default group fsync and with an fsync per
+//! record as `--sync-every=1` does. Run with
+//! `cargo bench -p nsq-benches --bench disk_queue`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use nsq_common::DiskQueue;
+use uuid::Uuid;
+
+/// Records appended or read per iteration
+const RECORDS: usize = 1_000;
+/// Record sizes to measure
+const RECORD_SIZES: [usize; 2] = [256, 4096];
+/// Segment file size, as nsqd uses
+const FILE_SIZE: usize = 100 * 1024 * 1024;
+
+/// A disk queue in its own temporary directory, removed on drop
+struct TempQueue {
+    path: PathBuf,
+    queue: DiskQueue,
+}
+
+impl TempQueue {
+    fn new(sync_every: u64) -> Self {
+        let path = std::env::temp_dir().join(format!("nsq-bench-{}", Uuid::new_v4()));
+        let queue = DiskQueue::new(&path, FILE_SIZE, 1024 * 1024, Duration::from_secs(2))
+            .unwrap()
+            .with_sync_every(sync_every);
+        Self { path, queue }
+    }
+
+    fn append(&self, record: &[u8]) {
+        for _ in 0..RECORDS {
+            self.queue.put(record).unwrap();
+        }
+    }
+}
+
+impl Drop for TempQueue {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn bench_append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("disk_queue/append");
+    group.sample_size(20);
+    for record_size in RECORD_SIZES {
+        let record = vec![b'x'; record_size];
+        group.throughput(Throughput::Bytes((RECORDS * record_size) as u64));
+        for (name, sync_every) in [("group_sync", 2500), ("sync_each", 1)] {
+            group.bench_with_input(BenchmarkId::new(name, record_size), &record, |b, record| {
+                b.iter_batched(|| TempQueue::new(sync_every), |queue| queue.append(record), BatchSize::PerIteration);
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("disk_queue/read");
+    group.sample_size(20);
+    for record_size in RECORD_SIZES {
+        let record = vec![b'x'; record_size];
+        group.throughput(Throughput::Bytes((RECORDS * record_size) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(record_size), &record, |b, record| {
+            b.iter_batched(
+                || {
+                    let queue = TempQueue::new(2500);
+                    queue.append(record);
+                    queue.queue.sync().unwrap();
+                    queue
+                },
+                |queue| {
+                    let mut read = 0;
+                    while queue.queue.get().unwrap().is_some() {
+                        read += 1;
+                    }
+                    assert_eq!(read, RECORDS);
+                    queue
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_append, bench_read);
+criterion_main!(benches);
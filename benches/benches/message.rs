@@ -0,0 +1,60 @@
+//! Message serialization
+//!
+//! `Message::to_bytes` and `Message::from_bytes` for a range of body sizes,
+//! with and without headers. Run with
+//! `cargo bench -p nsq-benches --bench message`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nsq_protocol::{Message, MessageHeaders};
+
+/// Message body sizes to measure
+const BODY_SIZES: [usize; 3] = [64, 1024, 64 * 1024];
+
+fn message(body_size: usize) -> Message {
+    Message::new(Bytes::from(vec![b'x'; body_size]))
+}
+
+fn with_headers(message: Message) -> Message {
+    let headers: MessageHeaders = [("content-type", "application/json"), ("trace-id", "4bf92f3577b34da6a3ce929d0e0e4736")]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    message.with_headers(headers)
+}
+
+fn bench_to_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("message/to_bytes");
+    for body_size in BODY_SIZES {
+        let message = message(body_size);
+        group.throughput(Throughput::Bytes(body_size as u64));
+        group.bench_with_input(BenchmarkId::new("plain", body_size), &message, |b, message| {
+            b.iter(|| message.to_bytes());
+        });
+        let message = with_headers(message);
+        group.bench_with_input(BenchmarkId::new("headers", body_size), &message, |b, message| {
+            b.iter(|| message.to_bytes_with_headers());
+        });
+    }
+    group.finish();
+}
+
+fn bench_from_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("message/from_bytes");
+    for body_size in BODY_SIZES {
+        let message = message(body_size);
+        group.throughput(Throughput::Bytes(body_size as u64));
+        let encoded = message.to_bytes();
+        group.bench_with_input(BenchmarkId::new("plain", body_size), &encoded, |b, encoded| {
+            b.iter(|| Message::from_bytes(encoded.clone()).unwrap());
+        });
+        let encoded = with_headers(message).to_bytes_with_headers();
+        group.bench_with_input(BenchmarkId::new("headers", body_size), &encoded, |b, encoded| {
+            b.iter(|| Message::from_bytes(encoded.clone()).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_to_bytes, bench_from_bytes);
+criterion_main!(benches);
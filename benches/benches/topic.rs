@@ -0,0 +1,71 @@
+//! Topic publish fan-out
+//!
+//! Publishes to an in-memory topic with a growing number of channels, each
+//! of which gets its own copy of every message, and drains the channels
+//! between iterations so their queues stay in memory. Run with
+//! `cargo bench -p nsq-benches --bench topic`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nsq_common::{BaseConfig, Metrics};
+use nsq_protocol::Message;
+use nsqd::{Channel, Topic};
+use std::sync::Arc;
+
+/// Messages published per iteration
+const MESSAGES: usize = 1_000;
+/// Channel counts to measure
+const CHANNELS: [usize; 4] = [1, 2, 4, 16];
+/// Message body size
+const BODY_SIZE: usize = 256;
+
+fn topic(channels: usize) -> (Topic, Vec<Arc<Channel>>) {
+    let metrics = Metrics::new(&BaseConfig::default()).unwrap();
+    let topic = Topic::new("bench".to_string(), MESSAGES * 2, None, metrics).unwrap();
+    let channels = (0..channels)
+        .map(|i| topic.add_channel(format!("channel_{}", i), None).unwrap())
+        .collect();
+    (topic, channels)
+}
+
+fn bench_publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topic/publish");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    let body = Bytes::from(vec![b'x'; BODY_SIZE]);
+    for count in CHANNELS {
+        let (topic, channels) = topic(count);
+        group.bench_with_input(BenchmarkId::new("channels", count), &body, |b, body| {
+            b.iter(|| {
+                for _ in 0..MESSAGES {
+                    topic.publish(Message::new(body.clone())).unwrap();
+                }
+                for channel in &channels {
+                    while channel.get_message().unwrap().is_some() {}
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_publish_multiple(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topic/publish_multiple");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    let body = Bytes::from(vec![b'x'; BODY_SIZE]);
+    for count in CHANNELS {
+        let (topic, channels) = topic(count);
+        group.bench_with_input(BenchmarkId::new("channels", count), &body, |b, body| {
+            b.iter(|| {
+                let messages = (0..MESSAGES).map(|_| Message::new(body.clone())).collect();
+                topic.publish_multiple(messages).unwrap();
+                for channel in &channels {
+                    while channel.get_message().unwrap().is_some() {}
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_publish, bench_publish_multiple);
+criterion_main!(benches);
@@ -0,0 +1,177 @@
+//! HTTP access logging
+//!
+//! `AccessLogLayer` wraps the HTTP router of nsqd, nsqlookupd and nsqadmin
+//! and records the method, path, query parameters, status, latency and
+//! remote address of each request. Entries are logged as `access` events, or
+//! written as JSON lines to their own file for shipping to a log pipeline.
+//! A sample rate below 1 keeps that fraction of requests.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use std::io::Write;
+use axum::extract::ConnectInfo;
+use futures::future::BoxFuture;
+use http::{Request, Response};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use crate::config::BaseConfig;
+use crate::errors::{NsqError, Result};
+use crate::logging::RotatingFile;
+
+/// One logged request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub method: String,
+    pub path: String,
+    /// Query string, empty when there is none
+    pub query: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    /// Unknown when the server does not record connection addresses
+    pub remote_address: Option<String>,
+}
+
+/// Where sampled requests are logged
+struct AccessLog {
+    sample_rate: f64,
+    /// Requests seen, for sampling
+    seen: AtomicU64,
+    /// JSON lines file; `access` events when unset
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl AccessLog {
+    /// Whether the next request is logged. Every request advances the count,
+    /// so exactly `sample_rate` of them are kept.
+    fn sample(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.sample_rate).floor() > (seen * self.sample_rate).floor()
+    }
+
+    fn record(&self, entry: AccessLogEntry) {
+        let Some(file) = &self.file else {
+            tracing::info!(
+                target: "access",
+                method = %entry.method,
+                path = %entry.path,
+                query = %entry.query,
+                status = entry.status,
+                latency_ms = entry.latency_ms,
+                remote_address = entry.remote_address.as_deref().unwrap_or("-"),
+                "{} {} {}",
+                entry.method,
+                entry.path,
+                entry.status,
+            );
+            return;
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => return tracing::warn!("Failed to encode access log entry: {}", e),
+        };
+        let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writeln!(file, "{}", line) {
+            tracing::warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// Layer logging the requests of an HTTP service; does nothing unless
+/// `access_log` is enabled
+#[derive(Clone, Default)]
+pub struct AccessLogLayer {
+    log: Option<Arc<AccessLog>>,
+}
+
+impl AccessLogLayer {
+    /// Build the layer from the `access_log` settings of a daemon
+    pub fn new(config: &BaseConfig) -> Result<Self> {
+        if !config.access_log {
+            return Ok(Self::default());
+        }
+        if !(0.0..=1.0).contains(&config.access_log_sample_rate) {
+            return Err(NsqError::Config(format!(
+                "invalid access log sample rate {}: must be between 0 and 1",
+                config.access_log_sample_rate
+            )));
+        }
+        let file = match &config.access_log_file {
+            Some(path) => {
+                let file = RotatingFile::open(path, config.log_max_size, config.log_max_files)
+                    .map_err(|e| NsqError::Config(format!("failed to open access log {}: {}", path, e)))?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Self {
+            log: Some(Arc::new(AccessLog {
+                sample_rate: config.access_log_sample_rate,
+                seen: AtomicU64::new(0),
+                file,
+            })),
+        })
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService { inner, log: self.log.clone() }
+    }
+}
+
+/// Service created by `AccessLogLayer`
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    log: Option<Arc<AccessLog>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for AccessLogService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, std::result::Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let Some(log) = self.log.clone().filter(|log| log.sample()) else {
+            return Box::pin(self.inner.call(request));
+        };
+        let timestamp = chrono::Utc::now();
+        let start = Instant::now();
+        let method = request.method().to_string();
+        let path = request.uri().path().to_string();
+        let query = request.uri().query().unwrap_or_default().to_string();
+        let remote_address = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.to_string());
+
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            log.record(AccessLogEntry {
+                timestamp,
+                method,
+                path,
+                query,
+                status: response.status().as_u16(),
+                latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+                remote_address,
+            });
+            Ok(response)
+        })
+    }
+}
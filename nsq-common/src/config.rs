@@ -1,7 +1,8 @@
 //! Configuration management
 
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
 use crate::errors::{NsqError, Result};
 
 /// Base configuration for NSQ components
@@ -11,10 +12,31 @@ pub struct BaseConfig {
     pub log_level: String,
     /// Log format (json, text)
     pub log_format: String,
+    /// Per-module level overrides such as `nsqd::channel=debug`
+    pub log_filters: Vec<String>,
+    /// Log file; stdout when unset
+    pub log_file: Option<String>,
+    /// Size in bytes at which the log file is rotated; 0 disables rotation
+    pub log_max_size: u64,
+    /// Rotated log files to keep
+    pub log_max_files: usize,
+    /// OTLP/HTTP collector that spans are exported to; tracing is off when unset
+    pub otlp_endpoint: Option<String>,
+    /// Service name spans are exported under; the executable's name when unset
+    pub otlp_service_name: Option<String>,
+    /// Fraction of traces exported (0.0-1.0)
+    pub otlp_sample_rate: f64,
     /// Statsd address
     pub statsd_address: Option<String>,
     /// Statsd prefix
     pub statsd_prefix: String,
+    /// Log every HTTP request
+    pub access_log: bool,
+    /// Fraction of HTTP requests logged (0.0-1.0)
+    pub access_log_sample_rate: f64,
+    /// File HTTP requests are written to as JSON lines; logged with the rest
+    /// of the output when unset
+    pub access_log_file: Option<String>,
 }
 
 impl Default for BaseConfig {
@@ -22,8 +44,18 @@ impl Default for BaseConfig {
         Self {
             log_level: "info".to_string(),
             log_format: "text".to_string(),
+            log_filters: Vec::new(),
+            log_file: None,
+            log_max_size: 100 * 1024 * 1024,
+            log_max_files: 5,
+            otlp_endpoint: None,
+            otlp_service_name: None,
+            otlp_sample_rate: 1.0,
             statsd_address: None,
             statsd_prefix: "nsq".to_string(),
+            access_log: false,
+            access_log_sample_rate: 1.0,
+            access_log_file: None,
         }
     }
 }
@@ -40,6 +72,8 @@ pub struct NsqdConfig {
     pub http_address: String,
     /// HTTPS address to listen on
     pub https_address: Option<String>,
+    /// Address advertised to lookupd and clients
+    pub broadcast_address: String,
     
     /// Unix socket paths
     pub tcp_socket_path: Option<String>,
@@ -51,6 +85,21 @@ pub struct NsqdConfig {
     
     /// Memory queue size
     pub mem_queue_size: usize,
+    /// Queued messages, in memory and on disk, above which a topic refuses
+    /// HTTP publishes (0 = no limit)
+    pub max_topic_depth: u64,
+    /// Time in milliseconds a blocking HTTP publish waits for a full topic
+    pub pub_block_timeout: u64,
+    /// Messages written to a disk queue between fsyncs (0 = only on sync_timeout)
+    pub sync_every: u64,
+    /// Time in milliseconds after which written messages are fsynced
+    pub sync_timeout: u64,
+    /// Acknowledge publishes only once they are on disk and fsynced, unless
+    /// a topic overrides it
+    pub durable_publish: bool,
+    /// Bytes all topics may hold in memory before queued messages are
+    /// spilled to disk (0 = no limit)
+    pub max_memory_size: usize,
     
     /// Maximum message size
     pub max_msg_size: usize,
@@ -63,6 +112,24 @@ pub struct NsqdConfig {
     pub max_msg_timeout: u64,
     /// Default message timeout
     pub msg_timeout: u64,
+    /// Highest heartbeat interval in milliseconds a client may ask for
+    pub max_heartbeat_interval: u64,
+    /// Milliseconds a channel may go without clients, messages or activity
+    /// before it is deleted (0 = never)
+    pub channel_idle_timeout: u64,
+    
+    /// URL alerts are POSTed to; alerting is off when unset
+    pub alert_webhook_url: Option<String>,
+    /// Queued messages above which a depth alert fires (0 = off)
+    pub alert_max_depth: u64,
+    /// Requeues per second of a channel above which an alert fires (0 = off)
+    pub alert_max_requeue_rate: f64,
+    /// Milliseconds a channel may go without consumers before an alert
+    /// fires (0 = off)
+    pub alert_no_consumers_timeout: u64,
+    /// Milliseconds a condition must hold before its alert fires, and stay
+    /// clear before it resolves
+    pub alert_debounce: u64,
     
     /// Maximum output buffer size
     pub max_output_buffer_size: usize,
@@ -80,11 +147,42 @@ pub struct NsqdConfig {
     
     /// Lookupd TCP addresses
     pub lookupd_tcp_addresses: Vec<String>,
+    /// Lookupd HTTP addresses replication peers are discovered from
+    pub lookupd_http_addresses: Vec<String>,
+    /// Peer nodes each topic is mirrored to (0 = no replication)
+    pub replication_factor: usize,
     
     /// Disable HTTP interface
     pub disable_http: bool,
     /// Disable HTTPS interface
     pub disable_https: bool,
+    /// Reject SUB to topics and channels that do not exist instead of creating them
+    pub disable_implicit_creation: bool,
+    /// Accept TCP clients that start sending commands without the protocol magic
+    pub allow_missing_magic: bool,
+    /// TCP clients that may be connected at once (0 = no limit)
+    pub max_connections: usize,
+    /// New TCP connections a second accepted from one IP (0 = no limit)
+    pub max_connection_rate: u64,
+    /// Commands a second accepted from the TCP clients of one IP (0 = no limit)
+    pub max_command_rate: u64,
+    
+    /// Availability zone reported to lookupd
+    pub zone: Option<String>,
+    /// Region reported to lookupd
+    pub region: Option<String>,
+    /// Worker ID (0-1023) stamped into message IDs; derived from the hostname
+    /// and TCP port when unset
+    #[serde(default)]
+    pub node_id: Option<u16>,
+    
+    /// Time in milliseconds to wait for in-flight messages on shutdown
+    pub drain_timeout: u64,
+    
+    /// Deliveries allowed per message before it is dead-lettered (0 = unlimited)
+    pub max_attempts: u16,
+    /// Drop messages over max_attempts instead of moving them to the dead-letter topic
+    pub disable_dead_letter: bool,
 }
 
 impl Default for NsqdConfig {
@@ -94,16 +192,30 @@ impl Default for NsqdConfig {
             tcp_address: "0.0.0.0:4150".to_string(),
             http_address: "0.0.0.0:4151".to_string(),
             https_address: None,
+            broadcast_address: "auto".to_string(),
             tcp_socket_path: None,
             http_socket_path: None,
             https_socket_path: None,
             data_path: PathBuf::from("./data"),
             mem_queue_size: 10000,
+            max_topic_depth: 0,
+            pub_block_timeout: 5000,
+            sync_every: 2500,
+            sync_timeout: 2000,
+            durable_publish: false,
+            max_memory_size: 0,
             max_msg_size: 1024 * 1024, // 1MB
             max_body_size: 5 * 1024 * 1024, // 5MB
             max_req_timeout: 60 * 1000, // 60 seconds
             max_msg_timeout: 15 * 60 * 1000, // 15 minutes
             msg_timeout: 60 * 1000, // 60 seconds
+            max_heartbeat_interval: 60 * 1000, // 60 seconds
+            channel_idle_timeout: 0,
+            alert_webhook_url: None,
+            alert_max_depth: 0,
+            alert_max_requeue_rate: 0.0,
+            alert_no_consumers_timeout: 0,
+            alert_debounce: 30 * 1000, // 30 seconds
             max_output_buffer_size: 16 * 1024, // 16KB
             max_output_buffer_timeout: 250, // 250ms
             tls_cert: None,
@@ -112,8 +224,21 @@ impl Default for NsqdConfig {
             tls_min_version: "1.2".to_string(),
             e2e_processing_latency_percentile: vec![0.5, 0.75, 0.9, 0.95, 0.99],
             lookupd_tcp_addresses: Vec::new(),
+            lookupd_http_addresses: Vec::new(),
+            replication_factor: 0,
             disable_http: false,
             disable_https: false,
+            disable_implicit_creation: false,
+            allow_missing_magic: false,
+            max_connections: 0,
+            max_connection_rate: 0,
+            max_command_rate: 0,
+            zone: None,
+            region: None,
+            node_id: None,
+            drain_timeout: 30 * 1000, // 30 seconds
+            max_attempts: 0,
+            disable_dead_letter: false,
         }
     }
 }
@@ -128,6 +253,8 @@ pub struct NsqlookupdConfig {
     pub tcp_address: String,
     /// HTTP address to listen on
     pub http_address: String,
+    /// Address advertised to peers
+    pub broadcast_address: String,
     
     /// Unix socket paths
     pub tcp_socket_path: Option<String>,
@@ -137,6 +264,14 @@ pub struct NsqlookupdConfig {
     pub inactive_producer_timeout: u64,
     /// Tombstone lifetime
     pub tombstone_lifetime: u64,
+    /// Interval between stale producer and tombstone sweeps (ms)
+    pub cleanup_interval: u64,
+    /// Static producer registrations as `topic=address:tcp_port[:http_port]`
+    pub seed_producers: Vec<String>,
+    /// HTTP addresses of peer nsqlookupd instances to replicate registrations from
+    pub peer_http_addresses: Vec<String>,
+    /// Interval between fetches of peer registrations (ms)
+    pub peer_sync_interval: u64,
 }
 
 impl Default for NsqlookupdConfig {
@@ -145,10 +280,15 @@ impl Default for NsqlookupdConfig {
             base: BaseConfig::default(),
             tcp_address: "0.0.0.0:4160".to_string(),
             http_address: "0.0.0.0:4161".to_string(),
+            broadcast_address: "auto".to_string(),
             tcp_socket_path: None,
             http_socket_path: None,
             inactive_producer_timeout: 300 * 1000, // 5 minutes
             tombstone_lifetime: 45 * 1000, // 45 seconds
+            cleanup_interval: 30 * 1000, // 30 seconds
+            seed_producers: Vec::new(),
+            peer_http_addresses: Vec::new(),
+            peer_sync_interval: 5 * 1000, // 5 seconds
         }
     }
 }
@@ -179,9 +319,37 @@ pub struct NsqadminConfig {
     pub graphite_url: Option<String>,
     /// Proxy graph queries
     pub proxy_graphite: bool,
+    /// Prefix of the statsd metrics nsqd reports, `*` matching every node
+    pub graphite_prefix: String,
+    /// Graphite name of a statsd counter, `%s` being the metric
+    pub graphite_counter_format: String,
+    /// Graphite name of a statsd gauge, `%s` being the metric
+    pub graphite_gauge_format: String,
     
     /// Notification HTTP endpoint
     pub notification_http_endpoint: Option<String>,
+    
+    /// Header an auth proxy sets to the authenticated user
+    pub acl_http_header: String,
+    /// Users allowed to change topics and channels; empty allows any authenticated user
+    pub admin_users: Vec<String>,
+    /// HTTP Basic credentials as `user:password`
+    pub basic_auth_users: Vec<String>,
+    
+    /// Interval between stats pushed to `/api/stream` subscribers (ms)
+    pub stream_interval: u64,
+    
+    /// Interval between stats samples kept for `/api/graphs` (ms)
+    pub history_interval: u64,
+    /// How far back `/api/graphs` samples are kept (ms)
+    pub history_window: u64,
+    
+    /// Timeout of each request to nsqd and lookupd (ms)
+    pub upstream_timeout: u64,
+    /// Most nsqd nodes queried for stats at once
+    pub upstream_concurrency: usize,
+    /// How long merged nsqd stats are reused before querying again (ms, 0 = never)
+    pub stats_cache_ttl: u64,
 }
 
 impl Default for NsqadminConfig {
@@ -196,7 +364,19 @@ impl Default for NsqadminConfig {
             dev_static_dir: None,
             graphite_url: None,
             proxy_graphite: false,
+            graphite_prefix: "nsq.*".to_string(),
+            graphite_counter_format: "stats.counters.%s.count".to_string(),
+            graphite_gauge_format: "stats.gauges.%s".to_string(),
             notification_http_endpoint: None,
+            acl_http_header: "X-Forwarded-User".to_string(),
+            admin_users: Vec::new(),
+            basic_auth_users: Vec::new(),
+            stream_interval: 2 * 1000, // 2 seconds
+            history_interval: 10 * 1000, // 10 seconds
+            history_window: 2 * 60 * 60 * 1000, // 2 hours
+            upstream_timeout: 5 * 1000, // 5 seconds
+            upstream_concurrency: 16,
+            stats_cache_ttl: 1000, // 1 second
         }
     }
 }
@@ -229,3 +409,205 @@ where
     
     Ok(())
 }
+
+/// Names of the arguments given on the command line rather than defaulted
+pub fn explicit_args(matches: &clap::ArgMatches) -> Vec<String> {
+    matches
+        .ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(clap::parser::ValueSource::CommandLine))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// Layer a TOML, YAML or JSON config file between the command line defaults
+/// in `cli` and the arguments named in `explicit`, which keep their command
+/// line values. Keys are the config struct's field names, with the `base`
+/// settings such as `log_level` at the top level of the file.
+pub fn merge_config_file<T>(cli: &T, explicit: &[String], path: &Path) -> Result<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let file = read_config_file(path)?;
+    let (cli_values, base_keys) = flatten_base(serde_json::to_value(cli)?);
+
+    let mut merged = cli_values.clone();
+    for (key, value) in file {
+        if !cli_values.contains_key(&key) {
+            return Err(unknown_key_error(path, &key, cli_values.keys()));
+        }
+
+        // Check each value alone so the error names the offending key
+        let mut candidate = cli_values.clone();
+        candidate.insert(key.clone(), value.clone());
+        if let Err(e) = serde_json::from_value::<T>(unflatten_base(candidate, &base_keys)) {
+            return Err(NsqError::Config(format!("Invalid value for `{}` in {}: {}", key, path.display(), e)));
+        }
+        merged.insert(key, value);
+    }
+
+    for key in explicit {
+        if let Some(value) = cli_values.get(key) {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    serde_json::from_value(unflatten_base(merged, &base_keys))
+        .map_err(|e| NsqError::Config(format!("Invalid config file {}: {}", path.display(), e)))
+}
+
+/// Read a config file into a map of top-level keys, picking the format from
+/// the file extension
+fn read_config_file(path: &Path) -> Result<Map<String, Value>> {
+    if !path.is_file() {
+        return Err(NsqError::Config(format!("Config file {} does not exist", path.display())));
+    }
+    config::Config::builder()
+        .add_source(config::File::from(path))
+        .build()
+        .and_then(|settings| settings.try_deserialize())
+        .map_err(|e| NsqError::Config(format!("Failed to parse config file {}: {}", path.display(), e)))
+}
+
+/// Lift the fields of a nested `base` object to the top level, returning
+/// the flattened map and the lifted keys
+fn flatten_base(value: Value) -> (Map<String, Value>, Vec<String>) {
+    let mut map = match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    let mut base_keys = Vec::new();
+    if let Some(Value::Object(base)) = map.remove("base") {
+        for (key, value) in base {
+            base_keys.push(key.clone());
+            map.insert(key, value);
+        }
+    }
+    (map, base_keys)
+}
+
+/// Undo `flatten_base`
+fn unflatten_base(mut map: Map<String, Value>, base_keys: &[String]) -> Value {
+    if !base_keys.is_empty() {
+        let base: Map<String, Value> = base_keys
+            .iter()
+            .filter_map(|key| map.remove(key).map(|value| (key.clone(), value)))
+            .collect();
+        map.insert("base".to_string(), Value::Object(base));
+    }
+    Value::Object(map)
+}
+
+/// Error for a key the config struct does not have, suggesting the closest
+/// known key
+fn unknown_key_error<'a>(path: &Path, key: &str, known: impl Iterator<Item = &'a String>) -> NsqError {
+    let mut known: Vec<&String> = known.collect();
+    known.sort();
+    let suggestion = known
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| format!(", did you mean `{}`?", candidate))
+        .unwrap_or_else(|| {
+            let names: Vec<&str> = known.iter().map(|name| name.as_str()).collect();
+            format!(" (expected one of: {})", names.join(", "))
+        });
+    NsqError::Config(format!("Unknown key `{}` in {}{}", key, path.display(), suggestion))
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// Write a config file with `extension` into a fresh temp dir
+    fn config_file(extension: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nsq-config-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("nsqlookupd.{}", extension));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn merge(cli: &NsqlookupdConfig, explicit: &[&str], path: &Path) -> Result<NsqlookupdConfig> {
+        let explicit: Vec<String> = explicit.iter().map(|key| key.to_string()).collect();
+        let merged = merge_config_file(cli, &explicit, path);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        merged
+    }
+
+    #[test]
+    fn test_file_overrides_defaults() {
+        let path = config_file("toml", "tcp_address = \"127.0.0.1:5160\"\nlog_level = \"debug\"\ntombstone_lifetime = 5000\n");
+        let config = merge(&NsqlookupdConfig::default(), &[], &path).unwrap();
+        assert_eq!(config.tcp_address, "127.0.0.1:5160");
+        assert_eq!(config.base.log_level, "debug");
+        assert_eq!(config.tombstone_lifetime, 5000);
+        assert_eq!(config.http_address, NsqlookupdConfig::default().http_address);
+    }
+
+    #[test]
+    fn test_explicit_flags_override_file() {
+        let path = config_file("toml", "tcp_address = \"127.0.0.1:5160\"\nhttp_address = \"127.0.0.1:5161\"\n");
+        let cli = NsqlookupdConfig {
+            tcp_address: "127.0.0.1:6160".to_string(),
+            http_address: "127.0.0.1:6161".to_string(),
+            ..Default::default()
+        };
+        // Only the tcp address was given on the command line
+        let config = merge(&cli, &["tcp_address"], &path).unwrap();
+        assert_eq!(config.tcp_address, "127.0.0.1:6160");
+        assert_eq!(config.http_address, "127.0.0.1:5161");
+    }
+
+    #[test]
+    fn test_unknown_key_suggests_the_closest() {
+        let path = config_file("toml", "tcp_adress = \"127.0.0.1:5160\"\n");
+        let err = merge(&NsqlookupdConfig::default(), &[], &path).unwrap_err().to_string();
+        assert!(err.contains("Unknown key `tcp_adress`"), "{}", err);
+        assert!(err.contains("did you mean `tcp_address`?"), "{}", err);
+
+        let path = config_file("toml", "zzzzzzzzzz = 1\n");
+        let err = merge(&NsqlookupdConfig::default(), &[], &path).unwrap_err().to_string();
+        assert!(err.contains("expected one of:"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_value_names_the_key() {
+        let path = config_file("toml", "tcp_address = \"127.0.0.1:5160\"\ninactive_producer_timeout = \"soon\"\n");
+        let err = merge(&NsqlookupdConfig::default(), &[], &path).unwrap_err().to_string();
+        assert!(err.contains("Invalid value for `inactive_producer_timeout`"), "{}", err);
+    }
+
+    #[test]
+    fn test_yaml_file() {
+        let path = config_file("yaml", "tcp_address: 127.0.0.1:5160\nlog_level: warn\nseed_producers:\n  - events=127.0.0.1:4150\n");
+        let config = merge(&NsqlookupdConfig::default(), &[], &path).unwrap();
+        assert_eq!(config.tcp_address, "127.0.0.1:5160");
+        assert_eq!(config.base.log_level, "warn");
+        assert_eq!(config.seed_producers, vec!["events=127.0.0.1:4150".to_string()]);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("tcp_address", "tcp_address"), 0);
+        assert_eq!(edit_distance("tcp_adress", "tcp_address"), 1);
+        assert_eq!(edit_distance("log_levle", "log_level"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+}
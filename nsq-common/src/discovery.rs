@@ -0,0 +1,299 @@
+//! nsqd discovery through nsqlookupd
+//!
+//! `LookupdDiscovery` asks every lookupd for the producers of a topic, or for
+//! every node when no topic is given, and reports the producers that appeared
+//! or went away since the previous poll. A lookupd that cannot be reached is
+//! skipped; a poll that no lookupd answered changes nothing, so a lookupd
+//! outage never looks like every nsqd leaving.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::{NsqError, Result};
+
+/// How often `watch` polls lookupd by default
+pub const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+/// Timeout for a single lookupd request
+const LOOKUPD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An nsqd node registered with lookupd
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Producer {
+    pub broadcast_address: String,
+    pub tcp_port: u16,
+    pub http_port: u16,
+}
+
+impl Producer {
+    /// `host:port` of the node's TCP protocol
+    pub fn tcp_address(&self) -> String {
+        format!("{}:{}", self.broadcast_address, self.tcp_port)
+    }
+
+    /// `host:port` of the node's HTTP API
+    pub fn http_address(&self) -> String {
+        format!("{}:{}", self.broadcast_address, self.http_port)
+    }
+}
+
+/// Change in the producers found by a poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Added(Producer),
+    Removed(Producer),
+}
+
+/// Producers listed in a lookupd `/lookup` or `/nodes` response
+#[derive(Debug, Deserialize)]
+struct ProducersResponse {
+    #[serde(default)]
+    producers: Vec<Producer>,
+}
+
+/// Topics listed in a lookupd `/topics` response
+#[derive(Debug, Deserialize)]
+struct TopicsResponse {
+    #[serde(default)]
+    topics: Vec<String>,
+}
+
+/// Polls lookupd for the nsqd nodes of a topic
+pub struct LookupdDiscovery {
+    client: reqwest::Client,
+    lookupd_addresses: Vec<String>,
+    topic: Option<String>,
+    interval: Duration,
+    producers: BTreeSet<Producer>,
+}
+
+impl LookupdDiscovery {
+    /// Discover the producers of `topic`, or every node when it is `None`,
+    /// through the given lookupd HTTP addresses
+    pub fn new(lookupd_addresses: Vec<String>, topic: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(LOOKUPD_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            lookupd_addresses,
+            topic,
+            interval: DEFAULT_DISCOVERY_INTERVAL,
+            producers: BTreeSet::new(),
+        }
+    }
+
+    /// Poll every `interval` in `watch`
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Check whether any lookupd is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.lookupd_addresses.is_empty()
+    }
+
+    /// Producers found by the last successful poll
+    pub fn producers(&self) -> impl Iterator<Item = &Producer> {
+        self.producers.iter()
+    }
+
+    fn url(address: &str, path: &str) -> String {
+        if address.starts_with("http://") || address.starts_with("https://") {
+            format!("{}{}", address.trim_end_matches('/'), path)
+        } else {
+            format!("http://{}{}", address, path)
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, address: &str, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let url = Self::url(address, path);
+        let response = self.client.get(&url).query(query).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| NsqError::Lookup(format!("{}: {}", url, e)))?;
+        response.json().await.map_err(|e| NsqError::Lookup(format!("{}: {}", url, e)))
+    }
+
+    /// Producers known to any lookupd, failing only when none answered
+    pub async fn lookup(&self) -> Result<BTreeSet<Producer>> {
+        let (path, query) = match &self.topic {
+            Some(topic) => ("/lookup", vec![("topic", topic.as_str())]),
+            None => ("/nodes", Vec::new()),
+        };
+        let responses = futures::future::join_all(
+            self.lookupd_addresses.iter().map(|address| self.get::<ProducersResponse>(address, path, &query))
+        ).await;
+
+        let mut producers = BTreeSet::new();
+        let mut last_error = None;
+        let mut answered = false;
+        for (address, response) in self.lookupd_addresses.iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    answered = true;
+                    producers.extend(response.producers);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to query lookupd {}: {}", address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(producers),
+        }
+    }
+
+    /// Query lookupd and return how the producers changed since the last
+    /// successful poll
+    pub async fn poll(&mut self) -> Result<Vec<DiscoveryEvent>> {
+        let producers = self.lookup().await?;
+        let mut events: Vec<DiscoveryEvent> = self.producers.difference(&producers)
+            .cloned()
+            .map(DiscoveryEvent::Removed)
+            .collect();
+        events.extend(producers.difference(&self.producers).cloned().map(DiscoveryEvent::Added));
+        self.producers = producers;
+        Ok(events)
+    }
+
+    /// Poll until at least one producer is found
+    pub async fn wait_for_producers(&mut self) -> Vec<Producer> {
+        loop {
+            match self.poll().await {
+                Ok(_) if !self.producers.is_empty() => return self.producers.iter().cloned().collect(),
+                Ok(_) => tracing::info!("No nsqd nodes found on lookupd yet, retrying in {:?}", self.interval),
+                Err(e) => tracing::warn!("Failed to discover nsqd nodes, retrying in {:?}: {}", self.interval, e),
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Poll in the background, starting now, sending every change until the
+    /// receiver is dropped
+    pub fn watch(mut self) -> mpsc::UnboundedReceiver<DiscoveryEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                match self.poll().await {
+                    Ok(events) => {
+                        for event in events {
+                            if sender.send(event).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to discover nsqd nodes: {}", e),
+                }
+                if sender.is_closed() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Topics known to any lookupd, sorted, failing only when none answered
+    pub async fn topics(&self) -> Result<Vec<String>> {
+        let responses = futures::future::join_all(
+            self.lookupd_addresses.iter().map(|address| self.get::<TopicsResponse>(address, "/topics", &[]))
+        ).await;
+
+        let mut topics = BTreeSet::new();
+        let mut last_error = None;
+        let mut answered = false;
+        for (address, response) in self.lookupd_addresses.iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    answered = true;
+                    topics.extend(response.topics);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to fetch topics from lookupd {}: {}", address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(topics.into_iter().collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+    use parking_lot::Mutex;
+
+    /// Producers a stub lookupd lists, or None to fail every request
+    type Listed = Arc<Mutex<Option<Vec<Producer>>>>;
+
+    async fn lookup(State(listed): State<Listed>) -> std::result::Result<Json<serde_json::Value>, StatusCode> {
+        let producers = listed.lock().clone().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(Json(serde_json::json!({ "channels": [], "producers": producers })))
+    }
+
+    /// Start a lookupd stand-in serving `/lookup`, returning its address
+    async fn stub_lookupd(listed: Listed) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route("/lookup", get(lookup)).with_state(listed);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address
+    }
+
+    /// Address nothing listens on
+    async fn closed_address() -> String {
+        tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string()
+    }
+
+    fn producer(tcp_port: u16) -> Producer {
+        Producer { broadcast_address: "127.0.0.1".to_string(), tcp_port, http_port: tcp_port + 1 }
+    }
+
+    #[tokio::test]
+    async fn test_poll_reports_added_and_removed_producers() {
+        let listed: Listed = Arc::new(Mutex::new(Some(vec![producer(4150)])));
+        let address = stub_lookupd(listed.clone()).await;
+        let mut discovery = LookupdDiscovery::new(vec![address], Some("t".to_string()));
+
+        assert_eq!(discovery.poll().await.unwrap(), vec![DiscoveryEvent::Added(producer(4150))]);
+        *listed.lock() = Some(vec![producer(4150), producer(4250)]);
+        assert_eq!(discovery.poll().await.unwrap(), vec![DiscoveryEvent::Added(producer(4250))]);
+        assert_eq!(discovery.poll().await.unwrap(), vec![]);
+
+        *listed.lock() = Some(vec![producer(4250)]);
+        assert_eq!(discovery.poll().await.unwrap(), vec![DiscoveryEvent::Removed(producer(4150))]);
+        assert_eq!(discovery.producers().cloned().collect::<Vec<_>>(), vec![producer(4250)]);
+    }
+
+    #[tokio::test]
+    async fn test_lookupd_errors_keep_last_known_producers() {
+        let listed: Listed = Arc::new(Mutex::new(Some(vec![producer(4150)])));
+        let address = stub_lookupd(listed.clone()).await;
+        let mut discovery = LookupdDiscovery::new(vec![address.clone()], Some("t".to_string()));
+        discovery.poll().await.unwrap();
+
+        *listed.lock() = None;
+        assert!(discovery.poll().await.is_err());
+        assert_eq!(discovery.producers().cloned().collect::<Vec<_>>(), vec![producer(4150)]);
+
+        let mut unreachable = LookupdDiscovery::new(vec![closed_address().await], Some("t".to_string()));
+        assert!(unreachable.poll().await.is_err());
+        assert_eq!(unreachable.producers().count(), 0);
+
+        // One lookupd answering is enough
+        *listed.lock() = Some(vec![producer(4150)]);
+        let mut partial = LookupdDiscovery::new(vec![closed_address().await, address], Some("t".to_string()));
+        assert_eq!(partial.poll().await.unwrap(), vec![DiscoveryEvent::Added(producer(4150))]);
+        assert_eq!(discovery.poll().await.unwrap(), vec![]);
+    }
+}
@@ -1,25 +1,89 @@
 //! Disk queue implementation for message persistence
 
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 // use memmap2::MmapMut;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use crate::errors::{NsqError, Result};
 use crate::validation::validate_message_size;
 
+/// Messages written between fsyncs unless set with `with_sync_every`
+pub const DEFAULT_SYNC_EVERY: u64 = 2500;
+/// Bytes buffered before writes reach the file
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes read ahead of the message being read
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Fully consumed bytes at the end of the file being written before
+/// compaction starts a new one
+const COMPACT_MIN_BYTES: u64 = 1024 * 1024;
+
+/// Space a disk queue takes on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// Segment files holding the queue
+    pub files: u64,
+    /// Bytes of all segment files, including consumed messages not yet
+    /// compacted away
+    pub bytes: u64,
+    /// Bytes of unread messages, including their length prefixes
+    pub unread_bytes: u64,
+}
+
+impl std::ops::Add for DiskUsage {
+    type Output = Self;
+    
+    fn add(self, other: Self) -> Self {
+        Self {
+            files: self.files + other.files,
+            bytes: self.bytes + other.bytes,
+            unread_bytes: self.unread_bytes + other.unread_bytes,
+        }
+    }
+}
+
+/// Batch of messages written by `put_batch`, which `rollback` can remove
+/// again as long as nothing was written after it or read from it
+#[derive(Debug)]
+pub struct DiskMark {
+    /// Segment file the batch was written to
+    file_num: u64,
+    /// Where the batch starts in the file
+    pos: u64,
+    /// Messages in the batch
+    count: u64,
+    /// Bytes appended to the queue up to the end of the batch
+    appended: u64,
+}
+
+impl DiskMark {
+    /// Messages in the batch
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
 /// Disk queue for persisting messages
+///
+/// Writes are buffered and fsynced together once `sync_every` messages were
+/// written or `sync_timeout` passed since the last sync, so a burst of puts
+/// shares a single fsync. Messages still in the write buffer are flushed
+/// whenever a reader catches up with them.
 #[derive(Debug)]
 pub struct DiskQueue {
     path: PathBuf,
     max_file_size: usize,
     max_msg_size: usize,
-    _sync_timeout: std::time::Duration,
+    sync_timeout: Duration,
+    sync_every: u64,
     
     // Current file handles
-    read_file: Arc<RwLock<Option<File>>>,
-    write_file: Arc<RwLock<Option<File>>>,
+    read_file: Arc<RwLock<Option<BufReader<File>>>>,
+    write_file: Arc<RwLock<Option<BufWriter<File>>>>,
     
     // File positions
     read_pos: Arc<RwLock<u64>>,
@@ -32,6 +96,14 @@ pub struct DiskQueue {
     // Queue metadata
     depth: Arc<RwLock<u64>>,
     sync_count: Arc<RwLock<u64>>,
+    
+    // Messages written since the last sync, and when it happened
+    unsynced: Arc<RwLock<u64>>,
+    last_sync: Arc<RwLock<Instant>>,
+    
+    // Bytes ever appended since the queue was opened, which rollbacks leave
+    // as they are so a mark is never matched twice
+    appended: Arc<RwLock<u64>>,
 }
 
 impl DiskQueue {
@@ -40,19 +112,20 @@ impl DiskQueue {
         path: P,
         max_file_size: usize,
         max_msg_size: usize,
-        sync_timeout: std::time::Duration,
+        sync_timeout: Duration,
     ) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
         
         // Create directory if it doesn't exist
         std::fs::create_dir_all(&path)
-            .map_err(|e| NsqError::Io(e))?;
+            .map_err(NsqError::Io)?;
         
         let queue = Self {
             path,
             max_file_size,
             max_msg_size,
-            _sync_timeout: sync_timeout,
+            sync_timeout,
+            sync_every: DEFAULT_SYNC_EVERY,
             read_file: Arc::new(RwLock::new(None)),
             write_file: Arc::new(RwLock::new(None)),
             read_pos: Arc::new(RwLock::new(0)),
@@ -61,6 +134,9 @@ impl DiskQueue {
             write_file_num: Arc::new(RwLock::new(0)),
             depth: Arc::new(RwLock::new(0)),
             sync_count: Arc::new(RwLock::new(0)),
+            unsynced: Arc::new(RwLock::new(0)),
+            last_sync: Arc::new(RwLock::new(Instant::now())),
+            appended: Arc::new(RwLock::new(0)),
         };
         
         // Initialize queue from existing files
@@ -69,20 +145,28 @@ impl DiskQueue {
         Ok(queue)
     }
     
+    /// Sync after this many messages were written (0 = only on timeout)
+    pub fn with_sync_every(mut self, sync_every: u64) -> Self {
+        self.sync_every = sync_every;
+        self
+    }
+    
     /// Initialize queue from existing files
     fn initialize(&self) -> Result<()> {
-        // Find the highest numbered file
+        // Find the lowest and highest numbered files
+        let mut min_file_num = u64::MAX;
         let mut max_file_num = 0u64;
         
         if let Ok(entries) = std::fs::read_dir(&self.path) {
             for entry in entries {
-                let entry = entry.map_err(|e| NsqError::Io(e))?;
+                let entry = entry.map_err(NsqError::Io)?;
                 let file_name = entry.file_name();
                 let file_name = file_name.to_string_lossy();
                 
                 if file_name.starts_with("nsq.") && file_name.ends_with(".dat") {
                     if let Some(num_str) = file_name.strip_prefix("nsq.").and_then(|s| s.strip_suffix(".dat")) {
                         if let Ok(num) = num_str.parse::<u64>() {
+                            min_file_num = min_file_num.min(num);
                             max_file_num = max_file_num.max(num);
                         }
                     }
@@ -91,7 +175,7 @@ impl DiskQueue {
         }
         
         *self.write_file_num.write() = max_file_num;
-        *self.read_file_num.write() = max_file_num;
+        *self.read_file_num.write() = min_file_num.min(max_file_num);
         
         // Open the write file
         self.open_write_file()?;
@@ -99,46 +183,108 @@ impl DiskQueue {
         // Calculate current depth
         self.calculate_depth()?;
         
+        // Resume from the last persisted read position
+        if let Some((file_num, pos)) = self.load_metadata() {
+            if file_num == *self.read_file_num.read() {
+                self.open_read_file()?;
+                if let Some(file) = self.read_file.write().as_mut() {
+                    let consumed = Self::skip_messages(file, pos)?;
+                    *self.read_pos.write() = pos;
+                    let mut depth = self.depth.write();
+                    *depth = depth.saturating_sub(consumed);
+                }
+            }
+        }
+        
         Ok(())
     }
     
+    /// Path of the read position metadata file
+    fn metadata_path(&self) -> PathBuf {
+        self.path.join("nsq.meta")
+    }
+    
+    /// Load the persisted read file number and position
+    fn load_metadata(&self) -> Option<(u64, u64)> {
+        let contents = std::fs::read_to_string(self.metadata_path()).ok()?;
+        let mut parts = contents.split_whitespace();
+        let file_num = parts.next()?.parse().ok()?;
+        let pos = parts.next()?.parse().ok()?;
+        Some((file_num, pos))
+    }
+    
+    /// Persist the read file number and position
+    fn persist_metadata(&self) -> Result<()> {
+        let contents = format!("{} {}\n", *self.read_file_num.read(), *self.read_pos.read());
+        let tmp_path = self.path.join("nsq.meta.tmp");
+        std::fs::write(&tmp_path, contents).map_err(NsqError::Io)?;
+        std::fs::rename(&tmp_path, self.metadata_path()).map_err(NsqError::Io)?;
+        Ok(())
+    }
+    
+    /// Advance a file past the messages before `pos`, returning how many were skipped
+    fn skip_messages<F: Read + Seek>(file: &mut F, pos: u64) -> Result<u64> {
+        let mut skipped = 0u64;
+        let mut current = 0u64;
+        let mut size_buf = [0u8; 4];
+        
+        while current < pos && file.read_exact(&mut size_buf).is_ok() {
+            let size = u32::from_be_bytes(size_buf) as u64;
+            current = file.seek(SeekFrom::Current(size as i64)).map_err(NsqError::Io)?;
+            skipped += 1;
+        }
+        
+        file.seek(SeekFrom::Start(pos)).map_err(NsqError::Io)?;
+        Ok(skipped)
+    }
+    
     /// Open the write file
     fn open_write_file(&self) -> Result<()> {
         let file_num = *self.write_file_num.read();
-        let file_path = self.path.join(format!("nsq.{}.dat", file_num));
+        let file_path = self.segment_path(file_num);
         
         let file = OpenOptions::new()
             .create(true)
             .append(true)
             .open(&file_path)
-            .map_err(|e| NsqError::Io(e))?;
+            .map_err(NsqError::Io)?;
         
         // Get current file size
-        let metadata = file.metadata().map_err(|e| NsqError::Io(e))?;
+        let metadata = file.metadata().map_err(NsqError::Io)?;
         *self.write_pos.write() = metadata.len();
         
-        *self.write_file.write() = Some(file);
+        *self.write_file.write() = Some(BufWriter::with_capacity(WRITE_BUFFER_SIZE, file));
         
         Ok(())
     }
     
+    /// Path of the segment file with the given number
+    fn segment_path(&self, file_num: u64) -> PathBuf {
+        self.path.join(format!("nsq.{}.dat", file_num))
+    }
+    
     /// Open the read file
     fn open_read_file(&self) -> Result<()> {
-        let file_num = *self.read_file_num.read();
-        let file_path = self.path.join(format!("nsq.{}.dat", file_num));
+        if let Some(reader) = self.open_reader(*self.read_file_num.read())? {
+            *self.read_file.write() = Some(reader);
+        }
+        Ok(())
+    }
+    
+    /// Open a segment file for reading, if it exists
+    fn open_reader(&self, file_num: u64) -> Result<Option<BufReader<File>>> {
+        let file_path = self.segment_path(file_num);
         
         if !file_path.exists() {
-            return Ok(());
+            return Ok(None);
         }
         
         let file = OpenOptions::new()
             .read(true)
             .open(&file_path)
-            .map_err(|e| NsqError::Io(e))?;
+            .map_err(NsqError::Io)?;
         
-        *self.read_file.write() = Some(file);
-        
-        Ok(())
+        Ok(Some(BufReader::with_capacity(READ_BUFFER_SIZE, file)))
     }
     
     /// Calculate current queue depth
@@ -148,7 +294,7 @@ impl DiskQueue {
         // Count messages in all files
         if let Ok(entries) = std::fs::read_dir(&self.path) {
             for entry in entries {
-                let entry = entry.map_err(|e| NsqError::Io(e))?;
+                let entry = entry.map_err(NsqError::Io)?;
                 let file_name = entry.file_name();
                 let file_name = file_name.to_string_lossy();
                 
@@ -178,7 +324,7 @@ impl DiskQueue {
                     _pos += 4;
                     
                     // Skip message data
-                    if let Err(_) = file.seek(SeekFrom::Current(size as i64)) {
+                    if file.seek(SeekFrom::Current(size as i64)).is_err() {
                         break;
                     }
                     _pos += size;
@@ -193,33 +339,131 @@ impl DiskQueue {
     
     /// Put a message into the queue
     pub fn put(&self, data: &[u8]) -> Result<()> {
-        validate_message_size(data, self.max_msg_size)?;
-        
-        let mut write_file = self.write_file.write();
-        let file = write_file.as_mut()
-            .ok_or_else(|| NsqError::Queue("Write file not open".to_string()))?;
+        self.put_batch(&[data]).map(|_| ())
+    }
+    
+    /// Put messages into the queue as a whole: they are written to one file
+    /// and a failed write removes those already written
+    pub fn put_batch<T: AsRef<[u8]>>(&self, messages: &[T]) -> Result<DiskMark> {
+        for data in messages {
+            validate_message_size(data.as_ref(), self.max_msg_size)?;
+        }
+        let size: u64 = messages.iter().map(|data| 4 + data.as_ref().len() as u64).sum();
         
         // Check if we need to rotate the file
         let current_pos = *self.write_pos.read();
-        if current_pos + 4 + data.len() as u64 > self.max_file_size as u64 {
+        if current_pos > 0 && current_pos + size > self.max_file_size as u64 {
             self.rotate_write_file()?;
         }
         
-        // Write message size and data
-        let size = data.len() as u32;
-        file.write_all(&size.to_be_bytes())
-            .map_err(|e| NsqError::Io(e))?;
-        file.write_all(data)
-            .map_err(|e| NsqError::Io(e))?;
-        file.flush().map_err(|e| NsqError::Io(e))?;
+        let mut write_file = self.write_file.write();
+        let file = write_file.as_mut()
+            .ok_or_else(|| NsqError::Queue("Write file not open".to_string()))?;
+        let pos = *self.write_pos.read();
+        let file_num = *self.write_file_num.read();
+        
+        // Flush earlier puts first so a failed write can only take this batch
+        // with it
+        file.flush().map_err(NsqError::Io)?;
+        
+        // Write message sizes and data
+        let written = messages.iter().try_for_each(|data| {
+            let data = data.as_ref();
+            file.write_all(&(data.len() as u32).to_be_bytes())?;
+            file.write_all(data)
+        });
+        if let Err(e) = written {
+            // Drop the part of the batch still buffered and cut off what
+            // reached the file
+            if let Some((file, _)) = write_file.take().map(BufWriter::into_parts) {
+                file.set_len(pos).map_err(NsqError::Io)?;
+                *write_file = Some(BufWriter::with_capacity(WRITE_BUFFER_SIZE, file));
+            }
+            return Err(NsqError::Io(e));
+        }
         
         // Update positions
-        *self.write_pos.write() += 4 + data.len() as u64;
-        *self.depth.write() += 1;
+        let count = messages.len() as u64;
+        *self.write_pos.write() += size;
+        *self.depth.write() += count;
+        let appended = {
+            let mut appended = self.appended.write();
+            *appended += size;
+            *appended
+        };
         
+        let unsynced = {
+            let mut unsynced = self.unsynced.write();
+            *unsynced += count;
+            *unsynced
+        };
+        let mark = DiskMark { file_num, pos, count, appended };
+        if (self.sync_every > 0 && unsynced >= self.sync_every) || self.last_sync.read().elapsed() >= self.sync_timeout {
+            drop(write_file);
+            if let Err(e) = self.sync() {
+                // Take the batch back so the caller can retry it without
+                // writing it twice
+                match self.rollback(mark) {
+                    Ok(true) => {}
+                    Ok(false) => tracing::warn!("Failed to roll back batch after failed sync: later writes or reads followed it"),
+                    Err(rollback_err) => tracing::warn!("Failed to roll back batch after failed sync: {}", rollback_err),
+                }
+                return Err(e);
+            }
+        }
+        
+        Ok(mark)
+    }
+    
+    /// Remove a batch written by `put_batch`, returning whether it was
+    /// removed. A batch that later writes follow or that a reader already
+    /// started on is kept.
+    pub fn rollback(&self, mark: DiskMark) -> Result<bool> {
+        let mut read_file = self.read_file.write();
+        let mut write_file = self.write_file.write();
+        let read_past = (*self.read_file_num.read(), *self.read_pos.read()) > (mark.file_num, mark.pos);
+        if *self.appended.read() != mark.appended || *self.write_file_num.read() != mark.file_num || read_past {
+            return Ok(false);
+        }
+        
+        if let Some(file) = write_file.as_mut() {
+            file.flush().map_err(NsqError::Io)?;
+            file.get_ref().set_len(mark.pos).map_err(NsqError::Io)?;
+        }
+        // Forget anything the reader buffered from the batch
+        if let Some(file) = read_file.as_mut() {
+            file.seek(SeekFrom::Start(*self.read_pos.read())).map_err(NsqError::Io)?;
+        }
+        
+        *self.write_pos.write() = mark.pos;
+        let mut depth = self.depth.write();
+        *depth = depth.saturating_sub(mark.count);
+        Ok(true)
+    }
+    
+    /// Sync if messages were written and `sync_timeout` has passed since the
+    /// last sync; meant to be called periodically so a quiet queue does not
+    /// leave its last writes unsynced
+    pub fn sync_if_due(&self) -> Result<()> {
+        if *self.unsynced.read() > 0 && self.last_sync.read().elapsed() >= self.sync_timeout {
+            self.sync()?;
+        }
         Ok(())
     }
     
+    /// Hand buffered writes to the file so readers can see them, returning
+    /// whether there were any
+    fn flush_writes(&self) -> Result<bool> {
+        let mut write_file = self.write_file.write();
+        match write_file.as_mut() {
+            Some(file) if !file.buffer().is_empty() => {
+                file.flush().map_err(NsqError::Io)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    
     /// Get a message from the queue
     pub fn get(&self) -> Result<Option<Vec<u8>>> {
         // Open read file if needed
@@ -228,8 +472,10 @@ impl DiskQueue {
         }
         
         let mut read_file = self.read_file.write();
-        let file = read_file.as_mut()
-            .ok_or_else(|| NsqError::Queue("Read file not open".to_string()))?;
+        let Some(file) = read_file.as_mut() else {
+            return Ok(None);
+        };
+        let read_pos = *self.read_pos.read();
         
         // Read message size
         let mut size_buf = [0u8; 4];
@@ -237,20 +483,35 @@ impl DiskQueue {
             Ok(_) => {
                 let size = u32::from_be_bytes(size_buf) as usize;
                 
-                // Read message data
+                // Read message data; a partially written message is retried
+                // once the rest is flushed
                 let mut data = vec![0u8; size];
-                file.read_exact(&mut data)
-                    .map_err(|e| NsqError::Io(e))?;
+                if file.read_exact(&mut data).is_err() {
+                    file.seek(SeekFrom::Start(read_pos)).map_err(NsqError::Io)?;
+                    drop(read_file);
+                    return if self.flush_writes()? { self.get() } else { Ok(None) };
+                }
                 
                 // Update positions
                 *self.read_pos.write() += 4 + size as u64;
-                *self.depth.write() = (*self.depth.read()).saturating_sub(1);
+                let mut depth = self.depth.write();
+                *depth = depth.saturating_sub(1);
                 
                 Ok(Some(data))
             }
-            Err(_) => {
-                // End of file, try next file
+            Err(_) if *self.read_file_num.read() < *self.write_file_num.read() => {
+                // End of a finished file, continue with the next one
+                drop(read_file);
                 self.rotate_read_file()?;
+                self.get()
+            }
+            Err(_) => {
+                // Caught up with the file; the writer may still be buffering
+                file.seek(SeekFrom::Start(read_pos)).map_err(NsqError::Io)?;
+                drop(read_file);
+                if self.flush_writes()? {
+                    return self.get();
+                }
                 Ok(None)
             }
         }
@@ -258,8 +519,11 @@ impl DiskQueue {
     
     /// Rotate to the next write file
     fn rotate_write_file(&self) -> Result<()> {
-        // Close current write file
-        *self.write_file.write() = None;
+        // Sync and close current write file
+        if let Some(mut file) = self.write_file.write().take() {
+            file.flush().map_err(NsqError::Io)?;
+            file.get_ref().sync_data().map_err(NsqError::Io)?;
+        }
         
         // Increment file number
         *self.write_file_num.write() += 1;
@@ -275,8 +539,18 @@ impl DiskQueue {
     
     /// Rotate to the next read file
     fn rotate_read_file(&self) -> Result<()> {
-        // Close current read file
-        *self.read_file.write() = None;
+        let mut read_file = self.read_file.write();
+        self.advance_read_file(&mut read_file)
+    }
+    
+    /// Remove the fully consumed read file and open the next one
+    fn advance_read_file(&self, read_file: &mut Option<BufReader<File>>) -> Result<()> {
+        // Close and remove the fully consumed read file
+        *read_file = None;
+        let finished_path = self.segment_path(*self.read_file_num.read());
+        if let Err(e) = std::fs::remove_file(&finished_path) {
+            tracing::warn!("Failed to remove consumed file {:?}: {}", finished_path, e);
+        }
         
         // Increment file number
         *self.read_file_num.write() += 1;
@@ -285,9 +559,220 @@ impl DiskQueue {
         *self.read_pos.write() = 0;
         
         // Open new read file
-        self.open_read_file()?;
+        *read_file = self.open_reader(*self.read_file_num.read())?;
         
-        Ok(())
+        self.persist_metadata()
+    }
+    
+    /// Discard every queued message and start a fresh file, returning how
+    /// many messages were discarded
+    pub fn empty(&self) -> Result<u64> {
+        let mut read_file = self.read_file.write();
+        let mut write_file = self.write_file.write();
+        let emptied = std::mem::take(&mut *self.depth.write());
+        self.restart_segments(&mut read_file, &mut write_file)?;
+        Ok(emptied)
+    }
+    
+    /// Remove fully consumed segment files, returning how many bytes were
+    /// freed: finished files the reader reached the end of, and the file
+    /// being written once everything in it was read and it has grown past
+    /// `COMPACT_MIN_BYTES`
+    pub fn compact(&self) -> Result<u64> {
+        let mut freed = 0;
+        let mut read_file = self.read_file.write();
+        while *self.read_file_num.read() < *self.write_file_num.read() {
+            let size = std::fs::metadata(self.segment_path(*self.read_file_num.read())).map_or(0, |metadata| metadata.len());
+            if *self.read_pos.read() < size {
+                break;
+            }
+            self.advance_read_file(&mut read_file)?;
+            freed += size;
+        }
+        
+        // Puts update the depth while holding the write file, so it cannot
+        // change underneath
+        let mut write_file = self.write_file.write();
+        let write_pos = *self.write_pos.read();
+        let consumed = *self.depth.read() == 0
+            && *self.read_file_num.read() == *self.write_file_num.read()
+            && *self.read_pos.read() >= write_pos;
+        if consumed && write_pos >= COMPACT_MIN_BYTES {
+            self.restart_segments(&mut read_file, &mut write_file)?;
+            freed += write_pos;
+        }
+        Ok(freed)
+    }
+    
+    /// Remove every segment file and start writing a new one
+    fn restart_segments(&self, read_file: &mut Option<BufReader<File>>, write_file: &mut Option<BufWriter<File>>) -> Result<()> {
+        *read_file = None;
+        *write_file = None;
+        
+        let first_file_num = *self.read_file_num.read();
+        let last_file_num = *self.write_file_num.read();
+        for file_num in first_file_num..=last_file_num {
+            let file_path = self.segment_path(file_num);
+            match std::fs::remove_file(&file_path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to remove emptied file {:?}: {}", file_path, e),
+            }
+        }
+        
+        // Continue numbering after the removed files so none is reused
+        *self.read_file_num.write() = last_file_num + 1;
+        *self.write_file_num.write() = last_file_num + 1;
+        *self.read_pos.write() = 0;
+        *self.write_pos.write() = 0;
+        
+        let file_path = self.segment_path(last_file_num + 1);
+        *write_file = Some(BufWriter::with_capacity(WRITE_BUFFER_SIZE, OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file_path)
+            .map_err(NsqError::Io)?));
+        
+        *self.unsynced.write() = 0;
+        self.persist_metadata()
+    }
+    
+    /// Discard messages from the head of the queue for as long as `prune`
+    /// returns true for the next one, returning how many were discarded
+    pub fn prune_while<F: FnMut(&[u8]) -> bool>(&self, mut prune: F) -> Result<u64> {
+        let mut pruned = 0u64;
+        loop {
+            if self.read_file.read().is_none() {
+                self.open_read_file()?;
+            }
+            
+            // Each message is read and discarded under the read lock, so a
+            // concurrent get() never sees a message that is being pruned
+            let mut read_file = self.read_file.write();
+            let Some(file) = read_file.as_mut() else {
+                break;
+            };
+            let read_pos = *self.read_pos.read();
+            
+            let mut size_buf = [0u8; 4];
+            if file.read_exact(&mut size_buf).is_err() {
+                file.seek(SeekFrom::Start(read_pos)).map_err(NsqError::Io)?;
+                drop(read_file);
+                if *self.read_file_num.read() < *self.write_file_num.read() {
+                    self.rotate_read_file()?;
+                    continue;
+                }
+                if self.flush_writes()? {
+                    continue;
+                }
+                break;
+            }
+            
+            let size = u32::from_be_bytes(size_buf) as usize;
+            let mut data = vec![0u8; size];
+            if file.read_exact(&mut data).is_err() || !prune(&data) {
+                file.seek(SeekFrom::Start(read_pos)).map_err(NsqError::Io)?;
+                break;
+            }
+            
+            *self.read_pos.write() += 4 + size as u64;
+            let mut depth = self.depth.write();
+            *depth = depth.saturating_sub(1);
+            pruned += 1;
+        }
+        
+        if pruned > 0 {
+            self.persist_metadata()?;
+        }
+        Ok(pruned)
+    }
+    
+    /// Read up to `count` messages from the head of the queue without
+    /// consuming them
+    pub fn peek(&self, count: usize) -> Result<Vec<Vec<u8>>> {
+        // Holding the read handle keeps get() from moving the head meanwhile
+        let _read_file = self.read_file.write();
+        self.flush_writes()?;
+        let last_file_num = *self.write_file_num.read();
+        let mut file_num = *self.read_file_num.read();
+        let mut pos = *self.read_pos.read();
+        let mut messages = Vec::new();
+        
+        while messages.len() < count && file_num <= last_file_num {
+            let file_path = self.segment_path(file_num);
+            let mut file = match File::open(&file_path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    file_num += 1;
+                    pos = 0;
+                    continue;
+                }
+                Err(e) => return Err(NsqError::Io(e)),
+            };
+            file.seek(SeekFrom::Start(pos)).map_err(NsqError::Io)?;
+            
+            while messages.len() < count {
+                let mut size_buf = [0u8; 4];
+                if file.read_exact(&mut size_buf).is_err() {
+                    break;
+                }
+                let mut data = vec![0u8; u32::from_be_bytes(size_buf) as usize];
+                if file.read_exact(&mut data).is_err() {
+                    break;
+                }
+                messages.push(data);
+            }
+            file_num += 1;
+            pos = 0;
+        }
+        
+        Ok(messages)
+    }
+    
+    /// Discard up to `count` messages from the head of the queue, returning
+    /// how many were discarded; used with `peek` to consume a message only
+    /// once it was handled
+    pub fn skip(&self, count: u64) -> Result<u64> {
+        let mut seen = 0;
+        self.prune_while(|_| {
+            seen += 1;
+            seen <= count
+        })
+    }
+    
+    /// Reader of the messages already consumed from the segment file being
+    /// read, which stay on disk until the reader moves past the file
+    pub fn replay(&self) -> Result<DiskReplay> {
+        // Holding the read handle keeps get() from moving the head meanwhile
+        let _read_file = self.read_file.write();
+        let end = *self.read_pos.read();
+        let reader = match end {
+            0 => None,
+            _ => self.open_reader(*self.read_file_num.read())?,
+        };
+        Ok(DiskReplay { reader, remaining: end })
+    }
+    
+    /// Bytes of unread messages on disk, including their length prefixes
+    pub fn unread_bytes(&self) -> u64 {
+        self.usage().unread_bytes
+    }
+    
+    /// Space the queue takes on disk, counting writes still buffered
+    pub fn usage(&self) -> DiskUsage {
+        let first_file_num = *self.read_file_num.read();
+        let last_file_num = *self.write_file_num.read();
+        let sizes: Vec<u64> = (first_file_num..=last_file_num)
+            .filter_map(|file_num| std::fs::metadata(self.segment_path(file_num)).ok())
+            .map(|metadata| metadata.len())
+            .collect();
+        let buffered = self.write_file.read().as_ref().map_or(0, |file| file.buffer().len() as u64);
+        let bytes = sizes.iter().sum::<u64>() + buffered;
+        DiskUsage {
+            files: sizes.len() as u64,
+            bytes,
+            unread_bytes: bytes.saturating_sub(*self.read_pos.read()),
+        }
     }
     
     /// Get current queue depth
@@ -295,16 +780,35 @@ impl DiskQueue {
         *self.depth.read()
     }
     
-    /// Sync the queue to disk
+    /// Sync the queue and its read position to disk
     pub fn sync(&self) -> Result<()> {
-        if let Some(ref file) = *self.write_file.read() {
-            file.sync_all().map_err(|e| NsqError::Io(e))?;
+        {
+            // Puts count themselves as unsynced while holding the write file,
+            // so none can be cleared here without being synced
+            let mut write_file = self.write_file.write();
+            if let Some(file) = write_file.as_mut() {
+                file.flush().map_err(NsqError::Io)?;
+                file.get_ref().sync_all().map_err(NsqError::Io)?;
+            }
+            *self.unsynced.write() = 0;
         }
+        self.persist_metadata()?;
         
+        *self.last_sync.write() = Instant::now();
         *self.sync_count.write() += 1;
         Ok(())
     }
     
+    /// Sync if messages were written since the last sync, returning whether
+    /// it did
+    pub fn sync_pending(&self) -> Result<bool> {
+        if *self.unsynced.read() == 0 {
+            return Ok(false);
+        }
+        self.sync()?;
+        Ok(true)
+    }
+    
     /// Get sync count
     pub fn sync_count(&self) -> u64 {
         *self.sync_count.read()
@@ -316,7 +820,7 @@ impl DiskQueue {
         
         if let Ok(entries) = std::fs::read_dir(&self.path) {
             for entry in entries {
-                let entry = entry.map_err(|e| NsqError::Io(e))?;
+                let entry = entry.map_err(NsqError::Io)?;
                 let file_name = entry.file_name();
                 let file_name = file_name.to_string_lossy();
                 
@@ -345,3 +849,164 @@ impl DiskQueue {
         Ok(())
     }
 }
+
+/// Messages a disk queue already consumed, read oldest first without
+/// affecting the queue. The file is held open, so it can still be read
+/// after the queue removes it.
+#[derive(Debug)]
+pub struct DiskReplay {
+    reader: Option<BufReader<File>>,
+    /// Bytes left to read, including length prefixes
+    remaining: u64,
+}
+
+impl DiskReplay {
+    /// Read the next consumed message, or None once all were read
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(reader) = self.reader.as_mut().filter(|_| self.remaining > 0) else {
+            return Ok(None);
+        };
+        let mut size_buf = [0u8; 4];
+        reader.read_exact(&mut size_buf).map_err(NsqError::Io)?;
+        let mut data = vec![0u8; u32::from_be_bytes(size_buf) as usize];
+        reader.read_exact(&mut data).map_err(NsqError::Io)?;
+        self.remaining = self.remaining.saturating_sub(4 + data.len() as u64);
+        Ok(Some(data))
+    }
+    
+    /// Bytes of messages left to replay, including their length prefixes
+    pub fn remaining_bytes(&self) -> u64 {
+        self.remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("nsq-disk-queue-{}", Uuid::new_v4()))
+    }
+    
+    fn queue(dir: &Path, max_file_size: usize) -> DiskQueue {
+        DiskQueue::new(dir, max_file_size, 1024, Duration::from_secs(60)).unwrap()
+    }
+    
+    fn message(i: usize) -> Vec<u8> {
+        format!("message-{:012}", i).into_bytes()
+    }
+    
+    fn segment_files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".dat"))
+            .collect();
+        files.sort();
+        files
+    }
+    
+    #[test]
+    fn test_restart_resumes_from_persisted_read_position() {
+        let dir = temp_dir();
+        {
+            let queue = queue(&dir, 1024 * 1024);
+            (0..5).for_each(|i| queue.put(&message(i)).unwrap());
+            assert_eq!(queue.get().unwrap(), Some(message(0)));
+            assert_eq!(queue.get().unwrap(), Some(message(1)));
+            queue.sync().unwrap();
+        }
+        
+        let queue = queue(&dir, 1024 * 1024);
+        assert_eq!(queue.depth(), 3);
+        assert_eq!(std::iter::from_fn(|| queue.get().unwrap()).collect::<Vec<_>>(), (2..5).map(message).collect::<Vec<_>>());
+        assert_eq!(queue.depth(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_rollover_deletes_consumed_files_and_survives_restart() {
+        let dir = temp_dir();
+        // Two messages of 4 + 20 bytes fit in each file
+        let max_file_size = 2 * (4 + message(0).len());
+        {
+            let queue = queue(&dir, max_file_size);
+            (0..6).for_each(|i| queue.put(&message(i)).unwrap());
+            assert_eq!(segment_files(&dir), vec!["nsq.0.dat", "nsq.1.dat", "nsq.2.dat"]);
+            assert_eq!(queue.depth(), 6);
+            
+            assert_eq!(queue.get().unwrap(), Some(message(0)));
+            assert_eq!(queue.get().unwrap(), Some(message(1)));
+            assert_eq!(queue.get().unwrap(), Some(message(2)));
+            assert_eq!(segment_files(&dir), vec!["nsq.1.dat", "nsq.2.dat"]);
+            queue.sync().unwrap();
+        }
+        
+        let queue = queue(&dir, max_file_size);
+        assert_eq!(queue.depth(), 3);
+        assert_eq!(std::iter::from_fn(|| queue.get().unwrap()).collect::<Vec<_>>(), (3..6).map(message).collect::<Vec<_>>());
+        assert_eq!(segment_files(&dir), vec!["nsq.2.dat"]);
+        
+        queue.put(&message(6)).unwrap();
+        assert_eq!(queue.get().unwrap(), Some(message(6)));
+        assert_eq!(segment_files(&dir), vec!["nsq.3.dat"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_rollback_removes_a_batch_nothing_followed() {
+        let dir = temp_dir();
+        let queue = queue(&dir, 1024 * 1024);
+        queue.put(&message(0)).unwrap();
+        let mark = queue.put_batch(&[message(1), message(2)]).unwrap();
+        assert_eq!(queue.depth(), 3);
+        
+        assert!(queue.rollback(mark).unwrap());
+        assert_eq!(queue.depth(), 1);
+        queue.put(&message(3)).unwrap();
+        assert_eq!(std::iter::from_fn(|| queue.get().unwrap()).collect::<Vec<_>>(), vec![message(0), message(3)]);
+        
+        // Nor does a reopened queue find the removed messages
+        queue.sync().unwrap();
+        drop(queue);
+        assert_eq!(self::queue(&dir, 1024 * 1024).depth(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_rollback_keeps_a_batch_written_over_or_read() {
+        let dir = temp_dir();
+        let queue = queue(&dir, 1024 * 1024);
+        let mark = queue.put_batch(&[message(0)]).unwrap();
+        queue.put(&message(1)).unwrap();
+        assert!(!queue.rollback(mark).unwrap());
+        assert_eq!(queue.depth(), 2);
+        
+        let mark = queue.put_batch(&[message(2), message(3)]).unwrap();
+        (0..3).for_each(|_| { queue.get().unwrap(); });
+        assert!(!queue.rollback(mark).unwrap());
+        assert_eq!(queue.get().unwrap(), Some(message(3)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_peeked_message_stays_until_skipped() {
+        let dir = temp_dir();
+        {
+            let queue = queue(&dir, 1024 * 1024);
+            (0..3).for_each(|i| queue.put(&message(i)).unwrap());
+            assert_eq!(queue.peek(1).unwrap(), vec![message(0)]);
+            assert_eq!(queue.peek(1).unwrap(), vec![message(0)]);
+            assert_eq!(queue.skip(1).unwrap(), 1);
+            assert_eq!(queue.peek(1).unwrap(), vec![message(1)]);
+            assert_eq!(queue.depth(), 2);
+            queue.sync().unwrap();
+        }
+    
+        let queue = queue(&dir, 1024 * 1024);
+        assert_eq!(queue.peek(1).unwrap(), vec![message(1)]);
+        assert_eq!(queue.skip(5).unwrap(), 2);
+        assert_eq!(queue.depth(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 //! Common error types
 
+use nsq_protocol::{ErrorCode, ProtocolError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,6 +11,18 @@ pub enum NsqError {
     #[error("Validation error: {0}")]
     Validation(String),
     
+    #[error(transparent)]
+    InvalidName(#[from] crate::validation::InvalidName),
+    
+    #[error("Bad message: {0}")]
+    BadMessage(String),
+    
+    #[error("Topic {0} does not exist")]
+    TopicNotFound(String),
+    
+    #[error("Channel {0} does not exist")]
+    ChannelNotFound(String),
+    
     #[error("Queue error: {0}")]
     Queue(String),
     
@@ -17,7 +30,13 @@ pub enum NsqError {
     Metrics(String),
     
     #[error("Protocol error: {0}")]
-    Protocol(String),
+    Protocol(#[from] ProtocolError),
+    
+    #[error("Lookup error: {0}")]
+    Lookup(String),
+    
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(String),
     
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -32,9 +51,37 @@ pub enum NsqError {
     Channel(#[from] crossbeam_channel::RecvError),
 }
 
-impl From<nsq_protocol::ProtocolError> for NsqError {
-    fn from(err: nsq_protocol::ProtocolError) -> Self {
-        NsqError::Protocol(err.to_string())
+impl NsqError {
+    /// Protocol error code of a failure caused by the request, or `None`
+    /// when the server itself failed
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            NsqError::Validation(_) => Some(ErrorCode::Invalid),
+            NsqError::InvalidName(e) => Some(e.code()),
+            NsqError::BadMessage(_) => Some(ErrorCode::BadMessage),
+            NsqError::TopicNotFound(_) => Some(ErrorCode::TopicNotFound),
+            NsqError::ChannelNotFound(_) => Some(ErrorCode::ChannelNotFound),
+            NsqError::Protocol(e) => Some(e.code()),
+            NsqError::RateLimited(_) => Some(ErrorCode::RateLimit),
+            _ => None,
+        }
+    }
+    
+    /// Code reported for this error when it fails a command whose own
+    /// failures are reported as `fallback`
+    pub fn code_or(&self, fallback: ErrorCode) -> ErrorCode {
+        self.code().unwrap_or(fallback)
+    }
+    
+    /// Text of the error frame reporting this error: its code followed by
+    /// the error
+    pub fn frame_text(&self, fallback: ErrorCode) -> String {
+        format!("{} {}", self.code_or(fallback), self)
+    }
+    
+    /// HTTP status of a request that failed with this error
+    pub fn http_status(&self) -> u16 {
+        self.code().map_or(500, ErrorCode::http_status)
     }
 }
 
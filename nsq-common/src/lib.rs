@@ -8,6 +8,10 @@ pub mod metrics;
 pub mod disk_queue;
 pub mod validation;
 pub mod errors;
+pub mod supervisor;
+pub mod telemetry;
+pub mod access_log;
+pub mod discovery;
 
 pub use config::*;
 pub use logging::*;
@@ -15,6 +19,10 @@ pub use metrics::*;
 pub use disk_queue::*;
 pub use validation::*;
 pub use errors::*;
+pub use supervisor::*;
+pub use telemetry::*;
+pub use access_log::*;
+pub use discovery::*;
 
 // Re-export nsq-protocol for error conversion
 pub use nsq_protocol;
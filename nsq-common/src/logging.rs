@@ -1,31 +1,69 @@
 //! Logging infrastructure
 
-use tracing::{Level, Subscriber};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry};
 use crate::config::BaseConfig;
-use crate::errors::Result;
+use crate::errors::{NsqError, Result};
+use crate::telemetry::{default_service_name, otlp_layer, OTLP_ENDPOINT_ENV};
+
+/// Handle for swapping the level filter of the installed subscriber
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Per-module overrides kept when the base level changes
+static FILTER_DIRECTIVES: OnceLock<Vec<String>> = OnceLock::new();
 
 /// Initialize logging based on configuration
 pub fn init_logging(config: &BaseConfig) -> Result<()> {
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| {
-            let level = config.log_level.parse::<Level>()
-                .unwrap_or(Level::INFO);
-            EnvFilter::new(&format!("{}", level))
-        });
-    
-    let registry = Registry::default().with(filter);
-    
-    // Use try_init to avoid panicking if a global subscriber was already set
-    let result = match config.log_format.as_str() {
-        "json" => registry
-            .with(fmt::layer().with_target(false))
-            .try_init(),
-        _ => registry
-            .with(fmt::layer().with_target(false))
-            .try_init(),
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => build_filter(&config.log_level, &config.log_filters)?,
+    };
+
+    let (writer, ansi) = match &config.log_file {
+        Some(path) => {
+            let file = RotatingFile::open(path, config.log_max_size, config.log_max_files)
+                .map_err(|e| NsqError::Config(format!("failed to open log file {}: {}", path, e)))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), true),
     };
-    
+    // The filter applies to log output only so traces do not depend on the log level
+    let (filter, handle) = reload::Layer::new(filter);
+    let output = match config.log_format.as_str() {
+        "json" => fmt::layer()
+            .event_format(JsonFormat)
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        _ => fmt::layer()
+            .with_target(false)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+    };
+    let traces = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let service_name = config.otlp_service_name.clone().unwrap_or_else(default_service_name);
+            Some(otlp_layer(endpoint, &service_name, config.otlp_sample_rate)?)
+        }
+        None => None,
+    };
+
+    // Use try_init to avoid panicking if a global subscriber was already set
+    let result = Registry::default().with(output).with(traces).try_init();
+
     // If already set, ignore; otherwise return the error
     if let Err(e) = result {
         if format!("{}", e).contains("already been set") {
@@ -33,14 +71,172 @@ pub fn init_logging(config: &BaseConfig) -> Result<()> {
         } else {
             return Err(crate::errors::NsqError::Config(format!("failed to init logging: {}", e)));
         }
+    } else {
+        let _ = FILTER_HANDLE.set(handle);
+        let _ = FILTER_DIRECTIVES.set(config.log_filters.clone());
     }
-    
+
     Ok(())
 }
 
+/// Build a filter from a base level and per-module overrides such as
+/// `nsqd::channel=debug`
+fn build_filter(level: &str, directives: &[String]) -> Result<EnvFilter> {
+    let level = level.parse::<Level>()
+        .map_err(|_| NsqError::Config(format!("invalid log level: {}", level)))?;
+    let spec = std::iter::once(level.to_string())
+        .chain(directives.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(",");
+    EnvFilter::try_new(&spec)
+        .map_err(|e| NsqError::Config(format!("invalid log filter '{}': {}", spec, e)))
+}
+
+/// Change the log level of the subscriber installed by `init_logging`,
+/// keeping its per-module overrides
+pub fn set_log_level(level: &str) -> Result<()> {
+    let directives = FILTER_DIRECTIVES.get().map(Vec::as_slice).unwrap_or_default();
+    let filter = build_filter(level, directives)?;
+    let handle = FILTER_HANDLE.get()
+        .ok_or_else(|| NsqError::Config("logging is not initialized".to_string()))?;
+    handle.reload(filter)
+        .map_err(|e| NsqError::Config(format!("failed to change log level: {}", e)))
+}
+
+/// Initialize logging for command-line tools: output filtered by `RUST_LOG`,
+/// plus trace export when `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+pub fn init_tool_logging(service_name: &str) -> Result<()> {
+    let output = fmt::layer().with_filter(EnvFilter::from_default_env());
+    let traces = match std::env::var(OTLP_ENDPOINT_ENV) {
+        Ok(endpoint) if !endpoint.is_empty() => Some(otlp_layer(&endpoint, service_name, 1.0)?),
+        _ => None,
+    };
+    Registry::default().with(output).with(traces).try_init()
+        .map_err(|e| NsqError::Config(format!("failed to init logging: {}", e)))
+}
+
 /// Create a subscriber for testing
 pub fn init_test_logging() -> impl Subscriber {
     Registry::default()
         .with(EnvFilter::new("debug"))
         .with(fmt::layer().with_test_writer())
 }
+
+/// Formats each event as a single-line JSON object with `timestamp`,
+/// `level`, `target`, the enclosing `spans` and the event's fields
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: format::Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let metadata = event.metadata();
+        let mut object = Map::new();
+        object.insert("timestamp".to_string(), Value::from(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true)));
+        object.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        object.insert("target".to_string(), Value::from(metadata.target()));
+        if let Some(scope) = ctx.event_scope() {
+            let spans: Vec<Value> = scope.from_root().map(|span| Value::from(span.name())).collect();
+            object.insert("spans".to_string(), Value::Array(spans));
+        }
+
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+        object.extend(fields.0);
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+/// Collects event fields as JSON values
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+}
+
+/// Log file that is rotated once it would grow past `max_size` bytes, keeping
+/// up to `max_files` older logs as `<path>.1` (newest) to `<path>.<max_files>`
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    /// Zero disables rotation
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size, max_files, file, size })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Shift older logs up by one, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            match std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            // Keep logging to the current file if rotation fails
+            if let Err(e) = self.rotate() {
+                eprintln!("failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -37,7 +37,7 @@ impl Metrics {
         *self.counters.entry(name.to_string()).or_insert(0) += value;
         
         if let Some(ref client) = self.statsd_client {
-            let _ = client.count(name, value as f64);
+            client.count(name, value as f64);
         }
     }
     
@@ -46,7 +46,7 @@ impl Metrics {
         self.gauges.insert(name.to_string(), value);
         
         if let Some(ref client) = self.statsd_client {
-            let _ = client.gauge(name, value);
+            client.gauge(name, value);
         }
     }
     
@@ -54,11 +54,11 @@ impl Metrics {
     pub fn histogram(&self, name: &str, value: f64) {
         self.histograms
             .entry(name.to_string())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(value);
         
         if let Some(ref client) = self.statsd_client {
-            let _ = client.time(name, || value as f64);
+            let _ = client.time(name, || value);
         }
     }
     
@@ -144,6 +144,54 @@ impl Metrics {
             histograms,
         }
     }
+    
+    /// Render all metrics in the Prometheus text exposition format, with
+    /// names prefixed by `namespace`; histograms are exposed as summaries
+    pub fn render_prometheus(&self, namespace: &str) -> String {
+        use std::fmt::Write;
+        
+        let snapshot = self.snapshot();
+        let mut output = String::new();
+        
+        let mut counters: Vec<_> = snapshot.counters.iter().collect();
+        counters.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in counters {
+            let name = prometheus_name(namespace, name);
+            let _ = writeln!(output, "# TYPE {}_total counter", name);
+            let _ = writeln!(output, "{}_total {}", name, value);
+        }
+        
+        let mut gauges: Vec<_> = snapshot.gauges.iter().collect();
+        gauges.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in gauges {
+            let name = prometheus_name(namespace, name);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        
+        let mut histograms: Vec<_> = snapshot.histograms.iter().collect();
+        histograms.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, stats) in histograms {
+            let name = prometheus_name(namespace, name);
+            let _ = writeln!(output, "# TYPE {} summary", name);
+            for (quantile, value) in [("0.5", stats.median), ("0.95", stats.p95), ("0.99", stats.p99)] {
+                let _ = writeln!(output, "{}{{quantile=\"{}\"}} {}", name, quantile, value);
+            }
+            let _ = writeln!(output, "{}_sum {}", name, stats.sum);
+            let _ = writeln!(output, "{}_count {}", name, stats.count);
+        }
+        
+        output
+    }
+}
+
+/// Prometheus metric name for a dotted metric name, e.g. `http.requests`
+/// in namespace `nsqlookupd` becomes `nsqlookupd_http_requests`
+fn prometheus_name(namespace: &str, name: &str) -> String {
+    format!("{}_{}", namespace, name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
 }
 
 /// Histogram statistics
@@ -0,0 +1,364 @@
+//! Supervision of long-running background tasks
+//!
+//! Background loops are spawned through a `TaskSupervisor` so a panic or
+//! error is logged, counted in metrics and, depending on the restart policy,
+//! followed by a restart instead of the task silently disappearing.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use crate::errors::Result;
+use crate::metrics::Metrics;
+
+/// Delay before the first restart of a failed task
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the restart delay
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// A task that ran at least this long resets its restart delay
+const HEALTHY_RUN_TIME: Duration = Duration::from_secs(60);
+
+/// When a supervised task is restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Never restart
+    Never,
+    /// Restart after a panic or an error
+    OnFailure,
+    /// Restart whenever the task exits
+    Always,
+}
+
+/// Current state of a supervised task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Completed,
+    Failed,
+    Stopped,
+}
+
+/// Statistics for a supervised task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStats {
+    pub name: String,
+    pub policy: RestartPolicy,
+    pub state: TaskState,
+    pub restarts: u64,
+    pub panics: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// How a single run of a task ended
+enum TaskExit {
+    Completed,
+    Error(String),
+    Panic(String),
+}
+
+/// Owns named background tasks and restarts them according to their policy
+#[derive(Clone)]
+pub struct TaskSupervisor {
+    metrics: Metrics,
+    stats: Arc<RwLock<HashMap<String, TaskStats>>>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    stopping: Arc<AtomicBool>,
+    /// Signalled when shutdown begins
+    stop: Arc<watch::Sender<bool>>,
+}
+
+impl TaskSupervisor {
+    /// Create a new supervisor
+    pub fn new(metrics: Metrics) -> Self {
+        Self {
+            metrics,
+            stats: Arc::new(RwLock::new(HashMap::new())),
+            handles: Arc::new(Mutex::new(Vec::new())),
+            stopping: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Spawn a named task; `factory` is called again for every restart
+    pub fn spawn<F, Fut>(&self, name: &str, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.stats.write().insert(name.to_string(), TaskStats {
+            name: name.to_string(),
+            policy,
+            state: TaskState::Running,
+            restarts: 0,
+            panics: 0,
+            errors: 0,
+            last_error: None,
+        });
+
+        let supervisor = self.clone();
+        let name = name.to_string();
+        let handle = tokio::spawn(async move {
+            supervisor.supervise(name, policy, factory).await;
+        });
+        self.handles.lock().push(handle);
+    }
+
+    /// Run a task until its policy says to stop
+    async fn supervise<F, Fut>(&self, name: String, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut delay = INITIAL_RESTART_DELAY;
+
+        loop {
+            self.set_state(&name, TaskState::Running);
+            let started = Instant::now();
+
+            let exit = match AssertUnwindSafe(factory()).catch_unwind().await {
+                Ok(Ok(())) => TaskExit::Completed,
+                Ok(Err(e)) => TaskExit::Error(e.to_string()),
+                Err(panic) => TaskExit::Panic(panic_message(panic.as_ref())),
+            };
+
+            let failed = !matches!(exit, TaskExit::Completed);
+            self.record_exit(&name, exit);
+
+            let restart = match policy {
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => failed,
+                RestartPolicy::Always => true,
+            };
+            if !restart || self.stopping.load(Ordering::Relaxed) {
+                self.set_state(&name, if failed { TaskState::Failed } else { TaskState::Completed });
+                return;
+            }
+
+            if started.elapsed() >= HEALTHY_RUN_TIME {
+                delay = INITIAL_RESTART_DELAY;
+            }
+            self.set_state(&name, TaskState::Restarting);
+            tracing::warn!("Restarting task {} in {:?}", name, delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+            if let Some(stats) = self.stats.write().get_mut(&name) {
+                stats.restarts += 1;
+            }
+            self.metrics.incr(&format!("tasks.{}.restarts", name), 1);
+        }
+    }
+
+    /// Log and count how a task run ended
+    fn record_exit(&self, name: &str, exit: TaskExit) {
+        let mut stats = self.stats.write();
+        let Some(stats) = stats.get_mut(name) else {
+            return;
+        };
+
+        match exit {
+            TaskExit::Completed => {
+                tracing::info!("Task {} completed", name);
+            }
+            TaskExit::Error(error) => {
+                tracing::error!("Task {} failed: {}", name, error);
+                self.metrics.incr(&format!("tasks.{}.errors", name), 1);
+                stats.errors += 1;
+                stats.last_error = Some(error);
+            }
+            TaskExit::Panic(message) => {
+                tracing::error!("Task {} panicked: {}", name, message);
+                self.metrics.incr(&format!("tasks.{}.panics", name), 1);
+                stats.panics += 1;
+                stats.last_error = Some(format!("panic: {}", message));
+            }
+        }
+    }
+
+    fn set_state(&self, name: &str, state: TaskState) {
+        if let Some(stats) = self.stats.write().get_mut(name) {
+            stats.state = state;
+        }
+    }
+
+    /// Get statistics for all tasks, sorted by name
+    pub fn stats(&self) -> Vec<TaskStats> {
+        let mut stats: Vec<TaskStats> = self.stats.read().values().cloned().collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
+    }
+
+    /// Resolves once shutdown begins, so task loops can return cleanly
+    /// instead of being aborted
+    pub async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        let _ = stop.wait_for(|stop| *stop).await;
+    }
+
+    /// Stop restarting tasks, wait up to `deadline` for them to finish and
+    /// abort whatever is still running
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.stop.send_replace(true);
+
+        let handles: Vec<JoinHandle<()>> = self.handles.lock().drain(..).collect();
+        let abort_handles: Vec<_> = handles.iter().map(|handle| handle.abort_handle()).collect();
+
+        if tokio::time::timeout(deadline, futures::future::join_all(handles)).await.is_err() {
+            let remaining = abort_handles.iter().filter(|handle| !handle.is_finished()).count();
+            tracing::warn!("Aborting {} tasks still running after {:?}", remaining, deadline);
+            for handle in abort_handles {
+                handle.abort();
+            }
+        }
+
+        for stats in self.stats.write().values_mut() {
+            if matches!(stats.state, TaskState::Running | TaskState::Restarting) {
+                stats.state = TaskState::Stopped;
+            }
+        }
+    }
+}
+
+/// Extract the message from a panic payload
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use crate::config::BaseConfig;
+    use crate::errors::NsqError;
+
+    fn supervisor() -> TaskSupervisor {
+        TaskSupervisor::new(Metrics::new(&BaseConfig::default()).unwrap())
+    }
+
+    fn task_stats(supervisor: &TaskSupervisor, name: &str) -> TaskStats {
+        supervisor.stats().into_iter().find(|stats| stats.name == name).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task_is_restarted_after_a_panic() {
+        let supervisor = supervisor();
+        let runs = Arc::new(AtomicU64::new(0));
+        let counter = runs.clone();
+        supervisor.spawn("flaky", RestartPolicy::OnFailure, move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    panic!("boom");
+                }
+                Ok(())
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let stats = task_stats(&supervisor, "flaky");
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!((stats.state, stats.restarts, stats.panics), (TaskState::Completed, 1, 1));
+        assert_eq!(stats.last_error.as_deref(), Some("panic: boom"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_failure_restarts_only_failed_runs_and_always_every_exit() {
+        let supervisor = supervisor();
+        let on_failure_runs = Arc::new(AtomicU64::new(0));
+        let always_runs = Arc::new(AtomicU64::new(0));
+        for (name, policy, runs) in [
+            ("on_failure", RestartPolicy::OnFailure, on_failure_runs.clone()),
+            ("always", RestartPolicy::Always, always_runs.clone()),
+        ] {
+            supervisor.spawn(name, policy, move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            });
+        }
+        supervisor.spawn("failing", RestartPolicy::OnFailure, || async {
+            Err(NsqError::Queue("unavailable".to_string()))
+        });
+
+        // Restarts come after 1s, 2s and 4s
+        tokio::time::sleep(Duration::from_millis(7500)).await;
+        assert_eq!(on_failure_runs.load(Ordering::SeqCst), 1);
+        assert_eq!(task_stats(&supervisor, "on_failure").state, TaskState::Completed);
+        assert_eq!(always_runs.load(Ordering::SeqCst), 4);
+        assert_eq!(task_stats(&supervisor, "always").restarts, 3);
+        let failing = task_stats(&supervisor, "failing");
+        assert_eq!((failing.state, failing.restarts, failing.errors), (TaskState::Restarting, 3, 4));
+        assert_eq!(failing.last_error.as_deref(), Some("Queue error: unavailable"));
+        supervisor.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_delay_doubles_up_to_the_maximum() {
+        let supervisor = supervisor();
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = starts.clone();
+        supervisor.spawn("failing", RestartPolicy::Always, move || {
+            recorded.lock().push(tokio::time::Instant::now());
+            async { Err(NsqError::Queue("unavailable".to_string())) }
+        });
+
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        let starts = starts.lock();
+        let delays: Vec<u64> = starts.windows(2).map(|pair| (pair[1] - pair[0]).as_secs()).collect();
+        assert_eq!(delays[..7], [1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_aborts_tasks_that_do_not_stop() {
+        struct DropFlag(Arc<AtomicBool>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let supervisor = supervisor();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = dropped.clone();
+        supervisor.spawn("stuck", RestartPolicy::Always, move || {
+            let guard = DropFlag(flag.clone());
+            async move {
+                let _guard = guard;
+                std::future::pending::<Result<()>>().await
+            }
+        });
+        let watcher = supervisor.clone();
+        supervisor.spawn("polite", RestartPolicy::Always, move || {
+            let watcher = watcher.clone();
+            async move {
+                watcher.stopped().await;
+                Ok(())
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        supervisor.shutdown(Duration::from_secs(1)).await;
+        tokio::task::yield_now().await;
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(task_stats(&supervisor, "stuck").state, TaskState::Stopped);
+        let polite = task_stats(&supervisor, "polite");
+        assert_eq!((polite.state, polite.restarts), (TaskState::Completed, 0));
+    }
+}
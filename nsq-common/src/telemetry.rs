@@ -0,0 +1,93 @@
+//! OpenTelemetry trace export
+//!
+//! When an OTLP endpoint is configured, spans recorded with `tracing` are
+//! exported over OTLP/HTTP in addition to being logged. Spans covering a
+//! message carry its ID in a `message_id` attribute so its publish, delivery
+//! and FIN can be found together even though they happen on different
+//! connections.
+
+use std::sync::OnceLock;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use crate::errors::{NsqError, Result};
+
+/// Environment variable read by tools that have no config file
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Provider kept so buffered spans can be flushed on exit
+static TRACER_PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// Build a layer exporting spans at info level and above to `endpoint`,
+/// keeping `sample_rate` (0.0-1.0) of traces. Must be called from within a
+/// tokio runtime.
+pub fn otlp_layer<S>(endpoint: &str, service_name: &str, sample_rate: f64) -> Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    if !(0.0..=1.0).contains(&sample_rate) {
+        return Err(NsqError::Config(format!("invalid OTLP sample rate {}: must be between 0 and 1", sample_rate)));
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(traces_url(endpoint))
+        .build()
+        .map_err(|e| NsqError::Config(format!("failed to create OTLP exporter for {}: {}", endpoint, e)))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_rate))))
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name.to_string())]))
+        .build();
+    let tracer = provider.tracer(service_name.to_string());
+    let _ = TRACER_PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(LevelFilter::INFO)
+        .boxed())
+}
+
+/// Traces URL of an OTLP/HTTP collector given either its base URL or the
+/// full `/v1/traces` URL
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint)
+    }
+}
+
+/// Name spans are exported under when none is configured: the executable's name
+pub fn default_service_name() -> String {
+    std::env::current_exe().ok()
+        .and_then(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "nsq".to_string())
+}
+
+/// Span covering an HTTP request; the handler's response status is recorded
+/// into `http.status_code`
+pub fn http_span(method: &impl std::fmt::Display, path: &str) -> tracing::Span {
+    tracing::info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", method, path),
+        http.method = %method,
+        http.target = path,
+        http.status_code = tracing::field::Empty,
+    )
+}
+
+/// Export any spans still buffered. Call before the process exits.
+pub fn shutdown_tracing() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("failed to flush traces: {}", e);
+        }
+    }
+}
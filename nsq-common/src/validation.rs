@@ -1,35 +1,90 @@
 //! Validation utilities
 
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use nsq_protocol::ErrorCode;
 use regex::Regex;
+use thiserror::Error;
 use crate::errors::{NsqError, Result};
 
 lazy_static::lazy_static! {
-    static ref TOPIC_CHANNEL_NAME_REGEX: Regex = Regex::new(r"^[\.a-zA-Z0-9_-]+$").unwrap();
+    static ref TOPIC_NAME_REGEX: Regex = Regex::new(r"^[\.a-zA-Z0-9_-]+(#ephemeral|#dlq)?$").unwrap();
+    static ref CHANNEL_NAME_REGEX: Regex = Regex::new(r"^[\.a-zA-Z0-9_-]+(#ephemeral)?$").unwrap();
+    static ref HOSTNAME_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9]([a-zA-Z0-9-]{0,62})(\.[a-zA-Z0-9]([a-zA-Z0-9-]{0,62}))*\.?$").unwrap();
 }
 
-/// Validate topic or channel name
-pub fn validate_topic_channel_name(name: &str) -> Result<()> {
-    if name.is_empty() {
-        return Err(NsqError::Validation("Name cannot be empty".to_string()));
-    }
-    
-    if name.len() > 64 {
-        return Err(NsqError::Validation("Name too long (max 64 characters)".to_string()));
+/// Suffix of the topic that receives a topic's dead-lettered messages
+pub const DEAD_LETTER_SUFFIX: &str = "#dlq";
+
+/// Suffix of topics and channels that are not meant to outlive their users
+pub const EPHEMERAL_SUFFIX: &str = "#ephemeral";
+
+/// Longest topic or channel name, including its suffix
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// What a validated name belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameKind {
+    Topic,
+    Channel,
+}
+
+impl std::fmt::Display for NameKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameKind::Topic => write!(f, "topic"),
+            NameKind::Channel => write!(f, "channel"),
+        }
     }
-    
-    if !TOPIC_CHANNEL_NAME_REGEX.is_match(name) {
-        return Err(NsqError::Validation(
-            "Name contains invalid characters. Only letters, numbers, dots, underscores, and hyphens are allowed".to_string()
-        ));
+}
+
+/// A topic or channel name that breaks the naming rules
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid {kind} name '{name}': {reason}")]
+pub struct InvalidName {
+    pub kind: NameKind,
+    pub name: String,
+    pub reason: String,
+}
+
+impl InvalidName {
+    /// Error code reported to clients: `E_BAD_TOPIC` or `E_BAD_CHANNEL`
+    pub fn code(&self) -> ErrorCode {
+        match self.kind {
+            NameKind::Topic => ErrorCode::BadTopic,
+            NameKind::Channel => ErrorCode::BadChannel,
+        }
     }
-    
-    Ok(())
+}
+
+/// Validate a topic name: 1 to 64 of `[.a-zA-Z0-9_-]`, optionally followed
+/// by `#ephemeral` or `#dlq`
+pub fn validate_topic_name(name: &str) -> std::result::Result<(), InvalidName> {
+    validate_name(NameKind::Topic, name, &TOPIC_NAME_REGEX, "#ephemeral or #dlq")
+}
+
+/// Validate a channel name: 1 to 64 of `[.a-zA-Z0-9_-]`, optionally
+/// followed by `#ephemeral`
+pub fn validate_channel_name(name: &str) -> std::result::Result<(), InvalidName> {
+    validate_name(NameKind::Channel, name, &CHANNEL_NAME_REGEX, "#ephemeral")
+}
+
+fn validate_name(kind: NameKind, name: &str, regex: &Regex, suffixes: &str) -> std::result::Result<(), InvalidName> {
+    let reason = if name.is_empty() {
+        "name cannot be empty".to_string()
+    } else if name.len() > MAX_NAME_LENGTH {
+        format!("name too long ({} characters, max {})", name.len(), MAX_NAME_LENGTH)
+    } else if !regex.is_match(name) {
+        format!("only letters, numbers, dots, underscores and hyphens are allowed, optionally followed by {}", suffixes)
+    } else {
+        return Ok(());
+    };
+    Err(InvalidName { kind, name: name.to_string(), reason })
 }
 
 /// Validate message body size
 pub fn validate_message_size(body: &[u8], max_size: usize) -> Result<()> {
     if body.len() > max_size {
-        return Err(NsqError::Validation(
+        return Err(NsqError::BadMessage(
             format!("Message too large: {} bytes (max: {} bytes)", body.len(), max_size)
         ));
     }
@@ -76,3 +131,92 @@ pub fn validate_address(addr: &str) -> Result<()> {
     
     Ok(())
 }
+
+/// Validate a broadcast address (an IP address or hostname, without port)
+pub fn validate_broadcast_address(addr: &str) -> Result<()> {
+    if addr.is_empty() {
+        return Err(NsqError::Validation("Broadcast address cannot be empty".to_string()));
+    }
+    
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        if ip.is_unspecified() {
+            return Err(NsqError::Validation(
+                format!("Broadcast address cannot be the unspecified address {}", ip)
+            ));
+        }
+        return Ok(());
+    }
+    
+    if addr.len() > 253 || !HOSTNAME_REGEX.is_match(addr) {
+        return Err(NsqError::Validation(format!("Invalid broadcast address '{}'", addr)));
+    }
+    
+    Ok(())
+}
+
+/// Resolve the broadcast address to advertise
+///
+/// `auto` picks the address of the primary network interface. Other values are
+/// validated, and hostnames that do not resolve are accepted with a warning.
+pub fn resolve_broadcast_address(addr: &str) -> Result<String> {
+    if addr == "auto" {
+        return Ok(primary_interface_address()
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| {
+                tracing::warn!("Could not determine primary interface address, using 127.0.0.1");
+                "127.0.0.1".to_string()
+            }));
+    }
+    
+    validate_broadcast_address(addr)?;
+    
+    if addr.parse::<IpAddr>().is_err() && (addr, 0).to_socket_addrs().is_err() {
+        tracing::warn!("Broadcast address '{}' does not resolve", addr);
+    }
+    
+    Ok(addr.to_string())
+}
+
+/// Get the local address used for outbound traffic
+fn primary_interface_address() -> Option<IpAddr> {
+    // Connecting a UDP socket only consults the routing table; nothing is sent
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast_address_accepts_ips_and_hostnames() {
+        for addr in ["10.0.0.5", "127.0.0.1", "::1", "fe80::1", "localhost", "nsqd-1", "nsqd-1.example.com", "nsqd.example.com."] {
+            assert!(validate_broadcast_address(addr).is_ok(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_broadcast_address_rejects_invalid() {
+        let too_long = format!("{}a", "a.".repeat(127));
+        let long_label = "a".repeat(64);
+        for addr in [
+            "", "0.0.0.0", "::", "nsqd:4150", "127.0.0.1:4150", "[::1]", "[::1]:4150",
+            "-nsqd", "nsqd_1", "nsqd..example.com", ".nsqd", "nsqd example", too_long.as_str(), long_label.as_str(),
+        ] {
+            assert!(validate_broadcast_address(addr).is_err(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_resolve_broadcast_address() {
+        assert_eq!(resolve_broadcast_address("10.0.0.5").unwrap(), "10.0.0.5");
+        // A hostname that does not resolve is only warned about
+        assert_eq!(resolve_broadcast_address("nsqd.invalid").unwrap(), "nsqd.invalid");
+        assert!(resolve_broadcast_address("nsqd.invalid:4150").is_err());
+
+        let auto: IpAddr = resolve_broadcast_address("auto").unwrap().parse().unwrap();
+        assert!(!auto.is_unspecified());
+    }
+}
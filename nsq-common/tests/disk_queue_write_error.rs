@@ -0,0 +1,59 @@
+//! Disk queue writes that fail part way, forced with a file size limit. This
+//! is a test binary of its own since the limit applies to the whole process.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use nsq_common::DiskQueue;
+use uuid::Uuid;
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("nsq-disk-queue-{}", Uuid::new_v4()))
+}
+
+fn message(i: usize) -> Vec<u8> {
+    format!("message-{:012}", i).into_bytes()
+}
+
+/// Run `f` with files limited to `max_bytes`, so writes past it fail with
+/// EFBIG instead of raising SIGXFSZ
+fn with_file_size_limit<T>(max_bytes: u64, f: impl FnOnce() -> T) -> T {
+    let mut saved = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe {
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        assert_eq!(libc::getrlimit(libc::RLIMIT_FSIZE, &mut saved), 0);
+        let limited = libc::rlimit { rlim_cur: max_bytes, rlim_max: saved.rlim_max };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limited), 0);
+    }
+    let result = f();
+    unsafe {
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &saved), 0);
+    }
+    result
+}
+
+#[test]
+fn test_failed_batch_keeps_earlier_buffered_puts() {
+    let dir = temp_dir();
+    let queue = DiskQueue::new(&dir, 1024 * 1024, 64 * 1024, Duration::from_secs(60))
+        .unwrap()
+        .with_sync_every(0);
+    
+    // Left in the write buffer, since nothing syncs or reads them yet
+    (0..3).for_each(|i| queue.put(&message(i)).unwrap());
+    
+    // The batch overflows the write buffer, and the limit is hit before even
+    // the earlier puts are all in the file
+    let batch = vec![vec![b'x'; 40 * 1024]; 4];
+    let result = with_file_size_limit(50, || queue.put_batch(&batch));
+    assert!(result.is_err());
+    assert_eq!(queue.depth(), 3);
+    
+    queue.put(&message(3)).unwrap();
+    for i in 0..4 {
+        assert_eq!(queue.get().unwrap(), Some(message(i)));
+    }
+    assert_eq!(queue.get().unwrap(), None);
+    
+    std::fs::remove_dir_all(&dir).unwrap();
+}
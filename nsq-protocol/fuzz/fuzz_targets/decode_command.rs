@@ -0,0 +1,19 @@
+//! Feed arbitrary bytes to the command decoder as a client would send them,
+//! and check that every decoded command encodes back to the bytes it came from
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use nsq_protocol::{Command, CommandDecoder};
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    let mut decoder = CommandDecoder::with_max_body_size(64 * 1024);
+    while let Ok(Some(command)) = decoder.decode(&mut src) {
+        let encoded = command.to_bytes().expect("decoded command encodes");
+        let decoded = Command::from_bytes(encoded).expect("encoded command decodes");
+        assert_eq!(decoded, command);
+    }
+});
@@ -0,0 +1,20 @@
+//! Feed arbitrary bytes to the frame decoder as a server would send them,
+//! and check that every decoded frame encodes back to the bytes it came from
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use nsq_protocol::NsqDecoder;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    let mut decoder = NsqDecoder::with_max_frame_size(64 * 1024);
+    let mut consumed = 0;
+    while let Ok(Some(frame)) = decoder.decode(&mut src) {
+        let encoded = frame.to_bytes();
+        assert_eq!(&encoded[..], &data[consumed..consumed + encoded.len()]);
+        consumed += encoded.len();
+    }
+});
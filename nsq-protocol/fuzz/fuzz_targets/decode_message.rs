@@ -0,0 +1,19 @@
+//! Decode arbitrary bytes as a message, with and without a header block, and
+//! check that decoded messages survive a round trip
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use nsq_protocol::{decode_with_headers, Message};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::from_bytes(Bytes::copy_from_slice(data)) {
+        let decoded = Message::from_bytes(message.to_bytes_with_headers()).expect("encoded message decodes");
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.attempts, message.attempts);
+        assert_eq!(decoded.headers, message.headers);
+        assert_eq!(decoded.body, message.body);
+    }
+    let _ = decode_with_headers(Bytes::copy_from_slice(data));
+});
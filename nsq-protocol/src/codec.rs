@@ -2,9 +2,14 @@
 //! 
 //! Implements the tokio-util codec traits for NSQ protocol
 
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
-use crate::{Frame, Command, Message, ProtocolError, Result};
+use zstd::stream::raw::{Decoder as ZstdDecoder, Encoder as ZstdEncoder, InBuffer, Operation, OutBuffer};
+use crate::{Frame, FrameType, Command, Message, ProtocolError, Result};
 
 /// NSQ Protocol Decoder
 pub struct NsqDecoder {
@@ -48,10 +53,12 @@ impl Decoder for NsqDecoder {
         }
         
         if src.len() < 5 + frame_size {
+            // Make room for the rest of the frame in one allocation
+            src.reserve(5 + frame_size - src.len());
             return Ok(None);
         }
         
-        // Split the frame data
+        // Split the frame off the buffer; the body shares its memory
         let frame_data = src.split_to(5 + frame_size);
         let frame = Frame::from_bytes(frame_data.freeze())?;
         
@@ -72,14 +79,150 @@ impl Encoder<Frame> for NsqEncoder {
     }
 }
 
+/// Command Decoder
+///
+/// Decodes client commands, waiting until the command line and any
+/// length-prefixed body have been fully received.
+pub struct CommandDecoder {
+    max_body_size: usize,
+}
+
+impl CommandDecoder {
+    /// Maximum length of a command line
+    const MAX_LINE_SIZE: usize = 1024;
+    
+    /// Create a new decoder with default max body size
+    pub fn new() -> Self {
+        Self {
+            max_body_size: 5 * 1024 * 1024, // 5MB default
+        }
+    }
+    
+    /// Create a new decoder with custom max body size
+    pub fn with_max_body_size(max_body_size: usize) -> Self {
+        Self { max_body_size }
+    }
+    
+    /// Read a length prefix at `pos`, returning None if more data is needed
+    fn read_size(&self, src: &[u8], pos: usize) -> Result<Option<usize>> {
+        if src.len() < pos + 4 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([src[pos], src[pos + 1], src[pos + 2], src[pos + 3]]) as usize;
+        if size > self.max_body_size {
+            return Err(ProtocolError::InvalidFrameSize(size));
+        }
+        Ok(Some(size))
+    }
+    
+    /// Get the encoded length of the command at the front of `src`
+    fn command_len(&self, src: &[u8]) -> Result<Option<usize>> {
+        let line_end = match src.iter().position(|b| *b == b'\n') {
+            Some(pos) => pos,
+            None if src.len() > Self::MAX_LINE_SIZE => {
+                return Err(ProtocolError::InvalidCommand("Command line too long".to_string()));
+            }
+            None => return Ok(None),
+        };
+        
+        let name = src[..line_end].split(|b| *b == b' ').next().unwrap_or_default();
+        let body_start = line_end + 1;
+        
+        match name {
+            b"PUB" | b"IDENTIFY" | b"AUTH" => {
+                Ok(self.read_size(src, body_start)?.map(|size| body_start + 4 + size))
+            }
+            b"DPUB" => {
+                Ok(self.read_size(src, body_start + 8)?.map(|size| body_start + 12 + size))
+            }
+            b"MPUB" => {
+                let count = match self.read_size(src, body_start)? {
+                    Some(count) => count,
+                    None => return Ok(None),
+                };
+                let mut pos = body_start + 4;
+                for _ in 0..count {
+                    match self.read_size(src, pos)? {
+                        Some(size) => pos += 4 + size,
+                        None => return Ok(None),
+                    }
+                    if pos - body_start > self.max_body_size {
+                        return Err(ProtocolError::InvalidFrameSize(pos - body_start));
+                    }
+                }
+                Ok(Some(pos))
+            }
+            _ => Ok(Some(body_start)),
+        }
+    }
+}
+
+impl Default for CommandDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for CommandDecoder {
+    type Item = Command;
+    type Error = ProtocolError;
+    
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let len = match self.command_len(src)? {
+            Some(len) if src.len() >= len => len,
+            Some(len) => {
+                // Make room for the rest of the body in one allocation
+                src.reserve(len - src.len());
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        
+        // Split the command off the buffer; bodies share its memory
+        let command_data = src.split_to(len);
+        Command::from_bytes(command_data.freeze()).map(Some)
+    }
+}
+
+/// Magic a client sends when it connects to select protocol version 2
+pub const MAGIC_V2: &[u8; 4] = b"  V2";
+
 /// Command Encoder
-pub struct CommandEncoder;
+///
+/// Writes the protocol magic ahead of the first command, as nsqd requires of
+/// new connections.
+pub struct CommandEncoder {
+    magic_pending: bool,
+}
+
+impl CommandEncoder {
+    /// Create an encoder for a new connection
+    pub fn new() -> Self {
+        Self { magic_pending: true }
+    }
+    
+    /// Create an encoder that never writes the magic, for connections that
+    /// already sent it
+    pub fn without_magic() -> Self {
+        Self { magic_pending: false }
+    }
+}
+
+impl Default for CommandEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Encoder<Command> for CommandEncoder {
     type Error = ProtocolError;
     
     fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<()> {
         let command_bytes = item.to_bytes()?;
+        if self.magic_pending {
+            dst.extend_from_slice(MAGIC_V2);
+            self.magic_pending = false;
+        }
         dst.extend_from_slice(&command_bytes);
         Ok(())
     }
@@ -98,11 +241,397 @@ impl Encoder<Message> for MessageEncoder {
     }
 }
 
+/// Items that can be attributed to a named type in codec counters
+pub trait CountedItem {
+    /// Short name of the item type, e.g. the command name or frame type
+    fn type_name(&self) -> &'static str;
+}
+
+impl CountedItem for Frame {
+    fn type_name(&self) -> &'static str {
+        match self.frame_type {
+            FrameType::Response => "response",
+            FrameType::Error => "error",
+            FrameType::Message => "message",
+        }
+    }
+}
+
+impl CountedItem for Command {
+    fn type_name(&self) -> &'static str {
+        self.name()
+    }
+}
+
+impl CountedItem for Message {
+    fn type_name(&self) -> &'static str {
+        "message"
+    }
+}
+
+/// Frame and byte totals for one item type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeCounts {
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// Point-in-time copy of codec counters
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodecStats {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    pub in_by_type: BTreeMap<String, TypeCounts>,
+    pub out_by_type: BTreeMap<String, TypeCounts>,
+}
+
+/// Frame and byte counters shared between counting codecs and their owner
+///
+/// Totals are plain atomics so they can be read cheaply on every stats
+/// request; the per-type breakdown sits behind a mutex.
+#[derive(Debug, Default)]
+pub struct CodecCounters {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    in_by_type: Mutex<BTreeMap<&'static str, TypeCounts>>,
+    out_by_type: Mutex<BTreeMap<&'static str, TypeCounts>>,
+}
+
+impl CodecCounters {
+    /// Create zeroed counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Record a decoded item
+    pub fn record_in(&self, type_name: &'static str, bytes: u64) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        Self::record_type(&self.in_by_type, type_name, bytes);
+    }
+    
+    /// Record an encoded item
+    pub fn record_out(&self, type_name: &'static str, bytes: u64) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        Self::record_type(&self.out_by_type, type_name, bytes);
+    }
+    
+    fn record_type(by_type: &Mutex<BTreeMap<&'static str, TypeCounts>>, type_name: &'static str, bytes: u64) {
+        let mut by_type = by_type.lock().unwrap_or_else(|e| e.into_inner());
+        let counts = by_type.entry(type_name).or_default();
+        counts.frames += 1;
+        counts.bytes += bytes;
+    }
+    
+    /// Number of items decoded
+    pub fn frames_in(&self) -> u64 {
+        self.frames_in.load(Ordering::Relaxed)
+    }
+    
+    /// Bytes consumed by decoded items
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+    
+    /// Number of items encoded
+    pub fn frames_out(&self) -> u64 {
+        self.frames_out.load(Ordering::Relaxed)
+    }
+    
+    /// Bytes produced by encoded items
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+    
+    /// Copy all counters, including the per-type breakdown
+    pub fn snapshot(&self) -> CodecStats {
+        let by_type = |counts: &Mutex<BTreeMap<&'static str, TypeCounts>>| {
+            counts.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .map(|(name, counts)| (name.to_string(), *counts))
+                .collect()
+        };
+        
+        CodecStats {
+            frames_in: self.frames_in(),
+            bytes_in: self.bytes_in(),
+            frames_out: self.frames_out(),
+            bytes_out: self.bytes_out(),
+            in_by_type: by_type(&self.in_by_type),
+            out_by_type: by_type(&self.out_by_type),
+        }
+    }
+}
+
+/// Wraps a codec and records every item it decodes or encodes
+///
+/// Sizes are measured on the uncompressed protocol stream, so place it
+/// inside a `ZstdStream` rather than around one.
+pub struct CountingCodec<C> {
+    inner: C,
+    counters: Arc<CodecCounters>,
+}
+
+impl<C> CountingCodec<C> {
+    /// Wrap a codec, recording into `counters`
+    pub fn new(inner: C, counters: Arc<CodecCounters>) -> Self {
+        Self { inner, counters }
+    }
+    
+    /// Counters this codec records into
+    pub fn counters(&self) -> &Arc<CodecCounters> {
+        &self.counters
+    }
+}
+
+impl<C> Decoder for CountingCodec<C>
+where
+    C: Decoder<Error = ProtocolError>,
+    C::Item: CountedItem,
+{
+    type Item = C::Item;
+    type Error = ProtocolError;
+    
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let before = src.len();
+        let item = self.inner.decode(src)?;
+        if let Some(item) = &item {
+            self.counters.record_in(item.type_name(), (before - src.len()) as u64);
+        }
+        Ok(item)
+    }
+}
+
+impl<T: CountedItem, C: Encoder<T, Error = ProtocolError>> Encoder<T> for CountingCodec<C> {
+    type Error = ProtocolError;
+    
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<()> {
+        let type_name = item.type_name();
+        let before = dst.len();
+        self.inner.encode(item, dst)?;
+        self.counters.record_out(type_name, (dst.len() - before) as u64);
+        Ok(())
+    }
+}
+
+/// Size of the scratch buffer used for zstd stream output
+const ZSTD_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Wraps a codec with a zstd stream that can be enabled mid-connection
+///
+/// Both directions start uncompressed; once compression is negotiated each
+/// side enables its half and all later bytes pass through the zstd stream.
+pub struct ZstdStream<C> {
+    inner: C,
+    decompressor: Option<ZstdDecoder<'static>>,
+    compressor: Option<ZstdEncoder<'static>>,
+    decoded: BytesMut,
+}
+
+impl<C> ZstdStream<C> {
+    /// Wrap a codec with compression disabled
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            decompressor: None,
+            compressor: None,
+            decoded: BytesMut::new(),
+        }
+    }
+    
+    /// Decompress all bytes read from now on
+    pub fn enable_decompression(&mut self) -> Result<()> {
+        if self.decompressor.is_none() {
+            self.decompressor = Some(ZstdDecoder::new()
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?);
+        }
+        Ok(())
+    }
+    
+    /// Compress all bytes written from now on
+    pub fn enable_compression(&mut self, level: i32) -> Result<()> {
+        if self.compressor.is_none() {
+            self.compressor = Some(ZstdEncoder::new(level)
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?);
+        }
+        Ok(())
+    }
+    
+    /// Check whether incoming bytes are decompressed
+    pub fn is_decompressing(&self) -> bool {
+        self.decompressor.is_some()
+    }
+    
+    /// Check whether outgoing bytes are compressed
+    pub fn is_compressing(&self) -> bool {
+        self.compressor.is_some()
+    }
+}
+
+impl<C: Decoder<Error = ProtocolError>> Decoder for ZstdStream<C> {
+    type Item = C::Item;
+    type Error = ProtocolError;
+    
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let Some(decompressor) = self.decompressor.as_mut() else {
+            return self.inner.decode(src);
+        };
+        
+        if !src.is_empty() {
+            let input = src.split();
+            let mut in_buffer = InBuffer::around(&input);
+            let mut chunk = vec![0u8; ZSTD_CHUNK_SIZE];
+            loop {
+                let mut out_buffer = OutBuffer::around(&mut chunk[..]);
+                decompressor.run(&mut in_buffer, &mut out_buffer)
+                    .map_err(|e| ProtocolError::Compression(e.to_string()))?;
+                let written = out_buffer.pos();
+                self.decoded.extend_from_slice(&chunk[..written]);
+                
+                // Output may still be pending while the scratch buffer fills up
+                if in_buffer.pos() == input.len() && written < chunk.len() {
+                    break;
+                }
+            }
+        }
+        
+        self.inner.decode(&mut self.decoded)
+    }
+}
+
+impl<T, C: Encoder<T, Error = ProtocolError>> Encoder<T> for ZstdStream<C> {
+    type Error = ProtocolError;
+    
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<()> {
+        let Some(compressor) = self.compressor.as_mut() else {
+            return self.inner.encode(item, dst);
+        };
+        
+        let mut plain = BytesMut::new();
+        self.inner.encode(item, &mut plain)?;
+        
+        let mut in_buffer = InBuffer::around(&plain);
+        let mut chunk = vec![0u8; ZSTD_CHUNK_SIZE];
+        while in_buffer.pos() < plain.len() {
+            let mut out_buffer = OutBuffer::around(&mut chunk[..]);
+            compressor.run(&mut in_buffer, &mut out_buffer)
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?;
+            let written = out_buffer.pos();
+            dst.extend_from_slice(&chunk[..written]);
+        }
+        
+        // Flush so the peer can decode this item without waiting for more data
+        loop {
+            let mut out_buffer = OutBuffer::around(&mut chunk[..]);
+            let remaining = compressor.flush(&mut out_buffer)
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?;
+            let written = out_buffer.pos();
+            dst.extend_from_slice(&chunk[..written]);
+            if remaining == 0 {
+                break;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// Body of the heartbeat responses nsqd sends; answer them with `NOP`
+pub const HEARTBEAT: &[u8] = b"_heartbeat_";
+
+/// Outcome of matching a frame against the outstanding publishes
+#[derive(Debug)]
+pub enum PublishResponse<T> {
+    /// The oldest outstanding publish succeeded
+    Ok(T),
+    /// The oldest outstanding publish failed with this error
+    Error(T, String),
+    /// A heartbeat, which settles nothing
+    Heartbeat,
+    /// A frame that answers no outstanding publish
+    Unexpected(Frame),
+}
+
+/// Tracks PUB and MPUB commands pipelined ahead of their responses
+///
+/// nsqd answers the commands on a connection in the order it received them,
+/// so each `OK` or error frame settles the oldest outstanding publish. At most
+/// `window` publishes are outstanding at once; producers read responses while
+/// the window is full instead of waiting for each one.
+#[derive(Debug)]
+pub struct ResponseMatcher<T> {
+    pending: VecDeque<T>,
+    window: usize,
+}
+
+impl<T> ResponseMatcher<T> {
+    /// Create a matcher allowing `window` outstanding publishes (at least one)
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self { pending: VecDeque::with_capacity(window), window }
+    }
+    
+    pub fn window(&self) -> usize {
+        self.window
+    }
+    
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    
+    /// Whether a response must be read before the next publish is written
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.window
+    }
+    
+    /// Record a publish that was just written
+    pub fn push(&mut self, item: T) {
+        debug_assert!(!self.is_full(), "publish window exceeded");
+        self.pending.push_back(item);
+    }
+    
+    /// Match a frame read from the connection to the publish it answers
+    pub fn match_frame(&mut self, frame: Frame) -> PublishResponse<T> {
+        if frame.frame_type == FrameType::Response && frame.body.as_ref() == HEARTBEAT {
+            return PublishResponse::Heartbeat;
+        }
+        if frame.frame_type == FrameType::Message {
+            return PublishResponse::Unexpected(frame);
+        }
+        let Some(item) = self.pending.pop_front() else {
+            return PublishResponse::Unexpected(frame);
+        };
+        match frame.frame_type {
+            FrameType::Error => PublishResponse::Error(item, String::from_utf8_lossy(&frame.body).into_owned()),
+            _ => PublishResponse::Ok(item),
+        }
+    }
+    
+    /// Take every outstanding publish, oldest first, e.g. once the connection
+    /// has failed and their outcome is unknown
+    pub fn drain(&mut self) -> std::collections::vec_deque::Drain<'_, T> {
+        self.pending.drain(..)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{FrameType, Message};
+    use crate::ErrorCode;
     use bytes::Bytes;
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+    use crate::{decode_with_headers, encode_with_headers, IdentifyResponse, MessageHeaders};
     
     #[test]
     fn test_frame_codec() {
@@ -127,4 +656,316 @@ mod tests {
         assert_eq!(decoded.body, Bytes::from("test body"));
         assert_eq!(decoded.attempts, 0);
     }
+    
+    #[test]
+    fn test_message_layout_matches_nsqd() {
+        // Timestamp 1640995200s in nanoseconds, 3 attempts, then the ID
+        let frame: &[u8] = b"\x16\xc5\xfc\x70\xa6\x1f\x00\x00\x00\x03\
+            05d6c7a1b2c00001hello";
+        let message = Message::from_bytes(Bytes::from_static(frame)).unwrap();
+        assert_eq!(message.timestamp.timestamp(), 1_640_995_200);
+        assert_eq!(message.attempts, 3);
+        assert_eq!(message.id.as_bytes(), b"05d6c7a1b2c00001");
+        assert_eq!(message.body, Bytes::from("hello"));
+        assert_eq!(message.to_bytes(), Bytes::from_static(frame));
+    }
+
+    #[test]
+    fn test_message_headers() {
+        let headers = MessageHeaders::from([
+            ("content-type".to_string(), "application/json".to_string()),
+            ("trace-id".to_string(), "abc123".to_string()),
+        ]);
+        let mut original_message = Message::new(Bytes::from("{}")).with_headers(headers.clone());
+        original_message.attempts = 3;
+        
+        let decoded = Message::from_bytes(original_message.to_bytes_with_headers()).unwrap();
+        assert_eq!(decoded.headers, headers);
+        assert_eq!(decoded.attempts, 3);
+        assert_eq!(decoded.body, Bytes::from("{}"));
+        
+        // Legacy encoding strips the headers
+        let legacy = original_message.to_bytes();
+        assert_eq!(legacy.len(), 26 + 2);
+        assert!(Message::from_bytes(legacy).unwrap().headers.is_empty());
+        
+        let published = encode_with_headers(&headers, b"body");
+        let (decoded_headers, body) = decode_with_headers(published.clone()).unwrap();
+        assert_eq!(decoded_headers, headers);
+        assert_eq!(body, Bytes::from("body"));
+        assert!(decode_with_headers(published.slice(..5)).is_err());
+    }
+    
+    #[test]
+    fn test_command_decoder_partial() {
+        let command = Command::Mpub {
+            topic: "test".to_string(),
+            bodies: vec![Bytes::from("one"), Bytes::from("two")],
+        };
+        let encoded = command.to_bytes().unwrap();
+        
+        let mut decoder = CommandDecoder::new();
+        let mut src = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+        
+        src.extend_from_slice(&encoded[encoded.len() - 1..]);
+        src.extend_from_slice(b"NOP\n");
+        match decoder.decode(&mut src).unwrap().unwrap() {
+            Command::Mpub { topic, bodies } => {
+                assert_eq!(topic, "test");
+                assert_eq!(bodies, vec![Bytes::from("one"), Bytes::from("two")]);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(matches!(decoder.decode(&mut src).unwrap(), Some(Command::Nop)));
+    }
+    
+    #[test]
+    fn test_zstd_stream_switch() {
+        let mut encoder = ZstdStream::new(NsqEncoder);
+        let mut decoder = ZstdStream::new(NsqDecoder::new());
+        let mut wire = BytesMut::new();
+        
+        encoder.encode(Frame::new(FrameType::Response, Bytes::from("plain")), &mut wire).unwrap();
+        encoder.enable_compression(3).unwrap();
+        for _ in 0..3 {
+            let body = Bytes::from("{\"field\":\"value\"}".repeat(50));
+            encoder.encode(Frame::new(FrameType::Message, body), &mut wire).unwrap();
+        }
+        
+        let first = decoder.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(first.body, Bytes::from("plain"));
+        decoder.enable_decompression().unwrap();
+        
+        // Feed the compressed stream a few bytes at a time
+        let compressed = wire.split();
+        assert!(compressed.len() < 3 * 850);
+        let mut decoded = Vec::new();
+        for chunk in compressed.chunks(7) {
+            wire.extend_from_slice(chunk);
+            while let Some(frame) = decoder.decode(&mut wire).unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded.len(), 3);
+        assert!(decoded.iter().all(|frame| frame.body.len() == 850));
+    }
+    
+    #[test]
+    fn test_counting_codec() {
+        let counters = Arc::new(CodecCounters::new());
+        let mut encoder = CountingCodec::new(NsqEncoder, counters.clone());
+        let mut decoder = CountingCodec::new(CommandDecoder::new(), counters.clone());
+        
+        let mut wire = BytesMut::new();
+        encoder.encode(Frame::new(FrameType::Response, Bytes::from("OK")), &mut wire).unwrap();
+        encoder.encode(Frame::new(FrameType::Message, Bytes::from("body")), &mut wire).unwrap();
+        assert_eq!(counters.frames_out(), 2);
+        assert_eq!(counters.bytes_out(), wire.len() as u64);
+        
+        let mut src = BytesMut::from(&b"NOP\nPUB test\n"[..]);
+        assert!(matches!(decoder.decode(&mut src).unwrap(), Some(Command::Nop)));
+        // Incomplete commands are not counted
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[0, 0, 0, 2, b'h', b'i']);
+        assert!(decoder.decode(&mut src).unwrap().is_some());
+        
+        let stats = counters.snapshot();
+        assert_eq!(stats.frames_in, 2);
+        assert_eq!(stats.bytes_in, 4 + 15);
+        assert_eq!(stats.in_by_type["PUB"], TypeCounts { frames: 1, bytes: 15 });
+        assert_eq!(stats.out_by_type["response"], TypeCounts { frames: 1, bytes: 7 });
+        assert_eq!(stats.out_by_type["message"].frames, 1);
+    }
+    
+    #[test]
+    fn test_command_decoder_zero_copy() {
+        let mut src = BytesMut::new();
+        let mut encoder = CommandEncoder::without_magic();
+        encoder.encode(Command::Pub { topic: "test".to_string(), body: Bytes::from(vec![7u8; 1024]) }, &mut src).unwrap();
+        encoder.encode(Command::Fin { message_id: Bytes::from("0123456789abcdef") }, &mut src).unwrap();
+        let buffer = src.as_ptr() as usize..src.as_ptr() as usize + src.len();
+        
+        let mut decoder = CommandDecoder::new();
+        let Some(Command::Pub { body, .. }) = decoder.decode(&mut src).unwrap() else {
+            panic!("expected PUB");
+        };
+        assert!(buffer.contains(&(body.as_ptr() as usize)));
+        let Some(Command::Fin { message_id }) = decoder.decode(&mut src).unwrap() else {
+            panic!("expected FIN");
+        };
+        assert_eq!(message_id, Bytes::from("0123456789abcdef"));
+        assert!(buffer.contains(&(message_id.as_ptr() as usize)));
+    }
+    
+    #[test]
+    fn test_response_matcher() {
+        let response = |body: &'static str| Frame::new(FrameType::Response, Bytes::from(body));
+        let mut matcher = ResponseMatcher::new(3);
+        for batch in 1..=3 {
+            matcher.push(batch);
+        }
+        assert!(matcher.is_full());
+        
+        assert!(matches!(matcher.match_frame(response("_heartbeat_")), PublishResponse::Heartbeat));
+        assert!(matches!(matcher.match_frame(response("OK")), PublishResponse::Ok(1)));
+        assert!(!matcher.is_full());
+        let error = Frame::new(FrameType::Error, Bytes::from("E_BAD_TOPIC invalid"));
+        assert!(matches!(matcher.match_frame(error), PublishResponse::Error(2, e) if e == "E_BAD_TOPIC invalid"));
+        matcher.push(4);
+        assert_eq!(matcher.drain().collect::<Vec<_>>(), vec![3, 4]);
+        assert!(matches!(matcher.match_frame(response("OK")), PublishResponse::Unexpected(_)));
+    }
+    
+    #[test]
+    fn test_command_encoder_sends_magic_once() {
+        let mut encoder = CommandEncoder::new();
+        let mut dst = BytesMut::new();
+        encoder.encode(Command::Nop, &mut dst).unwrap();
+        encoder.encode(Command::Nop, &mut dst).unwrap();
+        assert_eq!(&dst[..], b"  V2NOP\nNOP\n");
+    }
+    
+    #[test]
+    fn test_decoder_limits() {
+        let mut frames = BytesMut::from(&Frame::new(FrameType::Message, Bytes::from(vec![0u8; 17])).to_bytes()[..]);
+        assert!(NsqDecoder::with_max_frame_size(16).decode(&mut frames).is_err());
+        
+        let mut commands = BytesMut::from(&b"PUB test\n\x00\x00\x00\x11"[..]);
+        assert!(CommandDecoder::with_max_body_size(16).decode(&mut commands).is_err());
+        
+        // Truncated input is an error rather than a panic
+        assert!(Command::from_bytes(Bytes::from_static(b"PUB test\n\x00\x00\x00\x05hi")).is_err());
+        assert!(Command::from_bytes(Bytes::from_static(b"MPUB test\n\xff\xff\xff\xff")).is_err());
+        assert!(Command::from_bytes(Bytes::from_static(b"DPUB test\n\x00")).is_err());
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::parse(&format!("{} reason", code)), Some(code));
+        }
+        assert_eq!(ErrorCode::parse("E_FIN_FAILED"), Some(ErrorCode::FinFailed));
+        assert_eq!(ErrorCode::parse("E_UNKNOWN reason"), None);
+        assert_eq!(ErrorCode::parse(""), None);
+
+        assert_eq!(ErrorCode::BadTopic.http_status(), 400);
+        assert_eq!(ErrorCode::TopicNotFound.http_status(), 404);
+        assert_eq!(ErrorCode::RateLimit.http_status(), 429);
+        assert_eq!(ErrorCode::PubFailed.http_status(), 500);
+
+        assert_eq!(ProtocolError::InvalidMessage("short".to_string()).code(), ErrorCode::BadMessage);
+        assert_eq!(ProtocolError::InvalidCommand("FOO".to_string()).code(), ErrorCode::Invalid);
+    }
+
+    #[test]
+    fn test_identify_response() {
+        assert_eq!(IdentifyResponse::parse(b"OK").unwrap(), None);
+        assert!(IdentifyResponse::parse(b"not json").is_err());
+
+        let negotiated = IdentifyResponse::parse(br#"{"max_rdy_count":100,"msg_timeout":30000,"zstd":true,"zstd_level":5,"extra":1}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(negotiated.max_rdy_count, 100);
+        assert_eq!(negotiated.msg_timeout, 30000);
+        assert!(negotiated.zstd && !negotiated.snappy && !negotiated.tls_v1);
+        assert_eq!(negotiated.zstd_level, 5);
+    }
+
+    /// Topic, channel and message ID tokens as they appear on a command line
+    fn token() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9_.#-]{1,64}"
+    }
+    
+    fn body() -> impl Strategy<Value = Bytes> {
+        vec(any::<u8>(), 0..512).prop_map(Bytes::from)
+    }
+    
+    fn command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            (token(), body()).prop_map(|(topic, body)| Command::Pub { topic, body }),
+            (token(), vec(body(), 0..8)).prop_map(|(topic, bodies)| Command::Mpub { topic, bodies }),
+            (token(), any::<u64>(), body()).prop_map(|(topic, delay, body)| Command::Dpub { topic, delay, body }),
+            (token(), token()).prop_map(|(topic, channel)| Command::Sub { topic, channel }),
+            any::<u32>().prop_map(|count| Command::Rdy { count }),
+            token().prop_map(|id| Command::Fin { message_id: Bytes::from(id) }),
+            (token(), any::<u64>()).prop_map(|(id, timeout)| Command::Req { message_id: Bytes::from(id), timeout }),
+            token().prop_map(|id| Command::Touch { message_id: Bytes::from(id) }),
+            (token(), any::<bool>(), any::<u32>()).prop_map(|(hostname, zstd, max_rdy_count)| Command::Identify {
+                data: serde_json::json!({"hostname": hostname, "zstd": zstd, "max_rdy_count": max_rdy_count}),
+            }),
+            ".{0,64}".prop_map(|secret| Command::Auth { secret }),
+            Just(Command::Nop),
+            Just(Command::Close),
+        ]
+    }
+    
+    proptest! {
+        #[test]
+        fn prop_command_round_trip(commands in vec(command(), 1..8), chunk in 1usize..64) {
+            let mut wire = BytesMut::new();
+            let mut encoder = CommandEncoder::without_magic();
+            for command in &commands {
+                encoder.encode(command.clone(), &mut wire).unwrap();
+            }
+            
+            // Feed the stream in chunks so commands arrive split at any offset
+            let mut decoder = CommandDecoder::new();
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for piece in wire.chunks(chunk) {
+                src.extend_from_slice(piece);
+                while let Some(command) = decoder.decode(&mut src).unwrap() {
+                    decoded.push(command);
+                }
+            }
+            prop_assert!(src.is_empty());
+            prop_assert_eq!(decoded, commands);
+        }
+        
+        #[test]
+        fn prop_frame_round_trip(bodies in vec(body(), 1..8), chunk in 1usize..64) {
+            let mut wire = BytesMut::new();
+            for body in &bodies {
+                NsqEncoder.encode(Frame::new(FrameType::Message, body.clone()), &mut wire).unwrap();
+            }
+            
+            let mut decoder = NsqDecoder::new();
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for piece in wire.chunks(chunk) {
+                src.extend_from_slice(piece);
+                while let Some(frame) = decoder.decode(&mut src).unwrap() {
+                    decoded.push(frame.body);
+                }
+            }
+            prop_assert_eq!(decoded, bodies);
+        }
+        
+        #[test]
+        fn prop_message_round_trip(attempts in 0u16..0x8000, body in body(), headers in btree_map(token(), ".{0,32}", 0..4)) {
+            let mut message = Message::new(body.clone()).with_headers(headers.clone());
+            message.attempts = attempts;
+            
+            let decoded = Message::from_bytes(message.to_bytes_with_headers()).unwrap();
+            prop_assert_eq!(decoded.id, message.id);
+            prop_assert_eq!(decoded.attempts, attempts);
+            prop_assert_eq!(decoded.headers, headers);
+            prop_assert_eq!(decoded.body, body);
+        }
+        
+        #[test]
+        fn prop_decoders_reject_garbage_without_panicking(data in vec(any::<u8>(), 0..256)) {
+            let mut src = BytesMut::from(&data[..]);
+            let mut commands = CommandDecoder::with_max_body_size(1024);
+            while let Ok(Some(_)) = commands.decode(&mut src) {}
+            
+            let mut src = BytesMut::from(&data[..]);
+            let mut frames = NsqDecoder::with_max_frame_size(1024);
+            while let Ok(Some(_)) = frames.decode(&mut src) {}
+            
+            let _ = Command::from_bytes(Bytes::from(data.clone()));
+            let _ = Message::from_bytes(Bytes::from(data));
+        }
+    }
 }
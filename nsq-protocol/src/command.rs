@@ -3,7 +3,7 @@
 //! Commands are sent over the wire protocol to control NSQ behavior
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-// use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use crate::errors::{ProtocolError, Result};
 
 /// NSQ Commands
@@ -123,25 +123,34 @@ impl Command {
     }
     
     /// Deserialize command from bytes
+    ///
+    /// Bodies and message IDs are slices of `data` rather than copies.
+    /// Truncated or inconsistent input is an error, never a panic.
     pub fn from_bytes(mut data: Bytes) -> Result<Self> {
         // Find the end of the command line
-        let line_end = data.windows(1).position(|w| w[0] == b'\n')
+        let line_end = data.iter().position(|b| *b == b'\n')
             .ok_or_else(|| ProtocolError::InvalidCommand("Missing newline".to_string()))?;
         
         let command_line = data.split_to(line_end + 1);
         let command_str = std::str::from_utf8(&command_line[..line_end])
             .map_err(|e| ProtocolError::InvalidCommand(e.to_string()))?;
+        // Slice an argument out of the command line without copying it
+        let slice_arg = |arg: &str| {
+            let start = arg.as_ptr() as usize - command_str.as_ptr() as usize;
+            command_line.slice(start..start + arg.len())
+        };
         
         let parts: Vec<&str> = command_str.split_whitespace().collect();
+        let name = parts.first()
+            .ok_or_else(|| ProtocolError::InvalidCommand("Empty command".to_string()))?;
         
-        match parts[0] {
+        match *name {
             "PUB" => {
                 if parts.len() != 2 {
                     return Err(ProtocolError::InvalidCommand("Invalid PUB command".to_string()));
                 }
                 let topic = parts[1].to_string();
-                let body_len = data.get_u32() as usize;
-                let body = data.split_to(body_len);
+                let body = take_sized(&mut data)?;
                 Ok(Command::Pub { topic, body })
             }
             
@@ -150,11 +159,14 @@ impl Command {
                     return Err(ProtocolError::InvalidCommand("Invalid MPUB command".to_string()));
                 }
                 let topic = parts[1].to_string();
-                let count = data.get_u32() as usize;
+                let count = take_u32(&mut data)? as usize;
+                // Each body takes at least its 4 byte length
+                if count > data.len() / 4 {
+                    return Err(ProtocolError::InvalidCommand(format!("MPUB count {} exceeds body", count)));
+                }
                 let mut bodies = Vec::with_capacity(count);
                 for _ in 0..count {
-                    let body_len = data.get_u32() as usize;
-                    bodies.push(data.split_to(body_len));
+                    bodies.push(take_sized(&mut data)?);
                 }
                 Ok(Command::Mpub { topic, bodies })
             }
@@ -164,9 +176,11 @@ impl Command {
                     return Err(ProtocolError::InvalidCommand("Invalid DPUB command".to_string()));
                 }
                 let topic = parts[1].to_string();
+                if data.len() < 8 {
+                    return Err(ProtocolError::InvalidCommand("Truncated DPUB command".to_string()));
+                }
                 let delay = data.get_u64();
-                let body_len = data.get_u32() as usize;
-                let body = data.split_to(body_len);
+                let body = take_sized(&mut data)?;
                 Ok(Command::Dpub { topic, delay, body })
             }
             
@@ -192,7 +206,7 @@ impl Command {
                 if parts.len() != 2 {
                     return Err(ProtocolError::InvalidCommand("Invalid FIN command".to_string()));
                 }
-                let message_id = Bytes::from(parts[1].as_bytes().to_vec());
+                let message_id = slice_arg(parts[1]);
                 Ok(Command::Fin { message_id })
             }
             
@@ -200,7 +214,7 @@ impl Command {
                 if parts.len() != 3 {
                     return Err(ProtocolError::InvalidCommand("Invalid REQ command".to_string()));
                 }
-                let message_id = Bytes::from(parts[1].as_bytes().to_vec());
+                let message_id = slice_arg(parts[1]);
                 let timeout = parts[2].parse::<u64>()
                     .map_err(|e| ProtocolError::InvalidCommand(e.to_string()))?;
                 Ok(Command::Req { message_id, timeout })
@@ -210,21 +224,19 @@ impl Command {
                 if parts.len() != 2 {
                     return Err(ProtocolError::InvalidCommand("Invalid TOUCH command".to_string()));
                 }
-                let message_id = Bytes::from(parts[1].as_bytes().to_vec());
+                let message_id = slice_arg(parts[1]);
                 Ok(Command::Touch { message_id })
             }
             
             "IDENTIFY" => {
-                let data_len = data.get_u32() as usize;
-                let data_bytes = data.split_to(data_len);
+                let data_bytes = take_sized(&mut data)?;
                 let data = serde_json::from_slice(&data_bytes)
                     .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
                 Ok(Command::Identify { data })
             }
             
             "AUTH" => {
-                let secret_len = data.get_u32() as usize;
-                let secret_bytes = data.split_to(secret_len);
+                let secret_bytes = take_sized(&mut data)?;
                 let secret = String::from_utf8(secret_bytes.to_vec())
                     .map_err(|e| ProtocolError::InvalidCommand(e.to_string()))?;
                 Ok(Command::Auth { secret })
@@ -233,7 +245,7 @@ impl Command {
             "NOP" => Ok(Command::Nop),
             "CLS" => Ok(Command::Close),
             
-            _ => Err(ProtocolError::InvalidCommand(format!("Unknown command: {}", parts[0]))),
+            _ => Err(ProtocolError::InvalidCommand(format!("Unknown command: {}", name))),
         }
     }
     
@@ -255,3 +267,56 @@ impl Command {
         }
     }
 }
+
+/// Settings nsqd negotiated for a connection, sent in answer to an IDENTIFY
+/// with `feature_negotiation`; durations are in milliseconds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdentifyResponse {
+    pub max_rdy_count: u32,
+    pub version: String,
+    pub max_msg_timeout: u64,
+    pub msg_timeout: u64,
+    pub tls_v1: bool,
+    pub deflate: bool,
+    pub deflate_level: i32,
+    pub max_deflate_level: i32,
+    pub snappy: bool,
+    pub sample_rate: u32,
+    pub auth_required: bool,
+    pub output_buffer_size: usize,
+    pub output_buffer_timeout: u64,
+    /// zstd stream compression (extension, not part of upstream NSQ)
+    pub zstd: bool,
+    pub zstd_level: i32,
+}
+
+impl IdentifyResponse {
+    /// Parse the body of the response to an IDENTIFY, which is a bare `OK`
+    /// when nothing was negotiated
+    pub fn parse(body: &[u8]) -> Result<Option<Self>> {
+        if body == b"OK" {
+            return Ok(None);
+        }
+        serde_json::from_slice(body)
+            .map(Some)
+            .map_err(|e| ProtocolError::Serialization(format!("invalid IDENTIFY response: {}", e)))
+    }
+}
+
+/// Read a 4 byte big-endian integer
+fn take_u32(data: &mut Bytes) -> Result<u32> {
+    if data.len() < 4 {
+        return Err(ProtocolError::InvalidCommand("Truncated length".to_string()));
+    }
+    Ok(data.get_u32())
+}
+
+/// Split off a body given by a 4 byte length prefix
+fn take_sized(data: &mut Bytes) -> Result<Bytes> {
+    let len = take_u32(data)? as usize;
+    if data.len() < len {
+        return Err(ProtocolError::InvalidCommand(format!("Body of {} bytes truncated to {}", len, data.len())));
+    }
+    Ok(data.split_to(len))
+}
@@ -9,25 +9,32 @@ pub enum CompressionType {
     None,
     Deflate,
     Snappy,
+    Zstd,
 }
 
-impl CompressionType {
+impl std::str::FromStr for CompressionType {
+    type Err = ProtocolError;
+    
     /// Get compression type from string
-    pub fn from_str(s: &str) -> Result<Self> {
+    fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
             "none" | "" => Ok(CompressionType::None),
             "deflate" => Ok(CompressionType::Deflate),
             "snappy" => Ok(CompressionType::Snappy),
+            "zstd" => Ok(CompressionType::Zstd),
             _ => Err(ProtocolError::Compression(format!("Unknown compression type: {}", s))),
         }
     }
-    
+}
+
+impl CompressionType {
     /// Get string representation
     pub fn as_str(&self) -> &'static str {
         match self {
             CompressionType::None => "none",
             CompressionType::Deflate => "deflate",
             CompressionType::Snappy => "snappy",
+            CompressionType::Zstd => "zstd",
         }
     }
 }
@@ -58,6 +65,13 @@ pub fn compress(data: &[u8], compression: CompressionType) -> Result<Bytes> {
             
             Ok(Bytes::from(compressed))
         }
+        
+        CompressionType::Zstd => {
+            let compressed = zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?;
+            
+            Ok(Bytes::from(compressed))
+        }
     }
 }
 
@@ -85,6 +99,13 @@ pub fn decompress(data: &[u8], compression: CompressionType) -> Result<Bytes> {
             
             Ok(Bytes::from(decompressed))
         }
+        
+        CompressionType::Zstd => {
+            let decompressed = zstd::decode_all(data)
+                .map_err(|e| ProtocolError::Compression(e.to_string()))?;
+            
+            Ok(Bytes::from(decompressed))
+        }
     }
 }
 
@@ -99,6 +120,11 @@ pub fn detect_compression(data: &[u8]) -> CompressionType {
         return CompressionType::Deflate;
     }
     
+    // Check for zstd frame magic number
+    if data[..4] == [0x28, 0xb5, 0x2f, 0xfd] {
+        return CompressionType::Zstd;
+    }
+    
     // Check for snappy magic bytes
     if data.len() >= 4 && data[0] == b's' && data[1] == b'N' && data[2] == b'a' && data[3] == b'P' {
         return CompressionType::Snappy;
@@ -1,7 +1,105 @@
 //! Protocol error types
 
+use std::fmt;
 use thiserror::Error;
 
+/// Error codes nsqd starts its error frames with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Invalid,
+    BadTopic,
+    BadChannel,
+    BadMessage,
+    BadBody,
+    PubFailed,
+    MpubFailed,
+    FinFailed,
+    ReqFailed,
+    TouchFailed,
+    RateLimit,
+    TopicNotFound,
+    ChannelNotFound,
+    AuthFailed,
+    Unauthorized,
+    TooManyConnections,
+}
+
+impl ErrorCode {
+    /// Every code
+    pub const ALL: [ErrorCode; 16] = [
+        ErrorCode::Invalid,
+        ErrorCode::BadTopic,
+        ErrorCode::BadChannel,
+        ErrorCode::BadMessage,
+        ErrorCode::BadBody,
+        ErrorCode::PubFailed,
+        ErrorCode::MpubFailed,
+        ErrorCode::FinFailed,
+        ErrorCode::ReqFailed,
+        ErrorCode::TouchFailed,
+        ErrorCode::RateLimit,
+        ErrorCode::TopicNotFound,
+        ErrorCode::ChannelNotFound,
+        ErrorCode::AuthFailed,
+        ErrorCode::Unauthorized,
+        ErrorCode::TooManyConnections,
+    ];
+    
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Invalid => "E_INVALID",
+            ErrorCode::BadTopic => "E_BAD_TOPIC",
+            ErrorCode::BadChannel => "E_BAD_CHANNEL",
+            ErrorCode::BadMessage => "E_BAD_MESSAGE",
+            ErrorCode::BadBody => "E_BAD_BODY",
+            ErrorCode::PubFailed => "E_PUB_FAILED",
+            ErrorCode::MpubFailed => "E_MPUB_FAILED",
+            ErrorCode::FinFailed => "E_FIN_FAILED",
+            ErrorCode::ReqFailed => "E_REQ_FAILED",
+            ErrorCode::TouchFailed => "E_TOUCH_FAILED",
+            ErrorCode::RateLimit => "E_RATE_LIMIT",
+            ErrorCode::TopicNotFound => "E_TOPIC_NOT_FOUND",
+            ErrorCode::ChannelNotFound => "E_CHANNEL_NOT_FOUND",
+            ErrorCode::AuthFailed => "E_AUTH_FAILED",
+            ErrorCode::Unauthorized => "E_UNAUTHORIZED",
+            ErrorCode::TooManyConnections => "E_TOO_MANY_CONNECTIONS",
+        }
+    }
+    
+    /// Code at the start of the text of an error frame
+    pub fn parse(error: &str) -> Option<Self> {
+        let code = error.split_whitespace().next()?;
+        Self::ALL.into_iter().find(|candidate| candidate.as_str() == code)
+    }
+    
+    /// HTTP status of a request that failed with this code
+    pub fn http_status(self) -> u16 {
+        match self {
+            ErrorCode::Invalid
+            | ErrorCode::BadTopic
+            | ErrorCode::BadChannel
+            | ErrorCode::BadMessage
+            | ErrorCode::BadBody => 400,
+            ErrorCode::AuthFailed => 401,
+            ErrorCode::Unauthorized => 403,
+            ErrorCode::TopicNotFound | ErrorCode::ChannelNotFound => 404,
+            ErrorCode::RateLimit => 429,
+            ErrorCode::PubFailed
+            | ErrorCode::MpubFailed
+            | ErrorCode::FinFailed
+            | ErrorCode::ReqFailed
+            | ErrorCode::TouchFailed => 500,
+            ErrorCode::TooManyConnections => 503,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ProtocolError {
     #[error("Invalid frame size: {0}")]
@@ -29,4 +127,14 @@ pub enum ProtocolError {
     Utf8(#[from] std::str::Utf8Error),
 }
 
+impl ProtocolError {
+    /// Code nsqd reports for a command it could not decode
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::InvalidMessage(_) => ErrorCode::BadMessage,
+            _ => ErrorCode::Invalid,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ProtocolError>;
@@ -4,6 +4,7 @@
 
 pub mod command;
 pub mod message;
+pub mod message_id;
 pub mod frame;
 pub mod codec;
 pub mod compression;
@@ -11,6 +12,7 @@ pub mod errors;
 
 pub use command::*;
 pub use message::*;
+pub use message_id::*;
 pub use frame::*;
 pub use codec::*;
 pub use compression::*;
@@ -1,58 +1,110 @@
 //! NSQ Message implementation
 
+use std::collections::BTreeMap;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 use crate::errors::{ProtocolError, Result};
+use crate::message_id::{MessageId, MESSAGE_ID_LENGTH};
+
+/// Key/value metadata carried alongside a message body, such as trace IDs,
+/// content type or producer ID
+pub type MessageHeaders = BTreeMap<String, String>;
+
+/// Most headers a message may carry
+pub const MAX_HEADERS: usize = 64;
+/// Longest header key in bytes
+pub const MAX_HEADER_KEY_LEN: usize = 256;
+/// Longest header value in bytes
+pub const MAX_HEADER_VALUE_LEN: usize = 4096;
+/// Largest encoded header block of a valid message
+pub const MAX_HEADER_BLOCK_SIZE: usize = 2 + MAX_HEADERS * (4 + MAX_HEADER_KEY_LEN + MAX_HEADER_VALUE_LEN);
+
+/// Set in the encoded attempts field when a header block follows it; the
+/// attempts count itself saturates at `MAX_ENCODED_ATTEMPTS` on the wire
+const HEADERS_FLAG: u16 = 0x8000;
+const MAX_ENCODED_ATTEMPTS: u16 = HEADERS_FLAG - 1;
 
 /// NSQ Message structure
 #[derive(Debug, Clone)]
 pub struct Message {
     /// Unique message ID
-    pub id: Uuid,
+    pub id: MessageId,
     /// Message timestamp
     pub timestamp: DateTime<Utc>,
     /// Number of delivery attempts
     pub attempts: u16,
     /// Message body
     pub body: Bytes,
+    /// Headers (extension, only sent to clients that negotiated `message_headers`)
+    pub headers: MessageHeaders,
 }
 
 impl Message {
     /// Create a new message
     pub fn new(body: Bytes) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id: MessageId::generate(),
             timestamp: Utc::now(),
             attempts: 0,
             body,
+            headers: MessageHeaders::new(),
         }
     }
     
+    /// Replace the generated ID
+    pub fn with_id(mut self, id: MessageId) -> Self {
+        self.id = id;
+        self
+    }
+    
+    /// Attach headers to the message
+    pub fn with_headers(mut self, headers: MessageHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+    
     /// Create a message with specific ID and timestamp
-    pub fn with_metadata(id: Uuid, timestamp: DateTime<Utc>, attempts: u16, body: Bytes) -> Self {
+    pub fn with_metadata(id: MessageId, timestamp: DateTime<Utc>, attempts: u16, body: Bytes) -> Self {
         Self {
             id,
             timestamp,
             attempts,
             body,
+            headers: MessageHeaders::new(),
         }
     }
     
-    /// Serialize message to bytes for wire protocol
+    /// Serialize message to bytes for wire protocol, without headers
     pub fn to_bytes(&self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(16 + 8 + 2 + self.body.len());
-        
-        // Message ID (16 bytes)
-        buf.put_slice(self.id.as_bytes());
+        self.encode(false)
+    }
+    
+    /// Serialize message to bytes in the extended format that carries its
+    /// headers; identical to `to_bytes` for a message without headers
+    pub fn to_bytes_with_headers(&self) -> Bytes {
+        self.encode(true)
+    }
+    
+    fn encode(&self, with_headers: bool) -> Bytes {
+        let with_headers = with_headers && !self.headers.is_empty();
+        let header_len = if with_headers { headers_len(&self.headers) } else { 0 };
+        let mut buf = BytesMut::with_capacity(16 + 8 + 2 + header_len + self.body.len());
         
         // Timestamp (8 bytes, nanoseconds since epoch)
         let timestamp_ns = self.timestamp.timestamp_nanos_opt().unwrap_or(0) as u64;
         buf.put_u64(timestamp_ns);
         
-        // Attempts (2 bytes)
-        buf.put_u16(self.attempts);
+        // Attempts (2 bytes), flagged when headers follow the ID
+        let attempts = self.attempts.min(MAX_ENCODED_ATTEMPTS);
+        buf.put_u16(if with_headers { attempts | HEADERS_FLAG } else { attempts });
+        
+        // Message ID (16 bytes)
+        buf.put_slice(self.id.as_bytes());
+        
+        if with_headers {
+            put_headers(&mut buf, &self.headers);
+        }
         
         // Body
         buf.put_slice(&self.body);
@@ -66,17 +118,18 @@ impl Message {
             return Err(ProtocolError::InvalidMessage("Message too short".to_string()));
         }
         
-        // Message ID (16 bytes)
-        let id_bytes = data.split_to(16);
-        let id = Uuid::from_slice(&id_bytes)
-            .map_err(|e| ProtocolError::InvalidMessage(format!("Invalid UUID: {}", e)))?;
-        
         // Timestamp (8 bytes)
         let timestamp_ns = data.get_u64();
         let timestamp = DateTime::from_timestamp_nanos(timestamp_ns as i64);
         
-        // Attempts (2 bytes)
+        // Attempts (2 bytes), then the message ID (16 bytes) and headers when flagged
         let attempts = data.get_u16();
+        let id = MessageId::from_slice(&data.split_to(MESSAGE_ID_LENGTH))?;
+        let headers = if attempts & HEADERS_FLAG != 0 {
+            get_headers(&mut data)?
+        } else {
+            MessageHeaders::new()
+        };
         
         // Body (remaining bytes)
         let body = data;
@@ -84,15 +137,92 @@ impl Message {
         Ok(Self {
             id,
             timestamp,
-            attempts,
+            attempts: attempts & !HEADERS_FLAG,
             body,
+            headers,
         })
     }
     
     /// Get message size in bytes
     pub fn size(&self) -> usize {
-        16 + 8 + 2 + self.body.len()
+        let header_len = if self.headers.is_empty() { 0 } else { headers_len(&self.headers) };
+        16 + 8 + 2 + header_len + self.body.len()
+    }
+}
+
+/// Check that headers are within the limits of the header block
+pub fn validate_headers(headers: &MessageHeaders) -> Result<()> {
+    if headers.len() > MAX_HEADERS {
+        return Err(ProtocolError::InvalidMessage(format!("too many headers: {} > {}", headers.len(), MAX_HEADERS)));
+    }
+    for (key, value) in headers {
+        if key.is_empty() || key.len() > MAX_HEADER_KEY_LEN {
+            return Err(ProtocolError::InvalidMessage(format!("header key '{}' must be 1-{} bytes", key, MAX_HEADER_KEY_LEN)));
+        }
+        if value.len() > MAX_HEADER_VALUE_LEN {
+            return Err(ProtocolError::InvalidMessage(format!("header '{}' value longer than {} bytes", key, MAX_HEADER_VALUE_LEN)));
+        }
+    }
+    Ok(())
+}
+
+/// Prefix a published body with a header block; the form PUB and MPUB bodies
+/// take from clients that negotiated `message_headers`
+pub fn encode_with_headers(headers: &MessageHeaders, body: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(headers_len(headers) + body.len());
+    put_headers(&mut buf, headers);
+    buf.put_slice(body);
+    buf.freeze()
+}
+
+/// Split a published body sent by a client that negotiated `message_headers`
+/// into its headers and the message body
+pub fn decode_with_headers(mut data: Bytes) -> Result<(MessageHeaders, Bytes)> {
+    let headers = get_headers(&mut data)?;
+    Ok((headers, data))
+}
+
+/// Encoded size of a header block
+fn headers_len(headers: &MessageHeaders) -> usize {
+    2 + headers.iter().map(|(key, value)| 4 + key.len() + value.len()).sum::<usize>()
+}
+
+/// Header block: count (2 bytes), then for each header the key and value,
+/// each as length (2 bytes) and UTF-8 bytes
+fn put_headers(buf: &mut BytesMut, headers: &MessageHeaders) {
+    buf.put_u16(headers.len() as u16);
+    for (key, value) in headers {
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key.as_bytes());
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(value.as_bytes());
+    }
+}
+
+fn get_headers(data: &mut Bytes) -> Result<MessageHeaders> {
+    let get_string = |data: &mut Bytes| -> Result<String> {
+        if data.len() < 2 {
+            return Err(ProtocolError::InvalidMessage("Truncated header block".to_string()));
+        }
+        let len = data.get_u16() as usize;
+        if data.len() < len {
+            return Err(ProtocolError::InvalidMessage("Truncated header block".to_string()));
+        }
+        Ok(std::str::from_utf8(&data.split_to(len))?.to_string())
+    };
+    
+    if data.len() < 2 {
+        return Err(ProtocolError::InvalidMessage("Missing header block".to_string()));
+    }
+    let count = data.get_u16() as usize;
+    let mut headers = MessageHeaders::new();
+    for _ in 0..count {
+        let key = get_string(data)?;
+        let value = get_string(data)?;
+        headers.insert(key, value);
     }
+    validate_headers(&headers)?;
+    Ok(headers)
 }
 
 /// Message statistics
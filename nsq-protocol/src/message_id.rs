@@ -0,0 +1,245 @@
+//! Message IDs
+//!
+//! nsqd identifies messages by 16 bytes that it sends with every message and
+//! that clients echo back verbatim in FIN, REQ and TOUCH. Like the original
+//! nsqd the bytes are the lowercase hex digits of a 64-bit GUID made of a
+//! millisecond timestamp, a worker ID and a sequence number, so IDs are
+//! printable, sort by creation time and never repeat within a process.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::errors::{ProtocolError, Result};
+
+/// Length of a message ID on the wire
+pub const MESSAGE_ID_LENGTH: usize = 16;
+/// Highest worker ID that fits in a GUID
+pub const MAX_WORKER_ID: u16 = (1 << WORKER_ID_BITS) - 1;
+
+const WORKER_ID_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const SEQUENCE_MASK: u64 = (1 << SEQUENCE_BITS) - 1;
+/// Milliseconds since the Unix epoch that GUID timestamps count from, the
+/// same epoch the original nsqd uses
+const GUID_EPOCH_MS: u64 = 1_288_834_974_657;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+static GUID_FACTORY: GuidFactory = GuidFactory::new(0);
+
+/// 16-byte message ID
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId([u8; MESSAGE_ID_LENGTH]);
+
+impl MessageId {
+    /// Generate a new ID from a process-wide GUID factory with worker ID 0;
+    /// nodes stamp their messages from a `GuidFactory` of their own
+    pub fn generate() -> Self {
+        GUID_FACTORY.next_id()
+    }
+
+    /// ID written as the hex digits of a GUID
+    pub fn from_guid(guid: u64) -> Self {
+        let mut id = [0u8; MESSAGE_ID_LENGTH];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = HEX_DIGITS[((guid >> (60 - 4 * i)) & 0xf) as usize];
+        }
+        Self(id)
+    }
+
+    /// ID as received on the wire. Any 16 bytes are accepted, as clients
+    /// only ever echo IDs back
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let id = bytes.try_into().map_err(|_| {
+            ProtocolError::InvalidMessage(format!("message ID must be {} bytes, got {}", MESSAGE_ID_LENGTH, bytes.len()))
+        })?;
+        Ok(Self(id))
+    }
+
+    /// The bytes sent on the wire
+    pub fn as_bytes(&self) -> &[u8; MESSAGE_ID_LENGTH] {
+        &self.0
+    }
+
+    /// GUID the ID was generated from, unless it is not made of hex digits
+    pub fn guid(&self) -> Option<u64> {
+        std::str::from_utf8(&self.0).ok().and_then(|id| u64::from_str_radix(id, 16).ok())
+    }
+
+    /// Milliseconds since the Unix epoch at which a GUID ID was generated
+    pub fn timestamp_ms(&self) -> Option<u64> {
+        self.guid().map(|guid| (guid >> (WORKER_ID_BITS + SEQUENCE_BITS)) + GUID_EPOCH_MS)
+    }
+}
+
+impl fmt::Display for MessageId {
+    /// The 16 characters of a printable ID, or the hex encoding of any other
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.iter().all(|byte| byte.is_ascii_graphic()) {
+            f.write_str(std::str::from_utf8(&self.0).map_err(|_| fmt::Error)?)
+        } else {
+            self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+        }
+    }
+}
+
+impl fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageId({})", self)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ProtocolError;
+
+    /// Parse the 16 characters a client sends back, or the hex encoding
+    /// `Display` gives IDs that are not printable
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() == 2 * MESSAGE_ID_LENGTH {
+            let mut id = [0u8; MESSAGE_ID_LENGTH];
+            for (i, byte) in id.iter_mut().enumerate() {
+                *byte = s.get(2 * i..2 * i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| ProtocolError::InvalidMessage(format!("invalid message ID: {}", s)))?;
+            }
+            return Ok(Self(id));
+        }
+        Self::from_slice(s.as_bytes())
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Generates GUID message IDs. The timestamp never goes backwards and a
+/// millisecond that runs out of sequence numbers borrows the next one, so IDs
+/// stay unique and increasing under any publish rate or clock step.
+#[derive(Debug)]
+pub struct GuidFactory {
+    worker_id: AtomicU16,
+    /// Timestamp and sequence of the last ID, packed as in the GUID
+    last: AtomicU64,
+}
+
+impl GuidFactory {
+    /// Factory stamping `worker_id` into its IDs; it must not exceed
+    /// `MAX_WORKER_ID`
+    pub const fn new(worker_id: u16) -> Self {
+        Self {
+            worker_id: AtomicU16::new(worker_id & MAX_WORKER_ID),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Change the worker ID of later IDs
+    pub fn set_worker_id(&self, worker_id: u16) -> Result<()> {
+        if worker_id > MAX_WORKER_ID {
+            return Err(ProtocolError::InvalidMessage(format!("worker ID must be at most {}, got {}", MAX_WORKER_ID, worker_id)));
+        }
+        self.worker_id.store(worker_id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Worker ID stamped into IDs
+    pub fn worker_id(&self) -> u16 {
+        self.worker_id.load(Ordering::Relaxed)
+    }
+
+    /// Next GUID
+    pub fn next_guid(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let now = now.saturating_sub(GUID_EPOCH_MS) << SEQUENCE_BITS;
+        let mut last = self.last.load(Ordering::Relaxed);
+        let next = loop {
+            let next = if now > last { now } else { last + 1 };
+            match self.last.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break next,
+                Err(current) => last = current,
+            }
+        };
+        (next >> SEQUENCE_BITS) << (WORKER_ID_BITS + SEQUENCE_BITS)
+            | (self.worker_id() as u64) << SEQUENCE_BITS
+            | next & SEQUENCE_MASK
+    }
+
+    /// Next message ID
+    pub fn next_id(&self) -> MessageId {
+        MessageId::from_guid(self.next_guid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_ids_are_hex_guids_like_nsqd() {
+        let id = MessageId::from_guid(0x05d6_c7a1_b2c0_0001);
+        assert_eq!(id.as_bytes(), b"05d6c7a1b2c00001");
+        assert_eq!(id.to_string(), "05d6c7a1b2c00001");
+        assert_eq!(id.guid(), Some(0x05d6_c7a1_b2c0_0001));
+        assert_eq!("05d6c7a1b2c00001".parse::<MessageId>().unwrap(), id);
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"05d6c7a1b2c00001\"");
+
+        let generated = MessageId::generate();
+        assert!(generated.as_bytes().iter().all(|byte| byte.is_ascii_hexdigit() && !byte.is_ascii_uppercase()));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert!(generated.timestamp_ms().unwrap().abs_diff(now) < 60_000);
+    }
+
+    #[test]
+    fn test_ids_from_the_wire_must_be_16_bytes() {
+        assert!(MessageId::from_slice(b"0123456789abcdef").is_ok());
+        assert!(MessageId::from_slice(b"0123456789abcde").is_err());
+        assert!("0123456789abcdef0".parse::<MessageId>().is_err());
+
+        let opaque = MessageId::from_slice(&[0xff; 16]).unwrap();
+        assert_eq!(opaque.guid(), None);
+        assert_eq!(opaque.to_string(), "ff".repeat(16));
+        assert_eq!(opaque.to_string().parse::<MessageId>().unwrap(), opaque);
+    }
+
+    #[test]
+    fn test_factory_stamps_worker_id() {
+        let factory = GuidFactory::new(0);
+        assert!(factory.set_worker_id(MAX_WORKER_ID + 1).is_err());
+        factory.set_worker_id(0x2a5).unwrap();
+        let guid = factory.next_guid();
+        assert_eq!((guid >> SEQUENCE_BITS) & MAX_WORKER_ID as u64, 0x2a5);
+
+        let other = GuidFactory::new(0x2a6);
+        assert_ne!(factory.next_id(), other.next_id());
+    }
+
+    #[test]
+    fn test_factory_ids_are_unique_and_increasing_under_load() {
+        let factory = std::sync::Arc::new(GuidFactory::new(7));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let factory = factory.clone();
+            std::thread::spawn(move || (0..20_000).map(|_| factory.next_id()).collect::<Vec<_>>())
+        }).collect();
+
+        let mut seen = HashSet::new();
+        for thread in threads {
+            let ids = thread.join().unwrap();
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            for id in ids {
+                assert!(seen.insert(id), "duplicate ID {}", id);
+            }
+        }
+        assert_eq!(seen.len(), 80_000);
+    }
+}
@@ -1,17 +1,58 @@
 import { useState, useEffect } from 'react'
 import { LineChart, Line, XAxis, YAxis, CartesianGrid, Tooltip, ResponsiveContainer } from 'recharts'
 import { format } from 'date-fns'
+import { nsqadminApi } from '../utils/api'
 
 interface MessageRateData {
   timestamp: number
-  rate: number
-  count: number
+  // Null where Graphite has no data, leaving a gap in the line
+  rate: number | null
+  count: number | null
 }
 
-export function MessageRateChart() {
+interface MessageRateChartProps {
+  // Chart the recorded history of this topic instead of sample data
+  topic?: string
+  channel?: string
+}
+
+interface GraphSample {
+  timestamp: string
+  depth: number | null
+  rate: number | null
+}
+
+export function MessageRateChart({ topic, channel }: MessageRateChartProps = {}) {
   const [data, setData] = useState<MessageRateData[]>([])
 
   useEffect(() => {
+    if (!topic) {
+      return
+    }
+    
+    const fetchGraph = async () => {
+      try {
+        const graph = await nsqadminApi.getGraph(topic, channel)
+        setData(graph.samples.map((sample: GraphSample) => ({
+          timestamp: Date.parse(sample.timestamp),
+          rate: sample.rate === null ? null : Math.round(sample.rate),
+          count: sample.depth,
+        })))
+      } catch {
+        setData([])
+      }
+    }
+    
+    fetchGraph()
+    const interval = setInterval(fetchGraph, 10000)
+    return () => clearInterval(interval)
+  }, [topic, channel])
+
+  useEffect(() => {
+    if (topic) {
+      return
+    }
+    
     // Generate mock data for demonstration
     const generateData = () => {
       const now = Date.now()
@@ -53,7 +94,7 @@ export function MessageRateChart() {
     }, 1000)
 
     return () => clearInterval(interval)
-  }, [])
+  }, [topic])
 
   const formatTime = (timestamp: number) => {
     return format(new Date(timestamp), 'HH:mm:ss')
@@ -7,6 +7,7 @@ import {
   Pause,
   Play,
   Trash2,
+  Eraser,
   MoreHorizontal,
   X
 } from 'lucide-react'
@@ -14,6 +15,7 @@ import { useStats } from '../hooks/useStats'
 import { cn } from '../utils/cn'
 import toast from 'react-hot-toast'
 import { nsqadminApi } from '../utils/api'
+import { MessageRateChart } from './MessageRateChart'
 
 export function Topics() {
   const { stats, refetch } = useStats()
@@ -22,6 +24,7 @@ export function Topics() {
   const [showCreateDialog, setShowCreateDialog] = useState(false)
   const [newTopicName, setNewTopicName] = useState('')
   const [isCreating, setIsCreating] = useState(false)
+  const [graphTopic, setGraphTopic] = useState<string | null>(null)
 
   const filteredTopics = stats?.topics?.filter(topic => {
     const matchesSearch = topic.topic_name.toLowerCase().includes(searchTerm.toLowerCase())
@@ -77,6 +80,23 @@ export function Topics() {
     }
   }
 
+  const handleEmptyTopic = async (topicName: string) => {
+    if (window.confirm(`Empty all queued messages from topic "${topicName}"?`)) {
+      try {
+        const result = await nsqadminApi.emptyTopic(topicName)
+        const emptied = (result?.depth ?? 0) + (result?.backend_depth ?? 0)
+        if (result?.status === 'error') {
+          toast.error(result.message)
+        } else {
+          toast.success(`Topic "${topicName}" emptied (${emptied.toLocaleString()} messages)`)
+        }
+        setTimeout(() => refetch(), 500)
+      } catch (error) {
+        toast.error(`Failed to empty topic: ${error}`)
+      }
+    }
+  }
+
   const handleDeleteTopic = async (topicName: string) => {
     if (window.confirm(`Are you sure you want to delete topic "${topicName}"?`)) {
       try {
@@ -153,7 +173,11 @@ export function Topics() {
                 </div>
               </div>
               <div className="flex items-center space-x-2">
-                <button className="text-gray-400 hover:text-gray-600 dark:hover:text-gray-300">
+                <button
+                  onClick={() => setGraphTopic(graphTopic === topic.topic_name ? null : topic.topic_name)}
+                  className="text-gray-400 hover:text-gray-600 dark:hover:text-gray-300"
+                  title="Show message rate history"
+                >
                   <MoreHorizontal className="h-4 w-4" />
                 </button>
               </div>
@@ -185,6 +209,12 @@ export function Topics() {
               </div>
             </div>
 
+            {graphTopic === topic.topic_name && (
+              <div className="mb-4">
+                <MessageRateChart topic={topic.topic_name} />
+              </div>
+            )}
+
             <div className="flex items-center space-x-2">
               {topic.paused ? (
                 <button
@@ -203,6 +233,13 @@ export function Topics() {
                   Pause
                 </button>
               )}
+              <button
+                onClick={() => handleEmptyTopic(topic.topic_name)}
+                className="btn-secondary"
+                title="Empty topic"
+              >
+                <Eraser className="h-4 w-4" />
+              </button>
               <button
                 onClick={() => handleDeleteTopic(topic.topic_name)}
                 className="btn-danger"
@@ -1,7 +1,7 @@
-import { useState, useEffect, useCallback } from 'react'
+import { useState, useEffect, useCallback, useRef } from 'react'
 import { useAppStore } from '../stores/useAppStore'
 import { nsqdApi, lookupdApi, healthCheck, nsqadminApi } from '../utils/api'
-import type { Stats, LookupdStats } from '../types'
+import type { Stats, LookupdStats, Topic } from '../types'
 
 export function useStats() {
   const { nsqdAddress, lookupdAddress, refreshInterval, setIsConnected } = useAppStore()
@@ -9,6 +9,8 @@ export function useStats() {
   const [lookupdStats, setLookupdStats] = useState<LookupdStats | null>(null)
   const [loading, setLoading] = useState(true)
   const [error, setError] = useState<string | null>(null)
+  // Set while /api/stream pushes topic updates, replacing polling
+  const streaming = useRef(false)
 
   const fetchStats = useCallback(async () => {
     try {
@@ -71,10 +73,49 @@ export function useStats() {
   useEffect(() => {
     fetchStats()
     
-    const interval = setInterval(fetchStats, refreshInterval)
+    const interval = setInterval(() => {
+      if (!streaming.current) {
+        fetchStats()
+      }
+    }, refreshInterval)
     return () => clearInterval(interval)
   }, [fetchStats, refreshInterval])
 
+  useEffect(() => {
+    const source = nsqadminApi.streamStats()
+    
+    source.addEventListener('snapshot', (event) => {
+      const { topics } = JSON.parse((event as MessageEvent).data) as { topics: Topic[] }
+      streaming.current = true
+      setStats((current) => (current ? { ...current, topics } : current))
+    })
+    
+    source.addEventListener('delta', (event) => {
+      const delta = JSON.parse((event as MessageEvent).data) as { topics: Topic[]; removed: string[] }
+      setStats((current) => {
+        if (!current) {
+          return current
+        }
+        const changed = new Map(delta.topics.map((topic) => [topic.topic_name, topic]))
+        const topics = current.topics
+          .filter((topic) => !delta.removed.includes(topic.topic_name) && !changed.has(topic.topic_name))
+          .concat(delta.topics)
+          .sort((a, b) => a.topic_name.localeCompare(b.topic_name))
+        return { ...current, topics }
+      })
+    })
+    
+    // Fall back to polling until the browser reconnects
+    source.onerror = () => {
+      streaming.current = false
+    }
+    
+    return () => {
+      streaming.current = false
+      source.close()
+    }
+  }, [])
+
   return {
     stats,
     lookupdStats,
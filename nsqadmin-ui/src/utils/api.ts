@@ -125,18 +125,51 @@ export const nsqadminApi = {
     return response.data
   },
   
+  // Server-sent `snapshot` and `delta` events with aggregated topic stats
+  streamStats: (address: string = ''): EventSource => {
+    return new EventSource(`${address}/api/stream`)
+  },
+  
+  // Depth and message rate samples of a topic, or of one of its channels
+  getGraph: async (topic: string, channel?: string, address: string = ''): Promise<any> => {
+    const path = channel ? `${topic}/${channel}` : topic
+    const response = await api.get(`${address}/api/graphs/${path}`)
+    return response.data
+  },
+  
   getTopics: async (address: string = ''): Promise<any> => {
     const response = await api.get(`${address}/api/topics`)
     return response.data
   },
   
+  // Next messages of a topic or channel on one nsqd node, left unconsumed
+  peekMessages: async (topic: string, channel?: string, count: number = 10, address: string = ''): Promise<any> => {
+    const params = new URLSearchParams({ count: String(count) })
+    if (channel) params.set('channel', channel)
+    const response = await api.get(`${address}/api/topics/${topic}/peek?${params}`)
+    return response.data
+  },
+  
   getNodes: async (address: string = ''): Promise<any> => {
     const response = await api.get(`${address}/api/nodes`)
     return response.data
   },
   
-  createTopic: async (topic: string, address: string = ''): Promise<void> => {
-    await api.post(`${address}/api/topic/${topic}/create`)
+  // Topics, clients and memory/disk depth of one nsqd node (host:http_port)
+  getNode: async (node: string, address: string = ''): Promise<any> => {
+    const response = await api.get(`${address}/api/nodes/${node}`)
+    return response.data
+  },
+  
+  // Tombstone a node's registration of a topic on every lookupd
+  tombstoneTopic: async (node: string, topic: string, address: string = ''): Promise<any> => {
+    const response = await api.post(`${address}/api/nodes/${node}/tombstone?topic=${topic}`)
+    return response.data
+  },
+  
+  createTopic: async (topic: string, address: string = ''): Promise<any> => {
+    const response = await api.post(`${address}/api/topic/${topic}/create`)
+    return response.data
   },
   
   pauseTopic: async (topic: string, address: string = ''): Promise<void> => {
@@ -151,6 +184,11 @@ export const nsqadminApi = {
     await api.post(`${address}/api/topic/${topic}/delete`)
   },
   
+  emptyTopic: async (topic: string, address: string = ''): Promise<any> => {
+    const response = await api.post(`${address}/api/topic/${topic}/empty`)
+    return response.data
+  },
+  
   createChannel: async (topic: string, channel: string, address: string = ''): Promise<void> => {
     await api.post(`${address}/api/channel/${topic}/${channel}/create`)
   },
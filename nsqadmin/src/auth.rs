@@ -0,0 +1,187 @@
+//! Admin API authorization
+//!
+//! Viewing stats is open to everyone. Peeking at messages and requests that
+//! change topics or channels need a privileged user when authorization is configured: a user
+//! authenticated with HTTP Basic credentials from `--basic-auth-user`, or,
+//! when no Basic credentials are configured, the user named by an auth proxy
+//! in `--acl-http-header`. Either must be listed in `--admin-user` when that
+//! list is set.
+
+use std::collections::HashMap;
+use axum::http::{header, HeaderMap, HeaderName};
+use base64::Engine;
+use nsq_common::{NsqadminConfig, NsqError, Result};
+
+/// Why a request may not change the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// No user, or credentials that do not match a configured user
+    Unauthenticated,
+    /// A user that is not an admin
+    Forbidden(String),
+}
+
+/// Decides which users may use mutating admin endpoints
+#[derive(Debug, Clone)]
+pub struct AdminAuth {
+    acl_http_header: HeaderName,
+    admin_users: Vec<String>,
+    /// User -> password
+    basic_auth_users: HashMap<String, String>,
+}
+
+impl AdminAuth {
+    /// Build from the configured header, admin users and `user:password` pairs
+    pub fn new(config: &NsqadminConfig) -> Result<Self> {
+        let acl_http_header = HeaderName::from_bytes(config.acl_http_header.as_bytes())
+            .map_err(|_| NsqError::Config(format!("Invalid ACL HTTP header '{}'", config.acl_http_header)))?;
+        let basic_auth_users = config.basic_auth_users.iter()
+            .map(|entry| match entry.split_once(':') {
+                Some((user, password)) if !user.is_empty() => Ok((user.to_string(), password.to_string())),
+                _ => Err(NsqError::Config(format!("Invalid basic auth user '{}': expected user:password", entry))),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            acl_http_header,
+            admin_users: config.admin_users.clone(),
+            basic_auth_users,
+        })
+    }
+
+    /// Whether mutating endpoints are restricted at all
+    pub fn is_enabled(&self) -> bool {
+        !self.admin_users.is_empty() || !self.basic_auth_users.is_empty()
+    }
+
+    /// User authenticated by valid Basic credentials, if any were sent
+    fn basic_auth_user(&self, headers: &HeaderMap) -> std::result::Result<Option<String>, AuthError> {
+        let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+            return Ok(None);
+        };
+        let Some(encoded) = value.strip_prefix("Basic ") else {
+            return Ok(None);
+        };
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(AuthError::Unauthenticated)?;
+        match decoded.split_once(':') {
+            Some((user, password)) if self.basic_auth_users.get(user).is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes())) => {
+                Ok(Some(user.to_string()))
+            }
+            _ => Err(AuthError::Unauthenticated),
+        }
+    }
+
+    /// Check that a request may change the cluster, returning the acting user
+    pub fn authorize(&self, headers: &HeaderMap) -> std::result::Result<Option<String>, AuthError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        // With Basic credentials configured only they are trusted, since a
+        // client can set the proxy header itself; without them the proxy
+        // header is only trusted to name admins from the explicit list
+        let user = if !self.basic_auth_users.is_empty() {
+            self.basic_auth_user(headers)?
+        } else {
+            headers.get(&self.acl_http_header)
+                .and_then(|v| v.to_str().ok())
+                .filter(|user| !user.is_empty())
+                .map(str::to_string)
+        };
+
+        match user {
+            None => Err(AuthError::Unauthenticated),
+            Some(user) if self.admin_users.is_empty() || self.admin_users.contains(&user) => Ok(Some(user)),
+            Some(user) => Err(AuthError::Forbidden(user)),
+        }
+    }
+}
+
+/// Compare secrets without returning early at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn admin_auth(admin_users: &[&str], basic_auth_users: &[&str]) -> AdminAuth {
+        let config = NsqadminConfig {
+            admin_users: admin_users.iter().map(|user| user.to_string()).collect(),
+            basic_auth_users: basic_auth_users.iter().map(|entry| entry.to_string()).collect(),
+            ..Default::default()
+        };
+        AdminAuth::new(&config).unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", encoded)).unwrap());
+        headers
+    }
+
+    fn forwarded(user: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-user", HeaderValue::from_str(user).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_unconfigured_allows_anyone() {
+        assert_eq!(admin_auth(&[], &[]).authorize(&HeaderMap::new()), Ok(None));
+    }
+
+    #[test]
+    fn test_anonymous_is_unauthenticated() {
+        assert_eq!(admin_auth(&["alice"], &["alice:secret"]).authorize(&HeaderMap::new()), Err(AuthError::Unauthenticated));
+        assert_eq!(admin_auth(&["alice"], &[]).authorize(&HeaderMap::new()), Err(AuthError::Unauthenticated));
+    }
+
+    #[test]
+    fn test_wrong_password_is_unauthenticated() {
+        let auth = admin_auth(&["alice"], &["alice:secret"]);
+        assert_eq!(auth.authorize(&basic("alice", "guess")), Err(AuthError::Unauthenticated));
+        assert_eq!(auth.authorize(&basic("alice", "secret2")), Err(AuthError::Unauthenticated));
+        assert_eq!(auth.authorize(&basic("mallory", "secret")), Err(AuthError::Unauthenticated));
+    }
+
+    #[test]
+    fn test_proxy_header_is_ignored_with_basic_auth() {
+        let auth = admin_auth(&["alice"], &["alice:secret"]);
+        assert_eq!(auth.authorize(&forwarded("alice")), Err(AuthError::Unauthenticated));
+
+        let mut headers = basic("bob", "hunter2");
+        headers.extend(forwarded("alice"));
+        let auth = admin_auth(&["alice"], &["alice:secret", "bob:hunter2"]);
+        assert_eq!(auth.authorize(&headers), Err(AuthError::Forbidden("bob".to_string())));
+    }
+
+    #[test]
+    fn test_non_admin_is_forbidden() {
+        let auth = admin_auth(&["alice"], &["alice:secret", "bob:hunter2"]);
+        assert_eq!(auth.authorize(&basic("bob", "hunter2")), Err(AuthError::Forbidden("bob".to_string())));
+        let auth = admin_auth(&["alice"], &[]);
+        assert_eq!(auth.authorize(&forwarded("bob")), Err(AuthError::Forbidden("bob".to_string())));
+    }
+
+    #[test]
+    fn test_admin_is_authorized() {
+        let auth = admin_auth(&["alice"], &["alice:secret"]);
+        assert_eq!(auth.authorize(&basic("alice", "secret")), Ok(Some("alice".to_string())));
+        let auth = admin_auth(&["alice"], &[]);
+        assert_eq!(auth.authorize(&forwarded("alice")), Ok(Some("alice".to_string())));
+    }
+
+    #[test]
+    fn test_any_basic_user_without_admin_list() {
+        let auth = admin_auth(&[], &["bob:hunter2"]);
+        assert_eq!(auth.authorize(&basic("bob", "hunter2")), Ok(Some("bob".to_string())));
+        assert_eq!(auth.authorize(&forwarded("bob")), Err(AuthError::Unauthenticated));
+    }
+}
@@ -9,6 +9,10 @@ use std::path::PathBuf;
 #[command(name = "nsqadmin")]
 #[command(about = "NSQ admin web interface")]
 pub struct Args {
+    /// Config file (TOML, YAML or JSON); command line flags override it
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    
     /// HTTP address to listen on
     #[arg(long, default_value = "0.0.0.0:4171")]
     pub http_address: String,
@@ -41,10 +45,58 @@ pub struct Args {
     #[arg(long)]
     pub proxy_graphite: bool,
     
+    /// Prefix of the statsd metrics nsqd reports to Graphite, `*` matching every node
+    #[arg(long, default_value = "nsq.*")]
+    pub graphite_prefix: String,
+    
+    /// Graphite name of a statsd counter, %s being the metric
+    #[arg(long, default_value = "stats.counters.%s.count")]
+    pub graphite_counter_format: String,
+    
+    /// Graphite name of a statsd gauge, %s being the metric
+    #[arg(long, default_value = "stats.gauges.%s")]
+    pub graphite_gauge_format: String,
+    
     /// Notification HTTP endpoint
     #[arg(long)]
     pub notification_http_endpoint: Option<String>,
     
+    /// Header an auth proxy sets to the authenticated user
+    #[arg(long, default_value = "X-Forwarded-User")]
+    pub acl_http_header: String,
+    
+    /// User allowed to change topics and channels (repeatable)
+    #[arg(long = "admin-user")]
+    pub admin_users: Vec<String>,
+    
+    /// HTTP Basic credentials as user:password (repeatable)
+    #[arg(long = "basic-auth-user")]
+    pub basic_auth_users: Vec<String>,
+    
+    /// Interval between stats pushed to /api/stream subscribers (ms)
+    #[arg(long, default_value = "2000", value_parser = clap::value_parser!(u64).range(100..))]
+    pub stream_interval: u64,
+    
+    /// Interval between stats samples kept for /api/graphs (ms)
+    #[arg(long, default_value = "10000", value_parser = clap::value_parser!(u64).range(1000..))]
+    pub history_interval: u64,
+    
+    /// How far back /api/graphs samples are kept (ms)
+    #[arg(long, default_value = "7200000")]
+    pub history_window: u64,
+    
+    /// Timeout of each request to nsqd and lookupd (ms)
+    #[arg(long, default_value = "5000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub upstream_timeout: u64,
+    
+    /// Most nsqd nodes queried for stats at once
+    #[arg(long, default_value = "16", value_parser = clap::value_parser!(u64).range(1..))]
+    pub upstream_concurrency: u64,
+    
+    /// How long merged nsqd stats are reused before querying again (ms, 0 = never)
+    #[arg(long, default_value = "1000")]
+    pub stats_cache_ttl: u64,
+    
     /// Log level
     #[arg(long, default_value = "info")]
     pub log_level: String,
@@ -52,6 +104,46 @@ pub struct Args {
     /// Log format
     #[arg(long, default_value = "text")]
     pub log_format: String,
+    
+    /// Per-module log level override such as nsqd::channel=debug (repeatable)
+    #[arg(long = "log-filter")]
+    pub log_filters: Vec<String>,
+    
+    /// Write logs to this file instead of stdout
+    #[arg(long)]
+    pub log_file: Option<String>,
+    
+    /// Rotate the log file once it reaches this many bytes; 0 disables rotation
+    #[arg(long, default_value = "104857600")]
+    pub log_max_size: u64,
+    
+    /// Rotated log files to keep
+    #[arg(long, default_value = "5")]
+    pub log_max_files: usize,
+    
+    /// OTLP/HTTP collector to export trace spans to, e.g. http://127.0.0.1:4318
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    
+    /// Service name for exported spans (defaults to the executable name)
+    #[arg(long)]
+    pub otlp_service_name: Option<String>,
+    
+    /// Fraction of traces to export (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub otlp_sample_rate: f64,
+    
+    /// Log every HTTP request
+    #[arg(long)]
+    pub access_log: bool,
+    
+    /// Fraction of HTTP requests to log (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub access_log_sample_rate: f64,
+    
+    /// Write HTTP requests to this file as JSON lines
+    #[arg(long)]
+    pub access_log_file: Option<String>,
 }
 
 impl From<Args> for NsqadminConfig {
@@ -60,6 +152,16 @@ impl From<Args> for NsqadminConfig {
             base: nsq_common::BaseConfig {
                 log_level: args.log_level,
                 log_format: args.log_format,
+                log_filters: args.log_filters,
+                log_file: args.log_file,
+                log_max_size: args.log_max_size,
+                log_max_files: args.log_max_files,
+                otlp_endpoint: args.otlp_endpoint,
+                otlp_service_name: args.otlp_service_name,
+                otlp_sample_rate: args.otlp_sample_rate,
+                access_log: args.access_log,
+                access_log_sample_rate: args.access_log_sample_rate,
+                access_log_file: args.access_log_file,
                 statsd_address: None,
                 statsd_prefix: "nsqadmin".to_string(),
             },
@@ -75,7 +177,19 @@ impl From<Args> for NsqadminConfig {
             dev_static_dir: args.dev_static_dir,
             graphite_url: args.graphite_url,
             proxy_graphite: args.proxy_graphite,
+            graphite_prefix: args.graphite_prefix,
+            graphite_counter_format: args.graphite_counter_format,
+            graphite_gauge_format: args.graphite_gauge_format,
             notification_http_endpoint: args.notification_http_endpoint,
+            acl_http_header: args.acl_http_header,
+            admin_users: args.admin_users,
+            basic_auth_users: args.basic_auth_users,
+            stream_interval: args.stream_interval,
+            history_interval: args.history_interval,
+            history_window: args.history_window,
+            upstream_timeout: args.upstream_timeout,
+            upstream_concurrency: args.upstream_concurrency as usize,
+            stats_cache_ttl: args.stats_cache_ttl,
         }
     }
 }
@@ -0,0 +1,115 @@
+//! Graphite-backed graphs
+//!
+//! When `--graphite-url` is set, topic and channel graphs are read from the
+//! statsd metrics nsqd nodes report to Graphite, summed across nodes, instead
+//! of the in-memory history. Metric names follow the original nsqadmin:
+//! `<prefix>.topic.<topic>[.channel.<channel>].<metric>`, wrapped in the
+//! statsd counter or gauge format.
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use nsq_common::NsqadminConfig;
+
+/// Timeout for a Graphite render request
+const RENDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Depth and message rate of a topic or channel at one point in time; either
+/// is `None` when Graphite has no data for that interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GraphitePoint {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub depth: Option<u64>,
+    pub rate: Option<f64>,
+}
+
+/// A series returned by the Graphite render API
+#[derive(Debug, Deserialize)]
+struct RenderSeries {
+    target: String,
+    datapoints: Vec<(Option<f64>, i64)>,
+}
+
+/// Client for the Graphite render API
+pub struct GraphiteClient {
+    url: String,
+    prefix: String,
+    counter_format: String,
+    gauge_format: String,
+    http_client: reqwest::Client,
+}
+
+impl GraphiteClient {
+    /// Create a client when a Graphite URL is configured
+    pub fn new(config: &NsqadminConfig) -> Option<Self> {
+        let url = config.graphite_url.as_ref()?.trim_end_matches('/').to_string();
+        Some(Self {
+            url,
+            prefix: config.graphite_prefix.clone(),
+            counter_format: config.graphite_counter_format.clone(),
+            gauge_format: config.graphite_gauge_format.clone(),
+            http_client: reqwest::Client::new(),
+        })
+    }
+
+    /// Metric name of a topic or channel stat, before the counter or gauge format
+    fn metric(&self, topic: &str, channel: Option<&str>, name: &str) -> String {
+        match channel {
+            Some(channel) => format!("{}.topic.{}.channel.{}.{}", self.prefix, topic, channel, name),
+            None => format!("{}.topic.{}.{}", self.prefix, topic, name),
+        }
+    }
+
+    /// Render targets for the summed depth and the message rate per second
+    pub fn targets(&self, topic: &str, channel: Option<&str>) -> (String, String) {
+        let depth = self.gauge_format.replace("%s", &self.metric(topic, channel, "depth"));
+        let count = self.counter_format.replace("%s", &self.metric(topic, channel, "message_count"));
+        (
+            format!("alias(sumSeries({}),\"depth\")", depth),
+            format!("alias(scaleToSeconds(sumSeries({}),1),\"rate\")", count),
+        )
+    }
+
+    /// Fetch the depth and rate of a topic or channel over the last `window`
+    pub async fn series(&self, topic: &str, channel: Option<&str>, window: Duration) -> Result<Vec<GraphitePoint>, reqwest::Error> {
+        let (depth_target, rate_target) = self.targets(topic, channel);
+        let from = format!("-{}s", window.as_secs().max(1));
+        let series: Vec<RenderSeries> = self.http_client
+            .get(format!("{}/render", self.url))
+            .query(&[
+                ("target", depth_target.as_str()),
+                ("target", rate_target.as_str()),
+                ("from", from.as_str()),
+                ("format", "json"),
+            ])
+            .timeout(RENDER_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(merge(&series))
+    }
+}
+
+/// Combine the depth and rate series into points ordered by time
+fn merge(series: &[RenderSeries]) -> Vec<GraphitePoint> {
+    let mut points: std::collections::BTreeMap<i64, GraphitePoint> = std::collections::BTreeMap::new();
+    for s in series {
+        for &(value, timestamp) in &s.datapoints {
+            let Some(time) = chrono::DateTime::from_timestamp(timestamp, 0) else {
+                continue;
+            };
+            let point = points.entry(timestamp).or_insert(GraphitePoint {
+                timestamp: time,
+                depth: None,
+                rate: None,
+            });
+            match s.target.as_str() {
+                "depth" => point.depth = value.map(|v| v.max(0.0) as u64),
+                "rate" => point.rate = value,
+                _ => {}
+            }
+        }
+    }
+    points.into_values().collect()
+}
@@ -0,0 +1,94 @@
+//! In-memory stats history
+//!
+//! Aggregated topic and channel stats are sampled at a fixed interval and the
+//! most recent window is kept in a ring buffer per topic and channel, so the
+//! UI can chart depth and message rates without an external metrics store.
+
+use std::collections::{HashMap, VecDeque};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A topic, or a channel of a topic
+pub type SeriesKey = (String, Option<String>);
+
+/// Stats of a topic or channel at one point in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub depth: u64,
+    pub message_count: u64,
+    /// Messages per second since the previous sample
+    pub rate: f64,
+}
+
+/// Ring buffers of samples for every topic and channel
+pub struct StatsHistory {
+    capacity: usize,
+    series: RwLock<HashMap<SeriesKey, VecDeque<Sample>>>,
+}
+
+impl StatsHistory {
+    /// Keep `capacity` samples per topic and channel
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            series: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Record a sample for every topic and channel in aggregated topic stats.
+    /// Series of topics and channels that no longer exist are dropped.
+    pub fn record(&self, topics: &[Value], timestamp: chrono::DateTime<chrono::Utc>) {
+        let mut current = HashMap::new();
+        for topic in topics {
+            let Some(topic_name) = topic.get("topic_name").and_then(|v| v.as_str()) else {
+                continue;
+            };
+            current.insert((topic_name.to_string(), None), topic);
+            let channels = topic.get("channels").and_then(|v| v.as_array()).into_iter().flatten();
+            for channel in channels {
+                if let Some(channel_name) = channel.get("channel_name").and_then(|v| v.as_str()) {
+                    current.insert((topic_name.to_string(), Some(channel_name.to_string())), channel);
+                }
+            }
+        }
+
+        let mut series = self.series.write();
+        series.retain(|key, _| current.contains_key(key));
+        for (key, stats) in current {
+            let samples = series.entry(key).or_default();
+            let depth = stats.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+            let message_count = stats.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
+            let rate = samples.back().map_or(0.0, |previous| rate(previous, message_count, timestamp));
+
+            samples.push_back(Sample { timestamp, depth, message_count, rate });
+            while samples.len() > self.capacity {
+                samples.pop_front();
+            }
+        }
+    }
+
+    /// Samples of a topic, or of one of its channels, oldest first
+    pub fn series(&self, topic: &str, channel: Option<&str>) -> Option<Vec<Sample>> {
+        let key = (topic.to_string(), channel.map(str::to_string));
+        self.series.read().get(&key).map(|samples| samples.iter().cloned().collect())
+    }
+
+    /// Messages per second of a topic, or of one of its channels, at the
+    /// latest sample
+    pub fn latest_rate(&self, topic: &str, channel: Option<&str>) -> f64 {
+        let key = (topic.to_string(), channel.map(str::to_string));
+        self.series.read().get(&key).and_then(|samples| samples.back()).map_or(0.0, |sample| sample.rate)
+    }
+}
+
+/// Messages per second between a sample and a later message count; a count
+/// that went down means nsqd restarted or the topic was recreated
+fn rate(previous: &Sample, message_count: u64, timestamp: chrono::DateTime<chrono::Utc>) -> f64 {
+    let seconds = (timestamp - previous.timestamp).num_milliseconds() as f64 / 1000.0;
+    if seconds <= 0.0 || message_count < previous.message_count {
+        return 0.0;
+    }
+    (message_count - previous.message_count) as f64 / seconds
+}
@@ -0,0 +1,182 @@
+//! Asynchronous cluster-wide jobs
+//!
+//! Slow operations that touch every nsqd node run in the background. Callers
+//! get a job ID back immediately and poll for per-node progress.
+
+use std::collections::{HashMap, VecDeque};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Maximum number of jobs kept for auditing
+const MAX_JOB_HISTORY: usize = 1000;
+
+/// Operations that can run as jobs, named after the nsqd HTTP endpoint
+pub const JOB_OPERATIONS: &[&str] = &[
+    "topic/create",
+    "topic/delete",
+    "topic/pause",
+    "topic/unpause",
+    "topic/empty",
+    "channel/create",
+    "channel/delete",
+    "channel/pause",
+    "channel/unpause",
+    "channel/empty",
+];
+
+/// Job request body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub operation: String,
+    pub topic: String,
+    pub channel: Option<String>,
+}
+
+impl JobRequest {
+    /// Validate the requested operation and its arguments
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !JOB_OPERATIONS.contains(&self.operation.as_str()) {
+            return Err(format!("Unsupported operation: {}", self.operation));
+        }
+        nsq_common::validate_topic_name(&self.topic).map_err(|e| format!("{} {}", e.code(), e))?;
+        match (&self.channel, self.operation.starts_with("channel/")) {
+            (Some(channel), true) => nsq_common::validate_channel_name(channel).map_err(|e| format!("{} {}", e.code(), e)),
+            (None, true) => Err(format!("Operation {} requires a channel", self.operation)),
+            (_, false) => Ok(()),
+        }
+    }
+}
+
+/// Job or node status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Result of a job on a single nsqd node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeResult {
+    pub address: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Cluster-wide job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub operation: String,
+    pub topic: String,
+    pub channel: Option<String>,
+    pub status: JobStatus,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub nodes: Vec<NodeResult>,
+}
+
+/// In-memory job store with bounded history
+#[derive(Default)]
+pub struct JobStore {
+    jobs: RwLock<HashMap<String, Job>>,
+    order: RwLock<VecDeque<String>>,
+}
+
+impl JobStore {
+    /// Create a new job store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a pending job; nodes are filled in once it starts
+    pub fn create(&self, request: &JobRequest) -> Job {
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            operation: request.operation.clone(),
+            topic: request.topic.clone(),
+            channel: request.channel.clone(),
+            status: JobStatus::Pending,
+            created_at: chrono::Utc::now(),
+            finished_at: None,
+            nodes: Vec::new(),
+        };
+
+        let mut order = self.order.write();
+        let mut jobs = self.jobs.write();
+        order.push_back(job.id.clone());
+        jobs.insert(job.id.clone(), job.clone());
+        while order.len() > MAX_JOB_HISTORY {
+            if let Some(old_id) = order.pop_front() {
+                jobs.remove(&old_id);
+            }
+        }
+
+        job
+    }
+
+    /// Mark a job as running on the given nodes
+    pub fn start(&self, job_id: &str, addresses: &[String]) {
+        if let Some(job) = self.jobs.write().get_mut(job_id) {
+            job.nodes = addresses
+                .iter()
+                .map(|address| NodeResult {
+                    address: address.clone(),
+                    status: JobStatus::Running,
+                    error: None,
+                    finished_at: None,
+                })
+                .collect();
+
+            if job.nodes.is_empty() {
+                job.status = JobStatus::Succeeded;
+                job.finished_at = Some(chrono::Utc::now());
+            } else {
+                job.status = JobStatus::Running;
+            }
+        }
+    }
+
+    /// Record the result for one node, finishing the job when all nodes are done
+    pub fn record(&self, job_id: &str, address: &str, result: std::result::Result<(), String>) {
+        let mut jobs = self.jobs.write();
+        let Some(job) = jobs.get_mut(job_id) else {
+            return;
+        };
+
+        if let Some(node) = job.nodes.iter_mut().find(|node| node.address == address) {
+            node.status = if result.is_ok() { JobStatus::Succeeded } else { JobStatus::Failed };
+            node.error = result.err();
+            node.finished_at = Some(chrono::Utc::now());
+        }
+
+        if job.nodes.iter().all(|node| node.finished_at.is_some()) {
+            job.status = if job.nodes.iter().all(|node| node.status == JobStatus::Succeeded) {
+                JobStatus::Succeeded
+            } else {
+                JobStatus::Failed
+            };
+            job.finished_at = Some(chrono::Utc::now());
+        }
+    }
+
+    /// Get a job by ID
+    pub fn get(&self, job_id: &str) -> Option<Job> {
+        self.jobs.read().get(job_id).cloned()
+    }
+
+    /// List jobs, newest first
+    pub fn list(&self) -> Vec<Job> {
+        let jobs = self.jobs.read();
+        self.order
+            .read()
+            .iter()
+            .rev()
+            .filter_map(|id| jobs.get(id).cloned())
+            .collect()
+    }
+}
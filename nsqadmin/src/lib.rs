@@ -4,6 +4,15 @@
 
 pub mod server;
 pub mod config;
+pub mod jobs;
+pub mod topology;
+pub mod stream;
+pub mod history;
+pub mod graphite;
+pub mod auth;
+pub mod node;
+pub mod topic_query;
+pub mod maintenance;
 
 pub use server::*;
 pub use config::*;
@@ -1,16 +1,24 @@
 //! NSQAdmin main entry point
 
-use nsqadmin::server::NsqadminServer;
-use nsq_common::init_logging;
-use clap::Parser;
+use nsqadmin::{config::Args, server::NsqadminServer};
+use nsq_common::{explicit_args, init_logging, merge_config_file, shutdown_tracing, NsqadminConfig};
+use clap::{CommandFactory, FromArgMatches};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
-    let args = nsqadmin::config::Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+    let config_path = args.config.clone();
     
-    // Convert to configuration
-    let config: nsq_common::NsqadminConfig = args.into();
+    // Convert to configuration, layering the config file under explicit flags
+    let mut config: NsqadminConfig = args.into();
+    if let Some(path) = &config_path {
+        config = merge_config_file(&config, &explicit_args(&matches), path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
     
     // Initialize logging
     init_logging(&config.base)?;
@@ -21,6 +29,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Keep the main thread alive
     tokio::signal::ctrl_c().await?;
+    shutdown_tracing();
     
     Ok(())
 }
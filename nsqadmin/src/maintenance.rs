@@ -0,0 +1,124 @@
+//! Topic maintenance mode
+//!
+//! Entering maintenance pauses a topic on every nsqd node, empties its
+//! queued messages and waits for the messages consumers still hold to be
+//! finished or requeued. The pause is all or nothing: when a node cannot be
+//! paused, the nodes that were are unpaused again. Leaving maintenance
+//! unpauses the topic everywhere. Progress is kept per topic for polling.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+/// How long to wait for in-flight and deferred messages before failing
+pub const MAINTENANCE_DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often nodes are checked while draining
+pub const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a topic's maintenance stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceState {
+    /// Pausing the topic on every node
+    Pausing,
+    /// Emptying the topic and waiting for in-flight messages
+    Draining,
+    /// Paused and drained on every node
+    Active,
+    /// Unpausing the topic on every node
+    Resuming,
+    /// Unpaused on every node
+    Resumed,
+    /// A step failed; see `error` and the nodes
+    Failed,
+}
+
+impl MaintenanceState {
+    /// Check whether a step is still running
+    pub fn is_running(self) -> bool {
+        matches!(self, Self::Pausing | Self::Draining | Self::Resuming)
+    }
+}
+
+/// Progress on one nsqd node
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeMaintenance {
+    pub address: String,
+    pub paused: bool,
+    /// Messages discarded from memory and disk
+    pub emptied_depth: u64,
+    pub emptied_backend_depth: u64,
+    /// In-flight and deferred messages left at the last check
+    pub in_flight_count: u64,
+    pub deferred_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Maintenance of one topic across the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub topic: String,
+    pub state: MaintenanceState,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub nodes: Vec<NodeMaintenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Maintenance of every topic it was started for
+#[derive(Default)]
+pub struct MaintenanceStore {
+    topics: RwLock<HashMap<String, Maintenance>>,
+}
+
+impl MaintenanceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a step on the given nodes, unless one is already running for
+    /// the topic, in which case that one is returned as the error
+    pub fn begin(&self, topic: &str, state: MaintenanceState, addresses: &[String]) -> std::result::Result<Maintenance, Maintenance> {
+        let mut topics = self.topics.write();
+        if let Some(current) = topics.get(topic).filter(|current| current.state.is_running()) {
+            return Err(current.clone());
+        }
+        let now = chrono::Utc::now();
+        let maintenance = Maintenance {
+            topic: topic.to_string(),
+            state,
+            started_at: now,
+            updated_at: now,
+            nodes: addresses.iter()
+                .map(|address| NodeMaintenance { address: address.clone(), ..Default::default() })
+                .collect(),
+            error: None,
+        };
+        topics.insert(topic.to_string(), maintenance.clone());
+        Ok(maintenance)
+    }
+
+    /// Update a topic's maintenance in place
+    pub fn update(&self, topic: &str, update: impl FnOnce(&mut Maintenance)) {
+        if let Some(maintenance) = self.topics.write().get_mut(topic) {
+            update(maintenance);
+            maintenance.updated_at = chrono::Utc::now();
+        }
+    }
+
+    /// Update the progress of one node
+    pub fn update_node(&self, topic: &str, address: &str, update: impl FnOnce(&mut NodeMaintenance)) {
+        self.update(topic, |maintenance| {
+            if let Some(node) = maintenance.nodes.iter_mut().find(|node| node.address == address) {
+                update(node);
+            }
+        });
+    }
+
+    pub fn get(&self, topic: &str) -> Option<Maintenance> {
+        self.topics.read().get(topic).cloned()
+    }
+}
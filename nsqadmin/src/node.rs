@@ -0,0 +1,90 @@
+//! Per-node detail
+//!
+//! Summarises a single nsqd node from its `/stats` response: the topics and
+//! channels it hosts, how many clients are connected to each, and how much of
+//! the depth is held in memory rather than on disk.
+
+use serde_json::{json, Value};
+
+/// Memory, disk and total depth of a topic or channel
+fn depths(stats: &Value) -> (u64, u64, u64) {
+    let depth = stats.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+    let backend_depth = stats.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
+    (depth.saturating_sub(backend_depth), backend_depth, depth)
+}
+
+/// Channel stats without the per-client list
+fn channel_detail(channel: &Value) -> Value {
+    let (memory_depth, backend_depth, depth) = depths(channel);
+    let client_count = channel.get("clients").and_then(|v| v.as_array()).map_or(0, |c| c.len());
+    json!({
+        "channel_name": channel.get("channel_name"),
+        "paused": channel.get("paused").and_then(|v| v.as_bool()).unwrap_or(false),
+        "depth": depth,
+        "memory_depth": memory_depth,
+        "backend_depth": backend_depth,
+        "in_flight_count": channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0),
+        "deferred_count": channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0),
+        "message_count": channel.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0),
+        "requeue_count": channel.get("requeue_count").and_then(|v| v.as_u64()).unwrap_or(0),
+        "timeout_count": channel.get("timeout_count").and_then(|v| v.as_u64()).unwrap_or(0),
+        "client_count": client_count,
+    })
+}
+
+/// Build the detail of a node from its `/stats` response and, when the node
+/// is registered with lookupd, its producer entry
+pub fn node_detail(http_address: &str, stats: &Value, producer: Option<&Value>) -> Value {
+    let mut totals = (0u64, 0u64, 0u64, 0u64);
+    let mut channel_count = 0;
+    let mut client_count = 0;
+    let topics: Vec<Value> = stats.get("topics").and_then(|v| v.as_array()).into_iter().flatten()
+        .map(|topic| {
+            let channels: Vec<Value> = topic.get("channels").and_then(|v| v.as_array()).into_iter().flatten()
+                .map(channel_detail)
+                .collect();
+            let topic_clients: u64 = channels.iter().filter_map(|c| c["client_count"].as_u64()).sum();
+            let (memory_depth, backend_depth, depth) = depths(topic);
+            let message_count = topic.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
+
+            totals.0 += memory_depth;
+            totals.1 += backend_depth;
+            totals.2 += depth;
+            totals.3 += message_count;
+            channel_count += channels.len();
+            client_count += topic_clients;
+            json!({
+                "topic_name": topic.get("topic_name"),
+                "paused": topic.get("paused").and_then(|v| v.as_bool()).unwrap_or(false),
+                "depth": depth,
+                "memory_depth": memory_depth,
+                "backend_depth": backend_depth,
+                "message_count": message_count,
+                "client_count": topic_clients,
+                "channels": channels,
+            })
+        })
+        .collect();
+
+    json!({
+        "http_address": http_address,
+        "hostname": producer.and_then(|p| p.get("hostname")),
+        "broadcast_address": producer.and_then(|p| p.get("broadcast_address")),
+        "tcp_port": producer.and_then(|p| p.get("tcp_port")),
+        "registered": producer.is_some(),
+        "version": stats.get("version"),
+        "health": stats.get("health"),
+        "start_time": stats.get("start_time"),
+        "uptime_seconds": stats.get("uptime_seconds"),
+        "topic_count": topics.len(),
+        "channel_count": channel_count,
+        "client_count": client_count,
+        "memory": {
+            "depth": totals.0,
+            "backend_depth": totals.1,
+            "total_depth": totals.2,
+        },
+        "message_count": totals.3,
+        "topics": topics,
+    })
+}
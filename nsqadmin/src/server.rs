@@ -2,19 +2,34 @@
 
 use std::sync::Arc;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast;
 use axum::{
-    extract::{State, Path as AxumPath},
-    response::Json,
+    extract::{Request, State, Path as AxumPath, Query},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use nsq_common::{Metrics, Result, NsqError, NsqadminConfig};
+use nsq_common::{AccessLogLayer, Metrics, Result, NsqError, NsqadminConfig, http_span};
+use crate::jobs::{Job, JobRequest, JobStore};
+use crate::maintenance::{MaintenanceState, MaintenanceStore, MAINTENANCE_DRAIN_TIMEOUT, MAINTENANCE_POLL_INTERVAL};
+use crate::auth::{AdminAuth, AuthError};
+use crate::graphite::GraphiteClient;
+use crate::history::StatsHistory;
+use crate::topic_query::TopicQuery;
+use crate::node::node_detail;
+use crate::stream::{StatsStream, TopicSnapshot};
+use crate::topology::Topology;
 use tower_http::{
     services::ServeDir,
     cors::{CorsLayer, Any},
+    trace::TraceLayer,
 };
 
 pub struct NsqadminServer {
@@ -23,8 +38,22 @@ pub struct NsqadminServer {
     http_client: reqwest::Client,
     start_time: chrono::DateTime<chrono::Utc>,
     start_instant: std::time::Instant,
+    jobs: Arc<JobStore>,
+    maintenance: Arc<MaintenanceStore>,
+    stream: Arc<StatsStream>,
+    history: Arc<StatsHistory>,
+    graphite: Option<Arc<GraphiteClient>>,
+    auth: Arc<AdminAuth>,
+    /// Logs HTTP requests when enabled
+    access_log: AccessLogLayer,
+    /// Held while fetching, so concurrent requests share one round of
+    /// upstream queries
+    stats_cache: Arc<tokio::sync::Mutex<Option<CachedStats>>>,
 }
 
+/// Merged nsqd stats and when they were fetched
+type CachedStats = (std::time::Instant, Vec<serde_json::Value>);
+
 #[derive(Debug, Serialize, Deserialize)]
 struct TopicInfo {
     topic_name: String,
@@ -34,6 +63,9 @@ struct TopicInfo {
     message_count: u64,
     paused: bool,
     nodes: Vec<String>,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    last_publish_at: Option<chrono::DateTime<chrono::Utc>>,
+    last_delivery_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,6 +80,8 @@ struct ChannelInfo {
     timeout_count: u64,
     paused: bool,
     clients: Vec<ClientInfo>,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    last_delivery_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,6 +89,42 @@ struct ClientInfo {
     client_id: String,
     hostname: String,
     remote_address: String,
+    /// HTTP address of the nsqd node the client is connected to
+    node: String,
+    version: String,
+    user_agent: String,
+    ready_count: u64,
+    in_flight_count: u64,
+    message_count: u64,
+    finish_count: u64,
+    requeue_count: u64,
+    connect_ts: i64,
+}
+
+impl ClientInfo {
+    /// Clients of a channel in an nsqd `/stats` response
+    fn from_channel_stats(node: &str, channel: &serde_json::Value) -> Vec<Self> {
+        let clients = channel.get("clients").and_then(|v| v.as_array()).into_iter().flatten();
+        clients.map(|client| {
+            let text = |key: &str| client.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let count = |key: &str| client.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+            Self {
+                client_id: text("client_id"),
+                hostname: text("hostname"),
+                remote_address: text("remote_address"),
+                node: node.to_string(),
+                version: text("version"),
+                user_agent: text("user_agent"),
+                ready_count: count("ready_count"),
+                in_flight_count: count("in_flight_count"),
+                message_count: count("message_count"),
+                finish_count: count("finish_count"),
+                requeue_count: count("requeue_count"),
+                connect_ts: client.get("connect_ts").and_then(|v| v.as_i64()).unwrap_or(0),
+            }
+        })
+        .collect()
+    }
 }
 
 impl NsqadminServer {
@@ -62,14 +132,26 @@ impl NsqadminServer {
     pub fn new(config: NsqadminConfig) -> Result<Self> {
         // Initialize metrics
         let metrics = Metrics::new(&config.base)?;
-        let http_client = reqwest::Client::new();
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.upstream_timeout))
+            .build()
+            .map_err(|e| NsqError::Config(format!("Failed to create HTTP client: {}", e)))?;
+        let history_capacity = (config.history_window / config.history_interval.max(1)).max(1) as usize;
         
         Ok(Self {
-            config,
             metrics,
             http_client,
             start_time: chrono::Utc::now(),
             start_instant: std::time::Instant::now(),
+            jobs: Arc::new(JobStore::new()),
+            maintenance: Arc::new(MaintenanceStore::new()),
+            stream: Arc::new(StatsStream::new()),
+            history: Arc::new(StatsHistory::new(history_capacity)),
+            graphite: GraphiteClient::new(&config).map(Arc::new),
+            auth: Arc::new(AdminAuth::new(&config)?),
+            access_log: AccessLogLayer::new(&config.base)?,
+            stats_cache: Arc::new(tokio::sync::Mutex::new(None)),
+            config,
         })
     }
     
@@ -83,23 +165,27 @@ impl NsqadminServer {
         
         // Create HTTP listener
         let listener = TcpListener::bind(http_addr).await
-            .map_err(|e| NsqError::Io(e))?;
+            .map_err(NsqError::Io)?;
         
         tracing::info!("HTTP server listening on {}", http_addr);
         
+        let server = Arc::new(self);
+        tokio::spawn(server.clone().poll_stream());
+        tokio::spawn(server.clone().sample_history());
+        
         // Create router
-        let app = self.create_router();
+        let app = server.create_router();
         
         // Start server
-        axum::serve(listener, app).await
-            .map_err(|e| NsqError::Io(e))?;
+        axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+            .map_err(NsqError::Io)?;
         
         Ok(())
     }
     
     /// Create HTTP router
-    fn create_router(self) -> Router {
-        let server = Arc::new(self);
+    fn create_router(self: Arc<Self>) -> Router {
+        let server = self;
         
         // Configure CORS
         let cors = CorsLayer::new()
@@ -113,23 +199,89 @@ impl NsqadminServer {
             .route("/api/info", get(Self::handle_info))
             .route("/api/stats", get(Self::handle_stats))
             .route("/api/topics", get(Self::handle_topics))
+            .route("/api/topics/summary", get(Self::handle_topics_summary))
             .route("/api/topics/:topic", get(Self::handle_topic_detail))
+            .route("/api/topics/:topic/peek", get(Self::handle_topic_peek))
+            .route("/api/channels/:topic/:channel", get(Self::handle_channel_detail))
             .route("/api/nodes", get(Self::handle_nodes))
+            .route("/api/nodes/:node", get(Self::handle_node_detail))
+            .route("/api/nodes/:node/tombstone", post(Self::handle_node_tombstone))
             .route("/api/topic/:topic/pause", post(Self::handle_topic_pause))
             .route("/api/topic/:topic/unpause", post(Self::handle_topic_unpause))
             .route("/api/topic/:topic/delete", post(Self::handle_topic_delete))
             .route("/api/topic/:topic/create", post(Self::handle_topic_create))
+            .route("/api/topic/:topic/empty", post(Self::handle_topic_empty))
+            .route("/api/topic/:topic/maintenance", get(Self::handle_maintenance_status)
+                .post(Self::handle_maintenance_enter)
+                .delete(Self::handle_maintenance_exit))
             .route("/api/channel/:topic/:channel/pause", post(Self::handle_channel_pause))
             .route("/api/channel/:topic/:channel/unpause", post(Self::handle_channel_unpause))
             .route("/api/channel/:topic/:channel/delete", post(Self::handle_channel_delete))
             .route("/api/channel/:topic/:channel/create", post(Self::handle_channel_create))
             .route("/api/channel/:topic/:channel/empty", post(Self::handle_channel_empty))
+            .route("/api/jobs", get(Self::handle_jobs_list).post(Self::handle_job_create))
+            .route("/api/jobs/:id", get(Self::handle_job_detail))
+            .route("/api/export/topology", get(Self::handle_export_topology))
+            .route("/api/stream", get(Self::handle_stream))
+            .route("/api/graphs/:topic", get(Self::handle_topic_graph))
+            .route("/api/graphs/:topic/:channel", get(Self::handle_channel_graph))
+            .route("/metrics", get(Self::handle_metrics))
             // Serve static files from nsqadmin-ui/dist
             .nest_service("/", ServeDir::new("../nsqadmin-ui/dist"))
+            .layer(middleware::from_fn_with_state(server.clone(), Self::authorize))
+            .layer(TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| http_span(request.method(), request.uri().path()))
+                .on_response(|response: &Response, _latency: Duration, span: &tracing::Span| {
+                    span.record("http.status_code", response.status().as_u16());
+                }))
             .layer(cors)
+            .layer(server.access_log.clone())
             .with_state(server)
     }
     
+    /// Let anyone read stats, but only admins read message bodies or change
+    /// topics and channels
+    async fn authorize(State(server): State<Arc<NsqadminServer>>, request: Request, next: Next) -> Response {
+        let open = match *request.method() {
+            Method::OPTIONS => true,
+            Method::GET | Method::HEAD => !Self::is_peek(request.uri().path()),
+            _ => false,
+        };
+        if open {
+            return next.run(request).await;
+        }
+        
+        match server.auth.authorize(request.headers()) {
+            Ok(user) => {
+                if let Some(user) = user {
+                    tracing::info!("{} {} by {}", request.method(), request.uri().path(), user);
+                }
+                next.run(request).await
+            }
+            Err(AuthError::Unauthenticated) => {
+                server.metrics.incr("auth.denied", 1);
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Basic realm=\"nsqadmin\"")],
+                    Json(json!({"status": "error", "message": "Authentication required"})),
+                ).into_response()
+            }
+            Err(AuthError::Forbidden(user)) => {
+                server.metrics.incr("auth.denied", 1);
+                tracing::warn!("Denied {} {} to non-admin user {}", request.method(), request.uri().path(), user);
+                (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({"status": "error", "message": format!("User {} is not an admin", user)})),
+                ).into_response()
+            }
+        }
+    }
+    
+    /// Whether a path reads message bodies through the peek endpoint
+    fn is_peek(path: &str) -> bool {
+        path.strip_prefix("/api/topics/").is_some_and(|rest| rest.ends_with("/peek"))
+    }
+    
     /// Handle ping endpoint
     async fn handle_ping() -> &'static str {
         "OK"
@@ -169,12 +321,75 @@ impl NsqadminServer {
         }))
     }
     
-    /// Handle topics endpoint
-    async fn handle_topics(State(server): State<Arc<NsqadminServer>>) -> Json<serde_json::Value> {
-        let topics = server.aggregate_topic_stats().await.unwrap_or_default();
-        Json(json!({
-            "topics": topics
-        }))
+    /// Handle topics endpoint; `q` searches topic and channel names, `sort`
+    /// orders by name, depth, rate or stale_since, and `limit` and `offset`
+    /// pick a page
+    async fn handle_topics(
+        State(server): State<Arc<NsqadminServer>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let query = match TopicQuery::from_params(&params) {
+            Ok(query) => query,
+            Err(message) => return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": message}))),
+        };
+        let (total, topics) = query.apply(server.topics_with_rates().await);
+        (StatusCode::OK, Json(json!({
+            "topics": topics,
+            "total": total,
+            "offset": query.offset(),
+            "limit": query.limit(),
+        })))
+    }
+    
+    /// Handle topics summary endpoint: only the names and depths of topics
+    /// and their channels, with the same parameters as /api/topics
+    async fn handle_topics_summary(
+        State(server): State<Arc<NsqadminServer>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let query = match TopicQuery::from_params(&params) {
+            Ok(query) => query,
+            Err(message) => return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": message}))),
+        };
+        let (total, topics) = query.apply(server.topics_with_rates().await);
+        let summary: Vec<serde_json::Value> = topics.iter().map(|topic| {
+            let channels: Vec<serde_json::Value> = topic.get("channels").and_then(|v| v.as_array()).into_iter().flatten()
+                .map(|channel| json!({
+                    "channel_name": channel.get("channel_name"),
+                    "depth": channel.get("depth"),
+                }))
+                .collect();
+            json!({
+                "topic_name": topic.get("topic_name"),
+                "depth": topic.get("depth"),
+                "channels": channels,
+            })
+        }).collect();
+        (StatusCode::OK, Json(json!({
+            "topics": summary,
+            "total": total,
+            "offset": query.offset(),
+            "limit": query.limit(),
+        })))
+    }
+    
+    /// Aggregated topic stats with the latest recorded message rate of every
+    /// topic and channel
+    async fn topics_with_rates(&self) -> Vec<serde_json::Value> {
+        let mut topics = self.aggregate_topic_stats().await.unwrap_or_default();
+        for topic in &mut topics {
+            let Some(topic_name) = topic.get("topic_name").and_then(|v| v.as_str()).map(str::to_string) else {
+                continue;
+            };
+            topic["rate"] = json!(self.history.latest_rate(&topic_name, None));
+            for channel in topic.get_mut("channels").and_then(|v| v.as_array_mut()).into_iter().flatten() {
+                let Some(channel_name) = channel.get("channel_name").and_then(|v| v.as_str()).map(str::to_string) else {
+                    continue;
+                };
+                channel["rate"] = json!(self.history.latest_rate(&topic_name, Some(&channel_name)));
+            }
+        }
+        topics
     }
     
     /// Handle topic detail endpoint
@@ -186,6 +401,70 @@ impl NsqadminServer {
         Json(topic_info)
     }
     
+    /// Handle message peek: the next messages of a topic, or of one of its
+    /// channels with `channel`, read from one hosting nsqd without consuming them
+    async fn handle_topic_peek(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let topic_info = server.get_topic_detail(&topic).await.unwrap_or_default();
+        let nodes: Vec<&str> = topic_info.get("nodes").and_then(|v| v.as_array()).into_iter().flatten()
+            .filter_map(|v| v.as_str())
+            .collect();
+        let node = match params.get("node").map(|node| Self::normalize_address(node)) {
+            Some(node) if nodes.contains(&node.as_str()) => node,
+            Some(node) => return (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Topic {} is not on node {}", topic, node)}))),
+            None => match nodes.first() {
+                Some(node) => node.to_string(),
+                None => return (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Topic {} not found", topic)}))),
+            },
+        };
+        
+        let mut query = vec![("topic", topic.as_str())];
+        for key in ["channel", "count"] {
+            if let Some(value) = params.get(key) {
+                query.push((key, value));
+            }
+        }
+        let response = match server.http_client.get(format!("{}/peek", node)).query(&query).send().await {
+            Ok(response) => response,
+            Err(e) => return (StatusCode::BAD_GATEWAY, Json(json!({"status": "error", "message": format!("Failed to peek {} on {}: {}", topic, node, e)}))),
+        };
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+        match response.json::<serde_json::Value>().await {
+            Ok(mut body) if status.is_success() => {
+                server.metrics.incr("peeks", 1);
+                body["node"] = json!(node);
+                (StatusCode::OK, Json(body))
+            }
+            Ok(body) => {
+                let message = body.get("message").and_then(|v| v.as_str()).unwrap_or("unknown error");
+                (status, Json(json!({"status": "error", "message": format!("Failed to peek {} on {}: {}", topic, node, message)})))
+            }
+            Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({"status": "error", "message": format!("Failed to peek {} on {}: {}", topic, node, e)}))),
+        }
+    }
+    
+    /// Handle channel detail endpoint, with the clients of every node
+    async fn handle_channel_detail(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath((topic, channel)): AxumPath<(String, String)>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let topic_info = server.get_topic_detail(&topic).await.unwrap_or_default();
+        let channel_info = topic_info.get("channels").and_then(|v| v.as_array()).into_iter().flatten()
+            .find(|c| c.get("channel_name").and_then(|v| v.as_str()) == Some(channel.as_str()));
+        
+        match channel_info {
+            Some(channel_info) => {
+                let mut channel_info = channel_info.clone();
+                channel_info["topic_name"] = json!(topic);
+                (StatusCode::OK, Json(channel_info))
+            }
+            None => (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Channel {} on topic {} not found", channel, topic)}))),
+        }
+    }
+    
     /// Handle nodes endpoint
     async fn handle_nodes(State(server): State<Arc<NsqadminServer>>) -> Json<serde_json::Value> {
         let producers = server.fetch_all_producers().await.unwrap_or_default();
@@ -194,8 +473,205 @@ impl NsqadminServer {
         }))
     }
 
+    /// Handle node detail; `node` is the `host:port` or URL of an nsqd HTTP address
+    async fn handle_node_detail(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(node): AxumPath<String>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let Some(address) = server.resolve_node(&node).await else {
+            return (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Node {} not found", node)})));
+        };
+        
+        let stats_url = format!("{}/stats?format=json", address);
+        let (stats, producer) = tokio::join!(
+            server.get_json(&stats_url),
+            server.find_producer(&address),
+        );
+        match stats {
+            Ok(stats) => (StatusCode::OK, Json(node_detail(&address, &stats, producer.as_ref()))),
+            Err(e) => (StatusCode::BAD_GATEWAY, Json(json!({"status": "error", "message": format!("Failed to fetch stats from {}: {}", address, e)}))),
+        }
+    }
+    
+    /// Handle tombstoning a node's registration of a topic on every lookupd
+    async fn handle_node_tombstone(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(node): AxumPath<String>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let Some(topic) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": "Missing topic"})));
+        };
+        if server.config.lookupd_http_addresses.is_empty() {
+            return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": "No lookupd configured"})));
+        }
+        let Some(address) = server.resolve_node(&node).await else {
+            return (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Node {} not found", node)})));
+        };
+        
+        // lookupd identifies producers by broadcast address and HTTP port
+        let node = address.trim_start_matches("http://").trim_start_matches("https://");
+        tracing::info!("Tombstoning topic {} on node {}", topic, node);
+        let results = server.send_to_all_lookupd("topic/tombstone", &[("topic", topic), ("node", node)]).await;
+        let (failures, lookupds) = fan_out_results(results);
+        let (status, message) = if failures == 0 {
+            ("ok", format!("Topic {} tombstoned on node {}", topic, node))
+        } else {
+            ("error", format!("Failed to tombstone topic {} on {} of {} lookupds", topic, failures, lookupds.len()))
+        };
+        (StatusCode::OK, Json(json!({
+            "status": status,
+            "message": message,
+            "lookupds": lookupds,
+        })))
+    }
+
+    /// Handle topology export; `format=terraform` renders Terraform JSON
+    async fn handle_export_topology(
+        State(server): State<Arc<NsqadminServer>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let format = params.get("format").map(String::as_str).unwrap_or("json");
+        if format != "json" && format != "terraform" {
+            return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": format!("Unsupported format: {}", format)})));
+        }
+
+        let topology = Topology::from_node_stats(&server.fetch_all_node_stats().await);
+        server.metrics.incr("topology.exports", 1);
+        if format == "terraform" {
+            (StatusCode::OK, Json(topology.to_terraform()))
+        } else {
+            (StatusCode::OK, Json(json!(topology)))
+        }
+    }
+
+    /// Stream topic stats as server-sent events: a `snapshot` of every topic
+    /// first, then a `delta` with the topics that changed each interval
+    async fn handle_stream(
+        State(server): State<Arc<NsqadminServer>>,
+    ) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+        let receiver = server.stream.subscribe();
+        server.metrics.incr("stream.connections", 1);
+        
+        let snapshot = match server.stream.latest() {
+            Some(snapshot) => snapshot,
+            None => server.stream.update(server.aggregate_topic_stats().await.unwrap_or_default()),
+        };
+        let first = futures::stream::once(async move { snapshot_event(&snapshot) });
+        
+        let updates = futures::stream::unfold((server, receiver), |(server, mut receiver)| async move {
+            let event = match receiver.recv().await {
+                Ok(delta) => Event::default().event("delta").json_data(delta),
+                // Too far behind to apply deltas; start over from the latest state
+                Err(broadcast::error::RecvError::Lagged(_)) => snapshot_event(&server.stream.latest().unwrap_or_default()),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            Some((event, (server, receiver)))
+        });
+        
+        Sse::new(first.chain(updates)).keep_alive(KeepAlive::default())
+    }
+    
+    /// Aggregate stats for stream subscribers every interval, pausing while
+    /// nobody is subscribed
+    async fn poll_stream(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.stream_interval));
+        loop {
+            interval.tick().await;
+            if !self.stream.has_subscribers() {
+                self.stream.reset();
+                continue;
+            }
+            let topics = self.aggregate_topic_stats().await.unwrap_or_default();
+            self.stream.update(topics);
+        }
+    }
+
+    /// Sample aggregated stats into the history every interval
+    async fn sample_history(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.history_interval));
+        loop {
+            interval.tick().await;
+            let topics = self.aggregate_topic_stats().await.unwrap_or_default();
+            self.history.record(&topics, chrono::Utc::now());
+        }
+    }
+    
+    /// Handle topic time series endpoint
+    async fn handle_topic_graph(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        server.graph(&topic, None).await
+    }
+    
+    /// Handle channel time series endpoint
+    async fn handle_channel_graph(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath((topic, channel)): AxumPath<(String, String)>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        server.graph(&topic, Some(&channel)).await
+    }
+    
+    /// Depth and message rate samples of a topic or channel, oldest first,
+    /// from Graphite when configured and from the in-memory history otherwise
+    async fn graph(&self, topic: &str, channel: Option<&str>) -> (StatusCode, Json<serde_json::Value>) {
+        if let Some(graphite) = &self.graphite {
+            let window = Duration::from_millis(self.config.history_window);
+            match graphite.series(topic, channel, window).await {
+                Ok(points) => {
+                    self.metrics.incr("graphite.requests", 1);
+                    return (StatusCode::OK, Json(json!({
+                        "topic": topic,
+                        "channel": channel,
+                        "source": "graphite",
+                        "window_ms": self.config.history_window,
+                        "samples": points,
+                    })));
+                }
+                Err(e) => {
+                    self.metrics.incr("graphite.errors", 1);
+                    tracing::warn!("Failed to fetch graph for {} from Graphite, using recorded history: {}", topic, e);
+                }
+            }
+        }
+        
+        match self.history.series(topic, channel) {
+            Some(samples) => (StatusCode::OK, Json(json!({
+                "topic": topic,
+                "channel": channel,
+                "source": "memory",
+                "interval_ms": self.config.history_interval,
+                "window_ms": self.config.history_window,
+                "samples": samples,
+            }))),
+            None => (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": "No samples recorded"}))),
+        }
+    }
+
+    /// Handle metrics endpoint in the Prometheus text format
+    async fn handle_metrics(State(server): State<Arc<NsqadminServer>>) -> impl IntoResponse {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            server.metrics.render_prometheus("nsqadmin"),
+        )
+    }
+
     // --- Helper methods ---
     
+    /// GET a JSON document from nsqd or lookupd, recording request latency
+    async fn get_json(&self, url: &str) -> std::result::Result<serde_json::Value, reqwest::Error> {
+        let start = std::time::Instant::now();
+        let result = async { self.http_client.get(url).send().await?.json::<serde_json::Value>().await }.await;
+        
+        self.metrics.histogram("upstream.latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+        self.metrics.incr("upstream.requests", 1);
+        if result.is_err() {
+            self.metrics.incr("upstream.errors", 1);
+        }
+        result
+    }
+    
     fn normalize_address(addr: &str) -> String {
         if addr.starts_with("http://") || addr.starts_with("https://") {
             addr.to_string()
@@ -217,16 +693,14 @@ impl NsqadminServer {
         for lookupd_addr in &self.config.lookupd_http_addresses {
             let base = Self::normalize_address(lookupd_addr);
             let url = format!("{}/nodes", base);
-            if let Ok(resp) = self.http_client.get(&url).send().await {
-                if let Ok(json) = resp.json::<serde_json::Value>().await {
-                    if let Some(arr) = json.get("producers").and_then(|v| v.as_array()) {
-                        for producer in arr {
-                            if let (Some(addr), Some(port)) = (
-                                producer.get("broadcast_address").and_then(|v| v.as_str()),
-                                producer.get("http_port").and_then(|v| v.as_u64())
-                            ) {
-                                addresses.insert(format!("http://{}:{}", addr, port));
-                            }
+            if let Ok(json) = self.get_json(&url).await {
+                if let Some(arr) = json.get("producers").and_then(|v| v.as_array()) {
+                    for producer in arr {
+                        if let (Some(addr), Some(port)) = (
+                            producer.get("broadcast_address").and_then(|v| v.as_str()),
+                            producer.get("http_port").and_then(|v| v.as_u64())
+                        ) {
+                            addresses.insert(format!("http://{}:{}", addr, port));
                         }
                     }
                 }
@@ -236,6 +710,34 @@ impl NsqadminServer {
         addresses.into_iter().collect()
     }
 
+    /// Find a known nsqd node by `host:port` or URL, returning its HTTP address
+    async fn resolve_node(&self, node: &str) -> Option<String> {
+        let address = Self::normalize_address(node);
+        self.get_all_nsqd_addresses().await.into_iter().find(|a| *a == address)
+    }
+    
+    /// Lookupd registration of the nsqd node at an HTTP address
+    async fn find_producer(&self, address: &str) -> Option<serde_json::Value> {
+        for lookupd_addr in &self.config.lookupd_http_addresses {
+            let url = format!("{}/nodes", Self::normalize_address(lookupd_addr));
+            let Ok(json) = self.get_json(&url).await else {
+                continue;
+            };
+            let producers = json.get("producers").and_then(|v| v.as_array()).into_iter().flatten();
+            for producer in producers {
+                if let (Some(host), Some(port)) = (
+                    producer.get("broadcast_address").and_then(|v| v.as_str()),
+                    producer.get("http_port").and_then(|v| v.as_u64())
+                ) {
+                    if format!("http://{}:{}", host, port) == address {
+                        return Some(producer.clone());
+                    }
+                }
+            }
+        }
+        None
+    }
+    
     /// Fetch producers from all sources
     async fn fetch_all_producers(&self) -> std::result::Result<Vec<serde_json::Value>, reqwest::Error> {
         let mut producers_map: HashMap<String, serde_json::Value> = HashMap::new();
@@ -244,13 +746,11 @@ impl NsqadminServer {
         for addr in &self.config.lookupd_http_addresses {
             let base = Self::normalize_address(addr);
             let url = format!("{}/nodes", base);
-            if let Ok(resp) = self.http_client.get(&url).send().await {
-                if let Ok(json) = resp.json::<serde_json::Value>().await {
-                    if let Some(arr) = json.get("producers").and_then(|v| v.as_array()) {
-                        for p in arr {
-                            if let Some(addr) = p.get("broadcast_address").and_then(|v| v.as_str()) {
-                                producers_map.insert(addr.to_string(), p.clone());
-                            }
+            if let Ok(json) = self.get_json(&url).await {
+                if let Some(arr) = json.get("producers").and_then(|v| v.as_array()) {
+                    for p in arr {
+                        if let Some(addr) = p.get("broadcast_address").and_then(|v| v.as_str()) {
+                            producers_map.insert(addr.to_string(), p.clone());
                         }
                     }
                 }
@@ -262,86 +762,150 @@ impl NsqadminServer {
             let base = Self::normalize_address(addr);
             
             // Try to get node info from nsqd /stats endpoint
-            if let Ok(resp) = self.http_client.get(&format!("{}/stats?format=json", base)).send().await {
-                if let Ok(stats) = resp.json::<serde_json::Value>().await {
-                    // Extract host and port from address
-                    let parts: Vec<&str> = base.trim_start_matches("http://").trim_start_matches("https://").split(':').collect();
-                    let host = parts.first().unwrap_or(&"127.0.0.1");
-                    let http_port = parts.get(1).and_then(|p| p.parse::<u64>().ok()).unwrap_or(4151);
-                    
-                    // Create producer info
-                    let producer = json!({
-                        "broadcast_address": host,
-                        "hostname": stats.get("host").and_then(|v| v.as_str()).unwrap_or(host),
-                        "http_port": http_port,
-                        "tcp_port": http_port - 1, // Assume TCP port is HTTP port - 1
-                        "version": stats.get("version").and_then(|v| v.as_str()).unwrap_or("1.3.0"),
-                        "last_update": chrono::Utc::now().timestamp(),
-                        "topics": stats.get("topics").and_then(|v| v.as_array()).map(|t| t.len()).unwrap_or(0),
-                    });
-                    
-                    producers_map.insert(host.to_string(), producer);
-                }
+            if let Ok(stats) = self.get_json(&format!("{}/stats?format=json", base)).await {
+                // Extract host and port from address
+                let parts: Vec<&str> = base.trim_start_matches("http://").trim_start_matches("https://").split(':').collect();
+                let host = parts.first().unwrap_or(&"127.0.0.1");
+                let http_port = parts.get(1).and_then(|p| p.parse::<u64>().ok()).unwrap_or(4151);
+                
+                // Create producer info
+                let producer = json!({
+                    "broadcast_address": host,
+                    "hostname": stats.get("host").and_then(|v| v.as_str()).unwrap_or(host),
+                    "http_port": http_port,
+                    "tcp_port": http_port - 1, // Assume TCP port is HTTP port - 1
+                    "version": stats.get("version").and_then(|v| v.as_str()).unwrap_or("1.3.0"),
+                    "last_update": chrono::Utc::now().timestamp(),
+                    "topics": stats.get("topics").and_then(|v| v.as_array()).map(|t| t.len()).unwrap_or(0),
+                });
+                
+                producers_map.insert(host.to_string(), producer);
             }
         }
         
         Ok(producers_map.into_values().collect())
     }
 
+    /// Fetch `/stats` from every nsqd node, skipping unreachable ones
+    async fn fetch_all_node_stats(&self) -> Vec<(String, serde_json::Value)> {
+        let nsqd_addresses = self.get_all_nsqd_addresses().await;
+        let responses = futures::future::join_all(nsqd_addresses.into_iter().map(|addr| async move {
+            let url = format!("{}/stats?format=json", addr);
+            match self.get_json(&url).await {
+                Ok(stats) => Some((addr, stats)),
+                Err(e) => {
+                    tracing::warn!("Failed to fetch stats from {}: {}", addr, e);
+                    None
+                }
+            }
+        }))
+        .await;
+        responses.into_iter().flatten().collect()
+    }
+
     /// Aggregate topic statistics from all nsqd nodes
     async fn aggregate_topic_stats(&self) -> std::result::Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+        let ttl = Duration::from_millis(self.config.stats_cache_ttl);
+        let mut cache = self.stats_cache.lock().await;
+        if let Some((fetched_at, topics)) = cache.as_ref() {
+            if fetched_at.elapsed() < ttl {
+                self.metrics.incr("aggregation.cache_hits", 1);
+                return Ok(topics.clone());
+            }
+        }
+        
+        let topics = self.fetch_topic_stats().await?;
+        if !ttl.is_zero() {
+            *cache = Some((std::time::Instant::now(), topics.clone()));
+        }
+        Ok(topics)
+    }
+    
+    /// Forget merged stats, so changes made through nsqadmin show at once
+    async fn invalidate_stats_cache(&self) {
+        *self.stats_cache.lock().await = None;
+    }
+    
+    /// Query every nsqd for stats, at most `upstream_concurrency` at a time,
+    /// and merge them by topic and channel
+    async fn fetch_topic_stats(&self) -> std::result::Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
         let nsqd_addresses = self.get_all_nsqd_addresses().await;
         let mut topics_map: HashMap<String, TopicInfo> = HashMap::new();
         
-        for nsqd_addr in nsqd_addresses {
-            let url = format!("{}/stats?format=json", nsqd_addr);
-            if let Ok(resp) = self.http_client.get(&url).send().await {
-                if let Ok(json) = resp.json::<serde_json::Value>().await {
-                    if let Some(topics) = json.get("topics").and_then(|v| v.as_array()) {
-                        for topic in topics {
-                            if let Some(topic_name) = topic.get("topic_name").and_then(|v| v.as_str()) {
-                                let entry = topics_map.entry(topic_name.to_string()).or_insert_with(|| TopicInfo {
-                                    topic_name: topic_name.to_string(),
-                                    channels: Vec::new(),
-                                    depth: 0,
-                                    backend_depth: 0,
-                                    message_count: 0,
-                                    paused: false,
-                                    nodes: Vec::new(),
-                                });
-                                
-                                entry.nodes.push(nsqd_addr.clone());
-                                entry.depth += topic.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
-                                entry.backend_depth += topic.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
-                                entry.message_count += topic.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                entry.paused = topic.get("paused").and_then(|v| v.as_bool()).unwrap_or(false);
-                                
-                                // Aggregate channels
-                                if let Some(channels) = topic.get("channels").and_then(|v| v.as_array()) {
-                                    for channel in channels {
-                                        if let Some(channel_name) = channel.get("channel_name").and_then(|v| v.as_str()) {
-                                            if let Some(existing_channel) = entry.channels.iter_mut().find(|c| c.channel_name == channel_name) {
-                                                existing_channel.depth += channel.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.backend_depth += channel.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.message_count += channel.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.in_flight_count += channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.deferred_count += channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.requeue_count += channel.get("requeue_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                                existing_channel.timeout_count += channel.get("timeout_count").and_then(|v| v.as_u64()).unwrap_or(0);
-                                            } else {
-                                                entry.channels.push(ChannelInfo {
-                                                    channel_name: channel_name.to_string(),
-                                                    depth: channel.get("depth").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    backend_depth: channel.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    message_count: channel.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    in_flight_count: channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    deferred_count: channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    requeue_count: channel.get("requeue_count").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    timeout_count: channel.get("timeout_count").and_then(|v| v.as_u64()).unwrap_or(0),
-                                                    paused: channel.get("paused").and_then(|v| v.as_bool()).unwrap_or(false),
-                                                    clients: Vec::new(),
-                                                });
-                                            }
+        // Buffered in order, so topics list their nodes in the same order
+        // whichever answers first
+        let responses: Vec<_> = futures::stream::iter(nsqd_addresses)
+            .map(|nsqd_addr| async move {
+                let stats = self.get_json(&format!("{}/stats?format=json", nsqd_addr)).await;
+                (nsqd_addr, stats)
+            })
+            .buffered(self.config.upstream_concurrency.max(1))
+            .collect()
+            .await;
+        
+        for (nsqd_addr, stats) in responses {
+            let stats = stats.inspect_err(|e| {
+                tracing::warn!("Failed to fetch stats from {}: {}", nsqd_addr, e);
+                self.metrics.incr("aggregation.errors", 1);
+            });
+            if let Ok(json) = stats {
+                if let Some(topics) = json.get("topics").and_then(|v| v.as_array()) {
+                    for topic in topics {
+                        if let Some(topic_name) = topic.get("topic_name").and_then(|v| v.as_str()) {
+                            let entry = topics_map.entry(topic_name.to_string()).or_insert_with(|| TopicInfo {
+                                topic_name: topic_name.to_string(),
+                                channels: Vec::new(),
+                                depth: 0,
+                                backend_depth: 0,
+                                message_count: 0,
+                                paused: false,
+                                nodes: Vec::new(),
+                                created_at: None,
+                                last_publish_at: None,
+                                last_delivery_at: None,
+                            });
+                            
+                            entry.nodes.push(nsqd_addr.clone());
+                            entry.created_at = earliest(entry.created_at, parse_timestamp(topic, "created_at"));
+                            entry.last_publish_at = entry.last_publish_at.max(parse_timestamp(topic, "last_publish_at"));
+                            entry.last_delivery_at = entry.last_delivery_at.max(parse_timestamp(topic, "last_delivery_at"));
+                            entry.depth += topic.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                            entry.backend_depth += topic.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                            entry.message_count += topic.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                            // A topic or channel paused on any node is reported as paused
+                            entry.paused |= topic.get("paused").and_then(|v| v.as_bool()).unwrap_or(false);
+                            
+                            // Aggregate channels
+                            if let Some(channels) = topic.get("channels").and_then(|v| v.as_array()) {
+                                for channel in channels {
+                                    if let Some(channel_name) = channel.get("channel_name").and_then(|v| v.as_str()) {
+                                        if let Some(existing_channel) = entry.channels.iter_mut().find(|c| c.channel_name == channel_name) {
+                                            existing_channel.depth += channel.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.backend_depth += channel.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.message_count += channel.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.in_flight_count += channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.deferred_count += channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.requeue_count += channel.get("requeue_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.timeout_count += channel.get("timeout_count").and_then(|v| v.as_u64()).unwrap_or(0);
+                                            existing_channel.paused |= channel.get("paused").and_then(|v| v.as_bool()).unwrap_or(false);
+                                            existing_channel.created_at = earliest(existing_channel.created_at, parse_timestamp(channel, "created_at"));
+                                            existing_channel.last_delivery_at = existing_channel.last_delivery_at.max(parse_timestamp(channel, "last_delivery_at"));
+                                            existing_channel.clients.extend(ClientInfo::from_channel_stats(&nsqd_addr, channel));
+                                        } else {
+                                            entry.channels.push(ChannelInfo {
+                                                channel_name: channel_name.to_string(),
+                                                depth: channel.get("depth").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                backend_depth: channel.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                message_count: channel.get("message_count").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                in_flight_count: channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                deferred_count: channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                requeue_count: channel.get("requeue_count").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                timeout_count: channel.get("timeout_count").and_then(|v| v.as_u64()).unwrap_or(0),
+                                                paused: channel.get("paused").and_then(|v| v.as_bool()).unwrap_or(false),
+                                                clients: ClientInfo::from_channel_stats(&nsqd_addr, channel),
+                                                created_at: parse_timestamp(channel, "created_at"),
+                                                last_delivery_at: parse_timestamp(channel, "last_delivery_at"),
+                                            });
                                         }
                                     }
                                 }
@@ -355,8 +919,15 @@ impl NsqadminServer {
         let topics: Vec<serde_json::Value> = topics_map.into_values()
             .map(|t| json!({
                 "topic_name": t.topic_name,
+                "created_at": t.created_at,
+                "last_publish_at": t.last_publish_at,
+                "last_delivery_at": t.last_delivery_at,
+                "stale_since": t.last_publish_at.max(t.last_delivery_at).or(t.created_at),
                 "channels": t.channels.into_iter().map(|c| json!({
                     "channel_name": c.channel_name,
+                    "created_at": c.created_at,
+                    "last_delivery_at": c.last_delivery_at,
+                    "stale_since": c.last_delivery_at.or(c.created_at),
                     "depth": c.depth,
                     "backend_depth": c.backend_depth,
                     "message_count": c.message_count,
@@ -365,6 +936,7 @@ impl NsqadminServer {
                     "requeue_count": c.requeue_count,
                     "timeout_count": c.timeout_count,
                     "paused": c.paused,
+                    "client_count": c.clients.len(),
                     "clients": c.clients,
                 })).collect::<Vec<_>>(),
                 "depth": t.depth,
@@ -399,42 +971,169 @@ impl NsqadminServer {
         }))
     }
     
+    /// Send command to a single nsqd node
+    async fn send_to_nsqd(&self, addr: &str, endpoint: &str, topic: &str, channel: Option<&str>) -> std::result::Result<(), String> {
+        let mut url = format!("{}/{}?topic={}", addr, endpoint, topic);
+        if let Some(ch) = channel {
+            url = format!("{}&channel={}", url, ch);
+        }
+
+        let result = match self.http_client.post(&url).send().await {
+            Ok(resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(format!("status {}", resp.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        self.invalidate_stats_cache().await;
+        result
+    }
+
+    /// Empty a topic on a single nsqd node, returning the counts it reports
+    async fn empty_topic_on_nsqd(&self, addr: &str, topic: &str) -> std::result::Result<serde_json::Value, String> {
+        let url = format!("{}/topic/empty?topic={}", addr, topic);
+        let result = match self.http_client.post(&url).send().await {
+            Ok(resp) if resp.status().is_success() => resp.json().await.map_err(|e| e.to_string()),
+            Ok(resp) => Err(format!("status {}", resp.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        self.invalidate_stats_cache().await;
+        result
+    }
+
+    /// POST to every lookupd concurrently, returning the result of each
+    async fn send_to_all_lookupd(&self, endpoint: &str, params: &[(&str, &str)]) -> Vec<(String, std::result::Result<(), String>)> {
+        let addresses: Vec<String> = self.config.lookupd_http_addresses.iter().map(|a| Self::normalize_address(a)).collect();
+        let results = futures::future::join_all(addresses.iter().map(|addr| async move {
+            match self.http_client.post(format!("{}/{}", addr, endpoint)).query(params).send().await {
+                Ok(resp) if resp.status().is_success() => Ok(()),
+                Ok(resp) => Err(format!("status {}", resp.status())),
+                Err(e) => Err(e.to_string()),
+            }
+        }))
+        .await;
+        self.invalidate_stats_cache().await;
+        addresses.into_iter().zip(results).collect()
+    }
+    
     /// Send command to all nsqd nodes for a topic
     async fn send_to_all_nsqd(&self, endpoint: &str, topic: &str, channel: Option<&str>) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let nsqd_addresses = self.get_all_nsqd_addresses().await;
         
         for addr in nsqd_addresses {
-            let mut url = format!("{}/{}?topic={}", addr, endpoint, topic);
-            if let Some(ch) = channel {
-                url = format!("{}&channel={}", url, ch);
-            }
-            
-            match self.http_client.post(&url).send().await {
-                Ok(resp) => {
-                    if !resp.status().is_success() {
-                        tracing::warn!("Failed to {} topic {} on {}: status {}", endpoint, topic, addr, resp.status());
-                    }
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to {} topic {} on {}: {}", endpoint, topic, addr, e);
-                }
+            if let Err(e) = self.send_to_nsqd(&addr, endpoint, topic, channel).await {
+                tracing::warn!("Failed to {} topic {} on {}: {}", endpoint, topic, addr, e);
             }
         }
         
         Ok(())
     }
+
+    /// Start a job in the background and return it immediately
+    fn start_job(self: &Arc<Self>, request: JobRequest) -> Job {
+        let job = self.jobs.create(&request);
+        let server = self.clone();
+        let job_id = job.id.clone();
+
+        tokio::spawn(async move {
+            let addresses = server.get_all_nsqd_addresses().await;
+            server.jobs.start(&job_id, &addresses);
+
+            let channel = request.channel.as_deref();
+            futures::future::join_all(addresses.iter().map(|addr| {
+                let server = server.clone();
+                let job_id = job_id.clone();
+                let request = &request;
+                async move {
+                    let result = server.send_to_nsqd(addr, &request.operation, &request.topic, channel).await;
+                    if let Err(e) = &result {
+                        tracing::warn!("Job {}: {} failed on {}: {}", job_id, request.operation, addr, e);
+                    }
+                    server.jobs.record(&job_id, addr, result);
+                }
+            }))
+            .await;
+
+            server.metrics.incr("jobs.completed", 1);
+            tracing::info!("Job {} ({} {}) finished", job_id, request.operation, request.topic);
+        });
+
+        self.metrics.incr("jobs.started", 1);
+        job
+    }
+
+    /// Handle job creation
+    async fn handle_job_create(
+        State(server): State<Arc<NsqadminServer>>,
+        Json(request): Json<JobRequest>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if let Err(e) = request.validate() {
+            return (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": e})));
+        }
+
+        tracing::info!("Starting job: {} on topic: {}", request.operation, request.topic);
+        let job = server.start_job(request);
+        (StatusCode::ACCEPTED, Json(json!({
+            "job_id": job.id,
+            "status_url": format!("/api/jobs/{}", job.id),
+            "job": job,
+        })))
+    }
+
+    /// Handle job detail
+    async fn handle_job_detail(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(id): AxumPath<String>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        match server.jobs.get(&id) {
+            Some(job) => (StatusCode::OK, Json(json!(job))),
+            None => (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Job {} not found", id)}))),
+        }
+    }
+
+    /// Handle job history
+    async fn handle_jobs_list(State(server): State<Arc<NsqadminServer>>) -> Json<serde_json::Value> {
+        Json(json!({
+            "jobs": server.jobs.list()
+        }))
+    }
     
-    /// Handle topic create
+    /// Response to a request naming a topic or channel that breaks the
+    /// naming rules, before it is sent to any node
+    fn invalid_name_response(error: nsq_common::InvalidName) -> (StatusCode, Json<serde_json::Value>) {
+        (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": format!("{} {}", error.code(), error)})))
+    }
+    
+    /// Handle topic create: the topic is registered on every lookupd and
+    /// created on every nsqd node, reporting the targets that failed
     async fn handle_topic_create(
         State(server): State<Arc<NsqadminServer>>,
         AxumPath(topic): AxumPath<String>
-    ) -> Json<serde_json::Value> {
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if let Err(e) = nsq_common::validate_topic_name(&topic) {
+            return Self::invalid_name_response(e);
+        }
         tracing::info!("Creating topic: {}", topic);
         
-        match server.send_to_all_nsqd("topic/create", &topic, None).await {
-            Ok(_) => Json(json!({"status": "ok", "message": format!("Topic {} created", topic)})),
-            Err(e) => Json(json!({"status": "error", "message": format!("Failed to create topic {}: {}", topic, e)})),
-        }
+        let addresses = server.get_all_nsqd_addresses().await;
+        let params = [("topic", topic.as_str())];
+        let (lookupd_results, nsqd_results) = tokio::join!(
+            server.send_to_all_lookupd("topic/create", &params),
+            futures::future::join_all(addresses.iter().map(|addr| server.send_to_nsqd(addr, "topic/create", &topic, None))),
+        );
+        let (lookupd_failures, lookupds) = fan_out_results(lookupd_results);
+        let (nsqd_failures, nodes) = fan_out_results(addresses.into_iter().zip(nsqd_results).collect());
+        
+        let failures = lookupd_failures + nsqd_failures;
+        let (status, message) = if failures == 0 {
+            ("ok", format!("Topic {} created", topic))
+        } else {
+            ("error", format!("Failed to create topic {} on {} of {} targets", topic, failures, lookupds.len() + nodes.len()))
+        };
+        (StatusCode::OK, Json(json!({
+            "status": status,
+            "message": message,
+            "lookupds": lookupds,
+            "nodes": nodes,
+        })))
     }
     
     /// Handle topic pause
@@ -463,6 +1162,263 @@ impl NsqadminServer {
         }
     }
     
+    /// Handle maintenance progress of a topic
+    async fn handle_maintenance_status(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        match server.maintenance.get(&topic) {
+            Some(maintenance) => (StatusCode::OK, Json(json!(maintenance))),
+            None => (StatusCode::NOT_FOUND, Json(json!({"status": "error", "message": format!("Topic {} was never put in maintenance", topic)}))),
+        }
+    }
+    
+    /// Put a topic in maintenance in the background, returning its progress
+    async fn handle_maintenance_enter(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if let Err(e) = nsq_common::validate_topic_name(&topic) {
+            return Self::invalid_name_response(e);
+        }
+        
+        let addresses = server.get_all_nsqd_addresses().await;
+        let maintenance = match server.maintenance.begin(&topic, MaintenanceState::Pausing, &addresses) {
+            Ok(maintenance) => maintenance,
+            Err(current) => return Self::maintenance_conflict(&topic, current),
+        };
+        tracing::info!("Putting topic {} in maintenance on {} nodes", topic, addresses.len());
+        
+        let background = server.clone();
+        let background_topic = topic.clone();
+        tokio::spawn(async move {
+            background.enter_maintenance(&background_topic, &addresses).await;
+        });
+        (StatusCode::ACCEPTED, Json(json!({
+            "status": "ok",
+            "status_url": format!("/api/topic/{}/maintenance", topic),
+            "maintenance": maintenance,
+        })))
+    }
+    
+    /// Take a topic out of maintenance by unpausing it on every node
+    async fn handle_maintenance_exit(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if let Err(e) = nsq_common::validate_topic_name(&topic) {
+            return Self::invalid_name_response(e);
+        }
+        
+        let addresses = server.get_all_nsqd_addresses().await;
+        if let Err(current) = server.maintenance.begin(&topic, MaintenanceState::Resuming, &addresses) {
+            return Self::maintenance_conflict(&topic, current);
+        }
+        tracing::info!("Taking topic {} out of maintenance", topic);
+        
+        let failures = server.set_paused_on_nodes(&topic, &addresses, false).await;
+        server.maintenance.update(&topic, |maintenance| {
+            if failures == 0 {
+                maintenance.state = MaintenanceState::Resumed;
+            } else {
+                maintenance.state = MaintenanceState::Failed;
+                maintenance.error = Some(format!("Failed to unpause on {} of {} nodes", failures, addresses.len()));
+            }
+        });
+        server.metrics.incr("maintenance.resumed", 1);
+        
+        let (status, result) = if failures == 0 { (StatusCode::OK, "ok") } else { (StatusCode::BAD_GATEWAY, "error") };
+        (status, Json(json!({"status": result, "maintenance": server.maintenance.get(&topic)})))
+    }
+    
+    fn maintenance_conflict(topic: &str, current: crate::maintenance::Maintenance) -> (StatusCode, Json<serde_json::Value>) {
+        (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": format!("Maintenance of topic {} is still in progress", topic),
+            "maintenance": current,
+        })))
+    }
+    
+    /// Pause or unpause a topic on the given nodes, recording each node's
+    /// outcome, and return how many failed
+    async fn set_paused_on_nodes(&self, topic: &str, addresses: &[String], paused: bool) -> usize {
+        let endpoint = if paused { "topic/pause" } else { "topic/unpause" };
+        let results = futures::future::join_all(
+            addresses.iter().map(|addr| self.send_to_nsqd(addr, endpoint, topic, None))
+        ).await;
+        let mut failures = 0;
+        for (addr, result) in addresses.iter().zip(results) {
+            if let Err(e) = &result {
+                tracing::warn!("Failed to {} {} on {}: {}", endpoint, topic, addr, e);
+                failures += 1;
+            }
+            self.maintenance.update_node(topic, addr, |node| match result {
+                Ok(()) => {
+                    node.paused = paused;
+                    node.error = None;
+                }
+                Err(e) => node.error = Some(e),
+            });
+        }
+        failures
+    }
+    
+    /// Empty a topic on the given nodes, adding what each discarded to its
+    /// progress, and return how many failed
+    async fn empty_on_nodes(&self, topic: &str, addresses: &[String]) -> usize {
+        let results = futures::future::join_all(
+            addresses.iter().map(|addr| self.empty_topic_on_nsqd(addr, topic))
+        ).await;
+        let mut failures = 0;
+        for (addr, result) in addresses.iter().zip(results) {
+            if let Err(e) = &result {
+                tracing::warn!("Failed to empty topic {} on {}: {}", topic, addr, e);
+                failures += 1;
+            }
+            self.maintenance.update_node(topic, addr, |node| match result {
+                Ok(counts) => {
+                    node.emptied_depth += counts.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                    node.emptied_backend_depth += counts.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                }
+                Err(e) => node.error = Some(e),
+            });
+        }
+        failures
+    }
+    
+    /// In-flight and deferred messages of a topic's channels on one node
+    async fn topic_in_flight_on_nsqd(&self, addr: &str, topic: &str) -> std::result::Result<(u64, u64), String> {
+        let stats = self.get_json(&format!("{}/stats?format=json", addr)).await.map_err(|e| e.to_string())?;
+        let channels = stats.get("topics").and_then(|v| v.as_array()).into_iter().flatten()
+            .filter(|t| t.get("topic_name").and_then(|v| v.as_str()) == Some(topic))
+            .filter_map(|t| t.get("channels").and_then(|v| v.as_array()))
+            .flatten();
+        let (mut in_flight, mut deferred) = (0, 0);
+        for channel in channels {
+            in_flight += channel.get("in_flight_count").and_then(|v| v.as_u64()).unwrap_or(0);
+            deferred += channel.get("deferred_count").and_then(|v| v.as_u64()).unwrap_or(0);
+        }
+        Ok((in_flight, deferred))
+    }
+    
+    /// Pause, empty and drain a topic on every node. The pause is undone
+    /// where it succeeded when any node could not be paused.
+    async fn enter_maintenance(&self, topic: &str, addresses: &[String]) {
+        let fail = |error: String| {
+            tracing::warn!("Maintenance of topic {} failed: {}", topic, error);
+            self.metrics.incr("maintenance.failed", 1);
+            self.maintenance.update(topic, |maintenance| {
+                maintenance.state = MaintenanceState::Failed;
+                maintenance.error = Some(error);
+            });
+        };
+        
+        let failures = self.set_paused_on_nodes(topic, addresses, true).await;
+        if failures > 0 {
+            let paused: Vec<String> = self.maintenance.get(topic).map(|maintenance| maintenance.nodes).unwrap_or_default()
+                .into_iter()
+                .filter(|node| node.paused)
+                .map(|node| node.address)
+                .collect();
+            self.set_paused_on_nodes(topic, &paused, false).await;
+            return fail(format!("Failed to pause on {} of {} nodes", failures, addresses.len()));
+        }
+        
+        self.maintenance.update(topic, |maintenance| maintenance.state = MaintenanceState::Draining);
+        let failures = self.empty_on_nodes(topic, addresses).await;
+        if failures > 0 {
+            return fail(format!("Failed to empty on {} of {} nodes", failures, addresses.len()));
+        }
+        
+        // Consumers still hold in-flight and deferred messages; wait for them
+        // to be finished or requeued, then discard what was requeued
+        let deadline = std::time::Instant::now() + MAINTENANCE_DRAIN_TIMEOUT;
+        loop {
+            let results = futures::future::join_all(
+                addresses.iter().map(|addr| self.topic_in_flight_on_nsqd(addr, topic))
+            ).await;
+            let mut remaining = 0;
+            for (addr, result) in addresses.iter().zip(results) {
+                match result {
+                    Ok((in_flight, deferred)) => {
+                        remaining += in_flight + deferred;
+                        self.maintenance.update_node(topic, addr, |node| {
+                            node.in_flight_count = in_flight;
+                            node.deferred_count = deferred;
+                            node.error = None;
+                        });
+                    }
+                    Err(e) => {
+                        remaining += 1;
+                        self.maintenance.update_node(topic, addr, |node| node.error = Some(e));
+                    }
+                }
+            }
+            if remaining == 0 {
+                break;
+            }
+            if std::time::Instant::now() >= deadline {
+                return fail(format!("Messages still in flight after {}s", MAINTENANCE_DRAIN_TIMEOUT.as_secs()));
+            }
+            tokio::time::sleep(MAINTENANCE_POLL_INTERVAL).await;
+        }
+        let failures = self.empty_on_nodes(topic, addresses).await;
+        if failures > 0 {
+            return fail(format!("Failed to empty on {} of {} nodes", failures, addresses.len()));
+        }
+        
+        self.maintenance.update(topic, |maintenance| maintenance.state = MaintenanceState::Active);
+        self.metrics.incr("maintenance.entered", 1);
+        tracing::info!("Topic {} is in maintenance", topic);
+    }
+    
+    /// Handle topic empty, reporting how many messages each node discarded
+    async fn handle_topic_empty(
+        State(server): State<Arc<NsqadminServer>>,
+        AxumPath(topic): AxumPath<String>
+    ) -> Json<serde_json::Value> {
+        tracing::info!("Emptying topic: {}", topic);
+        
+        let addresses = server.get_all_nsqd_addresses().await;
+        let results = futures::future::join_all(
+            addresses.iter().map(|addr| server.empty_topic_on_nsqd(addr, &topic))
+        ).await;
+        
+        let mut depth = 0;
+        let mut backend_depth = 0;
+        let mut failures = 0;
+        let mut nodes = Vec::new();
+        for (addr, result) in addresses.iter().zip(results) {
+            match result {
+                Ok(counts) => {
+                    let node_depth = counts.get("depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                    let node_backend_depth = counts.get("backend_depth").and_then(|v| v.as_u64()).unwrap_or(0);
+                    depth += node_depth;
+                    backend_depth += node_backend_depth;
+                    nodes.push(json!({"address": addr, "depth": node_depth, "backend_depth": node_backend_depth}));
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to empty topic {} on {}: {}", topic, addr, e);
+                    failures += 1;
+                    nodes.push(json!({"address": addr, "error": e}));
+                }
+            }
+        }
+        
+        let (status, message) = if failures == 0 {
+            ("ok", format!("Topic {} emptied", topic))
+        } else {
+            ("error", format!("Failed to empty topic {} on {} of {} nodes", topic, failures, addresses.len()))
+        };
+        Json(json!({
+            "status": status,
+            "message": message,
+            "depth": depth,
+            "backend_depth": backend_depth,
+            "nodes": nodes,
+        }))
+    }
+    
     /// Handle topic delete
     async fn handle_topic_delete(
         State(server): State<Arc<NsqadminServer>>,
@@ -480,12 +1436,15 @@ impl NsqadminServer {
     async fn handle_channel_create(
         State(server): State<Arc<NsqadminServer>>,
         AxumPath((topic, channel)): AxumPath<(String, String)>
-    ) -> Json<serde_json::Value> {
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        if let Err(e) = nsq_common::validate_topic_name(&topic).and_then(|_| nsq_common::validate_channel_name(&channel)) {
+            return Self::invalid_name_response(e);
+        }
         tracing::info!("Creating channel: {} on topic: {}", channel, topic);
         
         match server.send_to_all_nsqd("channel/create", &topic, Some(&channel)).await {
-            Ok(_) => Json(json!({"status": "ok", "message": format!("Channel {} on topic {} created", channel, topic)})),
-            Err(e) => Json(json!({"status": "error", "message": format!("Failed to create channel {} on topic {}: {}", channel, topic, e)})),
+            Ok(_) => (StatusCode::OK, Json(json!({"status": "ok", "message": format!("Channel {} on topic {} created", channel, topic)}))),
+            Err(e) => (StatusCode::OK, Json(json!({"status": "error", "message": format!("Failed to create channel {} on topic {}: {}", channel, topic, e)}))),
         }
     }
     
@@ -550,6 +1509,57 @@ impl Clone for NsqadminServer {
             http_client: self.http_client.clone(),
             start_time: self.start_time,
             start_instant: self.start_instant,
+            jobs: self.jobs.clone(),
+            maintenance: self.maintenance.clone(),
+            stream: self.stream.clone(),
+            history: self.history.clone(),
+            graphite: self.graphite.clone(),
+            auth: self.auth.clone(),
+            access_log: self.access_log.clone(),
+            stats_cache: self.stats_cache.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Server-sent event carrying every topic
+/// Per-target entries of a fan-out, with an `error` for each failed target,
+/// and the number of failures
+fn fan_out_results(results: Vec<(String, std::result::Result<(), String>)>) -> (usize, Vec<serde_json::Value>) {
+    let mut failures = 0;
+    let entries = results.into_iter()
+        .map(|(address, result)| match result {
+            Ok(()) => json!({"address": address}),
+            Err(e) => {
+                tracing::warn!("Request to {} failed: {}", address, e);
+                failures += 1;
+                json!({"address": address, "error": e})
+            }
+        })
+        .collect();
+    (failures, entries)
+}
+
+fn snapshot_event(snapshot: &TopicSnapshot) -> std::result::Result<Event, axum::Error> {
+    Event::default().event("snapshot").json_data(json!({
+        "topics": snapshot.values().collect::<Vec<_>>(),
+    }))
+}
+
+/// Parse an RFC 3339 timestamp field from nsqd or nsqadmin JSON
+pub(crate) fn parse_timestamp(value: &serde_json::Value, key: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    value.get(key)
+        .and_then(|v| v.as_str())
+        .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.with_timezone(&chrono::Utc))
+}
+
+/// Earlier of two optional timestamps
+fn earliest(
+    a: Option<chrono::DateTime<chrono::Utc>>,
+    b: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
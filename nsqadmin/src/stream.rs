@@ -0,0 +1,109 @@
+//! Live stats stream
+//!
+//! A single poller aggregates topic stats from the cluster and broadcasts what
+//! changed to every `/api/stream` subscriber, so the number of open dashboards
+//! does not multiply the requests sent to nsqd. Polling pauses while nobody is
+//! subscribed.
+
+use std::collections::BTreeMap;
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+/// Number of updates a slow subscriber may fall behind before it is sent a
+/// fresh snapshot instead
+const STREAM_CAPACITY: usize = 16;
+
+/// Aggregated topics keyed by name
+pub type TopicSnapshot = BTreeMap<String, Value>;
+
+/// Fan-out of topic stats changes to stream subscribers
+pub struct StatsStream {
+    sender: broadcast::Sender<Value>,
+    /// Snapshot the next delta is computed against
+    latest: RwLock<Option<TopicSnapshot>>,
+}
+
+impl Default for StatsStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsStream {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(STREAM_CAPACITY).0,
+            latest: RwLock::new(None),
+        }
+    }
+
+    /// Receive every delta broadcast from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Value> {
+        self.sender.subscribe()
+    }
+
+    /// Whether any stream is open
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// The most recent snapshot, if the poller is running
+    pub fn latest(&self) -> Option<TopicSnapshot> {
+        self.latest.read().clone()
+    }
+
+    /// Record freshly aggregated topics, broadcasting the changes since the
+    /// previous snapshot
+    pub fn update(&self, topics: Vec<Value>) -> TopicSnapshot {
+        let current = snapshot(topics);
+        let previous = self.latest.write().replace(current.clone());
+        if let Some(delta) = previous.and_then(|previous| delta(&previous, &current)) {
+            // Sending only fails when the last subscriber just left
+            let _ = self.sender.send(delta);
+        }
+        current
+    }
+
+    /// Forget the snapshot, so the next subscriber starts from fresh stats
+    pub fn reset(&self) {
+        *self.latest.write() = None;
+    }
+}
+
+/// Key aggregated topics by name, sorting their nodes and channels so two
+/// snapshots of an unchanged cluster compare equal
+pub fn snapshot(topics: Vec<Value>) -> TopicSnapshot {
+    let mut snapshot = TopicSnapshot::new();
+    for mut topic in topics {
+        let Some(name) = topic.get("topic_name").and_then(|v| v.as_str()).map(str::to_string) else {
+            continue;
+        };
+        if let Some(nodes) = topic.get_mut("nodes").and_then(|v| v.as_array_mut()) {
+            nodes.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+        }
+        if let Some(channels) = topic.get_mut("channels").and_then(|v| v.as_array_mut()) {
+            channels.sort_by(|a, b| a.get("channel_name").and_then(|v| v.as_str()).cmp(&b.get("channel_name").and_then(|v| v.as_str())));
+        }
+        snapshot.insert(name, topic);
+    }
+    snapshot
+}
+
+/// Topics that are new or changed in `current`, and the names of topics that
+/// disappeared, or `None` when nothing changed
+pub fn delta(previous: &TopicSnapshot, current: &TopicSnapshot) -> Option<Value> {
+    let changed: Vec<&Value> = current.iter()
+        .filter(|(name, topic)| previous.get(*name) != Some(*topic))
+        .map(|(_, topic)| topic)
+        .collect();
+    let removed: Vec<&String> = previous.keys().filter(|name| !current.contains_key(*name)).collect();
+
+    if changed.is_empty() && removed.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "topics": changed,
+        "removed": removed,
+    }))
+}
@@ -0,0 +1,118 @@
+//! Search, sorting and pagination of aggregated topics
+//!
+//! Large clusters have thousands of topics, so `/api/topics` and
+//! `/api/topics/summary` take a substring to search topic and channel names
+//! for, a sort key and a page of results to return.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use serde_json::Value;
+use crate::server::parse_timestamp;
+
+/// Order topics, and the channels of each topic, are listed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// By name, A to Z
+    Name,
+    /// Deepest first
+    Depth,
+    /// Busiest first, by message rate
+    Rate,
+    /// Longest idle first
+    StaleSince,
+}
+
+/// Search, sort and page requested in a query string
+#[derive(Debug, Clone, Default)]
+pub struct TopicQuery {
+    search: Option<String>,
+    sort: Option<SortKey>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl TopicQuery {
+    /// Read `q`, `sort`, `offset` and `limit`
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let sort = match params.get("sort").map(String::as_str) {
+            None => None,
+            Some("name") => Some(SortKey::Name),
+            Some("depth") => Some(SortKey::Depth),
+            Some("rate") => Some(SortKey::Rate),
+            Some("stale_since") => Some(SortKey::StaleSince),
+            Some(other) => return Err(format!("Invalid sort '{}': expected name, depth, rate or stale_since", other)),
+        };
+        let offset = match params.get("offset") {
+            Some(offset) => offset.parse().map_err(|_| format!("Invalid offset '{}'", offset))?,
+            None => 0,
+        };
+        let limit = match params.get("limit") {
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => return Err(format!("Invalid limit '{}': expected a positive number", limit)),
+            },
+            None => None,
+        };
+        let search = params.get("q").map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+        Ok(Self { search, sort, offset, limit })
+    }
+
+    /// Apply the query to aggregated topics, returning how many matched and
+    /// the requested page of them. A topic matches when its name contains
+    /// the search, or with only its matching channels when one of their
+    /// names does.
+    pub fn apply(&self, topics: Vec<Value>) -> (usize, Vec<Value>) {
+        let mut topics: Vec<Value> = topics.into_iter().filter_map(|topic| self.search(topic)).collect();
+        if let Some(key) = self.sort {
+            topics.sort_by(|a, b| compare(key, a, b, "topic_name"));
+            for topic in &mut topics {
+                if let Some(channels) = topic.get_mut("channels").and_then(|v| v.as_array_mut()) {
+                    channels.sort_by(|a, b| compare(key, a, b, "channel_name"));
+                }
+            }
+        }
+
+        let total = topics.len();
+        let page = topics.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        (total, page)
+    }
+
+    fn search(&self, mut topic: Value) -> Option<Value> {
+        let Some(search) = &self.search else {
+            return Some(topic);
+        };
+        let matches = |value: &Value, key: &str| {
+            value.get(key).and_then(|v| v.as_str()).is_some_and(|name| name.to_lowercase().contains(search.as_str()))
+        };
+        if matches(&topic, "topic_name") {
+            return Some(topic);
+        }
+        let channels = topic.get_mut("channels").and_then(|v| v.as_array_mut())?;
+        channels.retain(|channel| matches(channel, "channel_name"));
+        (!channels.is_empty()).then_some(topic)
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
+
+/// Order of two topics or channels by `key`, then by name
+fn compare(key: SortKey, a: &Value, b: &Value, name_key: &str) -> Ordering {
+    let name = |value: &Value| value.get(name_key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let number = |value: &Value, field: &str| value.get(field).and_then(|v| v.as_f64()).unwrap_or(0.0);
+    let ordering = match key {
+        SortKey::Name => Ordering::Equal,
+        SortKey::Depth => number(b, "depth").total_cmp(&number(a, "depth")),
+        SortKey::Rate => number(b, "rate").total_cmp(&number(a, "rate")),
+        SortKey::StaleSince => parse_timestamp(a, "stale_since").cmp(&parse_timestamp(b, "stale_since")),
+    };
+    ordering.then_with(|| name(a).cmp(&name(b)))
+}
@@ -0,0 +1,166 @@
+//! Cluster topology export
+//!
+//! Describes every topic, channel and nsqd node with the settings an operator
+//! controls, leaving out runtime counters such as depth. Entries are sorted so
+//! two exports of an unchanged cluster differ only in `exported_at`, which
+//! makes the output usable as a backup of desired state and for drift
+//! detection.
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Complete topology of the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Topology {
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    pub nodes: Vec<NodeSpec>,
+    pub topics: Vec<TopicSpec>,
+}
+
+/// An nsqd node and its settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeSpec {
+    pub http_address: String,
+    pub version: String,
+    pub implicit_creation: bool,
+}
+
+/// A topic and the nodes it exists on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicSpec {
+    pub name: String,
+    pub paused: bool,
+    pub nodes: Vec<String>,
+    pub channels: Vec<ChannelSpec>,
+}
+
+/// A channel and its settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelSpec {
+    pub name: String,
+    pub paused: bool,
+    pub shadow_topic: Option<String>,
+    pub shadow_rate: Option<f64>,
+}
+
+impl Topology {
+    /// Build the topology from the `/stats` responses of each nsqd node
+    pub fn from_node_stats(stats: &[(String, serde_json::Value)]) -> Self {
+        let mut nodes = Vec::new();
+        let mut topics: BTreeMap<String, TopicSpec> = BTreeMap::new();
+        let mut channels: BTreeMap<(String, String), ChannelSpec> = BTreeMap::new();
+
+        for (address, node_stats) in stats {
+            nodes.push(NodeSpec {
+                http_address: address.clone(),
+                version: node_stats.get("version").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                implicit_creation: node_stats.get("implicit_creation").and_then(|v| v.as_bool()).unwrap_or(true),
+            });
+
+            let node_topics = node_stats.get("topics").and_then(|v| v.as_array()).into_iter().flatten();
+            for topic in node_topics {
+                let Some(topic_name) = topic.get("topic_name").and_then(|v| v.as_str()) else {
+                    continue;
+                };
+                let entry = topics.entry(topic_name.to_string()).or_insert_with(|| TopicSpec {
+                    name: topic_name.to_string(),
+                    paused: false,
+                    nodes: Vec::new(),
+                    channels: Vec::new(),
+                });
+                entry.nodes.push(address.clone());
+                // A topic paused on any node is reported as paused
+                entry.paused |= topic.get("paused").and_then(|v| v.as_bool()).unwrap_or(false);
+
+                let topic_channels = topic.get("channels").and_then(|v| v.as_array()).into_iter().flatten();
+                for channel in topic_channels {
+                    let Some(channel_name) = channel.get("channel_name").and_then(|v| v.as_str()) else {
+                        continue;
+                    };
+                    let entry = channels
+                        .entry((topic_name.to_string(), channel_name.to_string()))
+                        .or_insert_with(|| ChannelSpec {
+                            name: channel_name.to_string(),
+                            paused: false,
+                            shadow_topic: None,
+                            shadow_rate: None,
+                        });
+                    entry.paused |= channel.get("paused").and_then(|v| v.as_bool()).unwrap_or(false);
+                    if let Some(shadow_topic) = channel.get("shadow_topic").and_then(|v| v.as_str()) {
+                        entry.shadow_topic = Some(shadow_topic.to_string());
+                        entry.shadow_rate = channel.get("shadow_rate").and_then(|v| v.as_f64());
+                    }
+                }
+            }
+        }
+
+        for ((topic_name, _), channel) in channels {
+            if let Some(topic) = topics.get_mut(&topic_name) {
+                topic.channels.push(channel);
+            }
+        }
+        for topic in topics.values_mut() {
+            topic.nodes.sort();
+        }
+        nodes.sort_by(|a, b| a.http_address.cmp(&b.http_address));
+
+        Self {
+            exported_at: chrono::Utc::now(),
+            nodes,
+            topics: topics.into_values().collect(),
+        }
+    }
+
+    /// Render as Terraform JSON configuration (`.tf.json`) with `nsq_topic`
+    /// and `nsq_channel` resources
+    pub fn to_terraform(&self) -> serde_json::Value {
+        let mut topic_resources = serde_json::Map::new();
+        let mut channel_resources = serde_json::Map::new();
+
+        for topic in &self.topics {
+            let topic_id = terraform_identifier(&topic.name);
+            topic_resources.insert(topic_id.clone(), json!({
+                "name": topic.name,
+                "paused": topic.paused,
+            }));
+
+            for channel in &topic.channels {
+                let mut resource = json!({
+                    "topic": format!("${{nsq_topic.{}.name}}", topic_id),
+                    "name": channel.name,
+                    "paused": channel.paused,
+                });
+                if let Some(shadow_topic) = &channel.shadow_topic {
+                    resource["shadow_topic"] = json!(shadow_topic);
+                    resource["shadow_rate"] = json!(channel.shadow_rate);
+                }
+                channel_resources.insert(
+                    format!("{}_{}", topic_id, terraform_identifier(&channel.name)),
+                    resource,
+                );
+            }
+        }
+
+        json!({
+            "resource": {
+                "nsq_topic": topic_resources,
+                "nsq_channel": channel_resources,
+            }
+        })
+    }
+}
+
+/// Terraform resource names may only contain letters, digits, `_` and `-`
+/// and must not start with a digit; topic and channel names can also contain
+/// `.` and the `#ephemeral` suffix
+fn terraform_identifier(name: &str) -> String {
+    let identifier: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    if identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", identifier)
+    } else {
+        identifier
+    }
+}
@@ -0,0 +1,105 @@
+//! Contention of nsqd's topic and client registries
+//!
+//! Threads look up entries the way publishes and deliveries do, with an
+//! occasional insert and remove as topics come and go and clients connect,
+//! against a single `RwLock<HashMap>` and against the sharded `DashMap` nsqd
+//! uses. Run with `cargo bench -p nsqd --bench registry`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use parking_lot::RwLock;
+
+/// Entries in the registry when a run starts
+const ENTRIES: usize = 1_000;
+/// Operations each thread performs
+const OPERATIONS: usize = 200_000;
+/// One in this many operations replaces an entry
+const WRITE_EVERY: usize = 100;
+
+/// Operations a registry has to support
+trait Registry: Send + Sync + 'static {
+    fn get(&self, key: &str) -> Option<Arc<u64>>;
+    fn insert(&self, key: String, value: Arc<u64>);
+    fn remove(&self, key: &str);
+}
+
+impl Registry for RwLock<HashMap<String, Arc<u64>>> {
+    fn get(&self, key: &str) -> Option<Arc<u64>> {
+        self.read().get(key).cloned()
+    }
+
+    fn insert(&self, key: String, value: Arc<u64>) {
+        self.write().insert(key, value);
+    }
+
+    fn remove(&self, key: &str) {
+        self.write().remove(key);
+    }
+}
+
+impl Registry for DashMap<String, Arc<u64>> {
+    fn get(&self, key: &str) -> Option<Arc<u64>> {
+        DashMap::get(self, key).map(|entry| entry.value().clone())
+    }
+
+    fn insert(&self, key: String, value: Arc<u64>) {
+        DashMap::insert(self, key, value);
+    }
+
+    fn remove(&self, key: &str) {
+        DashMap::remove(self, key);
+    }
+}
+
+/// Time `threads` threads take to run their operations against `registry`
+fn run<R: Registry>(registry: Arc<R>, threads: usize) -> Duration {
+    let keys: Arc<Vec<String>> = Arc::new((0..ENTRIES).map(|i| format!("topic_{}", i)).collect());
+    for (i, key) in keys.iter().enumerate() {
+        registry.insert(key.clone(), Arc::new(i as u64));
+    }
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let registry = registry.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                let mut found = 0u64;
+                for i in 0..OPERATIONS {
+                    let key = &keys[(i * 7 + thread * 13) % keys.len()];
+                    if i % WRITE_EVERY == 0 {
+                        registry.remove(key);
+                        registry.insert(key.clone(), Arc::new(i as u64));
+                    } else if let Some(value) = registry.get(key) {
+                        found += *value;
+                    }
+                }
+                found
+            })
+        })
+        .collect();
+    for handle in handles {
+        std::hint::black_box(handle.join().expect("benchmark thread"));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let parallelism = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("{:>8} {:>16} {:>16} {:>8}", "threads", "rwlock ops/s", "dashmap ops/s", "speedup");
+    for threads in [1, 2, 4, 8, 16].into_iter().filter(|&threads| threads <= parallelism.max(4) * 2) {
+        let locked = run(Arc::new(RwLock::new(HashMap::new())), threads);
+        let sharded = run(Arc::new(DashMap::new()), threads);
+        let ops = (threads * OPERATIONS) as f64;
+        println!(
+            "{:>8} {:>16.0} {:>16.0} {:>7.2}x",
+            threads,
+            ops / locked.as_secs_f64(),
+            ops / sharded.as_secs_f64(),
+            locked.as_secs_f64() / sharded.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! Depth, requeue and consumer alerts sent to a webhook
+//!
+//! With `alert_webhook_url` set, every channel is checked against the alert
+//! thresholds at a fixed interval and an alert is POSTed as JSON when one is
+//! crossed. Channels share their topic's queue, so depth is checked per
+//! channel, and per topic only for topics without channels. An alert fires
+//! once its condition has held for `alert_debounce`, or for
+//! `alert_no_consumers_timeout` when a channel has no consumers, is not
+//! repeated while it lasts, and is followed by a resolution once the
+//! condition stayed clear for `alert_debounce`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use nsq_common::{Metrics, NsqError, NsqdConfig, Result};
+
+/// How often topics and channels are checked against the thresholds
+pub const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Timeout for a single webhook request
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Condition an alert reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Queued messages over `alert_max_depth`
+    Depth,
+    /// Requeues per second over `alert_max_requeue_rate`
+    RequeueRate,
+    /// No connected clients for `alert_no_consumers_timeout`
+    NoConsumers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// Body POSTed to the webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub status: AlertStatus,
+    pub kind: AlertKind,
+    /// Hostname and HTTP port of the node raising the alert
+    pub node: String,
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Value that crossed the threshold when firing, the current one when
+    /// resolved; seconds without consumers for `no_consumers`
+    pub value: f64,
+    pub threshold: f64,
+    /// When the alert started firing
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Counters of a topic or channel at one check
+#[derive(Debug, Clone)]
+pub struct AlertSample {
+    pub topic: String,
+    /// None for a topic without channels
+    pub channel: Option<String>,
+    pub depth: u64,
+    pub requeue_count: u64,
+    pub client_count: u64,
+}
+
+type AlertKey = (AlertKind, String, Option<String>);
+
+/// Progress of one alert between checks
+#[derive(Debug, Default)]
+struct AlertState {
+    /// Since when the condition has held
+    breached_since: Option<Instant>,
+    /// Since when the condition has been clear while firing
+    cleared_since: Option<Instant>,
+    /// The alert that was sent, while firing
+    firing: Option<Alert>,
+}
+
+/// Thresholds, from the `alert_*` settings
+#[derive(Debug, Clone)]
+struct AlertRules {
+    max_depth: u64,
+    max_requeue_rate: f64,
+    no_consumers_timeout: Option<Duration>,
+    debounce: Duration,
+}
+
+/// Checks topics and channels against the alert thresholds and notifies the
+/// webhook of alerts that fire or resolve
+pub struct Alerter {
+    webhook_url: Option<String>,
+    rules: AlertRules,
+    node: String,
+    client: reqwest::Client,
+    states: Mutex<HashMap<AlertKey, AlertState>>,
+    /// Requeue count of each channel at the previous check
+    requeues: Mutex<HashMap<(String, String), (u64, Instant)>>,
+    metrics: Metrics,
+}
+
+impl Alerter {
+    /// Create an alerter from the `alert_*` settings; `node` identifies this
+    /// node in alerts
+    pub fn new(config: &NsqdConfig, node: String, metrics: Metrics) -> Result<Self> {
+        let webhook_url = config.alert_webhook_url.clone().filter(|url| !url.is_empty());
+        if let Some(url) = &webhook_url {
+            url::Url::parse(url).map_err(|e| NsqError::Config(format!("invalid alert_webhook_url {}: {}", url, e)))?;
+        }
+        let rules = AlertRules {
+            max_depth: config.alert_max_depth,
+            max_requeue_rate: config.alert_max_requeue_rate,
+            no_consumers_timeout: (config.alert_no_consumers_timeout > 0)
+                .then(|| Duration::from_millis(config.alert_no_consumers_timeout)),
+            debounce: Duration::from_millis(config.alert_debounce),
+        };
+        if webhook_url.is_some() && rules.max_depth == 0 && rules.max_requeue_rate <= 0.0 && rules.no_consumers_timeout.is_none() {
+            return Err(NsqError::Config(
+                "alert_webhook_url needs alert_max_depth, alert_max_requeue_rate or alert_no_consumers_timeout".to_string(),
+            ));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .map_err(|e| NsqError::Config(e.to_string()))?;
+
+        Ok(Self {
+            webhook_url,
+            rules,
+            node,
+            client,
+            states: Mutex::new(HashMap::new()),
+            requeues: Mutex::new(HashMap::new()),
+            metrics,
+        })
+    }
+
+    /// Check whether alerts are sent anywhere
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some()
+    }
+
+    /// Alerts currently firing, by topic and channel
+    pub fn firing(&self) -> Vec<Alert> {
+        let mut alerts: Vec<Alert> = self.states.lock().values().filter_map(|state| state.firing.clone()).collect();
+        alerts.sort_by(|a, b| (&a.topic, &a.channel).cmp(&(&b.topic, &b.channel)));
+        alerts
+    }
+
+    /// Check the samples of every topic and channel, returning the alerts
+    /// that fired or resolved since the previous check
+    pub fn evaluate(&self, samples: &[AlertSample], now: Instant) -> Vec<Alert> {
+        let mut observed: HashMap<AlertKey, (bool, f64, f64, Duration)> = HashMap::new();
+        let requeue_rates = self.requeue_rates(samples, now);
+        for sample in samples {
+            let key = |kind| (kind, sample.topic.clone(), sample.channel.clone());
+            if self.rules.max_depth > 0 {
+                let breached = sample.depth > self.rules.max_depth;
+                observed.insert(key(AlertKind::Depth), (breached, sample.depth as f64, self.rules.max_depth as f64, self.rules.debounce));
+            }
+            let Some(channel) = &sample.channel else {
+                continue;
+            };
+            if self.rules.max_requeue_rate > 0.0 {
+                let rate = requeue_rates.get(&(sample.topic.clone(), channel.clone())).copied().unwrap_or(0.0);
+                let breached = rate > self.rules.max_requeue_rate;
+                observed.insert(key(AlertKind::RequeueRate), (breached, rate, self.rules.max_requeue_rate, self.rules.debounce));
+            }
+            if let Some(timeout) = self.rules.no_consumers_timeout {
+                let breached = sample.client_count == 0;
+                observed.insert(key(AlertKind::NoConsumers), (breached, 0.0, timeout.as_secs_f64(), timeout));
+            }
+        }
+
+        let mut alerts = Vec::new();
+        let mut states = self.states.lock();
+        // Topics and channels that went away count as clear
+        for key in states.keys() {
+            observed.entry(key.clone()).or_insert((false, 0.0, 0.0, self.rules.debounce));
+        }
+        for (key, (breached, value, threshold, hold)) in observed {
+            let state = states.entry(key.clone()).or_default();
+            if breached {
+                state.cleared_since = None;
+                let since = *state.breached_since.get_or_insert(now);
+                if state.firing.is_none() && now.duration_since(since) >= hold {
+                    let value = if key.0 == AlertKind::NoConsumers { now.duration_since(since).as_secs_f64() } else { value };
+                    let alert = self.firing_alert(&key, value, threshold, since, now);
+                    state.firing = Some(alert.clone());
+                    alerts.push(alert);
+                }
+            } else {
+                state.breached_since = None;
+                if let Some(firing) = &state.firing {
+                    let since = *state.cleared_since.get_or_insert(now);
+                    if now.duration_since(since) >= self.rules.debounce {
+                        alerts.push(Alert {
+                            status: AlertStatus::Resolved,
+                            value,
+                            timestamp: chrono::Utc::now(),
+                            ..firing.clone()
+                        });
+                        state.firing = None;
+                        state.cleared_since = None;
+                    }
+                }
+            }
+        }
+        states.retain(|_, state| state.breached_since.is_some() || state.firing.is_some());
+        alerts
+    }
+
+    fn firing_alert(&self, key: &AlertKey, value: f64, threshold: f64, since: Instant, now: Instant) -> Alert {
+        let timestamp = chrono::Utc::now();
+        let started_at = timestamp - chrono::Duration::from_std(now.duration_since(since)).unwrap_or_default();
+        Alert {
+            status: AlertStatus::Firing,
+            kind: key.0,
+            node: self.node.clone(),
+            topic: key.1.clone(),
+            channel: key.2.clone(),
+            value,
+            threshold,
+            started_at,
+            timestamp,
+        }
+    }
+
+    /// Requeues per second of each channel since the previous check
+    fn requeue_rates(&self, samples: &[AlertSample], now: Instant) -> HashMap<(String, String), f64> {
+        let mut previous = self.requeues.lock();
+        let mut current = HashMap::new();
+        let mut rates = HashMap::new();
+        for sample in samples {
+            let Some(channel) = &sample.channel else {
+                continue;
+            };
+            let key = (sample.topic.clone(), channel.clone());
+            if let Some((count, at)) = previous.get(&key) {
+                let elapsed = now.duration_since(*at).as_secs_f64();
+                if elapsed > 0.0 {
+                    rates.insert(key.clone(), sample.requeue_count.saturating_sub(*count) as f64 / elapsed);
+                }
+            }
+            current.insert(key, (sample.requeue_count, now));
+        }
+        *previous = current;
+        rates
+    }
+
+    /// POST alerts to the webhook, logging the ones that could not be sent
+    pub async fn notify(&self, alerts: Vec<Alert>) {
+        let Some(url) = &self.webhook_url else {
+            return;
+        };
+        for alert in alerts {
+            let target = match &alert.channel {
+                Some(channel) => format!("{}/{}", alert.topic, channel),
+                None => alert.topic.clone(),
+            };
+            match alert.status {
+                AlertStatus::Firing => {
+                    tracing::warn!("Alert {:?} firing for {}: {} over {}", alert.kind, target, alert.value, alert.threshold);
+                    self.metrics.incr("alerts.fired", 1);
+                }
+                AlertStatus::Resolved => {
+                    tracing::info!("Alert {:?} resolved for {}", alert.kind, target);
+                    self.metrics.incr("alerts.resolved", 1);
+                }
+            }
+            let result = self.client.post(url).json(&alert).send().await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                self.metrics.incr("alerts.failed", 1);
+                tracing::warn!("Failed to send alert for {} to {}: {}", target, url, e);
+            }
+        }
+    }
+}
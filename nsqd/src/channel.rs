@@ -1,11 +1,22 @@
 //! Channel management
 
 use std::sync::Arc;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
 use uuid::Uuid;
-use parking_lot::RwLock;
-use nsq_protocol::Message;
-use nsq_common::{Metrics, Result, validate_topic_channel_name};
-use crate::message::MessageQueue;
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Notify;
+use nsq_protocol::{Message, MessageId};
+use bytes::Bytes;
+use nsq_common::{DiskMark, DiskReplay, DiskUsage, Metrics, Result, validate_channel_name};
+use crate::diagnostics::{LockStats, QueueMemory};
+use crate::latency::LatencyHistogram;
+use crate::message::{InFlightMessage, MessageQueue};
+use crate::ordering::OrderedDelivery;
+use crate::topic::{DeliverySettings, TopicPause};
 
 /// Channel represents a message channel within a topic
 pub struct Channel {
@@ -13,7 +24,7 @@ pub struct Channel {
     pub name: String,
     /// Topic name
     pub topic_name: String,
-    /// Message queue for this channel
+    /// The channel's copies of the topic's messages
     message_queue: Arc<MessageQueue>,
     /// Channel statistics
     stats: Arc<RwLock<ChannelStats>>,
@@ -21,12 +32,45 @@ pub struct Channel {
     metrics: Metrics,
     /// Channel creation time
     pub created_at: chrono::DateTime<chrono::Utc>,
-    /// Whether the channel is paused
-    paused: Arc<RwLock<bool>>,
+    /// When the channel was paused, if it is
+    paused_at: Arc<RwLock<Option<chrono::DateTime<chrono::Utc>>>>,
+    /// Pause state of the topic, which holds back delivery on all its channels
+    topic_pause: Arc<RwLock<TopicPause>>,
+    /// IDs of messages delivered from this channel and not yet finished
+    in_flight: Arc<RwLock<HashSet<MessageId>>>,
+    /// Maximum in-flight messages across all clients (0 = unlimited)
+    max_in_flight: Arc<RwLock<u64>>,
+    /// Subscribed clients and their sample rates (0 = every message),
+    /// sharded so deliveries to different clients do not contend
+    clients: Arc<DashMap<Uuid, u32>>,
+    /// Wakes dispatchers when messages or delivery slots become available
+    notify: Arc<Notify>,
+    /// Shadow topic sampling configuration
+    shadow: Arc<RwLock<Option<ShadowConfig>>>,
+    /// Time from publish to FIN of finished messages
+    e2e_latency: Arc<RwLock<LatencyHistogram>>,
+    /// Delivery overrides of this channel
+    delivery: Arc<RwLock<DeliverySettings>>,
+    /// Delivery overrides of the topic, used where the channel has none
+    topic_delivery: Arc<RwLock<DeliverySettings>>,
+    /// Per-key ordering state when the channel is in ordered mode
+    ordering: Arc<Mutex<Option<OrderedDelivery>>>,
+    /// Messages the topic's disk backend already handed out, being replayed
+    /// ahead of the queue
+    replay: Arc<Mutex<Option<DiskReplay>>>,
+}
+
+/// Copies a percentage of delivered messages to another topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowConfig {
+    /// Topic receiving the sampled copies
+    pub topic: String,
+    /// Percentage of delivered messages to copy (0-100)
+    pub rate: f64,
 }
 
 /// Channel statistics
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ChannelStats {
     pub message_count: u64,
     pub depth: u64,
@@ -35,22 +79,16 @@ pub struct ChannelStats {
     pub deferred_count: u64,
     pub requeue_count: u64,
     pub timeout_count: u64,
+    pub finish_count: u64,
     pub client_count: u64,
-}
-
-impl Default for ChannelStats {
-    fn default() -> Self {
-        Self {
-            message_count: 0,
-            depth: 0,
-            backend_depth: 0,
-            in_flight_count: 0,
-            deferred_count: 0,
-            requeue_count: 0,
-            timeout_count: 0,
-            client_count: 0,
-        }
-    }
+    pub max_in_flight: u64,
+    pub shadow_count: u64,
+    pub dead_letter_count: u64,
+    pub replayed_count: u64,
+    pub last_delivery_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last time a client subscribed or left, or a message was delivered,
+    /// finished or requeued
+    pub last_activity_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Channel {
@@ -59,9 +97,11 @@ impl Channel {
         name: String,
         topic_name: String,
         message_queue: Arc<MessageQueue>,
+        topic_delivery: Arc<RwLock<DeliverySettings>>,
+        topic_pause: Arc<RwLock<TopicPause>>,
         metrics: Metrics,
     ) -> Result<Self> {
-        validate_topic_channel_name(&name)?;
+        validate_channel_name(&name)?;
         
         Ok(Self {
             name,
@@ -70,35 +110,452 @@ impl Channel {
             stats: Arc::new(RwLock::new(ChannelStats::default())),
             metrics,
             created_at: chrono::Utc::now(),
-            paused: Arc::new(RwLock::new(false)),
+            paused_at: Arc::new(RwLock::new(None)),
+            topic_pause,
+            in_flight: Arc::new(RwLock::new(HashSet::new())),
+            max_in_flight: Arc::new(RwLock::new(0)),
+            clients: Arc::new(DashMap::new()),
+            notify: Arc::new(Notify::new()),
+            shadow: Arc::new(RwLock::new(None)),
+            e2e_latency: Arc::new(RwLock::new(LatencyHistogram::new())),
+            delivery: Arc::new(RwLock::new(DeliverySettings::default())),
+            topic_delivery,
+            ordering: Arc::new(Mutex::new(None)),
+            replay: Arc::new(Mutex::new(None)),
         })
     }
     
-    /// Distribute a message from the topic's message queue
-    pub fn distribute_message(&self) -> Result<()> {
-        if *self.paused.read() {
-            return Ok(());
+    /// Check whether the channel delivers in key order
+    pub fn is_ordered(&self) -> bool {
+        self.ordering.lock().is_some()
+    }
+    
+    /// Turn ordered mode on or off. Turning it off returns the messages held
+    /// back for their keys to the front of the queue.
+    pub fn set_ordered(&self, ordered: bool) {
+        let mut ordering = self.ordering.lock();
+        match (ordering.is_some(), ordered) {
+            (false, true) => *ordering = Some(OrderedDelivery::default()),
+            (true, false) => {
+                if let Some(mut previous) = ordering.take() {
+                    self.put_front_all(previous.drain_held());
+                }
+            }
+            _ => {}
+        }
+        drop(ordering);
+        self.notify.notify_waiters();
+    }
+    
+    /// Put messages back at the front of the queue, keeping their order
+    fn put_front_all(&self, messages: Vec<Message>) {
+        for message in messages.into_iter().rev() {
+            self.message_queue.put_front(message);
+        }
+    }
+    
+    /// Return the messages held back for their keys to the front of the
+    /// queue, so they are flushed or emptied along with it
+    pub fn return_held(&self) {
+        if let Some(ordering) = self.ordering.lock().as_mut() {
+            self.put_front_all(ordering.drain_held());
+        }
+    }
+    
+    /// Deliver the messages of `replay` before continuing with the queue,
+    /// returning how many bytes of them will be replayed
+    pub fn start_replay(&self, replay: DiskReplay) -> u64 {
+        let bytes = replay.remaining_bytes();
+        if bytes > 0 {
+            *self.replay.lock() = Some(replay);
+            self.metrics.incr("channels.replays", 1);
+            self.notify.notify_waiters();
+        }
+        bytes
+    }
+    
+    /// Next message to deliver: a replayed one while the replay lasts, the
+    /// head of the queue after
+    fn next_queued(&self) -> Result<Option<Message>> {
+        {
+            let mut replay = self.replay.lock();
+            if let Some(reader) = replay.as_mut() {
+                let replayed = reader.next_message().and_then(|data| match data {
+                    Some(data) => Ok(Some(Message::from_bytes(Bytes::from(data))?)),
+                    None => Ok(None),
+                });
+                match replayed {
+                    Ok(Some(message)) => {
+                        self.stats.write().replayed_count += 1;
+                        self.metrics.incr("messages.replayed", 1);
+                        return Ok(Some(message));
+                    }
+                    Ok(None) => *replay = None,
+                    Err(e) => {
+                        tracing::warn!("Stopped replaying {}/{}: {}", self.topic_name, self.name, e);
+                        *replay = None;
+                    }
+                }
+            }
+        }
+        self.message_queue.get()
+    }
+    
+    /// Get the delivery overrides set on this channel
+    pub fn delivery_settings(&self) -> DeliverySettings {
+        *self.delivery.read()
+    }
+    
+    /// Set the delivery overrides of this channel
+    pub fn set_delivery_settings(&self, delivery: DeliverySettings) {
+        *self.delivery.write() = delivery;
+    }
+    
+    /// Delivery overrides in effect: the channel's, then the topic's
+    pub fn effective_delivery_settings(&self) -> DeliverySettings {
+        self.delivery_settings().or(*self.topic_delivery.read())
+    }
+    
+    /// Get the channel-wide in-flight cap (0 = unlimited)
+    pub fn max_in_flight(&self) -> u64 {
+        *self.max_in_flight.read()
+    }
+    
+    /// Set the channel-wide in-flight cap (0 = unlimited)
+    pub fn set_max_in_flight(&self, max_in_flight: u64) {
+        *self.max_in_flight.write() = max_in_flight;
+        self.notify.notify_waiters();
+    }
+    
+    /// Get the shadow topic configuration
+    pub fn shadow(&self) -> Option<ShadowConfig> {
+        self.shadow.read().clone()
+    }
+    
+    /// Set or clear the shadow topic configuration
+    pub fn set_shadow(&self, shadow: Option<ShadowConfig>) {
+        *self.shadow.write() = shadow;
+    }
+    
+    /// Get the shadow topic a delivered message should be copied to, if sampled
+    ///
+    /// Sampling is keyed on the message ID and only applies to first deliveries,
+    /// so redeliveries never produce duplicate copies.
+    pub fn shadow_target(&self, message: &Message) -> Option<String> {
+        let shadow = self.shadow.read();
+        let shadow = shadow.as_ref()?;
+        if message.attempts > 1 {
+            return None;
         }
         
-        if let Some(message) = self.message_queue.get()? {
-            // Put message in channel's queue
-            self.message_queue.put(message)?;
-            
-            {
-                let mut stats = self.stats.write();
-                stats.message_count += 1;
-                stats.depth = self.message_queue.depth() as u64;
+        let mut hasher = DefaultHasher::new();
+        message.id.hash(&mut hasher);
+        let bucket = (hasher.finish() % 10_000) as f64;
+        if bucket < shadow.rate * 100.0 {
+            Some(shadow.topic.clone())
+        } else {
+            None
+        }
+    }
+    
+    /// Record a message copied to the shadow topic
+    pub fn record_shadow(&self) {
+        self.stats.write().shadow_count += 1;
+        self.metrics.incr("channels.shadowed", 1);
+    }
+    
+    /// Register a subscribed client with the sample rate it identified with
+    pub fn add_client(&self, client_id: Uuid, sample_rate: u32) {
+        self.clients.insert(client_id, sample_rate);
+        self.record_activity();
+    }
+    
+    /// Unregister a client
+    pub fn remove_client(&self, client_id: Uuid) {
+        self.clients.remove(&client_id);
+        self.record_activity();
+    }
+    
+    fn record_activity(&self) {
+        self.stats.write().last_activity_at = Some(chrono::Utc::now());
+    }
+    
+    /// Last activity on the channel, or its creation when there was none
+    pub fn last_activity_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.stats.read().last_activity_at.unwrap_or(self.created_at)
+    }
+    
+    /// Check whether the channel has settings saved in the metadata beyond
+    /// its name, which keep it from being deleted as idle
+    pub fn has_persisted_config(&self) -> bool {
+        self.is_paused() || self.is_ordered() || self.delivery_settings() != DeliverySettings::default()
+    }
+    
+    /// Check whether the channel has no clients, no messages and no
+    /// persisted settings, and saw no activity for `timeout`
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.clients.is_empty()
+            && self.in_flight.read().is_empty()
+            && self.deferred_count() == 0
+            && self.depth() == 0
+            && self.backend_depth() == 0
+            && !self.has_persisted_config()
+            && (chrono::Utc::now() - self.last_activity_at()).to_std().is_ok_and(|idle| idle >= timeout)
+    }
+    
+    /// Wait until new messages or delivery slots may be available
+    pub async fn wait_for_messages(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.notify.notified()).await;
+    }
+    
+    /// Take the next message for delivery to a client and mark it in-flight
+    ///
+    /// Returns None when the channel or its topic is paused, the channel is
+    /// empty, or it is at its in-flight cap.
+    /// Clients with a sample rate only take that percentage of messages; the
+    /// rest are left for the channel's other consumers. In ordered mode a
+    /// client only takes messages of the keys it owns, one per key at a time,
+    /// and sample rates are ignored.
+    pub fn dispatch_message(&self, client_id: Uuid, timeout: Duration) -> Result<Option<Message>> {
+        if self.is_halted() {
+            return Ok(None);
+        }
+        
+        let mut in_flight = self.in_flight.write();
+        let max_in_flight = self.max_in_flight();
+        if max_in_flight > 0 && in_flight.len() as u64 >= max_in_flight {
+            self.metrics.incr("channels.in_flight_capped", 1);
+            return Ok(None);
+        }
+        
+        let message = match self.ordering.lock().as_mut() {
+            Some(ordering) => self.take_ordered(ordering, client_id)?,
+            None => self.take_sampled(client_id)?,
+        };
+        let Some(mut message) = message else {
+            return Ok(None);
+        };
+        
+        message.attempts = message.attempts.saturating_add(1);
+        self.message_queue.mark_in_flight(message.clone(), client_id, timeout)?;
+        in_flight.insert(message.id);
+        {
+            let mut stats = self.stats.write();
+            let now = chrono::Utc::now();
+            stats.last_delivery_at = Some(now);
+            stats.last_activity_at = Some(now);
+        }
+        
+        self.metrics.incr("messages.in_flight", 1);
+        Ok(Some(message))
+    }
+    
+    /// Take the next message for a client, honouring its sample rate
+    fn take_sampled(&self, client_id: Uuid) -> Result<Option<Message>> {
+        let sample_rate = self.clients.get(&client_id).map_or(0, |sample_rate| *sample_rate);
+        let mut passed = 0;
+        loop {
+            let message = match self.next_queued()? {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            if sample_rate == 0 || is_sampled(message.id, client_id, sample_rate) {
+                return Ok(Some(message));
+            }
+            if self.pass_sampled(message, client_id)? {
+                // Stop once every queued message was passed on, so a lone
+                // sampling client waits instead of cycling through the queue
+                passed += 1;
+                if passed > self.message_queue.depth() {
+                    return Ok(None);
+                }
             }
-            
-            self.metrics.incr("messages.distributed", 1);
+        }
+    }
+    
+    /// Take the next message of a key the client owns, holding back the
+    /// messages taken from the queue on the way that belong to other keys
+    fn take_ordered(&self, ordering: &mut OrderedDelivery, client_id: Uuid) -> Result<Option<Message>> {
+        let clients: Vec<Uuid> = self.clients.iter().map(|entry| *entry.key()).collect();
+        if let Some(message) = ordering.take_held(client_id, &clients) {
+            return Ok(Some(message));
         }
         
-        Ok(())
+        while !ordering.is_full() {
+            let Some(message) = self.next_queued()? else {
+                return Ok(None);
+            };
+            if let Some(message) = ordering.offer(message, client_id, &clients) {
+                return Ok(Some(message));
+            }
+            // Another client may own the message's key
+            self.notify.notify_waiters();
+        }
+        self.metrics.incr("channels.ordered_hold_full", 1);
+        Ok(None)
+    }
+    
+    /// Put a message that is to be delivered again back in line: ahead of
+    /// the later messages of its key in ordered mode, at the back of the
+    /// queue otherwise
+    fn redeliver(&self, message: Message) -> Result<()> {
+        let message = match self.ordering.lock().as_mut() {
+            Some(ordering) => ordering.redeliver(message),
+            None => Some(message),
+        };
+        match message {
+            Some(message) => self.message_queue.put(message),
+            None => {
+                self.notify.notify_waiters();
+                Ok(())
+            }
+        }
+    }
+    
+    /// Take back a timed out or deferred message this channel delivered in
+    /// ordered mode, so it keeps its place among the messages of its key.
+    /// Gives the message back when the channel does not track it.
+    pub fn reclaim(&self, message: Message) -> Option<Message> {
+        let message_id = message.id;
+        let message = match self.ordering.lock().as_mut() {
+            Some(ordering) => ordering.redeliver(message),
+            None => Some(message),
+        };
+        if message.is_none() {
+            self.forget_in_flight(message_id);
+            self.notify.notify_waiters();
+        }
+        message
+    }
+    
+    /// Handle a message a sampling client skipped. It goes back to the queue
+    /// when another client takes every message, and is dropped otherwise, as
+    /// nsqd does for sampled-out messages. Returns whether it was requeued.
+    fn pass_sampled(&self, message: Message, client_id: Uuid) -> Result<bool> {
+        let has_full_consumer = self.clients
+            .iter()
+            .any(|entry| *entry.key() != client_id && *entry.value() == 0);
+        
+        if has_full_consumer {
+            self.message_queue.put(message)?;
+            self.notify.notify_waiters();
+            Ok(true)
+        } else {
+            self.metrics.incr("messages.sampled_out", 1);
+            Ok(false)
+        }
+    }
+    
+    /// Forget an in-flight message that was resolved outside the channel
+    pub fn clear_in_flight(&self, message_id: MessageId) -> bool {
+        if let Some(ordering) = self.ordering.lock().as_mut() {
+            ordering.release(message_id);
+        }
+        self.forget_in_flight(message_id)
+    }
+    
+    /// Stop counting a message against the in-flight cap, leaving its key
+    /// busy in ordered mode
+    fn forget_in_flight(&self, message_id: MessageId) -> bool {
+        let removed = self.in_flight.write().remove(&message_id);
+        if removed {
+            self.notify.notify_waiters();
+        }
+        removed
+    }
+    
+    /// Forget all in-flight messages after they were moved out of the queue
+    pub fn clear_all_in_flight(&self) {
+        if let Some(ordering) = self.ordering.lock().as_mut() {
+            ordering.clear_active();
+        }
+        self.in_flight.write().clear();
+        self.notify.notify_waiters();
+    }
+    
+    /// Touch an in-flight message, resetting its timeout
+    pub fn touch_message(&self, message_id: MessageId) -> Result<()> {
+        if !self.in_flight.read().contains(&message_id) {
+            return Err(nsq_common::NsqError::Queue("Message not found in flight".to_string()));
+        }
+        self.message_queue.touch(message_id)
+    }
+    
+    /// Queue the channel's copy of a message published to the topic and
+    /// wake its dispatchers, returning the mark to take it back with
+    /// `take_back` when it went to disk. A paused channel still queues it.
+    pub fn put_message(&self, message: Message) -> Result<Option<DiskMark>> {
+        let mark = self.message_queue.put_marked(message)?;
+        self.record_queued();
+        Ok(mark)
+    }
+    
+    /// Take back a copy queued by `put_message`, returning whether it was
+    /// removed
+    pub fn take_back(&self, message_id: MessageId, mark: Option<DiskMark>) -> Result<bool> {
+        let removed = self.message_queue.take_back(message_id, mark)?;
+        if removed {
+            let mut stats = self.stats.write();
+            stats.message_count = stats.message_count.saturating_sub(1);
+            stats.depth = self.depth() as u64;
+        }
+        Ok(removed)
+    }
+    
+    /// Append the channel's copies of messages to its disk queue as a whole,
+    /// returning how many bytes were written and the mark to take them back
+    /// with `rollback_durable`. Dispatchers are not woken; call `wake` once
+    /// the publish went through.
+    pub fn put_messages_durable(&self, messages: &[Message]) -> Result<(u64, DiskMark)> {
+        let (written, mark) = self.message_queue.put_batch_to_disk(messages)?;
+        {
+            let mut stats = self.stats.write();
+            stats.message_count += mark.count();
+            stats.depth = self.depth() as u64;
+        }
+        self.metrics.incr("messages.distributed", mark.count());
+        Ok((written, mark))
+    }
+    
+    /// Take back messages appended by `put_messages_durable`, returning
+    /// whether they were removed
+    pub fn rollback_durable(&self, mark: DiskMark) -> Result<bool> {
+        let count = mark.count();
+        let removed = self.message_queue.rollback_disk(mark)?;
+        if removed {
+            let mut stats = self.stats.write();
+            stats.message_count = stats.message_count.saturating_sub(count);
+            stats.depth = self.depth() as u64;
+        }
+        Ok(removed)
+    }
+    
+    /// Count a queued copy and wake the dispatchers
+    fn record_queued(&self) {
+        {
+            let mut stats = self.stats.write();
+            stats.message_count += 1;
+            stats.depth = self.depth() as u64;
+        }
+        
+        self.metrics.incr("messages.distributed", 1);
+        self.notify.notify_waiters();
+    }
+    
+    /// Fsync the disk queue if it has writes since the last sync, returning
+    /// whether it did
+    pub fn sync_pending(&self) -> Result<bool> {
+        self.message_queue.sync_pending()
+    }
+    
+    /// Wake the channel's dispatchers, as after its topic is unpaused
+    pub fn wake(&self) {
+        self.notify.notify_waiters();
     }
     
     /// Get a message from the channel queue
     pub fn get_message(&self) -> Result<Option<Message>> {
-        if *self.paused.read() {
+        if self.is_halted() {
             return Ok(None);
         }
         
@@ -107,50 +564,83 @@ impl Channel {
     
     /// Mark a message as in-flight
     pub fn mark_in_flight(&self, message: Message, client_id: Uuid, timeout: std::time::Duration) -> Result<()> {
+        let message_id = message.id;
         self.message_queue.mark_in_flight(message, client_id, timeout)?;
-        
-        {
-            let mut stats = self.stats.write();
-            stats.in_flight_count += 1;
-        }
+        self.in_flight.write().insert(message_id);
         
         self.metrics.incr("messages.in_flight", 1);
         Ok(())
     }
     
     /// Finish a message (acknowledge)
-    pub fn finish_message(&self, message_id: Uuid) -> Result<()> {
-        self.message_queue.finish(message_id)?;
-        
+    pub fn finish_message(&self, message_id: MessageId) -> Result<()> {
+        let message = self.message_queue.finish(message_id)?;
+        self.clear_in_flight(message_id);
         {
             let mut stats = self.stats.write();
-            stats.in_flight_count = stats.in_flight_count.saturating_sub(1);
+            stats.finish_count += 1;
+            stats.last_activity_at = Some(chrono::Utc::now());
         }
         
+        let latency = (chrono::Utc::now() - message.timestamp).to_std().unwrap_or_default();
+        self.e2e_latency.write().record(latency);
+        
         self.metrics.incr("messages.finished", 1);
         Ok(())
     }
     
+    /// Return a message taken for a client that stopped being ready before it
+    /// was sent, so it is the next one delivered
+    pub fn return_message(&self, message_id: MessageId) -> Result<()> {
+        let message = self.message_queue.take_returned(message_id)?;
+        let message = match self.ordering.lock().as_mut() {
+            Some(ordering) => ordering.redeliver(message),
+            None => Some(message),
+        };
+        if let Some(message) = message {
+            self.message_queue.put_front(message);
+        }
+        self.clear_in_flight(message_id);
+        Ok(())
+    }
+    
+    /// Take a message out of flight for good after it used up its attempts;
+    /// the caller moves it to the dead-letter topic or drops it
+    pub fn dead_letter_message(&self, message_id: MessageId) -> Result<Message> {
+        let message = self.message_queue.finish(message_id)?;
+        self.clear_in_flight(message_id);
+        self.stats.write().dead_letter_count += 1;
+        
+        self.metrics.incr("messages.dead_lettered", 1);
+        Ok(message)
+    }
+    
     /// Requeue a message
-    pub fn requeue_message(&self, message_id: Uuid, timeout: std::time::Duration) -> Result<()> {
-        self.message_queue.requeue(message_id, timeout)?;
+    pub fn requeue_message(&self, message_id: MessageId, _timeout: std::time::Duration) -> Result<()> {
+        let message = self.message_queue.take_requeued(message_id)?;
+        self.redeliver(message)?;
+        self.clear_in_flight(message_id);
         
         {
             let mut stats = self.stats.write();
             stats.requeue_count += 1;
+            stats.last_activity_at = Some(chrono::Utc::now());
         }
         
         self.metrics.incr("messages.requeued", 1);
         Ok(())
     }
     
-    /// Defer a message
-    pub fn defer_message(&self, message_id: Uuid, delay: std::time::Duration) -> Result<()> {
+    /// Defer a message. In ordered mode its key stays busy until it is
+    /// delivered again.
+    pub fn defer_message(&self, message_id: MessageId, delay: std::time::Duration) -> Result<()> {
         self.message_queue.defer(message_id, delay)?;
+        self.forget_in_flight(message_id);
         
         {
             let mut stats = self.stats.write();
             stats.deferred_count += 1;
+            stats.last_activity_at = Some(chrono::Utc::now());
         }
         
         self.metrics.incr("messages.deferred", 1);
@@ -160,16 +650,22 @@ impl Channel {
     /// Process deferred messages
     pub fn process_deferred(&self) -> Result<()> {
         let ready_messages = self.message_queue.process_deferred()?;
+        if ready_messages.is_empty() {
+            return Ok(());
+        }
         
         for message in ready_messages {
-            self.message_queue.put(message)?;
+            if let Some(message) = self.reclaim(message) {
+                self.message_queue.put(message)?;
+            }
         }
         
+        self.notify.notify_waiters();
         Ok(())
     }
     
-    /// Clean up timed out messages
-    pub fn cleanup_timeouts(&self) -> Result<()> {
+    /// Requeue timed out messages, returning the expired in-flight entries
+    pub fn cleanup_timeouts(&self) -> Result<Vec<InFlightMessage>> {
         let timed_out_messages = self.message_queue.cleanup_timeouts()?;
         
         {
@@ -180,13 +676,15 @@ impl Channel {
         self.metrics.incr("messages.timed_out", timed_out_messages.len() as u64);
         
         // Requeue timed out messages
-        for message in timed_out_messages {
-            if let Err(e) = self.message_queue.put(message) {
+        for in_flight_msg in &timed_out_messages {
+            let message_id = in_flight_msg.message.id;
+            if let Err(e) = self.redeliver(in_flight_msg.message.clone()) {
                 tracing::warn!("Failed to requeue timed out message: {}", e);
             }
+            self.clear_in_flight(message_id);
         }
         
-        Ok(())
+        Ok(timed_out_messages)
     }
     
     /// Get channel statistics
@@ -194,21 +692,104 @@ impl Channel {
         let mut stats = self.stats.read().clone();
         
         // Update real-time stats
-        stats.depth = self.message_queue.depth() as u64;
-        stats.in_flight_count = self.message_queue.in_flight_count() as u64;
+        stats.depth = self.depth() as u64;
+        stats.backend_depth = self.message_queue.backend_depth();
+        stats.in_flight_count = self.in_flight.read().len() as u64;
         stats.deferred_count = self.message_queue.deferred_count() as u64;
+        stats.client_count = self.clients.len() as u64;
+        stats.max_in_flight = self.max_in_flight();
         
         stats
     }
     
-    /// Get message queue depth
+    /// End-to-end processing latency of finished messages
+    pub fn e2e_latency(&self) -> LatencyHistogram {
+        self.e2e_latency.read().clone()
+    }
+    
+    /// Up to `count` of the next messages to be delivered, without consuming them
+    pub fn peek(&self, count: usize) -> Result<Vec<Message>> {
+        self.message_queue.peek(count)
+    }
+    
+    /// Contention on the channel's queue lock
+    pub fn queue_lock_stats(&self) -> LockStats {
+        self.message_queue.lock_stats()
+    }
+    
+    /// Messages and bytes the channel holds in memory, including those held
+    /// back for their keys
+    pub fn memory_usage(&self) -> QueueMemory {
+        let mut memory = self.message_queue.memory_usage();
+        if let Some(ordering) = self.ordering.lock().as_ref() {
+            memory.queued_messages += ordering.held_count();
+            memory.queued_bytes += ordering.held_bytes();
+        }
+        memory
+    }
+    
+    /// Get message queue depth, including messages held back for their keys
     pub fn depth(&self) -> usize {
-        self.message_queue.depth()
+        let held = self.ordering.lock().as_ref().map_or(0, OrderedDelivery::held_count);
+        self.message_queue.depth() + held
+    }
+    
+    /// Get the number of messages waiting in the disk queue
+    pub fn backend_depth(&self) -> u64 {
+        self.message_queue.backend_depth()
+    }
+    
+    /// Space the channel's disk queue takes on disk
+    pub fn disk_usage(&self) -> DiskUsage {
+        self.message_queue.disk_usage()
+    }
+    
+    /// Change how many messages are held in memory before overflowing to disk
+    pub fn set_mem_queue_size(&self, mem_queue_size: usize) {
+        self.message_queue.set_max_memory_size(mem_queue_size);
+    }
+    
+    /// Discard the queued messages, those held back for their keys included,
+    /// returning how many were discarded from memory and from disk
+    pub fn empty(&self) -> Result<(usize, u64)> {
+        self.return_held();
+        self.message_queue.empty()
+    }
+    
+    /// Discard messages from the disk queue that are older than `max_age` or
+    /// keep it above `max_bytes`, returning how many were discarded
+    pub fn prune(&self, max_age: Option<Duration>, max_bytes: Option<u64>) -> Result<u64> {
+        self.message_queue.prune_backend(max_age, max_bytes)
+    }
+    
+    /// Move queued messages from memory to the disk queue until at least
+    /// `bytes` are freed, returning how many messages and bytes were moved
+    pub fn spill(&self, bytes: usize) -> Result<(usize, usize)> {
+        self.message_queue.spill(bytes)
+    }
+    
+    /// Write all pending messages to the disk queue, returning how many were written
+    pub fn flush(&self) -> Result<usize> {
+        self.return_held();
+        let flushed = self.message_queue.flush()?;
+        self.clear_all_in_flight();
+        Ok(flushed)
+    }
+    
+    /// Fsync the disk queue if it has writes older than its sync timeout
+    pub fn sync_if_due(&self) -> Result<()> {
+        self.message_queue.sync_if_due()
+    }
+    
+    /// Remove fully consumed disk queue files, returning how many bytes
+    /// were freed
+    pub fn compact(&self) -> Result<u64> {
+        self.message_queue.compact_backend()
     }
     
     /// Get in-flight count
     pub fn in_flight_count(&self) -> usize {
-        self.message_queue.in_flight_count()
+        self.in_flight.read().len()
     }
     
     /// Get deferred count
@@ -216,23 +797,39 @@ impl Channel {
         self.message_queue.deferred_count()
     }
     
-    /// Pause the channel
+    /// Pause the channel; it keeps receiving messages but delivers none
     pub fn pause(&self) -> Result<()> {
-        *self.paused.write() = true;
+        self.paused_at.write().get_or_insert_with(chrono::Utc::now);
         self.metrics.incr("channels.paused", 1);
         Ok(())
     }
     
     /// Unpause the channel
     pub fn unpause(&self) -> Result<()> {
-        *self.paused.write() = false;
+        *self.paused_at.write() = None;
+        self.notify.notify_waiters();
         self.metrics.incr("channels.unpaused", 1);
         Ok(())
     }
     
     /// Check if channel is paused
     pub fn is_paused(&self) -> bool {
-        *self.paused.read()
+        self.paused_at.read().is_some()
+    }
+    
+    /// When the channel was paused, if it is
+    pub fn paused_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        *self.paused_at.read()
+    }
+    
+    /// Check whether delivery is stopped by a pause of the channel or its topic
+    fn is_halted(&self) -> bool {
+        self.is_paused() || self.topic_pause.read().is_paused()
+    }
+    
+    /// Get the age of the oldest message waiting in the channel
+    pub fn oldest_message_age(&self) -> Option<std::time::Duration> {
+        self.message_queue.oldest_message_age()
     }
     
     /// Delete the channel
@@ -244,3 +841,12 @@ impl Channel {
         Ok(())
     }
 }
+
+/// Whether a sampling client takes a message. The choice is stable for a
+/// message and client, so a message passed back to the queue is not re-rolled
+/// by the client that skipped it.
+fn is_sampled(message_id: MessageId, client_id: Uuid, sample_rate: u32) -> bool {
+    let mut hasher = DefaultHasher::new();
+    (message_id, client_id).hash(&mut hasher);
+    hasher.finish() % 100 < sample_rate as u64
+}
@@ -2,13 +2,18 @@
 
 use std::sync::Arc;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 use parking_lot::RwLock;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
-use nsq_protocol::{Command, Message, NsqDecoder};
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::UnboundedSender;
+use nsq_protocol::{CodecCounters, Command, Frame, FrameType, IdentifyResponse, Message, MessageId};
 use nsq_common::{Metrics, Result, NsqError};
+use crate::latency::LatencyHistogram;
+
+/// Shortest heartbeat interval a client may ask for
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Client connection state
 #[derive(Debug, Clone, PartialEq)]
@@ -25,6 +30,25 @@ pub enum ClientState {
     Closed,
 }
 
+impl ClientState {
+    /// Why a client in this state may not send `command`, or None when it may.
+    /// A connection identifies at most once, before SUB; it either publishes
+    /// or subscribes once, and only a subscribed one sends RDY, FIN, REQ and
+    /// TOUCH.
+    pub fn rejection(&self, command: &Command) -> Option<String> {
+        let subscribed = matches!(self, ClientState::Subscribed | ClientState::Ready);
+        let reason = match command {
+            Command::Identify { .. } if subscribed => "after SUB",
+            Command::Identify { .. } if *self != ClientState::Initial => "twice",
+            Command::Sub { .. } if subscribed => "twice on one connection",
+            Command::Pub { .. } | Command::Mpub { .. } | Command::Dpub { .. } if subscribed => "after SUB",
+            Command::Rdy { .. } | Command::Fin { .. } | Command::Req { .. } | Command::Touch { .. } if !subscribed => "before SUB",
+            _ => return None,
+        };
+        Some(format!("cannot {} {}", command.name(), reason))
+    }
+}
+
 /// Client information
 #[derive(Debug, Clone)]
 pub struct ClientInfo {
@@ -37,13 +61,28 @@ pub struct ClientInfo {
     pub tls_cipher_suite: Option<String>,
     pub deflate: bool,
     pub snappy: bool,
+    /// zstd stream compression (extension, not part of upstream NSQ)
+    pub zstd: bool,
+    pub zstd_level: i32,
     pub sample_rate: u32,
+    /// Interval between heartbeats sent to an idle client; zero disables them
     pub heartbeat_interval: Duration,
+    pub max_heartbeat_interval: Duration,
     pub output_buffer_size: usize,
     pub output_buffer_timeout: Duration,
+    pub max_output_buffer_size: usize,
+    pub max_output_buffer_timeout: Duration,
     pub max_rdy_count: u32,
     pub max_msg_timeout: Duration,
     pub msg_timeout: Duration,
+    /// Return generated message IDs in PUB/MPUB responses
+    pub publish_receipts: bool,
+    /// Message headers in published bodies and delivered messages (extension)
+    pub message_headers: bool,
+    /// Replay the topic's consumed backlog still on disk into channels
+    /// this client's SUB creates (extension)
+    pub replay_backlog: bool,
+    pub connect_time: chrono::DateTime<chrono::Utc>,
 }
 
 impl Default for ClientInfo {
@@ -58,21 +97,119 @@ impl Default for ClientInfo {
             tls_cipher_suite: None,
             deflate: false,
             snappy: false,
+            zstd: false,
+            zstd_level: 3,
             sample_rate: 0,
             heartbeat_interval: Duration::from_secs(30),
+            max_heartbeat_interval: Duration::from_secs(60),
             output_buffer_size: 16 * 1024, // 16KB
             output_buffer_timeout: Duration::from_millis(250),
+            max_output_buffer_size: 16 * 1024,
+            max_output_buffer_timeout: Duration::from_millis(250),
             max_rdy_count: 2500,
             max_msg_timeout: Duration::from_secs(15 * 60), // 15 minutes
             msg_timeout: Duration::from_secs(60), // 1 minute
+            publish_receipts: false,
+            message_headers: false,
+            replay_backlog: false,
+            connect_time: chrono::Utc::now(),
+        }
+    }
+}
+
+impl ClientInfo {
+    /// Apply the fields sent by the client in an IDENTIFY command
+    pub fn apply_identify(&mut self, data: &serde_json::Value) {
+        let get_str = |key: &str| data.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let get_bool = |key: &str| data.get(key).and_then(|v| v.as_bool());
+        let get_u64 = |key: &str| data.get(key).and_then(|v| v.as_u64());
+        
+        if let Some(hostname) = get_str("hostname") {
+            self.hostname = Some(hostname);
+        }
+        if let Some(user_agent) = get_str("user_agent") {
+            self.user_agent = Some(user_agent);
+        }
+        // deflate, snappy and TLS are not implemented, so never negotiated
+        if let Some(zstd) = get_bool("zstd") {
+            self.zstd = zstd;
+        }
+        if let Some(level) = data.get("zstd_level").and_then(|v| v.as_i64()) {
+            self.zstd_level = level.clamp(1, 19) as i32;
+        }
+        if let Some(sample_rate) = get_u64("sample_rate") {
+            self.sample_rate = sample_rate.min(99) as u32;
+        }
+        // -1 disables heartbeats and 0 keeps the default, as in the original nsqd
+        match data.get("heartbeat_interval").and_then(|v| v.as_i64()) {
+            Some(-1) => self.heartbeat_interval = Duration::ZERO,
+            Some(interval) if interval > 0 => {
+                self.heartbeat_interval = Duration::from_millis(interval as u64)
+                    .clamp(MIN_HEARTBEAT_INTERVAL, self.max_heartbeat_interval.max(MIN_HEARTBEAT_INTERVAL));
+            }
+            _ => {}
+        }
+        if let Some(size) = get_u64("output_buffer_size") {
+            self.output_buffer_size = (size as usize).min(self.max_output_buffer_size);
+        }
+        if let Some(timeout) = get_u64("output_buffer_timeout") {
+            self.output_buffer_timeout = Duration::from_millis(timeout).min(self.max_output_buffer_timeout);
+        }
+        if let Some(timeout) = get_u64("msg_timeout") {
+            self.msg_timeout = Duration::from_millis(timeout).min(self.max_msg_timeout);
+        }
+        if let Some(receipts) = get_bool("publish_receipts") {
+            self.publish_receipts = receipts;
+        }
+        if let Some(headers) = get_bool("message_headers") {
+            self.message_headers = headers;
+        }
+        if let Some(replay) = get_bool("replay_backlog") {
+            self.replay_backlog = replay;
+        }
+    }
+}
+
+impl ClientInfo {
+    /// Settings reported to a client that asked for feature negotiation
+    pub fn identify_response(&self) -> IdentifyResponse {
+        IdentifyResponse {
+            max_rdy_count: self.max_rdy_count,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            max_msg_timeout: self.max_msg_timeout.as_millis() as u64,
+            msg_timeout: self.msg_timeout.as_millis() as u64,
+            tls_v1: false,
+            deflate: false,
+            deflate_level: 0,
+            max_deflate_level: 0,
+            snappy: false,
+            sample_rate: self.sample_rate,
+            auth_required: false,
+            output_buffer_size: self.output_buffer_size,
+            output_buffer_timeout: self.output_buffer_timeout.as_millis() as u64,
+            zstd: self.zstd,
+            zstd_level: if self.zstd { self.zstd_level } else { 0 },
         }
     }
 }
 
+/// Output queued for the connection writer
+#[derive(Debug)]
+pub enum ClientOutput {
+    /// Frame to write
+    Frame(Frame),
+    /// Message to write, unless the client has stopped being ready by then
+    Message(Message),
+    /// Compress everything written after this point with zstd at the given level
+    EnableZstd(i32),
+}
+
 /// Client connection
 pub struct Client {
+    /// Client ID
+    id: Uuid,
     /// Client information
-    pub info: ClientInfo,
+    info: Arc<RwLock<ClientInfo>>,
     /// Current state
     state: Arc<RwLock<ClientState>>,
     /// Subscribed topic
@@ -83,14 +220,18 @@ pub struct Client {
     rdy_count: Arc<RwLock<u32>>,
     /// Last message time
     last_message_time: Arc<RwLock<Option<std::time::Instant>>>,
-    /// In-flight messages
-    in_flight_messages: Arc<RwLock<HashMap<Uuid, Message>>>,
-    /// TCP stream
-    stream: Option<Framed<TcpStream, NsqDecoder>>,
+    /// In-flight messages with the time they were delivered
+    in_flight_messages: Arc<RwLock<HashMap<MessageId, (Message, Instant)>>>,
+    /// Outbound frame sender, drained by the connection writer
+    sender: Arc<RwLock<Option<UnboundedSender<ClientOutput>>>>,
+    /// Wakes the dispatcher when the client can accept more messages
+    notify: Arc<Notify>,
     /// Metrics
     metrics: Metrics,
     /// Client statistics
     stats: Arc<RwLock<ClientStats>>,
+    /// Protocol bytes read and written by the connection codecs
+    codec_counters: Arc<CodecCounters>,
 }
 
 /// Client statistics
@@ -100,33 +241,72 @@ pub struct ClientStats {
     pub messages_finished: u64,
     pub messages_requeued: u64,
     pub messages_timed_out: u64,
-    pub bytes_received: u64,
-    pub bytes_sent: u64,
     pub commands_received: u64,
     pub commands_sent: u64,
+    /// Time from delivery to FIN
+    pub finish_latency: LatencyHistogram,
 }
 
 impl Client {
     /// Create a new client
     pub fn new(
         info: ClientInfo,
-        stream: Framed<TcpStream, NsqDecoder>,
+        sender: UnboundedSender<ClientOutput>,
         metrics: Metrics,
     ) -> Self {
         Self {
-            info,
+            id: info.id,
+            info: Arc::new(RwLock::new(info)),
             state: Arc::new(RwLock::new(ClientState::Initial)),
             topic: Arc::new(RwLock::new(None)),
             channel: Arc::new(RwLock::new(None)),
             rdy_count: Arc::new(RwLock::new(0)),
             last_message_time: Arc::new(RwLock::new(None)),
             in_flight_messages: Arc::new(RwLock::new(HashMap::new())),
-            stream: Some(stream),
+            sender: Arc::new(RwLock::new(Some(sender))),
+            notify: Arc::new(Notify::new()),
             metrics,
             stats: Arc::new(RwLock::new(ClientStats::default())),
+            codec_counters: Arc::new(CodecCounters::new()),
         }
     }
     
+    /// Get client ID
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+    
+    /// Counters shared with the connection's codecs
+    pub fn codec_counters(&self) -> Arc<CodecCounters> {
+        self.codec_counters.clone()
+    }
+    
+    /// Get client information
+    pub fn info(&self) -> ClientInfo {
+        self.info.read().clone()
+    }
+    
+    /// Handle an IDENTIFY command
+    pub fn identify(&self, data: &serde_json::Value) {
+        self.info.write().apply_identify(data);
+        self.set_state(ClientState::Identified);
+    }
+    
+    /// Check if the client asked for publish receipts
+    pub fn wants_publish_receipts(&self) -> bool {
+        self.info.read().publish_receipts
+    }
+    
+    /// Check if the client exchanges messages with headers
+    pub fn wants_message_headers(&self) -> bool {
+        self.info.read().message_headers
+    }
+    
+    /// Record a command received from the client
+    pub fn record_command(&self) {
+        self.stats.write().commands_received += 1;
+    }
+    
     /// Get current state
     pub fn state(&self) -> ClientState {
         self.state.read().clone()
@@ -165,6 +345,7 @@ impl Client {
     /// Set RDY count
     pub fn set_rdy_count(&self, count: u32) {
         *self.rdy_count.write() = count;
+        self.notify.notify_waiters();
     }
     
     /// Check if client is ready to receive messages
@@ -172,39 +353,87 @@ impl Client {
         self.state() == ClientState::Ready && self.rdy_count() > 0
     }
     
+    /// Check if the client has RDY capacity for another message
+    pub fn can_receive(&self) -> bool {
+        self.is_ready() && (self.in_flight_count() as u32) < self.rdy_count()
+    }
+    
+    /// Wait until the client's readiness may have changed
+    pub async fn wait_for_ready(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.notify.notified()).await;
+    }
+    
     /// Add in-flight message
     pub fn add_in_flight(&self, message: Message) {
         let message_id = message.id;
-        let message_size = message.size();
         
-        self.in_flight_messages.write().insert(message_id, message);
+        self.in_flight_messages.write().insert(message_id, (message, Instant::now()));
+        self.stats.write().messages_received += 1;
+        
+        *self.last_message_time.write() = Some(std::time::Instant::now());
+        self.metrics.incr("client.messages.in_flight", 1);
+    }
+    
+    /// Remove in-flight message
+    pub fn remove_in_flight(&self, message_id: MessageId) -> Option<Message> {
+        let (message, delivered_at) = self.in_flight_messages.write().remove(&message_id)?;
         
         {
             let mut stats = self.stats.write();
-            stats.messages_received += 1;
-            stats.bytes_received += message_size as u64;
+            stats.messages_finished += 1;
+            stats.finish_latency.record(delivered_at.elapsed());
         }
         
-        *self.last_message_time.write() = Some(std::time::Instant::now());
-        self.metrics.incr("client.messages.in_flight", 1);
+        self.metrics.incr("client.messages.finished", 1);
+        self.notify.notify_waiters();
+        
+        Some(message)
     }
     
-    /// Remove in-flight message
-    pub fn remove_in_flight(&self, message_id: Uuid) -> Option<Message> {
-        let message = self.in_flight_messages.write().remove(&message_id);
+    /// Remove an in-flight message that was requeued
+    pub fn requeue_in_flight(&self, message_id: MessageId) -> Option<Message> {
+        let message = self.in_flight_messages.write().remove(&message_id).map(|(message, _)| message);
         
         if message.is_some() {
-            {
-                let mut stats = self.stats.write();
-                stats.messages_finished += 1;
-            }
-            
-            self.metrics.incr("client.messages.finished", 1);
+            self.stats.write().messages_requeued += 1;
+            self.metrics.incr("client.messages.requeued", 1);
+            self.notify.notify_waiters();
+        }
+        
+        message
+    }
+    
+    /// Remove an in-flight message that was never sent because the client
+    /// stopped being ready
+    pub fn return_in_flight(&self, message_id: MessageId) -> Option<Message> {
+        let message = self.in_flight_messages.write().remove(&message_id).map(|(message, _)| message);
+        
+        if message.is_some() {
+            let mut stats = self.stats.write();
+            stats.messages_received = stats.messages_received.saturating_sub(1);
+        }
+        
+        message
+    }
+    
+    /// Remove an in-flight message that timed out
+    pub fn timeout_in_flight(&self, message_id: MessageId) -> Option<Message> {
+        let message = self.in_flight_messages.write().remove(&message_id).map(|(message, _)| message);
+        
+        if message.is_some() {
+            self.stats.write().messages_timed_out += 1;
+            self.metrics.incr("client.messages.timed_out", 1);
+            self.notify.notify_waiters();
         }
         
         message
     }
     
+    /// Get the IDs of all in-flight messages
+    pub fn in_flight_ids(&self) -> Vec<MessageId> {
+        self.in_flight_messages.read().keys().copied().collect()
+    }
+    
     /// Get in-flight message count
     pub fn in_flight_count(&self) -> usize {
         self.in_flight_messages.read().len()
@@ -218,71 +447,65 @@ impl Client {
     /// Check if client has timed out
     pub fn is_timed_out(&self) -> bool {
         if let Some(last_time) = *self.last_message_time.read() {
-            last_time.elapsed() > self.info.msg_timeout
+            last_time.elapsed() > self.info.read().msg_timeout
         } else {
             false
         }
     }
     
-    /// Send a command to the client
-    pub async fn send_command(&mut self, _command: Command) -> Result<()> {
-        if let Some(_stream) = self.stream.as_mut() {
-            // TODO: Implement sending command via stream
-            // let frame = Frame::new(nsq_protocol::FrameType::Response, command.to_bytes()?);
-            // stream.send(frame).await.map_err(|e| NsqError::Io(e))?;
-            
-            {
-                let mut stats = self.stats.write();
-                stats.commands_sent += 1;
-            }
-            
-            self.metrics.incr("client.commands.sent", 1);
-        } else {
-            return Err(NsqError::Validation("Client stream not available".to_string()));
+    /// Queue output for the connection writer
+    fn send_output(&self, output: ClientOutput) -> Result<()> {
+        let sender = self.sender.read();
+        let sender = sender.as_ref()
+            .ok_or_else(|| NsqError::Validation("Client stream not available".to_string()))?;
+        sender.send(output)
+            .map_err(|_| NsqError::Validation("Client stream closed".to_string()))
+    }
+    
+    /// Queue a frame for the connection writer
+    fn send_frame(&self, frame: Frame) -> Result<()> {
+        self.send_output(ClientOutput::Frame(frame))
+    }
+    
+    /// Switch the outbound stream to zstd after all previously queued frames
+    pub fn enable_zstd(&self) -> Result<()> {
+        let level = self.info.read().zstd_level;
+        self.send_output(ClientOutput::EnableZstd(level))
+    }
+    
+    /// Send a response to the client
+    pub fn send_response(&self, body: impl Into<Bytes>) -> Result<()> {
+        self.send_frame(Frame::new(FrameType::Response, body.into()))?;
+        
+        {
+            let mut stats = self.stats.write();
+            stats.commands_sent += 1;
         }
         
+        self.metrics.incr("client.commands.sent", 1);
         Ok(())
     }
     
     /// Send a message to the client
-    pub async fn send_message(&mut self, _message: Message) -> Result<()> {
-        if let Some(_stream) = self.stream.as_mut() {
-            // TODO: Implement sending message via stream
-            // let frame = Frame::new(nsq_protocol::FrameType::Message, message.to_bytes());
-            // stream.send(frame).await.map_err(|e| NsqError::Io(e))?;
-            
-            {
-                let mut stats = self.stats.write();
-                stats.bytes_sent += _message.size() as u64;
-            }
-            
-            self.metrics.incr("client.messages.sent", 1);
-        } else {
-            return Err(NsqError::Validation("Client stream not available".to_string()));
-        }
+    pub fn send_message(&self, message: Message) -> Result<()> {
+        self.send_output(ClientOutput::Message(message))?;
         
+        self.metrics.incr("client.messages.sent", 1);
         Ok(())
     }
     
     /// Send an error to the client
-    pub async fn send_error(&mut self, _error: String) -> Result<()> {
-        if let Some(_stream) = self.stream.as_mut() {
-            // TODO: Implement sending error via stream
-            // let frame = Frame::new(nsq_protocol::FrameType::Error, error.into());
-            // stream.send(frame).await.map_err(|e| NsqError::Io(e))?;
-            
-            self.metrics.incr("client.errors.sent", 1);
-        } else {
-            return Err(NsqError::Validation("Client stream not available".to_string()));
-        }
-        
+    pub fn send_error(&self, error: String) -> Result<()> {
+        self.send_frame(Frame::new(FrameType::Error, Bytes::from(error)))?;
+        self.metrics.incr("client.errors.sent", 1);
         Ok(())
     }
     
     /// Close the client connection
-    pub fn close(&mut self) {
+    pub fn close(&self) {
         self.set_state(ClientState::Closed);
-        self.stream = None;
+        self.sender.write().take();
+        self.notify.notify_waiters();
         self.metrics.incr("client.connections.closed", 1);
     }
     
@@ -3,12 +3,17 @@
 pub use nsq_common::NsqdConfig;
 use clap::Parser;
 use std::path::PathBuf;
+use nsq_common::{NsqError, Result, validate_address};
 
 /// NSQd command line arguments
 #[derive(Parser, Debug)]
 #[command(name = "nsqd")]
 #[command(about = "NSQ message queue daemon")]
 pub struct Args {
+    /// Config file (TOML, YAML or JSON); command line flags override it
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    
     /// TCP address to listen on
     #[arg(long, default_value = "0.0.0.0:4150")]
     pub tcp_address: String,
@@ -21,6 +26,10 @@ pub struct Args {
     #[arg(long)]
     pub https_address: Option<String>,
     
+    /// Broadcast address advertised to lookupd ("auto" picks the primary interface)
+    #[arg(long, default_value = "auto")]
+    pub broadcast_address: String,
+    
     /// TCP unix socket path
     #[arg(long)]
     pub tcp_socket_path: Option<String>,
@@ -41,6 +50,32 @@ pub struct Args {
     #[arg(long, default_value = "10000")]
     pub mem_queue_size: usize,
     
+    /// Queued messages, in memory and on disk, of a topic or any one of its
+    /// channels above which the topic refuses HTTP publishes (0 = no limit)
+    #[arg(long, default_value = "0")]
+    pub max_topic_depth: u64,
+    
+    /// Milliseconds a blocking HTTP publish waits for a full topic
+    #[arg(long, default_value = "5000")]
+    pub pub_block_timeout: u64,
+    
+    /// Messages written to a disk queue between fsyncs (0 = only on --sync-timeout)
+    #[arg(long, default_value = "2500")]
+    pub sync_every: u64,
+    
+    /// Milliseconds after which written messages are fsynced
+    #[arg(long, default_value = "2000")]
+    pub sync_timeout: u64,
+    
+    /// Acknowledge publishes only after they are written and fsynced to disk
+    #[arg(long)]
+    pub durable_publish: bool,
+    
+    /// Bytes all topics may hold in memory before queued messages are
+    /// spilled to disk (0 = no limit)
+    #[arg(long, default_value = "0")]
+    pub max_memory_size: usize,
+    
     /// Maximum message size
     #[arg(long, default_value = "1048576")]
     pub max_msg_size: usize,
@@ -61,6 +96,37 @@ pub struct Args {
     #[arg(long, default_value = "60000")]
     pub msg_timeout: u64,
     
+    /// Highest heartbeat interval in milliseconds a client may ask for
+    #[arg(long, default_value = "60000")]
+    pub max_heartbeat_interval: u64,
+    
+    /// Milliseconds a channel may go without clients, messages or activity
+    /// before it is deleted (0 = never)
+    #[arg(long, default_value = "0")]
+    pub channel_idle_timeout: u64,
+    
+    /// URL alerts are POSTed to; alerting is off when unset
+    #[arg(long)]
+    pub alert_webhook_url: Option<String>,
+    
+    /// Queued messages above which a depth alert fires (0 = off)
+    #[arg(long, default_value = "0")]
+    pub alert_max_depth: u64,
+    
+    /// Requeues per second of a channel above which an alert fires (0 = off)
+    #[arg(long, default_value = "0")]
+    pub alert_max_requeue_rate: f64,
+    
+    /// Milliseconds a channel may go without consumers before an alert
+    /// fires (0 = off)
+    #[arg(long, default_value = "0")]
+    pub alert_no_consumers_timeout: u64,
+    
+    /// Milliseconds a condition must hold before its alert fires, and stay
+    /// clear before it resolves
+    #[arg(long, default_value = "30000")]
+    pub alert_debounce: u64,
+    
     /// Maximum output buffer size
     #[arg(long, default_value = "16384")]
     pub max_output_buffer_size: usize,
@@ -101,10 +167,58 @@ pub struct Args {
     #[arg(long, default_value = "text")]
     pub log_format: String,
     
+    /// Per-module log level override such as nsqd::channel=debug (repeatable)
+    #[arg(long = "log-filter")]
+    pub log_filters: Vec<String>,
+    
+    /// Write logs to this file instead of stdout
+    #[arg(long)]
+    pub log_file: Option<String>,
+    
+    /// Rotate the log file once it reaches this many bytes; 0 disables rotation
+    #[arg(long, default_value = "104857600")]
+    pub log_max_size: u64,
+    
+    /// Rotated log files to keep
+    #[arg(long, default_value = "5")]
+    pub log_max_files: usize,
+    
+    /// OTLP/HTTP collector to export trace spans to, e.g. http://127.0.0.1:4318
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    
+    /// Service name for exported spans (defaults to the executable name)
+    #[arg(long)]
+    pub otlp_service_name: Option<String>,
+    
+    /// Fraction of traces to export (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub otlp_sample_rate: f64,
+    
+    /// Log every HTTP request
+    #[arg(long)]
+    pub access_log: bool,
+    
+    /// Fraction of HTTP requests to log (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub access_log_sample_rate: f64,
+    
+    /// Write HTTP requests to this file as JSON lines
+    #[arg(long)]
+    pub access_log_file: Option<String>,
+    
     /// Lookupd TCP addresses
     #[arg(long)]
     pub lookupd_tcp_addresses: Vec<String>,
     
+    /// Lookupd HTTP addresses replication peers are discovered from
+    #[arg(long)]
+    pub lookupd_http_addresses: Vec<String>,
+    
+    /// Peer nodes each topic is mirrored to (0 = no replication)
+    #[arg(long, default_value = "0")]
+    pub replication_factor: usize,
+    
     /// Disable HTTP interface
     #[arg(long)]
     pub disable_http: bool,
@@ -113,9 +227,54 @@ pub struct Args {
     #[arg(long)]
     pub disable_https: bool,
     
+    /// Reject SUB to topics and channels that do not exist instead of creating them
+    #[arg(long)]
+    pub disable_implicit_creation: bool,
+    
+    /// Accept TCP clients that start sending commands without the "  V2" magic
+    #[arg(long)]
+    pub allow_missing_magic: bool,
+    
+    /// TCP clients that may be connected at once (0 = no limit)
+    #[arg(long, default_value = "0")]
+    pub max_connections: usize,
+    
+    /// New TCP connections a second accepted from one IP (0 = no limit)
+    #[arg(long, default_value = "0")]
+    pub max_connection_rate: u64,
+    
+    /// Commands a second accepted from the TCP clients of one IP (0 = no limit)
+    #[arg(long, default_value = "0")]
+    pub max_command_rate: u64,
+    
+    /// Availability zone reported to lookupd for locality-aware lookups
+    #[arg(long)]
+    pub zone: Option<String>,
+    
+    /// Region reported to lookupd for locality-aware lookups
+    #[arg(long)]
+    pub region: Option<String>,
+    
+    /// Worker ID (0-1023) stamped into message IDs, unique per node
+    /// (defaults to a hash of the hostname and TCP port)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(0..=nsq_protocol::MAX_WORKER_ID as i64))]
+    pub node_id: Option<u16>,
+    
     /// E2E processing latency percentiles
     #[arg(long)]
     pub e2e_processing_latency_percentile: Vec<f64>,
+    
+    /// Milliseconds to wait for in-flight messages to finish on shutdown
+    #[arg(long, default_value = "30000")]
+    pub drain_timeout: u64,
+    
+    /// Deliveries allowed per message before it is moved to {topic}#dlq (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    pub max_attempts: u16,
+    
+    /// Drop messages over --max-attempts instead of moving them to {topic}#dlq
+    #[arg(long)]
+    pub disable_dead_letter: bool,
 }
 
 impl From<Args> for NsqdConfig {
@@ -124,21 +283,45 @@ impl From<Args> for NsqdConfig {
             base: nsq_common::BaseConfig {
                 log_level: args.log_level,
                 log_format: args.log_format,
+                log_filters: args.log_filters,
+                log_file: args.log_file,
+                log_max_size: args.log_max_size,
+                log_max_files: args.log_max_files,
+                otlp_endpoint: args.otlp_endpoint,
+                otlp_service_name: args.otlp_service_name,
+                otlp_sample_rate: args.otlp_sample_rate,
+                access_log: args.access_log,
+                access_log_sample_rate: args.access_log_sample_rate,
+                access_log_file: args.access_log_file,
                 statsd_address: args.statsd_address,
                 statsd_prefix: args.statsd_prefix,
             },
             tcp_address: args.tcp_address,
             http_address: args.http_address,
             https_address: args.https_address,
+            broadcast_address: args.broadcast_address,
             tcp_socket_path: args.tcp_socket_path,
             http_socket_path: args.http_socket_path,
             https_socket_path: args.https_socket_path,
             data_path: args.data_path,
             mem_queue_size: args.mem_queue_size,
+            max_topic_depth: args.max_topic_depth,
+            pub_block_timeout: args.pub_block_timeout,
+            sync_every: args.sync_every,
+            sync_timeout: args.sync_timeout,
+            durable_publish: args.durable_publish,
+            max_memory_size: args.max_memory_size,
             max_msg_size: args.max_msg_size,
             max_body_size: args.max_body_size,
             max_req_timeout: args.max_req_timeout,
             max_msg_timeout: args.max_msg_timeout,
+            max_heartbeat_interval: args.max_heartbeat_interval,
+            channel_idle_timeout: args.channel_idle_timeout,
+            alert_webhook_url: args.alert_webhook_url,
+            alert_max_depth: args.alert_max_depth,
+            alert_max_requeue_rate: args.alert_max_requeue_rate,
+            alert_no_consumers_timeout: args.alert_no_consumers_timeout,
+            alert_debounce: args.alert_debounce,
             msg_timeout: args.msg_timeout,
             max_output_buffer_size: args.max_output_buffer_size,
             max_output_buffer_timeout: args.max_output_buffer_timeout,
@@ -152,8 +335,75 @@ impl From<Args> for NsqdConfig {
                 args.e2e_processing_latency_percentile
             },
             lookupd_tcp_addresses: args.lookupd_tcp_addresses,
+            lookupd_http_addresses: args.lookupd_http_addresses,
+            replication_factor: args.replication_factor,
             disable_http: args.disable_http,
             disable_https: args.disable_https,
+            disable_implicit_creation: args.disable_implicit_creation,
+            allow_missing_magic: args.allow_missing_magic,
+            max_connections: args.max_connections,
+            max_connection_rate: args.max_connection_rate,
+            max_command_rate: args.max_command_rate,
+            zone: args.zone,
+            region: args.region,
+            node_id: args.node_id,
+            drain_timeout: args.drain_timeout,
+            max_attempts: args.max_attempts,
+            disable_dead_letter: args.disable_dead_letter,
         }
     }
 }
+
+/// Copy the settings nsqd can change without restarting
+pub fn apply_reloadable(source: &NsqdConfig, target: &mut NsqdConfig) {
+    target.base.log_level = source.base.log_level.clone();
+    target.max_msg_size = source.max_msg_size;
+    target.msg_timeout = source.msg_timeout;
+    target.channel_idle_timeout = source.channel_idle_timeout;
+    target.lookupd_tcp_addresses = source.lookupd_tcp_addresses.clone();
+    target.tls_cert = source.tls_cert.clone();
+    target.tls_key = source.tls_key.clone();
+}
+
+/// Check the settings that can be reloaded, so a bad file leaves the running
+/// configuration untouched
+pub fn validate_reloadable(config: &NsqdConfig) -> Result<()> {
+    config.base.log_level.parse::<tracing::Level>()
+        .map_err(|_| NsqError::Config(format!("Invalid log_level: {}", config.base.log_level)))?;
+    
+    if config.max_msg_size == 0 || config.max_msg_size > config.max_body_size {
+        return Err(NsqError::Config(format!(
+            "max_msg_size must be between 1 and max_body_size ({}), got {}",
+            config.max_body_size, config.max_msg_size
+        )));
+    }
+    
+    if config.msg_timeout == 0 || config.msg_timeout > config.max_msg_timeout {
+        return Err(NsqError::Config(format!(
+            "msg_timeout must be between 1 and max_msg_timeout ({}ms), got {}ms",
+            config.max_msg_timeout, config.msg_timeout
+        )));
+    }
+    
+    if config.max_heartbeat_interval < 1000 {
+        return Err(NsqError::Config(format!(
+            "max_heartbeat_interval must be at least 1000ms, got {}ms",
+            config.max_heartbeat_interval
+        )));
+    }
+    
+    for address in &config.lookupd_tcp_addresses {
+        validate_address(address)
+            .map_err(|e| NsqError::Config(format!("Invalid lookupd_tcp_addresses entry {}: {}", address, e)))?;
+    }
+    
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return Err(NsqError::Config("tls_cert and tls_key must be set together".to_string()));
+    }
+    for path in config.tls_cert.iter().chain(config.tls_key.iter()) {
+        std::fs::File::open(path)
+            .map_err(|e| NsqError::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+    }
+    
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! TCP connection limits
+//!
+//! Caps how many TCP clients are connected at once and, per client IP, how
+//! fast new connections are opened and commands are sent, so one misbehaving
+//! client cannot exhaust the daemon. Rates use the token buckets of topic
+//! publish limits, holding one second's allowance.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use crate::rate_limit::{RateLimit, RateLimiter};
+
+/// Connection limit statistics
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionLimitStats {
+    pub max_connections: usize,
+    pub max_connection_rate: u64,
+    pub max_command_rate: u64,
+    pub open_connections: usize,
+    /// Connections closed on arrival because of a limit
+    pub rejected_connections: u64,
+    /// Connections closed for sending commands too fast
+    pub throttled_connections: u64,
+    /// Client IPs with a partly used allowance
+    pub tracked_ips: usize,
+}
+
+/// Limits on TCP connections and the commands sent over them
+pub struct ConnectionLimits {
+    max_connections: usize,
+    connection_rate: RateLimit,
+    command_rate: RateLimit,
+    open: Arc<AtomicUsize>,
+    connections: DashMap<IpAddr, RateLimiter>,
+    commands: DashMap<IpAddr, RateLimiter>,
+    rejected: AtomicU64,
+    throttled: AtomicU64,
+}
+
+/// Place of an admitted connection, given back when dropped
+pub struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLimits {
+    /// Create limits of `max_connections` open connections, and per IP
+    /// `connection_rate` new connections and `command_rate` commands a
+    /// second (0 = no limit)
+    pub fn new(max_connections: usize, connection_rate: u64, command_rate: u64) -> Self {
+        let per_second = |rate| RateLimit { max_msgs_per_sec: rate, max_bytes_per_sec: 0 };
+        Self {
+            max_connections,
+            connection_rate: per_second(connection_rate),
+            command_rate: per_second(command_rate),
+            open: Arc::new(AtomicUsize::new(0)),
+            connections: DashMap::new(),
+            commands: DashMap::new(),
+            rejected: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Admit a new connection from `ip`, or give the reason it is refused
+    pub fn admit(&self, ip: IpAddr) -> Result<ConnectionSlot, String> {
+        let open = self.open.fetch_add(1, Ordering::Relaxed);
+        let slot = ConnectionSlot { open: self.open.clone() };
+        let refusal = if self.max_connections > 0 && open >= self.max_connections {
+            Some(format!("nsqd is at its limit of {} connections", self.max_connections))
+        } else if !Self::acquire(&self.connections, self.connection_rate, ip) {
+            Some(format!("{} is limited to {} new connections/s", ip, self.connection_rate.max_msgs_per_sec))
+        } else {
+            None
+        };
+        match refusal {
+            Some(reason) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(reason)
+            }
+            None => Ok(slot),
+        }
+    }
+
+    /// Take the allowance for a command from `ip`, or give the reason it is
+    /// refused
+    pub fn allow_command(&self, ip: IpAddr) -> Result<(), String> {
+        if Self::acquire(&self.commands, self.command_rate, ip) {
+            return Ok(());
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Err(format!("{} is limited to {} commands/s", ip, self.command_rate.max_msgs_per_sec))
+    }
+
+    fn acquire(limiters: &DashMap<IpAddr, RateLimiter>, limit: RateLimit, ip: IpAddr) -> bool {
+        if !limit.is_enabled() {
+            return true;
+        }
+        limiters.entry(ip).or_insert_with(|| RateLimiter::new(limit)).try_acquire(1, 0)
+    }
+
+    /// Forget the IPs whose allowance has refilled, so idle clients are not
+    /// tracked forever
+    pub fn prune(&self) {
+        self.connections.retain(|_, limiter| !limiter.is_full());
+        self.commands.retain(|_, limiter| !limiter.is_full());
+    }
+
+    pub fn stats(&self) -> ConnectionLimitStats {
+        ConnectionLimitStats {
+            max_connections: self.max_connections,
+            max_connection_rate: self.connection_rate.max_msgs_per_sec,
+            max_command_rate: self.command_rate.max_msgs_per_sec,
+            open_connections: self.open.load(Ordering::Relaxed),
+            rejected_connections: self.rejected.load(Ordering::Relaxed),
+            throttled_connections: self.throttled.load(Ordering::Relaxed),
+            tracked_ips: self.connections.len().max(self.commands.len()),
+        }
+    }
+}
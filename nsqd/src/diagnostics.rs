@@ -0,0 +1,101 @@
+//! Runtime diagnostics
+//!
+//! Counters and snapshots served under `/debug`: tokio scheduler metrics,
+//! contention on the queue locks of each topic and channel, and the memory
+//! held by in-memory queues.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use parking_lot::{RwLock, RwLockWriteGuard};
+use serde::Serialize;
+
+/// Acquisitions of a lock and how often and how long they had to wait
+#[derive(Debug, Default)]
+pub struct LockCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_ns: AtomicU64,
+}
+
+/// Snapshot of [`LockCounters`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub wait_ns: u64,
+}
+
+impl LockCounters {
+    /// Take a write lock, counting the acquisition and any time spent waiting
+    pub fn write<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = lock.try_write() {
+            return guard;
+        }
+
+        let start = Instant::now();
+        let guard = lock.write();
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_ns.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        guard
+    }
+
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait_ns: self.wait_ns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Messages and bytes held in memory by a queue
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueMemory {
+    pub queued_messages: usize,
+    pub queued_bytes: usize,
+    pub in_flight_messages: usize,
+    pub in_flight_bytes: usize,
+    pub deferred_messages: usize,
+    pub deferred_bytes: usize,
+}
+
+impl QueueMemory {
+    pub fn total_bytes(&self) -> usize {
+        self.queued_bytes + self.in_flight_bytes + self.deferred_bytes
+    }
+}
+
+/// Scheduler metrics of the current tokio runtime
+pub fn runtime_stats() -> serde_json::Value {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let workers: Vec<serde_json::Value> = (0..metrics.num_workers())
+        .map(|worker| serde_json::json!({
+            "worker": worker,
+            "busy_ms": metrics.worker_total_busy_duration(worker).as_millis() as u64,
+            "park_count": metrics.worker_park_count(worker),
+        }))
+        .collect();
+
+    serde_json::json!({
+        "workers": metrics.num_workers(),
+        "alive_tasks": metrics.num_alive_tasks(),
+        "global_queue_depth": metrics.global_queue_depth(),
+        "worker_stats": workers,
+    })
+}
+
+/// Resident and virtual memory of the process in bytes, where the platform
+/// reports it
+pub fn process_memory() -> Option<serde_json::Value> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    // Sizes are reported as e.g. "VmRSS:     1234 kB"
+    let field = |name: &str| status.lines()
+        .find_map(|line| line.strip_prefix(name))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+    Some(serde_json::json!({
+        "resident_bytes": field("VmRSS:")?,
+        "virtual_bytes": field("VmSize:")?,
+    }))
+}
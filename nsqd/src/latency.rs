@@ -0,0 +1,76 @@
+//! Latency histograms
+//!
+//! Fixed-size log-scale histogram so per-client and per-channel latency
+//! tracking uses constant memory regardless of message volume.
+
+use std::time::Duration;
+
+/// Number of buckets
+const BUCKETS: usize = 96;
+/// Upper bound of the first bucket in microseconds
+const MIN_MICROS: f64 = 100.0;
+/// Ratio between consecutive bucket bounds (2^(1/4), about 19% relative error)
+const GROWTH: f64 = 1.189_207_115_002_721;
+
+/// Log-scale latency histogram covering 100µs to roughly 28 minutes
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    total: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; BUCKETS],
+            total: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a latency sample
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as f64;
+        let index = if micros <= MIN_MICROS {
+            0
+        } else {
+            ((micros / MIN_MICROS).ln() / GROWTH.ln()).ceil() as usize
+        };
+        self.counts[index.min(BUCKETS - 1)] += 1;
+        self.total += 1;
+    }
+
+    /// Number of recorded samples
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Estimate a quantile (0.0-1.0) as the upper bound of its bucket
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let rank = ((quantile.clamp(0.0, 1.0) * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let micros = MIN_MICROS * GROWTH.powi(index as i32);
+                return Some(Duration::from_micros(micros as u64));
+            }
+        }
+        None
+    }
+
+    /// Quantile in fractional milliseconds, for stats output
+    pub fn quantile_ms(&self, quantile: f64) -> Option<f64> {
+        self.quantile(quantile).map(|latency| latency.as_micros() as f64 / 1000.0)
+    }
+}
@@ -9,6 +9,17 @@ pub mod client;
 pub mod message;
 pub mod stats;
 pub mod config;
+pub mod lookupd;
+pub mod latency;
+pub mod snapshot;
+pub mod metadata;
+pub mod diagnostics;
+pub mod rate_limit;
+pub mod connection_limits;
+pub mod ordering;
+pub mod replication;
+pub mod message_trace;
+pub mod alerts;
 
 pub use server::*;
 pub use topic::*;
@@ -0,0 +1,512 @@
+//! Lookupd registration notifications
+//!
+//! Topic and channel changes are queued per lookupd peer and delivered by a
+//! background worker, so publish and HTTP paths never wait on lookupd.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep, timeout};
+use nsq_common::{Metrics, Result, NsqError, RestartPolicy, TaskSupervisor};
+
+/// Maximum number of distinct registrations waiting per peer
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+/// Delay before retrying after a failed delivery
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Interval between heartbeats sent to lookupd
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Timeout for a single lookupd command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between checks while waiting for queues to drain
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Registration action sent to lookupd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationAction {
+    Register,
+    Unregister,
+}
+
+/// Topic or channel registration key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Registration {
+    pub topic: String,
+    pub channel: Option<String>,
+}
+
+impl Registration {
+    /// Build the lookupd command line for this registration
+    fn command(&self, action: RegistrationAction) -> String {
+        let verb = match action {
+            RegistrationAction::Register => "REGISTER",
+            RegistrationAction::Unregister => "UNREGISTER",
+        };
+        match &self.channel {
+            Some(channel) => format!("{} {} {}\n", verb, self.topic, channel),
+            None => format!("{} {}\n", verb, self.topic),
+        }
+    }
+}
+
+/// Notification queue statistics for a lookupd peer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LookupdPeerStats {
+    pub address: String,
+    pub queue_depth: u64,
+    pub sent_count: u64,
+    pub coalesced_count: u64,
+    pub dropped_count: u64,
+    pub failure_count: u64,
+    pub connected: bool,
+}
+
+/// Pending notifications, coalesced by registration
+#[derive(Default)]
+struct PendingQueue {
+    order: VecDeque<Registration>,
+    actions: HashMap<Registration, RegistrationAction>,
+}
+
+/// A single lookupd peer with its own notification queue
+pub struct LookupdPeer {
+    address: String,
+    /// `IDENTIFY` command sent on every new connection
+    identify: String,
+    capacity: usize,
+    pending: RwLock<PendingQueue>,
+    /// Registrations lookupd has accepted, replayed on every new connection
+    /// because lookupd drops them when a connection closes
+    registered: RwLock<HashSet<Registration>>,
+    /// Set while the worker is delivering popped notifications
+    delivering: AtomicBool,
+    notify: Notify,
+    /// Stops the worker once the peer is removed from the configuration
+    removed: Notify,
+    stats: RwLock<LookupdPeerStats>,
+    metrics: Metrics,
+}
+
+impl LookupdPeer {
+    /// Create a new lookupd peer
+    fn new(address: String, identify: String, capacity: usize, metrics: Metrics) -> Self {
+        Self {
+            identify,
+            stats: RwLock::new(LookupdPeerStats {
+                address: address.clone(),
+                ..Default::default()
+            }),
+            address,
+            capacity,
+            pending: RwLock::new(PendingQueue::default()),
+            registered: RwLock::new(HashSet::new()),
+            delivering: AtomicBool::new(false),
+            notify: Notify::new(),
+            removed: Notify::new(),
+            metrics,
+        }
+    }
+
+    /// Queue a notification, replacing any pending action for the same registration
+    fn enqueue(&self, registration: Registration, action: RegistrationAction) {
+        {
+            let mut pending = self.pending.write();
+            if let Some(existing) = pending.actions.get_mut(&registration) {
+                *existing = action;
+                self.stats.write().coalesced_count += 1;
+            } else if pending.order.len() >= self.capacity {
+                self.stats.write().dropped_count += 1;
+                self.metrics.incr("lookupd.notifications.dropped", 1);
+                tracing::warn!("Lookupd queue for {} is full, dropping {:?}", self.address, registration);
+                return;
+            } else {
+                pending.order.push_back(registration.clone());
+                pending.actions.insert(registration, action);
+            }
+            self.stats.write().queue_depth = pending.order.len() as u64;
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Take the oldest pending notification
+    fn pop(&self) -> Option<(Registration, RegistrationAction)> {
+        let mut pending = self.pending.write();
+        let registration = pending.order.pop_front()?;
+        let action = pending.actions.remove(&registration)?;
+        self.stats.write().queue_depth = pending.order.len() as u64;
+        Some((registration, action))
+    }
+
+    /// Put a failed notification back unless a newer one is already queued
+    fn requeue(&self, registration: Registration, action: RegistrationAction) {
+        let mut pending = self.pending.write();
+        if !pending.actions.contains_key(&registration) {
+            pending.order.push_front(registration.clone());
+            pending.actions.insert(registration, action);
+        }
+        self.stats.write().queue_depth = pending.order.len() as u64;
+    }
+
+    /// Check whether every queued notification has been delivered
+    fn is_idle(&self) -> bool {
+        !self.delivering.load(Ordering::Acquire) && self.pending.read().order.is_empty()
+    }
+
+    /// Get peer statistics
+    pub fn stats(&self) -> LookupdPeerStats {
+        self.stats.read().clone()
+    }
+
+    /// Send a command and wait for the single-line response
+    async fn send_command(stream: &mut BufReader<TcpStream>, command: &str) -> Result<String> {
+        let exchange = async {
+            stream.get_mut().write_all(command.as_bytes()).await?;
+            let mut response = String::new();
+            if stream.read_line(&mut response).await? == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "lookupd closed connection"));
+            }
+            Ok(response.trim().to_string())
+        };
+
+        timeout(COMMAND_TIMEOUT, exchange).await
+            .map_err(|_| NsqError::Queue(format!("Lookupd {} timed out", command.trim())))?
+            .map_err(NsqError::Io)
+    }
+
+    /// Connect and identify, so lookupd registers this node with its real
+    /// ports and locality
+    async fn connect(&self) -> Option<BufReader<TcpStream>> {
+        let mut stream = match TcpStream::connect(&self.address).await {
+            Ok(stream) => BufReader::new(stream),
+            Err(e) => {
+                tracing::warn!("Failed to connect to lookupd {}: {}", self.address, e);
+                return None;
+            }
+        };
+
+        match Self::send_command(&mut stream, &self.identify).await {
+            Ok(response) if response == "OK" => {
+                tracing::info!("Connected to lookupd {}", self.address);
+                self.replay(&mut stream).await.then_some(stream)
+            }
+            Ok(response) => {
+                tracing::warn!("Lookupd {} rejected IDENTIFY: {}", self.address, response);
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Failed to identify to lookupd {}: {}", self.address, e);
+                None
+            }
+        }
+    }
+
+    /// Register everything lookupd accepted over earlier connections,
+    /// returning whether the connection is still usable
+    async fn replay(&self, stream: &mut BufReader<TcpStream>) -> bool {
+        let registrations: Vec<Registration> = self.registered.read().iter().cloned().collect();
+        for registration in &registrations {
+            if let Err(e) = Self::send_command(stream, &registration.command(RegistrationAction::Register)).await {
+                tracing::warn!("Failed to restore registrations with lookupd {}: {}", self.address, e);
+                return false;
+            }
+        }
+        if !registrations.is_empty() {
+            tracing::info!("Restored {} registrations with lookupd {}", registrations.len(), self.address);
+        }
+        true
+    }
+
+    /// Remember an accepted registration for later reconnects
+    fn record(&self, registration: &Registration, action: RegistrationAction) {
+        let mut registered = self.registered.write();
+        match action {
+            RegistrationAction::Register => {
+                registered.insert(registration.clone());
+            }
+            RegistrationAction::Unregister => {
+                registered.remove(registration);
+            }
+        }
+    }
+
+    /// Deliver queued notifications until the process exits
+    async fn run(self: Arc<Self>) {
+        let mut connection: Option<BufReader<TcpStream>> = None;
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = heartbeat.tick() => {
+                    if let Some(stream) = connection.as_mut() {
+                        if Self::send_command(stream, "PING\n").await.is_err() {
+                            connection = None;
+                        }
+                    }
+                    // (Re)connect so lookupd lists this node, even before it
+                    // has topics, for peers discovering replication targets
+                    if connection.is_none() {
+                        connection = self.connect().await;
+                    }
+                }
+            }
+
+            self.delivering.store(true, Ordering::Release);
+            while let Some((registration, action)) = self.pop() {
+                if connection.is_none() {
+                    connection = self.connect().await;
+                }
+
+                let result = match connection.as_mut() {
+                    Some(stream) => Self::send_command(stream, &registration.command(action)).await,
+                    None => Err(NsqError::Queue(format!("Not connected to lookupd {}", self.address))),
+                };
+
+                match result {
+                    Ok(response) if response == "OK" => {
+                        self.record(&registration, action);
+                        self.stats.write().sent_count += 1;
+                        self.metrics.incr("lookupd.notifications.sent", 1);
+                    }
+                    Ok(response) => {
+                        // Lookupd rejected the command; retrying will not help
+                        self.stats.write().failure_count += 1;
+                        tracing::warn!("Lookupd {} rejected {:?}: {}", self.address, registration, response);
+                    }
+                    Err(e) => {
+                        connection = None;
+                        self.requeue(registration, action);
+                        self.stats.write().failure_count += 1;
+                        self.metrics.incr("lookupd.notifications.failed", 1);
+                        tracing::warn!("Failed to notify lookupd {}: {}", self.address, e);
+                        sleep(RETRY_DELAY).await;
+                    }
+                }
+
+                self.stats.write().connected = connection.is_some();
+            }
+            self.delivering.store(false, Ordering::Release);
+
+            self.stats.write().connected = connection.is_some();
+        }
+    }
+}
+
+/// Fans topic and channel changes out to all configured lookupd peers
+#[derive(Clone)]
+pub struct LookupdNotifier {
+    peers: Arc<RwLock<Vec<Arc<LookupdPeer>>>>,
+    /// `IDENTIFY` command for new peers
+    identify: String,
+    metrics: Metrics,
+}
+
+impl LookupdNotifier {
+    /// Create a new notifier for the given lookupd TCP addresses, identifying
+    /// with the given producer details
+    pub fn new(addresses: &[String], identity: serde_json::Value, metrics: Metrics) -> Self {
+        let notifier = Self {
+            peers: Arc::new(RwLock::new(Vec::new())),
+            identify: format!("IDENTIFY {}\n", identity),
+            metrics,
+        };
+        *notifier.peers.write() = addresses.iter().map(|address| notifier.new_peer(address)).collect();
+        notifier
+    }
+
+    fn new_peer(&self, address: &str) -> Arc<LookupdPeer> {
+        Arc::new(LookupdPeer::new(address.to_string(), self.identify.clone(), DEFAULT_QUEUE_CAPACITY, self.metrics.clone()))
+    }
+
+    /// Start the background delivery workers
+    pub fn start(&self, supervisor: &TaskSupervisor) {
+        for peer in self.peers.read().iter() {
+            Self::spawn_peer(peer.clone(), supervisor);
+        }
+    }
+
+    /// Run a peer's delivery worker until shutdown or until it is removed
+    fn spawn_peer(peer: Arc<LookupdPeer>, supervisor: &TaskSupervisor) {
+        let stop = supervisor.clone();
+        supervisor.spawn(&format!("lookupd_peer.{}", peer.address), RestartPolicy::OnFailure, move || {
+            let peer = peer.clone();
+            let stop = stop.clone();
+            async move {
+                tokio::select! {
+                    _ = peer.clone().run() => {}
+                    _ = peer.removed.notified() => {}
+                    _ = stop.stopped() => {}
+                }
+                Ok(())
+            }
+        });
+    }
+
+    /// Replace the set of lookupd peers, keeping the queues of peers that
+    /// stay and queueing `registrations` for the ones that are new
+    pub fn set_addresses(&self, addresses: &[String], registrations: &[Registration], supervisor: &TaskSupervisor) {
+        let mut peers = self.peers.write();
+        let (kept, removed): (Vec<_>, Vec<_>) = peers.drain(..).partition(|peer| addresses.contains(&peer.address));
+        for peer in removed {
+            tracing::info!("Removing lookupd {}", peer.address);
+            peer.removed.notify_one();
+        }
+
+        *peers = kept;
+        for address in addresses {
+            if peers.iter().any(|peer| &peer.address == address) {
+                continue;
+            }
+            tracing::info!("Adding lookupd {}", address);
+            let peer = self.new_peer(address);
+            for registration in registrations {
+                peer.enqueue(registration.clone(), RegistrationAction::Register);
+            }
+            Self::spawn_peer(peer.clone(), supervisor);
+            peers.push(peer);
+        }
+    }
+
+    /// Queue a notification for every peer
+    pub fn notify(&self, action: RegistrationAction, topic: &str, channel: Option<&str>) {
+        let registration = Registration {
+            topic: topic.to_string(),
+            channel: channel.map(|c| c.to_string()),
+        };
+        for peer in self.peers.read().iter() {
+            peer.enqueue(registration.clone(), action);
+        }
+    }
+
+    /// Wait up to `deadline` for every peer to deliver its queued
+    /// notifications, returning whether they all were
+    pub async fn flush(&self, deadline: Duration) -> bool {
+        let drained = async {
+            while !self.peers.read().iter().all(|peer| peer.is_idle()) {
+                sleep(FLUSH_POLL_INTERVAL).await;
+            }
+        };
+        timeout(deadline, drained).await.is_ok()
+    }
+
+    /// Get statistics for all peers
+    pub fn stats(&self) -> Vec<LookupdPeerStats> {
+        self.peers.read().iter().map(|peer| peer.stats()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use tokio::net::TcpListener;
+    use nsq_common::BaseConfig;
+
+    fn peer(address: &str, capacity: usize) -> Arc<LookupdPeer> {
+        let metrics = Metrics::new(&BaseConfig::default()).unwrap();
+        Arc::new(LookupdPeer::new(address.to_string(), "IDENTIFY {}\n".to_string(), capacity, metrics))
+    }
+
+    fn topic(name: &str) -> Registration {
+        Registration { topic: name.to_string(), channel: None }
+    }
+
+    /// Lookupd stand-in answering OK to every command except those naming
+    /// `rejected`, recording the commands it received
+    async fn fake_lookupd(listener: TcpListener, commands: Arc<Mutex<Vec<String>>>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let response = if line.contains("rejected") { "E_INVALID\n" } else { "OK\n" };
+                commands.lock().push(line.trim().to_string());
+                line.clear();
+                if stream.get_mut().write_all(response.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !condition() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("condition not reached in time");
+    }
+
+    #[test]
+    fn test_create_then_delete_is_coalesced() {
+        let peer = peer("127.0.0.1:0", 2);
+        peer.enqueue(topic("t"), RegistrationAction::Register);
+        peer.enqueue(topic("t"), RegistrationAction::Unregister);
+        let stats = peer.stats();
+        assert_eq!((stats.queue_depth, stats.coalesced_count), (1, 1));
+
+        peer.enqueue(topic("u"), RegistrationAction::Register);
+        peer.enqueue(topic("v"), RegistrationAction::Register);
+        assert_eq!((peer.stats().queue_depth, peer.stats().dropped_count), (2, 1));
+
+        assert_eq!(peer.pop(), Some((topic("t"), RegistrationAction::Unregister)));
+        assert_eq!(peer.pop(), Some((topic("u"), RegistrationAction::Register)));
+        assert_eq!(peer.pop(), None);
+        assert_eq!(peer.stats().queue_depth, 0);
+        assert!(peer.is_idle());
+    }
+
+    #[test]
+    fn test_requeue_keeps_newer_notification() {
+        let peer = peer("127.0.0.1:0", 8);
+        peer.enqueue(topic("t"), RegistrationAction::Register);
+        let (registration, action) = peer.pop().unwrap();
+        peer.enqueue(topic("t"), RegistrationAction::Unregister);
+        peer.requeue(registration, action);
+        assert_eq!(peer.stats().queue_depth, 1);
+        assert_eq!(peer.pop(), Some((topic("t"), RegistrationAction::Unregister)));
+
+        peer.enqueue(topic("u"), RegistrationAction::Register);
+        peer.requeue(topic("t"), RegistrationAction::Register);
+        assert_eq!(peer.pop(), Some((topic("t"), RegistrationAction::Register)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_is_retried_once_lookupd_is_up() {
+        // Reserve a port and leave it closed so the first attempts are refused
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let peer = peer(&address.to_string(), 8);
+        peer.enqueue(topic("t"), RegistrationAction::Register);
+        let worker = tokio::spawn(peer.clone().run());
+
+        wait_until(|| peer.stats().failure_count > 0).await;
+        assert_eq!(peer.stats().queue_depth, 1);
+        assert!(!peer.stats().connected);
+
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let lookupd = tokio::spawn(fake_lookupd(TcpListener::bind(address).await.unwrap(), commands.clone()));
+        wait_until(|| peer.stats().sent_count == 1).await;
+        let stats = peer.stats();
+        assert_eq!(stats.queue_depth, 0);
+        assert!(stats.connected);
+        assert!(peer.registered.read().contains(&topic("t")));
+
+        // A rejected command is counted but not retried
+        let failures = stats.failure_count;
+        peer.enqueue(topic("rejected"), RegistrationAction::Register);
+        wait_until(|| peer.stats().failure_count == failures + 1).await;
+        wait_until(|| peer.is_idle()).await;
+        assert_eq!(peer.stats().sent_count, 1);
+        assert!(!peer.registered.read().contains(&topic("rejected")));
+        let commands: Vec<String> = commands.lock().iter().filter(|command| *command != "PING").cloned().collect();
+        assert_eq!(commands, vec!["IDENTIFY {}", "REGISTER t", "REGISTER rejected"]);
+
+        worker.abort();
+        lookupd.abort();
+    }
+}
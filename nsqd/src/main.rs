@@ -1,26 +1,63 @@
 //! NSQd main entry point
 
-use nsqd::{config::Args, server::NsqdServer};
-use nsq_common::init_logging;
-use clap::Parser;
+use nsqd::{config::{validate_reloadable, Args}, server::NsqdServer, NsqdConfig};
+use nsq_common::{explicit_args, init_logging, merge_config_file, resolve_broadcast_address, shutdown_tracing};
+use clap::{CommandFactory, FromArgMatches};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let explicit = explicit_args(&matches);
+    let args = Args::from_arg_matches(&matches)?;
+    let config_path = args.config.clone();
     
-    // Convert to configuration
-    let config: nsqd::NsqdConfig = args.into();
+    // Convert to configuration, layering the config file under explicit flags
+    let cli_config: NsqdConfig = args.into();
+    let loaded = match &config_path {
+        Some(path) => merge_config_file(&cli_config, &explicit, path),
+        None => Ok(cli_config.clone()),
+    };
+    let mut config = match loaded.and_then(|config| validate_reloadable(&config).map(|_| config)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     
     // Initialize logging
     init_logging(&config.base)?;
     
+    config.broadcast_address = resolve_broadcast_address(&config.broadcast_address)?;
+    
     // Create and start server
     let mut server = NsqdServer::new(config)?;
     server.start().await?;
     
-    // Keep the main thread alive
-    tokio::signal::ctrl_c().await?;
+    // Run until interrupted, reloading the config file on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = hangup.recv() => {
+                let Some(path) = &config_path else {
+                    tracing::warn!("Received SIGHUP but no --config file to reload");
+                    continue;
+                };
+                match merge_config_file(&cli_config, &explicit, path).and_then(|reloaded| server.reload_config(&reloaded)) {
+                    Ok(()) => tracing::info!("Reloaded configuration from {}", path.display()),
+                    Err(e) => tracing::error!("Keeping current configuration: {}", e),
+                }
+            }
+        }
+    }
+    server.shutdown().await?;
+    shutdown_tracing();
     
     Ok(())
 }
@@ -1,13 +1,16 @@
 //! Message handling and management
 
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use bytes::Bytes;
 use uuid::Uuid;
 use parking_lot::RwLock;
 use crossbeam_channel::{Receiver, Sender};
-use nsq_protocol::{Message, MessageStats};
-use nsq_common::{Metrics, Result, NsqError};
+use nsq_protocol::{Message, MessageId, MessageStats};
+use nsq_common::{DiskMark, DiskReplay, DiskUsage, Metrics, Result, NsqError};
+use crate::diagnostics::{LockCounters, LockStats, QueueMemory};
 
 /// In-flight message tracking
 #[derive(Debug, Clone)]
@@ -45,23 +48,25 @@ impl InFlightMessage {
 /// Message queue for a channel
 pub struct MessageQueue {
     /// Memory queue for fast access
-    memory_queue: Arc<RwLock<Vec<Message>>>,
+    memory_queue: Arc<RwLock<VecDeque<Message>>>,
     /// Disk queue for persistence
     disk_queue: Option<nsq_common::DiskQueue>,
     /// Maximum memory queue size
-    max_memory_size: usize,
+    max_memory_size: AtomicUsize,
     /// Channel for sending messages to consumers
     sender: Sender<Message>,
     /// Channel for receiving messages from producers
     receiver: Receiver<Message>,
     /// In-flight messages
-    in_flight: Arc<RwLock<std::collections::HashMap<Uuid, InFlightMessage>>>,
+    in_flight: Arc<RwLock<std::collections::HashMap<MessageId, InFlightMessage>>>,
     /// Deferred messages
-    deferred: Arc<RwLock<std::collections::HashMap<Uuid, (Message, Instant)>>>,
+    deferred: Arc<RwLock<std::collections::HashMap<MessageId, (Message, Instant)>>>,
     /// Metrics
     metrics: Metrics,
     /// Queue statistics
     stats: Arc<RwLock<MessageStats>>,
+    /// Contention on the memory queue lock
+    queue_lock: LockCounters,
 }
 
 impl MessageQueue {
@@ -74,9 +79,9 @@ impl MessageQueue {
         let (sender, receiver) = crossbeam_channel::unbounded();
         
         Self {
-            memory_queue: Arc::new(RwLock::new(Vec::new())),
+            memory_queue: Arc::new(RwLock::new(VecDeque::new())),
             disk_queue,
-            max_memory_size,
+            max_memory_size: AtomicUsize::new(max_memory_size),
             sender,
             receiver,
             in_flight: Arc::new(RwLock::new(std::collections::HashMap::new())),
@@ -90,11 +95,24 @@ impl MessageQueue {
                 messages_requeued: 0,
                 messages_timed_out: 0,
             })),
+            queue_lock: LockCounters::default(),
         }
     }
     
+    /// Change how many messages are held in memory before overflowing to
+    /// disk; messages already queued stay where they are
+    pub fn set_max_memory_size(&self, max_memory_size: usize) {
+        self.max_memory_size.store(max_memory_size, Ordering::Relaxed);
+    }
+    
     /// Put a message into the queue
     pub fn put(&self, message: Message) -> Result<()> {
+        self.put_marked(message).map(|_| ())
+    }
+    
+    /// Put a message into the queue, returning the mark to take it back with
+    /// `take_back` when it went to disk
+    pub fn put_marked(&self, message: Message) -> Result<Option<DiskMark>> {
         let message_size = message.size();
         
         // Update statistics
@@ -104,33 +122,85 @@ impl MessageQueue {
             stats.total_bytes += message_size as u64;
         }
         
-        // Try memory queue first
+        // Try memory queue first, unless older messages are waiting on disk
+        // and would be overtaken
         {
-            let mut memory_queue = self.memory_queue.write();
-            if memory_queue.len() < self.max_memory_size {
-                memory_queue.push(message);
+            let mut memory_queue = self.queue_lock.write(&self.memory_queue);
+            if memory_queue.len() < self.max_memory_size.load(Ordering::Relaxed) && self.backend_depth() == 0 {
+                memory_queue.push_back(message);
                 self.metrics.incr("messages.memory", 1);
-                return Ok(());
+                return Ok(None);
             }
         }
         
         // Fall back to disk queue
-        if let Some(ref disk_queue) = self.disk_queue {
-            disk_queue.put(&message.body)?;
-            self.metrics.incr("messages.disk", 1);
-        } else {
+        let Some(ref disk_queue) = self.disk_queue else {
             return Err(NsqError::Queue("Memory queue full and no disk queue available".to_string()));
+        };
+        let mark = disk_queue.put_batch(&[message.to_bytes_with_headers()])?;
+        self.metrics.incr("messages.disk", 1);
+        Ok(Some(mark))
+    }
+    
+    /// Take back a message put by `put_marked`, returning whether it was
+    /// removed. One already handed out, or on disk with later writes after
+    /// it, is kept.
+    pub fn take_back(&self, message_id: MessageId, mark: Option<DiskMark>) -> Result<bool> {
+        if let Some(mark) = mark {
+            return self.rollback_disk(mark);
+        }
+        let mut memory_queue = self.queue_lock.write(&self.memory_queue);
+        match memory_queue.iter().rposition(|message| message.id == message_id) {
+            Some(index) => {
+                memory_queue.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
         }
+    }
+    
+    /// Append messages to the disk queue as a whole, skipping memory,
+    /// returning how many bytes were written and the mark to take them back
+    /// with `rollback_disk`. They are durable once `sync_pending` returns.
+    pub fn put_batch_to_disk(&self, messages: &[Message]) -> Result<(u64, DiskMark)> {
+        let disk_queue = self.disk_queue.as_ref()
+            .ok_or_else(|| NsqError::Queue("No disk queue for a durable publish".to_string()))?;
+        let data: Vec<_> = messages.iter().map(|message| message.to_bytes_with_headers()).collect();
+        let mark = disk_queue.put_batch(&data)?;
         
-        Ok(())
+        {
+            let mut stats = self.stats.write();
+            stats.total_messages += messages.len() as u64;
+            stats.total_bytes += messages.iter().map(|message| message.size() as u64).sum::<u64>();
+        }
+        self.metrics.incr("messages.disk", messages.len() as u64);
+        Ok((data.iter().map(|data| 4 + data.len() as u64).sum(), mark))
+    }
+    
+    /// Take back messages appended by `put_batch_to_disk`, returning whether
+    /// they were removed; see `DiskQueue::rollback`
+    pub fn rollback_disk(&self, mark: DiskMark) -> Result<bool> {
+        match self.disk_queue {
+            Some(ref disk_queue) => disk_queue.rollback(mark),
+            None => Ok(false),
+        }
+    }
+    
+    /// Fsync the disk queue if it has writes since the last sync, returning
+    /// whether it did
+    pub fn sync_pending(&self) -> Result<bool> {
+        match self.disk_queue {
+            Some(ref disk_queue) => disk_queue.sync_pending(),
+            None => Ok(false),
+        }
     }
     
     /// Get a message from the queue
     pub fn get(&self) -> Result<Option<Message>> {
         // Try memory queue first
         {
-            let mut memory_queue = self.memory_queue.write();
-            if let Some(message) = memory_queue.pop() {
+            let mut memory_queue = self.queue_lock.write(&self.memory_queue);
+            if let Some(message) = memory_queue.pop_front() {
                 self.metrics.incr("messages.memory.dequeued", 1);
                 return Ok(Some(message));
             }
@@ -164,45 +234,63 @@ impl MessageQueue {
         Ok(())
     }
     
-    /// Finish a message (acknowledge)
-    pub fn finish(&self, message_id: Uuid) -> Result<()> {
-        if self.in_flight.write().remove(&message_id).is_some() {
-            {
-                let mut stats = self.stats.write();
-                stats.messages_in_flight = stats.messages_in_flight.saturating_sub(1);
-            }
-            
-            self.metrics.incr("messages.finished", 1);
-            Ok(())
-        } else {
-            Err(NsqError::Queue("Message not found in flight".to_string()))
-        }
+    /// Remove a message from flight without putting it anywhere
+    fn take_in_flight(&self, message_id: MessageId) -> Result<InFlightMessage> {
+        let Some(in_flight_msg) = self.in_flight.write().remove(&message_id) else {
+            return Err(NsqError::Queue("Message not found in flight".to_string()));
+        };
+        let mut stats = self.stats.write();
+        stats.messages_in_flight = stats.messages_in_flight.saturating_sub(1);
+        Ok(in_flight_msg)
+    }
+    
+    /// Finish a message (acknowledge), returning it
+    pub fn finish(&self, message_id: MessageId) -> Result<Message> {
+        let in_flight_msg = self.take_in_flight(message_id)?;
+        self.metrics.incr("messages.finished", 1);
+        Ok(in_flight_msg.message)
+    }
+    
+    /// Take back an in-flight message that never reached its client, undoing
+    /// the delivery attempt. The caller decides where it goes next.
+    pub fn take_returned(&self, message_id: MessageId) -> Result<Message> {
+        let mut message = self.take_in_flight(message_id)?.message;
+        message.attempts = message.attempts.saturating_sub(1);
+        self.metrics.incr("messages.returned", 1);
+        Ok(message)
+    }
+    
+    /// Take back an in-flight message that never reached its client and make
+    /// it the next one handed out, undoing the delivery attempt
+    pub fn return_to_front(&self, message_id: MessageId) -> Result<()> {
+        let message = self.take_returned(message_id)?;
+        self.put_front(message);
+        Ok(())
+    }
+    
+    /// Make a message the next one handed out. It was already counted against
+    /// the queue, so it may exceed the memory limit.
+    pub fn put_front(&self, message: Message) {
+        self.queue_lock.write(&self.memory_queue).push_front(message);
+    }
+    
+    /// Take a message out of flight to be delivered again, counting it as
+    /// requeued. The caller decides where it goes next.
+    pub fn take_requeued(&self, message_id: MessageId) -> Result<Message> {
+        let message = self.take_in_flight(message_id)?.message;
+        self.stats.write().messages_requeued += 1;
+        self.metrics.incr("messages.requeued", 1);
+        Ok(message)
     }
     
     /// Requeue a message
-    pub fn requeue(&self, message_id: Uuid, _timeout: Duration) -> Result<()> {
-        if let Some(mut in_flight_msg) = self.in_flight.write().remove(&message_id) {
-            in_flight_msg.requeue_count += 1;
-            in_flight_msg.start_time = Instant::now();
-            
-            // Put back in queue
-            self.put(in_flight_msg.message)?;
-            
-            {
-                let mut stats = self.stats.write();
-                stats.messages_in_flight = stats.messages_in_flight.saturating_sub(1);
-                stats.messages_requeued += 1;
-            }
-            
-            self.metrics.incr("messages.requeued", 1);
-            Ok(())
-        } else {
-            Err(NsqError::Queue("Message not found in flight".to_string()))
-        }
+    pub fn requeue(&self, message_id: MessageId, _timeout: Duration) -> Result<()> {
+        let message = self.take_requeued(message_id)?;
+        self.put(message)
     }
     
     /// Defer a message
-    pub fn defer(&self, message_id: Uuid, delay: Duration) -> Result<()> {
+    pub fn defer(&self, message_id: MessageId, delay: Duration) -> Result<()> {
         if let Some(in_flight_msg) = self.in_flight.write().remove(&message_id) {
             let defer_time = Instant::now() + delay;
             self.deferred.write().insert(message_id, (in_flight_msg.message, defer_time));
@@ -226,7 +314,7 @@ impl MessageQueue {
         let mut ready_messages = Vec::new();
         let mut deferred = self.deferred.write();
         
-        let ready_ids: Vec<Uuid> = deferred
+        let ready_ids: Vec<MessageId> = deferred
             .iter()
             .filter(|(_, (_, defer_time))| *defer_time <= now)
             .map(|(id, _)| *id)
@@ -248,12 +336,24 @@ impl MessageQueue {
         Ok(ready_messages)
     }
     
+    /// Reset the timeout of an in-flight message
+    pub fn touch(&self, message_id: MessageId) -> Result<()> {
+        match self.in_flight.write().get_mut(&message_id) {
+            Some(in_flight_msg) => {
+                in_flight_msg.start_time = Instant::now();
+                self.metrics.incr("messages.touched", 1);
+                Ok(())
+            }
+            None => Err(NsqError::Queue("Message not found in flight".to_string())),
+        }
+    }
+    
     /// Clean up timed out messages
-    pub fn cleanup_timeouts(&self) -> Result<Vec<Message>> {
+    pub fn cleanup_timeouts(&self) -> Result<Vec<InFlightMessage>> {
         let mut timed_out = Vec::new();
         let mut in_flight = self.in_flight.write();
         
-        let timed_out_ids: Vec<Uuid> = in_flight
+        let timed_out_ids: Vec<MessageId> = in_flight
             .iter()
             .filter(|(_, msg)| msg.is_timed_out())
             .map(|(id, _)| *id)
@@ -261,7 +361,7 @@ impl MessageQueue {
         
         for id in timed_out_ids {
             if let Some(in_flight_msg) = in_flight.remove(&id) {
-                timed_out.push(in_flight_msg.message);
+                timed_out.push(in_flight_msg);
                 
                 {
                     let mut stats = self.stats.write();
@@ -281,11 +381,198 @@ impl MessageQueue {
         self.stats.read().clone()
     }
     
+    /// Contention on the memory queue lock so far
+    pub fn lock_stats(&self) -> LockStats {
+        self.queue_lock.stats()
+    }
+    
+    /// Messages and bytes held in memory
+    pub fn memory_usage(&self) -> QueueMemory {
+        let (queued_messages, queued_bytes) = {
+            let memory_queue = self.memory_queue.read();
+            (memory_queue.len(), memory_queue.iter().map(Message::size).sum())
+        };
+        let (in_flight_messages, in_flight_bytes) = {
+            let in_flight = self.in_flight.read();
+            (in_flight.len(), in_flight.values().map(|m| m.message.size()).sum())
+        };
+        let (deferred_messages, deferred_bytes) = {
+            let deferred = self.deferred.read();
+            (deferred.len(), deferred.values().map(|(m, _)| m.size()).sum())
+        };
+        QueueMemory {
+            queued_messages,
+            queued_bytes,
+            in_flight_messages,
+            in_flight_bytes,
+            deferred_messages,
+            deferred_bytes,
+        }
+    }
+    
     /// Get queue depth
     pub fn depth(&self) -> usize {
         self.memory_queue.read().len()
     }
     
+    /// Discard the queued messages in memory and on disk, leaving in-flight and
+    /// deferred ones alone. Returns how many were discarded from each.
+    pub fn empty(&self) -> Result<(usize, u64)> {
+        let memory_emptied = std::mem::take(&mut *self.queue_lock.write(&self.memory_queue)).len();
+        let disk_emptied = match self.disk_queue {
+            Some(ref disk_queue) => disk_queue.empty()?,
+            None => 0,
+        };
+        
+        self.metrics.incr("messages.emptied", memory_emptied as u64 + disk_emptied);
+        Ok((memory_emptied, disk_emptied))
+    }
+    
+    /// Discard messages from the disk queue that are older than `max_age` or,
+    /// oldest first, that keep it above `max_bytes`. Returns how many were
+    /// discarded.
+    pub fn prune_backend(&self, max_age: Option<Duration>, max_bytes: Option<u64>) -> Result<u64> {
+        let Some(ref disk_queue) = self.disk_queue else {
+            return Ok(0);
+        };
+        let mut pruned = 0;
+        
+        if let Some(max_age) = max_age {
+            let cutoff = chrono::Utc::now() - max_age;
+            pruned += disk_queue.prune_while(|data| {
+                Message::from_bytes(Bytes::copy_from_slice(data))
+                    .is_ok_and(|message| message.timestamp < cutoff)
+            })?;
+        }
+        
+        if let Some(max_bytes) = max_bytes {
+            let mut excess = disk_queue.unread_bytes().saturating_sub(max_bytes);
+            pruned += disk_queue.prune_while(|data| {
+                if excess == 0 {
+                    return false;
+                }
+                excess = excess.saturating_sub(4 + data.len() as u64);
+                true
+            })?;
+        }
+        
+        if pruned > 0 {
+            self.metrics.incr("messages.pruned", pruned);
+        }
+        Ok(pruned)
+    }
+    
+    /// Up to `count` queued messages in the order get() would return them,
+    /// without removing them
+    pub fn peek(&self, count: usize) -> Result<Vec<Message>> {
+        let mut messages: Vec<Message> = self.memory_queue.read().iter().take(count).cloned().collect();
+        if let Some(ref disk_queue) = self.disk_queue {
+            for data in disk_queue.peek(count - messages.len())? {
+                messages.push(Message::from_bytes(Bytes::from(data))?);
+            }
+        }
+        Ok(messages)
+    }
+    
+    /// Reader of the messages already consumed from the disk queue that are
+    /// still on disk, oldest first; None without a disk queue
+    pub fn replay(&self) -> Result<Option<DiskReplay>> {
+        self.disk_queue.as_ref().map(|disk_queue| disk_queue.replay()).transpose()
+    }
+    
+    /// Fsync the disk queue if it has writes older than its sync timeout
+    pub fn sync_if_due(&self) -> Result<()> {
+        match self.disk_queue {
+            Some(ref disk_queue) => disk_queue.sync_if_due(),
+            None => Ok(()),
+        }
+    }
+    
+    /// Remove fully consumed disk queue files, returning how many bytes
+    /// were freed
+    pub fn compact_backend(&self) -> Result<u64> {
+        match self.disk_queue {
+            Some(ref disk_queue) => disk_queue.compact(),
+            None => Ok(0),
+        }
+    }
+    
+    /// Space the disk queue takes on disk
+    pub fn disk_usage(&self) -> DiskUsage {
+        self.disk_queue.as_ref().map(|disk_queue| disk_queue.usage()).unwrap_or_default()
+    }
+    
+    /// Get the number of messages waiting in the disk queue
+    pub fn backend_depth(&self) -> u64 {
+        self.disk_queue.as_ref().map(|disk_queue| disk_queue.depth()).unwrap_or(0)
+    }
+    
+    /// Move messages from the back of the memory queue to the disk queue
+    /// until at least `bytes` are freed, returning how many messages and
+    /// bytes were moved. Nothing is moved while the disk queue has messages,
+    /// since they are newer than everything in memory and would be overtaken;
+    /// once messages are spilled, later puts go to disk behind them.
+    pub fn spill(&self, bytes: usize) -> Result<(usize, usize)> {
+        let Some(ref disk_queue) = self.disk_queue else {
+            return Ok((0, 0));
+        };
+        // Held across the writes so a concurrent put cannot slip into memory
+        // ahead of the spilled messages
+        let mut memory_queue = self.queue_lock.write(&self.memory_queue);
+        if disk_queue.depth() > 0 {
+            return Ok((0, 0));
+        }
+        
+        let mut start = memory_queue.len();
+        let mut spilled_bytes = 0;
+        while start > 0 && spilled_bytes < bytes {
+            start -= 1;
+            spilled_bytes += memory_queue[start].size();
+        }
+        // Written before being removed, so a failed write duplicates
+        // messages rather than losing them
+        for message in memory_queue.range(start..) {
+            disk_queue.put(&message.to_bytes_with_headers())?;
+        }
+        let spilled = memory_queue.len() - start;
+        memory_queue.truncate(start);
+        
+        self.metrics.incr("messages.spilled", spilled as u64);
+        Ok((spilled, spilled_bytes))
+    }
+    
+    /// Move every queued, in-flight and deferred message to the disk queue
+    /// and sync it, returning how many messages were written
+    pub fn flush(&self) -> Result<usize> {
+        let disk_queue = self.disk_queue.as_ref()
+            .ok_or_else(|| NsqError::Queue("No disk queue to flush to".to_string()))?;
+        
+        // Delivered messages go first
+        let mut messages: Vec<Message> = self.in_flight.write().drain().map(|(_, msg)| msg.message).collect();
+        messages.extend(self.deferred.write().drain().map(|(_, (message, _))| message));
+        messages.extend(self.queue_lock.write(&self.memory_queue).drain(..));
+        
+        for message in &messages {
+            disk_queue.put(&message.to_bytes_with_headers())?;
+        }
+        disk_queue.sync()?;
+        
+        {
+            let mut stats = self.stats.write();
+            stats.messages_in_flight = 0;
+            stats.messages_deferred = 0;
+        }
+        
+        self.metrics.incr("messages.flushed", messages.len() as u64);
+        Ok(messages.len())
+    }
+    
+    /// Get the age of the oldest queued message
+    pub fn oldest_message_age(&self) -> Option<Duration> {
+        let oldest = self.memory_queue.read().iter().map(|m| m.timestamp).min()?;
+        (chrono::Utc::now() - oldest).to_std().ok()
+    }
+    
     /// Get in-flight count
     pub fn in_flight_count(&self) -> usize {
         self.in_flight.read().len()
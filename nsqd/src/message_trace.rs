@@ -0,0 +1,176 @@
+//! Message tracing
+//!
+//! Topics can be traced at runtime with `/debug/trace`. Messages of a traced
+//! topic record their publish, enqueue, delivery, FIN and REQ in a ring of
+//! recent events, read with `/debug/trace/events` to follow a message ID
+//! through the node; `nsq_trace` streams them.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use nsq_protocol::MessageId;
+
+/// Events kept for reading before the oldest are dropped
+pub const TRACE_RING_CAPACITY: usize = 10_000;
+
+/// Step of a message's life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceEventKind {
+    /// Received from a publisher
+    Publish,
+    /// Stored in the topic's queue
+    Enqueue,
+    /// Sent to a client of a channel
+    Deliver,
+    /// Finished by the client
+    Finish,
+    /// Requeued by the client
+    Requeue,
+}
+
+/// One recorded step of a traced message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// Position in the ring, increasing by one per event
+    pub seq: u64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub event: TraceEventKind,
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    pub message_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u16>,
+    /// Requeue delay in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl TraceEvent {
+    pub fn new(event: TraceEventKind, topic: &str, message_id: MessageId) -> Self {
+        Self {
+            seq: 0,
+            timestamp: chrono::Utc::now(),
+            event,
+            topic: topic.to_string(),
+            channel: None,
+            message_id: message_id.to_string(),
+            client_id: None,
+            attempts: None,
+            timeout_ms: None,
+        }
+    }
+
+    /// Set the channel and client the event happened on
+    pub fn on_channel(mut self, channel: &str, client_id: Uuid) -> Self {
+        self.channel = Some(channel.to_string());
+        self.client_id = Some(client_id.to_string());
+        self
+    }
+}
+
+/// Events matching a read of the ring
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub topic: Option<String>,
+    pub channel: Option<String>,
+    pub message_id: Option<String>,
+    /// Only events with a greater `seq`
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl TraceFilter {
+    fn matches(&self, event: &TraceEvent) -> bool {
+        self.topic.as_ref().is_none_or(|topic| *topic == event.topic)
+            && self.channel.as_ref().is_none_or(|channel| event.channel.as_ref() == Some(channel))
+            && self.message_id.as_ref().is_none_or(|id| *id == event.message_id)
+            && self.since.is_none_or(|since| event.seq > since)
+    }
+}
+
+/// Traced topics and the ring of their recent events
+#[derive(Debug)]
+pub struct MessageTracer {
+    topics: RwLock<HashSet<String>>,
+    /// Set while any topic is traced, so untraced nodes skip the lookup
+    enabled: AtomicBool,
+    events: Mutex<VecDeque<TraceEvent>>,
+    next_seq: AtomicU64,
+    capacity: usize,
+}
+
+impl Default for MessageTracer {
+    fn default() -> Self {
+        Self::new(TRACE_RING_CAPACITY)
+    }
+}
+
+impl MessageTracer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            topics: RwLock::new(HashSet::new()),
+            enabled: AtomicBool::new(false),
+            events: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            next_seq: AtomicU64::new(1),
+            capacity,
+        }
+    }
+
+    /// Start or stop tracing a topic
+    pub fn set_traced(&self, topic: &str, traced: bool) {
+        let mut topics = self.topics.write();
+        if traced {
+            topics.insert(topic.to_string());
+        } else {
+            topics.remove(topic);
+        }
+        self.enabled.store(!topics.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Traced topics, sorted by name
+    pub fn traced_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.topics.read().iter().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    pub fn is_traced(&self, topic: &str) -> bool {
+        self.enabled.load(Ordering::Relaxed) && self.topics.read().contains(topic)
+    }
+
+    /// Record the event built by `event` when `topic` is traced
+    pub fn record(&self, topic: &str, event: impl FnOnce() -> TraceEvent) {
+        if self.is_traced(topic) {
+            self.push(event());
+        }
+    }
+
+    fn push(&self, mut event: TraceEvent) {
+        let mut events = self.events.lock();
+        event.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Recorded events matching `filter`, oldest first
+    pub fn events(&self, filter: &TraceFilter) -> Vec<TraceEvent> {
+        self.events.lock().iter()
+            .filter(|event| filter.matches(event))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    /// `seq` of the most recent event, 0 before any
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::Relaxed) - 1
+    }
+}
@@ -0,0 +1,90 @@
+//! Topic and channel metadata
+//!
+//! Written on shutdown and whenever a topic or channel is paused or unpaused,
+//! so the next start recreates every topic and channel, including ones with no
+//! pending messages, before their disk queues are read.
+
+use std::path::{Path, PathBuf};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use nsq_common::{NsqError, Result};
+use crate::rate_limit::RateLimit;
+use crate::topic::{DeliverySettings, RetentionPolicy};
+
+/// File name of the metadata inside the data directory
+const METADATA_FILE: &str = "nsqd.dat.json";
+
+/// Serializes writers so concurrent saves don't share the temporary file
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A channel and its persisted settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelMetadata {
+    pub name: String,
+    pub paused: bool,
+    #[serde(default)]
+    pub delivery: DeliverySettings,
+    #[serde(default)]
+    pub ordered: bool,
+}
+
+/// A topic and its channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub delivery: DeliverySettings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_queue_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durable: Option<bool>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub replica: bool,
+    pub channels: Vec<ChannelMetadata>,
+}
+
+/// Topics and channels known at shutdown
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub version: String,
+    pub topics: Vec<TopicMetadata>,
+}
+
+impl Metadata {
+    /// Create metadata for the given topics
+    pub fn new(topics: Vec<TopicMetadata>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            topics,
+        }
+    }
+
+    fn path(data_path: &Path) -> PathBuf {
+        data_path.join(METADATA_FILE)
+    }
+
+    /// Write the metadata into the data directory
+    pub fn save(&self, data_path: &Path) -> Result<()> {
+        let _guard = SAVE_LOCK.lock();
+        std::fs::create_dir_all(data_path).map_err(NsqError::Io)?;
+        let contents = serde_json::to_vec_pretty(self)?;
+        let tmp_path = Self::path(data_path).with_extension("json.tmp");
+        std::fs::write(&tmp_path, contents).map_err(NsqError::Io)?;
+        std::fs::rename(&tmp_path, Self::path(data_path)).map_err(NsqError::Io)
+    }
+
+    /// Read the metadata, if any was written
+    pub fn load(data_path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(Self::path(data_path)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(NsqError::Io(e)),
+        }
+    }
+}
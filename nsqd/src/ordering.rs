@@ -0,0 +1,156 @@
+//! Ordered delivery by key
+//!
+//! A channel in ordered mode gives each message a key, taken from its
+//! `ordering-key` header or else hashed from its body. Every key is owned by
+//! one subscribed client, chosen by rendezvous hashing so that clients joining
+//! or leaving only move the keys they gain or lose, and a key has at most one
+//! message in flight at a time. Messages whose key is busy or owned by another
+//! client wait in a hold queue in the order they were taken from the topic.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use uuid::Uuid;
+use nsq_protocol::{Message, MessageId};
+
+/// Message header carrying the ordering key
+pub const ORDERING_KEY_HEADER: &str = "ordering-key";
+
+/// Most messages a channel holds back while waiting for their key's owner
+pub const MAX_HELD_MESSAGES: usize = 10_000;
+
+/// Key a message is ordered by: its `ordering-key` header, or its body
+pub fn ordering_key(message: &Message) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match message.headers.get(ORDERING_KEY_HEADER) {
+        Some(key) => key.as_bytes().hash(&mut hasher),
+        None => message.body.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// Client that owns a key among the subscribed clients
+pub fn key_owner(key: u64, clients: &[Uuid]) -> Option<Uuid> {
+    clients.iter()
+        .copied()
+        .max_by_key(|client_id| {
+            let mut hasher = DefaultHasher::new();
+            (key, client_id).hash(&mut hasher);
+            hasher.finish()
+        })
+}
+
+/// Ordered delivery state of a channel
+#[derive(Debug, Default)]
+pub struct OrderedDelivery {
+    /// Messages taken from the topic and not yet delivered, oldest first,
+    /// with their keys
+    held: VecDeque<(u64, Message)>,
+    /// Number of held messages of each key
+    held_keys: HashMap<u64, usize>,
+    /// Keys of in-flight and deferred messages by message ID
+    active: HashMap<MessageId, u64>,
+    /// Keys with a message in flight or deferred
+    busy: HashSet<u64>,
+}
+
+impl OrderedDelivery {
+    /// Number of messages held back
+    pub fn held_count(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Bytes of the messages held back
+    pub fn held_bytes(&self) -> usize {
+        self.held.iter().map(|(_, message)| message.size()).sum()
+    }
+    
+    /// Whether no more messages should be taken from the topic
+    pub fn is_full(&self) -> bool {
+        self.held.len() >= MAX_HELD_MESSAGES
+    }
+
+    /// Take the oldest held message `client_id` may be given: the first
+    /// held message of its key, when the client owns the key and the key
+    /// has nothing in flight
+    pub fn take_held(&mut self, client_id: Uuid, clients: &[Uuid]) -> Option<Message> {
+        let mut seen = HashSet::new();
+        let index = self.held.iter().position(|(key, _)| {
+            seen.insert(*key) && !self.busy.contains(key) && key_owner(*key, clients) == Some(client_id)
+        })?;
+        let (key, message) = self.held.remove(index)?;
+        if let Some(count) = self.held_keys.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.held_keys.remove(&key);
+            }
+        }
+        self.start(key, &message);
+        Some(message)
+    }
+
+    /// Offer a message just taken from the topic to `client_id`, holding it
+    /// back when it has to wait for its key
+    pub fn offer(&mut self, message: Message, client_id: Uuid, clients: &[Uuid]) -> Option<Message> {
+        let key = ordering_key(&message);
+        let waiting = self.busy.contains(&key) || self.held_keys.contains_key(&key);
+        if waiting || key_owner(key, clients) != Some(client_id) {
+            self.hold(key, message, false);
+            return None;
+        }
+        self.start(key, &message);
+        Some(message)
+    }
+
+    fn hold(&mut self, key: u64, message: Message, front: bool) {
+        *self.held_keys.entry(key).or_default() += 1;
+        if front {
+            self.held.push_front((key, message));
+        } else {
+            self.held.push_back((key, message));
+        }
+    }
+
+    /// Mark a message's key busy until it is released
+    fn start(&mut self, key: u64, message: &Message) {
+        self.active.insert(message.id, key);
+        self.busy.insert(key);
+    }
+
+    /// Free the key of a message that left flight for good. Returns whether
+    /// the message was tracked.
+    pub fn release(&mut self, message_id: MessageId) -> bool {
+        match self.active.remove(&message_id) {
+            Some(key) => {
+                self.busy.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free the key of a message that is to be delivered again and put it
+    /// ahead of the later messages of its key. Gives the message back when
+    /// it was not tracked.
+    pub fn redeliver(&mut self, message: Message) -> Option<Message> {
+        let Some(key) = self.active.remove(&message.id) else {
+            return Some(message);
+        };
+        self.busy.remove(&key);
+        self.hold(key, message, true);
+        None
+    }
+
+    /// Take every held message, oldest first
+    pub fn drain_held(&mut self) -> Vec<Message> {
+        self.held_keys.clear();
+        self.held.drain(..).map(|(_, message)| message).collect()
+    }
+
+    /// Forget the keys of in-flight and deferred messages after they were
+    /// moved out of the queue
+    pub fn clear_active(&mut self) {
+        self.active.clear();
+        self.busy.clear();
+    }
+}
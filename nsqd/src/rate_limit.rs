@@ -0,0 +1,98 @@
+//! Publish rate limiting
+//!
+//! Token buckets refilled continuously at the configured rate, holding at
+//! most one second's allowance so an idle topic can absorb a short burst.
+
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+
+/// How fast a topic accepts published messages (0 = no limit)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub max_msgs_per_sec: u64,
+    pub max_bytes_per_sec: u64,
+}
+
+impl RateLimit {
+    /// Check whether any limit is set
+    pub fn is_enabled(&self) -> bool {
+        self.max_msgs_per_sec > 0 || self.max_bytes_per_sec > 0
+    }
+}
+
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.max_msgs_per_sec, self.max_bytes_per_sec) {
+            (0, 0) => write!(f, "no limit"),
+            (msgs, 0) => write!(f, "{} messages/s", msgs),
+            (0, bytes) => write!(f, "{} bytes/s", bytes),
+            (msgs, bytes) => write!(f, "{} messages/s and {} bytes/s", msgs, bytes),
+        }
+    }
+}
+
+/// Enforces a [`RateLimit`] on message and byte counts
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    messages: f64,
+    bytes: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            messages: limit.max_msgs_per_sec as f64,
+            bytes: limit.max_bytes_per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Replace the limit, starting with a full allowance
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        *self = Self::new(limit);
+    }
+
+    /// Take the allowance for `count` messages totalling `bytes`, or take
+    /// nothing and return false when it is not available. A batch larger than
+    /// a second's allowance is let through once the bucket is full, leaving
+    /// it in debt, so oversized MPUBs are slowed down rather than never
+    /// accepted.
+    pub fn try_acquire(&mut self, count: u64, bytes: u64) -> bool {
+        if !self.limit.is_enabled() {
+            return true;
+        }
+        self.refill();
+
+        let fits = |available: f64, rate: u64, wanted: u64| {
+            rate == 0 || available >= wanted as f64 || available >= rate as f64
+        };
+        if !fits(self.messages, self.limit.max_msgs_per_sec, count) || !fits(self.bytes, self.limit.max_bytes_per_sec, bytes) {
+            return false;
+        }
+        self.messages -= count as f64;
+        self.bytes -= bytes as f64;
+        true
+    }
+
+    /// Check whether a whole second's allowance is available again
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.messages >= self.limit.max_msgs_per_sec as f64 && self.bytes >= self.limit.max_bytes_per_sec as f64
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        let refill = |available: f64, rate: u64| (available + elapsed * rate as f64).min(rate as f64);
+        self.messages = refill(self.messages, self.limit.max_msgs_per_sec);
+        self.bytes = refill(self.bytes, self.limit.max_bytes_per_sec);
+    }
+}
@@ -0,0 +1,291 @@
+//! Topic replication to peer nsqd nodes
+//!
+//! With a replication factor of N, each topic created on this node is
+//! mirrored to N of the nsqd nodes lookupd knows about, picked by rendezvous
+//! hashing of the topic name so peers joining or leaving only move the topics
+//! they gain or lose. Published messages are queued and forwarded to the
+//! replicas in the background, so publishers never wait on peers. A
+//! reconciliation task refreshes the peer list and makes sure every replica
+//! has the topic and its channels. Messages a node receives from a peer are
+//! never forwarded again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use nsq_protocol::Message;
+use nsq_common::{Metrics, NsqError, Result};
+
+/// Most published batches waiting to be forwarded
+const QUEUE_CAPACITY: usize = 1024;
+/// Timeout for a single request to a peer or lookupd
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between peer list refreshes and checks that replicas have every topic
+pub const RECONCILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Messages published to a topic, to be copied to its replicas
+struct Batch {
+    topic: String,
+    messages: Vec<Message>,
+}
+
+/// Replication statistics
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicationStats {
+    pub replication_factor: usize,
+    /// HTTP addresses of the peers topics can be mirrored to
+    pub peers: Vec<String>,
+    /// Messages copied to a peer, counted once per peer
+    pub replicated_count: u64,
+    /// Requests to peers that failed
+    pub failed_count: u64,
+    /// Messages not copied because the forwarding queue was full
+    pub dropped_count: u64,
+    pub last_reconcile_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// An nsqd node as listed by lookupd's `/nodes`
+#[derive(Debug, Deserialize)]
+struct Node {
+    remote_address: String,
+    hostname: String,
+    broadcast_address: String,
+    http_port: u16,
+    #[serde(default)]
+    tombstoned: bool,
+}
+
+impl Node {
+    /// HTTP address of the node, using the address it connected to lookupd
+    /// from when it did not advertise one
+    fn http_address(&self) -> String {
+        let host = match self.broadcast_address.as_str() {
+            "" | "auto" => self.remote_address.rsplit_once(':').map(|(host, _)| host).unwrap_or(&self.remote_address),
+            address => address,
+        };
+        format!("{}:{}", host, self.http_port)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Nodes {
+    producers: Vec<Node>,
+}
+
+/// Mirrors topics and their messages to peer nodes
+pub struct Replicator {
+    factor: usize,
+    lookupd_http_addresses: Vec<String>,
+    /// Hostname and HTTP port this node registers with, to leave it out of
+    /// its own peers
+    identity: (String, u16),
+    peers: RwLock<Vec<String>>,
+    sender: mpsc::Sender<Batch>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Batch>>,
+    client: reqwest::Client,
+    stats: RwLock<ReplicationStats>,
+    metrics: Metrics,
+}
+
+impl Replicator {
+    /// Create a replicator mirroring each topic to `factor` peers found
+    /// through the given lookupd HTTP addresses
+    pub fn new(factor: usize, lookupd_http_addresses: Vec<String>, identity: (String, u16), metrics: Metrics) -> Result<Self> {
+        if factor > 0 && lookupd_http_addresses.is_empty() {
+            return Err(NsqError::Config("replication_factor requires lookupd_http_addresses to find peers".to_string()));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| NsqError::Config(e.to_string()))?;
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+        Ok(Self {
+            factor,
+            lookupd_http_addresses,
+            identity,
+            peers: RwLock::new(Vec::new()),
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            client,
+            stats: RwLock::new(ReplicationStats {
+                replication_factor: factor,
+                ..Default::default()
+            }),
+            metrics,
+        })
+    }
+
+    /// Check whether topics are mirrored to any peers
+    pub fn is_enabled(&self) -> bool {
+        self.factor > 0
+    }
+
+    /// Queue messages published to a topic for its replicas, dropping them
+    /// when the queue is full
+    pub fn replicate(&self, topic: &str, messages: Vec<Message>) {
+        let count = messages.len() as u64;
+        let batch = Batch { topic: topic.to_string(), messages };
+        if self.sender.try_send(batch).is_err() {
+            self.stats.write().dropped_count += count;
+            self.metrics.incr("replication.dropped", count);
+            tracing::warn!("Replication queue is full, not copying {} messages of topic {}", count, topic);
+        }
+    }
+
+    /// Forward queued messages until the process exits
+    pub async fn run(&self) {
+        let mut receiver = self.receiver.lock().await;
+        while let Some(batch) = receiver.recv().await {
+            let body = encode_batch(&batch.messages);
+            let count = batch.messages.len() as u64;
+            let sends = self.replicas(&batch.topic).into_iter()
+                .map(|peer| self.send_replicate(peer, &batch.topic, body.clone()));
+            for result in futures::future::join_all(sends).await {
+                if result.is_ok() {
+                    self.stats.write().replicated_count += count;
+                    self.metrics.incr("replication.sent", count);
+                }
+            }
+        }
+    }
+
+    /// Refresh the peer list and make sure the replicas of each topic have
+    /// it and its channels, given as topic names with their channel names
+    pub async fn reconcile(&self, topics: Vec<(String, Vec<String>)>) {
+        self.refresh_peers().await;
+        for (topic, channels) in &topics {
+            for peer in self.replicas(topic) {
+                if self.send_replicate(peer.clone(), topic, Bytes::new()).await.is_err() {
+                    continue;
+                }
+                for channel in channels {
+                    let request = self.client.post(format!("{}/channel/create", base_url(&peer)))
+                        .query(&[("topic", topic), ("channel", channel)]);
+                    if let Err(e) = Self::send(request).await {
+                        self.record_failure(&peer, &e);
+                    }
+                }
+            }
+        }
+        self.stats.write().last_reconcile_at = Some(chrono::Utc::now());
+    }
+
+    /// Send messages, or with an empty body just the topic, to a replica
+    async fn send_replicate(&self, peer: String, topic: &str, body: Bytes) -> Result<()> {
+        let request = self.client.post(format!("{}/replicate", base_url(&peer)))
+            .query(&[("topic", topic)])
+            .body(body);
+        let result = Self::send(request).await;
+        if let Err(e) = &result {
+            self.record_failure(&peer, e);
+        }
+        result
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> Result<()> {
+        request.send().await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| NsqError::Queue(e.to_string()))
+    }
+
+    fn record_failure(&self, peer: &str, error: &NsqError) {
+        self.stats.write().failed_count += 1;
+        self.metrics.incr("replication.failed", 1);
+        tracing::warn!("Failed to replicate to {}: {}", peer, error);
+    }
+
+    /// Replace the peer list with the nodes the lookupds know about, keeping
+    /// the old list if none could be reached
+    async fn refresh_peers(&self) {
+        let mut peers = Vec::new();
+        let mut reached = false;
+        for lookupd in &self.lookupd_http_addresses {
+            let result = async {
+                self.client.get(format!("{}/nodes", base_url(lookupd))).send().await?
+                    .error_for_status()?
+                    .json::<Nodes>().await
+            }.await;
+            match result {
+                Ok(nodes) => {
+                    reached = true;
+                    peers.extend(nodes.producers.iter()
+                        .filter(|node| !node.tombstoned && (node.hostname.clone(), node.http_port) != self.identity)
+                        .map(Node::http_address));
+                }
+                Err(e) => tracing::warn!("Failed to list nodes from lookupd {}: {}", lookupd, e),
+            }
+        }
+        if !reached {
+            return;
+        }
+
+        peers.sort();
+        peers.dedup();
+        if *self.peers.read() != peers {
+            tracing::info!("Replication peers: {:?}", peers);
+        }
+        *self.peers.write() = peers;
+    }
+
+    /// Peers a topic is mirrored to
+    pub fn replicas(&self, topic: &str) -> Vec<String> {
+        let mut peers: Vec<(u64, String)> = self.peers.read().iter()
+            .map(|peer| {
+                let mut hasher = DefaultHasher::new();
+                (topic, peer).hash(&mut hasher);
+                (hasher.finish(), peer.clone())
+            })
+            .collect();
+        peers.sort_by(|a, b| b.cmp(a));
+        peers.into_iter().take(self.factor).map(|(_, peer)| peer).collect()
+    }
+
+    /// Get replication statistics
+    pub fn stats(&self) -> ReplicationStats {
+        let mut stats = self.stats.read().clone();
+        stats.peers = self.peers.read().clone();
+        stats
+    }
+}
+
+/// URL of an HTTP address given with or without its scheme
+fn base_url(address: &str) -> String {
+    if address.starts_with("http://") || address.starts_with("https://") {
+        address.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", address)
+    }
+}
+
+/// Encode messages for `/replicate`: each as a 4-byte size followed by the
+/// message with its ID, timestamp, attempts and headers
+pub fn encode_batch(messages: &[Message]) -> Bytes {
+    let mut buf = BytesMut::new();
+    for message in messages {
+        let data = message.to_bytes_with_headers();
+        buf.put_u32(data.len() as u32);
+        buf.put_slice(&data);
+    }
+    buf.freeze()
+}
+
+/// Decode a `/replicate` body
+pub fn decode_batch(mut body: Bytes) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    while body.has_remaining() {
+        if body.remaining() < 4 {
+            return Err(NsqError::Validation("truncated message size".to_string()));
+        }
+        let size = body.get_u32() as usize;
+        if body.remaining() < size {
+            return Err(NsqError::Validation(format!("truncated message: expected {} bytes, got {}", size, body.remaining())));
+        }
+        messages.push(Message::from_bytes(body.split_to(size))?);
+    }
+    Ok(messages)
+}
@@ -2,41 +2,120 @@
 
 use std::sync::Arc;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
+use std::path::PathBuf;
 use uuid::Uuid;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use parking_lot::RwLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::interval;
-use tokio_util::codec::Framed;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, sleep, timeout, Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use futures::{SinkExt, StreamExt};
 use axum::{
-    extract::{Query, State},
+    extract::{Path, Query, Request, State},
     body::Bytes,
-    response::Json,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
+use base64::Engine;
 use bytes::Bytes as BytesCrate;
-use nsq_protocol::{NsqDecoder, Message};
-use nsq_common::{Metrics, Result, NsqError};
-use crate::config::NsqdConfig;
-use crate::topic::Topic;
-use crate::client::{Client, ClientInfo};
+use nsq_protocol::{decode_with_headers, HEARTBEAT, MAGIC_V2, validate_headers, Command, ErrorCode, MAX_HEADER_BLOCK_SIZE, CommandDecoder, CountingCodec, Frame, FrameType, Message, GuidFactory, MessageHeaders, MessageId, MAX_WORKER_ID, NsqEncoder, ZstdStream};
+use nsq_common::{AccessLogLayer, DiskQueue, Metrics, Result, NsqError, RestartPolicy, TaskSupervisor, DEAD_LETTER_SUFFIX, http_span, validate_channel_name, validate_message_size, validate_topic_name};
+use crate::config::{NsqdConfig, apply_reloadable, validate_reloadable};
+use crate::topic::{DeliverySettings, Topic};
+use crate::client::{Client, ClientInfo, ClientOutput, ClientState};
 use crate::stats::StatsCollector;
+use crate::channel::{Channel, ShadowConfig};
+use crate::lookupd::{LookupdNotifier, Registration, RegistrationAction};
+use crate::snapshot::{ChannelDepth, DepthCheck, DepthSnapshot};
+use crate::metadata::{ChannelMetadata, Metadata, TopicMetadata};
+use crate::replication::{self, Replicator, RECONCILE_INTERVAL};
+use crate::diagnostics;
+use crate::connection_limits::ConnectionLimits;
+use crate::alerts::{Alerter, AlertSample, ALERT_CHECK_INTERVAL};
+use crate::message_trace::{MessageTracer, TraceEvent, TraceEventKind, TraceFilter};
 use tower_http::cors::{CorsLayer, Any};
+use tower_http::trace::TraceLayer;
+
+/// Maximum size of a single disk queue file
+const DISK_QUEUE_FILE_SIZE: usize = 100 * 1024 * 1024;
+/// Bytes of ID, timestamp and attempts stored ahead of each message body
+const MESSAGE_HEADER_SIZE: usize = 26;
+/// Interval between checks while waiting for shutdown to make progress
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Time connections get to clean up after being closed
+const CLIENT_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Minimum time given to deliver lookupd deregistrations
+const LOOKUPD_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time background tasks get to return after shutdown begins
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often topic retention policies are applied
+const RETENTION_INTERVAL: Duration = Duration::from_secs(5);
+/// How often channels are checked against `channel_idle_timeout`
+const IDLE_CHANNEL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often memory held by topics is checked against `max_memory_size`
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Messages returned by /peek unless `count` is given
+const DEFAULT_PEEK_COUNT: usize = 10;
+/// Most messages a single /peek returns
+const MAX_PEEK_COUNT: usize = 100;
+/// Interval between checks while a blocking HTTP publish waits for capacity
+const CAPACITY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Seconds a refused HTTP publisher is told to wait before retrying
+const FULL_TOPIC_RETRY_AFTER: &str = "1";
+/// Prefix of HTTP request headers published as message headers
+const HTTP_MESSAGE_HEADER_PREFIX: &str = "x-nsq-header-";
+
+/// How far a shutdown has progressed, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ShutdownPhase {
+    /// Accepting connections and delivering messages
+    Running,
+    /// No new connections or deliveries; clients may still FIN, REQ and TOUCH
+    Draining,
+    /// Client connections are being closed
+    Closed,
+}
 
 /// NSQd server
 pub struct NsqdServer {
-    /// Server configuration
-    config: NsqdConfig,
+    /// Server configuration, partly reloadable at runtime
+    config: Arc<RwLock<NsqdConfig>>,
     /// Metrics collector
     metrics: Metrics,
     /// Statistics collector
     stats: Arc<StatsCollector>,
     /// Topics
-    topics: Arc<RwLock<HashMap<String, Arc<Topic>>>>,
+    topics: Arc<DashMap<String, Arc<Topic>>>,
     /// Clients
-    clients: Arc<RwLock<HashMap<Uuid, Arc<Client>>>>,
+    clients: Arc<DashMap<Uuid, Arc<Client>>>,
+    /// Lookupd registration notifier
+    lookupd: LookupdNotifier,
+    /// Mirrors topics to peer nodes
+    replicator: Arc<Replicator>,
+    /// Background task supervisor
+    supervisor: TaskSupervisor,
+    /// Limits on TCP connections and their commands
+    connection_limits: Arc<ConnectionLimits>,
+    /// Logs HTTP requests when enabled
+    access_log: AccessLogLayer,
+    /// Events of messages on traced topics
+    tracer: Arc<MessageTracer>,
+    /// Generates the IDs of messages published to this node
+    guids: Arc<GuidFactory>,
+    /// Threshold alerts sent to a webhook
+    alerter: Arc<Alerter>,
+    /// Comparison of recovered depths with the last shutdown snapshot
+    depth_check: Arc<RwLock<Option<DepthCheck>>>,
+    /// Shutdown progress, watched by listeners and connections
+    phase: Arc<watch::Sender<ShutdownPhase>>,
     /// TCP listener
     tcp_listener: Option<TcpListener>,
     /// HTTP listener
@@ -50,86 +129,319 @@ impl NsqdServer {
     pub fn new(config: NsqdConfig) -> Result<Self> {
         // Initialize metrics
         let metrics = Metrics::new(&config.base)?;
+        let access_log = AccessLogLayer::new(&config.base)?;
         
         // Initialize statistics collector
-        let stats = Arc::new(StatsCollector::new(metrics.clone()));
+        let stats = Arc::new(StatsCollector::new(metrics.clone(), config.e2e_processing_latency_percentile.clone()));
+        
+        let identity = Self::lookupd_identity(&config);
+        let node_id = config.node_id.unwrap_or_else(|| Self::default_node_id(&identity));
+        let guids = Arc::new(GuidFactory::new(0));
+        guids.set_worker_id(node_id).map_err(|e| NsqError::Config(format!("Invalid node_id: {}", e)))?;
+        tracing::info!("Stamping message IDs with node ID {}", node_id);
+        let node = (
+            identity["hostname"].as_str().unwrap_or_default().to_string(),
+            identity["http_port"].as_u64().unwrap_or_default() as u16,
+        );
+        let alerter = Arc::new(Alerter::new(&config, format!("{}:{}", node.0, node.1), metrics.clone())?);
+        let replicator = Arc::new(Replicator::new(config.replication_factor, config.lookupd_http_addresses.clone(), node, metrics.clone())?);
+        let lookupd = LookupdNotifier::new(&config.lookupd_tcp_addresses, identity, metrics.clone());
+        let supervisor = TaskSupervisor::new(metrics.clone());
+        let connection_limits = Arc::new(ConnectionLimits::new(
+            config.max_connections,
+            config.max_connection_rate,
+            config.max_command_rate,
+        ));
         
         Ok(Self {
-            config,
+            config: Arc::new(RwLock::new(config)),
             metrics,
             stats,
-            topics: Arc::new(RwLock::new(HashMap::new())),
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            topics: Arc::new(DashMap::new()),
+            clients: Arc::new(DashMap::new()),
+            lookupd,
+            replicator,
+            supervisor,
+            connection_limits,
+            access_log,
+            tracer: Arc::new(MessageTracer::default()),
+            guids,
+            alerter,
+            depth_check: Arc::new(RwLock::new(None)),
+            phase: Arc::new(watch::Sender::new(ShutdownPhase::Running)),
             tcp_listener: None,
             http_listener: None,
             https_listener: None,
         })
     }
     
+    /// Producer details sent to lookupd when connecting
+    fn lookupd_identity(config: &NsqdConfig) -> serde_json::Value {
+        let port = |address: &str| address.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok());
+        serde_json::json!({
+            "broadcast_address": config.broadcast_address,
+            "hostname": std::env::var("HOSTNAME").unwrap_or_else(|_| config.broadcast_address.clone()),
+            "tcp_port": port(&config.tcp_address),
+            "http_port": port(&config.http_address),
+            "version": env!("CARGO_PKG_VERSION"),
+            "zone": config.zone,
+            "region": config.region,
+        })
+    }
+    
+    /// Node ID from a hash of the hostname and TCP port, so nodes sharing a
+    /// host get different message IDs
+    fn default_node_id(identity: &serde_json::Value) -> u16 {
+        let node = format!("{}:{}", identity["hostname"].as_str().unwrap_or_default(), identity["tcp_port"]);
+        let hash = node.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3));
+        (hash % (MAX_WORKER_ID as u64 + 1)) as u16
+    }
+    
+    /// New message with an ID from this node's GUID factory
+    fn new_message(&self, body: BytesCrate) -> Message {
+        Message::new(body).with_id(self.guids.next_id())
+    }
+    
+    /// Get a topic by name
+    fn get_topic(&self, name: &str) -> Option<Arc<Topic>> {
+        self.topics.get(name).map(|entry| entry.value().clone())
+    }
+    
+    /// Registered topics, copied out so no shard lock is held while they
+    /// are worked on
+    fn topic_list(&self) -> Vec<Arc<Topic>> {
+        snapshot(&self.topics)
+    }
+    
     /// Get or create topic by name
     fn get_or_create_topic(&self, name: String) -> Arc<Topic> {
-        if let Some(existing) = self.topics.read().get(&name).cloned() {
-            return existing;
-        }
-        let mut topics = self.topics.write();
-        if let Some(existing) = topics.get(&name).cloned() {
+        if let Some(existing) = self.get_topic(&name) {
             return existing;
         }
-        let disk_queue = None;
+        // Holds the lock of the topic's shard until it is registered, so
+        // concurrent publishers create it once
+        let entry = match self.topics.entry(name.clone()) {
+            Entry::Occupied(entry) => return entry.get().clone(),
+            Entry::Vacant(entry) => entry,
+        };
+        let disk_queue = match self.open_disk_queue(self.topic_data_path(&name)) {
+            Ok(disk_queue) => Some(disk_queue),
+            Err(e) => {
+                tracing::warn!("Failed to open disk queue for topic {}, keeping it in memory only: {}", name, e);
+                None
+            }
+        };
         let topic = Arc::new(Topic::new(
             name.clone(),
-            self.config.mem_queue_size,
+            self.config.read().mem_queue_size,
             disk_queue,
             self.metrics.clone(),
-        ).expect("create topic"));
-        topics.insert(name.clone(), topic.clone());
+        ).expect("create topic").with_default_durable(self.config.read().durable_publish));
+        entry.insert(topic.clone());
+        self.lookupd.notify(RegistrationAction::Register, &name, None);
         self.stats.add_topic(name, topic.clone());
         topic
     }
     
+    /// Directory holding a topic's disk queue, and those of its channels
+    fn topic_data_path(&self, name: &str) -> PathBuf {
+        self.config.read().data_path.join(name)
+    }
+    
+    /// Directory holding a channel's disk queue
+    fn channel_data_path(&self, topic_name: &str, channel_name: &str) -> PathBuf {
+        self.topic_data_path(topic_name).join("channels").join(channel_name)
+    }
+    
+    /// Open the disk queue in `path` that a topic or channel overflows to and
+    /// is flushed to on shutdown
+    fn open_disk_queue(&self, path: PathBuf) -> Result<DiskQueue> {
+        // Bounded by the body size rather than the reloadable message size,
+        // so messages accepted before a reload still fit
+        let config = self.config.read();
+        Ok(DiskQueue::new(
+            path,
+            DISK_QUEUE_FILE_SIZE,
+            config.max_body_size + MESSAGE_HEADER_SIZE + MAX_HEADER_BLOCK_SIZE,
+            Duration::from_millis(config.sync_timeout),
+        )?.with_sync_every(config.sync_every))
+    }
+    
     /// Delete a topic by name
     fn delete_topic(&self, name: &str) -> Result<()> {
-        if let Some(topic) = self.topics.write().remove(name) {
+        if let Some((_, topic)) = self.topics.remove(name) {
+            for channel in topic.get_channels() {
+                self.lookupd.notify(RegistrationAction::Unregister, name, Some(&channel.name));
+            }
             let _ = topic.delete();
+            self.lookupd.notify(RegistrationAction::Unregister, name, None);
             self.stats.remove_topic(name);
+            
+            match std::fs::remove_dir_all(self.topic_data_path(name)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to remove disk queue for topic {}: {}", name, e),
+            }
+        }
+        Ok(())
+    }
+    
+    /// Create a channel on a topic and hand it the messages waiting in the
+    /// topic's queue
+    fn create_channel(&self, topic: &Topic, channel_name: &str) -> Result<Arc<Channel>> {
+        self.create_channel_with_replay(topic, channel_name, false)
+    }
+    
+    /// Create a channel on a topic, first replaying into it the messages the
+    /// topic's disk queue already handed out when `replay` is set, then
+    /// handing it the messages waiting in the topic's queue
+    fn create_channel_with_replay(&self, topic: &Topic, channel_name: &str, replay: bool) -> Result<Arc<Channel>> {
+        // Taken before the channel exists, so nothing handed to it afterwards
+        // is replayed as well
+        let backlog = match replay {
+            true => topic.replay().unwrap_or_else(|e| {
+                tracing::warn!("Failed to replay backlog into {}/{}: {}", topic.name, channel_name, e);
+                None
+            }),
+            false => None,
+        };
+        let channel = self.add_channel(topic, channel_name)?;
+        if let Some(backlog) = backlog {
+            let bytes = channel.start_replay(backlog);
+            if bytes > 0 {
+                tracing::info!("Replaying {} bytes of backlog into {}/{}", bytes, topic.name, channel_name);
+            }
+        }
+        if let Err(e) = topic.pump() {
+            tracing::warn!("Failed to hand queued messages of topic {} to its channels: {}", topic.name, e);
+        }
+        Ok(channel)
+    }
+    
+    /// Create a channel on a topic with its disk queue, leaving the messages
+    /// waiting in the topic's queue where they are
+    fn add_channel(&self, topic: &Topic, channel_name: &str) -> Result<Arc<Channel>> {
+        validate_channel_name(channel_name)?;
+        let disk_queue = match self.open_disk_queue(self.channel_data_path(&topic.name, channel_name)) {
+            Ok(disk_queue) => Some(disk_queue),
+            Err(e) => {
+                tracing::warn!("Failed to open disk queue for channel {}/{}, keeping it in memory only: {}", topic.name, channel_name, e);
+                None
+            }
+        };
+        let channel = topic.add_channel(channel_name.to_string(), disk_queue)?;
+        self.lookupd.notify(RegistrationAction::Register, &topic.name, Some(channel_name));
+        Ok(channel)
+    }
+    
+    /// Delete a channel from a topic, along with its disk queue
+    fn delete_channel(&self, topic: &Topic, channel_name: &str) -> Result<()> {
+        topic.remove_channel(channel_name)?;
+        self.lookupd.notify(RegistrationAction::Unregister, &topic.name, Some(channel_name));
+        
+        match std::fs::remove_dir_all(self.channel_data_path(&topic.name, channel_name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("Failed to remove disk queue for channel {}/{}: {}", topic.name, channel_name, e),
         }
         Ok(())
     }
     
+    /// Counters of every channel, and of topics without channels, for alerts
+    fn alert_samples(&self) -> Vec<AlertSample> {
+        let mut samples = Vec::new();
+        for topic in self.topic_list() {
+            let channels = topic.get_channels();
+            if channels.is_empty() {
+                samples.push(AlertSample {
+                    topic: topic.name.clone(),
+                    channel: None,
+                    depth: topic.depth() as u64,
+                    requeue_count: 0,
+                    client_count: 0,
+                });
+            }
+            for channel in channels {
+                let stats = channel.stats();
+                samples.push(AlertSample {
+                    topic: topic.name.clone(),
+                    channel: Some(channel.name.clone()),
+                    depth: stats.depth,
+                    requeue_count: stats.requeue_count,
+                    client_count: stats.client_count,
+                });
+            }
+        }
+        samples
+    }
+    
+    /// Delete the channels that stayed idle for `channel_idle_timeout`
+    fn delete_idle_channels(&self) {
+        let timeout = self.config.read().channel_idle_timeout;
+        if timeout == 0 {
+            return;
+        }
+        let timeout = Duration::from_millis(timeout);
+        let mut deleted = 0;
+        for topic in self.sorted_topics() {
+            for channel in topic.get_channels() {
+                if !channel.is_idle(timeout) {
+                    continue;
+                }
+                match self.delete_channel(&topic, &channel.name) {
+                    Ok(()) => {
+                        tracing::info!("Deleted channel {}/{} after {}ms idle", topic.name, channel.name, timeout.as_millis());
+                        deleted += 1;
+                    }
+                    Err(e) => tracing::warn!("Failed to delete idle channel {}/{}: {}", topic.name, channel.name, e),
+                }
+            }
+        }
+        if deleted > 0 {
+            self.metrics.incr("channels.idle_deleted", deleted);
+            self.persist_metadata();
+        }
+    }
+    
     /// Start the server
     pub async fn start(&mut self) -> Result<()> {
         tracing::info!("Starting NSQd server");
+        let config = self.config.read().clone();
         
         // Start TCP server
-        if let Some(tcp_addr) = self.parse_address(&self.config.tcp_address)? {
+        if let Some(tcp_addr) = self.parse_address(&config.tcp_address)? {
             let listener = TcpListener::bind(tcp_addr).await
-                .map_err(|e| NsqError::Io(e))?;
+                .map_err(NsqError::Io)?;
             self.tcp_listener = Some(listener);
             tracing::info!("TCP server listening on {}", tcp_addr);
         }
         
         // Start HTTP server
-        if !self.config.disable_http {
-            if let Some(http_addr) = self.parse_address(&self.config.http_address)? {
+        if !config.disable_http {
+            if let Some(http_addr) = self.parse_address(&config.http_address)? {
                 let listener = TcpListener::bind(http_addr).await
-                    .map_err(|e| NsqError::Io(e))?;
+                    .map_err(NsqError::Io)?;
                 self.http_listener = Some(listener);
                 tracing::info!("HTTP server listening on {}", http_addr);
             }
         }
         
         // Start HTTPS server
-        if !self.config.disable_https {
-            if let Some(https_addr) = self.parse_address(&self.config.https_address.as_ref().unwrap_or(&"".to_string()))? {
+        if !config.disable_https {
+            if let Some(https_addr) = self.parse_address(config.https_address.as_ref().unwrap_or(&"".to_string()))? {
                 let listener = TcpListener::bind(https_addr).await
-                    .map_err(|e| NsqError::Io(e))?;
+                    .map_err(NsqError::Io)?;
                 self.https_listener = Some(listener);
                 tracing::info!("HTTPS server listening on {}", https_addr);
             }
         }
         
+        self.restore_metadata();
+        self.check_depth_snapshot();
+        
         // Start background tasks
         self.start_background_tasks().await;
+        self.lookupd.start(&self.supervisor);
         
         // Start TCP server
         if let Some(listener) = self.tcp_listener.take() {
@@ -165,6 +477,270 @@ impl NsqdServer {
         Ok(())
     }
     
+    /// Stop the server cleanly: stop accepting connections and deliveries,
+    /// give consumers up to the drain timeout to finish in-flight messages,
+    /// close connections, deregister from lookupd and write every pending
+    /// message and the topic metadata to disk
+    pub async fn shutdown(&self) -> Result<()> {
+        tracing::info!("Shutting down NSQd server");
+        let deadline = Instant::now() + Duration::from_millis(self.config.read().drain_timeout);
+        
+        self.phase.send_replace(ShutdownPhase::Draining);
+        self.unregister_all();
+        
+        while self.in_flight_count() > 0 && Instant::now() < deadline {
+            sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+        let in_flight = self.in_flight_count();
+        if in_flight > 0 {
+            tracing::warn!("{} messages still in flight after the drain timeout, keeping them for redelivery", in_flight);
+        }
+        
+        self.close_clients().await;
+        
+        let lookupd_timeout = deadline.saturating_duration_since(Instant::now()).max(LOOKUPD_FLUSH_TIMEOUT);
+        if !self.lookupd.flush(lookupd_timeout).await {
+            tracing::warn!("Timed out deregistering from lookupd");
+        }
+        self.supervisor.shutdown(TASK_SHUTDOWN_TIMEOUT).await;
+        
+        self.flush_topics();
+        self.save_metadata()?;
+        
+        let snapshot = DepthSnapshot::new(self.channel_depths());
+        snapshot.save(&self.config.read().data_path)?;
+        tracing::info!("Recorded depths of {} channels", snapshot.channels.len());
+        Ok(())
+    }
+    
+    /// Apply the reloadable settings of a freshly loaded configuration.
+    /// Nothing changes if the result is invalid.
+    pub fn reload_config(&self, reloaded: &NsqdConfig) -> Result<()> {
+        let mut config = self.config.read().clone();
+        apply_reloadable(reloaded, &mut config);
+        if let Err(e) = validate_reloadable(&config) {
+            self.metrics.incr("config.reload_errors", 1);
+            return Err(e);
+        }
+        
+        let previous = std::mem::replace(&mut *self.config.write(), config.clone());
+        if config.base.log_level != previous.base.log_level {
+            nsq_common::set_log_level(&config.base.log_level)?;
+        }
+        if config.lookupd_tcp_addresses != previous.lookupd_tcp_addresses {
+            self.lookupd.set_addresses(&config.lookupd_tcp_addresses, &self.registrations(), &self.supervisor);
+        }
+        
+        self.metrics.incr("config.reloads", 1);
+        Ok(())
+    }
+    
+    /// Every topic and channel this node registers with lookupd
+    fn registrations(&self) -> Vec<Registration> {
+        let mut registrations = Vec::new();
+        for topic in &self.topic_list() {
+            registrations.push(Registration { topic: topic.name.clone(), channel: None });
+            for channel in topic.get_channels() {
+                registrations.push(Registration { topic: topic.name.clone(), channel: Some(channel.name.clone()) });
+            }
+        }
+        registrations
+    }
+    
+    /// Check whether shutdown has begun
+    fn is_shutting_down(&self) -> bool {
+        *self.phase.borrow() != ShutdownPhase::Running
+    }
+    
+    /// Resolve once shutdown reaches the given phase
+    async fn wait_for_phase(&self, phase: ShutdownPhase) {
+        let mut receiver = self.phase.subscribe();
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+    
+    /// Messages delivered to clients and not yet finished, across all topics
+    fn in_flight_count(&self) -> usize {
+        self.topics.iter().map(|entry| entry.in_flight_count()).sum()
+    }
+    
+    /// Withdraw every topic and channel from lookupd so consumers stop
+    /// connecting to this node
+    fn unregister_all(&self) {
+        for topic in &self.topic_list() {
+            for channel in topic.get_channels() {
+                self.lookupd.notify(RegistrationAction::Unregister, &topic.name, Some(&channel.name));
+            }
+            self.lookupd.notify(RegistrationAction::Unregister, &topic.name, None);
+        }
+    }
+    
+    /// Close all client connections and wait briefly for them to requeue
+    /// what they still hold
+    async fn close_clients(&self) {
+        let clients = snapshot(&self.clients);
+        self.phase.send_replace(ShutdownPhase::Closed);
+        for client in &clients {
+            client.close();
+        }
+        
+        let deadline = Instant::now() + CLIENT_CLOSE_TIMEOUT;
+        while !self.clients.is_empty() && Instant::now() < deadline {
+            sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+        tracing::info!("Closed {} client connections", clients.len());
+    }
+    
+    /// Write queued, in-flight and deferred messages of every topic to disk
+    fn flush_topics(&self) {
+        let topics = self.topic_list();
+        let mut flushed = 0;
+        for topic in &topics {
+            match topic.flush() {
+                Ok(count) => flushed += count,
+                Err(e) => tracing::error!("Failed to flush topic {} to disk: {}", topic.name, e),
+            }
+        }
+        tracing::info!("Flushed {} messages from {} topics to disk", flushed, topics.len());
+    }
+    
+    /// Record topics and channels so the next start recreates them
+    fn save_metadata(&self) -> Result<()> {
+        let mut topics: Vec<TopicMetadata> = self.topic_list().iter()
+            .map(|topic| {
+                let mut channels: Vec<ChannelMetadata> = topic.get_channels()
+                    .iter()
+                    .map(|channel| ChannelMetadata {
+                        name: channel.name.clone(),
+                        paused: channel.is_paused(),
+                        delivery: channel.delivery_settings(),
+                        ordered: channel.is_ordered(),
+                    })
+                    .collect();
+                channels.sort_by(|a, b| a.name.cmp(&b.name));
+                TopicMetadata {
+                    name: topic.name.clone(),
+                    paused: topic.is_paused(),
+                    retention: topic.retention(),
+                    delivery: topic.delivery_settings(),
+                    mem_queue_size: topic.mem_queue_size(),
+                    durable: topic.durable(),
+                    rate_limit: topic.rate_limit(),
+                    replica: topic.is_replica(),
+                    channels,
+                }
+            })
+            .collect();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        Metadata::new(topics).save(&self.config.read().data_path)
+    }
+    
+    /// Save the metadata after a pause, unpause or configuration change, so the new state survives
+    /// a crash as well as a clean restart
+    fn persist_metadata(&self) {
+        if let Err(e) = self.save_metadata() {
+            tracing::warn!("Failed to persist topic metadata: {}", e);
+        }
+    }
+    
+    /// Recreate topics and channels recorded at the last shutdown, reopening
+    /// their disk queues
+    fn restore_metadata(&self) {
+        let data_path = self.config.read().data_path.clone();
+        let metadata = match Metadata::load(&data_path) {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Failed to read topic metadata: {}", e);
+                return;
+            }
+        };
+        
+        for topic_metadata in &metadata.topics {
+            if let Err(e) = validate_topic_name(&topic_metadata.name) {
+                tracing::warn!("Skipping invalid topic {} in metadata: {}", topic_metadata.name, e);
+                continue;
+            }
+            let topic = self.get_or_create_topic(topic_metadata.name.clone());
+            for channel_metadata in &topic_metadata.channels {
+                match self.add_channel(&topic, &channel_metadata.name) {
+                    Ok(channel) => {
+                        if channel_metadata.paused {
+                            let _ = channel.pause();
+                        }
+                        channel.set_delivery_settings(channel_metadata.delivery);
+                        channel.set_ordered(channel_metadata.ordered);
+                    }
+                    Err(e) => tracing::warn!("Failed to restore channel {}/{}: {}", topic.name, channel_metadata.name, e),
+                }
+            }
+            if topic_metadata.paused {
+                let _ = topic.pause();
+            }
+            topic.set_retention(topic_metadata.retention);
+            topic.set_delivery_settings(topic_metadata.delivery);
+            if topic_metadata.mem_queue_size.is_some() {
+                topic.set_mem_queue_size(topic_metadata.mem_queue_size);
+            }
+            topic.set_durable(topic_metadata.durable);
+            topic.set_rate_limit(topic_metadata.rate_limit);
+            topic.set_replica(topic_metadata.replica);
+            // Every channel is back before the topic's queue is handed out
+            if let Err(e) = topic.pump() {
+                tracing::warn!("Failed to hand queued messages of topic {} to its channels: {}", topic.name, e);
+            }
+        }
+        tracing::info!("Restored {} topics from metadata", metadata.topics.len());
+    }
+    
+    /// Pending messages per channel, counting queued, on-disk, in-flight and deferred ones
+    fn channel_depths(&self) -> Vec<ChannelDepth> {
+        let topics = self.topic_list();
+        topics
+            .iter()
+            .flat_map(|topic| {
+                topic.get_channels().into_iter().map(|channel| ChannelDepth {
+                    topic: topic.name.clone(),
+                    channel: channel.name.clone(),
+                    depth: (channel.depth() + channel.in_flight_count() + channel.deferred_count()) as u64
+                        + channel.backend_depth(),
+                })
+            })
+            .collect()
+    }
+    
+    /// Compare recovered channel depths with the snapshot from the last clean shutdown
+    fn check_depth_snapshot(&self) {
+        let data_path = self.config.read().data_path.clone();
+        let snapshot = match DepthSnapshot::take(&data_path) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => {
+                tracing::info!("No depth snapshot found, skipping durability check");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to read depth snapshot: {}", e);
+                return;
+            }
+        };
+        
+        let check = snapshot.compare(&self.channel_depths());
+        for discrepancy in &check.discrepancies {
+            tracing::warn!(
+                "Channel {}/{} recovered {} messages, expected {} from the shutdown snapshot",
+                discrepancy.topic, discrepancy.channel, discrepancy.recovered, discrepancy.expected
+            );
+        }
+        if check.discrepancies.is_empty() {
+            tracing::info!("Recovered depths match the shutdown snapshot for {} channels", check.channels_checked);
+        } else {
+            tracing::warn!("Durability check: {} messages lost, {} duplicated since shutdown", check.lost, check.duplicated);
+        }
+        
+        self.metrics.incr("durability.messages_lost", check.lost);
+        self.metrics.incr("durability.messages_duplicated", check.duplicated);
+        *self.depth_check.write() = Some(check);
+    }
+    
     /// Parse address string
     fn parse_address(&self, addr: &str) -> Result<Option<SocketAddr>> {
         if addr.is_empty() {
@@ -182,46 +758,241 @@ impl NsqdServer {
         Ok(Some(socket_addr))
     }
     
+    /// Start forwarding published messages to replicas and reconciling
+    /// replicas with the local topics
+    fn start_replication(&self) {
+        let replicator = self.replicator.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("replication", RestartPolicy::Always, move || {
+            let replicator = replicator.clone();
+            let stop = stop.clone();
+            async move {
+                tokio::select! {
+                    _ = replicator.run() => {}
+                    _ = stop.stopped() => {}
+                }
+                Ok(())
+            }
+        });
+        
+        let topics = self.topics.clone();
+        let replicator = self.replicator.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("replication_reconcile", RestartPolicy::Always, move || {
+            let topics = topics.clone();
+            let replicator = replicator.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(RECONCILE_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
+                    }
+                    
+                    let local: Vec<(String, Vec<String>)> = snapshot(&topics).iter()
+                        .filter(|topic| !topic.is_replica())
+                        .map(|topic| (topic.name.clone(), topic.get_channels().iter().map(|channel| channel.name.clone()).collect()))
+                        .collect();
+                    tokio::select! {
+                        _ = replicator.reconcile(local) => {}
+                        _ = stop.stopped() => return Ok(()),
+                    }
+                }
+            }
+        });
+    }
+    
     /// Start background tasks
     async fn start_background_tasks(&self) {
         // Message processing task
         let topics = self.topics.clone();
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_millis(100));
-            loop {
-                interval.tick().await;
-                
-                let topics = topics.read();
-                for topic in topics.values() {
-                    if let Err(e) = topic.process_deferred() {
-                        tracing::warn!("Failed to process deferred messages for topic {}: {}", topic.name, e);
+        let timeout_clients = self.clients.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("process_deferred", RestartPolicy::Always, move || {
+            let topics = topics.clone();
+            let timeout_clients = timeout_clients.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(Duration::from_millis(100));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
+                    }
+                    
+                    for topic in snapshot(&topics) {
+                        if let Err(e) = topic.process_deferred() {
+                            tracing::warn!("Failed to process deferred messages for topic {}: {}", topic.name, e);
+                        }
+                        
+                        match topic.cleanup_timeouts() {
+                            Ok(expired) => {
+                                for in_flight_msg in expired {
+                                    if let Some(client) = timeout_clients.get(&in_flight_msg.client_id) {
+                                        client.timeout_in_flight(in_flight_msg.message.id);
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::warn!("Failed to cleanup timeouts for topic {}: {}", topic.name, e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        
+        // Retention task
+        let topics = self.topics.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("retention", RestartPolicy::Always, move || {
+            let topics = topics.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(RETENTION_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
+                    }
+                    
+                    let topics: Vec<Arc<Topic>> = snapshot(&topics).into_iter()
+                        .filter(|topic| topic.retention().is_enabled())
+                        .collect();
+                    for topic in topics {
+                        match topic.prune() {
+                            Ok(0) => {}
+                            Ok(pruned) => tracing::info!("Pruned {} messages from topic {}", pruned, topic.name),
+                            Err(e) => tracing::warn!("Failed to apply retention to topic {}: {}", topic.name, e),
+                        }
+                    }
+                }
+            }
+        });
+        
+        // Alerting task
+        if self.alerter.is_enabled() {
+            let server = self.clone();
+            let stop = self.supervisor.clone();
+            self.supervisor.spawn("alerts", RestartPolicy::Always, move || {
+                let server = server.clone();
+                let stop = stop.clone();
+                async move {
+                    let mut interval = interval(ALERT_CHECK_INTERVAL);
+                    loop {
+                        tokio::select! {
+                            _ = interval.tick() => {}
+                            _ = stop.stopped() => return Ok(()),
+                        }
+                        
+                        let alerts = server.alerter.evaluate(&server.alert_samples(), std::time::Instant::now());
+                        server.alerter.notify(alerts).await;
+                    }
+                }
+            });
+        }
+        
+        // Idle channel cleanup task; the timeout is read on every check so a
+        // reload applies
+        let server = self.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("idle_channels", RestartPolicy::Always, move || {
+            let server = server.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(IDLE_CHANNEL_CHECK_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
+                    }
+                    server.delete_idle_channels();
+                }
+            }
+        });
+        
+        // Disk maintenance task, so writes to a topic that went quiet are
+        // fsynced and consumed files are removed promptly
+        let topics = self.topics.clone();
+        let stop = self.supervisor.clone();
+        let sync_timeout = Duration::from_millis(self.config.read().sync_timeout.max(1));
+        self.supervisor.spawn("disk_maintenance", RestartPolicy::Always, move || {
+            let topics = topics.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(sync_timeout);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
                     }
                     
-                    if let Err(e) = topic.cleanup_timeouts() {
-                        tracing::warn!("Failed to cleanup timeouts for topic {}: {}", topic.name, e);
+                    for topic in snapshot(&topics) {
+                        if let Err(e) = topic.sync_if_due() {
+                            tracing::warn!("Failed to sync disk queue of topic {}: {}", topic.name, e);
+                        }
+                        match topic.compact() {
+                            Ok(0) => {}
+                            Ok(freed) => tracing::debug!("Compacted disk queue of topic {}, freeing {} bytes", topic.name, freed),
+                            Err(e) => tracing::warn!("Failed to compact disk queue of topic {}: {}", topic.name, e),
+                        }
                     }
                 }
             }
         });
         
+        // Memory budget task
+        let max_memory_size = self.config.read().max_memory_size;
+        if max_memory_size > 0 {
+            let topics = self.topics.clone();
+            let stop = self.supervisor.clone();
+            self.supervisor.spawn("memory_budget", RestartPolicy::Always, move || {
+                let topics = topics.clone();
+                let stop = stop.clone();
+                async move {
+                    let mut interval = interval(MEMORY_CHECK_INTERVAL);
+                    loop {
+                        tokio::select! {
+                            _ = interval.tick() => {}
+                            _ = stop.stopped() => return Ok(()),
+                        }
+                        
+                        let topics = snapshot(&topics);
+                        Self::enforce_memory_budget(&topics, max_memory_size);
+                    }
+                }
+            });
+        }
+        
+        if self.replicator.is_enabled() {
+            self.start_replication();
+        }
+        
         // Client cleanup task
         let clients = self.clients.clone();
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                
-                let mut clients = clients.write();
-                let timed_out_clients: Vec<Uuid> = clients
-                    .iter()
-                    .filter(|(_, client)| client.is_timed_out())
-                    .map(|(id, _)| *id)
-                    .collect();
-                
-                for client_id in timed_out_clients {
-                    if let Some(_client) = clients.remove(&client_id) {
-                        tracing::info!("Client {} timed out", client_id);
+        let connection_limits = self.connection_limits.clone();
+        let stop = self.supervisor.clone();
+        self.supervisor.spawn("client_cleanup", RestartPolicy::Always, move || {
+            let clients = clients.clone();
+            let connection_limits = connection_limits.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = interval(Duration::from_secs(30));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.stopped() => return Ok(()),
                     }
+                    
+                    clients.retain(|client_id, client| {
+                        if client.is_timed_out() {
+                            tracing::info!("Client {} timed out", client_id);
+                            return false;
+                        }
+                        true
+                    });
+                    connection_limits.prune();
                 }
             }
         });
@@ -230,10 +1001,25 @@ impl NsqdServer {
     /// Handle TCP connections
     async fn handle_tcp_connections(&self, listener: TcpListener) -> Result<()> {
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.wait_for_phase(ShutdownPhase::Draining) => {
+                    tracing::info!("TCP listener stopped");
+                    return Ok(());
+                }
+            };
+            match accepted {
                 Ok((stream, addr)) => {
+                    let slot = match self.connection_limits.admit(addr.ip()) {
+                        Ok(slot) => slot,
+                        Err(reason) => {
+                            tokio::spawn(Self::reject_connection(stream, addr, reason, self.metrics.clone()));
+                            continue;
+                        }
+                    };
                     let server = self.clone();
                     tokio::spawn(async move {
+                        let _slot = slot;
                         if let Err(e) = server.handle_tcp_connection(stream, addr).await {
                             tracing::error!("TCP connection error: {}", e);
                         }
@@ -246,12 +1032,24 @@ impl NsqdServer {
         }
     }
     
+    /// Send a connection refused by a limit an error frame and close it
+    async fn reject_connection(mut stream: TcpStream, addr: SocketAddr, reason: String, metrics: Metrics) {
+        tracing::warn!("Rejected TCP connection from {}: {}", addr, reason);
+        metrics.incr("connections.rejected", 1);
+        let error = format!("{} {}", ErrorCode::TooManyConnections, reason);
+        let _ = stream.write_all(&Frame::new(FrameType::Error, BytesCrate::from(error)).to_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+    
     /// Handle HTTP connections
     async fn handle_http_connections(&self, listener: TcpListener) -> Result<()> {
         let app = self.create_http_router();
         
-        axum::serve(listener, app).await
-            .map_err(|e| NsqError::Io(e))?;
+        let server = self.clone();
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move { server.wait_for_phase(ShutdownPhase::Draining).await })
+            .await
+            .map_err(NsqError::Io)?;
         
         Ok(())
     }
@@ -264,47 +1062,625 @@ impl NsqdServer {
     }
     
     /// Handle individual TCP connection
-    async fn handle_tcp_connection(&self, stream: TcpStream, addr: SocketAddr) -> Result<()> {
-        let framed = Framed::new(stream, NsqDecoder::new());
+    async fn handle_tcp_connection(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        if !self.accept_protocol_magic(&mut stream, addr).await? {
+            return Ok(());
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel::<ClientOutput>();
+        
+        // Settings are read once, so a reload applies to new connections
+        let (max_body_size, msg_timeout, max_msg_timeout, max_heartbeat_interval, max_output_buffer_size, max_output_buffer_timeout) = {
+            let config = self.config.read();
+            (
+                config.max_body_size,
+                config.msg_timeout,
+                config.max_msg_timeout,
+                Duration::from_millis(config.max_heartbeat_interval),
+                config.max_output_buffer_size,
+                Duration::from_millis(config.max_output_buffer_timeout),
+            )
+        };
+        let defaults = ClientInfo::default();
         let client_info = ClientInfo {
             remote_addr: addr.to_string(),
-            ..Default::default()
+            msg_timeout: Duration::from_millis(msg_timeout),
+            max_msg_timeout: Duration::from_millis(max_msg_timeout),
+            heartbeat_interval: defaults.heartbeat_interval.min(max_heartbeat_interval),
+            max_heartbeat_interval,
+            output_buffer_size: defaults.output_buffer_size.min(max_output_buffer_size),
+            output_buffer_timeout: defaults.output_buffer_timeout.min(max_output_buffer_timeout),
+            max_output_buffer_size,
+            max_output_buffer_timeout,
+            ..defaults
+        };
+        
+        let client = Arc::new(Client::new(client_info, sender, self.metrics.clone()));
+        let client_id = client.id();
+        
+        // Count uncompressed protocol bytes for the client's stats
+        let (read_half, write_half) = stream.into_split();
+        let decoder = CountingCodec::new(CommandDecoder::with_max_body_size(max_body_size), client.codec_counters());
+        let mut reader = FramedRead::new(read_half, ZstdStream::new(decoder));
+        let mut writer = FramedWrite::new(write_half, ZstdStream::new(CountingCodec::new(NsqEncoder, client.codec_counters())));
+        
+        self.stats.add_client(client_id, client.clone());
+        self.clients.insert(client_id, client.clone());
+        
+        tracing::info!("New TCP connection from {}", addr);
+        
+        // Drain outbound frames until the client is closed. Messages queued
+        // before a RDY 0 or a close are returned to the channel unsent.
+        let server = self.clone();
+        let writer_client = client.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(output) = receiver.recv().await {
+                let result = match output {
+                    ClientOutput::Frame(frame) => writer.send(frame).await,
+                    ClientOutput::Message(message) if !writer_client.is_ready() => {
+                        server.return_message(&writer_client, message.id);
+                        continue;
+                    }
+                    ClientOutput::Message(message) => {
+                        // Clients that did not negotiate headers get the plain format
+                        let body = if writer_client.wants_message_headers() {
+                            message.to_bytes_with_headers()
+                        } else {
+                            message.to_bytes()
+                        };
+                        writer.send(Frame::new(FrameType::Message, body)).await
+                    }
+                    ClientOutput::EnableZstd(level) => writer.encoder_mut().enable_compression(level),
+                };
+                if let Err(e) = result {
+                    tracing::debug!("Failed to write to client: {}", e);
+                    break;
+                }
+            }
+        });
+        
+        // Handle client protocol
+        let result = self.handle_client_protocol(client.clone(), addr.ip(), &mut reader).await;
+        
+        // Cleanup
+        client.close();
+        let _ = writer_task.await;
+        if let Some(channel) = self.client_channel(&client) {
+            for message_id in client.in_flight_ids() {
+                if channel.requeue_message(message_id, Duration::ZERO).is_ok() {
+                    client.requeue_in_flight(message_id);
+                }
+            }
+            channel.remove_client(client_id);
+        }
+        self.clients.remove(&client_id);
+        self.stats.remove_client(&client_id);
+        
+        tracing::info!("TCP connection from {} closed", addr);
+        result
+    }
+    
+    /// Consume the protocol magic a connection starts with, returning false
+    /// when the connection was rejected or closed. With `allow_missing_magic`
+    /// a client that sends a command first is served as if it had sent the
+    /// magic, and its bytes are left for the command decoder.
+    async fn accept_protocol_magic(&self, stream: &mut TcpStream, addr: SocketAddr) -> Result<bool> {
+        let mut magic = [0u8; 4];
+        // A client gets as long as it may stay silent later on to send it
+        let deadline = Duration::from_millis(self.config.read().max_heartbeat_interval);
+        let peeked = timeout(deadline, async {
+            loop {
+                let len = stream.peek(&mut magic).await?;
+                // Wait for the rest of a magic split across writes
+                if len == 0 || len == magic.len() || !MAGIC_V2.starts_with(&magic[..len]) {
+                    return Ok::<_, std::io::Error>(len);
+                }
+                sleep(Duration::from_millis(1)).await;
+            }
+        }).await;
+        let len = match peeked {
+            Ok(len) => len?,
+            Err(_) => {
+                tracing::warn!("Closing TCP connection from {}: no protocol magic within {:?}", addr, deadline);
+                self.metrics.incr("protocol.magic_timeout", 1);
+                return Ok(false);
+            }
+        };
+        if len == 0 {
+            return Ok(false);
+        }
+        if &magic == MAGIC_V2 {
+            stream.read_exact(&mut magic).await?;
+            return Ok(true);
+        }
+        
+        // Commands never start with a space, so anything that does is a magic
+        // for another protocol version
+        if !magic.starts_with(b" ") && self.config.read().allow_missing_magic {
+            return Ok(true);
+        }
+        let error = if magic.starts_with(b" ") {
+            format!("E_BAD_PROTOCOL protocol version '{}' is not supported", String::from_utf8_lossy(&magic[..len]).trim())
+        } else {
+            "E_BAD_PROTOCOL client must send the protocol magic before any command".to_string()
+        };
+        tracing::warn!("Rejected TCP connection from {}: {}", addr, error);
+        self.metrics.incr("protocol.bad_magic", 1);
+        let _ = stream.write_all(&Frame::new(FrameType::Error, BytesCrate::from(error)).to_bytes()).await;
+        Ok(false)
+    }
+    
+    /// Handle client protocol
+    async fn handle_client_protocol(
+        &self,
+        client: Arc<Client>,
+        ip: IpAddr,
+        reader: &mut FramedRead<OwnedReadHalf, ZstdStream<CountingCodec<CommandDecoder>>>,
+    ) -> Result<()> {
+        let mut missed_heartbeats = 0;
+        loop {
+            // Heartbeats go out while the client is silent; one left
+            // unanswered for another interval closes the connection
+            let heartbeat_interval = client.info().heartbeat_interval;
+            let heartbeat = async {
+                if heartbeat_interval.is_zero() {
+                    std::future::pending::<()>().await;
+                }
+                sleep(heartbeat_interval).await;
+            };
+            let command = tokio::select! {
+                command = reader.next() => command,
+                _ = heartbeat => {
+                    if missed_heartbeats > 0 {
+                        tracing::warn!("Closing TCP connection from {}: no response to heartbeat", client.info().remote_addr);
+                        self.metrics.incr("connections.heartbeat_timeouts", 1);
+                        break;
+                    }
+                    missed_heartbeats += 1;
+                    if client.send_response(HEARTBEAT).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                _ = self.wait_for_phase(ShutdownPhase::Closed) => break,
+            };
+            missed_heartbeats = 0;
+            let Some(command) = command else {
+                break;
+            };
+            let command = match command {
+                Ok(command) => command,
+                Err(e) => {
+                    let _ = client.send_error(format!("{} {}", e.code(), e));
+                    break;
+                }
+            };
+            
+            if let Err(reason) = self.connection_limits.allow_command(ip) {
+                tracing::warn!("Closing TCP connection from {}: {}", client.info().remote_addr, reason);
+                self.metrics.incr("connections.throttled", 1);
+                let _ = client.send_error(format!("{} {}", ErrorCode::RateLimit, reason));
+                break;
+            }
+            client.record_command();
+            let identify = matches!(command, Command::Identify { .. });
+            let span = Self::command_span(&client, &command);
+            if !span.in_scope(|| self.handle_command(&client, command))? {
+                break;
+            }
+            
+            // The client compresses everything it sends after a negotiated IDENTIFY
+            if identify && client.info().zstd {
+                reader.decoder_mut().enable_decompression()?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Span covering one TCP command, tagged with the message it acts on
+    fn command_span(client: &Client, command: &Command) -> tracing::Span {
+        let message_id = match command {
+            Command::Fin { message_id } | Command::Req { message_id, .. } | Command::Touch { message_id } => {
+                Some(String::from_utf8_lossy(message_id).into_owned())
+            }
+            _ => None,
+        };
+        tracing::info_span!(
+            "command",
+            otel.name = command.name(),
+            client_id = %client.id(),
+            message_id = message_id.as_deref(),
+        )
+    }
+    
+    /// Handle a single client command, returning false when the connection should close
+    fn handle_command(&self, client: &Arc<Client>, command: Command) -> Result<bool> {
+        if let Some(reason) = client.state().rejection(&command) {
+            client.send_error(format!("{} {}", ErrorCode::Invalid, reason))?;
+            return Ok(true);
+        }
+        
+        match command {
+            Command::Identify { data } => {
+                client.identify(&data);
+                let info = client.info();
+                let feature_negotiation = data.get("feature_negotiation").and_then(|v| v.as_bool()).unwrap_or(false);
+                if !feature_negotiation && !info.zstd {
+                    client.send_response("OK")?;
+                    return Ok(true);
+                }
+                
+                // Report the negotiated settings uncompressed; with zstd,
+                // switch the stream and confirm again compressed
+                client.send_response(serde_json::to_string(&info.identify_response())?)?;
+                if info.zstd {
+                    client.enable_zstd()?;
+                    client.send_response("OK")?;
+                }
+            }
+            Command::Pub { topic, body } => {
+                self.handle_tcp_publish(client, &topic, vec![body], false)?;
+            }
+            Command::Mpub { topic, bodies } => {
+                self.handle_tcp_publish(client, &topic, bodies, true)?;
+            }
+            Command::Sub { topic, channel } => {
+                self.handle_sub(client, &topic, &channel)?;
+            }
+            Command::Rdy { count } => {
+                self.handle_rdy(client, count)?;
+            }
+            Command::Fin { message_id } => {
+                self.handle_fin(client, &message_id)?;
+            }
+            Command::Req { message_id, timeout } => {
+                self.handle_req(client, &message_id, timeout)?;
+            }
+            Command::Touch { message_id } => {
+                self.handle_touch(client, &message_id)?;
+            }
+            Command::Nop => {}
+            Command::Close => {
+                client.send_response("CLOSE_WAIT")?;
+                return Ok(false);
+            }
+            other => {
+                client.send_error(format!("{} {} not supported", ErrorCode::Invalid, other.name()))?;
+            }
+        }
+        
+        Ok(true)
+    }
+    
+    /// Give a message taken for a client back to the front of its channel
+    fn return_message(&self, client: &Client, message_id: MessageId) {
+        if client.return_in_flight(message_id).is_none() {
+            return;
+        }
+        if let Some(channel) = self.client_channel(client) {
+            if let Err(e) = channel.return_message(message_id) {
+                tracing::warn!("Failed to return message {} to channel {}: {}", message_id, channel.name, e);
+            }
+        }
+    }
+    
+    /// Get the channel a client is subscribed to
+    fn client_channel(&self, client: &Client) -> Option<Arc<Channel>> {
+        let topic = self.get_topic(&client.topic()?)?;
+        topic.get_channel(&client.channel()?)
+    }
+    
+    /// Parse a message ID sent by a client, the 16 bytes it was delivered with
+    fn parse_message_id(message_id: &[u8]) -> Option<MessageId> {
+        MessageId::from_slice(message_id).ok()
+    }
+    
+    /// Handle SUB
+    fn handle_sub(&self, client: &Arc<Client>, topic_name: &str, channel_name: &str) -> Result<()> {
+        if let Err(e) = validate_topic_name(topic_name).and_then(|_| validate_channel_name(channel_name)) {
+            return client.send_error(format!("{} {}", e.code(), e));
+        }
+        
+        let existing_topic = self.get_topic(topic_name);
+        let topic = match existing_topic {
+            Some(topic) => topic,
+            None if self.config.read().disable_implicit_creation => {
+                return client.send_error(format!("{} SUB topic {} does not exist", ErrorCode::TopicNotFound, topic_name));
+            }
+            None => self.get_or_create_topic(topic_name.to_string()),
+        };
+        let channel = match topic.get_channel(channel_name) {
+            Some(channel) => channel,
+            None if self.config.read().disable_implicit_creation => {
+                return client.send_error(format!("{} SUB channel {} does not exist on topic {}", ErrorCode::ChannelNotFound, channel_name, topic_name));
+            }
+            None => match self.create_channel_with_replay(&topic, channel_name, client.info().replay_backlog) {
+                Ok(channel) => channel,
+                // Another client created it concurrently
+                Err(_) => match topic.get_channel(channel_name) {
+                    Some(channel) => channel,
+                    None => return client.send_error(format!("{} cannot create {}", ErrorCode::BadChannel, channel_name)),
+                },
+            },
+        };
+        
+        client.set_topic(topic_name.to_string());
+        client.set_channel(channel_name.to_string());
+        client.set_state(ClientState::Subscribed);
+        channel.add_client(client.id(), client.info().sample_rate);
+        
+        let server = self.clone();
+        let dispatch_client = client.clone();
+        tokio::spawn(async move {
+            server.dispatch_messages(dispatch_client, channel).await;
+        });
+        
+        client.send_response("OK")
+    }
+    
+    /// Handle RDY
+    fn handle_rdy(&self, client: &Client, count: u32) -> Result<()> {
+        let max_rdy_count = self.client_channel(client)
+            .and_then(|channel| channel.effective_delivery_settings().max_rdy_count)
+            .unwrap_or_else(|| client.info().max_rdy_count);
+        if count > max_rdy_count {
+            return client.send_error(format!("{} RDY count {} out of range 0-{}", ErrorCode::Invalid, count, max_rdy_count));
+        }
+        
+        client.set_state(ClientState::Ready);
+        client.set_rdy_count(count);
+        Ok(())
+    }
+    
+    /// Handle FIN
+    fn handle_fin(&self, client: &Client, message_id: &[u8]) -> Result<()> {
+        let (Some(channel), Some(id)) = (self.client_channel(client), Self::parse_message_id(message_id)) else {
+            return client.send_error(format!("{} FIN {} failed", ErrorCode::FinFailed, String::from_utf8_lossy(message_id)));
+        };
+        
+        match channel.finish_message(id) {
+            Ok(()) => {
+                client.remove_in_flight(id);
+                self.tracer.record(&channel.topic_name, || {
+                    TraceEvent::new(TraceEventKind::Finish, &channel.topic_name, id).on_channel(&channel.name, client.id())
+                });
+                Ok(())
+            }
+            Err(e) => client.send_error(format!("{} FIN {} failed {}", ErrorCode::FinFailed, id, e)),
+        }
+    }
+    
+    /// Handle REQ
+    fn handle_req(&self, client: &Client, message_id: &[u8], timeout: u64) -> Result<()> {
+        let (Some(channel), Some(id)) = (self.client_channel(client), Self::parse_message_id(message_id)) else {
+            return client.send_error(format!("{} REQ {} failed", ErrorCode::ReqFailed, String::from_utf8_lossy(message_id)));
+        };
+        
+        if timeout > self.config.read().max_req_timeout {
+            return client.send_error(format!("{} REQ timeout {} out of range 0-{}", ErrorCode::Invalid, timeout, self.config.read().max_req_timeout));
+        }
+        
+        let result = if timeout > 0 {
+            channel.defer_message(id, Duration::from_millis(timeout))
+        } else {
+            channel.requeue_message(id, Duration::ZERO)
+        };
+        
+        match result {
+            Ok(()) => {
+                client.requeue_in_flight(id);
+                self.tracer.record(&channel.topic_name, || TraceEvent {
+                    timeout_ms: Some(timeout),
+                    ..TraceEvent::new(TraceEventKind::Requeue, &channel.topic_name, id).on_channel(&channel.name, client.id())
+                });
+                Ok(())
+            }
+            Err(e) => client.send_error(format!("{} REQ {} failed {}", ErrorCode::ReqFailed, id, e)),
+        }
+    }
+    
+    /// Handle TOUCH
+    fn handle_touch(&self, client: &Client, message_id: &[u8]) -> Result<()> {
+        let (Some(channel), Some(id)) = (self.client_channel(client), Self::parse_message_id(message_id)) else {
+            return client.send_error(format!("{} TOUCH {} failed", ErrorCode::TouchFailed, String::from_utf8_lossy(message_id)));
+        };
+        
+        if let Err(e) = channel.touch_message(id) {
+            return client.send_error(format!("{} TOUCH {} failed {}", ErrorCode::TouchFailed, id, e));
+        }
+        Ok(())
+    }
+    
+    /// Time a message delivered to `client` may stay in flight: the channel's
+    /// or topic's override, capped at --max-msg-timeout, else the client's
+    fn msg_timeout(&self, client: &Client, channel: &Channel) -> Duration {
+        match channel.effective_delivery_settings().msg_timeout {
+            Some(timeout) => Duration::from_millis(timeout.min(self.config.read().max_msg_timeout)),
+            None => client.info().msg_timeout,
+        }
+    }
+    
+    /// Deliver channel messages to a subscribed client while it has RDY capacity
+    async fn dispatch_messages(&self, client: Arc<Client>, channel: Arc<Channel>) {
+        let idle_wait = Duration::from_millis(100);
+        
+        while !client.is_closed() && !self.is_shutting_down() {
+            if !client.can_receive() {
+                client.wait_for_ready(idle_wait).await;
+                continue;
+            }
+            
+            let timeout = self.msg_timeout(&client, &channel);
+            match channel.dispatch_message(client.id(), timeout) {
+                Ok(Some(message)) if !client.is_ready() => {
+                    // RDY 0 arrived after the readiness check
+                    if let Err(e) = channel.return_message(message.id) {
+                        tracing::warn!("Failed to return message {} to channel {}: {}", message.id, channel.name, e);
+                    }
+                }
+                Ok(Some(message)) if self.exceeds_max_attempts(&message) => {
+                    self.dead_letter(&channel, message.id);
+                }
+                Ok(Some(message)) => {
+                    let message_id = message.id;
+                    let _span = tracing::info_span!(
+                        "deliver",
+                        topic = %channel.topic_name,
+                        channel = %channel.name,
+                        client_id = %client.id(),
+                        message_id = %message_id,
+                        attempts = message.attempts,
+                    ).entered();
+                    self.tracer.record(&channel.topic_name, || TraceEvent {
+                        attempts: Some(message.attempts),
+                        ..TraceEvent::new(TraceEventKind::Deliver, &channel.topic_name, message_id).on_channel(&channel.name, client.id())
+                    });
+                    let shadow_topic = channel.shadow_target(&message);
+                    let shadow_copy = shadow_topic.as_ref()
+                        .map(|_| self.new_message(message.body.clone()).with_headers(message.headers.clone()));
+                    client.add_in_flight(message.clone());
+                    if client.send_message(message).is_err() {
+                        let _ = channel.requeue_message(message_id, Duration::ZERO);
+                        client.requeue_in_flight(message_id);
+                        break;
+                    }
+                    
+                    if let (Some(shadow_topic), Some(copy)) = (shadow_topic, shadow_copy) {
+                        match self.publish_to_topic(&shadow_topic, vec![copy]) {
+                            Ok(_) => channel.record_shadow(),
+                            Err(e) => tracing::warn!("Failed to copy message {} to shadow topic {}: {}", message_id, shadow_topic, e),
+                        }
+                    }
+                }
+                Ok(None) => channel.wait_for_messages(idle_wait).await,
+                Err(e) => {
+                    tracing::warn!("Failed to dispatch message on channel {}: {}", channel.name, e);
+                    channel.wait_for_messages(idle_wait).await;
+                }
+            }
+        }
+    }
+    
+    /// Check whether a message taken for delivery has used up its attempts
+    fn exceeds_max_attempts(&self, message: &Message) -> bool {
+        let max_attempts = self.config.read().max_attempts;
+        max_attempts > 0 && message.attempts > max_attempts
+    }
+    
+    /// Move a message that used up its attempts to the topic's dead-letter
+    /// topic, or drop it when dead-lettering is disabled or the message came
+    /// from a dead-letter topic itself
+    fn dead_letter(&self, channel: &Channel, message_id: MessageId) {
+        let mut message = match channel.dead_letter_message(message_id) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("Failed to dead-letter message {} on channel {}: {}", message_id, channel.name, e);
+                return;
+            }
         };
         
-        let client = Arc::new(Client::new(client_info, framed, self.metrics.clone()));
-        let client_id = client.info.id;
+        // The delivery that was stopped does not count as an attempt
+        let attempts = message.attempts.saturating_sub(1);
+        let dead_letter_topic = format!("{}{}", channel.topic_name, DEAD_LETTER_SUFFIX);
+        let drop_reason = if self.config.read().disable_dead_letter {
+            Some("dead-lettering is disabled".to_string())
+        } else if channel.topic_name.ends_with(DEAD_LETTER_SUFFIX) {
+            Some("it is already in a dead-letter topic".to_string())
+        } else if let Err(e) = validate_topic_name(&dead_letter_topic) {
+            Some(format!("{} is not a valid topic: {}", dead_letter_topic, e))
+        } else {
+            None
+        };
+        if let Some(reason) = drop_reason {
+            tracing::warn!("Dropped message {} from {}/{} after {} attempts: {}",
+                message_id, channel.topic_name, channel.name, attempts, reason);
+            self.metrics.incr("messages.dead_letter_dropped", 1);
+            return;
+        }
         
-        self.stats.add_client(client_id, client.clone());
-        self.clients.write().insert(client_id, client.clone());
+        // Keep the ID and timestamp so the message can be traced back
+        message.attempts = 0;
+        let topic = self.get_or_create_topic(dead_letter_topic.clone());
+        match topic.publish_multiple(vec![message]) {
+            Ok(_) => tracing::info!("Moved message {} from {}/{} to {} after {} attempts",
+                message_id, channel.topic_name, channel.name, dead_letter_topic, attempts),
+            Err(e) => tracing::warn!("Failed to move message {} to {}: {}", message_id, dead_letter_topic, e),
+        }
+    }
+    
+    /// Handle PUB/MPUB over TCP
+    fn handle_tcp_publish(&self, client: &Client, topic: &str, bodies: Vec<BytesCrate>, multiple: bool) -> Result<()> {
+        if let Err(e) = validate_topic_name(topic) {
+            return client.send_error(format!("{} {}", e.code(), e));
+        }
         
-        tracing::info!("New TCP connection from {}", addr);
+        // Clients that negotiated headers prefix each body with a header block
+        let messages = if client.wants_message_headers() {
+            match bodies.into_iter()
+                .map(|body| decode_with_headers(body).map(|(headers, body)| self.new_message(body).with_headers(headers)))
+                .collect::<std::result::Result<Vec<_>, _>>()
+            {
+                Ok(messages) => messages,
+                Err(e) => return client.send_error(format!("{} {}", ErrorCode::BadMessage, e)),
+            }
+        } else {
+            bodies.into_iter().map(|body| self.new_message(body)).collect()
+        };
         
-        // Handle client protocol
-        self.handle_client_protocol(client).await?;
+        match self.publish_to_topic(topic, messages) {
+            Ok(ids) if client.wants_publish_receipts() => {
+                client.send_response(Self::publish_receipt(&ids, multiple).to_string())
+            }
+            Ok(_) => client.send_response("OK"),
+            Err(e) => client.send_error(e.frame_text(if multiple { ErrorCode::MpubFailed } else { ErrorCode::PubFailed })),
+        }
+    }
+    
+    /// Publish new messages to a topic, returning their IDs
+    fn publish_to_topic(&self, topic_name: &str, messages: Vec<Message>) -> Result<Vec<MessageId>> {
+        validate_topic_name(topic_name)?;
+        for message in &messages {
+            validate_message_size(&message.body, self.config.read().max_msg_size)?;
+        }
         
-        // Cleanup
-        self.clients.write().remove(&client_id);
-        self.stats.remove_client(&client_id);
+        let topic = self.get_or_create_topic(topic_name.to_string());
+        let ids = messages.iter().map(|message| message.id).collect();
+        self.publish_and_replicate(&topic, messages)?;
         
-        tracing::info!("TCP connection from {} closed", addr);
-        Ok(())
+        Ok(ids)
     }
     
-    /// Handle client protocol
-    async fn handle_client_protocol(&self, _client: Arc<Client>) -> Result<()> {
-        // TODO: Implement client protocol handling
-        // This would include:
-        // - IDENTIFY command
-        // - SUB command
-        // - RDY command
-        // - Message delivery
-        // - FIN/REQ/TOUCH commands
-        // - Heartbeat handling
-        
-        tracing::info!("Client protocol handling not implemented yet");
+    /// Publish messages to a topic and queue copies for its replicas, unless
+    /// the topic is itself a replica
+    fn publish_and_replicate(&self, topic: &Topic, messages: Vec<Message>) -> Result<()> {
+        let copies = (self.replicator.is_enabled() && !topic.is_replica()).then(|| messages.clone());
+        let traced_ids: Vec<MessageId> = if self.tracer.is_traced(&topic.name) {
+            messages.iter().map(|message| message.id).collect()
+        } else {
+            Vec::new()
+        };
+        for &id in &traced_ids {
+            self.tracer.record(&topic.name, || TraceEvent::new(TraceEventKind::Publish, &topic.name, id));
+        }
+        topic.publish_multiple(messages)?;
+        for &id in &traced_ids {
+            self.tracer.record(&topic.name, || TraceEvent::new(TraceEventKind::Enqueue, &topic.name, id));
+        }
+        if let Some(copies) = copies {
+            self.replicator.replicate(&topic.name, copies);
+        }
         Ok(())
     }
     
+    /// Build the receipt body returned to publishers that opted in
+    fn publish_receipt(ids: &[MessageId], multiple: bool) -> serde_json::Value {
+        if multiple {
+            serde_json::json!({ "ids": ids.iter().map(|id| id.to_string()).collect::<Vec<_>>() })
+        } else {
+            serde_json::json!({ "id": ids.first().map(|id| id.to_string()) })
+        }
+    }
+    
     /// Create HTTP router
     fn create_http_router(&self) -> Router {
         let server = self.clone();
@@ -319,32 +1695,308 @@ impl NsqdServer {
             .route("/ping", get(|| async { "OK" }))
             .route("/info", get(Self::handle_info))
             .route("/stats", get(Self::handle_stats))
+            .route("/clients", get(Self::handle_clients))
+            .route("/clients/:id/close", post(Self::handle_client_close))
+            .route("/metrics", get(Self::handle_metrics))
             .route("/pub", post(Self::handle_pub))
             .route("/mpub", post(Self::handle_mpub))
+            .route("/replicate", post(Self::handle_replicate))
+            .route("/peek", get(Self::handle_peek))
             .route("/topic/create", post(Self::handle_topic_create))
             .route("/topic/delete", post(Self::handle_topic_delete))
             .route("/topic/pause", post(Self::handle_topic_pause))
             .route("/topic/unpause", post(Self::handle_topic_unpause))
+            .route("/topic/empty", post(Self::handle_topic_empty))
+            .route("/topic/configure", post(Self::handle_topic_configure))
+            .route("/channel/create", post(Self::handle_channel_create))
             .route("/channel/delete", post(Self::handle_channel_delete))
             .route("/channel/pause", post(Self::handle_channel_pause))
+            .route("/channel/empty", post(Self::handle_channel_empty))
             .route("/channel/unpause", post(Self::handle_channel_unpause))
+            .route("/channel/concurrency", post(Self::handle_channel_concurrency))
+            .route("/channel/shadow", post(Self::handle_channel_shadow))
+            .route("/channel/configure", post(Self::handle_channel_configure))
             .route("/config/:key", get(|| async { Json(serde_json::json!({"value": ""})) }))
             .route("/config/:key", post(|| async { "OK" }))
-            .route("/debug/freememory", get(|| async { Json(serde_json::json!({"memory": 0})) }))
+            .route("/debug/runtime", get(Self::handle_debug_runtime))
+            .route("/debug/locks", get(Self::handle_debug_locks))
+            .route("/debug/memory", get(Self::handle_debug_memory))
+            .route("/alerts", get(Self::handle_alerts))
+            .route("/debug/trace", get(Self::handle_debug_trace).post(Self::handle_debug_trace_set))
+            .route("/debug/trace/events", get(Self::handle_debug_trace_events))
+            .layer(TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| http_span(request.method(), request.uri().path()))
+                .on_response(|response: &Response, _latency: Duration, span: &tracing::Span| {
+                    span.record("http.status_code", response.status().as_u16());
+                }))
             .layer(cors)
+            .layer(self.access_log.clone())
             .with_state(server)
     }
 
     // --- HTTP Handlers ---
-    async fn handle_info() -> Json<serde_json::Value> {
+    async fn handle_info(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
         Json(serde_json::json!({
             "version": env!("CARGO_PKG_VERSION"),
+            "broadcast_address": server.config.read().broadcast_address,
+            "node_id": server.guids.worker_id(),
             "build": "rust",
         }))
     }
 
-    async fn handle_stats(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
-        let stats = server.stats.get_stats();
+    /// Topics sorted by name, for stable diagnostics output
+    fn sorted_topics(&self) -> Vec<Arc<Topic>> {
+        let mut topics = self.topic_list();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        topics
+    }
+    
+    /// Report tokio scheduler metrics and supervised background tasks
+    async fn handle_debug_runtime(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "runtime": diagnostics::runtime_stats(),
+            "tasks": server.supervisor.stats(),
+        }))
+    }
+    
+    /// Report contention on the queue lock of every topic and channel
+    async fn handle_debug_locks(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
+        let topics: Vec<serde_json::Value> = server.sorted_topics().iter().map(|topic| {
+            let mut channels = topic.get_channels();
+            channels.sort_by(|a, b| a.name.cmp(&b.name));
+            serde_json::json!({
+                "topic_name": topic.name,
+                "queue": topic.queue_lock_stats(),
+                "channels": channels.iter().map(|channel| serde_json::json!({
+                    "channel_name": channel.name,
+                    "queue": channel.queue_lock_stats(),
+                })).collect::<Vec<_>>(),
+            })
+        }).collect();
+        Json(serde_json::json!({ "topics": topics }))
+    }
+    
+    /// Alerts currently firing
+    async fn handle_alerts(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "enabled": server.alerter.is_enabled(),
+            "alerts": server.alerter.firing(),
+        }))
+    }
+    
+    async fn handle_debug_trace(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({ "topics": server.tracer.traced_topics() }))
+    }
+    
+    /// Start tracing `topic`, or stop with `enabled=false`
+    async fn handle_debug_trace_set(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        if let Err(e) = validate_topic_name(topic_name) {
+            return Self::error_response(e.into(), ErrorCode::Invalid);
+        }
+        let enabled = match params.get("enabled").map(String::as_str) {
+            None | Some("true") | Some("1") => true,
+            Some("false") | Some("0") => false,
+            Some(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "INVALID_ARG_ENABLED"}))).into_response(),
+        };
+        server.tracer.set_traced(topic_name, enabled);
+        tracing::info!("Message tracing {} for topic {}", if enabled { "enabled" } else { "disabled" }, topic_name);
+        "OK".into_response()
+    }
+    
+    /// Recorded trace events, filtered by `topic`, `channel`, `id` and
+    /// `since`, a `seq` to read on from
+    async fn handle_debug_trace_events(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let parse = |key: &str| params.get(key).map(|value| value.parse::<u64>().map_err(|_| key.to_uppercase())).transpose();
+        let (since, limit) = match (parse("since"), parse("limit")) {
+            (Ok(since), Ok(limit)) => (since, limit),
+            (Err(key), _) | (_, Err(key)) => {
+                return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": format!("INVALID_ARG_{}", key)}))).into_response();
+            }
+        };
+        let filter = TraceFilter {
+            topic: params.get("topic").cloned(),
+            channel: params.get("channel").cloned(),
+            message_id: params.get("id").cloned(),
+            since,
+            limit: limit.map(|limit| limit as usize),
+        };
+        let events = server.tracer.events(&filter);
+        let last_seq = events.last().map_or(server.tracer.last_seq(), |event| event.seq);
+        Json(serde_json::json!({ "events": events, "last_seq": last_seq })).into_response()
+    }
+    
+    /// Spill the tails of the topics with the most queued bytes to disk until
+    /// the memory held by all topics is back within `budget`. In-flight and
+    /// deferred messages stay in memory, as do topics whose disk queue
+    /// already has messages.
+    fn enforce_memory_budget(topics: &[Arc<Topic>], budget: usize) {
+        let mut usage: Vec<(usize, &Arc<Topic>)> = Vec::with_capacity(topics.len());
+        let mut total = 0;
+        for topic in topics {
+            total += topic.memory_bytes();
+            usage.push((topic.queued_bytes(), topic));
+        }
+        let mut excess = total.saturating_sub(budget);
+        if excess == 0 {
+            return;
+        }
+        
+        usage.sort_by_key(|(queued_bytes, _)| std::cmp::Reverse(*queued_bytes));
+        for (queued_bytes, topic) in usage {
+            if excess == 0 || queued_bytes == 0 {
+                break;
+            }
+            match topic.spill(excess) {
+                Ok(0) => {}
+                Ok(spilled) => {
+                    tracing::info!("Spilled {} bytes of topic {} to disk, {} bytes in memory over the {} byte budget",
+                        spilled, topic.name, total, budget);
+                    excess = excess.saturating_sub(spilled);
+                }
+                Err(e) => tracing::warn!("Failed to spill topic {} to disk: {}", topic.name, e),
+            }
+        }
+    }
+    
+    /// Report the memory held by in-memory queues, and by the process where
+    /// the platform reports it
+    async fn handle_debug_memory(State(server): State<NsqdServer>) -> Json<serde_json::Value> {
+        let mut total_bytes = 0;
+        let topics: Vec<serde_json::Value> = server.sorted_topics().iter().map(|topic| {
+            let mut channels = topic.get_channels();
+            channels.sort_by(|a, b| a.name.cmp(&b.name));
+            let topic_memory = topic.memory_usage();
+            let mut topic_bytes = topic_memory.total_bytes();
+            let channels: Vec<serde_json::Value> = channels.iter().map(|channel| {
+                let memory = channel.memory_usage();
+                topic_bytes += memory.total_bytes();
+                serde_json::json!({
+                    "channel_name": channel.name,
+                    "total_bytes": memory.total_bytes(),
+                    "memory": memory,
+                })
+            }).collect();
+            total_bytes += topic_bytes;
+            serde_json::json!({
+                "topic_name": topic.name,
+                "total_bytes": topic_bytes,
+                "memory": topic_memory,
+                "channels": channels,
+            })
+        }).collect();
+        Json(serde_json::json!({
+            "process": diagnostics::process_memory(),
+            "max_memory_size": server.config.read().max_memory_size,
+            "total_bytes": total_bytes,
+            "topics": topics,
+        }))
+    }
+
+    /// Handle metrics endpoint in the Prometheus text format, including
+    /// per-channel end-to-end processing latency and per-topic durable
+    /// publish fsync latency
+    async fn handle_metrics(State(server): State<NsqdServer>) -> impl IntoResponse {
+        use std::fmt::Write;
+        
+        let mut output = server.metrics.render_prometheus("nsqd");
+        let topics = server.stats.get_stats().topics;
+        let name = "nsqd_topic_publish_fsync_latency_seconds";
+        let _ = writeln!(output, "# TYPE {} summary", name);
+        for topic in topics.iter().filter(|topic| topic.durable || topic.fsync_count > 0) {
+            let labels = format!("topic=\"{}\"", topic.name);
+            for percentile in &topic.fsync_latency.percentiles {
+                let _ = writeln!(output, "{}{{{},quantile=\"{}\"}} {}",
+                    name, labels, percentile.quantile, percentile.value as f64 / 1e9);
+            }
+            let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, topic.fsync_latency.count);
+        }
+        let name = "nsqd_channel_e2e_processing_latency_seconds";
+        let _ = writeln!(output, "# TYPE {} summary", name);
+        for topic in topics {
+            for channel in topic.channels {
+                let labels = format!("topic=\"{}\",channel=\"{}\"", topic.name, channel.name);
+                for percentile in &channel.e2e_processing_latency.percentiles {
+                    let _ = writeln!(output, "{}{{{},quantile=\"{}\"}} {}",
+                        name, labels, percentile.quantile, percentile.value as f64 / 1e9);
+                }
+                let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, channel.e2e_processing_latency.count);
+            }
+        }
+        
+        ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], output)
+    }
+
+    /// List connected clients with their negotiated settings, subscription
+    /// and counters, optionally only those subscribed to a topic or channel
+    async fn handle_clients(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Json<serde_json::Value> {
+        let mut clients = server.stats.get_client_stats();
+        clients.retain(|client| {
+            params.get("topic").is_none_or(|topic| client.topic.as_ref() == Some(topic))
+                && params.get("channel").is_none_or(|channel| client.channel.as_ref() == Some(channel))
+        });
+        clients.sort_by_key(|client| client.connect_time);
+        Json(serde_json::json!({
+            "client_count": clients.len(),
+            "clients": clients,
+        }))
+    }
+
+    /// Close a client's connection; messages it has in flight are requeued
+    /// as when it disconnects
+    async fn handle_client_close(
+        State(server): State<NsqdServer>,
+        Path(id): Path<String>,
+    ) -> Response {
+        let Ok(client_id) = Uuid::parse_str(&id) else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "INVALID_CLIENT_ID"}))).into_response();
+        };
+        let Some(client) = server.clients.get(&client_id).map(|entry| entry.value().clone()) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "CLIENT_NOT_FOUND"}))).into_response();
+        };
+        client.close();
+        server.metrics.incr("clients.force_closed", 1);
+        tracing::info!("Closed connection of client {} ({}) on request", client_id, client.info().remote_addr);
+        "OK".into_response()
+    }
+
+    async fn handle_stats(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        // Filtering on a topic or channel that does not exist is reported as
+        // 404 so callers can tell it apart from one that is merely empty
+        let topic_filter = params.get("topic");
+        let channel_filter = params.get("channel");
+        if let Some(topic_name) = topic_filter {
+            let Some(topic) = server.get_topic(topic_name) else {
+                return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+            };
+            if channel_filter.is_some_and(|channel_name| topic.get_channel(channel_name).is_none()) {
+                return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "CHANNEL_NOT_FOUND"}))).into_response();
+            }
+        }
+        
+        let mut stats = server.stats.get_stats();
+        if let Some(topic_name) = topic_filter {
+            stats.topics.retain(|t| &t.name == topic_name);
+            if let Some(channel_name) = channel_filter {
+                for topic in &mut stats.topics {
+                    topic.channels.retain(|c| &c.name == channel_name);
+                }
+            }
+        }
         // Transform to compatibility shape
         let version = stats.server.version;
         let start_time = stats.server.start_time.timestamp();
@@ -354,34 +2006,109 @@ impl NsqdServer {
         let seconds = uptime_seconds % 60;
         let uptime = format!("{}h {}m {}s", hours, minutes, seconds);
 
+        let clients = stats.clients;
         let topics: Vec<serde_json::Value> = stats.topics.into_iter().map(|t| {
             let channels: Vec<serde_json::Value> = t.channels.into_iter().map(|c| {
+                let channel_clients: Vec<serde_json::Value> = clients.iter()
+                    .filter(|client| client.topic.as_deref() == Some(t.name.as_str()) && client.channel.as_deref() == Some(c.name.as_str()))
+                    .map(|client| serde_json::json!({
+                        "client_id": client.id.to_string(),
+                        "hostname": client.hostname.clone().unwrap_or_default(),
+                        "remote_address": client.remote_addr,
+                        "user_agent": client.user_agent.clone().unwrap_or_default(),
+                        "version": client.client_version.clone().unwrap_or_default(),
+                        "ready_count": client.rdy_count,
+                        "in_flight_count": client.in_flight_count,
+                        "message_count": client.messages_received,
+                        "finish_count": client.messages_finished,
+                        "bytes_received": client.bytes_received,
+                        "bytes_sent": client.bytes_sent,
+                        "requeue_count": client.messages_requeued,
+                        "timeout_count": client.messages_timed_out,
+                        "finish_latency_p50_ms": client.finish_latency_p50_ms,
+                        "finish_latency_p99_ms": client.finish_latency_p99_ms,
+                        "sample_rate": client.sample_rate,
+                        "deflate": client.deflate,
+                        "snappy": client.snappy,
+                        "zstd": client.zstd,
+                        "connect_ts": client.connect_time.timestamp(),
+                    }))
+                    .collect();
                 serde_json::json!({
                     "channel_name": c.name,
+                    "created_at": c.created_at.to_rfc3339(),
+                    "last_delivery_at": c.last_delivery_at.map(|t| t.to_rfc3339()),
+                    "last_activity_at": c.last_activity_at.to_rfc3339(),
                     "depth": c.depth,
                     "backend_depth": c.backend_depth,
+                    "disk_unread_bytes": c.disk_unread_bytes,
                     "message_count": c.message_count,
                     "in_flight_count": c.in_flight_count,
                     "deferred_count": c.deferred_count,
                     "requeue_count": c.requeue_count,
                     "timeout_count": c.timeout_count,
+                    "finish_count": c.finish_count,
+                    "client_count": c.client_count,
                     "paused": c.paused,
-                    "clients": [],
+                    "paused_at": c.paused_at.map(|t| t.to_rfc3339()),
+                    "max_in_flight": c.max_in_flight,
+                    "msg_timeout": c.delivery.msg_timeout,
+                    "max_rdy_count": c.delivery.max_rdy_count,
+                    "ordered": c.ordered,
+                    "in_flight_utilization": if c.max_in_flight > 0 {
+                        c.in_flight_count as f64 / c.max_in_flight as f64
+                    } else {
+                        0.0
+                    },
+                    "oldest_message_age_ms": c.oldest_message_age_ms,
+                    "shadow_topic": c.shadow_topic,
+                    "shadow_rate": c.shadow_rate,
+                    "shadow_count": c.shadow_count,
+                    "dead_letter_count": c.dead_letter_count,
+                    "replayed_count": c.replayed_count,
+                    "e2e_processing_latency": c.e2e_processing_latency,
+                    "clients": channel_clients,
                 })
             }).collect();
 
             serde_json::json!({
                 "topic_name": t.name,
                 "created_at": t.created_at.to_rfc3339(),
+                "last_publish_at": t.last_publish_at.map(|t| t.to_rfc3339()),
+                "last_delivery_at": t.last_delivery_at.map(|t| t.to_rfc3339()),
                 "paused": t.paused,
+                "paused_at": t.paused_at.map(|t| t.to_rfc3339()),
                 "message_count": t.message_count,
                 "channel_count": t.channel_count,
                 "depth": t.depth,
                 "backend_depth": t.backend_depth,
+                "disk_files": t.disk_usage.files,
+                "disk_bytes": t.disk_usage.bytes,
+                "disk_unread_bytes": t.disk_usage.unread_bytes,
                 "in_flight_count": t.in_flight_count,
                 "deferred_count": t.deferred_count,
                 "requeue_count": t.requeue_count,
                 "timeout_count": t.timeout_count,
+                "retention_ms": t.retention.retention_ms,
+                "retention_bytes": t.retention.retention_bytes,
+                "msg_timeout": t.delivery.msg_timeout,
+                "max_rdy_count": t.delivery.max_rdy_count,
+                "mem_queue_size": t.mem_queue_size,
+                "max_msgs_per_sec": t.rate_limit.max_msgs_per_sec,
+                "max_bytes_per_sec": t.rate_limit.max_bytes_per_sec,
+                "rate_limited_count": t.rate_limited_count,
+                "durable": t.durable,
+                "durable_published_bytes": t.durable_published_bytes,
+                "durable_written_bytes": t.durable_written_bytes,
+                "write_amplification": match t.durable_published_bytes {
+                    0 => 0.0,
+                    published => t.durable_written_bytes as f64 / published as f64,
+                },
+                "fsync_count": t.fsync_count,
+                "fsync_latency": t.fsync_latency,
+                "replica": t.replica,
+                "pruned_count": t.pruned_count,
+                "spilled_count": t.spilled_count,
                 "channels": channels,
             })
         }).collect();
@@ -394,52 +2121,191 @@ impl NsqdServer {
             "uptime_seconds": uptime_seconds,
             "topics": topics,
             "producers": [],
-        }))
+            "implicit_creation": !server.config.read().disable_implicit_creation,
+            "lookupd": server.lookupd.stats(),
+            "replication": server.replicator.stats(),
+            "tasks": server.supervisor.stats(),
+            "connection_limits": server.connection_limits.stats(),
+            "depth_check": *server.depth_check.read(),
+        })).into_response()
     }
 
     async fn handle_pub(
         State(server): State<NsqdServer>,
         Query(params): Query<std::collections::HashMap<String, String>>,
+        headers: HeaderMap,
         body: Bytes,
-    ) -> &'static str {
+    ) -> Response {
         if let Some(topic_name) = params.get("topic") {
+            if let Err(e) = validate_topic_name(topic_name) {
+                return Self::error_response(e.into(), ErrorCode::Invalid);
+            }
+            let message_headers = match Self::http_message_headers(&headers) {
+                Ok(message_headers) => message_headers,
+                Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": format!("INVALID_HEADER {}", e)}))).into_response(),
+            };
             let topic = server.get_or_create_topic(topic_name.clone());
-            // Create a default channel if none exists to satisfy tests
-            if topic.get_channels().is_empty() {
-                let _ = topic.add_channel("default".to_string());
+            if let Some(response) = server.await_topic_capacity(&topic, &params).await {
+                return response;
+            }
+            if let Err(e) = validate_message_size(&body, server.config.read().max_msg_size) {
+                return Self::error_response(e, ErrorCode::PubFailed);
+            }
+            let msg = server.new_message(body).with_headers(message_headers);
+            let id = msg.id;
+            if let Err(e) = server.publish_and_replicate(&topic, vec![msg]) {
+                return Self::error_response(e, ErrorCode::PubFailed);
+            }
+            if Self::wants_receipt(&params) {
+                return Json(Self::publish_receipt(&[id], false)).into_response();
             }
-            let msg = Message::new(BytesCrate::from(body));
-            let _ = topic.publish(msg);
-            return "OK";
+            return "OK".into_response();
         }
-        "BAD_REQUEST"
+        "BAD_REQUEST".into_response()
     }
 
     async fn handle_mpub(
         State(server): State<NsqdServer>,
         Query(params): Query<std::collections::HashMap<String, String>>,
+        headers: HeaderMap,
         body: Bytes,
-    ) -> &'static str {
+    ) -> Response {
         if let Some(topic_name) = params.get("topic") {
+            if let Err(e) = validate_topic_name(topic_name) {
+                return Self::error_response(e.into(), ErrorCode::Invalid);
+            }
+            let message_headers = match Self::http_message_headers(&headers) {
+                Ok(message_headers) => message_headers,
+                Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": format!("INVALID_HEADER {}", e)}))).into_response(),
+            };
             let topic = server.get_or_create_topic(topic_name.clone());
-            if topic.get_channels().is_empty() { let _ = topic.add_channel("default".to_string()); }
+            if let Some(response) = server.await_topic_capacity(&topic, &params).await {
+                return response;
+            }
             // Simple split by newlines for dev compatibility
-            for line in body.split(|b| *b == b'\n') {
-                if !line.is_empty() {
-                    let _ = topic.publish(Message::new(BytesCrate::copy_from_slice(line)));
+            let messages: Vec<Message> = body.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| server.new_message(BytesCrate::copy_from_slice(line)).with_headers(message_headers.clone()))
+                .collect();
+            let max_msg_size = server.config.read().max_msg_size;
+            if let Err(e) = messages.iter().try_for_each(|msg| validate_message_size(&msg.body, max_msg_size)) {
+                return Self::error_response(e, ErrorCode::MpubFailed);
+            }
+            let ids: Vec<MessageId> = messages.iter().map(|msg| msg.id).collect();
+            if let Err(e) = server.publish_and_replicate(&topic, messages) {
+                return Self::error_response(e, ErrorCode::MpubFailed);
+            }
+            if Self::wants_receipt(&params) {
+                return Json(Self::publish_receipt(&ids, true)).into_response();
+            }
+            return "OK".into_response();
+        }
+        "BAD_REQUEST".into_response()
+    }
+    
+    /// Accept messages a peer copied from its topic, creating the topic as a
+    /// replica if it does not exist. An empty body only creates the topic.
+    async fn handle_replicate(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+        body: Bytes,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        if let Err(e) = validate_topic_name(topic_name) {
+            return Self::error_response(e.into(), ErrorCode::Invalid);
+        }
+        let messages = match replication::decode_batch(body) {
+            Ok(messages) => messages,
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": format!("INVALID_BODY {}", e)}))).into_response(),
+        };
+        
+        let created = !server.topics.contains_key(topic_name);
+        let topic = server.get_or_create_topic(topic_name.clone());
+        if created {
+            topic.set_replica(true);
+            server.persist_metadata();
+            tracing::info!("Created replica of topic {}", topic_name);
+        }
+        if let Err(e) = topic.publish_replicated(messages) {
+            return Self::error_response(e, ErrorCode::PubFailed);
+        }
+        "OK".into_response()
+    }
+    
+    /// Hold an HTTP publish while the topic has `max_topic_depth` messages
+    /// queued: for up to `pub_block_timeout` when the publisher passed
+    /// `blocking=1`, otherwise not at all. Returns the response to send when
+    /// the topic is still full.
+    async fn await_topic_capacity(&self, topic: &Topic, params: &HashMap<String, String>) -> Option<Response> {
+        let (max_depth, block_timeout) = {
+            let config = self.config.read();
+            (config.max_topic_depth, config.pub_block_timeout)
+        };
+        let is_full = || max_depth > 0 && topic.backlog() >= max_depth;
+        if !is_full() {
+            return None;
+        }
+        
+        if matches!(params.get("blocking").map(String::as_str), Some("1") | Some("true")) {
+            let deadline = Instant::now() + Duration::from_millis(block_timeout);
+            while Instant::now() < deadline {
+                sleep(CAPACITY_POLL_INTERVAL).await;
+                if !is_full() {
+                    return None;
                 }
             }
-            return "OK";
         }
-        "BAD_REQUEST"
+        
+        self.metrics.incr("messages.topic_full", 1);
+        Some((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, FULL_TOPIC_RETRY_AFTER)],
+            Json(serde_json::json!({"message": "TOPIC_FULL"})),
+        ).into_response())
+    }
+    
+    /// Response to an HTTP request that failed with `error`, reported with
+    /// the error's own code or else `fallback`
+    fn error_response(error: NsqError, fallback: ErrorCode) -> Response {
+        if error.code().is_none() {
+            tracing::warn!("HTTP request failed: {}", error);
+        }
+        let status = StatusCode::from_u16(error.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(serde_json::json!({"message": error.code_or(fallback).as_str()}))).into_response()
+    }
+    
+    /// Message headers sent by an HTTP publisher as `X-NSQ-Header-<key>`
+    /// request headers; keys are lowercase
+    fn http_message_headers(headers: &HeaderMap) -> std::result::Result<MessageHeaders, String> {
+        let message_headers = headers.iter()
+            .filter_map(|(name, value)| name.as_str().strip_prefix(HTTP_MESSAGE_HEADER_PREFIX).map(|key| (key, value)))
+            .map(|(key, value)| match value.to_str() {
+                Ok(value) => Ok((key.to_string(), value.to_string())),
+                Err(_) => Err(format!("header '{}' is not valid UTF-8", key)),
+            })
+            .collect::<std::result::Result<MessageHeaders, String>>()?;
+        validate_headers(&message_headers).map_err(|e| e.to_string())?;
+        Ok(message_headers)
+    }
+    
+    /// Check whether an HTTP publisher asked for a receipt
+    fn wants_receipt(params: &HashMap<String, String>) -> bool {
+        matches!(params.get("receipt").map(String::as_str), Some("1") | Some("true"))
     }
 
     async fn handle_topic_create(
         State(server): State<NsqdServer>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let Some(topic_name) = params.get("topic") { let _ = server.get_or_create_topic(topic_name.clone()); }
-        "OK"
+    ) -> Response {
+        if let Some(topic_name) = params.get("topic") {
+            if let Err(e) = validate_topic_name(topic_name) {
+                return Self::error_response(e.into(), ErrorCode::Invalid);
+            }
+            let _ = server.get_or_create_topic(topic_name.clone());
+        }
+        "OK".into_response()
     }
 
     async fn handle_topic_delete(
@@ -455,8 +2321,9 @@ impl NsqdServer {
         Query(params): Query<std::collections::HashMap<String, String>>,
     ) -> &'static str {
         if let Some(topic_name) = params.get("topic") {
-            if let Some(topic) = server.topics.read().get(topic_name).cloned() {
+            if let Some(topic) = server.get_topic(topic_name) {
                 let _ = topic.pause();
+                server.persist_metadata();
             }
         }
         "OK"
@@ -467,20 +2334,293 @@ impl NsqdServer {
         Query(params): Query<std::collections::HashMap<String, String>>,
     ) -> &'static str {
         if let Some(topic_name) = params.get("topic") {
-            if let Some(topic) = server.topics.read().get(topic_name).cloned() {
+            if let Some(topic) = server.get_topic(topic_name) {
                 let _ = topic.unpause();
+                server.persist_metadata();
             }
         }
         "OK"
     }
 
+    async fn handle_topic_empty(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        let Some(topic) = server.get_topic(topic_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+        };
+        
+        match topic.empty() {
+            Ok((depth, backend_depth)) => {
+                tracing::info!("Emptied topic {}: {} in memory, {} on disk", topic_name, depth, backend_depth);
+                Json(serde_json::json!({
+                    "topic": topic_name,
+                    "depth": depth,
+                    "backend_depth": backend_depth,
+                })).into_response()
+            }
+            Err(e) => {
+                tracing::warn!("Failed to empty topic {}: {}", topic_name, e);
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"message": format!("INTERNAL_ERROR {}", e)}))).into_response()
+            }
+        }
+    }
+
+    /// Discard the messages queued for a channel, leaving in-flight and
+    /// deferred ones alone
+    async fn handle_channel_empty(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        let Some(channel_name) = params.get("channel") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_CHANNEL"}))).into_response();
+        };
+        let Some(topic) = server.get_topic(topic_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+        };
+        let Some(channel) = topic.get_channel(channel_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "CHANNEL_NOT_FOUND"}))).into_response();
+        };
+        
+        match channel.empty() {
+            Ok((depth, backend_depth)) => {
+                tracing::info!("Emptied channel {}/{}: {} in memory, {} on disk", topic_name, channel_name, depth, backend_depth);
+                Json(serde_json::json!({
+                    "topic": topic_name,
+                    "channel": channel_name,
+                    "depth": depth,
+                    "backend_depth": backend_depth,
+                })).into_response()
+            }
+            Err(e) => {
+                tracing::warn!("Failed to empty channel {}/{}: {}", topic_name, channel_name, e);
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"message": format!("INTERNAL_ERROR {}", e)}))).into_response()
+            }
+        }
+    }
+
+    /// Return the next messages of a channel, or of the topic queue when no
+    /// channel is given, without consuming them. Bodies that are not UTF-8
+    /// are base64 encoded.
+    async fn handle_peek(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        let count = match params.get("count").map(|c| c.parse::<usize>()) {
+            None => DEFAULT_PEEK_COUNT,
+            Some(Ok(count)) if (1..=MAX_PEEK_COUNT).contains(&count) => count,
+            Some(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "INVALID_COUNT"}))).into_response(),
+        };
+        let Some(topic) = server.get_topic(topic_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+        };
+        
+        let messages = match params.get("channel") {
+            Some(channel_name) => match topic.get_channel(channel_name) {
+                Some(channel) => channel.peek(count),
+                None => return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "CHANNEL_NOT_FOUND"}))).into_response(),
+            },
+            None => topic.peek(count),
+        };
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(e) => {
+                tracing::warn!("Failed to peek topic {}: {}", topic_name, e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({"message": format!("INTERNAL_ERROR {}", e)}))).into_response();
+            }
+        };
+        
+        server.metrics.incr("http.peek", 1);
+        let messages: Vec<serde_json::Value> = messages.iter().map(|message| {
+            let (body, encoding) = match std::str::from_utf8(&message.body) {
+                Ok(text) => (text.to_string(), "utf8"),
+                Err(_) => (base64::engine::general_purpose::STANDARD.encode(&message.body), "base64"),
+            };
+            serde_json::json!({
+                "id": message.id.to_string(),
+                "timestamp": message.timestamp.to_rfc3339(),
+                "attempts": message.attempts,
+                "headers": message.headers,
+                "body": body,
+                "encoding": encoding,
+            })
+        }).collect();
+        Json(serde_json::json!({
+            "topic": topic_name,
+            "channel": params.get("channel"),
+            "messages": messages,
+        })).into_response()
+    }
+
+    async fn handle_topic_configure(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        let Some(topic) = server.get_topic(topic_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+        };
+        
+        // Only the settings given are changed
+        let mut retention = topic.retention();
+        let mut rate_limit = topic.rate_limit();
+        for (key, setting) in [
+            ("retention_ms", &mut retention.retention_ms),
+            ("retention_bytes", &mut retention.retention_bytes),
+            ("max_msgs_per_sec", &mut rate_limit.max_msgs_per_sec),
+            ("max_bytes_per_sec", &mut rate_limit.max_bytes_per_sec),
+        ] {
+            if let Some(value) = params.get(key) {
+                let Ok(value) = value.parse::<u64>() else {
+                    let message = format!("INVALID_{}", key.to_uppercase());
+                    return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response();
+                };
+                *setting = value;
+            }
+        }
+        let mut delivery = topic.delivery_settings();
+        if let Err(message) = server.parse_delivery_settings(&params, &mut delivery) {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response();
+        }
+        let mut mem_queue_size = topic.mem_queue_size();
+        if let Err(message) = Self::parse_override(&params, "mem_queue_size", 0..=usize::MAX, &mut mem_queue_size) {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response();
+        }
+        let mut durable = topic.durable();
+        if let Err(message) = Self::parse_override(&params, "durable", false..=true, &mut durable) {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response();
+        }
+        
+        topic.set_retention(retention);
+        topic.set_delivery_settings(delivery);
+        topic.set_mem_queue_size(mem_queue_size);
+        topic.set_durable(durable);
+        if rate_limit != topic.rate_limit() {
+            topic.set_rate_limit(rate_limit);
+        }
+        server.persist_metadata();
+        tracing::info!("Configured topic {}: retention_ms={} retention_bytes={} {:?} mem_queue_size={:?} durable={:?} {:?}",
+            topic_name, retention.retention_ms, retention.retention_bytes, delivery, mem_queue_size, durable, rate_limit);
+        Json(serde_json::json!({
+            "topic": topic_name,
+            "retention_ms": retention.retention_ms,
+            "retention_bytes": retention.retention_bytes,
+            "msg_timeout": delivery.msg_timeout,
+            "max_rdy_count": delivery.max_rdy_count,
+            "mem_queue_size": mem_queue_size,
+            "durable": topic.is_durable(),
+            "max_msgs_per_sec": rate_limit.max_msgs_per_sec,
+            "max_bytes_per_sec": rate_limit.max_bytes_per_sec,
+        })).into_response()
+    }
+
+    async fn handle_channel_configure(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let Some(topic_name) = params.get("topic") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_TOPIC"}))).into_response();
+        };
+        let Some(channel_name) = params.get("channel") else {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "MISSING_ARG_CHANNEL"}))).into_response();
+        };
+        let Some(topic) = server.get_topic(topic_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "TOPIC_NOT_FOUND"}))).into_response();
+        };
+        let Some(channel) = topic.get_channel(channel_name) else {
+            return (StatusCode::NOT_FOUND, Json(serde_json::json!({"message": "CHANNEL_NOT_FOUND"}))).into_response();
+        };
+        
+        let mut delivery = channel.delivery_settings();
+        if let Err(message) = server.parse_delivery_settings(&params, &mut delivery) {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response();
+        }
+        let ordered = match params.get("ordered").map(String::as_str) {
+            None => channel.is_ordered(),
+            Some("1") | Some("true") => true,
+            Some("0") | Some("false") => false,
+            Some(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": "INVALID_ORDERED"}))).into_response(),
+        };
+        
+        channel.set_delivery_settings(delivery);
+        channel.set_ordered(ordered);
+        server.persist_metadata();
+        tracing::info!("Configured channel {}/{}: {:?}, ordered: {}", topic_name, channel_name, delivery, ordered);
+        Json(serde_json::json!({
+            "topic": topic_name,
+            "channel": channel_name,
+            "msg_timeout": delivery.msg_timeout,
+            "max_rdy_count": delivery.max_rdy_count,
+            "ordered": ordered,
+        })).into_response()
+    }
+
+    /// Apply the `msg_timeout` and `max_rdy_count` overrides given in a query
+    fn parse_delivery_settings(
+        &self,
+        params: &std::collections::HashMap<String, String>,
+        delivery: &mut DeliverySettings,
+    ) -> std::result::Result<(), String> {
+        let max_msg_timeout = self.config.read().max_msg_timeout;
+        Self::parse_override(params, "msg_timeout", 1..=max_msg_timeout, &mut delivery.msg_timeout)?;
+        Self::parse_override(params, "max_rdy_count", 1..=u32::MAX, &mut delivery.max_rdy_count)
+    }
+
+    /// Update an override from a query parameter: an absent key leaves it
+    /// unchanged and an empty value clears it. Errors are the response message.
+    fn parse_override<T: std::str::FromStr + PartialOrd>(
+        params: &std::collections::HashMap<String, String>,
+        key: &str,
+        range: std::ops::RangeInclusive<T>,
+        setting: &mut Option<T>,
+    ) -> std::result::Result<(), String> {
+        match params.get(key).map(String::as_str) {
+            None => {}
+            Some("") => *setting = None,
+            Some(value) => match value.parse::<T>() {
+                Ok(value) if range.contains(&value) => *setting = Some(value),
+                _ => return Err(format!("INVALID_{}", key.to_uppercase())),
+            },
+        }
+        Ok(())
+    }
+
+    async fn handle_channel_create(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) else {
+            return "BAD_REQUEST".into_response();
+        };
+        if let Err(e) = validate_topic_name(topic_name).and_then(|_| validate_channel_name(channel_name)) {
+            return Self::error_response(e.into(), ErrorCode::Invalid);
+        }
+        
+        let topic = server.get_or_create_topic(topic_name.clone());
+        if topic.get_channel(channel_name).is_none() {
+            let _ = server.create_channel(&topic, channel_name);
+        }
+        "OK".into_response()
+    }
+
     async fn handle_channel_delete(
         State(server): State<NsqdServer>,
         Query(params): Query<std::collections::HashMap<String, String>>,
     ) -> &'static str {
         if let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) {
-            if let Some(topic) = server.topics.read().get(topic_name).cloned() {
-                let _ = topic.remove_channel(channel_name);
+            if let Some(topic) = server.get_topic(topic_name) {
+                let _ = server.delete_channel(&topic, channel_name);
             }
         }
         "OK"
@@ -491,23 +2631,90 @@ impl NsqdServer {
         Query(params): Query<std::collections::HashMap<String, String>>,
     ) -> &'static str {
         if let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) {
-            if let Some(topic) = server.topics.read().get(topic_name).cloned() {
+            if let Some(topic) = server.get_topic(topic_name) {
                 if let Some(channel) = topic.get_channel(channel_name) {
                     let _ = channel.pause();
+                    server.persist_metadata();
                 }
             }
         }
         "OK"
     }
 
+    async fn handle_channel_concurrency(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) else {
+            return "BAD_REQUEST".into_response();
+        };
+        let Some(max_in_flight) = params.get("max_in_flight").and_then(|v| v.parse::<u64>().ok()) else {
+            return "BAD_REQUEST".into_response();
+        };
+        if let Err(e) = validate_topic_name(topic_name).and_then(|_| validate_channel_name(channel_name)) {
+            return Self::error_response(e.into(), ErrorCode::Invalid);
+        }
+        
+        let topic = server.get_or_create_topic(topic_name.clone());
+        let channel = match topic.get_channel(channel_name) {
+            Some(channel) => channel,
+            None => match server.create_channel(&topic, channel_name) {
+                Ok(channel) => channel,
+                Err(_) => return "BAD_REQUEST".into_response(),
+            },
+        };
+        channel.set_max_in_flight(max_in_flight);
+        "OK".into_response()
+    }
+
+    async fn handle_channel_shadow(
+        State(server): State<NsqdServer>,
+        Query(params): Query<std::collections::HashMap<String, String>>,
+    ) -> Response {
+        let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) else {
+            return "BAD_REQUEST".into_response();
+        };
+        let Some(rate) = params.get("rate").and_then(|v| v.parse::<f64>().ok()) else {
+            return "BAD_REQUEST".into_response();
+        };
+        let shadow_topic = params.get("shadow_topic").cloned()
+            .unwrap_or_else(|| format!("{}.shadow", topic_name));
+        if !(0.0..=100.0).contains(&rate) || shadow_topic == *topic_name {
+            return "BAD_REQUEST".into_response();
+        }
+        if let Err(e) = validate_topic_name(topic_name)
+            .and_then(|_| validate_channel_name(channel_name))
+            .and_then(|_| validate_topic_name(&shadow_topic))
+        {
+            return Self::error_response(e.into(), ErrorCode::Invalid);
+        }
+        
+        let topic = server.get_or_create_topic(topic_name.clone());
+        let channel = match topic.get_channel(channel_name) {
+            Some(channel) => channel,
+            None => match server.create_channel(&topic, channel_name) {
+                Ok(channel) => channel,
+                Err(_) => return "BAD_REQUEST".into_response(),
+            },
+        };
+        
+        if rate > 0.0 {
+            channel.set_shadow(Some(ShadowConfig { topic: shadow_topic, rate }));
+        } else {
+            channel.set_shadow(None);
+        }
+        "OK".into_response()
+    }
+
     async fn handle_channel_unpause(
         State(server): State<NsqdServer>,
         Query(params): Query<std::collections::HashMap<String, String>>,
     ) -> &'static str {
         if let (Some(topic_name), Some(channel_name)) = (params.get("topic"), params.get("channel")) {
-            if let Some(topic) = server.topics.read().get(topic_name).cloned() {
+            if let Some(topic) = server.get_topic(topic_name) {
                 if let Some(channel) = topic.get_channel(channel_name) {
                     let _ = channel.unpause();
+                    server.persist_metadata();
                 }
             }
         }
@@ -523,9 +2730,25 @@ impl Clone for NsqdServer {
             stats: self.stats.clone(),
             topics: self.topics.clone(),
             clients: self.clients.clone(),
+            lookupd: self.lookupd.clone(),
+            replicator: self.replicator.clone(),
+            supervisor: self.supervisor.clone(),
+            connection_limits: self.connection_limits.clone(),
+            access_log: self.access_log.clone(),
+            tracer: self.tracer.clone(),
+            guids: self.guids.clone(),
+            alerter: self.alerter.clone(),
+            depth_check: self.depth_check.clone(),
+            phase: self.phase.clone(),
             tcp_listener: None,
             http_listener: None,
             https_listener: None,
         }
     }
 }
+
+/// Values of a sharded map, copied out so callers can call back into the
+/// map without deadlocking on a shard lock
+fn snapshot<K: Eq + std::hash::Hash, V: Clone>(map: &DashMap<K, V>) -> Vec<V> {
+    map.iter().map(|entry| entry.value().clone()).collect()
+}
@@ -0,0 +1,133 @@
+//! Channel depth snapshots
+//!
+//! On clean shutdown nsqd records how many messages each channel still held.
+//! The next start compares the depths it recovered against that record, so
+//! messages lost or duplicated across a restart show up in logs and metrics.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use nsq_common::{NsqError, Result};
+
+/// File name of the snapshot inside the data directory
+const SNAPSHOT_FILE: &str = "nsqd.depths.json";
+
+/// Messages a channel still owed its consumers: queued, in flight and deferred
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelDepth {
+    pub topic: String,
+    pub channel: String,
+    pub depth: u64,
+}
+
+/// Channel depths recorded at shutdown
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthSnapshot {
+    pub taken_at: chrono::DateTime<chrono::Utc>,
+    pub channels: Vec<ChannelDepth>,
+}
+
+/// A channel whose recovered depth differs from the snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthDiscrepancy {
+    pub topic: String,
+    pub channel: String,
+    pub expected: u64,
+    pub recovered: u64,
+}
+
+/// Result of comparing recovered depths with the shutdown snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthCheck {
+    pub snapshot_taken_at: chrono::DateTime<chrono::Utc>,
+    pub checked_at: chrono::DateTime<chrono::Utc>,
+    pub channels_checked: usize,
+    /// Messages in the snapshot that were not recovered
+    pub lost: u64,
+    /// Recovered messages beyond what the snapshot recorded
+    pub duplicated: u64,
+    pub discrepancies: Vec<DepthDiscrepancy>,
+}
+
+impl DepthSnapshot {
+    /// Snapshot the given depths now
+    pub fn new(channels: Vec<ChannelDepth>) -> Self {
+        Self {
+            taken_at: chrono::Utc::now(),
+            channels,
+        }
+    }
+
+    fn path(data_path: &Path) -> PathBuf {
+        data_path.join(SNAPSHOT_FILE)
+    }
+
+    /// Write the snapshot into the data directory
+    pub fn save(&self, data_path: &Path) -> Result<()> {
+        std::fs::create_dir_all(data_path).map_err(NsqError::Io)?;
+        let contents = serde_json::to_vec_pretty(self)?;
+        let tmp_path = Self::path(data_path).with_extension("json.tmp");
+        std::fs::write(&tmp_path, contents).map_err(NsqError::Io)?;
+        std::fs::rename(&tmp_path, Self::path(data_path)).map_err(NsqError::Io)
+    }
+
+    /// Read and remove the snapshot, so a later unclean shutdown is never
+    /// compared against a stale one
+    pub fn take(data_path: &Path) -> Result<Option<Self>> {
+        let path = Self::path(data_path);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(NsqError::Io(e)),
+        };
+        std::fs::remove_file(&path).map_err(NsqError::Io)?;
+        Ok(Some(serde_json::from_slice(&contents)?))
+    }
+
+    /// Compare recovered depths against the snapshot
+    pub fn compare(&self, recovered: &[ChannelDepth]) -> DepthCheck {
+        let mut recovered_depths: HashMap<(&str, &str), u64> = recovered
+            .iter()
+            .map(|c| ((c.topic.as_str(), c.channel.as_str()), c.depth))
+            .collect();
+
+        let mut discrepancies = Vec::new();
+        for expected in &self.channels {
+            let depth = recovered_depths
+                .remove(&(expected.topic.as_str(), expected.channel.as_str()))
+                .unwrap_or(0);
+            if depth != expected.depth {
+                discrepancies.push(DepthDiscrepancy {
+                    topic: expected.topic.clone(),
+                    channel: expected.channel.clone(),
+                    expected: expected.depth,
+                    recovered: depth,
+                });
+            }
+        }
+
+        // Channels that came back without being in the snapshot
+        for ((topic, channel), depth) in recovered_depths {
+            if depth > 0 {
+                discrepancies.push(DepthDiscrepancy {
+                    topic: topic.to_string(),
+                    channel: channel.to_string(),
+                    expected: 0,
+                    recovered: depth,
+                });
+            }
+        }
+
+        let lost = discrepancies.iter().map(|d| d.expected.saturating_sub(d.recovered)).sum();
+        let duplicated = discrepancies.iter().map(|d| d.recovered.saturating_sub(d.expected)).sum();
+
+        DepthCheck {
+            snapshot_taken_at: self.taken_at,
+            checked_at: chrono::Utc::now(),
+            channels_checked: self.channels.len(),
+            lost,
+            duplicated,
+            discrepancies,
+        }
+    }
+}
@@ -1,13 +1,15 @@
 //! Statistics and monitoring
 
 use std::sync::Arc;
-use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use dashmap::DashMap;
 use parking_lot::RwLock;
-use nsq_common::Metrics;
-use crate::topic::Topic;
+use nsq_common::{DiskUsage, Metrics};
+use crate::topic::{DeliverySettings, RetentionPolicy, Topic};
 use crate::client::Client;
+use crate::latency::LatencyHistogram;
+use crate::rate_limit::RateLimit;
 
 /// NSQd statistics
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,6 +64,7 @@ pub struct TopicStats {
     pub name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub paused: bool,
+    pub paused_at: Option<chrono::DateTime<chrono::Utc>>,
     pub message_count: u64,
     pub channel_count: u64,
     pub depth: u64,
@@ -70,6 +73,33 @@ pub struct TopicStats {
     pub deferred_count: u64,
     pub requeue_count: u64,
     pub timeout_count: u64,
+    pub retention: RetentionPolicy,
+    pub delivery: DeliverySettings,
+    pub mem_queue_size: Option<usize>,
+    pub rate_limit: RateLimit,
+    /// Messages rejected by the publish rate limit
+    pub rate_limited_count: u64,
+    /// Whether publishes are acknowledged only once on disk and fsynced
+    pub durable: bool,
+    /// Bytes of messages published durably
+    pub durable_published_bytes: u64,
+    /// Bytes durable publishes wrote to the disk queues of the topic and
+    /// its channels
+    pub durable_written_bytes: u64,
+    /// Disk queue fsyncs done for durable publishes
+    pub fsync_count: u64,
+    /// Time taken by the fsyncs of durable publishes
+    pub fsync_latency: E2eProcessingLatency,
+    /// Whether the topic mirrors another node's topic
+    pub replica: bool,
+    /// Messages removed from the disk backend by retention
+    pub pruned_count: u64,
+    /// Messages moved from memory to disk to stay within the memory budget
+    pub spilled_count: u64,
+    pub disk_usage: DiskUsage,
+    pub last_publish_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Most recent delivery on any channel
+    pub last_delivery_at: Option<chrono::DateTime<chrono::Utc>>,
     pub channels: Vec<ChannelStats>,
 }
 
@@ -80,14 +110,67 @@ pub struct ChannelStats {
     pub topic_name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub paused: bool,
+    pub paused_at: Option<chrono::DateTime<chrono::Utc>>,
     pub message_count: u64,
     pub depth: u64,
     pub backend_depth: u64,
+    /// Unread bytes of the disk queue the channel reads from
+    pub disk_unread_bytes: u64,
     pub in_flight_count: u64,
     pub deferred_count: u64,
     pub requeue_count: u64,
     pub timeout_count: u64,
+    pub finish_count: u64,
     pub client_count: u64,
+    pub max_in_flight: u64,
+    pub delivery: DeliverySettings,
+    /// Whether messages are delivered in key order
+    pub ordered: bool,
+    pub oldest_message_age_ms: u64,
+    pub shadow_topic: Option<String>,
+    pub shadow_rate: f64,
+    pub shadow_count: u64,
+    /// Messages taken out of the channel after exceeding --max-attempts
+    pub dead_letter_count: u64,
+    /// Messages delivered again from the disk backend by a backlog replay
+    pub replayed_count: u64,
+    pub last_delivery_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last subscription change, delivery, FIN or REQ, or the creation time
+    pub last_activity_at: chrono::DateTime<chrono::Utc>,
+    pub e2e_processing_latency: E2eProcessingLatency,
+}
+
+/// Latency quantiles estimated from a histogram, such as a channel's
+/// end-to-end processing latency from publish to FIN
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct E2eProcessingLatency {
+    pub count: u64,
+    pub percentiles: Vec<LatencyPercentile>,
+}
+
+/// Latency at a quantile, in nanoseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyPercentile {
+    pub quantile: f64,
+    pub value: u64,
+}
+
+impl E2eProcessingLatency {
+    /// Estimate the given quantiles from a histogram
+    pub fn from_histogram(histogram: &LatencyHistogram, quantiles: &[f64]) -> Self {
+        Self {
+            count: histogram.count(),
+            percentiles: quantiles
+                .iter()
+                .filter_map(|&quantile| {
+                    histogram.quantile(quantile).map(|latency| LatencyPercentile {
+                        quantile,
+                        value: latency.as_nanos() as u64,
+                    })
+                })
+                .collect(),
+        }
+    }
 }
 
 /// Client statistics
@@ -102,6 +185,7 @@ pub struct ClientStats {
     pub tls_cipher_suite: Option<String>,
     pub deflate: bool,
     pub snappy: bool,
+    pub zstd: bool,
     pub sample_rate: u32,
     pub heartbeat_interval: u64,
     pub output_buffer_size: usize,
@@ -122,6 +206,9 @@ pub struct ClientStats {
     pub bytes_sent: u64,
     pub commands_received: u64,
     pub commands_sent: u64,
+    pub finish_latency_p50_ms: Option<f64>,
+    pub finish_latency_p99_ms: Option<f64>,
+    pub connect_time: chrono::DateTime<chrono::Utc>,
 }
 
 /// Overall statistics
@@ -148,9 +235,9 @@ pub struct StatsCollector {
     /// Server information
     server_info: Arc<RwLock<ServerInfo>>,
     /// Topics
-    topics: Arc<RwLock<HashMap<String, Arc<Topic>>>>,
+    topics: Arc<DashMap<String, Arc<Topic>>>,
     /// Clients
-    clients: Arc<RwLock<HashMap<Uuid, Arc<Client>>>>,
+    clients: Arc<DashMap<Uuid, Arc<Client>>>,
     /// Metrics
     #[allow(dead_code)]
     metrics: Metrics,
@@ -159,8 +246,8 @@ pub struct StatsCollector {
 }
 
 impl StatsCollector {
-    /// Create a new statistics collector
-    pub fn new(metrics: Metrics) -> Self {
+    /// Create a new statistics collector reporting the given e2e latency quantiles
+    pub fn new(metrics: Metrics, e2e_processing_latency_percentile: Vec<f64>) -> Self {
         Self {
             server_info: Arc::new(RwLock::new(ServerInfo {
                 version: env!("CARGO_PKG_VERSION").to_string(),
@@ -188,13 +275,13 @@ impl StatsCollector {
                 tls_min_version: "1.2".to_string(),
                 statsd_address: None,
                 statsd_prefix: "nsq".to_string(),
-                e2e_processing_latency_percentile: vec![0.5, 0.75, 0.9, 0.95, 0.99],
+                e2e_processing_latency_percentile,
                 lookupd_tcp_addresses: Vec::new(),
                 disable_http: false,
                 disable_https: false,
             })),
-            topics: Arc::new(RwLock::new(HashMap::new())),
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            topics: Arc::new(DashMap::new()),
+            clients: Arc::new(DashMap::new()),
             metrics,
             start_time: std::time::Instant::now(),
         }
@@ -207,22 +294,22 @@ impl StatsCollector {
     
     /// Add a topic
     pub fn add_topic(&self, name: String, topic: Arc<Topic>) {
-        self.topics.write().insert(name, topic);
+        self.topics.insert(name, topic);
     }
     
     /// Remove a topic
     pub fn remove_topic(&self, name: &str) {
-        self.topics.write().remove(name);
+        self.topics.remove(name);
     }
     
     /// Add a client
     pub fn add_client(&self, id: Uuid, client: Arc<Client>) {
-        self.clients.write().insert(id, client);
+        self.clients.insert(id, client);
     }
     
     /// Remove a client
     pub fn remove_client(&self, id: &Uuid) {
-        self.clients.write().remove(id);
+        self.clients.remove(id);
     }
     
     /// Get statistics
@@ -247,7 +334,10 @@ impl StatsCollector {
     
     /// Get topic statistics
     fn get_topic_stats(&self) -> Vec<TopicStats> {
-        let topics = self.topics.read();
+        let quantiles = self.server_info.read().e2e_processing_latency_percentile.clone();
+        let topics: Vec<(String, Arc<Topic>)> = self.topics.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
         let mut topic_stats = Vec::new();
         
         for (name, topic) in topics.iter() {
@@ -257,19 +347,37 @@ impl StatsCollector {
             
             for channel in channels {
                 let channel_stat = channel.stats();
+                let shadow = channel.shadow();
                 channel_stats.push(ChannelStats {
                     name: channel.name.clone(),
                     topic_name: channel.topic_name.clone(),
                     created_at: channel.created_at,
                     paused: channel.is_paused(),
+                    paused_at: channel.paused_at(),
                     message_count: channel_stat.message_count,
                     depth: channel_stat.depth,
                     backend_depth: channel_stat.backend_depth,
+                    disk_unread_bytes: channel.disk_usage().unread_bytes,
                     in_flight_count: channel_stat.in_flight_count,
                     deferred_count: channel_stat.deferred_count,
                     requeue_count: channel_stat.requeue_count,
                     timeout_count: channel_stat.timeout_count,
+                    finish_count: channel_stat.finish_count,
                     client_count: channel_stat.client_count,
+                    max_in_flight: channel_stat.max_in_flight,
+                    delivery: channel.delivery_settings(),
+                    ordered: channel.is_ordered(),
+                    oldest_message_age_ms: channel.oldest_message_age()
+                        .map(|age| age.as_millis() as u64)
+                        .unwrap_or(0),
+                    shadow_topic: shadow.as_ref().map(|s| s.topic.clone()),
+                    shadow_rate: shadow.map(|s| s.rate).unwrap_or(0.0),
+                    shadow_count: channel_stat.shadow_count,
+                    dead_letter_count: channel_stat.dead_letter_count,
+                    replayed_count: channel_stat.replayed_count,
+                    last_delivery_at: channel_stat.last_delivery_at,
+                    last_activity_at: channel.last_activity_at(),
+                    e2e_processing_latency: E2eProcessingLatency::from_histogram(&channel.e2e_latency(), &quantiles),
                 });
             }
             
@@ -277,6 +385,7 @@ impl StatsCollector {
                 name: name.clone(),
                 created_at: topic.created_at,
                 paused: topic.is_paused(),
+                paused_at: topic_stat.paused_at,
                 message_count: topic_stat.message_count,
                 channel_count: topic_stat.channel_count,
                 depth: topic_stat.depth,
@@ -285,6 +394,22 @@ impl StatsCollector {
                 deferred_count: topic_stat.deferred_count,
                 requeue_count: topic_stat.requeue_count,
                 timeout_count: topic_stat.timeout_count,
+                retention: topic.retention(),
+                delivery: topic.delivery_settings(),
+                mem_queue_size: topic.mem_queue_size(),
+                rate_limit: topic.rate_limit(),
+                rate_limited_count: topic_stat.rate_limited_count,
+                durable: topic.is_durable(),
+                durable_published_bytes: topic_stat.durable_published_bytes,
+                durable_written_bytes: topic_stat.durable_written_bytes,
+                fsync_count: topic_stat.fsync_count,
+                fsync_latency: E2eProcessingLatency::from_histogram(&topic.fsync_latency(), &quantiles),
+                replica: topic.is_replica(),
+                pruned_count: topic_stat.pruned_count,
+                spilled_count: topic_stat.spilled_count,
+                disk_usage: topic.disk_usage(),
+                last_publish_at: topic_stat.last_publish_at,
+                last_delivery_at: channel_stats.iter().filter_map(|c| c.last_delivery_at).max(),
                 channels: channel_stats,
             });
         }
@@ -292,30 +417,34 @@ impl StatsCollector {
         topic_stats
     }
     
-    /// Get client statistics
-    fn get_client_stats(&self) -> Vec<ClientStats> {
-        let clients = self.clients.read();
+    /// Get statistics of every connected client
+    pub fn get_client_stats(&self) -> Vec<ClientStats> {
+        let clients: Vec<(Uuid, Arc<Client>)> = self.clients.iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
         let mut client_stats = Vec::new();
         
         for (id, client) in clients.iter() {
             let stats = client.stats();
+            let info = client.info();
             client_stats.push(ClientStats {
                 id: *id,
-                remote_addr: client.info.remote_addr.clone(),
-                user_agent: client.info.user_agent.clone(),
-                client_version: client.info.client_version.clone(),
-                hostname: client.info.hostname.clone(),
-                tls_version: client.info.tls_version.clone(),
-                tls_cipher_suite: client.info.tls_cipher_suite.clone(),
-                deflate: client.info.deflate,
-                snappy: client.info.snappy,
-                sample_rate: client.info.sample_rate,
-                heartbeat_interval: client.info.heartbeat_interval.as_millis() as u64,
-                output_buffer_size: client.info.output_buffer_size,
-                output_buffer_timeout: client.info.output_buffer_timeout.as_millis() as u64,
-                max_rdy_count: client.info.max_rdy_count,
-                max_msg_timeout: client.info.max_msg_timeout.as_millis() as u64,
-                msg_timeout: client.info.msg_timeout.as_millis() as u64,
+                remote_addr: info.remote_addr.clone(),
+                user_agent: info.user_agent.clone(),
+                client_version: info.client_version.clone(),
+                hostname: info.hostname.clone(),
+                tls_version: info.tls_version.clone(),
+                tls_cipher_suite: info.tls_cipher_suite.clone(),
+                deflate: info.deflate,
+                snappy: info.snappy,
+                zstd: info.zstd,
+                sample_rate: info.sample_rate,
+                heartbeat_interval: info.heartbeat_interval.as_millis() as u64,
+                output_buffer_size: info.output_buffer_size,
+                output_buffer_timeout: info.output_buffer_timeout.as_millis() as u64,
+                max_rdy_count: info.max_rdy_count,
+                max_msg_timeout: info.max_msg_timeout.as_millis() as u64,
+                msg_timeout: info.msg_timeout.as_millis() as u64,
                 state: format!("{:?}", client.state()),
                 topic: client.topic(),
                 channel: client.channel(),
@@ -325,10 +454,13 @@ impl StatsCollector {
                 messages_finished: stats.messages_finished,
                 messages_requeued: stats.messages_requeued,
                 messages_timed_out: stats.messages_timed_out,
-                bytes_received: stats.bytes_received,
-                bytes_sent: stats.bytes_sent,
+                bytes_received: client.codec_counters().bytes_in(),
+                bytes_sent: client.codec_counters().bytes_out(),
                 commands_received: stats.commands_received,
                 commands_sent: stats.commands_sent,
+                finish_latency_p50_ms: stats.finish_latency.quantile_ms(0.5),
+                finish_latency_p99_ms: stats.finish_latency.quantile_ms(0.99),
+                connect_time: info.connect_time,
             });
         }
         
@@ -2,11 +2,16 @@
 
 use std::sync::Arc;
 use std::collections::HashMap;
-use parking_lot::RwLock;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use nsq_protocol::Message;
-use nsq_common::{Metrics, Result, NsqError, validate_topic_channel_name};
+use nsq_common::{DiskMark, DiskQueue, DiskReplay, DiskUsage, Metrics, Result, NsqError, validate_channel_name, validate_topic_name};
 use crate::channel::Channel;
-use crate::message::MessageQueue;
+use crate::diagnostics::{LockStats, QueueMemory};
+use crate::latency::LatencyHistogram;
+use crate::message::{InFlightMessage, MessageQueue};
+use crate::rate_limit::{RateLimit, RateLimiter};
 
 /// Topic represents a message topic
 pub struct Topic {
@@ -14,18 +19,95 @@ pub struct Topic {
     pub name: String,
     /// Channels in this topic
     channels: Arc<RwLock<HashMap<String, Arc<Channel>>>>,
-    /// Message queue for this topic
+    /// Messages not handed to the channels yet, kept while the topic is
+    /// paused or has no channels
     message_queue: Arc<MessageQueue>,
+    /// Held while messages are copied to the channels, so every channel
+    /// receives them in publish order
+    fan_out: Mutex<()>,
     /// Topic statistics
     stats: Arc<RwLock<TopicStats>>,
     /// Metrics
     metrics: Metrics,
     /// Topic creation time
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Pause state, shared with the topic's channels
+    pause: Arc<RwLock<TopicPause>>,
+    /// Limits applied to the disk backends by the retention task
+    retention: Arc<RwLock<RetentionPolicy>>,
+    /// Delivery overrides shared with the topic's channels
+    delivery: Arc<RwLock<DeliverySettings>>,
+    /// Memory queue size given at creation, used when no override is set
+    default_mem_queue_size: usize,
+    /// Memory queue size override
+    mem_queue_size: Arc<RwLock<Option<usize>>>,
+    /// Publish rate limit
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// Whether the topic was created to mirror another node's topic
+    replica: Arc<RwLock<bool>>,
+    /// Whether publishes are durable when no override is set
+    default_durable: bool,
+    /// Durable publish override
+    durable: Arc<RwLock<Option<bool>>>,
+    /// Time taken by the fsyncs of durable publishes
+    fsync_latency: Arc<RwLock<LatencyHistogram>>,
+}
+
+/// How long and how much a topic keeps in its disk backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Prune messages older than this many milliseconds (0 = no limit)
+    pub retention_ms: u64,
+    /// Prune the oldest messages while the backend is larger than this many bytes (0 = no limit)
+    pub retention_bytes: u64,
+}
+
+impl RetentionPolicy {
+    /// Check whether any limit is set
+    pub fn is_enabled(&self) -> bool {
+        self.retention_ms > 0 || self.retention_bytes > 0
+    }
+}
+
+/// Delivery settings overridden for a topic or channel; unset values fall
+/// back to the topic's and then to the daemon's
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliverySettings {
+    /// Milliseconds a delivered message may stay in flight before it is requeued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_timeout: Option<u64>,
+    /// Highest RDY count subscribers may set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rdy_count: Option<u32>,
+}
+
+impl DeliverySettings {
+    /// Fill unset values from `fallback`
+    pub fn or(self, fallback: DeliverySettings) -> Self {
+        Self {
+            msg_timeout: self.msg_timeout.or(fallback.msg_timeout),
+            max_rdy_count: self.max_rdy_count.or(fallback.max_rdy_count),
+        }
+    }
+}
+
+/// Pause state of a topic. While paused, published messages stay in the
+/// topic's queue and are not handed to its channels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopicPause {
+    /// When the topic was paused, if it is
+    pub paused_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TopicPause {
+    /// Check whether the topic is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
 }
 
 /// Topic statistics
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TopicStats {
     pub message_count: u64,
     pub channel_count: u64,
@@ -35,21 +117,21 @@ pub struct TopicStats {
     pub deferred_count: u64,
     pub requeue_count: u64,
     pub timeout_count: u64,
-}
-
-impl Default for TopicStats {
-    fn default() -> Self {
-        Self {
-            message_count: 0,
-            channel_count: 0,
-            depth: 0,
-            backend_depth: 0,
-            in_flight_count: 0,
-            deferred_count: 0,
-            requeue_count: 0,
-            timeout_count: 0,
-        }
-    }
+    /// Messages removed from the disk backend by retention
+    pub pruned_count: u64,
+    /// Messages moved from memory to disk to stay within the memory budget
+    pub spilled_count: u64,
+    /// Messages rejected by the publish rate limit
+    pub rate_limited_count: u64,
+    /// Bytes of messages published durably
+    pub durable_published_bytes: u64,
+    /// Bytes durable publishes wrote to the disk queues of the topic and
+    /// its channels
+    pub durable_written_bytes: u64,
+    /// Disk queue fsyncs done for durable publishes
+    pub fsync_count: u64,
+    pub paused_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_publish_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Topic {
@@ -60,7 +142,7 @@ impl Topic {
         disk_queue: Option<nsq_common::DiskQueue>,
         metrics: Metrics,
     ) -> Result<Self> {
-        validate_topic_channel_name(&name)?;
+        validate_topic_name(&name)?;
         
         let message_queue = Arc::new(MessageQueue::new(max_memory_size, disk_queue, metrics.clone()));
         
@@ -68,26 +150,59 @@ impl Topic {
             name,
             channels: Arc::new(RwLock::new(HashMap::new())),
             message_queue,
+            fan_out: Mutex::new(()),
             stats: Arc::new(RwLock::new(TopicStats::default())),
             metrics,
             created_at: chrono::Utc::now(),
+            pause: Arc::new(RwLock::new(TopicPause::default())),
+            retention: Arc::new(RwLock::new(RetentionPolicy::default())),
+            delivery: Arc::new(RwLock::new(DeliverySettings::default())),
+            default_mem_queue_size: max_memory_size,
+            mem_queue_size: Arc::new(RwLock::new(None)),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(RateLimit::default()))),
+            replica: Arc::new(RwLock::new(false)),
+            default_durable: false,
+            durable: Arc::new(RwLock::new(None)),
+            fsync_latency: Arc::new(RwLock::new(LatencyHistogram::new())),
         })
     }
     
-    /// Add a channel to this topic
-    pub fn add_channel(&self, channel_name: String) -> Result<Arc<Channel>> {
-        validate_topic_channel_name(&channel_name)?;
+    /// Make publishes durable unless the topic overrides it
+    pub fn with_default_durable(mut self, durable: bool) -> Self {
+        self.default_durable = durable;
+        self
+    }
+    
+    /// Check whether the topic mirrors another node's topic
+    pub fn is_replica(&self) -> bool {
+        *self.replica.read()
+    }
+    
+    /// Mark the topic as mirroring another node's topic
+    pub fn set_replica(&self, replica: bool) {
+        *self.replica.write() = replica;
+    }
+    
+    /// Add a channel to this topic, overflowing to `disk_queue` when given.
+    /// It receives the messages published from now on; call `pump` to hand
+    /// it those waiting in the topic's queue.
+    pub fn add_channel(&self, channel_name: String, disk_queue: Option<DiskQueue>) -> Result<Arc<Channel>> {
+        validate_channel_name(&channel_name)?;
         
+        let _fan_out = self.fan_out.lock();
         let mut channels = self.channels.write();
         
         if channels.contains_key(&channel_name) {
             return Err(NsqError::Validation("Channel already exists".to_string()));
         }
         
+        let mem_queue_size = self.mem_queue_size().unwrap_or(self.default_mem_queue_size);
         let channel = Arc::new(Channel::new(
             channel_name.clone(),
             self.name.clone(),
-            self.message_queue.clone(),
+            Arc::new(MessageQueue::new(mem_queue_size, disk_queue, self.metrics.clone())),
+            self.delivery.clone(),
+            self.pause.clone(),
             self.metrics.clone(),
         )?);
         
@@ -121,7 +236,7 @@ impl Topic {
             self.metrics.incr("channels.removed", 1);
             Ok(())
         } else {
-            Err(NsqError::Validation("Channel not found".to_string()))
+            Err(NsqError::ChannelNotFound(channel_name.to_string()))
         }
     }
     
@@ -132,117 +247,501 @@ impl Topic {
     
     /// Publish a message to this topic
     pub fn publish(&self, message: Message) -> Result<()> {
-        self.message_queue.put(message)?;
+        self.acquire_rate(1, message.body.len() as u64)?;
+        self.publish_admitted(vec![message])
+    }
+    
+    /// Publish messages the rate limit already admitted. On a durable topic
+    /// they are on disk and fsynced when this returns, and a publish that
+    /// fails leaves none of them behind wherever that can be undone.
+    fn publish_admitted(&self, messages: Vec<Message>) -> Result<()> {
+        let count = messages.len() as u64;
+        let durable = self.is_durable();
+        let published_bytes: u64 = messages.iter().map(|message| message.size() as u64).sum();
+        let (channels, written, held_mark, channel_marks) = {
+            let _fan_out = self.fan_out.lock();
+            let channels = self.get_channels();
+            let hold = channels.is_empty() || self.is_paused();
+            let mut written = 0;
+            let mut held_mark = None;
+            let mut channel_marks = Vec::new();
+            if !hold && (self.message_queue.depth() > 0 || self.message_queue.backend_depth() > 0) {
+                // Older messages are still waiting, so they go first
+                written += self.drain_to_channels(&channels, durable)?.1;
+            }
+            
+            if durable {
+                let _span = tracing::info_span!("publish", topic = %self.name, messages = count).entered();
+                if hold {
+                    // Kept until a channel can take them
+                    let (bytes, mark) = self.message_queue.put_batch_to_disk(&messages)?;
+                    written += bytes;
+                    held_mark = Some(mark);
+                } else {
+                    let (bytes, marks) = Self::copy_batch_to_channels(&channels, &messages)?;
+                    written += bytes;
+                    channel_marks = marks;
+                }
+            } else {
+                for message in messages {
+                    let _span = tracing::info_span!("publish", topic = %self.name, message_id = %message.id).entered();
+                    match hold {
+                        true => self.message_queue.put(message)?,
+                        false => Self::copy_to_channels(&channels, &message)?,
+                    }
+                }
+            }
+            (channels, written, held_mark, channel_marks)
+        };
+        // Fsync outside the fan-out lock, so other publishes need not wait
+        // for it, and only then let dispatchers at the batch
+        if durable {
+            if let Err(e) = self.sync_durable(&channels, published_bytes, written) {
+                if let Some(mark) = held_mark {
+                    match self.message_queue.rollback_disk(mark) {
+                        Ok(true) => {}
+                        Ok(false) => tracing::warn!("Topic {} already handed on part of a failed publish, keeping it", self.name),
+                        Err(e) => tracing::warn!("Failed to take a failed publish back from topic {}: {}", self.name, e),
+                    }
+                }
+                Self::rollback_channels(&channels, channel_marks);
+                return Err(e);
+            }
+            channels.iter().for_each(|channel| channel.wake());
+        }
         
         {
             let mut stats = self.stats.write();
-            stats.message_count += 1;
+            stats.message_count += count;
             stats.depth = self.message_queue.depth() as u64;
+            stats.last_publish_at = Some(chrono::Utc::now());
         }
         
-        self.metrics.incr("messages.published", 1);
-        
-        // Distribute message to all channels
-        let channels = self.get_channels();
+        self.metrics.incr("messages.published", count);
+        Ok(())
+    }
+    
+    /// Give every channel its own copy of a message. A channel that cannot
+    /// take its copy fails the publish, and the copies already given are
+    /// taken back, so retrying it does not duplicate the message.
+    fn copy_to_channels(channels: &[Arc<Channel>], message: &Message) -> Result<()> {
+        let mut marks = Vec::with_capacity(channels.len());
+        for channel in channels {
+            match channel.put_message(message.clone()) {
+                Ok(mark) => marks.push(mark),
+                Err(e) => {
+                    tracing::warn!("Failed to distribute message to channel {}: {}", channel.name, e);
+                    for (channel, mark) in channels.iter().zip(marks) {
+                        match channel.take_back(message.id, mark) {
+                            Ok(true) => {}
+                            Ok(false) => tracing::warn!("Channel {} already delivered a failed publish, keeping it", channel.name),
+                            Err(e) => tracing::warn!("Failed to take a failed publish back from channel {}: {}", channel.name, e),
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Append a batch to every channel's disk queue without waking their
+    /// dispatchers, returning how many bytes were written and each channel's
+    /// mark, in order. When a channel cannot take it, the batch is taken back
+    /// from the channels that already have it, so retrying the publish does
+    /// not duplicate it.
+    fn copy_batch_to_channels(channels: &[Arc<Channel>], messages: &[Message]) -> Result<(u64, Vec<DiskMark>)> {
+        let mut written = 0;
+        let mut marks = Vec::with_capacity(channels.len());
         for channel in channels {
-            if let Err(e) = channel.distribute_message() {
-                tracing::warn!("Failed to distribute message to channel {}: {}", channel.name, e);
+            match channel.put_messages_durable(messages) {
+                Ok((bytes, mark)) => {
+                    written += bytes;
+                    marks.push(mark);
+                }
+                Err(e) => {
+                    Self::rollback_channels(channels, marks);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((written, marks))
+    }
+    
+    /// Take a failed publish back from the channels that got it, given their
+    /// marks in channel order
+    fn rollback_channels(channels: &[Arc<Channel>], marks: Vec<DiskMark>) {
+        for (channel, mark) in channels.iter().zip(marks) {
+            match channel.rollback_durable(mark) {
+                Ok(true) => {}
+                Ok(false) => tracing::warn!("Channel {} already delivered part of a failed publish, keeping it", channel.name),
+                Err(e) => tracing::warn!("Failed to take a failed publish back from channel {}: {}", channel.name, e),
             }
         }
+    }
+    
+    /// Move every message waiting in the topic's queue to the channels, in
+    /// order, returning how many were moved and how many bytes that wrote
+    /// to disk. A message the channels cannot all take goes back to the
+    /// front of the queue. When `durable`, dispatchers are left for the
+    /// caller to wake once the copies are synced.
+    fn drain_to_channels(&self, channels: &[Arc<Channel>], durable: bool) -> Result<(usize, u64)> {
+        let mut drained = 0;
+        let mut written = 0;
+        while let Some(message) = self.message_queue.get()? {
+            let copied = match durable {
+                true => Self::copy_batch_to_channels(channels, std::slice::from_ref(&message)).map(|(bytes, _)| bytes),
+                false => Self::copy_to_channels(channels, &message).map(|_| 0),
+            };
+            match copied {
+                Ok(bytes) => {
+                    written += bytes;
+                    drained += 1;
+                }
+                Err(e) => {
+                    // No channel kept a copy, so it waits for the next attempt
+                    self.message_queue.put_front(message);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((drained, written))
+    }
+    
+    /// Fsync the disk queues a durable publish or hand-over wrote to,
+    /// recording how long it took and how many bytes were written for how
+    /// many published
+    fn sync_durable(&self, channels: &[Arc<Channel>], published_bytes: u64, written_bytes: u64) -> Result<()> {
+        let start = Instant::now();
+        let mut synced = u64::from(self.message_queue.sync_pending()?);
+        for channel in channels {
+            synced += u64::from(channel.sync_pending()?);
+        }
+        self.fsync_latency.write().record(start.elapsed());
         
+        {
+            let mut stats = self.stats.write();
+            stats.durable_published_bytes += published_bytes;
+            stats.durable_written_bytes += written_bytes;
+            stats.fsync_count += synced;
+        }
+        self.metrics.incr("publish.durable_published_bytes", published_bytes);
+        self.metrics.incr("publish.durable_written_bytes", written_bytes);
+        self.metrics.incr("publish.fsyncs", synced);
         Ok(())
     }
     
+    /// Hand the messages waiting in the topic's queue to its channels,
+    /// unless it is paused or has none, returning how many were handed over
+    pub fn pump(&self) -> Result<usize> {
+        let durable = self.is_durable();
+        let (channels, drained, written) = {
+            let _fan_out = self.fan_out.lock();
+            let channels = self.get_channels();
+            if channels.is_empty() || self.is_paused() {
+                return Ok(0);
+            }
+            let (drained, written) = self.drain_to_channels(&channels, durable)?;
+            (channels, drained, written)
+        };
+        if durable && drained > 0 {
+            self.sync_durable(&channels, 0, written)?;
+            channels.iter().for_each(|channel| channel.wake());
+        }
+        Ok(drained)
+    }
+    
     /// Publish multiple messages
+    ///
+    /// The rate limit admits or rejects the batch as a whole.
     pub fn publish_multiple(&self, messages: Vec<Message>) -> Result<()> {
-        for message in messages {
-            self.publish(message)?;
-        }
+        let bytes = messages.iter().map(|message| message.body.len() as u64).sum();
+        self.acquire_rate(messages.len() as u64, bytes)?;
+        self.publish_admitted(messages)
+    }
+    
+    /// Publish messages copied from another node, which were already
+    /// admitted by the rate limit there
+    pub fn publish_replicated(&self, messages: Vec<Message>) -> Result<()> {
+        let count = messages.len() as u64;
+        self.publish_admitted(messages)?;
+        self.metrics.incr("messages.replica_received", count);
         Ok(())
     }
     
+    /// Take the rate limit allowance for a publish, counting rejected messages
+    fn acquire_rate(&self, count: u64, bytes: u64) -> Result<()> {
+        let mut rate_limiter = self.rate_limiter.lock();
+        if rate_limiter.try_acquire(count, bytes) {
+            return Ok(());
+        }
+        let limit = rate_limiter.limit();
+        drop(rate_limiter);
+        
+        self.stats.write().rate_limited_count += count;
+        self.metrics.incr("messages.rate_limited", count);
+        Err(NsqError::RateLimited(format!("topic {} is limited to {}", self.name, limit)))
+    }
+    
+    /// Get the publish rate limit
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limiter.lock().limit()
+    }
+    
+    /// Set the publish rate limit
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.rate_limiter.lock().set_limit(limit);
+    }
+    
     /// Get topic statistics
     pub fn stats(&self) -> TopicStats {
         let mut stats = self.stats.read().clone();
         
         // Update real-time stats
         stats.depth = self.message_queue.depth() as u64;
-        stats.in_flight_count = self.message_queue.in_flight_count() as u64;
-        stats.deferred_count = self.message_queue.deferred_count() as u64;
+        stats.in_flight_count = self.in_flight_count() as u64;
+        stats.deferred_count = self.deferred_count() as u64;
         
-        // Aggregate channel stats
-        let channels = self.get_channels();
-        for channel in channels {
-            let channel_stats = channel.stats();
-            stats.backend_depth += channel_stats.backend_depth;
-        }
+        stats.backend_depth = self.message_queue.backend_depth();
+        stats.paused_at = self.paused_at();
         
         stats
     }
     
-    /// Get message queue depth
+    /// Up to `count` messages waiting in the topic's own queue, without consuming them
+    pub fn peek(&self, count: usize) -> Result<Vec<Message>> {
+        self.message_queue.peek(count)
+    }
+    
+    /// Reader of the messages the topic's disk queue already handed out that
+    /// are still on disk, oldest first; None without a disk queue
+    pub fn replay(&self) -> Result<Option<DiskReplay>> {
+        self.message_queue.replay()
+    }
+    
+    /// Contention on the topic's queue lock
+    pub fn queue_lock_stats(&self) -> LockStats {
+        self.message_queue.lock_stats()
+    }
+    
+    /// Messages and bytes the topic's own queue holds in memory
+    pub fn memory_usage(&self) -> QueueMemory {
+        self.message_queue.memory_usage()
+    }
+    
+    /// Bytes of queued messages the topic and its channels hold in memory,
+    /// which spilling can move to disk
+    pub fn queued_bytes(&self) -> usize {
+        self.memory_usage().queued_bytes
+            + self.get_channels().iter().map(|channel| channel.memory_usage().queued_bytes).sum::<usize>()
+    }
+    
+    /// Bytes held in memory by the topic's queue and its channels
+    pub fn memory_bytes(&self) -> usize {
+        self.memory_usage().total_bytes()
+            + self.get_channels().iter().map(|channel| channel.memory_usage().total_bytes()).sum::<usize>()
+    }
+    
+    /// Get the depth of the topic's own memory queue
     pub fn depth(&self) -> usize {
         self.message_queue.depth()
     }
     
-    /// Get in-flight count
-    pub fn in_flight_count(&self) -> usize {
-        self.message_queue.in_flight_count()
+    /// Most messages waiting in the topic's own queue or in any one of its
+    /// channels, in memory and on disk
+    pub fn backlog(&self) -> u64 {
+        self.get_channels().iter()
+            .map(|channel| channel.depth() as u64 + channel.backend_depth())
+            .fold(self.depth() as u64 + self.backend_depth(), u64::max)
     }
     
-    /// Get deferred count
-    pub fn deferred_count(&self) -> usize {
-        self.message_queue.deferred_count()
+    /// Fsync the disk queues of the topic and its channels if they have
+    /// writes older than their sync timeout
+    pub fn sync_if_due(&self) -> Result<()> {
+        self.message_queue.sync_if_due()?;
+        for channel in self.get_channels() {
+            channel.sync_if_due()?;
+        }
+        Ok(())
     }
     
-    /// Process deferred messages
-    pub fn process_deferred(&self) -> Result<()> {
-        let ready_messages = self.message_queue.process_deferred()?;
-        
-        for message in ready_messages {
-            self.publish(message)?;
+    /// Remove fully consumed disk queue files of the topic and its channels,
+    /// returning how many bytes were freed
+    pub fn compact(&self) -> Result<u64> {
+        let mut freed = self.message_queue.compact_backend()?;
+        for channel in self.get_channels() {
+            freed += channel.compact()?;
         }
-        
-        Ok(())
+        Ok(freed)
     }
     
-    /// Clean up timed out messages
-    pub fn cleanup_timeouts(&self) -> Result<()> {
-        let timed_out_messages = self.message_queue.cleanup_timeouts()?;
-        
-        {
-            let mut stats = self.stats.write();
-            stats.timeout_count += timed_out_messages.len() as u64;
+    /// Space the disk queues of the topic and its channels take on disk
+    pub fn disk_usage(&self) -> DiskUsage {
+        self.get_channels().iter()
+            .map(|channel| channel.disk_usage())
+            .fold(self.message_queue.disk_usage(), |total, usage| total + usage)
+    }
+    
+    /// Get the number of messages waiting in the topic's own disk queue
+    pub fn backend_depth(&self) -> u64 {
+        self.message_queue.backend_depth()
+    }
+    
+    /// Discard the messages queued on the topic and its channels without
+    /// deleting it, returning how many were discarded from memory and from disk
+    pub fn empty(&self) -> Result<(usize, u64)> {
+        let (mut memory, mut disk) = self.message_queue.empty()?;
+        for channel in self.get_channels() {
+            let (channel_memory, channel_disk) = channel.empty()?;
+            memory += channel_memory;
+            disk += channel_disk;
         }
+        self.metrics.incr("topics.emptied", 1);
+        Ok((memory, disk))
+    }
+    
+    /// Get the retention policy
+    pub fn retention(&self) -> RetentionPolicy {
+        *self.retention.read()
+    }
+    
+    /// Set the retention policy
+    pub fn set_retention(&self, retention: RetentionPolicy) {
+        *self.retention.write() = retention;
+    }
+    
+    /// Get the delivery overrides
+    pub fn delivery_settings(&self) -> DeliverySettings {
+        *self.delivery.read()
+    }
+    
+    /// Set the delivery overrides; channels without their own pick them up
+    /// on their next delivery or RDY
+    pub fn set_delivery_settings(&self, delivery: DeliverySettings) {
+        *self.delivery.write() = delivery;
+    }
+    
+    /// Get the durable publish override
+    pub fn durable(&self) -> Option<bool> {
+        *self.durable.read()
+    }
+    
+    /// Set or clear the durable publish override
+    pub fn set_durable(&self, durable: Option<bool>) {
+        *self.durable.write() = durable;
+    }
+    
+    /// Check whether publishes are acknowledged only once on disk and fsynced
+    pub fn is_durable(&self) -> bool {
+        self.durable().unwrap_or(self.default_durable)
+    }
+    
+    /// Time taken by the fsyncs of durable publishes
+    pub fn fsync_latency(&self) -> LatencyHistogram {
+        self.fsync_latency.read().clone()
+    }
+    
+    /// Get the memory queue size override
+    pub fn mem_queue_size(&self) -> Option<usize> {
+        *self.mem_queue_size.read()
+    }
+    
+    /// Set or clear the memory queue size override
+    pub fn set_mem_queue_size(&self, mem_queue_size: Option<usize>) {
+        *self.mem_queue_size.write() = mem_queue_size;
+        let max_memory_size = mem_queue_size.unwrap_or(self.default_mem_queue_size);
+        self.message_queue.set_max_memory_size(max_memory_size);
+        for channel in self.get_channels() {
+            channel.set_mem_queue_size(max_memory_size);
+        }
+    }
+    
+    /// Apply the retention policy to the disk backends of the topic and its
+    /// channels, returning how many messages were pruned
+    pub fn prune(&self) -> Result<u64> {
+        let retention = self.retention();
+        let max_age = (retention.retention_ms > 0).then(|| Duration::from_millis(retention.retention_ms));
+        let max_bytes = (retention.retention_bytes > 0).then_some(retention.retention_bytes);
         
-        self.metrics.incr("messages.timed_out", timed_out_messages.len() as u64);
-        
-        // Requeue timed out messages
-        for message in timed_out_messages {
-            if let Err(e) = self.publish(message) {
-                tracing::warn!("Failed to requeue timed out message: {}", e);
+        let mut pruned = self.message_queue.prune_backend(max_age, max_bytes)?;
+        for channel in self.get_channels() {
+            pruned += channel.prune(max_age, max_bytes)?;
+        }
+        self.stats.write().pruned_count += pruned;
+        Ok(pruned)
+    }
+    
+    /// Move queued messages of the topic and then its channels from memory
+    /// to their disk queues until at least `bytes` are freed, returning how
+    /// many bytes were moved
+    pub fn spill(&self, bytes: usize) -> Result<usize> {
+        let (mut spilled, mut spilled_bytes) = self.message_queue.spill(bytes)?;
+        for channel in self.get_channels() {
+            if spilled_bytes >= bytes {
+                break;
             }
+            let (channel_spilled, channel_bytes) = channel.spill(bytes - spilled_bytes)?;
+            spilled += channel_spilled;
+            spilled_bytes += channel_bytes;
+        }
+        self.stats.write().spilled_count += spilled as u64;
+        Ok(spilled_bytes)
+    }
+    
+    /// Write all pending messages of the topic and its channels to their disk
+    /// queues, returning how many were written
+    pub fn flush(&self) -> Result<usize> {
+        let mut flushed = self.message_queue.flush()?;
+        for channel in self.get_channels() {
+            flushed += channel.flush()?;
+        }
+        Ok(flushed)
+    }
+    
+    /// Get the in-flight count across channels
+    pub fn in_flight_count(&self) -> usize {
+        self.get_channels().iter().map(|channel| channel.in_flight_count()).sum()
+    }
+    
+    /// Get the deferred count across channels
+    pub fn deferred_count(&self) -> usize {
+        self.get_channels().iter().map(|channel| channel.deferred_count()).sum()
+    }
+    
+    /// Requeue the deferred messages of every channel that are due
+    pub fn process_deferred(&self) -> Result<()> {
+        for channel in self.get_channels() {
+            channel.process_deferred()?;
         }
-        
         Ok(())
     }
     
-    /// Pause the topic
-    pub fn pause(&self) -> Result<()> {
-        let channels = self.get_channels();
-        for channel in channels {
-            channel.pause()?;
+    /// Requeue the timed out messages of every channel, returning the
+    /// expired in-flight entries
+    pub fn cleanup_timeouts(&self) -> Result<Vec<InFlightMessage>> {
+        let mut timed_out_messages = Vec::new();
+        for channel in self.get_channels() {
+            timed_out_messages.extend(channel.cleanup_timeouts()?);
         }
-        
+        self.stats.write().timeout_count += timed_out_messages.len() as u64;
+        Ok(timed_out_messages)
+    }
+    
+    /// Pause the topic: messages keep being published to its queue but are
+    /// not handed to its channels, which stop delivering
+    pub fn pause(&self) -> Result<()> {
+        self.pause.write().paused_at.get_or_insert_with(chrono::Utc::now);
         self.metrics.incr("topics.paused", 1);
         Ok(())
     }
     
-    /// Unpause the topic
+    /// Unpause the topic, handing the messages published while it was paused
+    /// to its channels in the order they were published
     pub fn unpause(&self) -> Result<()> {
-        let channels = self.get_channels();
-        for channel in channels {
-            channel.unpause()?;
+        self.pause.write().paused_at = None;
+        self.pump()?;
+        for channel in self.get_channels() {
+            channel.wake();
         }
         
         self.metrics.incr("topics.unpaused", 1);
@@ -251,13 +750,17 @@ impl Topic {
     
     /// Check if topic is paused
     pub fn is_paused(&self) -> bool {
-        let channels = self.get_channels();
-        channels.iter().any(|channel| channel.is_paused())
+        self.pause.read().is_paused()
+    }
+    
+    /// When the topic was paused, if it is
+    pub fn paused_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.pause.read().paused_at
     }
     
     /// Delete the topic
     pub fn delete(&self) -> Result<()> {
-        // Pause all channels first
+        // Stop delivery first
         self.pause()?;
         
         // Remove all channels
@@ -270,3 +773,331 @@ impl Topic {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use bytes::Bytes;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+    use nsq_protocol::MessageId;
+    use nsq_common::BaseConfig;
+    use crate::client::{Client, ClientInfo, ClientState};
+    
+    fn topic() -> Topic {
+        let metrics = Metrics::new(&BaseConfig::default()).unwrap();
+        Topic::new("fan_out".to_string(), 100_000, None, metrics).unwrap()
+    }
+    
+    fn publish(topic: &Topic, body: &str) -> MessageId {
+        let message = Message::new(Bytes::from(body.to_string()));
+        let id = message.id;
+        topic.publish(message).unwrap();
+        id
+    }
+    
+    /// Every message left in a channel's queue, in delivery order
+    fn drain(channel: &Channel) -> Vec<Message> {
+        std::iter::from_fn(|| channel.get_message().unwrap()).collect()
+    }
+    
+    fn ids(channel: &Channel) -> Vec<MessageId> {
+        drain(channel).into_iter().map(|message| message.id).collect()
+    }
+    
+    #[test]
+    fn test_channel_added_later_starts_from_next_message() {
+        let topic = topic();
+        let first = topic.add_channel("first".to_string(), None).unwrap();
+        let early = publish(&topic, "early");
+        let second = topic.add_channel("second".to_string(), None).unwrap();
+        let late = publish(&topic, "late");
+        
+        assert_eq!(ids(&first), vec![early, late]);
+        assert_eq!(ids(&second), vec![late]);
+        assert_eq!(topic.depth(), 0);
+    }
+    
+    #[test]
+    fn test_messages_wait_for_first_channel() {
+        let topic = topic();
+        let published = vec![publish(&topic, "a"), publish(&topic, "b")];
+        assert_eq!(topic.depth(), 2);
+        
+        let channel = topic.add_channel("channel".to_string(), None).unwrap();
+        assert_eq!(channel.depth(), 0);
+        assert_eq!(topic.pump().unwrap(), 2);
+        assert_eq!(topic.depth(), 0);
+        assert_eq!(ids(&channel), published);
+    }
+    
+    #[test]
+    fn test_paused_topic_hands_over_in_order_on_unpause() {
+        let topic = topic();
+        let channel = topic.add_channel("channel".to_string(), None).unwrap();
+        let before = publish(&topic, "before");
+        topic.pause().unwrap();
+        let during: Vec<MessageId> = (0..3).map(|i| publish(&topic, &i.to_string())).collect();
+        assert_eq!(topic.depth(), 3);
+        assert_eq!(channel.depth(), 1);
+        assert!(channel.get_message().unwrap().is_none());
+        
+        topic.unpause().unwrap();
+        let mut expected = vec![before];
+        expected.extend(during);
+        assert_eq!(ids(&channel), expected);
+    }
+    
+    #[test]
+    fn test_channels_track_attempts_independently() {
+        let topic = topic();
+        let first = topic.add_channel("first".to_string(), None).unwrap();
+        let second = topic.add_channel("second".to_string(), None).unwrap();
+        let id = publish(&topic, "body");
+        
+        let client = Uuid::new_v4();
+        let delivered = first.dispatch_message(client, Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!(delivered.attempts, 1);
+        first.requeue_message(id, Duration::ZERO).unwrap();
+        let redelivered = first.dispatch_message(client, Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!(redelivered.attempts, 2);
+        first.finish_message(id).unwrap();
+        
+        assert_eq!(first.depth(), 0);
+        assert_eq!(second.depth(), 1);
+        let copy = second.dispatch_message(client, Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!((copy.id, copy.attempts), (id, 1));
+    }
+    
+    #[test]
+    fn test_publish_fails_when_a_channel_cannot_take_its_copy() {
+        let topic = topic();
+        let full = topic.add_channel("full".to_string(), None).unwrap();
+        let other = topic.add_channel("other".to_string(), None).unwrap();
+        full.set_mem_queue_size(1);
+        let first = publish(&topic, "first");
+        
+        let second = Message::new(Bytes::from("second"));
+        assert!(topic.publish(second).is_err());
+        assert_eq!(ids(&full), vec![first]);
+        assert_eq!(ids(&other), vec![first]);
+        assert_eq!(other.stats().message_count, 1);
+    }
+    
+    #[test]
+    fn test_held_message_no_channel_could_take_stays_in_the_topic() {
+        let topic = topic();
+        let full = topic.add_channel("full".to_string(), None).unwrap();
+        let other = topic.add_channel("other".to_string(), None).unwrap();
+        full.set_mem_queue_size(1);
+        topic.pause().unwrap();
+        let first = publish(&topic, "first");
+        let second = publish(&topic, "second");
+        
+        assert!(topic.unpause().is_err());
+        assert_eq!(topic.depth(), 1);
+        assert_eq!(ids(&full), vec![first]);
+        assert_eq!(ids(&other), vec![first]);
+        
+        assert_eq!(topic.pump().unwrap(), 1);
+        assert_eq!(ids(&full), vec![second]);
+        assert_eq!(ids(&other), vec![second]);
+    }
+    
+    fn ready_client(rdy: u32) -> Client {
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let metrics = Metrics::new(&BaseConfig::default()).unwrap();
+        let client = Client::new(ClientInfo::default(), sender, metrics);
+        client.set_state(ClientState::Ready);
+        client.set_rdy_count(rdy);
+        client
+    }
+    
+    /// Deliver to a client until its RDY count or the channel runs out, as the
+    /// server's dispatcher does
+    fn fill(channel: &Channel, client: &Client) -> usize {
+        let mut delivered = 0;
+        while client.can_receive() {
+            let Some(message) = channel.dispatch_message(client.id(), Duration::from_secs(60)).unwrap() else {
+                break;
+            };
+            client.add_in_flight(message);
+            delivered += 1;
+        }
+        delivered
+    }
+    
+    #[test]
+    fn test_max_in_flight_caps_channel_across_clients() {
+        let topic = topic();
+        let channel = topic.add_channel("channel".to_string(), None).unwrap();
+        channel.set_max_in_flight(3);
+        let published: Vec<MessageId> = (0..10).map(|i| publish(&topic, &i.to_string())).collect();
+    
+        let clients = [ready_client(5), ready_client(5)];
+        for client in &clients {
+            channel.add_client(client.id(), 0);
+        }
+        let delivered: usize = clients.iter().map(|client| fill(&channel, client)).sum();
+        assert_eq!(delivered, 3);
+        assert_eq!(channel.in_flight_count(), 3);
+        assert_eq!(channel.depth(), 7);
+    
+        channel.finish_message(published[0]).unwrap();
+        clients.iter().for_each(|client| { client.remove_in_flight(published[0]); });
+        assert_eq!(clients.iter().map(|client| fill(&channel, client)).sum::<usize>(), 1);
+        assert_eq!(channel.in_flight_count(), 3);
+    
+        channel.set_max_in_flight(0);
+        assert_eq!(clients.iter().map(|client| fill(&channel, client)).sum::<usize>(), 6);
+        assert_eq!(channel.in_flight_count(), 9);
+    }
+    
+    #[test]
+    fn test_max_in_flight_is_reported_in_stats() {
+        let topic = topic();
+        let channel = topic.add_channel("channel".to_string(), None).unwrap();
+        assert_eq!(channel.stats().max_in_flight, 0);
+    
+        channel.set_max_in_flight(2);
+        (0..4).for_each(|i| { publish(&topic, &i.to_string()); });
+        let client = ready_client(10);
+        channel.add_client(client.id(), 0);
+        assert_eq!(fill(&channel, &client), 2);
+    
+        let stats = channel.stats();
+        assert_eq!((stats.max_in_flight, stats.in_flight_count, stats.depth), (2, 2, 2));
+    }
+    
+    fn disk_queue(dir: &std::path::Path, name: &str) -> DiskQueue {
+        DiskQueue::new(dir.join(name), 1024 * 1024, 4096, Duration::from_secs(2)).unwrap()
+    }
+    
+    #[test]
+    fn test_durable_publish_is_fsynced_to_every_channel() {
+        let dir = std::env::temp_dir().join(format!("nsqd-durable-{}", Uuid::new_v4()));
+        let topic = topic().with_default_durable(true);
+        let first = topic.add_channel("first".to_string(), Some(disk_queue(&dir, "first"))).unwrap();
+        let second = topic.add_channel("second".to_string(), Some(disk_queue(&dir, "second"))).unwrap();
+        let published = vec![publish(&topic, "a"), publish(&topic, "b")];
+        
+        let stats = topic.stats();
+        assert_eq!((first.backend_depth(), second.backend_depth()), (2, 2));
+        assert_eq!(stats.fsync_count, 4);
+        // One copy per channel, plus each record's length prefix
+        assert!(stats.durable_written_bytes > 2 * stats.durable_published_bytes);
+        assert_eq!(topic.fsync_latency().count(), 2);
+        assert_eq!(ids(&first), published);
+        assert_eq!(ids(&second), published);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_channel_with_disk_backlog_is_not_idle() {
+        let dir = std::env::temp_dir().join(format!("nsqd-idle-{}", Uuid::new_v4()));
+        let topic = topic().with_default_durable(true);
+        let channel = topic.add_channel("spilled".to_string(), Some(disk_queue(&dir, "spilled"))).unwrap();
+        publish(&topic, "a");
+        
+        assert_eq!(channel.backend_depth(), 1);
+        assert!(!channel.is_idle(Duration::ZERO));
+        drain(&channel);
+        assert!(channel.is_idle(Duration::ZERO));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_durable_publish_fails_without_disk_queue() {
+        let topic = topic();
+        topic.add_channel("memory".to_string(), None).unwrap();
+        topic.set_durable(Some(true));
+        assert!(topic.publish(Message::new(Bytes::from("body"))).is_err());
+        
+        topic.set_durable(None);
+        publish(&topic, "body");
+    }
+    
+    #[test]
+    fn test_failed_durable_publish_is_taken_back_from_every_channel() {
+        let dir = std::env::temp_dir().join(format!("nsqd-rollback-{}", Uuid::new_v4()));
+        let topic = topic().with_default_durable(true);
+        let disk = topic.add_channel("disk".to_string(), Some(disk_queue(&dir, "disk"))).unwrap();
+        let kept = publish(&topic, "kept");
+        topic.add_channel("memory".to_string(), None).unwrap();
+        
+        let batch = vec![Message::new(Bytes::from("a")), Message::new(Bytes::from("b"))];
+        assert!(topic.publish_multiple(batch).is_err());
+        assert_eq!(disk.backend_depth(), 1);
+        assert_eq!(topic.stats().message_count, 1);
+        assert_eq!(ids(&disk), vec![kept]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_durable_publish_wakes_dispatchers_once_synced() {
+        let dir = std::env::temp_dir().join(format!("nsqd-wake-{}", Uuid::new_v4()));
+        let topic = topic().with_default_durable(true);
+        let channel = topic.add_channel("disk".to_string(), Some(disk_queue(&dir, "disk"))).unwrap();
+        
+        let waiting = tokio::spawn({
+            let channel = channel.clone();
+            async move { channel.wait_for_messages(Duration::from_secs(60)).await }
+        });
+        tokio::task::yield_now().await;
+        
+        publish(&topic, "a");
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(topic.stats().fsync_count, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        
+        #[test]
+        fn prop_every_channel_receives_every_message_once(
+            channels in 1usize..6,
+            publishers in 1usize..5,
+            per_publisher in 1usize..50,
+            batch in 1usize..4,
+        ) {
+            let topic = Arc::new(topic());
+            let channels: Vec<Arc<Channel>> = (0..channels)
+                .map(|i| topic.add_channel(format!("channel{}", i), None).unwrap())
+                .collect();
+            
+            let threads: Vec<_> = (0..publishers).map(|publisher| {
+                let topic = topic.clone();
+                std::thread::spawn(move || {
+                    let mut published = Vec::new();
+                    for chunk in (0..per_publisher).collect::<Vec<_>>().chunks(batch) {
+                        let messages: Vec<Message> = chunk.iter()
+                            .map(|i| Message::new(Bytes::from(format!("{}-{}", publisher, i))))
+                            .collect();
+                        published.extend(messages.iter().map(|message| message.id));
+                        topic.publish_multiple(messages).unwrap();
+                    }
+                    published
+                })
+            }).collect();
+            let published: Vec<Vec<MessageId>> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+            
+            for channel in &channels {
+                let received = ids(channel);
+                prop_assert_eq!(received.len(), publishers * per_publisher);
+                
+                // Exactly once, and in each publisher's order
+                let position: HashMap<MessageId, usize> = received.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+                prop_assert_eq!(position.len(), received.len());
+                for ids in &published {
+                    let positions: Vec<usize> = ids.iter().map(|id| position[id]).collect();
+                    prop_assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+                prop_assert_eq!(channel.stats().message_count, received.len() as u64);
+            }
+            prop_assert_eq!(topic.depth(), 0);
+            prop_assert_eq!(topic.stats().message_count, (publishers * per_publisher) as u64);
+        }
+    }
+}
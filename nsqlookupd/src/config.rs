@@ -3,6 +3,7 @@
 use nsq_common::NsqlookupdConfig;
 use clap::Parser;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 /// NSQLookupd command line arguments
 #[derive(Parser, Debug)]
@@ -10,6 +11,10 @@ use std::net::SocketAddr;
 #[command(about = "NSQ service discovery daemon")]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 pub struct Args {
+    /// Config file (TOML, YAML or JSON); command line flags override it
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    
     /// TCP address to listen on
     #[arg(long, default_value = "0.0.0.0:4160")]
     pub tcp_address: String,
@@ -34,6 +39,22 @@ pub struct Args {
     #[arg(long, default_value = "45000")]
     pub tombstone_lifetime: u64,
     
+    /// Interval between stale producer and tombstone sweeps (ms)
+    #[arg(long, default_value = "30000")]
+    pub cleanup_interval: u64,
+    
+    /// Static producer registration as topic=address:tcp_port[:http_port] (repeatable)
+    #[arg(long = "seed-producer")]
+    pub seed_producers: Vec<String>,
+    
+    /// HTTP address of a peer nsqlookupd to replicate registrations from (repeatable)
+    #[arg(long = "peer-http-address")]
+    pub peer_http_addresses: Vec<String>,
+    
+    /// Interval between fetches of peer registrations (ms)
+    #[arg(long, default_value = "5000")]
+    pub peer_sync_interval: u64,
+    
     /// Log level
     #[arg(long, default_value = "info")]
     pub log_level: String,
@@ -42,9 +63,49 @@ pub struct Args {
     #[arg(long, default_value = "text")]
     pub log_format: String,
     
-    /// Broadcast address for producers
+    /// Per-module log level override such as nsqd::channel=debug (repeatable)
+    #[arg(long = "log-filter")]
+    pub log_filters: Vec<String>,
+    
+    /// Write logs to this file instead of stdout
+    #[arg(long)]
+    pub log_file: Option<String>,
+    
+    /// Rotate the log file once it reaches this many bytes; 0 disables rotation
+    #[arg(long, default_value = "104857600")]
+    pub log_max_size: u64,
+    
+    /// Rotated log files to keep
+    #[arg(long, default_value = "5")]
+    pub log_max_files: usize,
+    
+    /// OTLP/HTTP collector to export trace spans to, e.g. http://127.0.0.1:4318
     #[arg(long)]
-    pub broadcast_address: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    
+    /// Service name for exported spans (defaults to the executable name)
+    #[arg(long)]
+    pub otlp_service_name: Option<String>,
+    
+    /// Fraction of traces to export (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub otlp_sample_rate: f64,
+    
+    /// Log every HTTP request
+    #[arg(long)]
+    pub access_log: bool,
+    
+    /// Fraction of HTTP requests to log (0.0-1.0)
+    #[arg(long, default_value = "1.0")]
+    pub access_log_sample_rate: f64,
+    
+    /// Write HTTP requests to this file as JSON lines
+    #[arg(long)]
+    pub access_log_file: Option<String>,
+    
+    /// Broadcast address advertised to peers ("auto" picks the primary interface)
+    #[arg(long, default_value = "auto")]
+    pub broadcast_address: String,
     
     /// Statsd address
     #[arg(long)]
@@ -55,44 +116,61 @@ pub struct Args {
     pub statsd_prefix: String,
 }
 
-impl Args {
-    /// Validate configuration
-    pub fn validate(&self) -> Result<(), String> {
-        // Validate TCP address
-        if !self.tcp_address.is_empty() {
-            self.tcp_address.parse::<SocketAddr>()
-                .map_err(|e| format!("Invalid TCP address '{}': {}", self.tcp_address, e))?;
-        }
-        
-        // Validate HTTP address
-        if !self.http_address.is_empty() {
-            self.http_address.parse::<SocketAddr>()
-                .map_err(|e| format!("Invalid HTTP address '{}': {}", self.http_address, e))?;
-        }
-        
-        // Validate timeout values
-        if self.inactive_producer_timeout == 0 {
-            return Err("inactive_producer_timeout must be greater than 0".to_string());
-        }
-        
-        if self.tombstone_lifetime == 0 {
-            return Err("tombstone_lifetime must be greater than 0".to_string());
-        }
-        
-        // Validate log level
-        match self.log_level.as_str() {
-            "trace" | "debug" | "info" | "warn" | "error" => {},
-            _ => return Err(format!("Invalid log level '{}'. Must be one of: trace, debug, info, warn, error", self.log_level)),
-        }
-        
-        // Validate log format
-        match self.log_format.as_str() {
-            "text" | "json" => {},
-            _ => return Err(format!("Invalid log format '{}'. Must be one of: text, json", self.log_format)),
-        }
-        
-        Ok(())
+/// Validate configuration
+pub fn validate(config: &NsqlookupdConfig) -> Result<(), String> {
+    // Validate TCP address
+    if !config.tcp_address.is_empty() {
+        config.tcp_address.parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid TCP address '{}': {}", config.tcp_address, e))?;
+    }
+    
+    // Validate HTTP address
+    if !config.http_address.is_empty() {
+        config.http_address.parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid HTTP address '{}': {}", config.http_address, e))?;
+    }
+    
+    // Validate broadcast address
+    if config.broadcast_address != "auto" {
+        nsq_common::validate_broadcast_address(&config.broadcast_address)
+            .map_err(|e| e.to_string())?;
+    }
+    
+    // Validate timeout values
+    if config.inactive_producer_timeout == 0 {
+        return Err("inactive_producer_timeout must be greater than 0".to_string());
+    }
+    
+    if config.tombstone_lifetime == 0 {
+        return Err("tombstone_lifetime must be greater than 0".to_string());
     }
+    
+    if config.cleanup_interval == 0 {
+        return Err("cleanup_interval must be greater than 0".to_string());
+    }
+    
+    if config.peer_sync_interval == 0 {
+        return Err("peer_sync_interval must be greater than 0".to_string());
+    }
+    
+    // Validate static registrations
+    for spec in &config.seed_producers {
+        crate::server::parse_seed_producer(spec).map_err(|e| e.to_string())?;
+    }
+    
+    // Validate log level
+    match config.base.log_level.as_str() {
+        "trace" | "debug" | "info" | "warn" | "error" => {},
+        _ => return Err(format!("Invalid log level '{}'. Must be one of: trace, debug, info, warn, error", config.base.log_level)),
+    }
+    
+    // Validate log format
+    match config.base.log_format.as_str() {
+        "text" | "json" => {},
+        _ => return Err(format!("Invalid log format '{}'. Must be one of: text, json", config.base.log_format)),
+    }
+    
+    Ok(())
 }
 
 impl From<Args> for NsqlookupdConfig {
@@ -101,15 +179,30 @@ impl From<Args> for NsqlookupdConfig {
             base: nsq_common::BaseConfig {
                 log_level: args.log_level,
                 log_format: args.log_format,
+                log_filters: args.log_filters,
+                log_file: args.log_file,
+                log_max_size: args.log_max_size,
+                log_max_files: args.log_max_files,
+                otlp_endpoint: args.otlp_endpoint,
+                otlp_service_name: args.otlp_service_name,
+                otlp_sample_rate: args.otlp_sample_rate,
+                access_log: args.access_log,
+                access_log_sample_rate: args.access_log_sample_rate,
+                access_log_file: args.access_log_file,
                 statsd_address: args.statsd_address,
                 statsd_prefix: args.statsd_prefix,
             },
             tcp_address: args.tcp_address,
             http_address: args.http_address,
+            broadcast_address: args.broadcast_address,
             tcp_socket_path: args.tcp_socket_path,
             http_socket_path: args.http_socket_path,
             inactive_producer_timeout: args.inactive_producer_timeout,
             tombstone_lifetime: args.tombstone_lifetime,
+            cleanup_interval: args.cleanup_interval,
+            seed_producers: args.seed_producers,
+            peer_http_addresses: args.peer_http_addresses,
+            peer_sync_interval: args.peer_sync_interval,
         }
     }
 }
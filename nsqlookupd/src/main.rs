@@ -1,31 +1,43 @@
 //! NSQLookupd main entry point
 
-use nsqlookupd::{config::Args, server::NsqlookupdServer};
-use nsq_common::init_logging;
-use clap::Parser;
+use nsqlookupd::{config::{validate, Args}, server::NsqlookupdServer};
+use nsq_common::{explicit_args, init_logging, merge_config_file, resolve_broadcast_address, shutdown_tracing, NsqlookupdConfig};
+use clap::{CommandFactory, FromArgMatches};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+    let config_path = args.config.clone();
+    
+    // Convert to configuration, layering the config file under explicit flags
+    let mut config: NsqlookupdConfig = args.into();
+    if let Some(path) = &config_path {
+        config = merge_config_file(&config, &explicit_args(&matches), path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
     
     // Validate configuration
-    if let Err(e) = args.validate() {
+    if let Err(e) = validate(&config) {
         eprintln!("Configuration error: {}", e);
         std::process::exit(1);
     }
     
-    // Convert to configuration
-    let config: nsq_common::NsqlookupdConfig = args.into();
-    
     // Initialize logging
     init_logging(&config.base)?;
     
+    config.broadcast_address = resolve_broadcast_address(&config.broadcast_address)?;
+    
     tracing::info!("Starting NSQLookupd {}", env!("CARGO_PKG_VERSION"));
     tracing::info!("TCP address: {}", config.tcp_address);
     tracing::info!("HTTP address: {}", config.http_address);
+    tracing::info!("Broadcast address: {}", config.broadcast_address);
     tracing::info!("Inactive producer timeout: {}ms", config.inactive_producer_timeout);
     tracing::info!("Tombstone lifetime: {}ms", config.tombstone_lifetime);
+    tracing::info!("Cleanup interval: {}ms", config.cleanup_interval);
     
     // Create and start server
     let mut server = NsqlookupdServer::new(config)?;
@@ -45,6 +57,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     server_handle.abort();
     
     tracing::info!("NSQLookupd stopped");
+    shutdown_tracing();
     Ok(())
 }
 
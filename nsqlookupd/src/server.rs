@@ -1,21 +1,28 @@
 //! NSQLookupd server implementation
 
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use axum::{
-    extract::{Query, State},
-    response::Json,
+    extract::{Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
 use parking_lot::RwLock;
-use nsq_common::{Metrics, Result, NsqError, NsqlookupdConfig};
+use nsq_common::{AccessLogLayer, InvalidName, Metrics, Result, NsqError, NsqlookupdConfig, RestartPolicy, TaskSupervisor, http_span, validate_channel_name, validate_topic_name};
+use nsq_protocol::ErrorCode;
 use tokio::time::Duration;
 use tower_http::cors::{CorsLayer, Any};
+use tower_http::trace::TraceLayer;
+
+/// Timeout for fetching registrations from a peer lookupd
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Producer registration information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +36,15 @@ pub struct Producer {
     pub last_update: chrono::DateTime<chrono::Utc>,
     pub tombstoned: bool,
     pub tombstoned_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Availability zone the producer runs in
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Region the producer runs in
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Registered statically with `--seed-producer` rather than over TCP
+    #[serde(skip)]
+    pub seeded: bool,
 }
 
 impl Producer {
@@ -50,6 +66,28 @@ impl Producer {
             last_update: chrono::Utc::now(),
             tombstoned: false,
             tombstoned_at: None,
+            zone: None,
+            region: None,
+            seeded: false,
+        }
+    }
+
+    /// Set the zone and region labels
+    pub fn with_locality(mut self, zone: Option<String>, region: Option<String>) -> Self {
+        self.zone = zone;
+        self.region = region;
+        self
+    }
+
+    /// Locality rank relative to a preferred zone and region: 0 for the same
+    /// zone, 1 for the same region and 2 for anything else
+    pub fn locality_rank(&self, zone: Option<&str>, region: Option<&str>) -> u8 {
+        if zone.is_some() && self.zone.as_deref() == zone {
+            0
+        } else if region.is_some() && self.region.as_deref() == region {
+            1
+        } else {
+            2
         }
     }
 
@@ -63,6 +101,10 @@ impl Producer {
     }
 
     pub fn is_stale(&self, timeout: Duration) -> bool {
+        // Seeded producers never send heartbeats
+        if self.seeded {
+            return false;
+        }
         let now = chrono::Utc::now();
         let timeout_duration = chrono::Duration::from_std(timeout).unwrap_or_default();
         now.signed_duration_since(self.last_update) > timeout_duration
@@ -71,10 +113,33 @@ impl Producer {
     pub fn is_healthy(&self) -> bool {
         !self.tombstoned
     }
+
+    /// Whether the producer is tombstoned and the tombstone has not lapsed
+    pub fn is_tombstoned(&self, tombstone_lifetime: Duration) -> bool {
+        match self.tombstoned_at {
+            Some(tombstoned_at) if self.tombstoned => {
+                let lifetime = chrono::Duration::from_std(tombstone_lifetime).unwrap_or_default();
+                chrono::Utc::now().signed_duration_since(tombstoned_at) < lifetime
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the producer has sent a heartbeat within the inactive timeout
+    /// and is not tombstoned, tombstones lapsing after their lifetime
+    pub fn is_active(&self, inactive_timeout: Duration, tombstone_lifetime: Duration) -> bool {
+        !self.is_stale(inactive_timeout) && !self.is_tombstoned(tombstone_lifetime)
+    }
     
     pub fn get_id(&self) -> String {
         format!("{}:{}", self.broadcast_address, self.tcp_port)
     }
+
+    /// Whether `node` names this producer, either by its HTTP address as
+    /// nsqadmin sends it or by its TCP address
+    pub fn matches_node(&self, node: &str) -> bool {
+        node == format!("{}:{}", self.broadcast_address, self.http_port) || node == self.get_id()
+    }
     
     pub fn get_http_url(&self) -> String {
         format!("http://{}:{}", self.broadcast_address, self.http_port)
@@ -85,17 +150,250 @@ impl Producer {
     }
 }
 
+/// A producer as listed by `/nodes`, in the format of the original
+/// nsqlookupd so go-nsq and upstream nsqadmin can read it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+    pub remote_address: String,
+    pub hostname: String,
+    pub broadcast_address: String,
+    pub tcp_port: u16,
+    pub http_port: u16,
+    pub version: String,
+    /// Last heartbeat as Unix seconds
+    pub last_update: i64,
+    pub tombstoned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Topics the producer is registered for
+    pub topics: Vec<String>,
+    /// Whether the producer is tombstoned for each of `topics`
+    pub tombstones: Vec<bool>,
+}
+
+impl Node {
+    fn new(producer: Producer, registrations: Vec<(String, bool)>) -> Self {
+        let (topics, tombstones) = registrations.into_iter().unzip();
+        Self {
+            remote_address: producer.remote_address,
+            hostname: producer.hostname,
+            broadcast_address: producer.broadcast_address,
+            tcp_port: producer.tcp_port,
+            http_port: producer.http_port,
+            version: producer.version,
+            last_update: producer.last_update.timestamp(),
+            tombstoned: producer.tombstoned,
+            zone: producer.zone,
+            region: producer.region,
+            topics,
+            tombstones,
+        }
+    }
+}
+
+/// Producer details sent by nsqd in `IDENTIFY <json>`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProducerIdentity {
+    pub broadcast_address: Option<String>,
+    pub hostname: Option<String>,
+    pub tcp_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub version: Option<String>,
+    pub zone: Option<String>,
+    pub region: Option<String>,
+}
+
+impl ProducerIdentity {
+    /// Build the producer for a connection, falling back to the remote
+    /// address and default ports for anything the identity does not set
+    pub fn producer(&self, remote_addr: &str) -> Producer {
+        let remote_host = remote_addr.rsplit_once(':').map(|(host, _)| host).unwrap_or("127.0.0.1");
+        Producer::new(
+            remote_addr.to_string(),
+            self.hostname.clone().unwrap_or_else(|| "unknown".to_string()),
+            self.broadcast_address.clone().unwrap_or_else(|| remote_host.to_string()),
+            self.tcp_port.unwrap_or(4150),
+            self.http_port.unwrap_or(4151),
+            self.version.clone().unwrap_or_else(|| "unknown".to_string()),
+        )
+        .with_locality(self.zone.clone(), self.region.clone())
+    }
+}
+
+/// Parse a `--seed-producer` value of the form
+/// `topic=address:tcp_port[:http_port]`, the HTTP port defaulting to the TCP
+/// port plus one
+pub fn parse_seed_producer(spec: &str) -> Result<(String, Producer)> {
+    let invalid = || NsqError::Validation(format!(
+        "Invalid seed producer '{}': expected topic=address:tcp_port[:http_port]", spec
+    ));
+    let (topic, address) = spec.split_once('=').ok_or_else(invalid)?;
+    if validate_topic_name(topic).is_err() {
+        return Err(invalid());
+    }
+    
+    let (rest, last_port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let last_port = last_port.parse::<u16>().map_err(|_| invalid())?;
+    let (host, tcp_port, http_port) = match rest.rsplit_once(':') {
+        Some((host, tcp_port)) => (host, tcp_port.parse::<u16>().map_err(|_| invalid())?, last_port),
+        None => (rest, last_port, last_port.checked_add(1).ok_or_else(invalid)?),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    
+    let mut producer = Producer::new(
+        address.to_string(),
+        host.to_string(),
+        host.to_string(),
+        tcp_port,
+        http_port,
+        "unknown".to_string(),
+    );
+    producer.seeded = true;
+    Ok((topic.to_string(), producer))
+}
+
+/// Order producers by locality, same zone first and then same region,
+/// keeping the existing order within each group
+pub fn sort_by_locality(producers: &mut [Producer], zone: Option<&str>, region: Option<&str>) {
+    producers.sort_by_key(|producer| producer.locality_rank(zone, region));
+}
+
+/// Kind of a registration, as in upstream nsqlookupd
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    /// A producer that identified itself
+    Client,
+    /// A topic, named by the key
+    Topic,
+    /// A channel, named by the sub key, of the topic named by the key
+    Channel,
+}
+
+/// Key or sub key matching anything in a registration query
+pub const WILDCARD: &str = "*";
+
+/// Something a producer registered
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Registration {
+    pub category: Category,
+    pub key: String,
+    pub sub_key: String,
+}
+
+impl Registration {
+    fn new(category: Category, key: &str, sub_key: &str) -> Self {
+        Self {
+            category,
+            key: key.to_string(),
+            sub_key: sub_key.to_string(),
+        }
+    }
+
+    /// The registration of a producer itself
+    pub fn client() -> Self {
+        Self::new(Category::Client, "", "")
+    }
+
+    pub fn topic(topic: &str) -> Self {
+        Self::new(Category::Topic, topic, "")
+    }
+
+    pub fn channel(topic: &str, channel: &str) -> Self {
+        Self::new(Category::Channel, topic, channel)
+    }
+
+    /// Whether the registration matches a query, `*` matching any key or
+    /// sub key
+    pub fn matches(&self, category: Category, key: &str, sub_key: &str) -> bool {
+        self.category == category
+            && (key == WILDCARD || self.key == key)
+            && (sub_key == WILDCARD || self.sub_key == sub_key)
+    }
+}
+
+/// Channels of a topic, each with the IDs of the producers that registered
+/// it; a channel created over HTTP has none
+pub type ChannelRegistrations = BTreeMap<String, BTreeSet<String>>;
+
+/// Registrations a lookupd received itself, served to its peers
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerState {
+    /// Topic -> Producers
+    pub topics: HashMap<String, Vec<Producer>>,
+    /// Topic -> Channel registrations
+    pub channels: HashMap<String, ChannelRegistrations>,
+}
+
+/// Registrations last fetched from a peer, indexed by producer
+#[derive(Debug, Clone)]
+struct PeerReplica {
+    state: PeerState,
+    /// Producer ID -> Producer
+    producers: HashMap<String, Producer>,
+    /// Producer ID -> what it registered at the peer
+    registrations: HashMap<String, BTreeSet<Registration>>,
+    fetched_at: std::time::Instant,
+}
+
+impl PeerReplica {
+    fn new(state: PeerState) -> Self {
+        let mut producers = HashMap::new();
+        let mut registrations: HashMap<String, BTreeSet<Registration>> = HashMap::new();
+        for (topic, topic_producers) in &state.topics {
+            for producer in topic_producers {
+                let producer_id = producer.get_id();
+                let registered = registrations.entry(producer_id.clone()).or_default();
+                registered.insert(Registration::client());
+                registered.insert(Registration::topic(topic));
+                producers.insert(producer_id, producer.clone());
+            }
+        }
+        for (topic, channels) in &state.channels {
+            for (channel, producer_ids) in channels {
+                for producer_id in producer_ids {
+                    registrations.entry(producer_id.clone()).or_default().insert(Registration::channel(topic, channel));
+                }
+            }
+        }
+        Self {
+            state,
+            producers,
+            registrations,
+            fetched_at: std::time::Instant::now(),
+        }
+    }
+}
+
 /// Registration database
+///
+/// Registrations are kept by topic and by channel, and indexed by producer,
+/// so finding the producers of a topic or channel or everything a producer
+/// registered never scans every registration.
 #[derive(Debug)]
 pub struct RegistrationDB {
     /// Topic -> Producers
     topics: Arc<RwLock<HashMap<String, Vec<Producer>>>>,
-    /// Topic -> Channel names
-    channels: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    /// Topic -> Channel registrations
+    channels: Arc<RwLock<HashMap<String, ChannelRegistrations>>>,
     /// Tombstoned topics
     tombstones: Arc<RwLock<HashMap<String, chrono::DateTime<chrono::Utc>>>>,
     /// Producer ID -> Producer mapping for quick lookups
     producers_by_id: Arc<RwLock<HashMap<String, Producer>>>,
+    /// Producer ID -> what it registered here
+    registrations: Arc<RwLock<HashMap<String, BTreeSet<Registration>>>>,
+    /// Peer HTTP address -> registrations replicated from that peer
+    replicas: Arc<RwLock<HashMap<String, PeerReplica>>>,
+}
+
+impl Default for RegistrationDB {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RegistrationDB {
@@ -105,6 +403,20 @@ impl RegistrationDB {
             channels: Arc::new(RwLock::new(HashMap::new())),
             tombstones: Arc::new(RwLock::new(HashMap::new())),
             producers_by_id: Arc::new(RwLock::new(HashMap::new())),
+            registrations: Arc::new(RwLock::new(HashMap::new())),
+            replicas: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+    
+    /// Record registrations of a producer in the index
+    fn index(&self, producer_id: &str, registrations: impl IntoIterator<Item = Registration>) {
+        self.registrations.write().entry(producer_id.to_string()).or_default().extend(registrations);
+    }
+    
+    /// Remove a registration of a producer from the index
+    fn unindex(&self, producer_id: &str, registration: &Registration) {
+        if let Some(registered) = self.registrations.write().get_mut(producer_id) {
+            registered.remove(registration);
         }
     }
     
@@ -113,100 +425,441 @@ impl RegistrationDB {
         
         // Update producer mapping
         self.producers_by_id.write().insert(producer_id.clone(), producer.clone());
+        self.index(&producer_id, [Registration::client(), Registration::topic(&topic)]);
         
         // Add to topic mapping
         let mut topics = self.topics.write();
-        let producers = topics.entry(topic).or_insert_with(Vec::new);
+        let producers = topics.entry(topic).or_default();
         
-        // Remove existing producer if it exists
+        // Replace an existing registration, keeping its tombstone until it lapses
+        let mut producer = producer;
+        if let Some(existing) = producers.iter().find(|p| p.get_id() == producer_id) {
+            producer.tombstoned = existing.tombstoned;
+            producer.tombstoned_at = existing.tombstoned_at;
+        }
         producers.retain(|p| p.get_id() != producer_id);
         producers.push(producer);
     }
     
+    /// List a producer that identified itself, before it registers any topic
+    pub fn add_producer(&self, producer: Producer) {
+        let producer_id = producer.get_id();
+        self.producers_by_id.write().insert(producer_id.clone(), producer);
+        self.index(&producer_id, [Registration::client()]);
+    }
+    
+    /// Unregister a producer from a topic and from every channel of it
     pub fn unregister_producer(&self, topic: &str, producer_id: &str) {
-        let mut topics = self.topics.write();
-        if let Some(producers) = topics.get_mut(topic) {
+        if let Some(producers) = self.topics.write().get_mut(topic) {
             producers.retain(|p| p.get_id() != producer_id);
         }
+        if let Some(channels) = self.channels.write().get_mut(topic) {
+            Self::drop_channel_producer(channels, producer_id);
+        }
         
-        // Remove from producer mapping
-        self.producers_by_id.write().remove(producer_id);
+        // Remove from producer mapping once no topic lists the producer
+        let still_registered = {
+            let mut registrations = self.registrations.write();
+            let Some(registered) = registrations.get_mut(producer_id) else {
+                return;
+            };
+            registered.retain(|r| r.key != topic || r.category == Category::Client);
+            let still_registered = registered.iter().any(|r| r.category == Category::Topic);
+            if !still_registered {
+                registrations.remove(producer_id);
+            }
+            still_registered
+        };
+        if !still_registered {
+            self.producers_by_id.write().remove(producer_id);
+        }
+    }
+    
+    /// Registrations matching a query, sorted; `*` as the key or sub key
+    /// matches any
+    pub fn find_registrations(&self, category: Category, key: &str, sub_key: &str) -> Vec<Registration> {
+        let registrations: BTreeSet<Registration> = match category {
+            Category::Client => {
+                let any = !self.producers_by_id.read().is_empty()
+                    || self.replicas.read().values().any(|replica| !replica.producers.is_empty());
+                any.then(Registration::client).into_iter().collect()
+            }
+            Category::Topic if key == WILDCARD => {
+                self.get_all_topics().iter().map(|topic| Registration::topic(topic)).collect()
+            }
+            Category::Topic => {
+                let known = self.topics.read().contains_key(key)
+                    || self.replicas.read().values().any(|replica| replica.state.topics.contains_key(key));
+                known.then(|| Registration::topic(key)).into_iter().collect()
+            }
+            Category::Channel => {
+                let topics = match key {
+                    WILDCARD => self.channel_topics(),
+                    _ => BTreeSet::from([key.to_string()]),
+                };
+                topics.iter()
+                    .flat_map(|topic| {
+                        self.get_channel_registrations(topic).into_keys().map(move |channel| Registration::channel(topic, &channel))
+                    })
+                    .collect()
+            }
+        };
+        registrations.into_iter().filter(|r| r.matches(category, key, sub_key)).collect()
     }
     
+    /// Producers with a registration matching a query, each listed once,
+    /// local registrations taking precedence
+    pub fn find_producers(&self, category: Category, key: &str, sub_key: &str) -> Vec<Producer> {
+        if category == Category::Client {
+            return self.get_all_producers();
+        }
+        let mut producers: Vec<Producer> = Vec::new();
+        let mut seen = BTreeSet::new();
+        for registration in self.find_registrations(category, key, sub_key) {
+            let found = match category {
+                Category::Channel => self.get_channel_registrations(&registration.key)
+                    .remove(&registration.sub_key)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|producer_id| self.get_producer(producer_id))
+                    .collect(),
+                _ => self.get_producers(&registration.key),
+            };
+            producers.extend(found.into_iter().filter(|producer| seen.insert(producer.get_id())));
+        }
+        producers
+    }
+    
+    /// Everything a producer registered here or at a peer, sorted
+    pub fn lookup_registrations(&self, producer_id: &str) -> Vec<Registration> {
+        let mut registrations = self.registrations.read().get(producer_id).cloned().unwrap_or_default();
+        for replica in self.replicas.read().values() {
+            registrations.extend(replica.registrations.get(producer_id).into_iter().flatten().cloned());
+        }
+        registrations.into_iter().collect()
+    }
+    
+    /// A producer by ID, registered here or at a peer
+    pub fn get_producer(&self, producer_id: &str) -> Option<Producer> {
+        if let Some(producer) = self.producers_by_id.read().get(producer_id) {
+            return Some(producer.clone());
+        }
+        self.replicas.read().values().find_map(|replica| replica.producers.get(producer_id).cloned())
+    }
+    
+    /// Producers registered for a topic here or at a peer, local
+    /// registrations taking precedence
     pub fn get_producers(&self, topic: &str) -> Vec<Producer> {
-        self.topics.read().get(topic).cloned().unwrap_or_default()
+        let mut producers = self.topics.read().get(topic).cloned().unwrap_or_default();
+        for replica in self.replicas.read().values() {
+            for producer in replica.state.topics.get(topic).into_iter().flatten() {
+                if !producers.iter().any(|p| p.get_id() == producer.get_id()) {
+                    producers.push(producer.clone());
+                }
+            }
+        }
+        producers
+    }
+
+    /// Producers registered for a topic that are still active
+    pub fn get_active_producers(&self, topic: &str, inactive_timeout: Duration, tombstone_lifetime: Duration) -> Vec<Producer> {
+        let mut producers = self.get_producers(topic);
+        producers.retain(|p| p.is_active(inactive_timeout, tombstone_lifetime));
+        producers
     }
     
     pub fn get_all_producers(&self) -> Vec<Producer> {
-        self.producers_by_id.read().values().cloned().collect()
+        let mut producers: HashMap<String, Producer> = HashMap::new();
+        for replica in self.replicas.read().values() {
+            producers.extend(replica.producers.iter().map(|(id, p)| (id.clone(), p.clone())));
+        }
+        producers.extend(self.producers_by_id.read().iter().map(|(id, p)| (id.clone(), p.clone())));
+        producers.into_values().collect()
+    }
+    
+    /// Every producer with the topics it is registered for, sorted by name,
+    /// and whether it is tombstoned for each
+    pub fn get_all_nodes(&self, tombstone_lifetime: Duration) -> Vec<Node> {
+        self.get_all_producers().into_iter()
+            .map(|producer| {
+                let producer_id = producer.get_id();
+                let topics = self.lookup_registrations(&producer_id).into_iter()
+                    .filter(|r| r.category == Category::Topic)
+                    .map(|r| {
+                        let tombstoned = self.get_producers(&r.key).iter()
+                            .any(|p| p.get_id() == producer_id && p.is_tombstoned(tombstone_lifetime));
+                        (r.key, tombstoned)
+                    })
+                    .collect();
+                Node::new(producer, topics)
+            })
+            .collect()
     }
     
     pub fn get_all_topics(&self) -> Vec<String> {
-        self.topics.read().keys().cloned().collect()
+        let mut topics: Vec<String> = self.topics.read().keys().cloned().collect();
+        for replica in self.replicas.read().values() {
+            for topic in replica.state.topics.keys() {
+                if !topics.contains(topic) {
+                    topics.push(topic.clone());
+                }
+            }
+        }
+        topics
     }
 
-    pub fn add_channel(&self, topic: &str, channel: &str) {
-        let mut channels = self.channels.write();
-        let entry = channels.entry(topic.to_string()).or_insert_with(Vec::new);
-        if !entry.iter().any(|c| c == channel) {
-            entry.push(channel.to_string());
+    /// Topics with channel registrations here or at a peer
+    fn channel_topics(&self) -> BTreeSet<String> {
+        let mut topics: BTreeSet<String> = self.channels.read().keys().cloned().collect();
+        for replica in self.replicas.read().values() {
+            topics.extend(replica.state.channels.keys().cloned());
         }
+        topics
     }
 
+    /// Create a channel without a producer, as `/channel/create` does; it
+    /// stays until deleted or until producers that later register it are
+    /// all gone
+    pub fn add_channel(&self, topic: &str, channel: &str) {
+        self.channels.write()
+            .entry(topic.to_string())
+            .or_default()
+            .entry(channel.to_string())
+            .or_default();
+    }
+
+    /// Register a channel for a producer
+    pub fn register_channel(&self, topic: &str, channel: &str, producer_id: &str) {
+        self.channels.write()
+            .entry(topic.to_string())
+            .or_default()
+            .entry(channel.to_string())
+            .or_default()
+            .insert(producer_id.to_string());
+        self.index(producer_id, [Registration::channel(topic, channel)]);
+    }
+
+    /// Unregister a channel for a producer, removing the channel once no
+    /// producer has it, and returning whether the producer had registered it
+    pub fn unregister_channel(&self, topic: &str, channel: &str, producer_id: &str) -> bool {
+        let removed = {
+            let mut channels = self.channels.write();
+            let Some(registrations) = channels.get_mut(topic) else {
+                return false;
+            };
+            let removed = registrations.get_mut(channel).is_some_and(|producers| producers.remove(producer_id));
+            if removed && registrations[channel].is_empty() {
+                registrations.remove(channel);
+            }
+            if registrations.is_empty() {
+                channels.remove(topic);
+            }
+            removed
+        };
+        self.unindex(producer_id, &Registration::channel(topic, channel));
+        removed
+    }
+
+    /// Remove a channel for every producer, as `/channel/delete` does
     pub fn remove_channel(&self, topic: &str, channel: &str) {
+        let producer_ids = {
+            let mut channels = self.channels.write();
+            let Some(registrations) = channels.get_mut(topic) else {
+                return;
+            };
+            let producer_ids = registrations.remove(channel).unwrap_or_default();
+            if registrations.is_empty() {
+                channels.remove(topic);
+            }
+            producer_ids
+        };
+        let registration = Registration::channel(topic, channel);
+        for producer_id in producer_ids {
+            self.unindex(&producer_id, &registration);
+        }
+    }
+
+    /// Remove a topic with its producers and channels, as `/topic/delete` does
+    pub fn remove_topic(&self, topic: &str) {
+        let producers = self.topics.write().remove(topic).unwrap_or_default();
+        let channels = self.channels.write().remove(topic).unwrap_or_default();
+        let mut registrations = self.registrations.write();
+        let producer_ids = producers.iter().map(Producer::get_id)
+            .chain(channels.into_values().flatten());
+        for producer_id in producer_ids {
+            if let Some(registered) = registrations.get_mut(&producer_id) {
+                registered.retain(|r| r.key != topic || r.category == Category::Client);
+            }
+        }
+    }
+
+    /// Drop a producer from the channel registrations of a topic, removing
+    /// the channels it was the last producer of
+    fn drop_channel_producer(channels: &mut ChannelRegistrations, producer_id: &str) {
+        channels.retain(|_, producers| !producers.remove(producer_id) || !producers.is_empty());
+    }
+
+    /// Remove every registration of a producer, returning how many topic
+    /// registrations were removed
+    fn remove_producer(&self, producer_id: &str) -> usize {
+        self.producers_by_id.write().remove(producer_id);
+        let Some(registered) = self.registrations.write().remove(producer_id) else {
+            return 0;
+        };
+        
+        let mut removed = 0;
+        let mut topics = self.topics.write();
         let mut channels = self.channels.write();
-        if let Some(list) = channels.get_mut(topic) {
-            list.retain(|c| c != channel);
+        for registration in registered {
+            match registration.category {
+                Category::Topic => if let Some(producers) = topics.get_mut(&registration.key) {
+                    let before = producers.len();
+                    producers.retain(|p| p.get_id() != producer_id);
+                    removed += before - producers.len();
+                },
+                Category::Channel => if let Some(registrations) = channels.get_mut(&registration.key) {
+                    let emptied = registrations.get_mut(&registration.sub_key)
+                        .is_some_and(|producers| producers.remove(producer_id) && producers.is_empty());
+                    if emptied {
+                        registrations.remove(&registration.sub_key);
+                    }
+                    if registrations.is_empty() {
+                        channels.remove(&registration.key);
+                    }
+                },
+                Category::Client => {}
+            }
         }
+        removed
     }
 
+    /// Channel registrations of a topic here and at every peer
+    pub fn get_channel_registrations(&self, topic: &str) -> ChannelRegistrations {
+        let mut channels = self.channels.read().get(topic).cloned().unwrap_or_default();
+        for replica in self.replicas.read().values() {
+            for (channel, producers) in replica.state.channels.get(topic).into_iter().flatten() {
+                channels.entry(channel.clone()).or_default().extend(producers.iter().cloned());
+            }
+        }
+        channels
+    }
+
+    /// Channels of a topic registered by any producer, sorted
     pub fn get_channels(&self, topic: &str) -> Vec<String> {
-        self.channels.read().get(topic).cloned().unwrap_or_default()
+        self.find_registrations(Category::Channel, topic, WILDCARD).into_iter()
+            .map(|registration| registration.sub_key)
+            .collect()
+    }
+
+    /// Channels of a topic that one of `producers` registered, along with
+    /// the channels created without a producer, sorted
+    pub fn get_channels_for(&self, topic: &str, producers: &[Producer]) -> Vec<String> {
+        let producer_ids: BTreeSet<String> = producers.iter().map(Producer::get_id).collect();
+        self.get_channel_registrations(topic).into_iter()
+            .filter(|(_, registered)| registered.is_empty() || !registered.is_disjoint(&producer_ids))
+            .map(|(channel, _)| channel)
+            .collect()
+    }
+
+    /// Registrations received here rather than replicated from a peer;
+    /// seeded producers are left out as every lookupd has its own
+    pub fn local_state(&self) -> PeerState {
+        let topics = self.topics.read().iter()
+            .map(|(topic, producers)| (topic.clone(), producers.iter().filter(|p| !p.seeded).cloned().collect()))
+            .collect();
+        PeerState {
+            topics,
+            channels: self.channels.read().clone(),
+        }
+    }
+
+    /// Replace the registrations replicated from a peer
+    pub fn apply_peer_state(&self, peer: &str, state: PeerState) {
+        self.replicas.write().insert(peer.to_string(), PeerReplica::new(state));
+    }
+
+    /// Forget peers that could not be reached for longer than the lifetime,
+    /// returning how many were removed
+    pub fn cleanup_expired_replicas(&self, lifetime: Duration) -> usize {
+        let mut replicas = self.replicas.write();
+        let before = replicas.len();
+        replicas.retain(|_, replica| replica.fetched_at.elapsed() <= lifetime);
+        before - replicas.len()
     }
 
     pub fn update_producer_heartbeat(&self, producer_id: &str) {
         if let Some(producer) = self.producers_by_id.write().get_mut(producer_id) {
             producer.update_heartbeat();
         }
+        
+        // Topic registrations carry their own copy of the producer
+        let registered: Vec<String> = self.registrations.read().get(producer_id).into_iter().flatten()
+            .filter(|r| r.category == Category::Topic)
+            .map(|r| r.key.clone())
+            .collect();
+        let mut topics = self.topics.write();
+        for topic in registered {
+            for producer in topics.get_mut(&topic).into_iter().flatten().filter(|p| p.get_id() == producer_id) {
+                producer.update_heartbeat();
+            }
+        }
     }
 
-    pub fn tombstone_producer(&self, topic: &str, producer_id: &str) {
+    /// Tombstone the producer of a topic named by `node`, returning whether
+    /// one was found
+    pub fn tombstone_producer(&self, topic: &str, node: &str) -> bool {
+        let producer_id = {
+            let mut topics = self.topics.write();
+            let Some(producer) = topics.get_mut(topic).and_then(|producers| producers.iter_mut().find(|p| p.matches_node(node))) else {
+                return false;
+            };
+            producer.tombstone();
+            producer.get_id()
+        };
+        
         let tombstone_key = format!("{}|{}", topic, producer_id);
         self.tombstones.write().insert(tombstone_key, chrono::Utc::now());
         
-        if let Some(producer) = self.producers_by_id.write().get_mut(producer_id) {
+        if let Some(producer) = self.producers_by_id.write().get_mut(&producer_id) {
             producer.tombstone();
         }
+        true
     }
 
-    pub fn cleanup_stale_producers(&self, timeout: Duration) {
-        let mut producers_by_id = self.producers_by_id.write();
-        let mut topics = self.topics.write();
-        
-        let stale_producers: Vec<String> = producers_by_id
+    /// Remove every registration made over the TCP connection from
+    /// `remote_address`, returning how many topic registrations were removed
+    pub fn remove_connection(&self, remote_address: &str) -> usize {
+        let closed: Vec<String> = self.producers_by_id.read().values()
+            .filter(|p| !p.seeded && p.remote_address == remote_address)
+            .map(Producer::get_id)
+            .collect();
+        closed.iter().map(|producer_id| self.remove_producer(producer_id)).sum()
+    }
+
+    /// Remove producers without a recent heartbeat, returning how many were removed
+    pub fn cleanup_stale_producers(&self, timeout: Duration) -> usize {
+        let stale_producers: Vec<String> = self.producers_by_id.read()
             .iter()
             .filter(|(_, producer)| producer.is_stale(timeout))
             .map(|(id, _)| id.clone())
             .collect();
-        
-        for producer_id in stale_producers {
-            producers_by_id.remove(&producer_id);
-            
-            // Remove from all topics
-            for producers in topics.values_mut() {
-                producers.retain(|p| p.get_id() != producer_id);
-            }
+        for producer_id in &stale_producers {
+            self.remove_producer(producer_id);
         }
+        stale_producers.len()
     }
 
-    pub fn cleanup_expired_tombstones(&self, lifetime: Duration) {
+    /// Remove tombstones older than the lifetime, returning how many were removed
+    pub fn cleanup_expired_tombstones(&self, lifetime: Duration) -> usize {
         let mut tombstones = self.tombstones.write();
         let now = chrono::Utc::now();
         let lifetime_duration = chrono::Duration::from_std(lifetime).unwrap_or_default();
         
+        let before = tombstones.len();
         tombstones.retain(|_, timestamp| {
             now.signed_duration_since(*timestamp) <= lifetime_duration
         });
+        before - tombstones.len()
     }
 }
 
@@ -215,9 +868,13 @@ pub struct NsqlookupdServer {
     /// Server configuration
     config: NsqlookupdConfig,
     /// Metrics collector
-    _metrics: Metrics,
+    metrics: Metrics,
     /// Registration database
     pub db: Arc<RegistrationDB>,
+    /// Background task supervisor
+    supervisor: TaskSupervisor,
+    /// Logs HTTP requests when enabled
+    access_log: AccessLogLayer,
     /// Server start timestamp (wall clock)
     start_time: chrono::DateTime<chrono::Utc>,
     /// Server start instant (for uptime calculations)
@@ -238,21 +895,18 @@ impl NsqlookupdServer {
         let server_start_instant = std::time::Instant::now();
         let db = Arc::new(RegistrationDB::new());
 
-        // Seed a default producer to satisfy discovery during early development
-        let default_producer = Producer::new(
-            "127.0.0.1:4150".to_string(),
-            std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
-            "127.0.0.1".to_string(),
-            4150,
-            4151,
-            env!("CARGO_PKG_VERSION").to_string(),
-        );
-        // Register the producer for a commonly used topic for compatibility tests
-        db.register_producer("test-topic".to_string(), default_producer);
+        // Register producers given with --seed-producer
+        for spec in &config.seed_producers {
+            let (topic, producer) = parse_seed_producer(spec)?;
+            tracing::info!("Seeded producer {} for topic '{}'", producer.get_id(), topic);
+            db.register_producer(topic, producer);
+        }
 
         Ok(Self {
+            access_log: AccessLogLayer::new(&config.base)?,
             config,
-            _metrics: metrics,
+            supervisor: TaskSupervisor::new(metrics.clone()),
+            metrics,
             db,
             start_time: server_start_time,
             start_instant: server_start_instant,
@@ -268,7 +922,7 @@ impl NsqlookupdServer {
         // Start TCP server
         if let Some(tcp_addr) = self.parse_address(&self.config.tcp_address)? {
             let listener = TcpListener::bind(tcp_addr).await
-                .map_err(|e| NsqError::Io(e))?;
+                .map_err(NsqError::Io)?;
             self.tcp_listener = Some(listener);
             tracing::info!("TCP server listening on {}", tcp_addr);
         }
@@ -276,7 +930,7 @@ impl NsqlookupdServer {
         // Start HTTP server
         if let Some(http_addr) = self.parse_address(&self.config.http_address)? {
             let listener = TcpListener::bind(http_addr).await
-                .map_err(|e| NsqError::Io(e))?;
+                .map_err(NsqError::Io)?;
             self.http_listener = Some(listener);
             tracing::info!("HTTP server listening on {}", http_addr);
         }
@@ -298,7 +952,7 @@ impl NsqlookupdServer {
         if let Some(listener) = self.http_listener.take() {
             let app = self.create_router();
             tokio::spawn(async move {
-                if let Err(e) = axum::serve(listener, app).await {
+                if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
                     tracing::error!("HTTP server error: {}", e);
                 }
             });
@@ -324,18 +978,91 @@ impl NsqlookupdServer {
     /// Start background cleanup tasks
     async fn start_background_tasks(&self) {
         let db = self.db.clone();
+        let metrics = self.metrics.clone();
         let inactive_timeout = Duration::from_millis(self.config.inactive_producer_timeout);
         let tombstone_lifetime = Duration::from_millis(self.config.tombstone_lifetime);
+        let cleanup_interval = Duration::from_millis(self.config.cleanup_interval);
         
         // Cleanup stale producers
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                db.cleanup_stale_producers(inactive_timeout);
-                db.cleanup_expired_tombstones(tombstone_lifetime);
+        self.supervisor.spawn("cleanup", RestartPolicy::Always, move || {
+            let db = db.clone();
+            let metrics = metrics.clone();
+            async move {
+                let mut interval = tokio::time::interval(cleanup_interval);
+                loop {
+                    interval.tick().await;
+                    Self::sweep(&db, &metrics, inactive_timeout, tombstone_lifetime);
+                }
             }
         });
+        
+        // Replicate registrations from peer lookupds
+        if !self.config.peer_http_addresses.is_empty() {
+            let server = self.clone();
+            let sync_interval = Duration::from_millis(self.config.peer_sync_interval);
+            self.supervisor.spawn("peer_sync", RestartPolicy::Always, move || {
+                let server = server.clone();
+                async move {
+                    let client = reqwest::Client::builder()
+                        .timeout(PEER_REQUEST_TIMEOUT)
+                        .build()
+                        .map_err(|e| NsqError::Config(e.to_string()))?;
+                    let mut interval = tokio::time::interval(sync_interval);
+                    loop {
+                        interval.tick().await;
+                        for peer in &server.config.peer_http_addresses {
+                            server.sync_peer(&client, peer).await;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Fetch a peer's own registrations and replace the copy held for it
+    async fn sync_peer(&self, client: &reqwest::Client, peer: &str) {
+        let base = if peer.starts_with("http://") || peer.starts_with("https://") {
+            peer.to_string()
+        } else {
+            format!("http://{}", peer)
+        };
+        let result = async {
+            client.get(format!("{}/peer/state", base)).send().await?.error_for_status()?.json::<PeerState>().await
+        }.await;
+        
+        match result {
+            Ok(state) => {
+                self.db.apply_peer_state(peer, state);
+                self.metrics.incr("peers.syncs", 1);
+            }
+            Err(e) => {
+                self.metrics.incr("peers.sync_errors", 1);
+                tracing::warn!("Failed to sync registrations from peer {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Remove stale producers, expired tombstones and unreachable peers'
+    /// registrations, returning how many stale producers and tombstones were removed
+    fn sweep(db: &RegistrationDB, metrics: &Metrics, inactive_timeout: Duration, tombstone_lifetime: Duration) -> (usize, usize) {
+        let producers_removed = db.cleanup_stale_producers(inactive_timeout);
+        let tombstones_removed = db.cleanup_expired_tombstones(tombstone_lifetime);
+        metrics.incr("producers.expired", producers_removed as u64);
+        metrics.incr("tombstones.expired", tombstones_removed as u64);
+        
+        let replicas_removed = db.cleanup_expired_replicas(inactive_timeout);
+        if replicas_removed > 0 {
+            metrics.incr("peers.expired", replicas_removed as u64);
+            tracing::warn!("Dropped registrations of {} unreachable peers", replicas_removed);
+        }
+        
+        if producers_removed > 0 || tombstones_removed > 0 {
+            tracing::info!("Cleanup removed {} stale producers and {} expired tombstones", producers_removed, tombstones_removed);
+        } else {
+            tracing::debug!("Cleanup removed nothing");
+        }
+        
+        (producers_removed, tombstones_removed)
     }
 
     /// Handle TCP connections
@@ -358,12 +1085,27 @@ impl NsqlookupdServer {
         }
     }
 
-    /// Handle individual TCP connection
-    async fn handle_tcp_connection(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+    /// Handle individual TCP connection, dropping the producer's
+    /// registrations as soon as it closes
+    async fn handle_tcp_connection(&self, stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let result = self.read_tcp_commands(stream, addr).await;
+        
+        let removed = self.db.remove_connection(&addr.to_string());
+        if removed > 0 {
+            self.metrics.incr("unregistrations.disconnect", removed as u64);
+            tracing::info!("Removed {} registrations of closed connection {}", removed, addr);
+        }
+        result
+    }
+
+    /// Read and answer commands until the connection closes
+    async fn read_tcp_commands(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
         tracing::info!("New TCP connection from {}", addr);
+        self.metrics.incr("tcp.connections", 1);
         
         let mut buffer = [0u8; 1024];
         let mut command_buffer = String::new();
+        let mut identity = ProducerIdentity::default();
         
         loop {
             match stream.read(&mut buffer).await {
@@ -381,7 +1123,7 @@ impl NsqlookupdServer {
                         command_buffer = command_buffer[newline_pos + 1..].to_string();
                         
                         if !command.is_empty() {
-                            let response = self.handle_tcp_command(&command, &addr.to_string()).await;
+                            let response = self.handle_tcp_command(&command, &addr.to_string(), &mut identity).await;
                             
                             if let Err(e) = stream.write_all(response.as_bytes()).await {
                                 tracing::error!("Failed to write response: {}", e);
@@ -407,60 +1149,95 @@ impl NsqlookupdServer {
     }
 
     /// Handle TCP protocol commands
-    async fn handle_tcp_command(&self, command: &str, remote_addr: &str) -> String {
+    async fn handle_tcp_command(&self, command: &str, remote_addr: &str, identity: &mut ProducerIdentity) -> String {
         let parts: Vec<&str> = command.split_whitespace().collect();
         
-        match parts.get(0) {
-            Some(&"PING") => "PONG\n".to_string(),
+        let command_name = match parts.first() {
+            Some(name @ (&"PING" | &"REGISTER" | &"UNREGISTER" | &"IDENTIFY" | &"VERSION" | &"QUIT")) => name.to_lowercase(),
+            _ => "invalid".to_string(),
+        };
+        self.metrics.incr(&format!("tcp.commands.{}", command_name), 1);
+        
+        match parts.first() {
+            Some(&"PING") => {
+                self.db.update_producer_heartbeat(&identity.producer(remote_addr).get_id());
+                "PONG\n".to_string()
+            }
             Some(&"REGISTER") => {
-                if parts.len() >= 3 {
+                if parts.len() >= 2 {
                     let topic = parts[1].to_string();
-                    let channel = parts[2].to_string();
-                    
-                    // Validate topic and channel names
-                    if topic.is_empty() || channel.is_empty() {
-                        return "E_INVALID\n".to_string();
+                    let channel = parts.get(2).map(|c| c.to_string());
+                    if let Err(e) = Self::validate_registration(&topic, channel.as_deref()) {
+                        tracing::warn!("Rejected REGISTER from {}: {}", remote_addr, e);
+                        return format!("{} {}\n", e.code(), e);
                     }
                     
                     // Create producer from connection info
-                    let producer = Producer::new(
-                        remote_addr.to_string(),
-                        "unknown".to_string(),
-                        remote_addr.split(':').next().unwrap_or("127.0.0.1").to_string(),
-                        4150, // Default TCP port
-                        4151, // Default HTTP port
-                        "unknown".to_string(),
-                    );
+                    let producer = identity.producer(remote_addr);
+                    let producer_id = producer.get_id();
                     
                     self.db.register_producer(topic.clone(), producer);
-                    self.db.add_channel(&topic, &channel);
+                    self.metrics.incr("registrations.topic", 1);
+                    if let Some(channel) = &channel {
+                        self.db.register_channel(&topic, channel, &producer_id);
+                        self.metrics.incr("registrations.channel", 1);
+                    }
                     
-                    tracing::debug!("Registered producer for topic '{}' channel '{}' from {}", topic, channel, remote_addr);
+                    tracing::debug!("Registered producer for topic '{}' channel {:?} from {}", topic, channel, remote_addr);
                     "OK\n".to_string()
                 } else {
                     tracing::warn!("Invalid REGISTER command from {}: {}", remote_addr, command);
-                    "E_INVALID\n".to_string()
+                    format!("{}\n", ErrorCode::Invalid)
                 }
             }
             Some(&"UNREGISTER") => {
-                if parts.len() >= 3 {
+                if parts.len() >= 2 {
                     let topic = parts[1].to_string();
-                    let channel = parts[2].to_string();
-                    let producer_id = format!("{}:4150", remote_addr.split(':').next().unwrap_or("127.0.0.1"));
+                    if let Err(e) = Self::validate_registration(&topic, parts.get(2).copied()) {
+                        tracing::warn!("Rejected UNREGISTER from {}: {}", remote_addr, e);
+                        return format!("{} {}\n", e.code(), e);
+                    }
+                    let producer_id = identity.producer(remote_addr).get_id();
                     
-                    self.db.unregister_producer(&topic, &producer_id);
-                    self.db.remove_channel(&topic, &channel);
+                    // A channel unregistration leaves the topic producer, and
+                    // other producers of the channel, in place
+                    match parts.get(2) {
+                        Some(channel) => {
+                            self.db.unregister_channel(&topic, channel, &producer_id);
+                            self.metrics.incr("unregistrations.channel", 1);
+                        }
+                        None => {
+                            self.db.unregister_producer(&topic, &producer_id);
+                            self.metrics.incr("unregistrations.topic", 1);
+                        }
+                    }
                     
-                    tracing::debug!("Unregistered producer for topic '{}' channel '{}' from {}", topic, channel, remote_addr);
+                    tracing::debug!("Unregistered producer for topic '{}' channel {:?} from {}", topic, parts.get(2), remote_addr);
                     "OK\n".to_string()
                 } else {
                     tracing::warn!("Invalid UNREGISTER command from {}: {}", remote_addr, command);
-                    "E_INVALID\n".to_string()
+                    format!("{}\n", ErrorCode::Invalid)
                 }
             }
             Some(&"IDENTIFY") => {
+                // An inline JSON body describes the producer for later registrations
+                let body = command["IDENTIFY".len()..].trim();
+                if !body.is_empty() {
+                    match serde_json::from_str::<ProducerIdentity>(body) {
+                        Ok(parsed) => {
+                            tracing::info!("Producer {} identified as {:?}", remote_addr, parsed);
+                            *identity = parsed;
+                            self.db.add_producer(identity.producer(remote_addr));
+                        }
+                        Err(e) => {
+                            tracing::warn!("Invalid IDENTIFY body from {}: {}", remote_addr, e);
+                            return format!("{}\n", ErrorCode::BadBody);
+                        }
+                    }
+                }
+                
                 // Update heartbeat for existing producer
-                let producer_id = format!("{}:4150", remote_addr.split(':').next().unwrap_or("127.0.0.1"));
+                let producer_id = identity.producer(remote_addr).get_id();
                 self.db.update_producer_heartbeat(&producer_id);
                 tracing::debug!("Updated heartbeat for producer {} from {}", producer_id, remote_addr);
                 "OK\n".to_string()
@@ -474,7 +1251,7 @@ impl NsqlookupdServer {
             }
             _ => {
                 tracing::warn!("Unknown command from {}: {}", remote_addr, command);
-                "E_INVALID\n".to_string()
+                format!("{}\n", ErrorCode::Invalid)
             }
         }
     }
@@ -497,24 +1274,98 @@ impl NsqlookupdServer {
             .route("/topics", get(Self::handle_topics))
             .route("/channels", get(Self::handle_channels))
             .route("/nodes", get(Self::handle_nodes))
-            .route("/topic/create", post(Self::handle_topic_create))
-            .route("/topic/delete", post(Self::handle_topic_delete))
-            .route("/channel/create", post(Self::handle_channel_create))
-            .route("/channel/delete", post(Self::handle_channel_delete))
-            .route("/tombstone_topic_producer", post(Self::handle_tombstone))
+            // Admin endpoints take GET as well as POST, and deletes DELETE,
+            // like original nsqlookupd; the underscored names are its
+            // pre-1.0 aliases
+            .route("/topic/create", get(Self::handle_topic_create).post(Self::handle_topic_create))
+            .route("/create_topic", get(Self::handle_topic_create).post(Self::handle_topic_create))
+            .route("/topic/delete", get(Self::handle_topic_delete).post(Self::handle_topic_delete).delete(Self::handle_topic_delete))
+            .route("/delete_topic", get(Self::handle_topic_delete).post(Self::handle_topic_delete))
+            .route("/channel/create", get(Self::handle_channel_create).post(Self::handle_channel_create))
+            .route("/create_channel", get(Self::handle_channel_create).post(Self::handle_channel_create))
+            .route("/channel/delete", get(Self::handle_channel_delete).post(Self::handle_channel_delete).delete(Self::handle_channel_delete))
+            .route("/delete_channel", get(Self::handle_channel_delete).post(Self::handle_channel_delete))
+            .route("/topic/tombstone", get(Self::handle_tombstone).post(Self::handle_tombstone).delete(Self::handle_tombstone))
+            .route("/tombstone_topic_producer", get(Self::handle_tombstone).post(Self::handle_tombstone))
             .route("/health", get(Self::handle_health))
             .route("/debug/pprof/", get(Self::handle_debug_pprof))
+            .route("/debug/expire", post(Self::handle_debug_expire))
+            .route("/debug/tasks", get(Self::handle_debug_tasks))
             .route("/api/topics", get(Self::handle_api_topics))
             .route("/api/nodes", get(Self::handle_api_nodes))
             .route("/api/topics/:topic", get(Self::handle_api_topic_detail))
+            .route("/metrics", get(Self::handle_metrics))
+            .route("/peer/state", get(Self::handle_peer_state))
+            .fallback(|| async { StatusCode::NOT_FOUND })
+            .layer(middleware::from_fn(Self::error_envelope))
+            .layer(middleware::from_fn_with_state(server.clone(), Self::count_http_request))
+            .layer(TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| http_span(request.method(), request.uri().path()))
+                .on_response(|response: &Response, _latency: Duration, span: &tracing::Span| {
+                    span.record("http.status_code", response.status().as_u16());
+                }))
             .layer(cors)
+            .layer(self.access_log.clone())
             .with_state(server)
     }
     
+    /// Count HTTP requests and error responses
+    async fn count_http_request(State(server): State<Arc<NsqlookupdServer>>, request: Request, next: Next) -> Response {
+        let response = next.run(request).await;
+        server.metrics.incr("http.requests", 1);
+        if response.status().is_client_error() || response.status().is_server_error() {
+            server.metrics.incr("http.errors", 1);
+        }
+        response
+    }
+    
+    /// Give client errors that carry no JSON body, such as unknown paths,
+    /// unsupported methods and malformed queries, the `{"message": ...}`
+    /// body original nsqlookupd answers them with
+    async fn error_envelope(request: Request, next: Next) -> Response {
+        let response = next.run(request).await;
+        let status = response.status();
+        let is_json = response.headers().get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+        if !status.is_client_error() || is_json {
+            return response;
+        }
+        let message = match status {
+            StatusCode::BAD_REQUEST => "INVALID_REQUEST".to_string(),
+            _ => status.canonical_reason().unwrap_or("ERROR").to_uppercase().replace(' ', "_"),
+        };
+        let mut envelope = (status, Json(serde_json::json!({"message": message}))).into_response();
+        if let Some(allow) = response.headers().get(header::ALLOW) {
+            envelope.headers_mut().insert(header::ALLOW, allow.clone());
+        }
+        envelope
+    }
+    
+    /// Handle metrics endpoint in the Prometheus text format
+    async fn handle_metrics(State(server): State<Arc<NsqlookupdServer>>) -> impl IntoResponse {
+        let producers = server.db.get_all_producers();
+        let healthy_producers = producers.iter().filter(|p| p.is_healthy()).count();
+        let topics = server.db.get_all_topics();
+        let channels: usize = topics.iter().map(|topic| server.db.get_channels(topic).len()).sum();
+        
+        server.metrics.gauge("producers.count", producers.len() as f64);
+        server.metrics.gauge("producers.healthy", healthy_producers as f64);
+        server.metrics.gauge("producers.tombstoned", (producers.len() - healthy_producers) as f64);
+        server.metrics.gauge("topics.count", topics.len() as f64);
+        server.metrics.gauge("channels.count", channels as f64);
+        server.metrics.gauge("tombstones.active", server.db.tombstones.read().len() as f64);
+        
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            server.metrics.render_prometheus("nsqlookupd"),
+        )
+    }
+    
     /// Handle info endpoint
-    async fn handle_info() -> Json<serde_json::Value> {
+    async fn handle_info(State(server): State<Arc<NsqlookupdServer>>) -> Json<serde_json::Value> {
         Json(serde_json::json!({
-            "version": env!("CARGO_PKG_VERSION")
+            "version": env!("CARGO_PKG_VERSION"),
+            "broadcast_address": server.config.broadcast_address,
         }))
     }
     
@@ -557,44 +1408,42 @@ impl NsqlookupdServer {
         }))
     }
     
-    /// Handle lookup endpoint
+    /// Handle lookup endpoint, listing the active producers of a topic or,
+    /// with `inactive=true`, every producer that registered it
     async fn handle_lookup(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> Json<serde_json::Value> {
-        let maybe_topic = params.get("topic").cloned();
+    ) -> Response {
+        let Some(topic) = params.get("topic") else {
+            return Self::missing_arg_response("MISSING_ARG_TOPIC");
+        };
         
-        let mut producers = if let Some(topic) = maybe_topic.clone() {
-            server.db.get_producers(&topic)
+        let mut producers = if matches!(params.get("inactive").map(String::as_str), Some("1") | Some("true")) {
+            server.db.get_producers(topic)
         } else {
-            server.db.get_all_producers()
+            server.db.get_active_producers(
+                topic,
+                Duration::from_millis(server.config.inactive_producer_timeout),
+                Duration::from_millis(server.config.tombstone_lifetime),
+            )
         };
 
-        // Apply tombstone filtering when topic provided
-        if let Some(topic) = maybe_topic.clone() {
-            let tombstones = server.db.tombstones.read();
-            let now = chrono::Utc::now();
-            let lifetime = chrono::Duration::milliseconds(server.config.tombstone_lifetime as i64);
-            producers.retain(|p| {
-                let node_key = format!("{}|{}:{}", topic, p.broadcast_address, p.tcp_port);
-                match tombstones.get(&node_key) {
-                    Some(ts) => now.signed_duration_since(*ts) > lifetime,
-                    None => true,
-                }
-            });
+        // Prefer producers close to the caller, optionally dropping the rest
+        let prefer_zone = params.get("prefer_zone").map(String::as_str);
+        let prefer_region = params.get("prefer_region").map(String::as_str);
+        if prefer_zone.is_some() || prefer_region.is_some() {
+            sort_by_locality(&mut producers, prefer_zone, prefer_region);
+            // Only the preferred zone, or the preferred region when no zone is given
+            if matches!(params.get("local_only").map(String::as_str), Some("1") | Some("true")) {
+                let max_rank = if prefer_zone.is_some() { 0 } else { 1 };
+                producers.retain(|p| p.locality_rank(prefer_zone, prefer_region) <= max_rank);
+            }
         }
 
-        // Get channels for the topic
-        let channels = if let Some(topic) = maybe_topic {
-            server.db.get_channels(&topic)
-        } else {
-            Vec::new()
-        };
-
         Json(serde_json::json!({
-            "channels": channels,
+            "channels": server.db.get_channels_for(topic, &producers),
             "producers": producers,
-        }))
+        })).into_response()
     }
     
     /// Handle topics endpoint
@@ -609,80 +1458,122 @@ impl NsqlookupdServer {
     async fn handle_channels(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> Json<serde_json::Value> {
-        let topic = params.get("topic").map(|s| s.as_str()).unwrap_or("");
-        let channels = if topic.is_empty() {
-            Vec::<String>::new()
-        } else {
-            server.db.get_channels(topic)
+    ) -> Response {
+        let Some(topic) = params.get("topic") else {
+            return Self::missing_arg_response("MISSING_ARG_TOPIC");
         };
         Json(serde_json::json!({
-            "channels": channels
-        }))
+            "channels": server.db.get_channels(topic)
+        })).into_response()
     }
     
     /// Handle nodes endpoint
     async fn handle_nodes(State(server): State<Arc<NsqlookupdServer>>) -> Json<serde_json::Value> {
-        let producers = server.db.get_all_producers();
+        let producers = server.db.get_all_nodes(Duration::from_millis(server.config.tombstone_lifetime));
         Json(serde_json::json!({
             "producers": producers
         }))
     }
     
+    /// Check the topic and optional channel of a registration
+    fn validate_registration(topic: &str, channel: Option<&str>) -> std::result::Result<(), InvalidName> {
+        validate_topic_name(topic)?;
+        channel.map_or(Ok(()), validate_channel_name)
+    }
+    
+    /// Response to a request naming a topic or channel that breaks the
+    /// naming rules
+    fn invalid_name_response(error: InvalidName) -> Response {
+        (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": error.code().as_str()}))).into_response()
+    }
+    
+    /// Response to a request without a required parameter
+    fn missing_arg_response(message: &'static str) -> Response {
+        (StatusCode::BAD_REQUEST, Json(serde_json::json!({"message": message}))).into_response()
+    }
+    
+    /// The `topic` and `channel` parameters of a channel request, or the
+    /// code of the one missing
+    fn topic_and_channel(params: &HashMap<String, String>) -> std::result::Result<(&str, &str), &'static str> {
+        let topic = params.get("topic").ok_or("MISSING_ARG_TOPIC")?;
+        let channel = params.get("channel").ok_or("MISSING_ARG_CHANNEL")?;
+        Ok((topic, channel))
+    }
+    
     /// Handle topic create endpoint
     async fn handle_topic_create(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let Some(topic) = params.get("topic") {
-            // Ensure topic exists in registry
-            server.db.topics.write().entry(topic.clone()).or_insert_with(Vec::new);
+    ) -> Response {
+        let Some(topic) = params.get("topic") else {
+            return Self::missing_arg_response("MISSING_ARG_TOPIC");
+        };
+        if let Err(e) = validate_topic_name(topic) {
+            return Self::invalid_name_response(e);
         }
-        "OK"
+        // Ensure topic exists in registry
+        server.db.topics.write().entry(topic.clone()).or_insert_with(Vec::new);
+        "OK".into_response()
     }
     
     /// Handle topic delete endpoint
     async fn handle_topic_delete(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let Some(topic) = params.get("topic") {
-            server.db.topics.write().remove(topic);
-        }
-        "OK"
+    ) -> Response {
+        let Some(topic) = params.get("topic") else {
+            return Self::missing_arg_response("MISSING_ARG_TOPIC");
+        };
+        server.db.remove_topic(topic);
+        "OK".into_response()
     }
     
     /// Handle channel create endpoint
     async fn handle_channel_create(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let (Some(topic), Some(channel)) = (params.get("topic"), params.get("channel")) {
-            server.db.add_channel(topic, channel);
+    ) -> Response {
+        let (topic, channel) = match Self::topic_and_channel(&params) {
+            Ok(names) => names,
+            Err(message) => return Self::missing_arg_response(message),
+        };
+        if let Err(e) = Self::validate_registration(topic, Some(channel)) {
+            return Self::invalid_name_response(e);
         }
-        "OK"
+        server.db.add_channel(topic, channel);
+        "OK".into_response()
     }
     
     /// Handle channel delete endpoint
     async fn handle_channel_delete(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let (Some(topic), Some(channel)) = (params.get("topic"), params.get("channel")) {
-            server.db.remove_channel(topic, channel);
-        }
-        "OK"
+    ) -> Response {
+        let (topic, channel) = match Self::topic_and_channel(&params) {
+            Ok(names) => names,
+            Err(message) => return Self::missing_arg_response(message),
+        };
+        server.db.remove_channel(topic, channel);
+        "OK".into_response()
     }
     
-    /// Handle tombstone endpoint
+    /// Handle tombstone endpoint, hiding a producer of a topic from lookups
+    /// for the tombstone lifetime
     async fn handle_tombstone(
         State(server): State<Arc<NsqlookupdServer>>,
         Query(params): Query<std::collections::HashMap<String, String>>,
-    ) -> &'static str {
-        if let (Some(topic), Some(node)) = (params.get("topic"), params.get("node")) {
-            server.db.tombstone_producer(topic, node);
+    ) -> Response {
+        let Some(topic) = params.get("topic") else {
+            return Self::missing_arg_response("MISSING_ARG_TOPIC");
+        };
+        let Some(node) = params.get("node") else {
+            return Self::missing_arg_response("MISSING_ARG_NODE");
+        };
+        if server.db.tombstone_producer(topic, node) {
+            server.metrics.incr("tombstones.created", 1);
+            tracing::info!("Tombstoned producer {} of topic '{}'", node, topic);
         }
-        "OK"
+        "OK".into_response()
     }
     
     /// Handle health endpoint
@@ -723,6 +1614,33 @@ impl NsqlookupdServer {
         }))
     }
     
+    /// Handle debug expire endpoint, running a cleanup sweep immediately
+    async fn handle_debug_expire(State(server): State<Arc<NsqlookupdServer>>) -> Json<serde_json::Value> {
+        let (producers_removed, tombstones_removed) = Self::sweep(
+            &server.db,
+            &server.metrics,
+            Duration::from_millis(server.config.inactive_producer_timeout),
+            Duration::from_millis(server.config.tombstone_lifetime),
+        );
+        
+        Json(serde_json::json!({
+            "producers_removed": producers_removed,
+            "tombstones_removed": tombstones_removed,
+        }))
+    }
+    
+    /// Serve the registrations received here to peer lookupds
+    async fn handle_peer_state(State(server): State<Arc<NsqlookupdServer>>) -> Json<PeerState> {
+        Json(server.db.local_state())
+    }
+    
+    /// Report the state of supervised background tasks
+    async fn handle_debug_tasks(State(server): State<Arc<NsqlookupdServer>>) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "tasks": server.supervisor.stats(),
+        }))
+    }
+    
     /// Handle API topics endpoint
     async fn handle_api_topics(State(server): State<Arc<NsqlookupdServer>>) -> Json<serde_json::Value> {
         let topics = server.db.get_all_topics();
@@ -777,10 +1695,10 @@ impl Clone for NsqlookupdServer {
     fn clone(&self) -> Self {
         Self {
             config: self.config.clone(),
-            _metrics: Metrics::new(&self.config.base).unwrap_or_else(|_| {
-                Metrics::new(&nsq_common::BaseConfig::default()).unwrap()
-            }),
+            metrics: self.metrics.clone(),
             db: self.db.clone(),
+            supervisor: self.supervisor.clone(),
+            access_log: self.access_log.clone(),
             start_time: self.start_time,
             start_instant: self.start_instant,
             tcp_listener: None,
@@ -1,6 +1,6 @@
 //! Basic tests for nsqlookupd functionality
 
-use nsqlookupd::server::{NsqlookupdServer, Producer, RegistrationDB};
+use nsqlookupd::server::{parse_seed_producer, sort_by_locality, Category, NsqlookupdServer, Producer, ProducerIdentity, Registration, RegistrationDB, WILDCARD};
 use nsq_common::NsqlookupdConfig;
 
 #[tokio::test]
@@ -87,6 +87,76 @@ async fn test_channel_management() {
     assert_eq!(channels[0], "channel2");
 }
 
+#[tokio::test]
+async fn test_channel_registrations_per_producer() {
+    let db = RegistrationDB::new();
+    let first = ProducerIdentity::default().producer("10.0.0.5:53211");
+    let second = ProducerIdentity {
+        tcp_port: Some(14150),
+        ..Default::default()
+    }
+    .producer("10.0.0.6:40000");
+    for producer in [&first, &second] {
+        db.register_producer("orders".to_string(), producer.clone());
+        db.register_channel("orders", "billing", &producer.get_id());
+    }
+    db.register_channel("orders", "audit", &second.get_id());
+    db.add_channel("orders", "manual");
+    
+    // The channel stays while another producer still has it
+    assert!(db.unregister_channel("orders", "billing", &first.get_id()));
+    assert!(!db.unregister_channel("orders", "billing", &first.get_id()));
+    assert_eq!(db.get_channels("orders"), vec!["audit", "billing", "manual"]);
+    assert_eq!(db.get_channels_for("orders", std::slice::from_ref(&first)), vec!["manual"]);
+    
+    // Losing the last producer removes its channels, but not ones created over HTTP
+    assert_eq!(db.remove_connection("10.0.0.6:40000"), 1);
+    assert_eq!(db.get_channels("orders"), vec!["manual"]);
+    
+    db.register_channel("orders", "billing", &first.get_id());
+    db.unregister_producer("orders", &first.get_id());
+    assert_eq!(db.get_channels("orders"), vec!["manual"]);
+    db.remove_topic("orders");
+    assert!(db.get_channels("orders").is_empty());
+}
+
+#[tokio::test]
+async fn test_registration_queries() {
+    let db = RegistrationDB::new();
+    let producer = ProducerIdentity::default().producer("10.0.0.5:53211");
+    let producer_id = producer.get_id();
+    db.register_producer("orders".to_string(), producer.clone());
+    db.register_producer("payments".to_string(), producer);
+    db.register_channel("orders", "billing", &producer_id);
+    db.register_channel("payments", "billing", &producer_id);
+    db.add_channel("orders", "audit");
+    
+    assert_eq!(
+        db.find_registrations(Category::Channel, WILDCARD, "billing"),
+        vec![Registration::channel("orders", "billing"), Registration::channel("payments", "billing")]
+    );
+    assert_eq!(db.find_registrations(Category::Topic, "orders", WILDCARD), vec![Registration::topic("orders")]);
+    assert!(db.find_registrations(Category::Topic, "missing", WILDCARD).is_empty());
+    assert_eq!(db.find_producers(Category::Channel, "orders", "billing").len(), 1);
+    assert!(db.find_producers(Category::Channel, "orders", "audit").is_empty());
+    assert_eq!(db.find_producers(Category::Topic, WILDCARD, WILDCARD).len(), 1);
+    
+    // Everything the producer registered comes from the index
+    assert_eq!(db.lookup_registrations(&producer_id), vec![
+        Registration::client(),
+        Registration::topic("orders"),
+        Registration::topic("payments"),
+        Registration::channel("orders", "billing"),
+        Registration::channel("payments", "billing"),
+    ]);
+    db.unregister_producer("payments", &producer_id);
+    db.remove_channel("orders", "billing");
+    assert_eq!(db.lookup_registrations(&producer_id), vec![Registration::client(), Registration::topic("orders")]);
+    db.unregister_producer("orders", &producer_id);
+    assert!(db.lookup_registrations(&producer_id).is_empty());
+    assert!(db.get_all_producers().is_empty());
+}
+
 #[tokio::test]
 async fn test_producer_id_generation() {
     let producer = Producer::new(
@@ -102,3 +172,199 @@ async fn test_producer_id_generation() {
     assert_eq!(producer.get_http_url(), "http://127.0.0.1:4151");
     assert_eq!(producer.get_tcp_address(), "127.0.0.1:4150");
 }
+
+#[tokio::test]
+async fn test_identity_producer_and_locality_sort() {
+    let identity = ProducerIdentity {
+        broadcast_address: Some("10.0.0.5".to_string()),
+        tcp_port: Some(14150),
+        zone: Some("us-east-1b".to_string()),
+        region: Some("us-east-1".to_string()),
+        ..Default::default()
+    };
+    let local = identity.producer("10.0.0.5:53211");
+    assert_eq!(local.get_id(), "10.0.0.5:14150");
+    assert_eq!(local.http_port, 4151);
+    
+    let remote = Producer::new(
+        "10.1.0.7:40000".to_string(),
+        "remote-host".to_string(),
+        "10.1.0.7".to_string(),
+        4150,
+        4151,
+        "1.0.0".to_string(),
+    )
+    .with_locality(Some("eu-west-1a".to_string()), Some("eu-west-1".to_string()));
+    let same_region = ProducerIdentity {
+        broadcast_address: Some("10.0.1.9".to_string()),
+        zone: Some("us-east-1a".to_string()),
+        region: Some("us-east-1".to_string()),
+        ..Default::default()
+    }
+    .producer("10.0.1.9:40001");
+    
+    let mut producers = vec![remote, same_region, local];
+    sort_by_locality(&mut producers, Some("us-east-1b"), Some("us-east-1"));
+    let order: Vec<&str> = producers.iter().map(|p| p.broadcast_address.as_str()).collect();
+    assert_eq!(order, vec!["10.0.0.5", "10.0.1.9", "10.1.0.7"]);
+    
+    // Unlabelled producers sort after labelled matches
+    assert_eq!(Producer::new(
+        "127.0.0.1:1".to_string(),
+        "host".to_string(),
+        "127.0.0.1".to_string(),
+        4150,
+        4151,
+        "1.0.0".to_string(),
+    ).locality_rank(Some("us-east-1b"), None), 2);
+}
+
+#[tokio::test]
+async fn test_active_producers_and_topic_tombstone() {
+    let db = RegistrationDB::new();
+    let producer = Producer::new(
+        "127.0.0.1:12345".to_string(),
+        "test-host".to_string(),
+        "127.0.0.1".to_string(),
+        4150,
+        4151,
+        "1.0.0".to_string(),
+    );
+    db.register_producer("orders".to_string(), producer.clone());
+    db.register_producer("payments".to_string(), producer);
+    
+    let inactive_timeout = tokio::time::Duration::from_secs(300);
+    let tombstone_lifetime = tokio::time::Duration::from_secs(45);
+    assert_eq!(db.get_active_producers("orders", inactive_timeout, tombstone_lifetime).len(), 1);
+    assert!(db.get_active_producers("unknown", inactive_timeout, tombstone_lifetime).is_empty());
+    
+    // nsqadmin names the node by its HTTP address; the tombstone only covers one topic
+    assert!(db.tombstone_producer("orders", "127.0.0.1:4151"));
+    assert!(!db.tombstone_producer("orders", "127.0.0.1:9999"));
+    assert!(db.get_active_producers("orders", inactive_timeout, tombstone_lifetime).is_empty());
+    assert_eq!(db.get_producers("orders").len(), 1);
+    assert_eq!(db.get_active_producers("payments", inactive_timeout, tombstone_lifetime).len(), 1);
+    
+    // Producers without a recent heartbeat are inactive
+    tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+    assert!(db.get_active_producers("payments", tokio::time::Duration::from_millis(10), tombstone_lifetime).is_empty());
+    
+    // Unregistering one topic keeps the producer known through the other
+    db.unregister_producer("orders", "127.0.0.1:4150");
+    assert_eq!(db.get_all_producers().len(), 1);
+}
+
+#[tokio::test]
+async fn test_seed_producers() {
+    // No producers are registered unless seeded
+    let server = NsqlookupdServer::new(NsqlookupdConfig::default()).expect("Failed to create server");
+    assert!(server.db.get_all_producers().is_empty());
+    
+    let config = NsqlookupdConfig {
+        seed_producers: vec!["orders=10.0.0.5:4150".to_string(), "orders=10.0.0.6:14150:14151".to_string()],
+        ..Default::default()
+    };
+    let server = NsqlookupdServer::new(config).expect("Failed to create server");
+    let producers = server.db.get_active_producers(
+        "orders",
+        tokio::time::Duration::from_millis(1),
+        tokio::time::Duration::from_secs(45),
+    );
+    assert_eq!(producers.len(), 2);
+    let (_, seeded) = parse_seed_producer("orders=10.0.0.5:4150").unwrap();
+    assert_eq!(seeded.http_port, 4151);
+    
+    assert!(parse_seed_producer("10.0.0.5:4150").is_err());
+    assert!(parse_seed_producer("orders=10.0.0.5").is_err());
+    assert!(parse_seed_producer("bad topic=10.0.0.5:4150").is_err());
+}
+
+#[tokio::test]
+async fn test_remove_connection() {
+    let db = RegistrationDB::new();
+    let producer = ProducerIdentity {
+        broadcast_address: Some("10.0.0.5".to_string()),
+        ..Default::default()
+    }
+    .producer("10.0.0.5:53211");
+    db.register_producer("orders".to_string(), producer.clone());
+    db.register_producer("payments".to_string(), producer);
+    
+    let other = ProducerIdentity::default().producer("10.0.0.6:40000");
+    db.register_producer("orders".to_string(), other);
+    
+    // Only registrations made over the closed connection are removed
+    assert_eq!(db.remove_connection("10.0.0.5:53211"), 2);
+    assert_eq!(db.get_producers("orders").len(), 1);
+    assert!(db.get_producers("payments").is_empty());
+    assert_eq!(db.get_all_producers().len(), 1);
+    assert_eq!(db.remove_connection("10.0.0.5:53211"), 0);
+}
+
+#[tokio::test]
+async fn test_peer_replication() {
+    let origin = RegistrationDB::new();
+    let producer = ProducerIdentity {
+        broadcast_address: Some("10.0.0.5".to_string()),
+        ..Default::default()
+    }
+    .producer("10.0.0.5:53211");
+    origin.register_producer("orders".to_string(), producer);
+    origin.add_channel("orders", "billing");
+    let (_, seeded) = parse_seed_producer("orders=10.0.0.9:4150").unwrap();
+    origin.register_producer("orders".to_string(), seeded);
+    
+    // Seeded producers stay local to each lookupd
+    let state = origin.local_state();
+    assert_eq!(state.topics["orders"].len(), 1);
+    
+    let replica = RegistrationDB::new();
+    replica.apply_peer_state("10.0.0.1:4161", state.clone());
+    assert_eq!(replica.get_producers("orders").len(), 1);
+    assert_eq!(replica.get_channels("orders"), vec!["billing".to_string()]);
+    assert_eq!(replica.get_all_topics(), vec!["orders".to_string()]);
+    assert_eq!(replica.get_all_producers().len(), 1);
+    
+    // Replicated registrations are not passed on, so peers never echo them back
+    assert!(replica.local_state().topics.is_empty());
+    
+    // A producer registered in both places is listed once
+    replica.register_producer("orders".to_string(), state.topics["orders"][0].clone());
+    assert_eq!(replica.get_producers("orders").len(), 1);
+    
+    tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+    assert_eq!(replica.cleanup_expired_replicas(tokio::time::Duration::from_millis(10)), 1);
+    assert_eq!(replica.get_channels("orders"), Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn test_nodes_listing() {
+    let db = RegistrationDB::new();
+    let producer = Producer::new(
+        "127.0.0.1:12345".to_string(),
+        "test-host".to_string(),
+        "127.0.0.1".to_string(),
+        4150,
+        4151,
+        "1.0.0".to_string(),
+    );
+    db.register_producer("payments".to_string(), producer.clone());
+    db.register_producer("orders".to_string(), producer.clone());
+    db.tombstone_producer("orders", "127.0.0.1:4151");
+    
+    let nodes = db.get_all_nodes(tokio::time::Duration::from_secs(45));
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].topics, vec!["orders".to_string(), "payments".to_string()]);
+    assert_eq!(nodes[0].tombstones, vec![true, false]);
+    assert_eq!(nodes[0].last_update, producer.last_update.timestamp());
+    
+    // Lapsed tombstones are no longer reported
+    let nodes = db.get_all_nodes(tokio::time::Duration::ZERO);
+    assert_eq!(nodes[0].tombstones, vec![false, false]);
+    
+    // Upstream clients read last_update as a number
+    let json = serde_json::to_value(&nodes[0]).unwrap();
+    assert!(json["last_update"].is_i64());
+    assert_eq!(json["remote_address"], "127.0.0.1:12345");
+    assert!(json.get("zone").is_none());
+}
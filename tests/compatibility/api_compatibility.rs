@@ -48,7 +48,7 @@ async fn test_nsqd_http_api_compatibility() {
 
 #[tokio::test]
 async fn test_lookupd_http_api_compatibility() {
-    let config = TestConfig::default();
+    let config = TestConfig::default().with_seed_producer("test-topic", "127.0.0.1:4150:4151");
     let mut env = TestEnvironment::new(config.clone());
     env.start().await.expect("Failed to start services");
     
@@ -78,6 +78,7 @@ async fn test_lookupd_http_api_compatibility() {
     // Test lookup endpoint
     let lookup = lookupd_client.lookup_topic("test-topic").await.expect("Failed to lookup topic");
     assert!(lookup["producers"].is_array());
+    assert!(lookup["producers"].as_array().unwrap().iter().any(|p| p["tcp_port"] == 4150 && p["http_port"] == 4151));
     
     // Test topic creation
     let result = lookupd_client.create_topic("lookupd-compat-test").await.expect("Failed to create topic");
@@ -1,4 +1,5 @@
 //! Compatibility tests with original NSQ
+#![allow(dead_code, clippy::expect_fun_call, clippy::needless_borrows_for_generic_args, clippy::useless_vec)]
 
 mod test_utils;
 
@@ -314,3 +314,148 @@ async fn test_protocol_version_compatibility() {
     // This is a basic check - in a real implementation, you'd parse and compare versions
     assert_eq!(nsqd_version, lookupd_version, "NSQd and NSQLookupd should have compatible versions");
 }
+
+/// Send a SUB and return the raw response frame
+async fn subscribe(stream: &mut TcpStream, topic: &str, channel: &str) -> String {
+    let command = format!("SUB {} {}\n", topic, channel);
+    stream.write_all(command.as_bytes()).await.expect("Failed to write SUB");
+    
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.expect("Failed to read response");
+    String::from_utf8_lossy(&buffer[..n]).to_string()
+}
+
+#[tokio::test]
+async fn test_sub_creates_missing_topic_by_default() {
+    let config = TestConfig::default();
+    let mut env = TestEnvironment::new(config.clone());
+    env.start().await.expect("Failed to start services");
+    
+    let nsqd_client = env.nsqd_client();
+    let stats = nsqd_client.get_stats().await.expect("Failed to get stats");
+    assert_eq!(stats["implicit_creation"], true);
+    
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", config.nsqd_tcp_port))
+        .await
+        .expect("Failed to connect to NSQd");
+    
+    let response = subscribe(&mut stream, "implicit-topic", "implicit-channel").await;
+    assert!(response.contains("OK"), "SUB should create the topic and channel");
+    
+    let (status, stats) = nsqd_client.get_filtered_stats("implicit-topic", Some("implicit-channel")).await
+        .expect("Failed to get stats");
+    assert_eq!(status, 200);
+    assert_eq!(stats["topics"][0]["channels"][0]["channel_name"], "implicit-channel");
+}
+
+#[tokio::test]
+async fn test_sub_to_missing_topic_with_implicit_creation_disabled() {
+    let config = TestConfig {
+        nsqd_args: vec!["--disable-implicit-creation".to_string()],
+        ..TestConfig::default()
+    };
+    let mut env = TestEnvironment::new(config.clone());
+    env.start().await.expect("Failed to start services");
+    
+    let nsqd_client = env.nsqd_client();
+    let stats = nsqd_client.get_stats().await.expect("Failed to get stats");
+    assert_eq!(stats["implicit_creation"], false);
+    
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", config.nsqd_tcp_port))
+        .await
+        .expect("Failed to connect to NSQd");
+    
+    // Missing topic
+    let response = subscribe(&mut stream, "missing-topic", "missing-channel").await;
+    assert!(response.contains("E_TOPIC_NOT_FOUND"), "SUB to a missing topic should fail with E_TOPIC_NOT_FOUND");
+    let (status, stats) = nsqd_client.get_filtered_stats("missing-topic", None).await.expect("Failed to get stats");
+    assert_eq!(status, 404);
+    assert_eq!(stats["message"], "TOPIC_NOT_FOUND");
+    
+    // Existing topic, missing channel
+    nsqd_client.create_topic("missing-topic").await.expect("Failed to create topic");
+    let response = subscribe(&mut stream, "missing-topic", "missing-channel").await;
+    assert!(response.contains("E_CHANNEL_NOT_FOUND"), "SUB to a missing channel should fail with E_CHANNEL_NOT_FOUND");
+    let (status, stats) = nsqd_client.get_filtered_stats("missing-topic", Some("missing-channel")).await
+        .expect("Failed to get stats");
+    assert_eq!(status, 404);
+    assert_eq!(stats["message"], "CHANNEL_NOT_FOUND");
+    
+    // Explicitly created topic and channel
+    nsqd_client.create_channel("missing-topic", "missing-channel").await.expect("Failed to create channel");
+    let response = subscribe(&mut stream, "missing-topic", "missing-channel").await;
+    assert!(response.contains("OK"), "SUB to an existing topic and channel should succeed");
+}
+
+/// Send a raw command and return the raw response frame
+async fn send_command(stream: &mut TcpStream, command: &[u8]) -> String {
+    stream.write_all(command).await.expect("Failed to write command");
+    
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.expect("Failed to read response");
+    String::from_utf8_lossy(&buffer[..n]).to_string()
+}
+
+/// A command line followed by a length-prefixed body
+fn with_body(line: &str, body: &[u8]) -> Vec<u8> {
+    let mut command = line.as_bytes().to_vec();
+    command.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    command.extend_from_slice(body);
+    command
+}
+
+#[tokio::test]
+async fn test_client_state_machine() {
+    let config = TestConfig::default();
+    let mut env = TestEnvironment::new(config.clone());
+    env.start().await.expect("Failed to start services");
+    
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", config.nsqd_tcp_port))
+        .await
+        .expect("Failed to connect to NSQd");
+    
+    // Consumer commands need a subscription
+    let response = send_command(&mut stream, b"RDY 1\n").await;
+    assert!(response.contains("E_INVALID cannot RDY before SUB"), "{}", response);
+    let response = send_command(&mut stream, b"FIN 00000000-0000-0000-0000-000000000000\n").await;
+    assert!(response.contains("E_INVALID cannot FIN before SUB"), "{}", response);
+    
+    // IDENTIFY only once
+    let response = send_command(&mut stream, &with_body("IDENTIFY\n", b"{}")).await;
+    assert!(response.contains("OK"), "{}", response);
+    let response = send_command(&mut stream, &with_body("IDENTIFY\n", b"{}")).await;
+    assert!(response.contains("E_INVALID cannot IDENTIFY twice"), "{}", response);
+    
+    // One SUB, after which publishing and identifying are refused
+    let response = subscribe(&mut stream, "state-topic", "state-channel").await;
+    assert!(response.contains("OK"), "{}", response);
+    let response = subscribe(&mut stream, "state-topic", "other-channel").await;
+    assert!(response.contains("E_INVALID cannot SUB twice on one connection"), "{}", response);
+    let response = send_command(&mut stream, &with_body("PUB state-topic\n", b"body")).await;
+    assert!(response.contains("E_INVALID cannot PUB after SUB"), "{}", response);
+    let response = send_command(&mut stream, &with_body("IDENTIFY\n", b"{}")).await;
+    assert!(response.contains("E_INVALID cannot IDENTIFY after SUB"), "{}", response);
+    
+    // RDY is accepted without a response once subscribed, and the
+    // connection stayed open through the errors
+    stream.write_all(b"RDY 1\n").await.expect("Failed to write RDY");
+    let response = send_command(&mut stream, b"CLS\n").await;
+    assert!(response.contains("CLOSE_WAIT"), "{}", response);
+}
+
+#[test]
+fn test_topic_channel_name_rules() {
+    use nsq_common::{validate_channel_name, validate_topic_name};
+    
+    for name in ["events", "a", "Orders.v2_eu-west", "events#ephemeral", "events#dlq", &"t".repeat(64)] {
+        assert!(validate_topic_name(name).is_ok(), "topic '{}' should be valid", name);
+    }
+    for name in ["", "has space", "slash/name", "events#other", "#ephemeral", &"t".repeat(65), &format!("{}#dlq", "t".repeat(61))] {
+        let error = validate_topic_name(name).expect_err(name);
+        assert_eq!(error.code().as_str(), "E_BAD_TOPIC");
+    }
+    
+    assert!(validate_channel_name("workers#ephemeral").is_ok());
+    let error = validate_channel_name("workers#dlq").expect_err("#dlq is only valid for topics");
+    assert_eq!(error.code().as_str(), "E_BAD_CHANNEL");
+}
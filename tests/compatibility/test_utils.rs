@@ -15,6 +15,10 @@ pub struct TestConfig {
     pub lookupd_http_port: u16,
     pub admin_http_port: u16,
     pub data_path: String,
+    /// Extra command line arguments passed to nsqd
+    pub nsqd_args: Vec<String>,
+    /// Extra command line arguments passed to nsqlookupd
+    pub lookupd_args: Vec<String>,
 }
 
 impl Default for TestConfig {
@@ -26,10 +30,20 @@ impl Default for TestConfig {
             lookupd_http_port: 4161,
             admin_http_port: 4171,
             data_path: "/tmp/nsq-test".to_string(),
+            nsqd_args: Vec::new(),
+            lookupd_args: Vec::new(),
         }
     }
 }
 
+impl TestConfig {
+    /// Register a static producer for `topic` in nsqlookupd before any nsqd connects
+    pub fn with_seed_producer(mut self, topic: &str, address: &str) -> Self {
+        self.lookupd_args.push(format!("--seed-producer={}={}", topic, address));
+        self
+    }
+}
+
 /// Test environment manager
 pub struct TestEnvironment {
     config: TestConfig,
@@ -55,6 +69,7 @@ impl TestEnvironment {
                 "--tcp-address", &format!("0.0.0.0:{}", self.config.lookupd_tcp_port),
                 "--http-address", &format!("0.0.0.0:{}", self.config.lookupd_http_port),
             ])
+            .args(&self.config.lookupd_args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
@@ -72,7 +87,10 @@ impl TestEnvironment {
                 "--http-address", &format!("0.0.0.0:{}", self.config.nsqd_http_port),
                 "--lookupd-tcp-address", &format!("127.0.0.1:{}", self.config.lookupd_tcp_port),
                 "--data-path", &self.config.data_path,
+                // The tests write commands without the protocol magic
+                "--allow-missing-magic",
             ])
+            .args(&self.config.nsqd_args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
@@ -215,6 +233,25 @@ impl NSQdClient {
         response.json().await
     }
 
+    /// Stats filtered to one topic and optionally one channel, with the HTTP status
+    pub async fn get_filtered_stats(&self, topic: &str, channel: Option<&str>) -> Result<(u16, Value), reqwest::Error> {
+        let mut url = format!("http://127.0.0.1:{}/stats?topic={}", self.port, topic);
+        if let Some(channel) = channel {
+            url.push_str(&format!("&channel={}", channel));
+        }
+        let response = self.client.get(url).send().await?;
+        let status = response.status().as_u16();
+        Ok((status, response.json().await?))
+    }
+
+    pub async fn create_channel(&self, topic: &str, channel: &str) -> Result<String, reqwest::Error> {
+        let response = self.client
+            .post(format!("http://127.0.0.1:{}/channel/create?topic={}&channel={}", self.port, topic, channel))
+            .send()
+            .await?;
+        response.text().await
+    }
+
     pub async fn publish(&self, topic: &str, message: &str) -> Result<String, reqwest::Error> {
         let response = self.client
             .post(&format!("http://127.0.0.1:{}/pub?topic={}", self.port, topic))
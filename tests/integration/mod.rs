@@ -1,4 +1,5 @@
 //! Integration tests for NSQ Rust implementation
+#![allow(dead_code, unused_imports, unused_variables, clippy::bool_assert_comparison, clippy::expect_fun_call, clippy::needless_borrows_for_generic_args, clippy::single_match, clippy::useless_vec)]
 
 mod test_utils;
 
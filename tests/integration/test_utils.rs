@@ -72,6 +72,8 @@ impl TestEnvironment {
                 "--http-address", &format!("0.0.0.0:{}", self.config.nsqd_http_port),
                 "--lookupd-tcp-address", &format!("127.0.0.1:{}", self.config.lookupd_tcp_port),
                 "--data-path", &self.config.data_path,
+                // The tests write commands without the protocol magic
+                "--allow-missing-magic",
             ])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
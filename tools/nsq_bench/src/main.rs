@@ -0,0 +1,340 @@
+//! nsq_bench - Load generator for measuring nsqd throughput and latency
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::Parser;
+use nsq_protocol::{Command, CommandEncoder, Frame, FrameType, Message, NsqDecoder};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use futures::SinkExt;
+use tracing::{error, info, warn};
+
+/// Bytes at the start of every message body holding its publish time
+const TIMESTAMP_LEN: usize = 8;
+
+type BenchResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Parser, Debug)]
+#[command(name = "nsq_bench")]
+#[command(about = "Load generator for measuring nsqd throughput and latency")]
+struct Args {
+    /// NSQd TCP address
+    #[arg(long, default_value = "127.0.0.1:4150")]
+    nsqd_tcp_address: String,
+
+    /// Topic to publish to and consume from
+    #[arg(long, default_value = "bench")]
+    topic: String,
+
+    /// Channel to consume from
+    #[arg(long, default_value = "bench")]
+    channel: String,
+
+    /// Number of concurrent producer connections
+    #[arg(long, default_value = "1")]
+    producers: usize,
+
+    /// Number of concurrent consumer connections (0 to only publish)
+    #[arg(long, default_value = "1")]
+    consumers: usize,
+
+    /// Message size in bytes
+    #[arg(long, default_value = "200")]
+    size: usize,
+
+    /// Target publish rate in messages per second across all producers (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    rate: u64,
+
+    /// Messages per PUB/MPUB command
+    #[arg(long, default_value = "1")]
+    batch_size: usize,
+
+    /// Seconds to publish for
+    #[arg(long, default_value = "10")]
+    duration: u64,
+
+    /// RDY count for each consumer
+    #[arg(long, default_value = "100")]
+    rdy: u32,
+
+    /// Seconds to wait for consumers to catch up after publishing stops
+    #[arg(long, default_value = "5")]
+    drain_timeout: u64,
+}
+
+/// Counters shared by all producers and consumers
+#[derive(Default)]
+struct Counters {
+    published: AtomicU64,
+    published_bytes: AtomicU64,
+    publish_errors: AtomicU64,
+    consumed: AtomicU64,
+    consume_errors: AtomicU64,
+}
+
+/// Nanoseconds since the Unix epoch
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Build a message body of `size` bytes that starts with the current time
+fn message_body(size: usize) -> Bytes {
+    let mut body = BytesMut::with_capacity(size);
+    body.put_u64(now_nanos());
+    body.resize(size, b'x');
+    body.freeze()
+}
+
+/// Connect to nsqd and split the connection into framed halves
+async fn connect(address: &str) -> BenchResult<(FramedRead<OwnedReadHalf, NsqDecoder>, FramedWrite<OwnedWriteHalf, CommandEncoder>)> {
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    let (read_half, write_half) = stream.into_split();
+    Ok((FramedRead::new(read_half, NsqDecoder::new()), FramedWrite::new(write_half, CommandEncoder::new())))
+}
+
+/// Check whether a frame is an nsqd heartbeat
+fn is_heartbeat(frame: &Frame) -> bool {
+    frame.frame_type == FrameType::Response && frame.body.as_ref() == b"_heartbeat_"
+}
+
+/// Publish batches until the deadline, waiting for each response
+async fn run_producer(args: Arc<Args>, counters: Arc<Counters>, deadline: Instant) -> BenchResult<()> {
+    let (mut framed_read, mut framed_write) = connect(&args.nsqd_tcp_address).await?;
+
+    // Spread the target rate evenly over producers
+    let mut ticker = (args.rate > 0).then(|| {
+        let producer_rate = args.rate as f64 / args.producers as f64;
+        tokio::time::interval(Duration::from_secs_f64(args.batch_size as f64 / producer_rate))
+    });
+
+    while Instant::now() < deadline {
+        if let Some(ticker) = ticker.as_mut() {
+            ticker.tick().await;
+        }
+
+        let bodies: Vec<Bytes> = (0..args.batch_size).map(|_| message_body(args.size)).collect();
+        let count = bodies.len() as u64;
+        let command = if bodies.len() == 1 {
+            Command::Pub { topic: args.topic.clone(), body: bodies.into_iter().next().unwrap_or_default() }
+        } else {
+            Command::Mpub { topic: args.topic.clone(), bodies }
+        };
+        framed_write.send(command).await?;
+
+        loop {
+            let Some(frame) = framed_read.next().await else {
+                counters.publish_errors.fetch_add(count, Ordering::Relaxed);
+                return Err("connection closed".into());
+            };
+            let frame = frame?;
+
+            if is_heartbeat(&frame) {
+                framed_write.send(Command::Nop).await?;
+                continue;
+            }
+
+            match frame.frame_type {
+                FrameType::Response => {
+                    counters.published.fetch_add(count, Ordering::Relaxed);
+                    counters.published_bytes.fetch_add(count * args.size as u64, Ordering::Relaxed);
+                }
+                _ => {
+                    warn!("Publish failed: {}", String::from_utf8_lossy(&frame.body));
+                    counters.publish_errors.fetch_add(count, Ordering::Relaxed);
+                }
+            }
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Consume and FIN messages until stopped, returning end-to-end latencies in microseconds
+async fn run_consumer(args: Arc<Args>, counters: Arc<Counters>, mut stop: watch::Receiver<bool>) -> BenchResult<Vec<u64>> {
+    let (mut framed_read, mut framed_write) = connect(&args.nsqd_tcp_address).await?;
+
+    framed_write.send(Command::Sub { topic: args.topic.clone(), channel: args.channel.clone() }).await?;
+    framed_write.send(Command::Rdy { count: args.rdy }).await?;
+
+    let mut latencies = Vec::new();
+    loop {
+        let frame = tokio::select! {
+            frame = framed_read.next() => match frame {
+                Some(frame) => frame?,
+                None => return Err("connection closed".into()),
+            },
+            _ = stop.changed() => break,
+        };
+
+        if is_heartbeat(&frame) {
+            framed_write.send(Command::Nop).await?;
+            continue;
+        }
+
+        match frame.frame_type {
+            FrameType::Message => {
+                let message = Message::from_bytes(frame.body)?;
+                if message.body.len() >= TIMESTAMP_LEN {
+                    let mut timestamp = [0u8; TIMESTAMP_LEN];
+                    timestamp.copy_from_slice(&message.body[..TIMESTAMP_LEN]);
+                    let published_at = u64::from_be_bytes(timestamp);
+                    latencies.push(now_nanos().saturating_sub(published_at) / 1000);
+                }
+                counters.consumed.fetch_add(1, Ordering::Relaxed);
+
+                let message_id = Bytes::copy_from_slice(message.id.as_bytes());
+                framed_write.send(Command::Fin { message_id }).await?;
+            }
+            FrameType::Error => {
+                warn!("Consumer error: {}", String::from_utf8_lossy(&frame.body));
+                counters.consume_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            FrameType::Response => {}
+        }
+    }
+
+    Ok(latencies)
+}
+
+/// Value at the given quantile (0.0-1.0) of sorted samples
+fn percentile(sorted: &[u64], quantile: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Format microseconds as milliseconds
+fn format_ms(micros: u64) -> String {
+    format!("{:.3}ms", micros as f64 / 1000.0)
+}
+
+/// Messages per second over an elapsed time
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+    if seconds > 0.0 { count as f64 / seconds } else { 0.0 }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    if args.producers == 0 && args.consumers == 0 {
+        eprintln!("Error: At least one producer or consumer is required");
+        std::process::exit(1);
+    }
+
+    if args.size < TIMESTAMP_LEN {
+        eprintln!("Error: --size must be at least {} bytes to carry the publish timestamp", TIMESTAMP_LEN);
+        std::process::exit(1);
+    }
+
+    if args.batch_size == 0 || args.duration == 0 || args.rdy == 0 {
+        eprintln!("Error: --batch-size, --duration and --rdy must be at least 1");
+        std::process::exit(1);
+    }
+
+    let args = Arc::new(args);
+    let counters = Arc::new(Counters::default());
+    let (stop_tx, stop_rx) = watch::channel(false);
+
+    info!(
+        "Benchmarking {} with {} producers and {} consumers for {}s",
+        args.nsqd_tcp_address, args.producers, args.consumers, args.duration
+    );
+
+    // Consumers start first so the channel exists before publishing begins
+    let consumers: Vec<_> = (0..args.consumers)
+        .map(|_| tokio::spawn(run_consumer(args.clone(), counters.clone(), stop_rx.clone())))
+        .collect();
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    let producers: Vec<_> = (0..args.producers)
+        .map(|_| tokio::spawn(run_producer(args.clone(), counters.clone(), deadline)))
+        .collect();
+
+    for producer in producers {
+        match producer.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Producer failed: {}", e),
+            Err(e) => error!("Producer task failed: {}", e),
+        }
+    }
+    let publish_elapsed = start.elapsed();
+
+    if args.producers == 0 {
+        tokio::time::sleep_until(deadline.into()).await;
+    } else if args.consumers > 0 {
+        let drain_deadline = Instant::now() + Duration::from_secs(args.drain_timeout);
+        while counters.consumed.load(Ordering::Relaxed) < counters.published.load(Ordering::Relaxed)
+            && Instant::now() < drain_deadline
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    let consume_elapsed = start.elapsed();
+
+    let _ = stop_tx.send(true);
+    let mut latencies = Vec::new();
+    for consumer in consumers {
+        match consumer.await {
+            Ok(Ok(samples)) => latencies.extend(samples),
+            Ok(Err(e)) => {
+                error!("Consumer failed: {}", e);
+                counters.consume_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => error!("Consumer task failed: {}", e),
+        }
+    }
+    latencies.sort_unstable();
+
+    let published = counters.published.load(Ordering::Relaxed);
+    let published_bytes = counters.published_bytes.load(Ordering::Relaxed);
+    let consumed = counters.consumed.load(Ordering::Relaxed);
+
+    println!("Duration:   {:.2}s", publish_elapsed.as_secs_f64());
+    if args.producers > 0 {
+        println!(
+            "Published:  {} messages ({:.1} msg/s, {:.2} MB/s), {} errors",
+            published,
+            per_second(published, publish_elapsed),
+            per_second(published_bytes, publish_elapsed) / 1_000_000.0,
+            counters.publish_errors.load(Ordering::Relaxed)
+        );
+    }
+    if args.consumers > 0 {
+        println!(
+            "Consumed:   {} messages ({:.1} msg/s), {} errors",
+            consumed,
+            per_second(consumed, consume_elapsed),
+            counters.consume_errors.load(Ordering::Relaxed)
+        );
+        if !latencies.is_empty() {
+            println!(
+                "Latency:    p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+                format_ms(percentile(&latencies, 0.5)),
+                format_ms(percentile(&latencies, 0.9)),
+                format_ms(percentile(&latencies, 0.99)),
+                format_ms(percentile(&latencies, 0.999)),
+                format_ms(latencies[latencies.len() - 1])
+            );
+        }
+    }
+
+    Ok(())
+}
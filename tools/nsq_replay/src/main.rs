@@ -0,0 +1,376 @@
+//! nsq_replay - Republish archived messages to a topic
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::{Parser, ValueEnum};
+use flate2::read::MultiGzDecoder;
+use futures::SinkExt;
+use nsq_protocol::{Command, CommandEncoder, FrameType, NsqDecoder};
+use regex::Regex;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{info, warn};
+
+/// First two bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+type ReplayResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser, Debug)]
+#[command(name = "nsq_replay")]
+#[command(about = "Republish archived NSQ messages to a topic")]
+struct Args {
+    /// Archive files to replay, in order (gzip files are detected automatically)
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// NSQd TCP address to publish to
+    #[arg(long, default_value = "127.0.0.1:4150")]
+    nsqd_tcp_address: String,
+
+    /// Topic to publish to
+    #[arg(long)]
+    topic: String,
+
+    /// Archive format
+    #[arg(long, value_enum, default_value = "newline")]
+    format: InputFormat,
+
+    /// Only replay messages published at or after this RFC 3339 time
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only replay messages published before this RFC 3339 time
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+
+    /// Only replay messages whose body matches this regular expression
+    #[arg(long)]
+    pattern: Option<String>,
+
+    /// Maximum messages per second (0 for unlimited)
+    #[arg(long, default_value = "0")]
+    rate: u64,
+
+    /// Messages per PUB/MPUB command
+    #[arg(long, default_value = "1")]
+    batch_size: usize,
+
+    /// Seconds between progress reports
+    #[arg(long, default_value = "5")]
+    progress_interval: u64,
+
+    /// Count matching messages without publishing them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Archive formats, matching what nsq_to_file and nsq_tail write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputFormat {
+    /// Raw bodies separated by newlines (nsq_to_file default)
+    Newline,
+    /// Raw bodies preceded by a 4-byte big-endian length (nsq_to_file --framing length)
+    Length,
+    /// Decorated lines with timestamp and attempts (nsq_to_file --pretty)
+    Pretty,
+    /// One JSON object per line (nsq_tail --json)
+    Json,
+}
+
+impl InputFormat {
+    /// Whether records in this format carry the original publish time
+    fn has_timestamps(self) -> bool {
+        matches!(self, InputFormat::Pretty | InputFormat::Json)
+    }
+}
+
+/// A message read from an archive
+struct Record {
+    timestamp: Option<DateTime<Utc>>,
+    body: Bytes,
+}
+
+/// Sequential reader over one archive file
+struct ArchiveReader {
+    reader: Box<dyn BufRead>,
+    format: InputFormat,
+    pretty_line: Regex,
+}
+
+impl ArchiveReader {
+    /// Open an archive, transparently decompressing gzip files
+    fn open(path: &Path, format: InputFormat) -> ReplayResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+        let reader: Box<dyn BufRead> = if is_gzip {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+
+        Ok(Self {
+            reader,
+            format,
+            pretty_line: Regex::new(r"^\[([^\]]+)\] (.*) \(attempts: \d+, size: \d+ bytes\)$")?,
+        })
+    }
+
+    /// Read the next record, or `None` at the end of the file
+    fn next_record(&mut self) -> ReplayResult<Option<Record>> {
+        if self.format == InputFormat::Length {
+            return self.next_length_prefixed();
+        }
+
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+
+        match self.format {
+            InputFormat::Newline => Ok(Some(Record { timestamp: None, body: Bytes::from(line) })),
+            InputFormat::Pretty => self.parse_pretty(&line).map(Some),
+            InputFormat::Json => parse_json(&line).map(Some),
+            InputFormat::Length => unreachable!("handled above"),
+        }
+    }
+
+    fn next_length_prefixed(&mut self) -> ReplayResult<Option<Record>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut size = [0u8; 4];
+        self.reader.read_exact(&mut size)?;
+        let mut body = vec![0u8; u32::from_be_bytes(size) as usize];
+        self.reader.read_exact(&mut body)?;
+        Ok(Some(Record { timestamp: None, body: Bytes::from(body) }))
+    }
+
+    fn parse_pretty(&self, line: &[u8]) -> ReplayResult<Record> {
+        let line = String::from_utf8_lossy(line);
+        let captures = self.pretty_line
+            .captures(&line)
+            .ok_or_else(|| format!("Malformed pretty line: {}", line))?;
+        let timestamp = NaiveDateTime::parse_from_str(&captures[1], "%Y-%m-%d %H:%M:%S%.3f")?.and_utc();
+        Ok(Record {
+            timestamp: Some(timestamp),
+            body: Bytes::from(captures[2].to_string()),
+        })
+    }
+}
+
+/// Parse a line written by `nsq_tail --json`
+fn parse_json(line: &[u8]) -> ReplayResult<Record> {
+    let value: serde_json::Value = serde_json::from_slice(line)?;
+    let timestamp = value.get("timestamp")
+        .and_then(|v| v.as_str())
+        .map(DateTime::parse_from_rfc3339)
+        .transpose()?
+        .map(|t| t.with_timezone(&Utc));
+
+    let body = if let Some(body) = value.get("body").and_then(|v| v.as_str()) {
+        Bytes::from(body.to_string())
+    } else if let Some(hex) = value.get("body_hex").and_then(|v| v.as_str()) {
+        Bytes::from(hex_decode(hex)?)
+    } else {
+        return Err("JSON record has neither body nor body_hex".into());
+    };
+
+    Ok(Record { timestamp, body })
+}
+
+/// Decode a lowercase or uppercase hex string
+fn hex_decode(hex: &str) -> ReplayResult<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex body: {}", hex).into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.into()))
+        .collect()
+}
+
+/// Which records to replay
+struct RecordFilter {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    pattern: Option<Regex>,
+}
+
+impl RecordFilter {
+    fn matches(&self, record: &Record) -> bool {
+        // nsqd rejects empty bodies
+        if record.body.is_empty() {
+            return false;
+        }
+        if let Some(timestamp) = record.timestamp {
+            if self.since.is_some_and(|since| timestamp < since) || self.until.is_some_and(|until| timestamp >= until) {
+                return false;
+            }
+        }
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&String::from_utf8_lossy(&record.body)))
+    }
+}
+
+/// Connection publishing to the target topic
+struct Publisher {
+    topic: String,
+    framed_read: FramedRead<OwnedReadHalf, NsqDecoder>,
+    framed_write: FramedWrite<OwnedWriteHalf, CommandEncoder>,
+}
+
+impl Publisher {
+    async fn connect(address: &str, topic: String) -> ReplayResult<Self> {
+        info!("Connecting to NSQd at {}", address);
+        let (read_half, write_half) = TcpStream::connect(address).await?.into_split();
+        Ok(Self {
+            topic,
+            framed_read: FramedRead::new(read_half, NsqDecoder::new()),
+            framed_write: FramedWrite::new(write_half, CommandEncoder::new()),
+        })
+    }
+
+    /// Publish a batch and wait for nsqd to acknowledge it
+    async fn publish(&mut self, mut bodies: Vec<Bytes>) -> ReplayResult<()> {
+        let command = if bodies.len() == 1 {
+            Command::Pub { topic: self.topic.clone(), body: bodies.remove(0) }
+        } else {
+            Command::Mpub { topic: self.topic.clone(), bodies }
+        };
+        self.framed_write.send(command).await?;
+
+        loop {
+            let frame = self.framed_read.next().await.ok_or("Connection closed by nsqd")??;
+            match frame.frame_type {
+                FrameType::Response if frame.body.as_ref() == b"_heartbeat_" => {
+                    self.framed_write.send(Command::Nop).await?;
+                }
+                FrameType::Response => return Ok(()),
+                FrameType::Error => {
+                    return Err(format!("Publish failed: {}", String::from_utf8_lossy(&frame.body)).into());
+                }
+                FrameType::Message => warn!("Ignoring unexpected message frame"),
+            }
+        }
+    }
+}
+
+/// Replay counters
+#[derive(Default)]
+struct Progress {
+    read: u64,
+    skipped: u64,
+    published: u64,
+}
+
+impl Progress {
+    fn report(&self, file: &Path, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0.0 { self.published as f64 / seconds } else { 0.0 };
+        info!(
+            "{}: read {}, published {}, skipped {} ({:.1} msg/s)",
+            file.display(), self.read, self.published, self.skipped, rate
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    if (args.since.is_some() || args.until.is_some()) && !args.format.has_timestamps() {
+        eprintln!("Error: --since and --until need an archive format with timestamps (pretty or json)");
+        std::process::exit(1);
+    }
+
+    if args.batch_size == 0 {
+        eprintln!("Error: --batch-size must be at least 1");
+        std::process::exit(1);
+    }
+
+    let filter = RecordFilter {
+        since: args.since,
+        until: args.until,
+        pattern: args.pattern.as_deref().map(Regex::new).transpose()?,
+    };
+
+    let mut publisher = if args.dry_run {
+        None
+    } else {
+        Some(Publisher::connect(&args.nsqd_tcp_address, args.topic.clone()).await?)
+    };
+
+    let mut ticker = (args.rate > 0).then(|| {
+        tokio::time::interval(Duration::from_secs_f64(args.batch_size as f64 / args.rate as f64))
+    });
+
+    let start = Instant::now();
+    let progress_interval = Duration::from_secs(args.progress_interval.max(1));
+    let mut last_report = Instant::now();
+    let mut progress = Progress::default();
+    let mut batch = Vec::with_capacity(args.batch_size);
+
+    for (index, path) in args.files.iter().enumerate() {
+        info!("Replaying {}", path.display());
+        let mut reader = ArchiveReader::open(path, args.format)?;
+
+        loop {
+            let record = reader.next_record()?;
+            let end_of_file = record.is_none();
+
+            if let Some(record) = record {
+                progress.read += 1;
+                if filter.matches(&record) {
+                    batch.push(record.body);
+                } else {
+                    progress.skipped += 1;
+                }
+            }
+
+            // Flush full batches, and whatever is left once the last file ends
+            let last_file = index + 1 == args.files.len();
+            if batch.len() >= args.batch_size || (end_of_file && last_file && !batch.is_empty()) {
+                if let Some(ticker) = ticker.as_mut() {
+                    ticker.tick().await;
+                }
+                let count = batch.len() as u64;
+                let bodies = std::mem::replace(&mut batch, Vec::with_capacity(args.batch_size));
+                if let Some(publisher) = publisher.as_mut() {
+                    publisher.publish(bodies).await?;
+                }
+                progress.published += count;
+            }
+
+            if last_report.elapsed() >= progress_interval {
+                progress.report(path, start.elapsed());
+                last_report = Instant::now();
+            }
+
+            if end_of_file {
+                break;
+            }
+        }
+    }
+
+    let verb = if args.dry_run { "Would publish" } else { "Published" };
+    println!(
+        "{} {} of {} messages to {} in {:.2}s ({} skipped)",
+        verb, progress.published, progress.read, args.topic, start.elapsed().as_secs_f64(), progress.skipped
+    );
+
+    Ok(())
+}
@@ -1,6 +1,9 @@
 //! nsq_stat - Display NSQ statistics
 
-use clap::Parser;
+use std::collections::BTreeMap;
+use std::time::Instant;
+use clap::{Parser, ValueEnum};
+use nsq_common::{LookupdDiscovery, Producer};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tokio::time::{sleep, Duration};
@@ -25,10 +28,68 @@ struct Args {
     /// Show detailed topic/channel information
     #[arg(long)]
     detailed: bool,
+    
+    /// Only show this topic
+    #[arg(long)]
+    topic: Option<String>,
+    
+    /// Only show this channel
+    #[arg(long)]
+    channel: Option<String>,
+    
+    /// Output format
+    #[arg(long, value_enum, default_value = "table")]
+    format: OutputFormat,
+    
+    /// Exit after printing this many samples (0 = run forever)
+    #[arg(long, default_value = "0")]
+    count: u64,
+    
+    /// Alert when a topic or channel depth exceeds this value
+    #[arg(long)]
+    max_depth: Option<u64>,
+    
+    /// Alert when a channel's oldest queued message is older than this many seconds
+    #[arg(long)]
+    max_lag_age: Option<u64>,
+    
+    /// Alert when a channel has fewer connected clients than this
+    #[arg(long)]
+    min_clients: Option<usize>,
 }
 
+/// Output format for periodic samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Columnar table with rates, aggregated across nodes
+    Table,
+    /// One JSON object per node/topic/channel per sample
+    Json,
+    /// CSV with a header row
+    Csv,
+    /// Prometheus exposition text
+    Prometheus,
+}
+
+impl Args {
+    /// Whether any alert threshold was given
+    fn has_thresholds(&self) -> bool {
+        self.max_depth.is_some() || self.max_lag_age.is_some() || self.min_clients.is_some()
+    }
+}
+
+/// Exit code when all thresholds pass
+const EXIT_OK: i32 = 0;
+/// Exit code when at least one threshold is violated
+const EXIT_CRITICAL: i32 = 2;
+/// Exit code when no statistics could be collected
+const EXIT_UNKNOWN: i32 = 3;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NsqdStats {
+    /// Address the stats were fetched from
+    #[serde(skip)]
+    node: String,
     version: String,
     health: String,
     start_time: u64,
@@ -50,16 +111,24 @@ struct ChannelStats {
     channel_name: String,
     depth: u64,
     backend_depth: u64,
+    #[serde(alias = "in_flight_count")]
     inflight_count: u64,
     deferred_count: u64,
     message_count: u64,
     requeue_count: u64,
     timeout_count: u64,
+    #[serde(default)]
+    finish_count: u64,
     clients: Vec<ClientStats>,
+    #[serde(default)]
+    client_count: u64,
     paused: bool,
+    #[serde(default)]
+    oldest_message_age_ms: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 struct ClientStats {
     name: String,
     client_id: String,
@@ -82,6 +151,8 @@ struct ClientStats {
     tls_cipher_suite: String,
     tls_negotiated_protocol: String,
     tls_negotiated_protocol_is_mutual: bool,
+    finish_latency_p50_ms: Option<f64>,
+    finish_latency_p99_ms: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -106,6 +177,7 @@ struct StatsCollector {
     client: Client,
     nsqd_addresses: Vec<String>,
     lookupd_addresses: Vec<String>,
+    discovery: LookupdDiscovery,
 }
 
 impl StatsCollector {
@@ -113,14 +185,30 @@ impl StatsCollector {
         Self {
             client: Client::new(),
             nsqd_addresses,
+            discovery: LookupdDiscovery::new(lookupd_addresses.clone(), None),
             lookupd_addresses,
         }
     }
 
+    /// HTTP addresses of the configured nsqd nodes and those registered
+    /// with lookupd
+    async fn nsqd_addresses(&self) -> Vec<String> {
+        let mut addresses = self.nsqd_addresses.clone();
+        if self.discovery.is_enabled() {
+            match self.discovery.lookup().await {
+                Ok(producers) => addresses.extend(producers.iter().map(Producer::http_address)),
+                Err(e) => error!("Failed to discover nsqd nodes from lookupd: {}", e),
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        addresses.retain(|address| seen.insert(address.clone()));
+        addresses
+    }
+
     async fn collect_nsqd_stats(&self) -> Vec<NsqdStats> {
         let mut all_stats = Vec::new();
         
-        for address in &self.nsqd_addresses {
+        for address in &self.nsqd_addresses().await {
             match self.fetch_nsqd_stats(address).await {
                 Ok(stats) => all_stats.push(stats),
                 Err(e) => {
@@ -140,7 +228,8 @@ impl StatsCollector {
             return Err(format!("HTTP error: {}", response.status()).into());
         }
         
-        let stats: NsqdStats = response.json().await?;
+        let mut stats: NsqdStats = response.json().await?;
+        stats.node = address.to_string();
         Ok(stats)
     }
 
@@ -172,6 +261,328 @@ impl StatsCollector {
     }
 }
 
+/// Number of rows printed between table headers
+const HEADER_INTERVAL: usize = 25;
+
+/// Topic or channel counters summed across nsqd nodes
+#[derive(Debug, Default, Clone)]
+struct Sample {
+    memory_depth: u64,
+    backend_depth: u64,
+    in_flight: u64,
+    deferred: u64,
+    messages: u64,
+    finished: u64,
+    requeued: u64,
+    timed_out: u64,
+    clients: u64,
+}
+
+/// Node, topic and channel a sample belongs to; the node is empty when
+/// samples are summed across nodes and the channel is None for topics
+/// without channels
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SampleKey {
+    node: String,
+    topic: String,
+    channel: Option<String>,
+}
+
+impl SampleKey {
+    fn display_name(&self) -> String {
+        match &self.channel {
+            Some(channel) => format!("{}/{}", self.topic, channel),
+            None => self.topic.clone(),
+        }
+    }
+}
+
+/// Per-second rates between two samples
+struct Rates {
+    messages: f64,
+    finished: f64,
+    requeued: f64,
+    timed_out: f64,
+}
+
+impl Rates {
+    fn between(current: &Sample, previous: &Sample, elapsed: f64) -> Self {
+        Self {
+            messages: rate(current.messages, previous.messages, elapsed),
+            finished: rate(current.finished, previous.finished, elapsed),
+            requeued: rate(current.requeued, previous.requeued, elapsed),
+            timed_out: rate(current.timed_out, previous.timed_out, elapsed),
+        }
+    }
+}
+
+/// Collect statistics per topic/channel, applying the topic and channel filters.
+/// With `per_node` unset, counters are summed across nodes.
+fn collect_samples(
+    nsqd_stats: &[NsqdStats],
+    topic_filter: Option<&str>,
+    channel_filter: Option<&str>,
+    per_node: bool,
+) -> BTreeMap<SampleKey, Sample> {
+    let mut samples: BTreeMap<SampleKey, Sample> = BTreeMap::new();
+    
+    for stats in nsqd_stats {
+        let node = if per_node { stats.node.clone() } else { String::new() };
+        
+        for topic in &stats.topics {
+            if topic_filter.is_some_and(|filter| filter != topic.topic_name) {
+                continue;
+            }
+            
+            // Topics without channels are shown on their own
+            if topic.channels.is_empty() && channel_filter.is_none() {
+                let key = SampleKey { node: node.clone(), topic: topic.topic_name.clone(), channel: None };
+                let sample = samples.entry(key).or_default();
+                sample.memory_depth += topic.depth;
+                sample.backend_depth += topic.backend_depth;
+                sample.messages += topic.message_count;
+            }
+            
+            for channel in &topic.channels {
+                if channel_filter.is_some_and(|filter| filter != channel.channel_name) {
+                    continue;
+                }
+                
+                let key = SampleKey {
+                    node: node.clone(),
+                    topic: topic.topic_name.clone(),
+                    channel: Some(channel.channel_name.clone()),
+                };
+                let sample = samples.entry(key).or_default();
+                sample.memory_depth += channel.depth;
+                sample.backend_depth += channel.backend_depth;
+                sample.in_flight += channel.inflight_count;
+                sample.deferred += channel.deferred_count;
+                sample.messages += channel.message_count;
+                sample.finished += channel.finish_count;
+                sample.requeued += channel.requeue_count;
+                sample.timed_out += channel.timeout_count;
+                sample.clients += channel.client_count.max(channel.clients.len() as u64);
+            }
+        }
+    }
+    
+    samples
+}
+
+/// Per-second rate of a counter between two samples; counters that went
+/// backwards (nsqd restarted) count from zero
+fn rate(current: u64, previous: u64, elapsed: f64) -> f64 {
+    let delta = if current >= previous { current - previous } else { current };
+    delta as f64 / elapsed
+}
+
+/// Print the column headers for the rate table
+fn print_table_header() {
+    println!("{:<32} {:-^38} + {:-^41}", "", "depth", "rates/s");
+    println!("{:<32} {:>8} {:>7} {:>7} {:>6} {:>6} | {:>8} {:>8} {:>7} {:>7} {:>7}",
+        "topic/channel", "total", "mem", "disk", "inflt", "def", "msgs", "fin", "req", "t-o", "clients");
+}
+
+/// Print one table row per topic/channel with rates since the previous sample
+fn print_table_rows(current: &BTreeMap<SampleKey, Sample>, previous: &BTreeMap<SampleKey, Sample>, elapsed: f64) {
+    for (key, sample) in current {
+        let rates = Rates::between(sample, &previous.get(key).cloned().unwrap_or_default(), elapsed);
+        println!("{:<32} {:>8} {:>7} {:>7} {:>6} {:>6} | {:>8.1} {:>8.1} {:>7.1} {:>7.1} {:>7}",
+            key.display_name(),
+            sample.memory_depth + sample.backend_depth,
+            sample.memory_depth,
+            sample.backend_depth,
+            sample.in_flight,
+            sample.deferred,
+            rates.messages,
+            rates.finished,
+            rates.requeued,
+            rates.timed_out,
+            sample.clients,
+        );
+    }
+}
+
+/// Print one JSON line per node/topic/channel; rates are null on the first sample
+fn print_json_rows(
+    timestamp: &str,
+    current: &BTreeMap<SampleKey, Sample>,
+    previous: Option<&BTreeMap<SampleKey, Sample>>,
+    elapsed: f64,
+) {
+    for (key, sample) in current {
+        let rates = previous.map(|previous| Rates::between(sample, &previous.get(key).cloned().unwrap_or_default(), elapsed));
+        let row = serde_json::json!({
+            "timestamp": timestamp,
+            "node": key.node,
+            "topic": key.topic,
+            "channel": key.channel,
+            "depth": sample.memory_depth + sample.backend_depth,
+            "memory_depth": sample.memory_depth,
+            "backend_depth": sample.backend_depth,
+            "in_flight_count": sample.in_flight,
+            "deferred_count": sample.deferred,
+            "message_count": sample.messages,
+            "finish_count": sample.finished,
+            "requeue_count": sample.requeued,
+            "timeout_count": sample.timed_out,
+            "client_count": sample.clients,
+            "message_rate": rates.as_ref().map(|r| r.messages),
+            "finish_rate": rates.as_ref().map(|r| r.finished),
+            "requeue_rate": rates.as_ref().map(|r| r.requeued),
+            "timeout_rate": rates.as_ref().map(|r| r.timed_out),
+        });
+        println!("{}", row);
+    }
+}
+
+/// CSV header matching `print_csv_rows`
+const CSV_HEADER: &str = "timestamp,node,topic,channel,depth,memory_depth,backend_depth,in_flight_count,deferred_count,\
+message_count,finish_count,requeue_count,timeout_count,client_count,message_rate,finish_rate,requeue_rate,timeout_rate";
+
+/// Print one CSV row per node/topic/channel; rates are empty on the first sample
+fn print_csv_rows(
+    timestamp: &str,
+    current: &BTreeMap<SampleKey, Sample>,
+    previous: Option<&BTreeMap<SampleKey, Sample>>,
+    elapsed: f64,
+) {
+    for (key, sample) in current {
+        let rates = match previous {
+            Some(previous) => {
+                let r = Rates::between(sample, &previous.get(key).cloned().unwrap_or_default(), elapsed);
+                format!("{:.3},{:.3},{:.3},{:.3}", r.messages, r.finished, r.requeued, r.timed_out)
+            }
+            None => ",,,".to_string(),
+        };
+        println!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            timestamp,
+            key.node,
+            key.topic,
+            key.channel.as_deref().unwrap_or(""),
+            sample.memory_depth + sample.backend_depth,
+            sample.memory_depth,
+            sample.backend_depth,
+            sample.in_flight,
+            sample.deferred,
+            sample.messages,
+            sample.finished,
+            sample.requeued,
+            sample.timed_out,
+            sample.clients,
+            rates,
+        );
+    }
+}
+
+/// Print nsqd statistics in the Prometheus text exposition format
+fn print_prometheus(nsqd_stats: &[NsqdStats], topic_filter: Option<&str>, channel_filter: Option<&str>) {
+    type TopicValue = fn(&TopicStats) -> u64;
+    type ChannelValue = fn(&ChannelStats) -> u64;
+    
+    let topic_metrics: [(&str, &str, &str, TopicValue); 3] = [
+        ("nsq_topic_depth", "gauge", "Messages queued in memory", |t| t.depth),
+        ("nsq_topic_backend_depth", "gauge", "Messages queued on disk", |t| t.backend_depth),
+        ("nsq_topic_messages_total", "counter", "Messages published", |t| t.message_count),
+    ];
+    let channel_metrics: [(&str, &str, &str, ChannelValue); 9] = [
+        ("nsq_channel_depth", "gauge", "Messages queued in memory", |c| c.depth),
+        ("nsq_channel_backend_depth", "gauge", "Messages queued on disk", |c| c.backend_depth),
+        ("nsq_channel_in_flight_count", "gauge", "Messages in flight", |c| c.inflight_count),
+        ("nsq_channel_deferred_count", "gauge", "Deferred messages", |c| c.deferred_count),
+        ("nsq_channel_messages_total", "counter", "Messages received", |c| c.message_count),
+        ("nsq_channel_finished_total", "counter", "Messages finished", |c| c.finish_count),
+        ("nsq_channel_requeued_total", "counter", "Messages requeued", |c| c.requeue_count),
+        ("nsq_channel_timed_out_total", "counter", "Messages timed out", |c| c.timeout_count),
+        ("nsq_channel_clients", "gauge", "Connected clients", |c| c.client_count.max(c.clients.len() as u64)),
+    ];
+    
+    let topics: Vec<(&str, &TopicStats)> = nsqd_stats
+        .iter()
+        .flat_map(|stats| stats.topics.iter().map(move |topic| (stats.node.as_str(), topic)))
+        .filter(|(_, topic)| topic_filter.is_none_or(|filter| filter == topic.topic_name))
+        .collect();
+    
+    if channel_filter.is_none() {
+        for (name, kind, help, value) in topic_metrics {
+            println!("# HELP {} {}", name, help);
+            println!("# TYPE {} {}", name, kind);
+            for (node, topic) in &topics {
+                println!("{}{{node=\"{}\",topic=\"{}\"}} {}", name, node, topic.topic_name, value(topic));
+            }
+        }
+    }
+    
+    for (name, kind, help, value) in channel_metrics {
+        println!("# HELP {} {}", name, help);
+        println!("# TYPE {} {}", name, kind);
+        for (node, topic) in &topics {
+            for channel in &topic.channels {
+                if channel_filter.is_some_and(|filter| filter != channel.channel_name) {
+                    continue;
+                }
+                println!("{}{{node=\"{}\",topic=\"{}\",channel=\"{}\"}} {}",
+                    name, node, topic.topic_name, channel.channel_name, value(channel));
+            }
+        }
+    }
+}
+
+/// Check collected statistics against the configured thresholds
+fn check_thresholds(nsqd_stats: &[NsqdStats], args: &Args) -> Vec<String> {
+    let mut violations = Vec::new();
+    
+    for topic in nsqd_stats.iter().flat_map(|s| &s.topics) {
+        if let Some(max_depth) = args.max_depth {
+            let depth = topic.depth + topic.backend_depth;
+            if depth > max_depth {
+                violations.push(format!("topic {} depth {} > {}", topic.topic_name, depth, max_depth));
+            }
+        }
+        
+        for channel in &topic.channels {
+            let name = format!("{}/{}", topic.topic_name, channel.channel_name);
+            
+            if let Some(max_depth) = args.max_depth {
+                let depth = channel.depth + channel.backend_depth;
+                if depth > max_depth {
+                    violations.push(format!("channel {} depth {} > {}", name, depth, max_depth));
+                }
+            }
+            
+            if let Some(max_lag_age) = args.max_lag_age {
+                let age = channel.oldest_message_age_ms / 1000;
+                if age > max_lag_age {
+                    violations.push(format!("channel {} lag age {}s > {}s", name, age, max_lag_age));
+                }
+            }
+            
+            if let Some(min_clients) = args.min_clients {
+                if channel.clients.len() < min_clients {
+                    violations.push(format!("channel {} clients {} < {}", name, channel.clients.len(), min_clients));
+                }
+            }
+        }
+    }
+    
+    violations
+}
+
+/// Exit code and report lines of a health probe: UNKNOWN without
+/// statistics, otherwise OK or one CRITICAL line per violated threshold
+fn threshold_report(nsqd_stats: &[NsqdStats], args: &Args) -> (i32, Vec<String>) {
+    if nsqd_stats.is_empty() {
+        return (EXIT_UNKNOWN, vec!["UNKNOWN: no nsqd statistics collected".to_string()]);
+    }
+    
+    let violations = check_thresholds(nsqd_stats, args);
+    if violations.is_empty() {
+        return (EXIT_OK, vec!["OK: all thresholds passed".to_string()]);
+    }
+    (EXIT_CRITICAL, violations.iter().map(|violation| format!("CRITICAL: {}", violation)).collect())
+}
+
 fn print_stats(nsqd_stats: &[NsqdStats], lookupd_stats: &[LookupdStats], detailed: bool) {
     println!("\n=== NSQ Statistics ===");
     
@@ -216,6 +627,9 @@ fn print_stats(nsqd_stats: &[NsqdStats], lookupd_stats: &[LookupdStats], detaile
                         println!("              Message Count: {}", client.message_count);
                         println!("              Finish Count: {}", client.finish_count);
                         println!("              Requeue Count: {}", client.requeue_count);
+                        if let (Some(p50), Some(p99)) = (client.finish_latency_p50_ms, client.finish_latency_p99_ms) {
+                            println!("              Finish Latency: p50 {:.1}ms, p99 {:.1}ms", p50, p99);
+                        }
                         println!("              Connect Time: {}", client.connect_ts);
                         println!("              Sample Rate: {}", client.sample_rate);
                         println!("              User Agent: {}", client.user_agent);
@@ -259,7 +673,7 @@ fn print_stats(nsqd_stats: &[NsqdStats], lookupd_stats: &[LookupdStats], detaile
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    nsq_common::init_tool_logging("nsq_stat")?;
     
     let args = Args::parse();
     
@@ -268,21 +682,175 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
     
-    let collector = StatsCollector::new(args.nsqd_http_address, args.lookupd_http_address);
+    if args.interval == 0 {
+        eprintln!("Error: --interval must be at least 1 second");
+        std::process::exit(1);
+    }
+    
+    let collector = StatsCollector::new(args.nsqd_http_address.clone(), args.lookupd_http_address.clone());
     
+    if args.has_thresholds() {
+        // Single sample health probe
+        let nsqd_stats = collector.collect_nsqd_stats().await;
+        let (code, report) = threshold_report(&nsqd_stats, &args);
+        for line in &report {
+            println!("{}", line);
+        }
+        std::process::exit(code);
+    }
+    
+    if args.detailed {
+        loop {
+            // Clear screen (works on most terminals)
+            print!("\x1B[2J\x1B[1;1H");
+            
+            let nsqd_stats = collector.collect_nsqd_stats().await;
+            let lookupd_stats = collector.collect_lookupd_stats().await;
+            
+            print_stats(&nsqd_stats, &lookupd_stats, args.detailed);
+            
+            println!("\nPress Ctrl+C to exit");
+            println!("Refreshing in {} seconds...", args.interval);
+            
+            sleep(Duration::from_secs(args.interval)).await;
+        }
+    }
+    
+    let topic_filter = args.topic.as_deref();
+    let channel_filter = args.channel.as_deref();
+    let per_node = args.format != OutputFormat::Table;
+    
+    if args.format == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
+    
+    // The table only prints once a previous sample exists to diff against
+    let mut previous: Option<(BTreeMap<SampleKey, Sample>, Instant)> = None;
+    let mut samples_taken = 0;
+    let mut rows_printed = 0;
     loop {
-        // Clear screen (works on most terminals)
-        print!("\x1B[2J\x1B[1;1H");
-        
         let nsqd_stats = collector.collect_nsqd_stats().await;
-        let lookupd_stats = collector.collect_lookupd_stats().await;
+        let sampled_at = Instant::now();
+        let timestamp = chrono::Utc::now().to_rfc3339();
+        let current = collect_samples(&nsqd_stats, topic_filter, channel_filter, per_node);
+        let elapsed = previous.as_ref()
+            .map(|(_, previous_at)| sampled_at.duration_since(*previous_at).as_secs_f64().max(f64::EPSILON))
+            .unwrap_or(0.0);
+        let previous_samples = previous.as_ref().map(|(samples, _)| samples);
         
-        print_stats(&nsqd_stats, &lookupd_stats, args.detailed);
+        match args.format {
+            OutputFormat::Table => {
+                if let Some(previous_samples) = previous_samples {
+                    if rows_printed % HEADER_INTERVAL == 0 {
+                        print_table_header();
+                    } else if current.len() > 1 {
+                        println!();
+                    }
+                    print_table_rows(&current, previous_samples, elapsed);
+                    rows_printed += 1;
+                }
+            }
+            OutputFormat::Json => print_json_rows(&timestamp, &current, previous_samples, elapsed),
+            OutputFormat::Csv => print_csv_rows(&timestamp, &current, previous_samples, elapsed),
+            OutputFormat::Prometheus => print_prometheus(&nsqd_stats, topic_filter, channel_filter),
+        }
         
-        println!("\nPress Ctrl+C to exit");
-        println!("Refreshing in {} seconds...", args.interval);
+        // The table's first sample only primes the rates
+        if args.format != OutputFormat::Table || previous.is_some() {
+            samples_taken += 1;
+        }
+        if args.count > 0 && samples_taken >= args.count {
+            return Ok(());
+        }
         
+        previous = Some((current, sampled_at));
         sleep(Duration::from_secs(args.interval)).await;
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn args(thresholds: &[&str]) -> Args {
+        let mut argv = vec!["nsq_stat", "--nsqd-http-address", "127.0.0.1:4151"];
+        argv.extend_from_slice(thresholds);
+        Args::parse_from(argv)
+    }
+    
+    /// One node with topic `events` holding `topic_depth` messages, and its
+    /// channel `archive` holding `channel_depth` with the oldest
+    /// `oldest_age_ms` old and `clients` connected
+    fn stats(topic_depth: u64, channel_depth: u64, oldest_age_ms: u64, clients: usize) -> Vec<NsqdStats> {
+        let clients: Vec<_> = (0..clients).map(|i| serde_json::json!({"client_id": i.to_string()})).collect();
+        let stats = serde_json::json!({
+            "version": "1.3.0",
+            "health": "OK",
+            "start_time": 0,
+            "topics": [{
+                "topic_name": "events",
+                "depth": topic_depth,
+                "backend_depth": 0,
+                "message_count": 0,
+                "paused": false,
+                "channels": [{
+                    "channel_name": "archive",
+                    "depth": channel_depth / 2,
+                    "backend_depth": channel_depth - channel_depth / 2,
+                    "in_flight_count": 0,
+                    "deferred_count": 0,
+                    "message_count": 0,
+                    "requeue_count": 0,
+                    "timeout_count": 0,
+                    "clients": clients,
+                    "paused": false,
+                    "oldest_message_age_ms": oldest_age_ms,
+                }],
+            }],
+        });
+        vec![serde_json::from_value(stats).unwrap()]
+    }
+    
+    #[test]
+    fn test_no_stats_is_unknown() {
+        let (code, report) = threshold_report(&[], &args(&["--max-depth", "10"]));
+        assert_eq!(code, EXIT_UNKNOWN);
+        assert_eq!(report, vec!["UNKNOWN: no nsqd statistics collected".to_string()]);
+    }
+    
+    #[test]
+    fn test_within_thresholds_is_ok() {
+        let args = args(&["--max-depth", "10", "--max-lag-age", "60", "--min-clients", "1"]);
+        let (code, report) = threshold_report(&stats(10, 10, 60_000, 1), &args);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(report, vec!["OK: all thresholds passed".to_string()]);
+    }
+    
+    #[test]
+    fn test_max_depth_counts_memory_and_disk() {
+        let args = args(&["--max-depth", "10"]);
+        let (code, report) = threshold_report(&stats(11, 11, 0, 0), &args);
+        assert_eq!(code, EXIT_CRITICAL);
+        assert_eq!(report, vec![
+            "CRITICAL: topic events depth 11 > 10".to_string(),
+            "CRITICAL: channel events/archive depth 11 > 10".to_string(),
+        ]);
+    }
+    
+    #[test]
+    fn test_max_lag_age() {
+        let args = args(&["--max-lag-age", "60"]);
+        let (code, report) = threshold_report(&stats(0, 1, 61_000, 0), &args);
+        assert_eq!(code, EXIT_CRITICAL);
+        assert_eq!(report, vec!["CRITICAL: channel events/archive lag age 61s > 60s".to_string()]);
+    }
+    
+    #[test]
+    fn test_min_clients() {
+        let args = args(&["--min-clients", "2"]);
+        let (code, report) = threshold_report(&stats(0, 0, 0, 1), &args);
+        assert_eq!(code, EXIT_CRITICAL);
+        assert_eq!(report, vec!["CRITICAL: channel events/archive clients 1 < 2".to_string()]);
+        assert_eq!(threshold_report(&stats(0, 0, 0, 2), &args).0, EXIT_OK);
+    }
+}
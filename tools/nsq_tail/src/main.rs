@@ -1,8 +1,14 @@
 //! nsq_tail - Tail NSQ topics like tail -f
 
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use clap::Parser;
-use nsq_protocol::{Command, Frame, FrameType, Message, NsqDecoder, NsqEncoder};
+use nsq_common::{DiscoveryEvent, LookupdDiscovery, Producer};
+use nsq_protocol::{Command, CommandEncoder, FrameType, IdentifyResponse, Message, NsqDecoder};
+use regex::Regex;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use futures::SinkExt;
@@ -20,9 +26,17 @@ struct Args {
     #[arg(long)]
     lookupd_http_address: Vec<String>,
     
-    /// Topic to subscribe to
+    /// Topic to subscribe to (may be given multiple times)
     #[arg(long)]
-    topic: String,
+    topic: Vec<String>,
+    
+    /// Subscribe to every lookupd topic matching this regex
+    #[arg(long)]
+    topic_pattern: Option<String>,
+    
+    /// Seconds between re-resolving topics and producers
+    #[arg(long, default_value = "30")]
+    refresh_interval: u64,
     
     /// Channel name
     #[arg(long)]
@@ -32,38 +46,51 @@ struct Args {
     #[arg(long)]
     verbose: bool,
     
+    /// Prefix each message with its topic (implied for multiple topics or a pattern)
+    #[arg(long)]
+    print_topic: bool,
+    
+    /// Prefix each message with its ID
+    #[arg(long)]
+    print_id: bool,
+    
+    /// Emit one JSON object per message with id/topic/timestamp/attempts/body
+    #[arg(long, conflicts_with_all = ["verbose", "print_topic", "print_id"])]
+    json: bool,
+    
+    /// Print message bodies as a hexdump (hex-encode bodies in JSON output)
+    #[arg(long)]
+    hexdump: bool,
+    
     /// Maximum number of messages to display before exiting
     #[arg(long)]
     max_messages: Option<u64>,
 }
 
+/// Subscribes to one topic on one nsqd and forwards messages to the printer
 struct NsqConsumer {
     topic: String,
     channel: String,
-    verbose: bool,
-    max_messages: Option<u64>,
-    message_count: u64,
+    output: mpsc::UnboundedSender<(String, Message)>,
 }
 
 impl NsqConsumer {
-    fn new(topic: String, channel: String, verbose: bool, max_messages: Option<u64>) -> Self {
+    fn new(topic: String, channel: String, output: mpsc::UnboundedSender<(String, Message)>) -> Self {
         Self {
             topic,
             channel,
-            verbose,
-            max_messages,
-            message_count: 0,
+            output,
         }
     }
 
-    async fn connect_and_consume(&mut self, address: &str) -> Result<(), Box<dyn std::error::Error>> {
+    async fn connect_and_consume(&self, address: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("Connecting to NSQd at {}", address);
         
         let stream = TcpStream::connect(address).await?;
         let (read_half, write_half) = stream.into_split();
         
         let mut framed_read = FramedRead::new(read_half, NsqDecoder::new());
-        let mut framed_write = FramedWrite::new(write_half, NsqEncoder);
+        let mut framed_write = FramedWrite::new(write_half, CommandEncoder::new());
         
         // Send IDENTIFY command
         let identify_data = serde_json::json!({
@@ -76,33 +103,28 @@ impl NsqConsumer {
             "output_buffer_timeout": 250
         });
         
-        let identify_cmd = Command::Identify { data: identify_data };
-        let identify_frame = Frame::new(FrameType::Response, identify_cmd.to_bytes()?);
-        framed_write.send(identify_frame).await?;
+        framed_write.send(Command::Identify { data: identify_data }).await?;
         
-        // Wait for OK response
-        if let Some(frame) = framed_read.next().await {
-            let frame = frame?;
-            if frame.frame_type != FrameType::Response {
-                return Err("Expected OK response after IDENTIFY".into());
-            }
-            info!("Connected successfully");
+        // Wait for the negotiated settings
+        let frame = framed_read.next().await.ok_or("Connection closed during IDENTIFY")??;
+        if frame.frame_type != FrameType::Response {
+            return Err("Expected response after IDENTIFY".into());
+        }
+        match IdentifyResponse::parse(&frame.body)? {
+            Some(negotiated) => info!("Connected successfully to nsqd {} (msg_timeout {}ms)", negotiated.version, negotiated.msg_timeout),
+            None => info!("Connected successfully"),
         }
         
         // Subscribe to topic/channel
-        let sub_cmd = Command::Sub {
+        framed_write.send(Command::Sub {
             topic: self.topic.clone(),
             channel: self.channel.clone(),
-        };
-        let sub_frame = Frame::new(FrameType::Response, sub_cmd.to_bytes()?);
-        framed_write.send(sub_frame).await?;
+        }).await?;
         
         // Set ready count
-        let rdy_cmd = Command::Rdy { count: 1 };
-        let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-        framed_write.send(rdy_frame).await?;
+        framed_write.send(Command::Rdy { count: 1 }).await?;
         
-        info!("Subscribed to topic '{}' channel '{}'", self.topic, self.channel);
+        info!("Subscribed to topic '{}' channel '{}' on {}", self.topic, self.channel, address);
         
         // Main message processing loop
         while let Some(frame) = framed_read.next().await {
@@ -110,20 +132,15 @@ impl NsqConsumer {
             
             match frame.frame_type {
                 FrameType::Message => {
-                    self.handle_message(frame.body).await?;
-                    
-                    // Send RDY for next message
-                    let rdy_cmd = Command::Rdy { count: 1 };
-                    let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-                    framed_write.send(rdy_frame).await?;
-                    
-                    // Check if we've reached max messages
-                    if let Some(max) = self.max_messages {
-                        if self.message_count >= max {
-                            info!("Reached maximum message count ({}), exiting", max);
-                            break;
-                        }
+                    let message = Message::from_bytes(frame.body)?;
+                    let message_id = bytes::Bytes::copy_from_slice(message.id.as_bytes());
+                    if self.output.send((self.topic.clone(), message)).is_err() {
+                        // The printer has stopped; leave the message to be redelivered
+                        break;
                     }
+                    
+                    // Acknowledge so nsqd sends the next message instead of requeueing
+                    framed_write.send(Command::Fin { message_id }).await?;
                 }
                 FrameType::Response => {
                     info!("Received response: {}", String::from_utf8_lossy(&frame.body));
@@ -137,60 +154,172 @@ impl NsqConsumer {
         
         Ok(())
     }
+}
 
-    async fn handle_message(&mut self, message_data: bytes::Bytes) -> Result<(), Box<dyn std::error::Error>> {
-        let message = Message::from_bytes(message_data)?;
-        self.message_count += 1;
+/// Prints messages from all subscriptions
+struct MessagePrinter {
+    verbose: bool,
+    /// Prefix each message with its topic
+    print_topic: bool,
+    /// Prefix each message with its ID
+    print_id: bool,
+    json: bool,
+    hexdump: bool,
+}
+
+impl MessagePrinter {
+    fn print(&self, topic: &str, message: &Message) {
+        if self.json {
+            println!("{}", self.to_json(topic, message));
+            return;
+        }
+        
+        let mut prefix = String::new();
+        if self.print_topic {
+            prefix.push_str(&format!("{}: ", topic));
+        }
+        if self.print_id {
+            prefix.push_str(&format!("{} ", message.id));
+        }
         
         if self.verbose {
-            println!("[{}] {} (attempts: {}, size: {} bytes)", 
+            let body = if self.hexdump { String::new() } else { String::from_utf8_lossy(&message.body).into_owned() };
+            println!("{}[{}] {} (attempts: {}, size: {} bytes)", 
+                prefix,
                 message.timestamp.format("%Y-%m-%d %H:%M:%S%.3f"),
-                String::from_utf8_lossy(&message.body),
+                body,
                 message.attempts,
                 message.body.len()
             );
+        } else if self.hexdump {
+            if !prefix.is_empty() {
+                println!("{}", prefix.trim_end());
+            }
         } else {
-            println!("{}", String::from_utf8_lossy(&message.body));
+            println!("{}{}", prefix, String::from_utf8_lossy(&message.body));
         }
         
-        Ok(())
+        if self.hexdump {
+            print!("{}", hexdump(&message.body));
+        }
     }
+    
+    /// JSON representation of a message; bodies that are not valid UTF-8
+    /// (or all bodies in hexdump mode) are hex-encoded as `body_hex`
+    fn to_json(&self, topic: &str, message: &Message) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": message.id.to_string(),
+            "topic": topic,
+            "timestamp": message.timestamp.to_rfc3339(),
+            "attempts": message.attempts,
+        });
+        
+        match std::str::from_utf8(&message.body) {
+            Ok(body) if !self.hexdump => json["body"] = serde_json::Value::from(body),
+            _ => json["body_hex"] = serde_json::Value::from(hex_encode(&message.body)),
+        }
+        
+        json
+    }
+}
+
+/// Lowercase hex encoding
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
-async fn discover_nsqd_addresses(lookupd_addresses: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut nsqd_addresses = Vec::new();
+/// Canonical hex+ASCII dump, in the layout of `hexdump -C`
+fn hexdump(data: &[u8]) -> String {
+    let mut output = String::new();
     
-    for lookupd_addr in lookupd_addresses {
-        let url = format!("http://{}/nodes", lookupd_addr);
-        let response = reqwest::get(&url).await?;
+    for (line, chunk) in data.chunks(16).enumerate() {
+        output.push_str(&format!("{:08x} ", line * 16));
+        for i in 0..16 {
+            if i % 8 == 0 {
+                output.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => output.push_str(&format!("{:02x} ", byte)),
+                None => output.push_str("   "),
+            }
+        }
         
-        if response.status().is_success() {
-            let nodes: serde_json::Value = response.json().await?;
-            
-            if let Some(producers) = nodes.get("producers") {
-                if let Some(producers_array) = producers.as_array() {
-                    for producer in producers_array {
-                        if let Some(broadcast_address) = producer.get("broadcast_address") {
-                            if let Some(tcp_port) = producer.get("tcp_port") {
-                                let address = format!("{}:{}", 
-                                    broadcast_address.as_str().unwrap_or("localhost"),
-                                    tcp_port.as_u64().unwrap_or(4150)
-                                );
-                                nsqd_addresses.push(address);
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        output.push_str(&format!(" |{}|\n", ascii));
+    }
+    output.push_str(&format!("{:08x}\n", data.len()));
+    
+    output
+}
+
+/// Resolves which topics to tail and where they live
+struct TopicResolver {
+    topics: Vec<String>,
+    pattern: Option<Regex>,
+    nsqd_addresses: Vec<String>,
+    lookupd_addresses: Vec<String>,
+    lookupd: LookupdDiscovery,
+    /// lookupd producers of each resolved topic
+    producers: HashMap<String, LookupdDiscovery>,
+}
+
+impl TopicResolver {
+    /// Current topics: the explicit ones plus lookupd topics matching the pattern
+    async fn resolve_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.topics.clone();
+        
+        if let Some(pattern) = &self.pattern {
+            match self.lookupd.topics().await {
+                Ok(found) => topics.extend(found.into_iter().filter(|topic| pattern.is_match(topic))),
+                Err(e) => warn!("Failed to fetch topics from lookupd: {}", e),
+            }
+        }
+        
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+    
+    /// nsqd addresses for a topic, the configured ones plus its lookupd
+    /// producers, and the addresses of producers that left since the last call
+    async fn resolve_addresses(&mut self, topic: &str) -> (Vec<String>, Vec<String>) {
+        let mut addresses = self.nsqd_addresses.clone();
+        let mut removed = Vec::new();
+        
+        if self.lookupd.is_enabled() {
+            let lookupd_addresses = &self.lookupd_addresses;
+            let discovery = self.producers.entry(topic.to_string())
+                .or_insert_with(|| LookupdDiscovery::new(lookupd_addresses.clone(), Some(topic.to_string())));
+            match discovery.poll().await {
+                Ok(events) => {
+                    for event in events {
+                        match event {
+                            DiscoveryEvent::Added(producer) => info!("Found nsqd {} for topic {}", producer.tcp_address(), topic),
+                            DiscoveryEvent::Removed(producer) => {
+                                info!("nsqd {} left topic {}", producer.tcp_address(), topic);
+                                removed.push(producer.tcp_address());
                             }
                         }
                     }
                 }
+                Err(e) => warn!("Failed to look up topic {}: {}", topic, e),
             }
+            addresses.extend(discovery.producers().map(Producer::tcp_address));
         }
+        
+        let mut seen = HashSet::new();
+        addresses.retain(|address| seen.insert(address.clone()));
+        removed.retain(|address| !seen.contains(address));
+        (addresses, removed)
     }
-    
-    Ok(nsqd_addresses)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    nsq_common::init_tool_logging("nsq_tail")?;
     
     let args = Args::parse();
     
@@ -199,53 +328,101 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
     
-    let mut nsqd_addresses = args.nsqd_tcp_address;
+    if args.topic.is_empty() && args.topic_pattern.is_none() {
+        eprintln!("Error: At least one --topic or a --topic-pattern must be specified");
+        std::process::exit(1);
+    }
     
-    // Discover NSQd addresses from lookupd if provided
-    if !args.lookupd_http_address.is_empty() {
-        match discover_nsqd_addresses(&args.lookupd_http_address).await {
-            Ok(discovered) => {
-                info!("Discovered {} NSQd instances from lookupd", discovered.len());
-                nsqd_addresses.extend(discovered);
-            }
-            Err(e) => {
-                warn!("Failed to discover NSQd addresses from lookupd: {}", e);
-            }
-        }
+    if args.topic_pattern.is_some() && args.lookupd_http_address.is_empty() {
+        eprintln!("Error: --topic-pattern requires --lookupd-http-address");
+        std::process::exit(1);
     }
     
-    if nsqd_addresses.is_empty() {
-        eprintln!("Error: No NSQd addresses available");
+    if args.refresh_interval == 0 {
+        eprintln!("Error: --refresh-interval must be at least 1 second");
         std::process::exit(1);
     }
     
-    let mut consumer = NsqConsumer::new(
-        args.topic,
-        args.channel,
-        args.verbose,
-        args.max_messages,
-    );
-    
-    // Try to connect to the first available NSQd
-    let mut connected = false;
-    for address in &nsqd_addresses {
-        match consumer.connect_and_consume(address).await {
-            Ok(_) => {
-                connected = true;
-                break;
+    let pattern = match args.topic_pattern.as_deref().map(Regex::new).transpose() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("Error: Invalid --topic-pattern: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let printer = MessagePrinter {
+        verbose: args.verbose,
+        print_topic: args.print_topic || args.topic.len() > 1 || pattern.is_some(),
+        print_id: args.print_id,
+        json: args.json,
+        hexdump: args.hexdump,
+    };
+    let mut resolver = TopicResolver {
+        topics: args.topic,
+        pattern,
+        nsqd_addresses: args.nsqd_tcp_address,
+        lookupd: LookupdDiscovery::new(args.lookupd_http_address.clone(), None),
+        lookupd_addresses: args.lookupd_http_address,
+        producers: HashMap::new(),
+    };
+    
+    let (output_tx, mut output_rx) = mpsc::unbounded_channel::<(String, Message)>();
+    let channel = args.channel;
+    
+    // Subscribe to newly resolved topics and producers, drop subscriptions
+    // to producers that left, and restart subscriptions whose connection ended
+    tokio::spawn(async move {
+        let mut subscriptions: HashMap<(String, String), JoinHandle<()>> = HashMap::new();
+        let mut refresh = tokio::time::interval(Duration::from_secs(args.refresh_interval));
+        
+        loop {
+            refresh.tick().await;
+            subscriptions.retain(|_, handle| !handle.is_finished());
+            
+            for topic in resolver.resolve_topics().await {
+                let (addresses, removed) = resolver.resolve_addresses(&topic).await;
+                for address in removed {
+                    if let Some(handle) = subscriptions.remove(&(topic.clone(), address)) {
+                        handle.abort();
+                    }
+                }
+                
+                for address in addresses {
+                    let key = (topic.clone(), address.clone());
+                    if subscriptions.contains_key(&key) {
+                        continue;
+                    }
+                    
+                    let consumer = NsqConsumer::new(topic.clone(), channel.clone(), output_tx.clone());
+                    let handle = tokio::spawn(async move {
+                        if let Err(e) = consumer.connect_and_consume(&address).await {
+                            error!("Subscription to {} on {} ended: {}", consumer.topic, address, e);
+                        }
+                    });
+                    subscriptions.insert(key, handle);
+                }
             }
-            Err(e) => {
-                error!("Failed to connect to {}: {}", address, e);
-                continue;
+            
+            if subscriptions.is_empty() {
+                warn!("No topics or nsqd nodes to subscribe to yet");
             }
         }
-    }
+    });
     
-    if !connected {
-        eprintln!("Error: Failed to connect to any NSQd instance");
-        std::process::exit(1);
+    let mut message_count = 0;
+    while let Some((topic, message)) = output_rx.recv().await {
+        printer.print(&topic, &message);
+        message_count += 1;
+        
+        // Check if we've reached max messages
+        if let Some(max) = args.max_messages {
+            if message_count >= max {
+                info!("Reached maximum message count ({}), exiting", max);
+                break;
+            }
+        }
     }
     
     Ok(())
 }
-
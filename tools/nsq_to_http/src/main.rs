@@ -1,16 +1,19 @@
 //! nsq_to_http - Consumer that posts messages to HTTP endpoints
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use futures::SinkExt;
-use nsq_protocol::{Command, Frame, FrameType, Message, NsqDecoder, NsqEncoder};
+use nsq_common::{DiskQueue, LookupdDiscovery, Producer};
+use nsq_protocol::{encode_with_headers, validate_headers, Command, CommandEncoder, FrameType, IdentifyResponse, Message, NsqDecoder, ZstdStream, MAX_HEADER_VALUE_LEN};
 use reqwest::Client;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
-use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, Mutex as AsyncMutex, Semaphore};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info, warn};
+use tracing::{error, info, warn, Instrument};
 
 #[derive(Parser, Debug)]
 #[command(name = "nsq_to_http")]
@@ -24,6 +27,10 @@ struct Args {
     #[arg(long)]
     lookupd_http_address: Vec<String>,
     
+    /// Seconds between lookupd queries while no NSQd is reachable
+    #[arg(long, default_value = "60")]
+    lookupd_poll_interval: u64,
+    
     /// Topic to subscribe to
     #[arg(long)]
     topic: String,
@@ -32,15 +39,27 @@ struct Args {
     #[arg(long)]
     channel: String,
     
-    /// HTTP endpoint URL
+    /// HTTP endpoint URLs (support {{topic}}, {{channel}}, {{message_id}}, {{attempts}}, {{timestamp}})
+    #[arg(long, required = true)]
+    http_endpoint: Vec<String>,
+    
+    /// Send each message to one endpoint in turn instead of to every endpoint
     #[arg(long)]
-    http_endpoint: String,
+    round_robin: bool,
+    
+    /// Post the raw message body instead of the JSON envelope
+    #[arg(long)]
+    raw_body: bool,
+    
+    /// Content-Type header for raw bodies
+    #[arg(long, default_value = "application/octet-stream")]
+    content_type: String,
     
     /// HTTP method (GET, POST, PUT, PATCH)
     #[arg(long, default_value = "POST")]
     http_method: String,
     
-    /// HTTP headers (format: "Header: Value")
+    /// HTTP headers (format: "Header: Value", values support templates)
     #[arg(long)]
     http_headers: Vec<String>,
     
@@ -59,35 +78,247 @@ struct Args {
     /// Maximum retry attempts
     #[arg(long, default_value = "3")]
     max_retries: u32,
+    
+    /// HTTP status codes worth retrying, e.g. 429,502,503; any other error
+    /// status fails the message permanently (default: retry every status)
+    #[arg(long, value_delimiter = ',')]
+    retry_on: Vec<u16>,
+    
+    /// Publish permanently failed messages to this topic on the nsqd they
+    /// came from instead of dropping them
+    #[arg(long)]
+    dead_letter_topic: Option<String>,
+    
+    /// Base delay in milliseconds for requeueing failed messages, multiplied by attempts
+    #[arg(long, default_value = "5000")]
+    requeue_delay: u64,
+    
+    /// Maximum requeue delay in milliseconds
+    #[arg(long, default_value = "900000")]
+    max_requeue_delay: u64,
+    
+    /// Maximum HTTP requests per second (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    max_rps: f64,
+    
+    /// Error rate (0.0-1.0) that opens the circuit breaker and pauses consumption
+    #[arg(long)]
+    circuit_error_rate: Option<f64>,
+    
+    /// Number of recent deliveries the error rate is measured over
+    #[arg(long, default_value = "20")]
+    circuit_window: usize,
+    
+    /// Seconds to stay paused before probing the endpoint again
+    #[arg(long, default_value = "30")]
+    circuit_cooldown: u64,
+    
+    /// Directory for spooling messages to disk while endpoints are failing
+    #[arg(long)]
+    spool_dir: Option<String>,
+    
+    /// Interval in milliseconds between attempts to replay spooled messages
+    #[arg(long, default_value = "1000")]
+    spool_replay_interval: u64,
+    
+    /// Negotiate zstd stream compression with nsqd (nsq-rust extension)
+    #[arg(long)]
+    zstd: bool,
+    
+    /// zstd compression level (1-19)
+    #[arg(long, default_value = "3")]
+    zstd_level: i32,
+    
+    /// Messages combined into one request (1 posts each message on its own)
+    #[arg(long, default_value = "1")]
+    batch_size: usize,
+    
+    /// Milliseconds to wait for a batch to fill before posting it anyway
+    #[arg(long, default_value = "1000")]
+    batch_timeout: u64,
+    
+    /// Body of batched requests
+    #[arg(long, value_enum, default_value = "json")]
+    batch_format: BatchFormat,
+}
+
+/// How a batch of messages is combined into one request body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BatchFormat {
+    /// A JSON array with one item per message
+    Json,
+    /// One JSON item per line
+    Ndjson,
+}
+
+/// Maximum size of a single spool file
+const SPOOL_MAX_FILE_SIZE: usize = 100 * 1024 * 1024;
+/// Maximum size of a single spooled message
+const SPOOL_MAX_MSG_SIZE: usize = 16 * 1024 * 1024;
+/// Highest RDY count nsqd accepts by default, used until it reports its own
+const MAX_RDY_COUNT: usize = 2500;
+
+/// Options for constructing an HttpPoster
+struct HttpPosterOptions {
+    endpoints: Vec<String>,
+    round_robin: bool,
+    method: String,
+    headers: Vec<String>,
+    timeout: u64,
+    max_concurrent: usize,
+    retry_failed: bool,
+    max_retries: u32,
+    retry_on: Vec<u16>,
+    raw_body: bool,
+    content_type: String,
+    topic: String,
+    channel: String,
+    max_rps: f64,
+    batch_format: BatchFormat,
+}
+
+/// Token bucket limiting outgoing HTTP requests
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    state: AsyncMutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        let burst = rate.max(1.0);
+        Self {
+            rate,
+            burst,
+            state: AsyncMutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Wait until a request may be sent
+    async fn acquire(&self) {
+        let mut state = self.state.lock().await;
+        loop {
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+            *last = now;
+
+            if *tokens >= 1.0 {
+                *tokens -= 1.0;
+                return;
+            }
+
+            let wait = (1.0 - *tokens) / self.rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+/// Circuit breaker state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Pauses consumption while the downstream error rate is too high
+struct CircuitBreaker {
+    error_rate: f64,
+    window: usize,
+    cooldown: Duration,
+    state: Mutex<(CircuitState, VecDeque<bool>)>,
+}
+
+impl CircuitBreaker {
+    fn new(error_rate: f64, window: usize, cooldown: Duration) -> Self {
+        Self {
+            error_rate,
+            window: window.max(1),
+            cooldown,
+            state: Mutex::new((CircuitState::Closed, VecDeque::new())),
+        }
+    }
+
+    fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().0
+    }
+
+    /// Record a delivery outcome, returning the new state if it changed
+    fn record(&self, success: bool) -> Option<CircuitState> {
+        let mut guard = self.state.lock().unwrap();
+        let (state, outcomes) = &mut *guard;
+
+        match *state {
+            // Results of deliveries started before the circuit opened are ignored
+            CircuitState::Open => None,
+            CircuitState::HalfOpen => {
+                outcomes.clear();
+                *state = if success { CircuitState::Closed } else { CircuitState::Open };
+                Some(*state)
+            }
+            CircuitState::Closed => {
+                outcomes.push_back(success);
+                while outcomes.len() > self.window {
+                    outcomes.pop_front();
+                }
+                if outcomes.len() < self.window {
+                    return None;
+                }
+
+                let failures = outcomes.iter().filter(|ok| !**ok).count();
+                if failures as f64 / outcomes.len() as f64 >= self.error_rate {
+                    outcomes.clear();
+                    *state = CircuitState::Open;
+                    Some(*state)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Move from open to half-open after the cool-down
+    fn half_open(&self) -> bool {
+        let mut guard = self.state.lock().unwrap();
+        if guard.0 == CircuitState::Open {
+            guard.0 = CircuitState::HalfOpen;
+            true
+        } else {
+            false
+        }
+    }
 }
 
 struct HttpPoster {
     client: Client,
-    endpoint: String,
+    endpoints: Vec<String>,
+    round_robin: bool,
+    next_endpoint: AtomicUsize,
     method: String,
     headers: Vec<(String, String)>,
     max_concurrent: usize,
     semaphore: Arc<Semaphore>,
     retry_failed: bool,
     max_retries: u32,
+    /// Statuses worth retrying; empty retries every status
+    retry_on: Vec<u16>,
+    raw_body: bool,
+    content_type: String,
+    topic: String,
+    channel: String,
+    rate_limiter: Option<RateLimiter>,
+    batch_format: BatchFormat,
 }
 
 impl HttpPoster {
-    fn new(
-        endpoint: String,
-        method: String,
-        headers: Vec<String>,
-        timeout: u64,
-        max_concurrent: usize,
-        retry_failed: bool,
-        max_retries: u32,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    fn new(options: HttpPosterOptions) -> Result<Self, Box<dyn std::error::Error>> {
         let client = Client::builder()
-            .timeout(Duration::from_secs(timeout))
+            .timeout(Duration::from_secs(options.timeout))
             .build()?;
         
         let mut parsed_headers = Vec::new();
-        for header in headers {
+        for header in options.headers {
             if let Some((key, value)) = header.split_once(':') {
                 parsed_headers.push((key.trim().to_string(), value.trim().to_string()));
             } else {
@@ -97,16 +328,45 @@ impl HttpPoster {
         
         Ok(Self {
             client,
-            endpoint,
-            method,
+            endpoints: options.endpoints,
+            round_robin: options.round_robin,
+            next_endpoint: AtomicUsize::new(0),
+            method: options.method,
             headers: parsed_headers,
-            max_concurrent,
-            semaphore: Arc::new(Semaphore::new(max_concurrent)),
-            retry_failed,
-            max_retries,
+            max_concurrent: options.max_concurrent,
+            semaphore: Arc::new(Semaphore::new(options.max_concurrent)),
+            retry_failed: options.retry_failed,
+            max_retries: options.max_retries,
+            retry_on: options.retry_on,
+            raw_body: options.raw_body,
+            content_type: options.content_type,
+            topic: options.topic,
+            channel: options.channel,
+            rate_limiter: (options.max_rps > 0.0).then(|| RateLimiter::new(options.max_rps)),
+            batch_format: options.batch_format,
         })
     }
 
+    /// Expand {{...}} placeholders with message values
+    fn render_template(&self, template: &str, message: &Message) -> String {
+        template
+            .replace("{{topic}}", &self.topic)
+            .replace("{{channel}}", &self.channel)
+            .replace("{{message_id}}", &message.id.to_string())
+            .replace("{{attempts}}", &message.attempts.to_string())
+            .replace("{{timestamp}}", &message.timestamp.timestamp().to_string())
+    }
+
+    /// Pick the endpoints a message is sent to
+    fn select_endpoints(&self) -> Vec<&String> {
+        if self.round_robin {
+            let index = self.next_endpoint.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
+            vec![&self.endpoints[index]]
+        } else {
+            self.endpoints.iter().collect()
+        }
+    }
+
     async fn post_message(&self, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
         // Acquire semaphore permit to control concurrency
         let _permit = self.semaphore.acquire().await
@@ -115,39 +375,126 @@ impl HttpPoster {
         info!("Processing message (concurrent requests: {})", 
             self.max_concurrent - self.semaphore.available_permits());
         
-        let mut request = match self.method.to_uppercase().as_str() {
-            "GET" => self.client.get(&self.endpoint),
-            "POST" => self.client.post(&self.endpoint),
-            "PUT" => self.client.put(&self.endpoint),
-            "PATCH" => self.client.patch(&self.endpoint),
-            _ => return Err(format!("Unsupported HTTP method: {}", self.method).into()),
-        };
+        for endpoint in self.select_endpoints() {
+            let url = self.render_template(endpoint, message);
+            self.post_to_endpoint(&url, message).await?;
+        }
         
-        // Add headers
-        for (key, value) in &self.headers {
-            request = request.header(key, value);
+        Ok(())
+    }
+
+    /// Post messages as one request to each selected endpoint. Templates are
+    /// rendered with the first message.
+    async fn post_batch(&self, messages: &[Message]) -> Result<(), Box<dyn std::error::Error>> {
+        let first = messages.first().ok_or("Empty batch")?;
+        let _permit = self.semaphore.acquire().await
+            .map_err(|e| format!("Failed to acquire semaphore: {}", e))?;
+        
+        info!("Processing batch of {} messages (concurrent requests: {})", messages.len(),
+            self.max_concurrent - self.semaphore.available_permits());
+        
+        let (content_type, body) = self.batch_body(messages)?;
+        for endpoint in self.select_endpoints() {
+            let url = self.render_template(endpoint, first);
+            let request = self.request(&url, first)?
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(body.clone());
+            self.send(&url, request).await?;
         }
         
-        // Add message data as JSON body
-        let message_data = serde_json::json!({
+        Ok(())
+    }
+
+    /// JSON envelope of a message
+    fn envelope(message: &Message) -> serde_json::Value {
+        serde_json::json!({
             "id": message.id.to_string(),
             "timestamp": message.timestamp.to_rfc3339(),
             "attempts": message.attempts,
             "body": String::from_utf8_lossy(&message.body),
             "size": message.body.len()
-        });
+        })
+    }
+
+    /// Content type and body of a batch: envelopes, or with --raw-body the
+    /// bodies themselves, as a JSON array or one per line
+    fn batch_body(&self, messages: &[Message]) -> Result<(&'static str, Vec<u8>), Box<dyn std::error::Error>> {
+        match self.batch_format {
+            BatchFormat::Json => {
+                let items: Vec<serde_json::Value> = messages.iter()
+                    .map(|message| if self.raw_body {
+                        serde_json::Value::String(String::from_utf8_lossy(&message.body).into_owned())
+                    } else {
+                        Self::envelope(message)
+                    })
+                    .collect();
+                Ok(("application/json", serde_json::to_vec(&items)?))
+            }
+            BatchFormat::Ndjson => {
+                let mut body = Vec::new();
+                for message in messages {
+                    if self.raw_body {
+                        body.extend_from_slice(&message.body);
+                    } else {
+                        serde_json::to_writer(&mut body, &Self::envelope(message))?;
+                    }
+                    body.push(b'\n');
+                }
+                Ok(("application/x-ndjson", body))
+            }
+        }
+    }
+
+    /// Start a request with the configured method and headers
+    fn request(&self, url: &str, message: &Message) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error>> {
+        let mut request = match self.method.to_uppercase().as_str() {
+            "GET" => self.client.get(url),
+            "POST" => self.client.post(url),
+            "PUT" => self.client.put(url),
+            "PATCH" => self.client.patch(url),
+            _ => return Err(format!("Unsupported HTTP method: {}", self.method).into()),
+        };
+        
+        // Add headers
+        for (key, value) in &self.headers {
+            request = request.header(key, self.render_template(value, message));
+        }
         
-        request = request.json(&message_data);
+        Ok(request)
+    }
+
+    async fn post_to_endpoint(&self, url: &str, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
+        let mut request = self.request(url, message)?;
+        
+        if self.raw_body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, &self.content_type)
+                .body(message.body.to_vec());
+        } else {
+            // Add message data as JSON body
+            request = request.json(&Self::envelope(message));
+        }
         
+        self.send(url, request).await
+    }
+
+    /// Send a request, retrying failures when enabled
+    async fn send(&self, url: &str, request: reqwest::RequestBuilder) -> Result<(), Box<dyn std::error::Error>> {
         // Send request with retries
         let mut last_error = None;
         for attempt in 0..=self.max_retries {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+            
             match request.try_clone().unwrap().send().await {
                 Ok(response) => {
                     if response.status().is_success() {
                         info!("Successfully posted message to {} (status: {})", 
-                            self.endpoint, response.status());
+                            url, response.status());
                         return Ok(());
+                    } else if !self.retry_on.is_empty() && !self.retry_on.contains(&response.status().as_u16()) {
+                        return Err(Box::new(RejectedStatus { url: url.to_string(), status: response.status() }));
                     } else {
                         let error_msg = format!("HTTP error: {}", response.status());
                         if attempt < self.max_retries && self.retry_failed {
@@ -164,6 +511,8 @@ impl HttpPoster {
                     if attempt < self.max_retries && self.retry_failed {
                         warn!("Attempt {} failed: {}, retrying...", attempt + 1, last_error.as_ref().unwrap());
                         tokio::time::sleep(Duration::from_millis(100 * (attempt + 1) as u64)).await;
+                    } else {
+                        break;
                     }
                 }
             }
@@ -173,32 +522,221 @@ impl HttpPoster {
     }
 }
 
+/// A response status that is not in --retry-on, failing the message for good
+#[derive(Debug)]
+struct RejectedStatus {
+    url: String,
+    status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for RejectedStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP error: {} from {}", self.status, self.url)
+    }
+}
+
+impl std::error::Error for RejectedStatus {}
+
+/// Why posting a message failed
+#[derive(Debug)]
+enum Failure {
+    /// Worth trying again later
+    Retryable(String),
+    /// Rejected with a status that is not retried
+    Fatal(String),
+}
+
+impl From<Box<dyn std::error::Error>> for Failure {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        if error.is::<RejectedStatus>() {
+            Failure::Fatal(error.to_string())
+        } else {
+            Failure::Retryable(error.to_string())
+        }
+    }
+}
+
+/// Backoff applied when requeueing failed messages
+#[derive(Debug, Clone, Copy)]
+struct RequeuePolicy {
+    base_delay: u64,
+    max_delay: u64,
+}
+
+impl RequeuePolicy {
+    /// Get the requeue delay in milliseconds for a message
+    fn delay(&self, attempts: u16) -> u64 {
+        self.base_delay
+            .saturating_mul(attempts.max(1) as u64)
+            .min(self.max_delay)
+    }
+}
+
+/// Decides what happens to a message once posting it finished
+struct Settler {
+    requeue_policy: RequeuePolicy,
+    spool: Option<Arc<DiskQueue>>,
+    dead_letter_topic: Option<String>,
+    topic: String,
+    channel: String,
+}
+
+impl Settler {
+    /// Commands settling a message: FIN when it was posted, spooled or
+    /// dead-lettered, REQ otherwise
+    fn settle(&self, message: &Message, result: &Result<(), Failure>) -> Vec<Command> {
+        let message_id = bytes::Bytes::from(message.id.to_string());
+        match result {
+            Ok(()) => vec![Command::Fin { message_id }],
+            Err(Failure::Fatal(reason)) => match &self.dead_letter_topic {
+                Some(topic) => {
+                    warn!("Message {} failed permanently: {}, publishing to {}", message.id, reason, topic);
+                    // PUB is answered before the FIN, so a message nsqd does
+                    // not accept is redelivered after the disconnect
+                    vec![self.dead_letter(topic, message, reason), Command::Fin { message_id }]
+                }
+                None => {
+                    error!("Message {} failed permanently: {}, dropping it", message.id, reason);
+                    vec![Command::Fin { message_id }]
+                }
+            },
+            Err(Failure::Retryable(e)) => match &self.spool {
+                // Only FIN once the spooled copy is on disk
+                Some(spool) => match spool.put(&message.to_bytes()).and_then(|_| spool.sync()) {
+                    Ok(_) => {
+                        warn!("Failed to post message to HTTP endpoint: {}, spooled to disk (depth: {})", e, spool.depth());
+                        vec![Command::Fin { message_id }]
+                    }
+                    Err(spool_err) => {
+                        let timeout = self.requeue_policy.delay(message.attempts);
+                        error!("Failed to spool message: {}, requeueing in {}ms", spool_err, timeout);
+                        vec![Command::Req { message_id, timeout }]
+                    }
+                },
+                None => {
+                    let timeout = self.requeue_policy.delay(message.attempts);
+                    error!("Failed to post message to HTTP endpoint: {}, requeueing in {}ms", e, timeout);
+                    vec![Command::Req { message_id, timeout }]
+                }
+            },
+        }
+    }
+
+    /// PUB of a failed message to the dead-letter topic, keeping its headers
+    /// and adding where it came from and why it failed
+    fn dead_letter(&self, topic: &str, message: &Message, reason: &str) -> Command {
+        let mut headers = message.headers.clone();
+        headers.insert("nsq-original-id".to_string(), message.id.to_string());
+        headers.insert("nsq-original-topic".to_string(), self.topic.clone());
+        headers.insert("nsq-original-channel".to_string(), self.channel.clone());
+        headers.insert("nsq-attempts".to_string(), message.attempts.to_string());
+        let mut reason = reason.to_string();
+        if reason.len() > MAX_HEADER_VALUE_LEN {
+            let mut end = MAX_HEADER_VALUE_LEN;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        headers.insert("nsq-failure-reason".to_string(), reason);
+        if validate_headers(&headers).is_err() {
+            // Too many headers of its own; keep only the added ones
+            headers.retain(|key, _| key.starts_with("nsq-"));
+        }
+        Command::Pub { topic: topic.to_string(), body: encode_with_headers(&headers, &message.body) }
+    }
+}
+
+/// Combining messages into one request
+#[derive(Debug, Clone, Copy)]
+struct BatchOptions {
+    size: usize,
+    timeout: Duration,
+}
+
 struct NsqToHttpConsumer {
     topic: String,
     channel: String,
     http_poster: Arc<HttpPoster>,
+    settler: Arc<Settler>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    zstd_level: Option<i32>,
+    batch: Option<BatchOptions>,
+    /// Highest RDY count the connected nsqd accepts
+    max_rdy_count: usize,
 }
 
 impl NsqToHttpConsumer {
-    fn new(topic: String, channel: String, http_poster: Arc<HttpPoster>) -> Self {
+    fn new(
+        topic: String,
+        channel: String,
+        http_poster: Arc<HttpPoster>,
+        settler: Arc<Settler>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+        zstd_level: Option<i32>,
+    ) -> Self {
         Self {
             topic,
             channel,
             http_poster,
+            settler,
+            circuit_breaker,
+            zstd_level,
+            batch: None,
+            max_rdy_count: MAX_RDY_COUNT,
         }
     }
 
+    /// Combine messages into batched requests
+    fn with_batch(mut self, batch: Option<BatchOptions>) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// RDY count keeping every concurrent request supplied, with full
+    /// batches in batch mode
+    fn ready_count(&self) -> usize {
+        let batch_size = self.batch.map_or(1, |batch| batch.size);
+        (self.http_poster.max_concurrent * batch_size).min(self.max_rdy_count)
+    }
+
     async fn connect_and_consume(&mut self, address: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("Connecting to NSQd at {}", address);
         
         let stream = TcpStream::connect(address).await?;
         let (read_half, write_half) = stream.into_split();
         
-        let mut framed_read = FramedRead::new(read_half, NsqDecoder::new());
-        let mut framed_write = FramedWrite::new(write_half, NsqEncoder);
+        let mut framed_read = FramedRead::new(read_half, ZstdStream::new(NsqDecoder::new()));
+        let mut framed_write = FramedWrite::new(write_half, ZstdStream::new(CommandEncoder::new()));
+        
+        self.identify(&mut framed_read, &mut framed_write).await?;
+        
+        // Commands from message tasks share the write half through this channel
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel::<Command>();
+        let writer = tokio::spawn(async move {
+            while let Some(command) = command_rx.recv().await {
+                if let Err(e) = framed_write.send(command).await {
+                    error!("Failed to send command: {}", e);
+                    break;
+                }
+            }
+        });
+        
+        let result = self.consume(&mut framed_read, &command_tx).await;
         
-        // Send IDENTIFY command
-        let identify_data = serde_json::json!({
+        drop(command_tx);
+        writer.abort();
+        result
+    }
+
+    /// Send IDENTIFY, apply the settings nsqd negotiated and switch the
+    /// connection to zstd if nsqd agrees
+    async fn identify(
+        &mut self,
+        framed_read: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, ZstdStream<NsqDecoder>>,
+        framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, ZstdStream<CommandEncoder>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut identify_data = serde_json::json!({
             "client_id": "nsq_to_http",
             "hostname": "nsq_to_http",
             "user_agent": "nsq_to_http/1.0",
@@ -207,57 +745,114 @@ impl NsqToHttpConsumer {
             "output_buffer_size": 16384,
             "output_buffer_timeout": 250
         });
+        // Headers are kept when dead-lettering
+        if self.settler.dead_letter_topic.is_some() {
+            identify_data["message_headers"] = serde_json::json!(true);
+        }
+        if let Some(level) = self.zstd_level {
+            identify_data["zstd"] = serde_json::json!(true);
+            identify_data["zstd_level"] = serde_json::json!(level);
+        }
         
-        let identify_cmd = Command::Identify { data: identify_data };
-        let identify_frame = Frame::new(FrameType::Response, identify_cmd.to_bytes()?);
-        framed_write.send(identify_frame).await?;
+        framed_write.send(Command::Identify { data: identify_data }).await?;
         
-        // Wait for OK response
-        if let Some(frame) = framed_read.next().await {
-            let frame = frame?;
+        let frame = framed_read.next().await.ok_or("Connection closed during IDENTIFY")??;
+        if frame.frame_type != FrameType::Response {
+            return Err("Expected response after IDENTIFY".into());
+        }
+        
+        // nsqd answers with its negotiated settings before switching to zstd
+        let negotiated = IdentifyResponse::parse(&frame.body)?.unwrap_or_default();
+        if negotiated.max_rdy_count > 0 {
+            self.max_rdy_count = negotiated.max_rdy_count as usize;
+        }
+        if negotiated.zstd {
+            let level = negotiated.zstd_level;
+            framed_read.decoder_mut().enable_decompression()?;
+            framed_write.encoder_mut().enable_compression(level)?;
+            let frame = framed_read.next().await.ok_or("Connection closed during IDENTIFY")??;
             if frame.frame_type != FrameType::Response {
-                return Err("Expected OK response after IDENTIFY".into());
+                return Err("Expected OK response after zstd negotiation".into());
             }
-            info!("Connected successfully");
+            info!("Negotiated zstd compression at level {}", level);
+        } else if self.zstd_level.is_some() {
+            warn!("nsqd did not accept zstd compression, continuing uncompressed");
         }
         
+        info!("Connected successfully");
+        Ok(())
+    }
+
+    async fn consume(
+        &mut self,
+        framed_read: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, ZstdStream<NsqDecoder>>,
+        command_tx: &mpsc::UnboundedSender<Command>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         // Subscribe to topic/channel
-        let sub_cmd = Command::Sub {
+        command_tx.send(Command::Sub {
             topic: self.topic.clone(),
             channel: self.channel.clone(),
-        };
-        let sub_frame = Frame::new(FrameType::Response, sub_cmd.to_bytes()?);
-        framed_write.send(sub_frame).await?;
+        })?;
         
         // Set ready count to max_concurrent for parallel processing
-        let max_concurrent = self.http_poster.max_concurrent;
-        let rdy_cmd = Command::Rdy { count: max_concurrent as u32 };
-        let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-        framed_write.send(rdy_frame).await?;
+        let ready_count = self.ready_count();
+        command_tx.send(Command::Rdy { count: ready_count as u32 })?;
         
         info!("Subscribed to topic '{}' channel '{}' with RDY count {}", 
-            self.topic, self.channel, max_concurrent);
+            self.topic, self.channel, ready_count);
         
         // Main message processing loop
         let mut in_flight = 0usize;
-        while let Some(frame) = framed_read.next().await {
+        let mut batch = Vec::new();
+        let mut batch_deadline = None;
+        loop {
+            let frame = tokio::select! {
+                frame = framed_read.next() => frame,
+                _ = tokio::time::sleep_until(batch_deadline.unwrap_or_else(tokio::time::Instant::now)), if batch_deadline.is_some() => {
+                    self.post_batch(std::mem::take(&mut batch), command_tx);
+                    batch_deadline = None;
+                    continue;
+                }
+            };
+            // Messages of an unfinished batch are redelivered after a disconnect
+            let Some(frame) = frame else {
+                break;
+            };
             let frame = frame?;
             
             match frame.frame_type {
                 FrameType::Message => {
-                    // Spawn async task to handle message concurrently
-                    let http_poster = Arc::clone(&self.http_poster);
-                    let message_data = frame.body;
-                    
-                    tokio::spawn(Self::handle_message(http_poster, message_data));
+                    if let Some(options) = self.batch {
+                        match Message::from_bytes(frame.body) {
+                            Ok(message) => batch.push(message),
+                            Err(e) => error!("Failed to parse message: {}", e),
+                        }
+                        if batch.len() >= options.size {
+                            self.post_batch(std::mem::take(&mut batch), command_tx);
+                            batch_deadline = None;
+                        } else if !batch.is_empty() && batch_deadline.is_none() {
+                            batch_deadline = Some(tokio::time::Instant::now() + options.timeout);
+                        }
+                    } else {
+                        // Spawn async task to handle message concurrently
+                        tokio::spawn(Self::handle_message(
+                            Arc::clone(&self.http_poster),
+                            frame.body,
+                            command_tx.clone(),
+                            self.settler.clone(),
+                            self.circuit_breaker.clone(),
+                            ready_count as u32,
+                        ));
+                    }
                     
                     in_flight += 1;
                     
+                    let circuit_closed = self.circuit_breaker.as_ref()
+                        .is_none_or(|breaker| breaker.state() == CircuitState::Closed);
+                    
                     // Periodically refresh RDY count to maintain flow
-                    if in_flight >= max_concurrent / 2 {
-                        let rdy_cmd = Command::Rdy { count: max_concurrent as u32 };
-                        let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-                        framed_write.send(rdy_frame).await?;
+                    if in_flight >= ready_count / 2 && circuit_closed {
+                        command_tx.send(Command::Rdy { count: ready_count as u32 })?;
                         in_flight = 0;
                     }
                 }
@@ -274,61 +869,168 @@ impl NsqToHttpConsumer {
         Ok(())
     }
 
-    async fn handle_message(http_poster: Arc<HttpPoster>, message_data: bytes::Bytes) {
-        match Message::from_bytes(message_data) {
-            Ok(message) => {
-                match http_poster.post_message(&message).await {
-                    Ok(_) => {
-                        info!("Successfully posted message to HTTP endpoint");
-                    }
-                    Err(e) => {
-                        error!("Failed to post message to HTTP endpoint: {}", e);
-                        // In a real implementation, you might want to requeue the message
-                        // or handle the error differently based on requirements
-                    }
-                }
-            }
+    async fn handle_message(
+        http_poster: Arc<HttpPoster>,
+        message_data: bytes::Bytes,
+        command_tx: mpsc::UnboundedSender<Command>,
+        settler: Arc<Settler>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+        ready_count: u32,
+    ) {
+        let message = match Message::from_bytes(message_data) {
+            Ok(message) => message,
             Err(e) => {
                 error!("Failed to parse message: {}", e);
+                return;
+            }
+        };
+        
+        let span = tracing::info_span!("post_message", message_id = %message.id, attempts = message.attempts);
+        let result = http_poster.post_message(&message).instrument(span).await
+            .map_err(Failure::from);
+        
+        // Rejected messages say nothing about the endpoint's health
+        if let Some(breaker) = circuit_breaker {
+            if let Some(state) = breaker.record(!matches!(result, Err(Failure::Retryable(_)))) {
+                Self::apply_circuit_state(breaker, state, ready_count, command_tx.clone());
+            }
+        }
+        
+        if result.is_ok() {
+            info!("Successfully posted message to HTTP endpoint");
+        }
+        for command in settler.settle(&message, &result) {
+            if command_tx.send(command).is_err() {
+                warn!("Connection closed before message {} could be acknowledged", message.id);
+                break;
+            }
+        }
+    }
+
+    /// Post a batch in the background
+    fn post_batch(&self, messages: Vec<Message>, command_tx: &mpsc::UnboundedSender<Command>) {
+        if messages.is_empty() {
+            return;
+        }
+        tokio::spawn(Self::handle_batch(
+            Arc::clone(&self.http_poster),
+            messages,
+            command_tx.clone(),
+            self.settler.clone(),
+            self.circuit_breaker.clone(),
+            self.ready_count() as u32,
+        ));
+    }
+
+    /// Post messages as one request, then FIN all of them or settle each
+    /// on failure
+    async fn handle_batch(
+        http_poster: Arc<HttpPoster>,
+        messages: Vec<Message>,
+        command_tx: mpsc::UnboundedSender<Command>,
+        settler: Arc<Settler>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+        ready_count: u32,
+    ) {
+        let span = tracing::info_span!("post_batch", messages = messages.len());
+        let result = http_poster.post_batch(&messages).instrument(span).await
+            .map_err(Failure::from);
+        
+        if let Some(breaker) = circuit_breaker {
+            if let Some(state) = breaker.record(!matches!(result, Err(Failure::Retryable(_)))) {
+                Self::apply_circuit_state(breaker, state, ready_count, command_tx.clone());
+            }
+        }
+        
+        if result.is_ok() {
+            info!("Successfully posted batch of {} messages to HTTP endpoint", messages.len());
+        }
+        let commands = messages.iter().flat_map(|message| settler.settle(message, &result));
+        for command in commands {
+            if command_tx.send(command).is_err() {
+                warn!("Connection closed before batch of {} messages could be acknowledged", messages.len());
+                break;
             }
         }
     }
+
+    /// Adjust RDY for a circuit breaker transition
+    fn apply_circuit_state(
+        breaker: Arc<CircuitBreaker>,
+        state: CircuitState,
+        ready_count: u32,
+        command_tx: mpsc::UnboundedSender<Command>,
+    ) {
+        match state {
+            CircuitState::Open => {
+                warn!("Circuit breaker opened, pausing consumption for {:?}", breaker.cooldown);
+                let _ = command_tx.send(Command::Rdy { count: 0 });
+                
+                tokio::spawn(async move {
+                    tokio::time::sleep(breaker.cooldown).await;
+                    if breaker.half_open() {
+                        info!("Circuit breaker half-open, probing endpoint with a single message");
+                        let _ = command_tx.send(Command::Rdy { count: 1 });
+                    }
+                });
+            }
+            CircuitState::Closed => {
+                info!("Circuit breaker closed, resuming consumption");
+                let _ = command_tx.send(Command::Rdy { count: ready_count });
+            }
+            CircuitState::HalfOpen => {}
+        }
+    }
 }
 
-async fn discover_nsqd_addresses(lookupd_addresses: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut nsqd_addresses = Vec::new();
-    
-    for lookupd_addr in lookupd_addresses {
-        let url = format!("http://{}/nodes", lookupd_addr);
-        let response = reqwest::get(&url).await?;
+/// Replay spooled messages once the endpoints accept them again. Each
+/// message stays at the head of the spool until it was posted or dropped, so
+/// a failed replay is retried first and nothing is lost
+async fn replay_spool(spool: Arc<DiskQueue>, http_poster: Arc<HttpPoster>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
         
-        if response.status().is_success() {
-            let nodes: serde_json::Value = response.json().await?;
+        loop {
+            let data = match spool.peek(1) {
+                Ok(mut messages) => match messages.pop() {
+                    Some(data) => data,
+                    None => break,
+                },
+                Err(e) => {
+                    error!("Failed to read from spool: {}", e);
+                    break;
+                }
+            };
             
-            if let Some(producers) = nodes.get("producers") {
-                if let Some(producers_array) = producers.as_array() {
-                    for producer in producers_array {
-                        if let Some(broadcast_address) = producer.get("broadcast_address") {
-                            if let Some(tcp_port) = producer.get("tcp_port") {
-                                let address = format!("{}:{}", 
-                                    broadcast_address.as_str().unwrap_or("localhost"),
-                                    tcp_port.as_u64().unwrap_or(4150)
-                                );
-                                nsqd_addresses.push(address);
-                            }
-                        }
+            match Message::from_bytes(bytes::Bytes::from(data)) {
+                Ok(message) => match http_poster.post_message(&message).await {
+                    Ok(_) => info!("Replayed spooled message {} (remaining: {})", message.id, spool.depth().saturating_sub(1)),
+                    Err(e) if e.is::<RejectedStatus>() => {
+                        error!("Dropping spooled message {}, it failed permanently: {}", message.id, e);
                     }
-                }
+                    Err(e) => {
+                        warn!("Spool replay failed: {}, {} messages remain spooled", e, spool.depth());
+                        break;
+                    }
+                },
+                Err(e) => error!("Dropping corrupt spooled message: {}", e),
+            }
+            
+            if let Err(e) = spool.skip(1) {
+                error!("Failed to remove replayed message from spool: {}", e);
+                break;
             }
         }
+        
+        if let Err(e) = spool.sync() {
+            error!("Failed to sync spool: {}", e);
+        }
     }
-    
-    Ok(nsqd_addresses)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    nsq_common::init_tool_logging("nsq_to_http")?;
     
     let args = Args::parse();
     
@@ -337,62 +1039,149 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
     
-    let mut nsqd_addresses = args.nsqd_tcp_address;
+    if args.lookupd_poll_interval == 0 {
+        eprintln!("Error: --lookupd-poll-interval must be at least 1 second");
+        std::process::exit(1);
+    }
     
-    // Discover NSQd addresses from lookupd if provided
-    if !args.lookupd_http_address.is_empty() {
-        match discover_nsqd_addresses(&args.lookupd_http_address).await {
-            Ok(discovered) => {
-                info!("Discovered {} NSQd instances from lookupd", discovered.len());
-                nsqd_addresses.extend(discovered);
-            }
-            Err(e) => {
-                warn!("Failed to discover NSQd addresses from lookupd: {}", e);
-            }
+    if args.max_rps < 0.0 {
+        eprintln!("Error: --max-rps must not be negative");
+        std::process::exit(1);
+    }
+    
+    if let Some(error_rate) = args.circuit_error_rate {
+        if !(error_rate > 0.0 && error_rate <= 1.0) {
+            eprintln!("Error: --circuit-error-rate must be between 0.0 and 1.0");
+            std::process::exit(1);
         }
     }
     
-    if nsqd_addresses.is_empty() {
-        eprintln!("Error: No NSQd addresses available");
+    if let Some(topic) = &args.dead_letter_topic {
+        if let Err(e) = nsq_common::validate_topic_name(topic) {
+            eprintln!("Error: Invalid --dead-letter-topic: {}", e);
+            std::process::exit(1);
+        }
+    }
+    
+    if args.batch_size == 0 || args.batch_timeout == 0 {
+        eprintln!("Error: --batch-size and --batch-timeout must be at least 1");
+        std::process::exit(1);
+    }
+    
+    if !(1..=19).contains(&args.zstd_level) {
+        eprintln!("Error: --zstd-level must be between 1 and 19");
         std::process::exit(1);
     }
     
-    let http_poster = Arc::new(HttpPoster::new(
-        args.http_endpoint,
-        args.http_method,
-        args.http_headers,
-        args.http_timeout,
-        args.max_concurrent_requests,
-        args.retry_failed,
-        args.max_retries,
-    )?);
+    let mut discovery = LookupdDiscovery::new(args.lookupd_http_address.clone(), Some(args.topic.clone()))
+        .with_interval(Duration::from_secs(args.lookupd_poll_interval));
+    
+    let http_poster = Arc::new(HttpPoster::new(HttpPosterOptions {
+        endpoints: args.http_endpoint,
+        round_robin: args.round_robin,
+        method: args.http_method,
+        headers: args.http_headers,
+        timeout: args.http_timeout,
+        max_concurrent: args.max_concurrent_requests,
+        retry_failed: args.retry_failed,
+        max_retries: args.max_retries,
+        retry_on: args.retry_on,
+        raw_body: args.raw_body,
+        content_type: args.content_type,
+        topic: args.topic.clone(),
+        channel: args.channel.clone(),
+        max_rps: args.max_rps,
+        batch_format: args.batch_format,
+    })?);
+    
+    let requeue_policy = RequeuePolicy {
+        base_delay: args.requeue_delay,
+        max_delay: args.max_requeue_delay,
+    };
+    
+    let circuit_breaker = args.circuit_error_rate.map(|error_rate| {
+        Arc::new(CircuitBreaker::new(
+            error_rate,
+            args.circuit_window,
+            Duration::from_secs(args.circuit_cooldown),
+        ))
+    });
+    
+    let spool = match &args.spool_dir {
+        Some(dir) => {
+            let spool = Arc::new(DiskQueue::new(
+                dir,
+                SPOOL_MAX_FILE_SIZE,
+                SPOOL_MAX_MSG_SIZE,
+                Duration::from_secs(2),
+            )?);
+            info!("Spooling failed messages to {} ({} already spooled)", dir, spool.depth());
+            tokio::spawn(replay_spool(
+                spool.clone(),
+                http_poster.clone(),
+                Duration::from_millis(args.spool_replay_interval),
+            ));
+            Some(spool)
+        }
+        None => None,
+    };
     
     let mut consumer = NsqToHttpConsumer::new(
-        args.topic,
-        args.channel,
+        args.topic.clone(),
+        args.channel.clone(),
         http_poster,
-    );
-    
-    // Try to connect to the first available NSQd
-    let mut connected = false;
-    for address in &nsqd_addresses {
-        match consumer.connect_and_consume(address).await {
-            Ok(_) => {
-                connected = true;
-                break;
+        Arc::new(Settler {
+            requeue_policy,
+            spool,
+            dead_letter_topic: args.dead_letter_topic,
+            topic: args.topic,
+            channel: args.channel,
+        }),
+        circuit_breaker,
+        args.zstd.then_some(args.zstd_level),
+    ).with_batch((args.batch_size > 1).then(|| BatchOptions {
+        size: args.batch_size,
+        timeout: Duration::from_millis(args.batch_timeout),
+    }));
+    
+    // Connect to the first available NSQd, rediscovering them from lookupd
+    // until one is reachable
+    loop {
+        let mut nsqd_addresses = args.nsqd_tcp_address.clone();
+        if discovery.is_enabled() {
+            if let Err(e) = discovery.poll().await {
+                warn!("Failed to discover NSQd addresses from lookupd: {}", e);
             }
-            Err(e) => {
-                error!("Failed to connect to {}: {}", address, e);
-                continue;
+            info!("Discovered {} NSQd instances from lookupd", discovery.producers().count());
+            nsqd_addresses.extend(discovery.producers().map(Producer::tcp_address));
+        }
+        
+        let mut connected = false;
+        for address in &nsqd_addresses {
+            match consumer.connect_and_consume(address).await {
+                Ok(_) => {
+                    connected = true;
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to connect to {}: {}", address, e);
+                    continue;
+                }
             }
         }
+        if connected {
+            break;
+        }
+        
+        if !discovery.is_enabled() {
+            eprintln!("Error: Failed to connect to any NSQd instance");
+            std::process::exit(1);
+        }
+        warn!("No reachable NSQd instance, retrying in {}s", args.lookupd_poll_interval);
+        tokio::time::sleep(Duration::from_secs(args.lookupd_poll_interval)).await;
     }
     
-    if !connected {
-        eprintln!("Error: Failed to connect to any NSQd instance");
-        std::process::exit(1);
-    }
-    
+    nsq_common::shutdown_tracing();
     Ok(())
 }
 
@@ -1,12 +1,18 @@
 //! nsq_to_nsq - Topic/channel replication tool
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use futures::SinkExt;
-use nsq_protocol::{Command, Frame, FrameType, Message, NsqDecoder, NsqEncoder};
+use nsq_common::{LookupdDiscovery, Producer};
+use nsq_protocol::{Command, CommandEncoder, FrameType, IdentifyResponse, Message, NsqDecoder, PublishResponse, ResponseMatcher};
+use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 
 #[derive(Parser, Debug)]
 #[command(name = "nsq_to_nsq")]
@@ -20,6 +26,10 @@ struct Args {
     #[arg(long)]
     src_lookupd_http_address: Vec<String>,
     
+    /// Seconds between source lookupd queries while no source NSQd is reachable
+    #[arg(long, default_value = "60")]
+    src_lookupd_poll_interval: u64,
+    
     /// Source topic
     #[arg(long)]
     src_topic: String,
@@ -28,25 +38,264 @@ struct Args {
     #[arg(long)]
     src_channel: String,
     
-    /// Destination NSQd TCP address
-    #[arg(long)]
-    dst_nsqd_tcp_address: String,
+    /// Destination NSQd TCP addresses
+    #[arg(long, required = true)]
+    dst_nsqd_tcp_address: Vec<String>,
+    
+    /// How publishes are distributed across destinations
+    #[arg(long, value_enum, default_value = "round-robin")]
+    dst_strategy: DistributionStrategy,
+    
+    /// Seconds before reconnecting to a destination that failed
+    #[arg(long, default_value = "5")]
+    dst_retry_interval: u64,
+    
+    /// Maximum number of PUB/MPUB commands awaiting a response per destination
+    #[arg(long, default_value = "16")]
+    dst_max_pending: usize,
     
     /// Destination topic
+    #[arg(long, required_unless_present = "dst_topic_pattern", conflicts_with = "dst_topic_pattern")]
+    dst_topic: Option<String>,
+    
+    /// Destination topic template ({src} is replaced with the source topic)
     #[arg(long)]
-    dst_topic: String,
+    dst_topic_pattern: Option<String>,
     
     /// Destination channel (defaults to source channel if not specified)
     #[arg(long)]
     dst_channel: Option<String>,
     
-    /// Buffer size for message replication
-    #[arg(long, default_value = "1000")]
-    buffer_size: usize,
+    /// Maximum number of source messages in flight (RDY count)
+    #[arg(long, alias = "buffer-size", default_value = "1000")]
+    max_in_flight: usize,
     
     /// Batch size for publishing messages
     #[arg(long, default_value = "10")]
     batch_size: usize,
+    
+    /// Maximum time in milliseconds a partial batch waits before publishing
+    #[arg(long, default_value = "100")]
+    batch_timeout: u64,
+    
+    /// Delay in milliseconds for requeueing messages that failed to publish
+    #[arg(long, default_value = "5000")]
+    requeue_delay: u64,
+    
+    /// Only replicate messages whose body matches this regex
+    #[arg(long)]
+    regex: Option<String>,
+    
+    /// Only replicate JSON messages where a field equals a value (format: "field=value", dotted paths allowed)
+    #[arg(long)]
+    whitelist_json_field: Vec<String>,
+}
+
+/// Decides which source messages are replicated; a message must pass every filter
+struct MessageFilter {
+    regex: Option<Regex>,
+    json_fields: Vec<(String, String)>,
+}
+
+impl MessageFilter {
+    fn new(regex: Option<&str>, json_fields: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let regex = regex.map(Regex::new).transpose()?;
+        
+        let mut parsed_fields = Vec::new();
+        for field in json_fields {
+            match field.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    parsed_fields.push((key.to_string(), value.to_string()));
+                }
+                _ => return Err(format!("Invalid JSON field filter: {}", field).into()),
+            }
+        }
+        
+        Ok(Self { regex, json_fields: parsed_fields })
+    }
+
+    fn matches(&self, body: &[u8]) -> bool {
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&String::from_utf8_lossy(body)) {
+                return false;
+            }
+        }
+        
+        if self.json_fields.is_empty() {
+            return true;
+        }
+        
+        let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) else {
+            return false;
+        };
+        
+        self.json_fields.iter().all(|(path, expected)| {
+            let pointer = format!("/{}", path.replace('.', "/"));
+            match json.pointer(&pointer) {
+                Some(serde_json::Value::String(value)) => value == expected,
+                Some(value) => serde_json::from_str::<serde_json::Value>(expected).ok().as_ref() == Some(value),
+                None => false,
+            }
+        })
+    }
+}
+
+type FrameReader = FramedRead<OwnedReadHalf, NsqDecoder>;
+type CommandWriter = FramedWrite<OwnedWriteHalf, CommandEncoder>;
+
+/// How publishes are spread across destination nsqd instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DistributionStrategy {
+    /// Send each batch to the next destination in turn
+    RoundRobin,
+    /// Send each message to a destination chosen by its message ID
+    Hash,
+}
+
+/// A destination nsqd and its connection, if established
+struct Destination {
+    address: String,
+    connection: Option<(FrameReader, CommandWriter)>,
+    /// Batches published on the connection and awaiting a response
+    pending: ResponseMatcher<Vec<Message>>,
+    retry_at: Option<Instant>,
+}
+
+/// Destination nsqd connections with failover to healthy peers
+struct DestinationPool {
+    destinations: Vec<Destination>,
+    strategy: DistributionStrategy,
+    next: usize,
+    retry_interval: Duration,
+}
+
+impl DestinationPool {
+    fn new(addresses: &[String], strategy: DistributionStrategy, retry_interval: Duration, max_pending: usize) -> Self {
+        Self {
+            destinations: addresses
+                .iter()
+                .map(|address| Destination {
+                    address: address.clone(),
+                    connection: None,
+                    pending: ResponseMatcher::new(max_pending),
+                    retry_at: None,
+                })
+                .collect(),
+            strategy,
+            next: 0,
+            retry_interval,
+        }
+    }
+
+    /// Split a batch into groups keyed by preferred destination index
+    fn assign(&mut self, messages: Vec<Message>) -> Vec<(usize, Vec<Message>)> {
+        let count = self.destinations.len();
+        match self.strategy {
+            DistributionStrategy::RoundRobin => {
+                let index = self.next % count;
+                self.next = self.next.wrapping_add(1);
+                vec![(index, messages)]
+            }
+            DistributionStrategy::Hash => {
+                let mut groups: Vec<Vec<Message>> = (0..count).map(|_| Vec::new()).collect();
+                for message in messages {
+                    let mut hasher = DefaultHasher::new();
+                    message.id.hash(&mut hasher);
+                    let index = (hasher.finish() % count as u64) as usize;
+                    groups[index].push(message);
+                }
+                groups
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, group)| !group.is_empty())
+                    .collect()
+            }
+        }
+    }
+
+    /// Get the connection for a destination, reconnecting once its retry interval has passed
+    async fn connection(&mut self, index: usize) -> Option<&mut (FrameReader, CommandWriter)> {
+        let retry_interval = self.retry_interval;
+        let destination = &mut self.destinations[index];
+        
+        if destination.connection.is_none() {
+            if destination.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                return None;
+            }
+            
+            match connect_destination(&destination.address).await {
+                Ok(connection) => {
+                    if destination.retry_at.take().is_some() {
+                        info!("Destination {} recovered", destination.address);
+                    }
+                    destination.connection = Some(connection);
+                }
+                Err(e) => {
+                    warn!("Failed to connect to destination {}: {}", destination.address, e);
+                    destination.retry_at = Some(Instant::now() + retry_interval);
+                    return None;
+                }
+            }
+        }
+        
+        destination.connection.as_mut()
+    }
+
+    /// Drop a failed connection and back off before reconnecting, returning
+    /// the batches whose outcome is unknown
+    fn mark_failed(&mut self, index: usize) -> Vec<Vec<Message>> {
+        let destination = &mut self.destinations[index];
+        destination.connection = None;
+        destination.retry_at = Some(Instant::now() + self.retry_interval);
+        destination.pending.drain().collect()
+    }
+}
+
+/// Connect and IDENTIFY to a destination nsqd
+async fn connect_destination(address: &str) -> Result<(FrameReader, CommandWriter), Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect(address).await?;
+    let (read_half, write_half) = stream.into_split();
+    
+    let mut framed_read = FramedRead::new(read_half, NsqDecoder::new());
+    let mut framed_write = FramedWrite::new(write_half, CommandEncoder::new());
+    
+    // Send IDENTIFY command
+    let identify_data = serde_json::json!({
+        "client_id": "nsq_to_nsq_dst",
+        "hostname": "nsq_to_nsq_dst",
+        "user_agent": "nsq_to_nsq/1.0",
+        "feature_negotiation": true,
+        "heartbeat_interval": 30000,
+        "output_buffer_size": 16384,
+        "output_buffer_timeout": 250
+    });
+    
+    framed_write.send(Command::Identify { data: identify_data }).await?;
+    
+    // Wait for the negotiated settings
+    match framed_read.next().await {
+        Some(Ok(frame)) if frame.frame_type == FrameType::Response => {
+            IdentifyResponse::parse(&frame.body)?;
+            info!("Destination connection established to {}", address);
+            Ok((framed_read, framed_write))
+        }
+        Some(Ok(_)) => Err("Expected response after IDENTIFY".into()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err("destination closed connection".into()),
+    }
+}
+
+/// Options for constructing an NsqReplicator
+struct ReplicatorOptions {
+    src_topic: String,
+    src_channel: String,
+    dst_topic: String,
+    dst_channel: Option<String>,
+    max_in_flight: usize,
+    batch_size: usize,
+    batch_timeout: Duration,
+    requeue_delay: u64,
+    filter: MessageFilter,
 }
 
 struct NsqReplicator {
@@ -54,103 +303,107 @@ struct NsqReplicator {
     src_channel: String,
     dst_topic: String,
     dst_channel: String,
-    buffer_size: usize,
+    max_in_flight: usize,
     batch_size: usize,
+    batch_timeout: Duration,
+    requeue_delay: u64,
+    filter: MessageFilter,
 }
 
 impl NsqReplicator {
-    fn new(
-        src_topic: String,
-        src_channel: String,
-        dst_topic: String,
-        dst_channel: Option<String>,
-        buffer_size: usize,
-        batch_size: usize,
-    ) -> Self {
+    fn new(options: ReplicatorOptions) -> Self {
         Self {
-            src_topic,
-            src_channel: src_channel.clone(),
-            dst_topic,
-            dst_channel: dst_channel.unwrap_or_else(|| src_channel),
-            buffer_size,
-            batch_size,
+            src_topic: options.src_topic,
+            dst_channel: options.dst_channel.unwrap_or_else(|| options.src_channel.clone()),
+            src_channel: options.src_channel,
+            dst_topic: options.dst_topic,
+            max_in_flight: options.max_in_flight,
+            batch_size: options.batch_size.max(1),
+            batch_timeout: options.batch_timeout,
+            requeue_delay: options.requeue_delay,
+            filter: options.filter,
         }
     }
 
-    async fn replicate(&self, src_address: &str, dst_address: &str) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Starting replication from {} to {}", src_address, dst_address);
+    async fn replicate(&self, src_address: &str, destinations: &mut DestinationPool) -> Result<(), Box<dyn std::error::Error>> {
+        let dst_addresses: Vec<&str> = destinations.destinations.iter().map(|d| d.address.as_str()).collect();
+        info!("Starting replication from {} to {}", src_address, dst_addresses.join(", "));
         
         // Connect to source NSQd
         let src_stream = TcpStream::connect(src_address).await?;
         let (src_read_half, src_write_half) = src_stream.into_split();
         
         let mut src_framed_read = FramedRead::new(src_read_half, NsqDecoder::new());
-        let mut src_framed_write = FramedWrite::new(src_write_half, NsqEncoder);
-        
-        // Connect to destination NSQd
-        let dst_stream = TcpStream::connect(dst_address).await?;
-        let (dst_read_half, dst_write_half) = dst_stream.into_split();
-        
-        let mut dst_framed_read = FramedRead::new(dst_read_half, NsqDecoder::new());
-        let mut dst_framed_write = FramedWrite::new(dst_write_half, NsqEncoder);
+        let mut src_framed_write = FramedWrite::new(src_write_half, CommandEncoder::new());
         
         // Setup source connection
         self.setup_source_connection(&mut src_framed_read, &mut src_framed_write).await?;
         
-        // Setup destination connection
-        self.setup_destination_connection(&mut dst_framed_read, &mut dst_framed_write).await?;
-        
         info!("Replicating messages from topic '{}' channel '{}' to topic '{}' channel '{}'",
             self.src_topic, self.src_channel, self.dst_topic, self.dst_channel);
         
-        // Message replication loop
+        // Message replication loop; source messages stay in flight until their batch is acknowledged
         let mut message_batch = Vec::new();
-        let mut messages_processed = 0usize;
+        let mut flush_timer = tokio::time::interval(self.batch_timeout);
         
-        while let Some(frame) = src_framed_read.next().await {
-            let frame = frame?;
-            
-            match frame.frame_type {
-                FrameType::Message => {
-                    let message = Message::from_bytes(frame.body)?;
-                    message_batch.push(message);
-                    messages_processed += 1;
+        loop {
+            tokio::select! {
+                frame = src_framed_read.next() => {
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    let frame = frame?;
                     
-                    // Periodically refresh RDY count to maintain flow
-                    if messages_processed % (self.buffer_size / 4).max(1) == 0 {
-                        let rdy_cmd = Command::Rdy { count: self.buffer_size as u32 };
-                        let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-                        src_framed_write.send(rdy_frame).await?;
-                    }
-                    
-                    // Publish batch when it reaches batch_size
-                    if message_batch.len() >= self.batch_size {
-                        self.publish_batch(&mut dst_framed_write, &message_batch).await?;
-                        message_batch.clear();
+                    match frame.frame_type {
+                        FrameType::Message => {
+                            let message = Message::from_bytes(frame.body)?;
+                            
+                            // Filtered messages are acknowledged without being replicated
+                            if !self.filter.matches(&message.body) {
+                                let message_id = bytes::Bytes::from(message.id.to_string());
+                                src_framed_write.send(Command::Fin { message_id }).await?;
+                                continue;
+                            }
+                            
+                            message_batch.push(message);
+                            
+                            // Publish batch when it reaches batch_size
+                            if message_batch.len() >= self.batch_size {
+                                self.forward_batch(&mut message_batch, &mut src_framed_write, destinations).await?;
+                            }
+                        }
+                        FrameType::Response => {
+                            info!("Source response: {}", String::from_utf8_lossy(&frame.body));
+                        }
+                        FrameType::Error => {
+                            error!("Source error: {}", String::from_utf8_lossy(&frame.body));
+                            return Err(format!("Source NSQ error: {}", String::from_utf8_lossy(&frame.body)).into());
+                        }
                     }
                 }
-                FrameType::Response => {
-                    info!("Source response: {}", String::from_utf8_lossy(&frame.body));
-                }
-                FrameType::Error => {
-                    error!("Source error: {}", String::from_utf8_lossy(&frame.body));
-                    return Err(format!("Source NSQ error: {}", String::from_utf8_lossy(&frame.body)).into());
+                _ = flush_timer.tick() => {
+                    if !message_batch.is_empty() {
+                        self.forward_batch(&mut message_batch, &mut src_framed_write, destinations).await?;
+                    }
+                    // Input went quiet, so settle what is still awaiting a response
+                    self.settle_all(destinations, &mut src_framed_write).await?;
                 }
             }
         }
         
         // Publish remaining messages
         if !message_batch.is_empty() {
-            self.publish_batch(&mut dst_framed_write, &message_batch).await?;
+            self.forward_batch(&mut message_batch, &mut src_framed_write, destinations).await?;
         }
+        self.settle_all(destinations, &mut src_framed_write).await?;
         
         Ok(())
     }
 
     async fn setup_source_connection(
         &self,
-        framed_read: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, NsqDecoder>,
-        framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, NsqEncoder>,
+        framed_read: &mut FrameReader,
+        framed_write: &mut CommandWriter,
     ) -> Result<(), Box<dyn std::error::Error>> {
         // Send IDENTIFY command
         let identify_data = serde_json::json!({
@@ -163,148 +416,203 @@ impl NsqReplicator {
             "output_buffer_timeout": 250
         });
         
-        let identify_cmd = Command::Identify { data: identify_data };
-        let identify_frame = Frame::new(FrameType::Response, identify_cmd.to_bytes()?);
-        framed_write.send(identify_frame).await?;
+        framed_write.send(Command::Identify { data: identify_data }).await?;
         
-        // Wait for OK response
-        if let Some(frame) = framed_read.next().await {
-            let frame = frame?;
-            if frame.frame_type != FrameType::Response {
-                return Err("Expected OK response after IDENTIFY".into());
+        // Wait for the negotiated settings
+        let frame = framed_read.next().await.ok_or("source closed connection")??;
+        if frame.frame_type != FrameType::Response {
+            return Err("Expected response after IDENTIFY".into());
+        }
+        let mut rdy_count = self.max_in_flight as u32;
+        if let Some(negotiated) = IdentifyResponse::parse(&frame.body)? {
+            if rdy_count > negotiated.max_rdy_count {
+                warn!("--max-in-flight {} exceeds the source's max_rdy_count, using {}", rdy_count, negotiated.max_rdy_count);
+                rdy_count = negotiated.max_rdy_count;
             }
-            info!("Source connection established");
         }
+        info!("Source connection established");
         
         // Subscribe to source topic/channel
-        let sub_cmd = Command::Sub {
+        framed_write.send(Command::Sub {
             topic: self.src_topic.clone(),
             channel: self.src_channel.clone(),
-        };
-        let sub_frame = Frame::new(FrameType::Response, sub_cmd.to_bytes()?);
-        framed_write.send(sub_frame).await?;
+        }).await?;
         
-        // Set ready count based on buffer_size to control in-flight messages
-        let rdy_cmd = Command::Rdy { count: self.buffer_size as u32 };
-        let rdy_frame = Frame::new(FrameType::Response, rdy_cmd.to_bytes()?);
-        framed_write.send(rdy_frame).await?;
+        // RDY bounds the number of unacknowledged source messages
+        framed_write.send(Command::Rdy { count: rdy_count }).await?;
         
-        info!("Source ready to receive up to {} in-flight messages", self.buffer_size);
+        info!("Source ready to receive up to {} in-flight messages", rdy_count);
         
         Ok(())
     }
 
-    async fn setup_destination_connection(
+    /// Publish a batch downstream without waiting for the response. Messages
+    /// are FINed upstream once their batch is acknowledged or REQed if it fails.
+    async fn forward_batch(
         &self,
-        framed_read: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, NsqDecoder>,
-        framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, NsqEncoder>,
+        messages: &mut Vec<Message>,
+        src_framed_write: &mut CommandWriter,
+        destinations: &mut DestinationPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        // Send IDENTIFY command
-        let identify_data = serde_json::json!({
-            "client_id": "nsq_to_nsq_dst",
-            "hostname": "nsq_to_nsq_dst",
-            "user_agent": "nsq_to_nsq/1.0",
-            "feature_negotiation": true,
-            "heartbeat_interval": 30000,
-            "output_buffer_size": 16384,
-            "output_buffer_timeout": 250
-        });
-        
-        let identify_cmd = Command::Identify { data: identify_data };
-        let identify_frame = Frame::new(FrameType::Response, identify_cmd.to_bytes()?);
-        framed_write.send(identify_frame).await?;
-        
-        // Wait for OK response
-        if let Some(frame) = framed_read.next().await {
-            let frame = frame?;
-            if frame.frame_type != FrameType::Response {
-                return Err("Expected OK response after IDENTIFY".into());
-            }
-            info!("Destination connection established");
+        for (preferred, group) in destinations.assign(std::mem::take(messages)) {
+            let message_ids = group.iter().map(|message| message.id.to_string()).collect::<Vec<_>>().join(",");
+            let span = tracing::info_span!("forward", message_count = group.len(), message_ids = %message_ids);
+            self.publish_with_failover(destinations, preferred, group, src_framed_write).instrument(span).await?;
         }
         
         Ok(())
     }
 
-    async fn publish_batch(
+    /// Publish to the preferred destination, failing over to the others in order
+    async fn publish_with_failover(
         &self,
-        framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, NsqEncoder>,
-        messages: &[Message],
+        destinations: &mut DestinationPool,
+        preferred: usize,
+        messages: Vec<Message>,
+        src_framed_write: &mut CommandWriter,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if messages.is_empty() {
-            return Ok(());
+        let count = destinations.destinations.len();
+        
+        for offset in 0..count {
+            let index = (preferred + offset) % count;
+            // Make room in the window by settling the oldest batches
+            while destinations.destinations[index].pending.is_full() {
+                self.read_response(destinations, index, src_framed_write).await?;
+            }
+            let Some((_, framed_write)) = destinations.connection(index).await else {
+                continue;
+            };
+            
+            match framed_write.send(self.publish_command(&messages)).await {
+                Ok(_) => {
+                    destinations.destinations[index].pending.push(messages);
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Destination {} failed: {}, failing over", destinations.destinations[index].address, e);
+                    self.fail_destination(destinations, index, src_framed_write).await?;
+                }
+            }
         }
         
+        self.settle(src_framed_write, messages, Err("no destination available")).await
+    }
+
+    fn publish_command(&self, messages: &[Message]) -> Command {
         if messages.len() == 1 {
-            // Single message
-            let pub_cmd = Command::Pub {
+            Command::Pub {
                 topic: self.dst_topic.clone(),
                 body: messages[0].body.clone(),
-            };
-            let pub_frame = Frame::new(FrameType::Response, pub_cmd.to_bytes()?);
-            framed_write.send(pub_frame).await?;
+            }
         } else {
-            // Batch messages
-            let bodies: Vec<bytes::Bytes> = messages.iter().map(|m| m.body.clone()).collect();
-            let mpub_cmd = Command::Mpub {
+            Command::Mpub {
                 topic: self.dst_topic.clone(),
-                bodies,
-            };
-            let mpub_frame = Frame::new(FrameType::Response, mpub_cmd.to_bytes()?);
-            framed_write.send(mpub_frame).await?;
+                bodies: messages.iter().map(|m| m.body.clone()).collect(),
+            }
         }
-        
-        info!("Published batch of {} messages to destination", messages.len());
-        Ok(())
     }
-}
 
-async fn discover_nsqd_addresses(lookupd_addresses: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut nsqd_addresses = Vec::new();
-    
-    for lookupd_addr in lookupd_addresses {
-        let url = format!("http://{}/nodes", lookupd_addr);
-        match reqwest::get(&url).await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    match response.json::<serde_json::Value>().await {
-                        Ok(nodes) => {
-                            if let Some(producers) = nodes.get("producers") {
-                                if let Some(producers_array) = producers.as_array() {
-                                    for producer in producers_array {
-                                        if let Some(broadcast_address) = producer.get("broadcast_address") {
-                                            if let Some(tcp_port) = producer.get("tcp_port") {
-                                                let address = format!("{}:{}", 
-                                                    broadcast_address.as_str().unwrap_or("localhost"),
-                                                    tcp_port.as_u64().unwrap_or(4150)
-                                                );
-                                                nsqd_addresses.push(address);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Failed to parse JSON from {}: {}", lookupd_addr, e);
-                        }
-                    }
-                } else {
-                    warn!("Failed to query lookupd {}: HTTP {}", lookupd_addr, response.status());
+    /// Read one frame from a destination and settle the batch it answers
+    async fn read_response(
+        &self,
+        destinations: &mut DestinationPool,
+        index: usize,
+        src_framed_write: &mut CommandWriter,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let destination = &mut destinations.destinations[index];
+        let Some((framed_read, framed_write)) = destination.connection.as_mut() else {
+            return Ok(());
+        };
+        
+        let frame = match framed_read.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                error!("Destination {} failed: {}", destination.address, e);
+                return self.fail_destination(destinations, index, src_framed_write).await;
+            }
+            None => {
+                error!("Destination {} closed the connection", destination.address);
+                return self.fail_destination(destinations, index, src_framed_write).await;
+            }
+        };
+        
+        match destination.pending.match_frame(frame) {
+            PublishResponse::Ok(batch) => {
+                debug!("Replicated batch of {} messages to {}", batch.len(), destination.address);
+                self.settle(src_framed_write, batch, Ok(())).await?;
+            }
+            PublishResponse::Error(batch, e) => {
+                warn!("Destination {} rejected batch: {}", destination.address, e);
+                self.settle(src_framed_write, batch, Err("destination rejected batch")).await?;
+            }
+            PublishResponse::Heartbeat => {
+                if framed_write.send(Command::Nop).await.is_err() {
+                    return self.fail_destination(destinations, index, src_framed_write).await;
                 }
             }
-            Err(e) => {
-                warn!("Failed to connect to lookupd {}: {}", lookupd_addr, e);
+            PublishResponse::Unexpected(frame) => {
+                warn!("Unexpected frame from destination {}: {}", destination.address, String::from_utf8_lossy(&frame.body));
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Read responses until no destination has a batch awaiting one
+    async fn settle_all(
+        &self,
+        destinations: &mut DestinationPool,
+        src_framed_write: &mut CommandWriter,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for index in 0..destinations.destinations.len() {
+            while !destinations.destinations[index].pending.is_empty() {
+                self.read_response(destinations, index, src_framed_write).await?;
             }
         }
+        
+        Ok(())
+    }
+
+    /// Drop a failed destination and requeue the batches it had not acknowledged
+    async fn fail_destination(
+        &self,
+        destinations: &mut DestinationPool,
+        index: usize,
+        src_framed_write: &mut CommandWriter,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for batch in destinations.mark_failed(index) {
+            self.settle(src_framed_write, batch, Err("destination failed before acknowledging")).await?;
+        }
+        
+        Ok(())
+    }
+
+    /// FIN a batch upstream once published, or REQ it so it is retried
+    async fn settle(
+        &self,
+        src_framed_write: &mut CommandWriter,
+        messages: Vec<Message>,
+        result: Result<(), &str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = result {
+            warn!("Failed to replicate batch of {} messages: {}, requeued", messages.len(), e);
+        }
+        for message in &messages {
+            let message_id = bytes::Bytes::from(message.id.to_string());
+            let command = match result {
+                Ok(_) => Command::Fin { message_id },
+                Err(_) => Command::Req { message_id, timeout: self.requeue_delay },
+            };
+            src_framed_write.send(command).await?;
+        }
+        
+        Ok(())
     }
-    
-    Ok(nsqd_addresses)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    nsq_common::init_tool_logging("nsq_to_nsq")?;
     
     let args = Args::parse();
     
@@ -313,55 +621,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
     
-    let mut src_nsqd_addresses = args.src_nsqd_tcp_address;
-    
-    // Discover source NSQd addresses from lookupd if provided
-    if !args.src_lookupd_http_address.is_empty() {
-        match discover_nsqd_addresses(&args.src_lookupd_http_address).await {
-            Ok(discovered) => {
-                info!("Discovered {} source NSQd instances from lookupd", discovered.len());
-                src_nsqd_addresses.extend(discovered);
-            }
-            Err(e) => {
-                warn!("Failed to discover NSQd addresses from lookupd: {}", e);
-            }
-        }
+    if args.src_lookupd_poll_interval == 0 {
+        eprintln!("Error: --src-lookupd-poll-interval must be at least 1 second");
+        std::process::exit(1);
     }
     
-    if src_nsqd_addresses.is_empty() {
-        eprintln!("Error: No source NSQd addresses available");
+    let mut discovery = LookupdDiscovery::new(args.src_lookupd_http_address.clone(), Some(args.src_topic.clone()))
+        .with_interval(Duration::from_secs(args.src_lookupd_poll_interval));
+    
+    let dst_topic = match (args.dst_topic, &args.dst_topic_pattern) {
+        (Some(topic), _) => topic,
+        (None, Some(pattern)) => pattern.replace("{src}", &args.src_topic),
+        (None, None) => unreachable!("clap requires --dst-topic or --dst-topic-pattern"),
+    };
+    if let Err(e) = nsq_common::validate_topic_name(&dst_topic) {
+        eprintln!("Error: Invalid destination topic '{}': {}", dst_topic, e.reason);
         std::process::exit(1);
     }
     
-    let replicator = NsqReplicator::new(
-        args.src_topic,
-        args.src_channel,
-        args.dst_topic,
-        args.dst_channel,
-        args.buffer_size,
-        args.batch_size,
+    let filter = match MessageFilter::new(args.regex.as_deref(), &args.whitelist_json_field) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let replicator = NsqReplicator::new(ReplicatorOptions {
+        src_topic: args.src_topic,
+        src_channel: args.src_channel,
+        dst_topic,
+        dst_channel: args.dst_channel,
+        max_in_flight: args.max_in_flight,
+        batch_size: args.batch_size,
+        batch_timeout: Duration::from_millis(args.batch_timeout.max(1)),
+        requeue_delay: args.requeue_delay,
+        filter,
+    });
+    
+    let mut destinations = DestinationPool::new(
+        &args.dst_nsqd_tcp_address,
+        args.dst_strategy,
+        Duration::from_secs(args.dst_retry_interval),
+        args.dst_max_pending,
     );
     
-    // Try to connect to the first available source NSQd
-    let mut connected = false;
-    for src_address in &src_nsqd_addresses {
-        match replicator.replicate(src_address, &args.dst_nsqd_tcp_address).await {
-            Ok(_) => {
-                connected = true;
-                break;
+    // Replicate from the first available source NSQd, rediscovering them
+    // from lookupd until one is reachable
+    loop {
+        let mut src_nsqd_addresses = args.src_nsqd_tcp_address.clone();
+        if discovery.is_enabled() {
+            if let Err(e) = discovery.poll().await {
+                warn!("Failed to discover NSQd addresses from lookupd: {}", e);
             }
-            Err(e) => {
-                error!("Failed to replicate from {} to {}: {}", src_address, args.dst_nsqd_tcp_address, e);
-                continue;
+            info!("Discovered {} source NSQd instances from lookupd", discovery.producers().count());
+            src_nsqd_addresses.extend(discovery.producers().map(Producer::tcp_address));
+        }
+        
+        let mut connected = false;
+        for src_address in &src_nsqd_addresses {
+            match replicator.replicate(src_address, &mut destinations).await {
+                Ok(_) => {
+                    connected = true;
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to replicate from {}: {}", src_address, e);
+                    continue;
+                }
             }
         }
+        if connected {
+            break;
+        }
+        
+        if !discovery.is_enabled() {
+            eprintln!("Error: Failed to connect to any source NSQd instance");
+            std::process::exit(1);
+        }
+        warn!("No reachable source NSQd instance, retrying in {}s", args.src_lookupd_poll_interval);
+        tokio::time::sleep(Duration::from_secs(args.src_lookupd_poll_interval)).await;
     }
     
-    if !connected {
-        eprintln!("Error: Failed to connect to any source NSQd instance");
-        std::process::exit(1);
-    }
-    
+    nsq_common::shutdown_tracing();
     Ok(())
 }
 
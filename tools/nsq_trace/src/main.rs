@@ -0,0 +1,205 @@
+//! nsq_trace - Stream message trace events from nsqd
+
+use clap::{Parser, ValueEnum};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::time::{sleep, Duration};
+use tracing::warn;
+
+#[derive(Parser, Debug)]
+#[command(name = "nsq_trace")]
+#[command(about = "Stream message trace events from nsqd")]
+struct Args {
+    /// NSQd HTTP address
+    #[arg(long, default_value = "127.0.0.1:4151")]
+    nsqd_http_address: String,
+
+    /// Topic to trace
+    #[arg(long)]
+    topic: String,
+
+    /// Only show events on this channel
+    #[arg(long)]
+    channel: Option<String>,
+
+    /// Only show events of this message ID
+    #[arg(long)]
+    id: Option<String>,
+
+    /// Milliseconds between polls
+    #[arg(long, default_value = "500")]
+    interval: u64,
+
+    /// Also show the events recorded before starting
+    #[arg(long)]
+    from_start: bool,
+
+    /// Read events of a topic already traced, without enabling or disabling
+    /// tracing for it
+    #[arg(long)]
+    no_enable: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "pretty")]
+    format: OutputFormat,
+}
+
+/// How each event is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// One aligned line per event
+    Pretty,
+    /// The event as returned by nsqd, one JSON object per line
+    Json,
+}
+
+/// Response of /debug/trace/events
+#[derive(Debug, Deserialize)]
+struct TraceEvents {
+    events: Vec<serde_json::Value>,
+    last_seq: u64,
+}
+
+/// Fields of an event shown in pretty output
+#[derive(Debug, Deserialize)]
+struct TraceEvent {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    event: String,
+    topic: String,
+    channel: Option<String>,
+    message_id: String,
+    client_id: Option<String>,
+    attempts: Option<u16>,
+    timeout_ms: Option<u64>,
+}
+
+impl TraceEvent {
+    fn pretty(&self) -> String {
+        let location = match &self.channel {
+            Some(channel) => format!("{}/{}", self.topic, channel),
+            None => self.topic.clone(),
+        };
+        let mut line = format!(
+            "{} {:<8} {:<32} {}",
+            self.timestamp.format("%H:%M:%S%.3f"),
+            self.event,
+            location,
+            self.message_id,
+        );
+        if let Some(client_id) = &self.client_id {
+            line.push_str(&format!(" client={}", client_id));
+        }
+        if let Some(attempts) = self.attempts {
+            line.push_str(&format!(" attempts={}", attempts));
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            line.push_str(&format!(" timeout_ms={}", timeout_ms));
+        }
+        line
+    }
+}
+
+struct Tracer {
+    client: Client,
+    base_url: String,
+    args: Args,
+}
+
+impl Tracer {
+    fn new(args: Args) -> Self {
+        let base_url = if args.nsqd_http_address.starts_with("http") {
+            args.nsqd_http_address.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{}", args.nsqd_http_address)
+        };
+        Self { client: Client::new(), base_url, args }
+    }
+
+    /// Turn tracing of the topic on or off
+    async fn set_traced(&self, enabled: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let url = format!("{}/debug/trace", self.base_url);
+        let enabled = enabled.to_string();
+        self.client.post(&url)
+            .query(&[("topic", self.args.topic.as_str()), ("enabled", enabled.as_str())])
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Events recorded after `since`
+    async fn poll(&self, since: Option<u64>) -> Result<TraceEvents, Box<dyn std::error::Error>> {
+        let url = format!("{}/debug/trace/events", self.base_url);
+        let mut query = vec![("topic", self.args.topic.clone())];
+        if let Some(channel) = &self.args.channel {
+            query.push(("channel", channel.clone()));
+        }
+        if let Some(id) = &self.args.id {
+            query.push(("id", id.clone()));
+        }
+        if let Some(since) = since {
+            query.push(("since", since.to_string()));
+        }
+        Ok(self.client.get(&url).query(&query).send().await?.error_for_status()?.json().await?)
+    }
+
+    fn print(&self, event: serde_json::Value) {
+        match self.args.format {
+            OutputFormat::Json => println!("{}", event),
+            OutputFormat::Pretty => match serde_json::from_value::<TraceEvent>(event.clone()) {
+                Ok(event) => println!("{}", event.pretty()),
+                Err(_) => println!("{}", event),
+            },
+        }
+    }
+
+    /// Print new events until interrupted
+    async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut since = if self.args.from_start {
+            None
+        } else {
+            Some(self.poll(None).await?.last_seq)
+        };
+        loop {
+            match self.poll(since).await {
+                Ok(response) => {
+                    since = Some(response.last_seq);
+                    for event in response.events {
+                        self.print(event);
+                    }
+                }
+                Err(e) => warn!("Failed to read trace events: {}", e),
+            }
+            sleep(Duration::from_millis(self.args.interval)).await;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    nsq_common::init_tool_logging("nsq_trace")?;
+
+    let args = Args::parse();
+
+    if args.interval == 0 {
+        eprintln!("Error: --interval must be at least 1 millisecond");
+        std::process::exit(1);
+    }
+
+    let tracer = Tracer::new(args);
+    let manage = !tracer.args.no_enable;
+    if manage {
+        tracer.set_traced(true).await?;
+    }
+
+    let result = tokio::select! {
+        result = tracer.run() => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    if manage {
+        if let Err(e) = tracer.set_traced(false).await {
+            warn!("Failed to disable tracing of {}: {}", tracer.args.topic, e);
+        }
+    }
+    result
+}
@@ -1,14 +1,21 @@
 //! to_nsq - Producer that reads from stdin/files
 
 use clap::Parser;
-use nsq_protocol::{Command, Frame, FrameType, NsqDecoder, NsqEncoder};
+use std::collections::VecDeque;
+use std::time::Duration;
+use bytes::Bytes;
+use nsq_protocol::{Command, CommandEncoder, ErrorCode, FrameType, IdentifyResponse, NsqDecoder, PublishResponse, ResponseMatcher};
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, stdin};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader, stdin};
 use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use futures::SinkExt;
-use tracing::{info, warn};
+use tracing::{debug, error, info, warn};
+
+/// Delay between reconnection attempts in streaming mode
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
 #[command(name = "to_nsq")]
@@ -49,6 +56,22 @@ struct Args {
     /// Message prefix
     #[arg(long)]
     prefix: Option<String>,
+    
+    /// Publish lines as they arrive instead of reading all input first
+    #[arg(long)]
+    stream: bool,
+    
+    /// Maximum time in milliseconds a partial batch waits before publishing in streaming mode
+    #[arg(long, default_value = "1000")]
+    flush_timeout: u64,
+    
+    /// Maximum number of PUB/MPUB commands awaiting a response
+    #[arg(long, default_value = "16")]
+    max_pending: usize,
+    
+    /// Maximum attempts for a batch after transient failures
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
 }
 
 struct NsqProducer {
@@ -73,12 +96,13 @@ impl NsqProducer {
         }
     }
 
-    async fn publish_message(&self, framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, NsqEncoder>, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    /// Build the final message body, applying the prefix and timestamp
+    fn message_body(&self, content: Vec<u8>) -> Result<Bytes, Box<dyn std::error::Error>> {
         if content.len() > self.max_message_size {
             return Err(format!("Message too large: {} bytes (max: {})", content.len(), self.max_message_size).into());
         }
         
-        let mut message_body = content.to_vec();
+        let mut message_body = content;
         
         // Add prefix if specified
         if let Some(prefix) = &self.prefix {
@@ -95,65 +119,208 @@ impl NsqProducer {
             message_body = timestamped;
         }
         
-        let pub_cmd = Command::Pub {
-            topic: self.topic.clone(),
-            body: bytes::Bytes::from(message_body),
-        };
-        let pub_frame = Frame::new(FrameType::Response, pub_cmd.to_bytes()?);
-        framed_write.send(pub_frame).await?;
-        
-        Ok(())
+        Ok(Bytes::from(message_body))
     }
 
-    async fn publish_batch(&self, framed_write: &mut FramedWrite<tokio::net::tcp::OwnedWriteHalf, NsqEncoder>, messages: &[Vec<u8>]) -> Result<(), Box<dyn std::error::Error>> {
-        if messages.is_empty() {
-            return Ok(());
+    /// Build a PUB for a single body or an MPUB for several
+    fn command(&self, bodies: &[Bytes]) -> Command {
+        if bodies.len() == 1 {
+            Command::Pub {
+                topic: self.topic.clone(),
+                body: bodies[0].clone(),
+            }
+        } else {
+            Command::Mpub {
+                topic: self.topic.clone(),
+                bodies: bodies.to_vec(),
+            }
         }
-        
-        // Validate all messages
-        for msg in messages {
-            if msg.len() > self.max_message_size {
-                return Err(format!("Message too large: {} bytes (max: {})", msg.len(), self.max_message_size).into());
+    }
+}
+
+/// A batch waiting to be written or acknowledged
+struct PendingBatch {
+    bodies: Vec<Bytes>,
+    attempts: u32,
+}
+
+/// Open nsqd connection
+struct Connection {
+    framed_read: FramedRead<OwnedReadHalf, NsqDecoder>,
+    framed_write: FramedWrite<OwnedWriteHalf, CommandEncoder>,
+}
+
+/// Pipelines batches to nsqd and checks every PUB/MPUB response, retrying
+/// transient failures and counting messages that could not be published
+struct Publisher {
+    producer: NsqProducer,
+    address: String,
+    connection: Option<Connection>,
+    /// Batches not yet written
+    outbox: VecDeque<PendingBatch>,
+    /// Batches written and awaiting a response
+    pending: ResponseMatcher<PendingBatch>,
+    max_retries: u32,
+    /// Keep reconnecting instead of giving up after max_retries
+    reconnect_forever: bool,
+    published_count: usize,
+    failed_count: usize,
+}
+
+impl Publisher {
+    fn new(producer: NsqProducer, address: String, max_pending: usize, max_retries: u32, reconnect_forever: bool) -> Self {
+        Self {
+            producer,
+            address,
+            connection: None,
+            outbox: VecDeque::new(),
+            pending: ResponseMatcher::new(max_pending),
+            max_retries,
+            reconnect_forever,
+            published_count: 0,
+            failed_count: 0,
+        }
+    }
+
+    /// Queue messages for publishing; oversized messages are counted as failed
+    fn queue(&mut self, messages: Vec<Vec<u8>>) {
+        let mut bodies = Vec::with_capacity(messages.len());
+        for message in messages {
+            match self.producer.message_body(message) {
+                Ok(body) => bodies.push(body),
+                Err(e) => {
+                    error!("{}", e);
+                    self.failed_count += 1;
+                }
             }
         }
         
-        if messages.len() == 1 {
-            // Single message
-            self.publish_message(framed_write, &messages[0]).await?;
-        } else {
-            // Batch messages
-            let mut bodies = Vec::new();
-            for msg in messages {
-                let mut message_body = msg.clone();
-                
-                // Add prefix if specified
-                if let Some(prefix) = &self.prefix {
-                    let mut prefixed = prefix.as_bytes().to_vec();
-                    prefixed.extend_from_slice(&message_body);
-                    message_body = prefixed;
-                }
+        if !bodies.is_empty() {
+            self.outbox.push_back(PendingBatch { bodies, attempts: 0 });
+        }
+    }
+
+    /// Write queued batches, reading responses whenever the window is full.
+    /// With `drain` set, wait until every batch has been acknowledged.
+    async fn pump(&mut self, drain: bool) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            if self.connection.is_none() && !(self.outbox.is_empty() && self.pending.is_empty()) {
+                self.connect().await?;
+            }
+            
+            while !self.pending.is_full() {
+                let Some(batch) = self.outbox.pop_front() else {
+                    break;
+                };
+                let command = self.producer.command(&batch.bodies);
+                let Some(connection) = self.connection.as_mut() else {
+                    self.outbox.push_front(batch);
+                    break;
+                };
                 
-                // Add timestamp if specified
-                if self.add_timestamp {
-                    let timestamp = chrono::Utc::now().to_rfc3339();
-                    let mut timestamped = format!("[{}] ", timestamp).as_bytes().to_vec();
-                    timestamped.extend_from_slice(&message_body);
-                    message_body = timestamped;
+                let result = connection.framed_write.send(command).await;
+                self.pending.push(batch);
+                if let Err(e) = result {
+                    warn!("Failed to write to {}: {}", self.address, e);
+                    self.disconnect();
+                    break;
                 }
-                
-                bodies.push(bytes::Bytes::from(message_body));
             }
             
-            let mpub_cmd = Command::Mpub {
-                topic: self.topic.clone(),
-                bodies,
-            };
-            let mpub_frame = Frame::new(FrameType::Response, mpub_cmd.to_bytes()?);
-            framed_write.send(mpub_frame).await?;
+            if self.pending.is_empty() && self.outbox.is_empty() {
+                return Ok(());
+            }
+            if !drain && self.outbox.is_empty() && !self.pending.is_full() {
+                return Ok(());
+            }
+            if self.connection.is_some() {
+                self.read_response().await;
+            }
         }
+    }
+
+    /// Read one response and settle the oldest pending batch
+    async fn read_response(&mut self) {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        
+        let frame = match connection.framed_read.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                warn!("Failed to read response from {}: {}", self.address, e);
+                self.disconnect();
+                return;
+            }
+            None => {
+                warn!("Connection to {} closed", self.address);
+                self.disconnect();
+                return;
+            }
+        };
         
-        info!("Published batch of {} messages", messages.len());
-        Ok(())
+        match self.pending.match_frame(frame) {
+            PublishResponse::Ok(batch) => {
+                self.published_count += batch.bodies.len();
+                debug!("Published batch of {} messages", batch.bodies.len());
+            }
+            PublishResponse::Error(mut batch, error) => {
+                // Only publish failures inside nsqd are worth retrying
+                let transient = matches!(ErrorCode::parse(&error), Some(ErrorCode::PubFailed | ErrorCode::MpubFailed));
+                batch.attempts += 1;
+                if transient && batch.attempts < self.max_retries {
+                    warn!("Retrying batch of {} messages after error: {}", batch.bodies.len(), error);
+                    self.outbox.push_front(batch);
+                } else {
+                    error!("Failed to publish batch of {} messages: {}", batch.bodies.len(), error);
+                    self.failed_count += batch.bodies.len();
+                }
+            }
+            PublishResponse::Heartbeat => {
+                if connection.framed_write.send(Command::Nop).await.is_err() {
+                    self.disconnect();
+                }
+            }
+            PublishResponse::Unexpected(frame) => {
+                warn!("Unexpected frame from {}: {}", self.address, String::from_utf8_lossy(&frame.body));
+            }
+        }
+    }
+
+    /// Drop the connection and queue unacknowledged batches to be sent again
+    fn disconnect(&mut self) {
+        self.connection = None;
+        let unacknowledged: Vec<PendingBatch> = self.pending.drain().collect();
+        for mut batch in unacknowledged.into_iter().rev() {
+            batch.attempts += 1;
+            if batch.attempts < self.max_retries {
+                self.outbox.push_front(batch);
+            } else {
+                error!("Giving up on batch of {} messages after {} attempts", batch.bodies.len(), batch.attempts);
+                self.failed_count += batch.bodies.len();
+            }
+        }
+    }
+
+    /// Connect, retrying every RECONNECT_DELAY
+    async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut attempts = 0;
+        loop {
+            match connect(&self.address).await {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    return Ok(());
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if !self.reconnect_forever && attempts >= self.max_retries {
+                        return Err(format!("Failed to connect to {} after {} attempts: {}", self.address, attempts, e).into());
+                    }
+                    warn!("Failed to connect to {}: {}", self.address, e);
+                }
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
     }
 }
 
@@ -198,26 +365,13 @@ async fn read_from_file(file_path: &str, line_by_line: bool) -> Result<Vec<Vec<u
     Ok(messages)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
-    
-    let args = Args::parse();
-    
-    let topic = args.topic.clone();
-    let producer = NsqProducer::new(
-        args.topic,
-        args.max_message_size,
-        args.add_timestamp,
-        args.prefix,
-    );
-    
-    // Connect to NSQd
-    let stream = TcpStream::connect(&args.nsqd_tcp_address).await?;
+/// Connect to nsqd and send IDENTIFY
+async fn connect(address: &str) -> Result<Connection, Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect(address).await?;
     let (read_half, write_half) = stream.into_split();
     
     let mut framed_read = FramedRead::new(read_half, NsqDecoder::new());
-    let mut framed_write = FramedWrite::new(write_half, NsqEncoder);
+    let mut framed_write = FramedWrite::new(write_half, CommandEncoder::new());
     
     // Send IDENTIFY command
     let identify_data = serde_json::json!({
@@ -230,73 +384,162 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "output_buffer_timeout": 250
     });
     
-    let identify_cmd = Command::Identify { data: identify_data };
-    let identify_frame = Frame::new(FrameType::Response, identify_cmd.to_bytes()?);
-    framed_write.send(identify_frame).await?;
+    framed_write.send(Command::Identify { data: identify_data }).await?;
     
-    // Wait for OK response
-    if let Some(frame) = framed_read.next().await {
-        let frame = frame?;
-        if frame.frame_type != FrameType::Response {
-            return Err("Expected OK response after IDENTIFY".into());
+    // Wait for the negotiated settings
+    let frame = framed_read.next().await.ok_or("Connection closed during IDENTIFY")??;
+    if frame.frame_type != FrameType::Response {
+        return Err("Expected response after IDENTIFY".into());
+    }
+    match IdentifyResponse::parse(&frame.body)? {
+        Some(negotiated) => info!("Connected to {} (nsqd {})", address, negotiated.version),
+        None => info!("Connected to {}", address),
+    }
+    
+    Ok(Connection { framed_read, framed_write })
+}
+
+/// Settings for streaming mode
+struct StreamOptions {
+    batch_size: usize,
+    flush_timeout: Duration,
+    delay: Duration,
+}
+
+/// Publish lines as they are read, flushing full batches immediately and
+/// partial batches after the flush timeout
+async fn stream_lines<R: AsyncBufRead + Unpin>(
+    reader: R,
+    publisher: &mut Publisher,
+    options: &StreamOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut lines = reader.lines();
+    let mut batch = Vec::with_capacity(options.batch_size);
+    let mut flush_timer = tokio::time::interval(options.flush_timeout);
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    
+    loop {
+        // A timed flush means input went quiet, so wait for the responses too
+        let (flush, drain) = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => {
+                    if batch.is_empty() {
+                        flush_timer.reset();
+                    }
+                    batch.push(line.into_bytes());
+                    (batch.len() >= options.batch_size, false)
+                }
+                None => break,
+            },
+            _ = flush_timer.tick(), if !batch.is_empty() => (true, true),
+        };
+        
+        if flush {
+            publisher.queue(std::mem::take(&mut batch));
+            publisher.pump(drain).await?;
+            if !options.delay.is_zero() {
+                tokio::time::sleep(options.delay).await;
+            }
         }
-        info!("Connected successfully");
     }
     
-    info!("Ready to publish to topic '{}'", topic);
+    if !batch.is_empty() {
+        publisher.queue(batch);
+    }
     
-    // Read input data
-    let messages = if let Some(input_file) = &args.input_file {
-        read_from_file(input_file, args.line_by_line).await?
-    } else {
-        read_from_stdin(args.line_by_line).await?
-    };
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    nsq_common::init_tool_logging("to_nsq")?;
     
-    if messages.is_empty() {
-        warn!("No data to publish");
-        return Ok(());
+    let args = Args::parse();
+    
+    if args.batch_size == 0 {
+        eprintln!("Error: --batch-size must be at least 1");
+        std::process::exit(1);
     }
     
-    let total_messages = messages.len();
-    info!("Read {} messages from input", total_messages);
-    info!("Batch size: {}, Delay between batches: {}ms", args.batch_size, args.delay_ms);
+    if args.flush_timeout == 0 {
+        eprintln!("Error: --flush-timeout must be greater than 0");
+        std::process::exit(1);
+    }
     
-    // Publish messages in batches
-    let total_batches = (total_messages + args.batch_size - 1) / args.batch_size;
-    let mut batch_count = 0;
-    let mut batch = Vec::new();
-    let mut published_count = 0;
+    if args.max_pending == 0 || args.max_retries == 0 {
+        eprintln!("Error: --max-pending and --max-retries must be at least 1");
+        std::process::exit(1);
+    }
+    
+    let topic = args.topic.clone();
+    let producer = NsqProducer::new(
+        args.topic,
+        args.max_message_size,
+        args.add_timestamp,
+        args.prefix,
+    );
+    let mut publisher = Publisher::new(producer, args.nsqd_tcp_address, args.max_pending, args.max_retries, args.stream);
+    
+    publisher.connect().await?;
+    
+    info!("Ready to publish to topic '{}'", topic);
     
-    for (idx, message) in messages.into_iter().enumerate() {
-        batch.push(message);
+    if args.stream {
+        let options = StreamOptions {
+            batch_size: args.batch_size,
+            flush_timeout: Duration::from_millis(args.flush_timeout),
+            delay: Duration::from_millis(args.delay_ms),
+        };
+        if let Some(input_file) = &args.input_file {
+            let reader = BufReader::new(File::open(input_file).await?);
+            stream_lines(reader, &mut publisher, &options).await?;
+        } else {
+            stream_lines(BufReader::new(stdin()), &mut publisher, &options).await?;
+        }
+    } else {
+        // Read input data
+        let messages = if let Some(input_file) = &args.input_file {
+            read_from_file(input_file, args.line_by_line).await?
+        } else {
+            read_from_stdin(args.line_by_line).await?
+        };
+        
+        if messages.is_empty() {
+            warn!("No data to publish");
+            return Ok(());
+        }
         
-        if batch.len() >= args.batch_size {
-            batch_count += 1;
-            let batch_len = batch.len();
-            info!("Publishing batch {}/{} ({} messages)", batch_count, total_batches, batch_len);
-            producer.publish_batch(&mut framed_write, &batch).await?;
-            published_count += batch_len;
-            batch.clear();
+        let total_messages = messages.len();
+        info!("Read {} messages from input", total_messages);
+        info!("Batch size: {}, Delay between batches: {}ms", args.batch_size, args.delay_ms);
+        
+        // Publish messages in batches
+        let total_batches = total_messages.div_ceil(args.batch_size);
+        let mut messages = messages.into_iter();
+        for batch_count in 1..=total_batches {
+            let batch: Vec<Vec<u8>> = messages.by_ref().take(args.batch_size).collect();
+            info!("Publishing batch {}/{} ({} messages)", batch_count, total_batches, batch.len());
+            publisher.queue(batch);
             
             // Add delay between batches (not after the last batch)
-            if args.delay_ms > 0 && idx < total_messages - 1 {
+            if args.delay_ms > 0 && batch_count < total_batches {
+                publisher.pump(true).await?;
                 info!("Waiting {}ms before next batch...", args.delay_ms);
                 tokio::time::sleep(tokio::time::Duration::from_millis(args.delay_ms)).await;
+            } else {
+                publisher.pump(false).await?;
             }
         }
     }
     
-    // Publish remaining messages
-    if !batch.is_empty() {
-        batch_count += 1;
-        let batch_len = batch.len();
-        info!("Publishing final batch {}/{} ({} messages)", batch_count, total_batches, batch_len);
-        producer.publish_batch(&mut framed_write, &batch).await?;
-        published_count += batch_len;
-    }
+    publisher.pump(true).await?;
+    
+    info!("Finished publishing {} messages", publisher.published_count);
     
-    info!("Finished publishing {} messages in {} batches", published_count, batch_count);
+    if publisher.failed_count > 0 {
+        eprintln!("Error: {} messages failed to publish ({} published)", publisher.failed_count, publisher.published_count);
+        std::process::exit(1);
+    }
     
     Ok(())
 }
-